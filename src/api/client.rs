@@ -0,0 +1,340 @@
+use std::io::{Read, Write};
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, TcpStream};
+
+use super::request::ApiRequest;
+use super::ApiResponse;
+use crate::block::Header;
+use crate::blockchain::{ChainStats, ChainTip};
+use crate::crypto::hash::H256;
+use crate::explorer::AnnotatedTransaction;
+use crate::fee::FeeRate;
+use crate::miner::{BlockTemplate, MinerStatus};
+use crate::network::peer_manager::{PeerId, PeerInfo};
+use crate::network::traffic::TrafficReport;
+use crate::transaction::{SignatureScheme, TxOutput};
+
+/// Reasons for an API call to fail
+#[derive(Debug)]
+pub enum ClientError {
+    Io(std::io::Error),
+    /// The server answered with a non-200 status code
+    Status(u16),
+    /// The response body could not be decoded
+    Decode(String),
+    /// The server reported a failure
+    Failed(String),
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "i/o error: {}", e),
+            ClientError::Status(code) => write!(f, "unexpected status code {}", code),
+            ClientError::Decode(e) => write!(f, "error decoding response: {}", e),
+            ClientError::Failed(message) => write!(f, "request failed: {}", message),
+        }
+    }
+}
+
+impl From<std::io::Error> for ClientError {
+    fn from(e: std::io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+/// A typed client for the API server of a node
+#[derive(Debug, Clone)]
+pub struct NodeClient {
+    addr: SocketAddr,
+}
+
+impl NodeClient {
+    pub fn new(addr: SocketAddr) -> Self {
+        NodeClient { addr }
+    }
+
+    /// Send a request and return the raw response body
+    pub fn call(&self, request: &ApiRequest) -> Result<Vec<u8>, ClientError> {
+        let mut stream = TcpStream::connect(self.addr)?;
+        let head = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n",
+            request.to_path_and_query(),
+            self.addr
+        );
+        stream.write_all(head.as_bytes())?;
+        let mut response: Vec<u8> = Vec::new();
+        stream.read_to_end(&mut response)?;
+
+        let split = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or_else(|| ClientError::Decode("truncated response".to_string()))?;
+        let head = String::from_utf8_lossy(&response[..split]);
+        let status = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(|| ClientError::Decode("malformed status line".to_string()))?;
+        if status != 200 {
+            return Err(ClientError::Status(status));
+        }
+        return Ok(response[split + 4..].to_vec());
+    }
+
+    /// Send a request answered by an `ApiResponse`, failing if the response reports no success
+    fn call_json(&self, request: &ApiRequest) -> Result<ApiResponse, ClientError> {
+        let body = self.call(request)?;
+        let response: ApiResponse =
+            serde_json::from_slice(&body).map_err(|e| ClientError::Decode(e.to_string()))?;
+        if !response.success {
+            return Err(ClientError::Failed(response.message));
+        }
+        return Ok(response);
+    }
+
+    pub fn start_miner(&self, lambda: u64) -> Result<(), ClientError> {
+        self.call_json(&ApiRequest::MinerStart { lambda })?;
+        return Ok(());
+    }
+
+    /// Mine exactly `blocks` blocks, then pause
+    pub fn step_miner(&self, blocks: u64) -> Result<(), ClientError> {
+        self.call_json(&ApiRequest::MinerStep { blocks })?;
+        return Ok(());
+    }
+
+    pub fn pause_miner(&self) -> Result<(), ClientError> {
+        self.call_json(&ApiRequest::MinerPause)?;
+        return Ok(());
+    }
+
+    pub fn miner_status(&self) -> Result<MinerStatus, ClientError> {
+        let response = self.call_json(&ApiRequest::MinerStatus)?;
+        return serde_json::from_str(&response.message).map_err(|e| ClientError::Decode(e.to_string()));
+    }
+
+    /// Pay the mined blocks to `payout`, by its script if it has one or else by its address, with
+    /// `tag` in the coinbase
+    pub fn set_coinbase(&self, payout: &TxOutput, tag: &str) -> Result<(), ClientError> {
+        let (address, script) = match payout.scheme {
+            SignatureScheme::Script => (None, Some(hex::encode(payout.script_pubkey.as_bytes()))),
+            _ => (Some(payout.recipient), None),
+        };
+        self.call_json(&ApiRequest::MinerCoinbase { address, script, tag: Some(tag.to_string()) })?;
+        return Ok(());
+    }
+
+    /// Mine `blocks` blocks at once on a regtest node, returning their hashes
+    pub fn generate(&self, blocks: u64) -> Result<Vec<H256>, ClientError> {
+        let response = self.call_json(&ApiRequest::MinerGenerate { blocks })?;
+        let hashes: Vec<String> =
+            serde_json::from_str(&response.message).map_err(|e| ClientError::Decode(e.to_string()))?;
+        return hashes
+            .iter()
+            .map(|h| h.parse().map_err(|e: hex::FromHexError| ClientError::Decode(e.to_string())))
+            .collect();
+    }
+
+    /// A block template to mine outside of the node
+    pub fn block_template(&self) -> Result<BlockTemplate, ClientError> {
+        let response = self.call_json(&ApiRequest::MinerTemplate)?;
+        return serde_json::from_str(&response.message).map_err(|e| ClientError::Decode(e.to_string()));
+    }
+
+    /// Submit the header of a block template with a nonce solving it, returning the hash of the
+    /// block once inserted
+    pub fn submit_header(&self, header: &Header) -> Result<H256, ClientError> {
+        let header = hex::encode(bincode::serialize(header).unwrap());
+        let response = self.call_json(&ApiRequest::MinerSubmit { header })?;
+        return response.message.parse().map_err(|e: hex::FromHexError| ClientError::Decode(e.to_string()));
+    }
+
+    /// The statistics of the miner and the chain, in the Prometheus text format
+    pub fn metrics(&self) -> Result<String, ClientError> {
+        let body = self.call(&ApiRequest::Metrics)?;
+        return String::from_utf8(body).map_err(|e| ClientError::Decode(e.to_string()));
+    }
+
+    pub fn ping(&self) -> Result<(), ClientError> {
+        self.call_json(&ApiRequest::NetworkPing)?;
+        return Ok(());
+    }
+
+    /// The peers connected to the node
+    pub fn peers(&self) -> Result<Vec<PeerInfo>, ClientError> {
+        let response = self.call_json(&ApiRequest::NetworkPeers)?;
+        return serde_json::from_str(&response.message).map_err(|e| ClientError::Decode(e.to_string()));
+    }
+
+    /// The bytes exchanged with the peers, and the state of the upload target
+    pub fn traffic(&self) -> Result<TrafficReport, ClientError> {
+        let response = self.call_json(&ApiRequest::NetworkTraffic)?;
+        return serde_json::from_str(&response.message).map_err(|e| ClientError::Decode(e.to_string()));
+    }
+
+    pub fn disconnect_peer(&self, id: PeerId) -> Result<(), ClientError> {
+        self.call_json(&ApiRequest::NetworkDisconnect { id })?;
+        return Ok(());
+    }
+
+    pub fn headers(&self, from: u32, to: u32) -> Result<Vec<Header>, ClientError> {
+        let body = self.call(&ApiRequest::BlockchainHeaders { from, to })?;
+        // failures are reported as JSON, successes as bincode
+        if let Ok(response) = serde_json::from_slice::<ApiResponse>(&body) {
+            return Err(ClientError::Failed(response.message));
+        }
+        return bincode::deserialize(&body).map_err(|e| ClientError::Decode(e.to_string()));
+    }
+
+    /// Export a signed archive of the chain state to the file `file` of the export directory of
+    /// the node, returning the message of the node, which contains the public key of the signer
+    pub fn export_archive(&self, file: &str) -> Result<String, ClientError> {
+        let response = self.call_json(&ApiRequest::BlockchainExportArchive { file: file.to_string() })?;
+        return Ok(response.message);
+    }
+
+    /// Annotate the confirmed transaction `txid`
+    pub fn annotate_transaction(&self, txid: &str) -> Result<AnnotatedTransaction, ClientError> {
+        let request = ApiRequest::TransactionAnnotate { txid: Some(txid.to_string()), raw: None };
+        let response = self.call_json(&request)?;
+        return serde_json::from_str(&response.message).map_err(|e| ClientError::Decode(e.to_string()));
+    }
+
+    /// All chain tips known to the node, the active one first
+    pub fn chain_tips(&self) -> Result<Vec<ChainTip>, ClientError> {
+        let response = self.call_json(&ApiRequest::BlockchainTips)?;
+        return serde_json::from_str(&response.message).map_err(|e| ClientError::Decode(e.to_string()));
+    }
+
+    /// Statistics about the chain, with block times averaged over the last `window` blocks
+    pub fn stats(&self, window: u32) -> Result<ChainStats, ClientError> {
+        let response = self.call_json(&ApiRequest::BlockchainStats { window })?;
+        return serde_json::from_str(&response.message).map_err(|e| ClientError::Decode(e.to_string()));
+    }
+
+    pub fn invalidate_block(&self, hash: &H256) -> Result<(), ClientError> {
+        self.call_json(&ApiRequest::BlockchainInvalidateBlock { hash: *hash })?;
+        return Ok(());
+    }
+
+    pub fn reconsider_block(&self, hash: &H256) -> Result<(), ClientError> {
+        self.call_json(&ApiRequest::BlockchainReconsiderBlock { hash: *hash })?;
+        return Ok(());
+    }
+
+    /// The configured size of each thread pool of the node
+    /// The fee rate for a transaction to be mined within `target` blocks, failing without enough
+    /// history
+    pub fn estimate_fee(&self, target: usize) -> Result<FeeRate, ClientError> {
+        let response = self.call_json(&ApiRequest::TransactionEstimateFee { target })?;
+        let sat_per_kvb = response.message.parse::<u64>().map_err(|e| ClientError::Decode(e.to_string()))?;
+        return Ok(FeeRate::from_sat_per_kvb(sat_per_kvb));
+    }
+
+    pub fn pools(&self) -> Result<BTreeMap<String, usize>, ClientError> {
+        let response = self.call_json(&ApiRequest::AdminPools)?;
+        return serde_json::from_str(&response.message).map_err(|e| ClientError::Decode(e.to_string()));
+    }
+
+    pub fn resize_pool(&self, name: &str, size: usize) -> Result<(), ClientError> {
+        self.call_json(&ApiRequest::AdminResizePool { name: name.to_string(), size })?;
+        return Ok(());
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::api::Server;
+    use crate::blockchain::Blockchain;
+    use crate::block::test::generate_random_block;
+    use crate::crypto::hash::Hashable;
+    use crate::fee_estimator::FeeEstimator;
+    use crate::mempool::Mempool;
+    use crate::miner;
+    use crate::network::peer_manager::PeerLimits;
+    use crate::network::server;
+    use crate::runtime::{Runtime, MINING_POOL};
+    use crossbeam::channel;
+    use std::sync::{Arc, RwLock};
+
+    #[test]
+    fn headers_and_miner() {
+        let mut blockchain = Blockchain::new();
+        let block = generate_random_block(&blockchain.tip());
+        blockchain.insert(&block).unwrap();
+        let blockchain = Arc::new(RwLock::new(blockchain));
+        let (msg_tx, _msg_rx) = channel::unbounded();
+        let (_server_ctx, server) = server::new("127.0.0.1:0".parse().unwrap(), PeerLimits::default(), msg_tx).unwrap();
+        let mempool = Arc::new(RwLock::new(Mempool::new()));
+        let runtime = Arc::new(Runtime::new(&BTreeMap::new()).unwrap());
+        let (_miner_ctx, miner, _blocks) = miner::new(&blockchain, &mempool, runtime.pool(MINING_POOL).unwrap());
+        let addr = Server::start("127.0.0.1:0".parse().unwrap(), &miner, &server, &blockchain, &runtime, &Arc::new(RwLock::new(FeeEstimator::new())), None);
+
+        let client = NodeClient::new(addr);
+        let headers = client.headers(0, 10).unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[1].hash(), block.hash());
+        assert!(matches!(client.headers(5, 1), Err(ClientError::Failed(_))));
+        let tips = client.chain_tips().unwrap();
+        assert_eq!(tips[0].hash, block.hash());
+        assert!(matches!(client.reconsider_block(&block.hash()), Err(ClientError::Failed(_))));
+        assert!(client.stats(10).unwrap().blocks >= 2);
+        assert!(client.start_miner(0).is_ok());
+        assert!(client.pause_miner().is_ok());
+        assert!(client.step_miner(1).is_ok());
+        assert_eq!(client.miner_status().unwrap().blocks_mined, 0);
+        client.set_coinbase(&TxOutput::new(0, H256::from([1u8; 32])), "/node/").unwrap();
+        let tag = "x".repeat(miner::MAX_COINBASE_TAG + 1);
+        assert!(matches!(client.set_coinbase(&TxOutput::new(0, H256::from([1u8; 32])), &tag), Err(ClientError::Failed(_))));
+        let metrics = client.metrics().unwrap();
+        assert!(metrics.contains("\nminer_blocks_orphaned_total 0\n"));
+        assert!(metrics.contains("\nblockchain_height 1\n"));
+        assert!(metrics.contains("\nnetwork_bytes_sent_total 0\n"));
+
+        // mine a template outside of the node
+        let template = client.block_template().unwrap();
+        assert_eq!(template.header.get_parent(), block.hash());
+        let mut header = template.header.clone();
+        if header.hash() > template.target {
+            assert!(matches!(client.submit_header(&header), Err(ClientError::Failed(_))));
+        }
+        while header.hash() > template.target {
+            header.set_nonce(header.get_nonce().wrapping_add(1));
+        }
+        assert_eq!(client.submit_header(&header).unwrap(), header.hash());
+        assert_eq!(blockchain.read().unwrap().tip(), header.hash());
+        assert!(matches!(client.submit_header(&header), Err(ClientError::Failed(_))));
+        assert!(matches!(client.generate(1), Err(ClientError::Failed(_))));
+        let txid = block.get_transactions()[0].hash().to_string();
+        assert_eq!(client.annotate_transaction(&txid).unwrap().block, Some(block.hash().to_string()));
+        client.resize_pool("network", 3).unwrap();
+        assert_eq!(client.pools().unwrap()["network"], 3);
+        assert!(matches!(client.resize_pool("unknown", 3), Err(ClientError::Failed(_))));
+        assert!(client.peers().unwrap().is_empty());
+        let traffic = client.traffic().unwrap();
+        assert_eq!(traffic.upload_target, None);
+        assert!(!traffic.upload_target_reached);
+        assert!(matches!(client.disconnect_peer(0), Err(ClientError::Failed(_))));
+        assert!(matches!(client.estimate_fee(6), Err(ClientError::Failed(_))));
+    }
+
+    #[test]
+    fn regtest_generate() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::regtest()));
+        let (msg_tx, _msg_rx) = channel::unbounded();
+        let (_server_ctx, server) = server::new("127.0.0.1:0".parse().unwrap(), PeerLimits::default(), msg_tx).unwrap();
+        let mempool = Arc::new(RwLock::new(Mempool::new()));
+        let runtime = Arc::new(Runtime::new(&BTreeMap::new()).unwrap());
+        let (_miner_ctx, miner, _blocks) = miner::new(&blockchain, &mempool, runtime.pool(MINING_POOL).unwrap());
+        let addr = Server::start("127.0.0.1:0".parse().unwrap(), &miner, &server, &blockchain, &runtime, &Arc::new(RwLock::new(FeeEstimator::new())), None);
+
+        let client = NodeClient::new(addr);
+        let hashes = client.generate(5).unwrap();
+        assert_eq!(hashes.len(), 5);
+        let blockchain = blockchain.read().unwrap();
+        assert_eq!(blockchain.tip_height(), 5);
+        assert_eq!(blockchain.tip(), hashes[4]);
+    }
+}
@@ -1,11 +1,28 @@
-use serde::Serialize;
-use crate::miner::Handle as MinerHandle;
+pub mod client;
+pub mod request;
+
+use serde::{Serialize, Deserialize};
+use crate::block::Header as BlockHeader;
+use crate::miner::{self, CoinbaseConfig, Handle as MinerHandle, MinerStatus};
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
+use crate::network::traffic::TrafficStats;
+use crate::blockchain::Blockchain;
+use crate::archive::ChainArchive;
+use crate::snapshot::UtxoSnapshot;
+use crate::runtime::Runtime;
+use crate::fee_estimator::FeeEstimator;
+use crate::explorer;
+use crate::script::Script;
+use crate::transaction::{Transaction, TxOutput};
+use crate::crypto::hash::H256;
+use self::request::{ApiRequest, ParseError};
 
 use log::info;
-use std::collections::HashMap;
+use ring::signature::Ed25519KeyPair;
+use std::path::{Component, Path, PathBuf};
 use std::thread;
+use std::sync::{Arc, RwLock};
 use tiny_http::Header;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
@@ -15,12 +32,56 @@ pub struct Server {
     handle: HTTPServer,
     miner: MinerHandle,
     network: NetworkServerHandle,
+    blockchain: Arc<RwLock<Blockchain>>,
+    runtime: Arc<Runtime>,
+    fee_estimator: Arc<RwLock<FeeEstimator>>,
+    exports: Option<Arc<Exports>>,
+}
+
+/// Where the exports requested through the API are written, and how archives are signed
+pub struct Exports {
+    /// The only directory exports are written to
+    pub dir: PathBuf,
+    /// The persistent key of the node, so that the signature of an archive identifies its node
+    pub archive_key: Ed25519KeyPair,
 }
 
-#[derive(Serialize)]
-struct ApiResponse {
-    success: bool,
-    message: String,
+/// The path of the export `file`, which must be a plain file name, so that exports stay in the
+/// export directory
+fn export_path(exports: Option<&Exports>, file: &str) -> Result<PathBuf, String> {
+    let exports = exports.ok_or("exports need a data directory")?;
+    let mut components = Path::new(file).components();
+    return match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => Ok(exports.dir.join(name)),
+        _ => Err(format!("invalid file name {:?}", file)),
+    };
+}
+
+/// Maximum number of headers served by a single `/blockchain/headers` request
+pub const MAX_HEADERS_PER_REQUEST: u32 = 2000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ApiResponse {
+    pub success: bool,
+    pub message: String,
+}
+
+/// The statistics of the miner, the chain and the traffic in the Prometheus text format
+pub fn metrics(miner: &MinerStatus, blockchain: &Blockchain, traffic: &TrafficStats) -> String {
+    let metrics: Vec<(&str, &str, &str, String)> = vec![
+        ("miner_attempts_total", "counter", "Nonces tried", miner.attempts.to_string()),
+        ("miner_hash_rate", "gauge", "Nonces tried per second", miner.hash_rate.to_string()),
+        ("miner_blocks_mined_total", "counter", "Blocks mined", miner.blocks_mined.to_string()),
+        ("miner_blocks_orphaned_total", "counter", "Blocks mined that are not on the longest chain", miner.orphaned.to_string()),
+        ("blockchain_height", "gauge", "Height of the tip", blockchain.tip_height().to_string()),
+        ("network_bytes_sent_total", "counter", "Bytes sent to peers", traffic.bytes_sent.to_string()),
+        ("network_bytes_received_total", "counter", "Bytes received from peers", traffic.bytes_received.to_string()),
+    ];
+    let mut text = String::new();
+    for (name, kind, help, value) in metrics {
+        text.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value));
+    }
+    return text;
 }
 
 macro_rules! respond_result {
@@ -37,21 +98,36 @@ macro_rules! respond_result {
 }
 
 impl Server {
+    /// Serve the API on `addr`, returning the address bound, which gives the port when `addr`
+    /// has port 0
     pub fn start(
         addr: std::net::SocketAddr,
         miner: &MinerHandle,
         network: &NetworkServerHandle,
-    ) {
+        blockchain: &Arc<RwLock<Blockchain>>,
+        runtime: &Arc<Runtime>,
+        fee_estimator: &Arc<RwLock<FeeEstimator>>,
+        exports: Option<Exports>,
+    ) -> std::net::SocketAddr {
         let handle = HTTPServer::http(&addr).unwrap();
+        let addr = handle.server_addr();
         let server = Self {
             handle,
             miner: miner.clone(),
             network: network.clone(),
+            blockchain: Arc::clone(blockchain),
+            runtime: Arc::clone(runtime),
+            fee_estimator: Arc::clone(fee_estimator),
+            exports: exports.map(Arc::new),
         };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
                 let miner = server.miner.clone();
                 let network = server.network.clone();
+                let blockchain = Arc::clone(&server.blockchain);
+                let runtime = Arc::clone(&server.runtime);
+                let fee_estimator = Arc::clone(&server.fee_estimator);
+                let exports = server.exports.clone();
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -62,36 +138,9 @@ impl Server {
                             return;
                         }
                     };
-                    match url.path() {
-                        "/miner/start" => {
-                            let params = url.query_pairs();
-                            let params: HashMap<_, _> = params.into_owned().collect();
-                            let lambda = match params.get("lambda") {
-                                Some(v) => v,
-                                None => {
-                                    respond_result!(req, false, "missing lambda");
-                                    return;
-                                }
-                            };
-                            let lambda = match lambda.parse::<u64>() {
-                                Ok(v) => v,
-                                Err(e) => {
-                                    respond_result!(
-                                        req,
-                                        false,
-                                        format!("error parsing lambda: {}", e)
-                                    );
-                                    return;
-                                }
-                            };
-                            miner.start(lambda);
-                            respond_result!(req, true, "ok");
-                        }
-                        "/network/ping" => {
-                            network.broadcast(Message::Ping(String::from("Test ping")));
-                            respond_result!(req, true, "ok");
-                        }
-                        _ => {
+                    let request = match ApiRequest::from_url(&url) {
+                        Ok(r) => r,
+                        Err(ParseError::NotFound) => {
                             let content_type =
                                 "Content-Type: application/json".parse::<Header>().unwrap();
                             let payload = ApiResponse {
@@ -104,11 +153,269 @@ impl Server {
                             .with_header(content_type)
                             .with_status_code(404);
                             req.respond(resp).unwrap();
+                            return;
+                        }
+                        Err(e) => {
+                            respond_result!(req, false, e);
+                            return;
+                        }
+                    };
+                    match request {
+                        ApiRequest::MinerStart { lambda } => {
+                            miner.start(lambda);
+                            respond_result!(req, true, "ok");
+                        }
+                        ApiRequest::MinerStep { blocks } => {
+                            miner.step(blocks);
+                            respond_result!(req, true, "ok");
+                        }
+                        ApiRequest::MinerPause => {
+                            miner.pause();
+                            respond_result!(req, true, "ok");
+                        }
+                        ApiRequest::MinerStatus => {
+                            respond_result!(req, true, serde_json::to_string(&miner.status()).unwrap());
+                        }
+                        ApiRequest::MinerCoinbase { address, script, tag } => {
+                            let payout = match (address, script) {
+                                (Some(address), _) => TxOutput::new(0, address),
+                                (None, Some(script)) => match hex::decode(&script) {
+                                    Ok(bytes) => TxOutput::with_script(0, Script::from_bytes(bytes)),
+                                    Err(e) => {
+                                        respond_result!(req, false, format!("error parsing script: {}", e));
+                                        return;
+                                    }
+                                },
+                                (None, None) => unreachable!(),
+                            };
+                            let tag = tag.unwrap_or_default().into_bytes();
+                            match miner.set_coinbase(CoinbaseConfig { payout, tag }) {
+                                Ok(()) => respond_result!(req, true, "ok"),
+                                Err(e) => respond_result!(req, false, format!("error setting coinbase: {}", e)),
+                            }
+                        }
+                        ApiRequest::MinerGenerate { blocks } => {
+                            if !blockchain.read().unwrap().is_regtest() {
+                                respond_result!(req, false, "generating blocks requires a regtest chain");
+                                return;
+                            }
+                            let mut hashes: Vec<String> = Vec::new();
+                            for _ in 0..blocks {
+                                match miner::worker::submit(&blockchain, &network, &miner.generate_block()) {
+                                    Ok(hash) => hashes.push(hash.to_string()),
+                                    Err(e) => {
+                                        respond_result!(req, false, e);
+                                        return;
+                                    }
+                                }
+                            }
+                            respond_result!(req, true, serde_json::to_string(&hashes).unwrap());
+                        }
+                        ApiRequest::MinerTemplate => {
+                            respond_result!(req, true, serde_json::to_string(&miner.block_template()).unwrap());
+                        }
+                        ApiRequest::MinerSubmit { header } => {
+                            let header: BlockHeader = match hex::decode(&header)
+                                .map_err(|e| e.to_string())
+                                .and_then(|bytes| bincode::deserialize(&bytes).map_err(|e| e.to_string()))
+                            {
+                                Ok(header) => header,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing header: {}", e));
+                                    return;
+                                }
+                            };
+                            let block = match miner.solved_block(header) {
+                                Some(block) => block,
+                                None => {
+                                    respond_result!(req, false, "unknown block template");
+                                    return;
+                                }
+                            };
+                            match miner::worker::submit(&blockchain, &network, &block) {
+                                Ok(hash) => respond_result!(req, true, hash),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        ApiRequest::Metrics => {
+                            let text = metrics(&miner.status(), &blockchain.read().unwrap(), &network.traffic().totals);
+                            let content_type = "Content-Type: text/plain; version=0.0.4".parse::<Header>().unwrap();
+                            req.respond(Response::from_string(text).with_header(content_type)).unwrap();
+                        }
+                        ApiRequest::NetworkPing => {
+                            network.broadcast(Message::Ping(String::from("Test ping")));
+                            respond_result!(req, true, "ok");
+                        }
+                        ApiRequest::NetworkPeers => {
+                            respond_result!(req, true, serde_json::to_string(&network.peers()).unwrap());
+                        }
+                        ApiRequest::NetworkTraffic => {
+                            respond_result!(req, true, serde_json::to_string(&network.traffic()).unwrap());
+                        }
+                        ApiRequest::NetworkDisconnect { id } => {
+                            if network.disconnect_peer(id) {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, format!("no peer with id {}", id));
+                            }
+                        }
+                        ApiRequest::BlockchainHeaders { from, to } => {
+                            if to < from || to - from >= MAX_HEADERS_PER_REQUEST {
+                                respond_result!(
+                                    req,
+                                    false,
+                                    format!("invalid range, at most {} headers per request", MAX_HEADERS_PER_REQUEST)
+                                );
+                                return;
+                            }
+                            let headers = blockchain.read().unwrap().headers_in_range(from, to);
+                            let content_type =
+                                "Content-Type: application/octet-stream".parse::<Header>().unwrap();
+                            let resp = Response::from_data(bincode::serialize(&headers).unwrap())
+                                .with_header(content_type);
+                            req.respond(resp).unwrap();
+                        }
+                        ApiRequest::BlockchainExportArchive { file } => {
+                            let path = match export_path(exports.as_deref(), &file) {
+                                Ok(path) => path,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let key = &exports.as_ref().unwrap().archive_key;
+                            let archive = ChainArchive::create(&blockchain.read().unwrap(), key);
+                            match archive.save(&path) {
+                                Ok(_) => {
+                                    let public_key = hex::encode(ring::signature::KeyPair::public_key(key));
+                                    respond_result!(req, true, format!("signed by {}", public_key));
+                                }
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error writing archive: {}", e));
+                                }
+                            }
+                        }
+                        ApiRequest::BlockchainTips => {
+                            let tips = blockchain.read().unwrap().chain_tips();
+                            respond_result!(req, true, serde_json::to_string(&tips).unwrap());
+                        }
+                        ApiRequest::BlockchainExportUtxoSnapshot { file } => {
+                            let path = match export_path(exports.as_deref(), &file) {
+                                Ok(path) => path,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let snapshot = UtxoSnapshot::create(&blockchain.read().unwrap());
+                            match snapshot.save(&path) {
+                                Ok(_) => {
+                                    respond_result!(req, true, format!("UTXO set {}", snapshot.utxo_hash()));
+                                }
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error writing snapshot: {}", e));
+                                }
+                            }
+                        }
+                        ApiRequest::BlockchainExportBootstrap { file } => {
+                            let path = match export_path(exports.as_deref(), &file) {
+                                Ok(path) => path,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            match blockchain.read().unwrap().export(&path) {
+                                Ok(n) => {
+                                    respond_result!(req, true, format!("exported {} blocks", n));
+                                }
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error writing bootstrap file: {}", e));
+                                }
+                            }
+                        }
+                        ApiRequest::BlockchainStats { window } => {
+                            let stats = blockchain.read().unwrap().stats(window);
+                            respond_result!(req, true, serde_json::to_string(&stats).unwrap());
+                        }
+                        ApiRequest::BlockchainInvalidateBlock { hash } => {
+                            match blockchain.write().unwrap().invalidate_block(&hash) {
+                                Ok(()) => respond_result!(req, true, "ok"),
+                                Err(e) => respond_result!(req, false, format!("error invalidating block: {}", e)),
+                            }
+                        }
+                        ApiRequest::BlockchainReconsiderBlock { hash } => {
+                            match blockchain.write().unwrap().reconsider_block(&hash) {
+                                Ok(()) => respond_result!(req, true, "ok"),
+                                Err(e) => respond_result!(req, false, format!("error reconsidering block: {}", e)),
+                            }
+                        }
+                        ApiRequest::TransactionAnnotate { txid, raw } => {
+                            let blockchain = blockchain.read().unwrap();
+                            let transaction: Result<Transaction, String> = match (txid, raw) {
+                                (_, Some(raw)) => hex::decode(&raw)
+                                    .map_err(|e| e.to_string())
+                                    .and_then(|bytes| bincode::deserialize(&bytes).map_err(|e| e.to_string())),
+                                (Some(txid), None) => {
+                                    let mut raw_hash = [0u8; 32];
+                                    hex::decode_to_slice(&txid, &mut raw_hash)
+                                        .map_err(|e| e.to_string())
+                                        .and_then(|_| {
+                                            blockchain
+                                                .find_transaction(&H256::from(raw_hash))
+                                                .map(|(t, _)| t)
+                                                .ok_or_else(|| "transaction not found".to_string())
+                                        })
+                                }
+                                (None, None) => unreachable!(),
+                            };
+                            match transaction {
+                                Ok(t) => {
+                                    let annotated = explorer::annotate(&t, &blockchain);
+                                    respond_result!(req, true, serde_json::to_string(&annotated).unwrap());
+                                }
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error loading transaction: {}", e));
+                                }
+                            }
+                        }
+                        ApiRequest::TransactionEstimateFee { target } => {
+                            match fee_estimator.read().unwrap().estimate_feerate(target) {
+                                Some(fee_rate) => respond_result!(req, true, fee_rate.sat_per_kvb()),
+                                None => respond_result!(req, false, "not enough history to estimate a fee rate"),
+                            }
+                        }
+                        ApiRequest::AdminPools => {
+                            let sizes = serde_json::to_string(&runtime.sizes()).unwrap();
+                            respond_result!(req, true, sizes);
+                        }
+                        ApiRequest::AdminResizePool { name, size } => {
+                            match runtime.resize(&name, size) {
+                                Ok(()) => respond_result!(req, true, "ok"),
+                                Err(e) => respond_result!(req, false, e.to_string()),
+                            }
                         }
                     }
                 });
             }
         });
         info!("API server listening at {}", &addr);
+        return addr;
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::crypto::key_pair;
+
+    #[test]
+    fn export_paths() {
+        assert!(export_path(None, "chain").is_err());
+        let exports = Exports { dir: PathBuf::from("/data/exports"), archive_key: key_pair::random() };
+        assert_eq!(export_path(Some(&exports), "chain").unwrap(), PathBuf::from("/data/exports/chain"));
+        for file in &["", "/etc/passwd", "../chain", "a/b", ".", ".."] {
+            assert!(export_path(Some(&exports), file).is_err(), "{}", file);
+        }
     }
 }
@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use url::Url;
+
+use crate::crypto::hash::H256;
+
+/// A request to the API server. The server parses incoming URLs into this type, and the client
+/// turns it back into a URL, so both sides always agree on paths and parameters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiRequest {
+    MinerStart { lambda: u64 },
+    /// Mine exactly `blocks` blocks, then pause
+    MinerStep { blocks: u64 },
+    MinerPause,
+    MinerStatus,
+    /// Pay the mined blocks to an address, or to a hex-encoded script, with an optional tag in
+    /// the coinbase
+    MinerCoinbase { address: Option<H256>, script: Option<String>, tag: Option<String> },
+    /// Mine `blocks` blocks at once on a regtest chain
+    MinerGenerate { blocks: u64 },
+    /// A block template for an external miner
+    MinerTemplate,
+    /// Submit the hex-encoded header of a block template, solved by an external miner
+    MinerSubmit { header: String },
+    /// Statistics of the miner and the chain, in the Prometheus text format
+    Metrics,
+    NetworkPing,
+    /// The connected peers
+    NetworkPeers,
+    /// Drop the connection with a peer by its id
+    NetworkDisconnect { id: u64 },
+    /// The bytes exchanged with the peers, and the state of the upload target
+    NetworkTraffic,
+    BlockchainHeaders { from: u32, to: u32 },
+    /// Write a signed archive of the chain state to the file `file` of the export directory
+    BlockchainExportArchive { file: String },
+    /// All known chain tips, like `getchaintips`
+    BlockchainTips,
+    /// Write the UTXO set to the file `file` of the export directory
+    BlockchainExportUtxoSnapshot { file: String },
+    /// Write the blocks of the longest chain to the bootstrap file `file` of the export directory
+    BlockchainExportBootstrap { file: String },
+    /// Statistics about the chain, with block times averaged over the last `window` blocks
+    BlockchainStats { window: u32 },
+    /// Mark a block and its descendants invalid
+    BlockchainInvalidateBlock { hash: H256 },
+    /// Undo `BlockchainInvalidateBlock`
+    BlockchainReconsiderBlock { hash: H256 },
+    /// Annotate a confirmed transaction by its id, or a raw hex-encoded transaction
+    TransactionAnnotate { txid: Option<String>, raw: Option<String> },
+    /// The fee rate for a transaction to be mined within `target` blocks, in sat/kvB
+    TransactionEstimateFee { target: usize },
+    AdminPools,
+    AdminResizePool { name: String, size: usize },
+}
+
+/// Reasons for a URL not to be a valid request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    NotFound,
+    MissingParam(&'static str),
+    InvalidParam(&'static str, String),
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseError::NotFound => write!(f, "endpoint not found"),
+            ParseError::MissingParam(name) => write!(f, "missing {}", name),
+            ParseError::InvalidParam(name, e) => write!(f, "error parsing {}: {}", name, e),
+        }
+    }
+}
+
+fn param<T>(params: &HashMap<String, String>, name: &'static str) -> Result<T, ParseError>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value = params.get(name).ok_or(ParseError::MissingParam(name))?;
+    return value
+        .parse::<T>()
+        .map_err(|e| ParseError::InvalidParam(name, e.to_string()));
+}
+
+impl ApiRequest {
+    /// Parse a request from the path and query of a URL
+    pub fn from_url(url: &Url) -> Result<Self, ParseError> {
+        let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        let request = match url.path() {
+            "/miner/start" => ApiRequest::MinerStart {
+                lambda: param(&params, "lambda")?,
+            },
+            "/miner/step" => ApiRequest::MinerStep {
+                blocks: param(&params, "blocks")?,
+            },
+            "/miner/pause" => ApiRequest::MinerPause,
+            "/miner/status" => ApiRequest::MinerStatus,
+            "/miner/coinbase" => {
+                let address = match params.get("address") {
+                    Some(_) => Some(param(&params, "address")?),
+                    None => None,
+                };
+                let script = params.get("script").cloned();
+                if address.is_some() == script.is_some() {
+                    return Err(ParseError::MissingParam("address or script"));
+                }
+                ApiRequest::MinerCoinbase { address, script, tag: params.get("tag").cloned() }
+            }
+            "/miner/generate" => ApiRequest::MinerGenerate {
+                blocks: param(&params, "blocks")?,
+            },
+            "/miner/template" => ApiRequest::MinerTemplate,
+            "/miner/submit" => ApiRequest::MinerSubmit {
+                header: param(&params, "header")?,
+            },
+            "/metrics" => ApiRequest::Metrics,
+            "/network/ping" => ApiRequest::NetworkPing,
+            "/network/peers" => ApiRequest::NetworkPeers,
+            "/network/disconnect" => ApiRequest::NetworkDisconnect {
+                id: param(&params, "id")?,
+            },
+            "/network/traffic" => ApiRequest::NetworkTraffic,
+            "/blockchain/headers" => ApiRequest::BlockchainHeaders {
+                from: param(&params, "from")?,
+                to: param(&params, "to")?,
+            },
+            "/blockchain/export-archive" => ApiRequest::BlockchainExportArchive {
+                file: param(&params, "file")?,
+            },
+            "/blockchain/tips" => ApiRequest::BlockchainTips,
+            "/blockchain/export-utxo-snapshot" => ApiRequest::BlockchainExportUtxoSnapshot {
+                file: param(&params, "file")?,
+            },
+            "/blockchain/export-bootstrap" => ApiRequest::BlockchainExportBootstrap {
+                file: param(&params, "file")?,
+            },
+            "/blockchain/stats" => ApiRequest::BlockchainStats {
+                window: param(&params, "window")?,
+            },
+            "/blockchain/invalidate-block" => ApiRequest::BlockchainInvalidateBlock {
+                hash: param(&params, "hash")?,
+            },
+            "/blockchain/reconsider-block" => ApiRequest::BlockchainReconsiderBlock {
+                hash: param(&params, "hash")?,
+            },
+            "/transaction/annotate" => {
+                let txid = params.get("txid").cloned();
+                let raw = params.get("raw").cloned();
+                if txid.is_none() && raw.is_none() {
+                    return Err(ParseError::MissingParam("txid or raw"));
+                }
+                ApiRequest::TransactionAnnotate { txid, raw }
+            }
+            "/transaction/estimate-fee" => ApiRequest::TransactionEstimateFee {
+                target: param(&params, "target")?,
+            },
+            "/admin/pools" => ApiRequest::AdminPools,
+            "/admin/pool/resize" => ApiRequest::AdminResizePool {
+                name: param(&params, "name")?,
+                size: param(&params, "size")?,
+            },
+            _ => return Err(ParseError::NotFound),
+        };
+        return Ok(request);
+    }
+
+    /// The path and query of the URL of this request
+    pub fn to_path_and_query(&self) -> String {
+        let (path, params): (&str, Vec<(&str, String)>) = match self {
+            ApiRequest::MinerStart { lambda } => ("/miner/start", vec![("lambda", lambda.to_string())]),
+            ApiRequest::MinerStep { blocks } => ("/miner/step", vec![("blocks", blocks.to_string())]),
+            ApiRequest::MinerPause => ("/miner/pause", vec![]),
+            ApiRequest::MinerStatus => ("/miner/status", vec![]),
+            ApiRequest::MinerCoinbase { address, script, tag } => {
+                let mut params = Vec::new();
+                if let Some(address) = address {
+                    params.push(("address", address.to_string()));
+                }
+                if let Some(script) = script {
+                    params.push(("script", script.clone()));
+                }
+                if let Some(tag) = tag {
+                    params.push(("tag", tag.clone()));
+                }
+                ("/miner/coinbase", params)
+            }
+            ApiRequest::MinerGenerate { blocks } => ("/miner/generate", vec![("blocks", blocks.to_string())]),
+            ApiRequest::MinerTemplate => ("/miner/template", vec![]),
+            ApiRequest::MinerSubmit { header } => ("/miner/submit", vec![("header", header.clone())]),
+            ApiRequest::Metrics => ("/metrics", vec![]),
+            ApiRequest::NetworkPing => ("/network/ping", vec![]),
+            ApiRequest::NetworkPeers => ("/network/peers", vec![]),
+            ApiRequest::NetworkDisconnect { id } => ("/network/disconnect", vec![("id", id.to_string())]),
+            ApiRequest::NetworkTraffic => ("/network/traffic", vec![]),
+            ApiRequest::BlockchainHeaders { from, to } => (
+                "/blockchain/headers",
+                vec![("from", from.to_string()), ("to", to.to_string())],
+            ),
+            ApiRequest::BlockchainExportArchive { file } => {
+                ("/blockchain/export-archive", vec![("file", file.clone())])
+            }
+            ApiRequest::BlockchainTips => ("/blockchain/tips", vec![]),
+            ApiRequest::BlockchainExportUtxoSnapshot { file } => {
+                ("/blockchain/export-utxo-snapshot", vec![("file", file.clone())])
+            }
+            ApiRequest::BlockchainExportBootstrap { file } => {
+                ("/blockchain/export-bootstrap", vec![("file", file.clone())])
+            }
+            ApiRequest::BlockchainStats { window } => ("/blockchain/stats", vec![("window", window.to_string())]),
+            ApiRequest::BlockchainInvalidateBlock { hash } => {
+                ("/blockchain/invalidate-block", vec![("hash", hash.to_string())])
+            }
+            ApiRequest::BlockchainReconsiderBlock { hash } => {
+                ("/blockchain/reconsider-block", vec![("hash", hash.to_string())])
+            }
+            ApiRequest::TransactionAnnotate { txid, raw } => {
+                let mut params = Vec::new();
+                if let Some(txid) = txid {
+                    params.push(("txid", txid.clone()));
+                }
+                if let Some(raw) = raw {
+                    params.push(("raw", raw.clone()));
+                }
+                ("/transaction/annotate", params)
+            }
+            ApiRequest::TransactionEstimateFee { target } => {
+                ("/transaction/estimate-fee", vec![("target", target.to_string())])
+            }
+            ApiRequest::AdminPools => ("/admin/pools", vec![]),
+            ApiRequest::AdminResizePool { name, size } => (
+                "/admin/pool/resize",
+                vec![("name", name.clone()), ("size", size.to_string())],
+            ),
+        };
+        let mut url = Url::parse("http://localhost").unwrap();
+        url.set_path(path);
+        if !params.is_empty() {
+            url.query_pairs_mut().extend_pairs(params);
+        }
+        return url[url::Position::BeforePath..].to_string();
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let requests = vec![
+            ApiRequest::MinerStart { lambda: 42 },
+            ApiRequest::MinerStep { blocks: 3 },
+            ApiRequest::MinerPause,
+            ApiRequest::MinerStatus,
+            ApiRequest::MinerCoinbase { address: Some(H256::from([5u8; 32])), script: None, tag: Some("/node 1/".to_string()) },
+            ApiRequest::MinerCoinbase { address: None, script: Some("00ab".to_string()), tag: None },
+            ApiRequest::MinerGenerate { blocks: 101 },
+            ApiRequest::MinerTemplate,
+            ApiRequest::MinerSubmit { header: "00ff".to_string() },
+            ApiRequest::Metrics,
+            ApiRequest::NetworkPing,
+            ApiRequest::NetworkPeers,
+            ApiRequest::NetworkDisconnect { id: 12 },
+            ApiRequest::NetworkTraffic,
+            ApiRequest::BlockchainHeaders { from: 3, to: 7 },
+            ApiRequest::BlockchainExportArchive { file: "a b&c".to_string() },
+            ApiRequest::BlockchainTips,
+            ApiRequest::BlockchainExportUtxoSnapshot { file: "utxo".to_string() },
+            ApiRequest::BlockchainExportBootstrap { file: "bootstrap".to_string() },
+            ApiRequest::BlockchainStats { window: 20 },
+            ApiRequest::BlockchainInvalidateBlock { hash: H256::from([3u8; 32]) },
+            ApiRequest::BlockchainReconsiderBlock { hash: H256::from([4u8; 32]) },
+            ApiRequest::TransactionAnnotate { txid: Some("ab".to_string()), raw: None },
+            ApiRequest::TransactionEstimateFee { target: 6 },
+            ApiRequest::AdminPools,
+            ApiRequest::AdminResizePool { name: "network".to_string(), size: 8 },
+        ];
+        let base = Url::parse("http://127.0.0.1:7000/").unwrap();
+        for request in requests {
+            let url = base.join(&request.to_path_and_query()).unwrap();
+            assert_eq!(ApiRequest::from_url(&url), Ok(request));
+        }
+        let url = base.join("/miner/start?lambda=x").unwrap();
+        assert!(matches!(ApiRequest::from_url(&url), Err(ParseError::InvalidParam("lambda", _))));
+        let url = base.join("/miner/coinbase?tag=x").unwrap();
+        assert_eq!(ApiRequest::from_url(&url), Err(ParseError::MissingParam("address or script")));
+        let url = base.join("/nothing").unwrap();
+        assert_eq!(ApiRequest::from_url(&url), Err(ParseError::NotFound));
+    }
+}
@@ -0,0 +1,248 @@
+use serde::{Serialize, Deserialize};
+use ring::digest::{SHA256, digest};
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::block::{Block, Header};
+use crate::blockchain::{Blockchain, InsertError};
+use crate::bootstrap::{write_record, RecordReader};
+use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::merkle::MerkleTree;
+use crate::validation::ValidationError;
+
+/// A signed, self-verifying export of the state of the longest chain
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChainArchive {
+    headers: Vec<Header>,
+    blocks: Vec<Block>,
+    /// Merkle root of the header hashes
+    headers_root: H256,
+    /// Merkle root of the block hashes
+    state_root: H256,
+    public_key: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+/// Reasons for an archive to be rejected
+#[derive(Debug)]
+pub enum ArchiveError {
+    Io(std::io::Error),
+    Encoding(bincode::Error),
+    Empty,
+    BrokenChain,
+    CommitmentMismatch,
+    BadSignature,
+    UntrustedSigner,
+    /// The archive starts from another genesis block
+    GenesisMismatch(H256),
+    /// A block of the archive failed validation
+    Invalid(H256, ValidationError),
+    /// A block of the archive could not be inserted
+    Insert(H256, InsertError),
+}
+
+impl std::fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ArchiveError::Io(e) => write!(f, "i/o error: {}", e),
+            ArchiveError::Encoding(e) => write!(f, "encoding error: {}", e),
+            ArchiveError::Empty => write!(f, "archive contains no blocks"),
+            ArchiveError::BrokenChain => write!(f, "headers and blocks do not form a chain"),
+            ArchiveError::CommitmentMismatch => write!(f, "commitments do not match the content"),
+            ArchiveError::BadSignature => write!(f, "invalid signature"),
+            ArchiveError::UntrustedSigner => write!(f, "archive signed by an untrusted key"),
+            ArchiveError::GenesisMismatch(hash) => write!(f, "unexpected genesis block {}", hash),
+            ArchiveError::Invalid(hash, e) => write!(f, "invalid block {}: {}", hash, e),
+            ArchiveError::Insert(hash, e) => write!(f, "error inserting block {}: {}", hash, e),
+        }
+    }
+}
+
+impl From<std::io::Error> for ArchiveError {
+    fn from(e: std::io::Error) -> Self {
+        ArchiveError::Io(e)
+    }
+}
+
+impl From<bincode::Error> for ArchiveError {
+    fn from(e: bincode::Error) -> Self {
+        ArchiveError::Encoding(e)
+    }
+}
+
+fn merkle_root(hashes: &[H256]) -> H256 {
+    if hashes.is_empty() {
+        return H256::default();
+    }
+    return MerkleTree::new(hashes).root();
+}
+
+/// The message covered by the signature: both commitments
+fn signed_message(headers_root: &H256, state_root: &H256) -> H256 {
+    return digest(&SHA256, &[headers_root.as_ref(), state_root.as_ref()].concat()).into();
+}
+
+impl ChainArchive {
+    /// Export the longest chain of `blockchain`, signed with `key`
+    pub fn create(blockchain: &Blockchain, key: &Ed25519KeyPair) -> Self {
+        let headers = blockchain.headers_in_range(0, blockchain.tip_height());
+        let blocks: Vec<Block> = headers.iter().map(|h| blockchain.get(&h.hash())).collect();
+        let header_hashes: Vec<H256> = headers.iter().map(|h| h.hash()).collect();
+        let block_hashes: Vec<H256> = blocks.iter().map(|b| b.hash()).collect();
+        let headers_root = merkle_root(&header_hashes);
+        let state_root = merkle_root(&block_hashes);
+        let signature = key.sign(signed_message(&headers_root, &state_root).as_ref());
+        return ChainArchive {
+            headers,
+            blocks,
+            headers_root,
+            state_root,
+            public_key: key.public_key().as_ref().to_vec(),
+            signature: signature.as_ref().to_vec(),
+        };
+    }
+
+    /// Check that the content forms a chain, matches the commitments, and is signed by the
+    /// embedded key. If `trusted_key` is given, the archive must be signed by that key.
+    pub fn verify(&self, trusted_key: Option<&[u8]>) -> Result<(), ArchiveError> {
+        if self.blocks.is_empty() || self.blocks.len() != self.headers.len() {
+            return Err(ArchiveError::Empty);
+        }
+        for i in 0..self.headers.len() {
+            if self.headers[i].hash() != self.blocks[i].hash() {
+                return Err(ArchiveError::BrokenChain);
+            }
+            if i > 0 && self.headers[i].get_parent() != self.headers[i - 1].hash() {
+                return Err(ArchiveError::BrokenChain);
+            }
+        }
+        let header_hashes: Vec<H256> = self.headers.iter().map(|h| h.hash()).collect();
+        let block_hashes: Vec<H256> = self.blocks.iter().map(|b| b.hash()).collect();
+        if merkle_root(&header_hashes) != self.headers_root || merkle_root(&block_hashes) != self.state_root {
+            return Err(ArchiveError::CommitmentMismatch);
+        }
+        if let Some(key) = trusted_key {
+            if key != &self.public_key[..] {
+                return Err(ArchiveError::UntrustedSigner);
+            }
+        }
+        let public_key = UnparsedPublicKey::new(&ED25519, &self.public_key);
+        let message = signed_message(&self.headers_root, &self.state_root);
+        if public_key.verify(message.as_ref(), &self.signature).is_err() {
+            return Err(ArchiveError::BadSignature);
+        }
+        return Ok(());
+    }
+
+    /// Verify the archive and build a blockchain from its content. The archive must start from
+    /// the genesis block, and every other block is validated like a block received from a peer:
+    /// the signature only tells who made the archive.
+    pub fn import(&self, trusted_key: Option<&[u8]>) -> Result<Blockchain, ArchiveError> {
+        self.verify(trusted_key)?;
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = self.blocks[0].hash();
+        if genesis_hash != blockchain.tip() {
+            return Err(ArchiveError::GenesisMismatch(genesis_hash));
+        }
+        for block in &self.blocks[1..] {
+            let hash = block.hash();
+            blockchain.validate(block).map_err(|e| ArchiveError::Invalid(hash, e))?;
+            blockchain.insert(block).map_err(|e| ArchiveError::Insert(hash, e))?;
+        }
+        return Ok(blockchain);
+    }
+
+    /// Write the archive in the format of bootstrap files, preceded by a record of the signed
+    /// commitments. The headers are those of the blocks, so they are not written.
+    pub fn save(&self, path: &Path) -> Result<(), ArchiveError> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let signed = (&self.headers_root, &self.state_root, &self.public_key, &self.signature);
+        write_record(&mut file, &bincode::serialize(&signed)?)?;
+        for block in &self.blocks {
+            write_record(&mut file, &bincode::serialize(block)?)?;
+        }
+        file.flush()?;
+        return Ok(());
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ArchiveError> {
+        let mut records = RecordReader::new(BufReader::new(File::open(path)?));
+        let signed = records.next().ok_or(ArchiveError::Empty)??;
+        let (headers_root, state_root, public_key, signature) = bincode::deserialize(&signed)?;
+        let mut blocks: Vec<Block> = Vec::new();
+        for record in records {
+            blocks.push(bincode::deserialize(&record?)?);
+        }
+        let headers = blocks.iter().map(|b| b.get_header().clone()).collect();
+        return Ok(ChainArchive {
+            headers,
+            blocks,
+            headers_root,
+            state_root,
+            public_key,
+            signature,
+        });
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block::test::{generate_mined_block_with_height, generate_random_block};
+    use crate::crypto::key_pair;
+
+    #[test]
+    fn export_import() {
+        let mut blockchain = Blockchain::new();
+        let block1 = generate_mined_block_with_height(&blockchain.tip(), 1);
+        blockchain.insert(&block1).unwrap();
+        let block2 = generate_mined_block_with_height(&block1.hash(), 2);
+        blockchain.insert(&block2).unwrap();
+        let key = key_pair::random();
+        let archive = ChainArchive::create(&blockchain, &key);
+        assert!(archive.verify(Some(key.public_key().as_ref())).is_ok());
+        let imported = archive.import(None).unwrap();
+        assert_eq!(imported.tip(), blockchain.tip());
+        assert_eq!(imported.num_blocks(), 3);
+
+        // saved archives load back
+        let path = std::env::temp_dir().join(format!("archive_{}", rand::random::<u32>()));
+        archive.save(&path).unwrap();
+        let loaded = ChainArchive::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.verify(Some(key.public_key().as_ref())).is_ok());
+        assert_eq!(loaded.import(None).unwrap().tip(), blockchain.tip());
+
+        let other_key = key_pair::random();
+        match archive.verify(Some(other_key.public_key().as_ref())) {
+            Err(ArchiveError::UntrustedSigner) => {}
+            _ => panic!("archive accepted from untrusted signer"),
+        }
+        let mut tampered = archive.clone();
+        tampered.blocks.pop();
+        tampered.headers.pop();
+        match tampered.verify(None) {
+            Err(ArchiveError::CommitmentMismatch) => {}
+            _ => panic!("tampered archive accepted"),
+        }
+
+        // a correctly signed archive is still refused if a block is invalid
+        let invalid = generate_random_block(&block2.hash());
+        blockchain.insert(&invalid).unwrap();
+        let archive = ChainArchive::create(&blockchain, &key);
+        assert!(archive.verify(Some(key.public_key().as_ref())).is_ok());
+        match archive.import(None) {
+            Err(ArchiveError::Invalid(hash, _)) => assert_eq!(hash, invalid.hash()),
+            _ => panic!("archive with an invalid block imported"),
+        }
+
+        // and so is an archive of another chain
+        let other = Blockchain::with_genesis(generate_random_block(&H256::default()));
+        match ChainArchive::create(&other, &key).import(None) {
+            Err(ArchiveError::GenesisMismatch(hash)) => assert_eq!(hash, other.tip()),
+            _ => panic!("archive of another chain imported"),
+        }
+    }
+}
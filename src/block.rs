@@ -6,7 +6,8 @@ use std::time::{SystemTime};
 use rand::Rng;
 
 use crate::crypto::hash::{H256, Hashable};
-use crate::transaction::{Transaction};
+use crate::transaction::{Transaction, TxOutput};
+use crate::crypto::merkle::{MerkleTree, MerkleProof};
 
 /// A block in the blockchain
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -16,20 +17,23 @@ pub struct Block {
 }
 
 impl Block {
+    /// A block with a random nonce, timestamped now
     pub fn new(parent: H256, difficulty: H256, transactions: Vec<Transaction>, merkle_root: H256) -> Self {
         let mut rng = rand::thread_rng();
         let nonce: u32 = rng.gen();
         let timestamp = SystemTime::now();
+        return Block::new_at(parent, difficulty, transactions, merkle_root, nonce, timestamp);
+    }
+
+    /// A block with the given nonce and timestamp, so that the same block can be built again
+    pub fn new_at(
+        parent: H256, difficulty: H256, transactions: Vec<Transaction>, merkle_root: H256, nonce: u32, timestamp: SystemTime
+    ) -> Self {
         let block: Block = Block {
-            header: Header {
-                parent,
-                nonce,
-                difficulty,
-                timestamp,
-            },
+            header: Header::new(parent, difficulty, merkle_root, nonce, timestamp),
             content: Content {
                 transactions,
-                merkle_root,
+                pruned: false,
             },
         };
         return block;
@@ -42,6 +46,145 @@ impl Block {
     pub fn get_difficulty(&self) -> H256 {
         return self.header.difficulty;
     }
+
+    pub fn get_header(&self) -> &Header {
+        return &self.header;
+    }
+
+    pub fn get_timestamp(&self) -> SystemTime {
+        return self.header.timestamp;
+    }
+
+    pub fn get_nonce(&self) -> u32 {
+        return self.header.nonce;
+    }
+
+    /// Change the nonce of the header, changing the hash of the block
+    pub fn set_nonce(&mut self, nonce: u32) {
+        self.header.nonce = nonce;
+    }
+
+    /// Change the timestamp of the header, changing the hash of the block
+    pub fn set_timestamp(&mut self, timestamp: SystemTime) {
+        self.header.timestamp = timestamp;
+    }
+
+    pub fn get_transactions(&self) -> &[Transaction] {
+        return &self.content.transactions;
+    }
+
+    /// Total weight of the transactions, see `validation::MAX_BLOCK_WEIGHT`
+    pub fn weight(&self) -> usize {
+        return self.content.transactions.iter().map(|t| t.weight()).sum();
+    }
+
+    /// Copy of the block with another header, e.g. one solved by an external miner
+    pub fn with_header(&self, header: Header) -> Block {
+        return Block {
+            header,
+            content: self.content.clone(),
+        };
+    }
+
+    /// Build a proof of inclusion of the transaction `txid`, checkable against the merkle root in
+    /// the header. Returns `None` if the transaction is not in the block.
+    pub fn merkle_proof(&self, txid: &H256) -> Option<MerkleProof> {
+        let transactions = &self.content.transactions;
+        let index = transactions.iter().position(|t| &t.hash() == txid)?;
+        let merkle_tree = MerkleTree::new(transactions);
+        return Some(MerkleProof::new(&merkle_tree, index, transactions.len()));
+    }
+
+    /// A pruned block with the given header
+    pub fn from_header(header: Header) -> Block {
+        return Block {
+            header,
+            content: Content {
+                transactions: Vec::new(),
+                pruned: true,
+            },
+        };
+    }
+
+    /// Copy of the block without its transactions, as kept by pruned nodes
+    pub fn pruned(&self) -> Block {
+        return Block {
+            header: self.header.clone(),
+            content: Content {
+                transactions: Vec::new(),
+                pruned: true,
+            },
+        };
+    }
+
+    /// Whether the transactions of the block were discarded
+    pub fn is_pruned(&self) -> bool {
+        return self.content.pruned;
+    }
+
+    /// Commit to the witness data of the block's transactions in an output of its coinbase,
+    /// replacing any previous commitment, and update the merkle root accordingly
+    pub fn commit_witnesses(&mut self) {
+        let commitment = witness_commitment(&self.content.transactions);
+        let coinbase = match self.content.transactions.first_mut() {
+            Some(coinbase) if coinbase.is_coinbase() => coinbase,
+            _ => return,
+        };
+        let mut outputs: Vec<TxOutput> = coinbase.get_outputs().iter().filter(|o| commitment_of(o).is_none()).cloned().collect();
+        outputs.push(witness_commitment_output(&commitment));
+        coinbase.set_outputs(outputs);
+        self.header.merkle_root = MerkleTree::new(&self.content.transactions).root();
+    }
+
+    /// The witness commitment of the block: the last commitment output of its coinbase
+    pub fn get_witness_commitment(&self) -> Option<H256> {
+        let coinbase = self.content.transactions.first().filter(|t| t.is_coinbase())?;
+        return coinbase.get_outputs().iter().rev().find_map(commitment_of);
+    }
+
+    /// Recompute the witness commitment and check it against the one in the coinbase. Blocks
+    /// without a witness commitment are valid if none of their transactions has a witness.
+    pub fn verify_witness_commitment(&self) -> bool {
+        return match self.get_witness_commitment() {
+            Some(commitment) => commitment == witness_commitment(&self.content.transactions),
+            None => !self.content.transactions.iter().any(|t| t.has_witness()),
+        };
+    }
+}
+
+/// Prefix of the data of the coinbase output carrying the witness commitment, as in Bitcoin
+pub const WITNESS_COMMITMENT_HEADER: [u8; 4] = [0xaa, 0x21, 0xa9, 0xed];
+
+/// The coinbase output carrying a witness commitment, see `witness_commitment`. It is a data
+/// output, so that the commitment is covered by the merkle root of the block.
+pub fn witness_commitment_output(commitment: &H256) -> TxOutput {
+    return TxOutput::data(&[&WITNESS_COMMITMENT_HEADER[..], commitment.as_ref()].concat());
+}
+
+/// The witness commitment carried by `output`, if it is a `witness_commitment_output`
+fn commitment_of(output: &TxOutput) -> Option<H256> {
+    let payload = output.script_pubkey.null_data_payload().filter(|_| output.is_unspendable())?;
+    if payload.len() != 36 || payload[..4] != WITNESS_COMMITMENT_HEADER {
+        return None;
+    }
+    let mut commitment = [0u8; 32];
+    commitment.copy_from_slice(&payload[4..]);
+    return Some(H256::from(commitment));
+}
+
+/// Compute the witness commitment of a list of transactions, which is the hash of the Merkle root
+/// of their wtxids. As in Bitcoin, the wtxid of the first (coinbase) transaction is taken as zero.
+pub fn witness_commitment(transactions: &[Transaction]) -> H256 {
+    if transactions.is_empty() {
+        return H256::default();
+    }
+    let mut wtxids: Vec<H256> = Vec::new();
+    wtxids.push(H256::default());
+    for transaction in &transactions[1..] {
+        wtxids.push(transaction.wtxid());
+    }
+    let merkle_tree = MerkleTree::new(&wtxids);
+    return merkle_tree.root().hash();
 }
 
 impl Hashable for Block {
@@ -50,6 +193,12 @@ impl Hashable for Block {
     }
 }
 
+/// Verify that `tx` is included in the block with the given header, using a proof obtained from
+/// `Block::merkle_proof`
+pub fn verify_tx_in_block(header: &Header, tx: &Transaction, proof: &MerkleProof) -> bool {
+    return proof.verify(&header.get_merkle_root(), &tx.hash());
+}
+
 /// The header of a block
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Header {
@@ -57,6 +206,44 @@ pub struct Header {
     nonce: u32,
     difficulty: H256,
     timestamp: SystemTime,
+    merkle_root: H256,
+}
+
+impl Header {
+    pub fn new(parent: H256, difficulty: H256, merkle_root: H256, nonce: u32, timestamp: SystemTime) -> Self {
+        return Header {
+            parent,
+            nonce,
+            difficulty,
+            timestamp,
+            merkle_root,
+        };
+    }
+
+    pub fn get_parent(&self) -> H256 {
+        return self.parent;
+    }
+
+    pub fn get_timestamp(&self) -> SystemTime {
+        return self.timestamp;
+    }
+
+    pub fn get_merkle_root(&self) -> H256 {
+        return self.merkle_root;
+    }
+
+    pub fn get_difficulty(&self) -> H256 {
+        return self.difficulty;
+    }
+
+    pub fn get_nonce(&self) -> u32 {
+        return self.nonce;
+    }
+
+    /// Change the nonce, changing the hash of the header
+    pub fn set_nonce(&mut self, nonce: u32) {
+        self.nonce = nonce;
+    }
 }
 
 impl Hashable for Header {
@@ -72,7 +259,9 @@ impl Hashable for Header {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Content {
     transactions: Vec<Transaction>,
-    merkle_root: H256,
+    /// The transactions were discarded to save space. Only meaningful for blocks of the local
+    /// store: received blocks with it are rejected by `validation::check_stateless`
+    pruned: bool,
 }
 
 #[cfg(any(test, test_utilities))]
@@ -81,11 +270,19 @@ pub mod test {
     use crate::crypto::hash::H256;
     use crate::crypto::merkle::{MerkleTree};
     use crate::blockchain::Blockchain;
+    use crate::transaction::BLOCK_REWARD;
+    use crate::transaction::tests::generate_random_transaction;
 
     pub fn generate_random_block(parent: &H256) -> Block {
+        return generate_random_block_with_height(parent, 0);
+    }
+
+    /// Generate a random block whose coinbase commits to `height`, as validation requires
+    pub fn generate_random_block_with_height(parent: &H256, height: u32) -> Block {
         let difficulty: H256 = Blockchain::get_difficulty().into();
         let mut transactions: Vec<Transaction> = Vec::new();
-        let transaction = Transaction::new("rand in".to_string(), "rand_out".to_string());
+        let recipient = H256::from(rand::thread_rng().gen::<[u8; 32]>());
+        let transaction = Transaction::coinbase(height, recipient, BLOCK_REWARD);
         transactions.push(transaction);
         let merkle_tree = MerkleTree::new(&transactions);
         let merkle_root = merkle_tree.root();
@@ -93,4 +290,77 @@ pub mod test {
         let block: Block = Block::new(parent.clone(), difficulty, transactions, merkle_root);
         return block;
     }
+
+    /// Generate random blocks until one meets its difficulty target
+    pub fn generate_mined_block(parent: &H256) -> Block {
+        return generate_mined_block_with_height(parent, 0);
+    }
+
+    /// `generate_mined_block`, with a coinbase committing to `height`
+    pub fn generate_mined_block_with_height(parent: &H256, height: u32) -> Block {
+        loop {
+            let block = generate_random_block_with_height(parent, height);
+            if block.hash() <= block.get_difficulty() {
+                return block;
+            }
+        }
+    }
+
+    pub fn generate_random_block_at(parent: &H256, timestamp: SystemTime) -> Block {
+        let mut block = generate_random_block(parent);
+        block.header.timestamp = timestamp;
+        return block;
+    }
+
+    #[test]
+    fn merkle_proof() {
+        let parent = H256::default();
+        let difficulty: H256 = Blockchain::get_difficulty();
+        let transactions: Vec<Transaction> = (0..5)
+            .map(|_| generate_random_transaction())
+            .collect();
+        let merkle_root = MerkleTree::new(&transactions).root();
+        let block = Block::new(parent, difficulty, transactions.clone(), merkle_root);
+        for transaction in &transactions {
+            let proof = block.merkle_proof(&transaction.hash()).unwrap();
+            assert!(proof.verify(&block.get_header().get_merkle_root(), &transaction.hash()));
+        }
+        let absent = generate_random_transaction();
+        assert!(block.merkle_proof(&absent.hash()).is_none());
+    }
+
+    #[test]
+    fn tx_in_block() {
+        let block = generate_random_block(&H256::default());
+        let transaction = block.get_transactions()[0].clone();
+        let proof = block.merkle_proof(&transaction.hash()).unwrap();
+        assert!(verify_tx_in_block(block.get_header(), &transaction, &proof));
+        let other = generate_random_transaction();
+        assert!(!verify_tx_in_block(block.get_header(), &other, &proof));
+    }
+
+    #[test]
+    fn witness_commitment() {
+        let mut block = generate_random_block(&H256::default());
+        assert_eq!(block.get_witness_commitment(), None);
+        assert!(block.verify_witness_commitment());
+        block.commit_witnesses();
+        assert!(block.get_witness_commitment().is_some());
+        assert!(block.verify_witness_commitment());
+        // the commitment is in the coinbase, so the merkle root covers it
+        assert_eq!(MerkleTree::new(block.get_transactions()).root(), block.get_header().get_merkle_root());
+        block.content.transactions.push(generate_random_transaction());
+        assert!(!block.verify_witness_commitment());
+
+        // witnesses must be committed to, and committing again replaces the commitment
+        let mut witnessed = generate_random_transaction();
+        witnessed.set_witness(0, vec![vec![1]]);
+        block.content.transactions.push(witnessed);
+        assert!(!block.verify_witness_commitment());
+        block.commit_witnesses();
+        assert!(block.verify_witness_commitment());
+        assert_eq!(block.get_transactions()[0].get_outputs().len(), 2);
+        block.content.transactions.pop();
+        assert!(!block.verify_witness_commitment());
+    }
 }
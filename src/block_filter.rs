@@ -0,0 +1,387 @@
+//! Compact block filters, after BIP157 and BIP158: each block gets a Golomb-coded set of the
+//! outputs it creates and spends, which light clients download and match against their own
+//! addresses without telling anyone which these are. Filters are chained by filter headers, so
+//! that a client can check the filters of several peers against each other.
+
+use ring::digest::{Context as DigestContext, SHA256};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+
+use crate::block::Block;
+use crate::crypto::hash::{H256, Hashable};
+use crate::encoding::{self, DecodeError, Reader};
+use crate::transaction::TxOutput;
+use crate::utxo::UndoData;
+
+/// Number of low bits of each delta written verbatim by the Golomb-Rice coding
+pub const FILTER_P: u8 = 19;
+/// Inverse of the false positive rate of the filters
+pub const FILTER_M: u64 = 784_931;
+/// Most filters sent in answer to one `GetCFilters`
+pub const MAX_CFILTERS: u32 = 1000;
+/// Most filter hashes sent in answer to one `GetCFHeaders`
+pub const MAX_CFHEADERS: u32 = 2000;
+
+/// SipHash-2-4 of `data` with the key `(k0, k1)`
+pub fn siphash(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v0: u64 = 0x736f_6d65_7073_6575 ^ k0;
+    let mut v1: u64 = 0x646f_7261_6e64_6f6d ^ k1;
+    let mut v2: u64 = 0x6c79_6765_6e65_7261 ^ k0;
+    let mut v3: u64 = 0x7465_6462_7974_6573 ^ k1;
+    let round = |v0: &mut u64, v1: &mut u64, v2: &mut u64, v3: &mut u64| {
+        *v0 = v0.wrapping_add(*v1);
+        *v1 = v1.rotate_left(13) ^ *v0;
+        *v0 = v0.rotate_left(32);
+        *v2 = v2.wrapping_add(*v3);
+        *v3 = v3.rotate_left(16) ^ *v2;
+        *v0 = v0.wrapping_add(*v3);
+        *v3 = v3.rotate_left(21) ^ *v0;
+        *v2 = v2.wrapping_add(*v1);
+        *v1 = v1.rotate_left(17) ^ *v2;
+        *v2 = v2.rotate_left(32);
+    };
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(chunk);
+        let m = u64::from_le_bytes(bytes);
+        v3 ^= m;
+        round(&mut v0, &mut v1, &mut v2, &mut v3);
+        round(&mut v0, &mut v1, &mut v2, &mut v3);
+        v0 ^= m;
+    }
+    let mut last = [0u8; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    last[7] = data.len() as u8;
+    let m = u64::from_le_bytes(last);
+    v3 ^= m;
+    round(&mut v0, &mut v1, &mut v2, &mut v3);
+    round(&mut v0, &mut v1, &mut v2, &mut v3);
+    v0 ^= m;
+    v2 ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v0, &mut v1, &mut v2, &mut v3);
+    }
+    return v0 ^ v1 ^ v2 ^ v3;
+}
+
+/// The element a filter holds for an output: its locking script, or its recipient for outputs
+/// without one
+pub fn output_element(output: &TxOutput) -> Vec<u8> {
+    if output.script_pubkey.is_empty() {
+        return output.recipient.as_ref().to_vec();
+    }
+    return output.script_pubkey.as_bytes().to_vec();
+}
+
+/// The SipHash key of the filter of a block: the first 16 bytes of its hash
+fn key(block_hash: &H256) -> (u64, u64) {
+    let bytes = block_hash.as_ref();
+    let mut k0 = [0u8; 8];
+    let mut k1 = [0u8; 8];
+    k0.copy_from_slice(&bytes[0..8]);
+    k1.copy_from_slice(&bytes[8..16]);
+    return (u64::from_le_bytes(k0), u64::from_le_bytes(k1));
+}
+
+/// Map an element uniformly to `[0, range)`
+fn hash_to_range(key: (u64, u64), element: &[u8], range: u64) -> u64 {
+    return ((siphash(key.0, key.1, element) as u128 * range as u128) >> 64) as u64;
+}
+
+/// Bits written from the most significant of each byte
+struct BitWriter {
+    bytes: Vec<u8>,
+    used: u8,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> self.used;
+        }
+        self.used = (self.used + 1) % 8;
+    }
+
+    fn write_bits(&mut self, value: u64, n: u8) {
+        for i in (0..n).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.bytes.get(self.position / 8)?;
+        let bit = byte & (0x80 >> (self.position % 8)) != 0;
+        self.position += 1;
+        return Some(bit);
+    }
+
+    fn read_bits(&mut self, n: u8) -> Option<u64> {
+        let mut value: u64 = 0;
+        for _ in 0..n {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        return Some(value);
+    }
+}
+
+/// The Golomb-coded set of the elements of a block, serialized as the number of elements
+/// followed by the Golomb-Rice coded deltas between their sorted hashes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockFilter {
+    data: Vec<u8>,
+}
+
+impl BlockFilter {
+    /// The filter of `elements` for the block `block_hash`
+    pub fn new(block_hash: &H256, elements: &[Vec<u8>]) -> Self {
+        let mut unique: Vec<&Vec<u8>> = elements.iter().filter(|e| !e.is_empty()).collect();
+        unique.sort();
+        unique.dedup();
+        let n = unique.len() as u64;
+        let key = key(block_hash);
+        let mut hashes: Vec<u64> = unique.iter().map(|e| hash_to_range(key, e, n * FILTER_M)).collect();
+        hashes.sort_unstable();
+        let mut data: Vec<u8> = Vec::new();
+        encoding::write_compact_size(&mut data, n);
+        let mut writer = BitWriter { bytes: Vec::new(), used: 0 };
+        let mut last: u64 = 0;
+        for hash in hashes {
+            let delta = hash - last;
+            for _ in 0..(delta >> FILTER_P) {
+                writer.write_bit(true);
+            }
+            writer.write_bit(false);
+            writer.write_bits(delta, FILTER_P);
+            last = hash;
+        }
+        data.extend(writer.bytes);
+        return BlockFilter { data };
+    }
+
+    /// The basic filter of a block: the outputs it creates and those it spends, given by its
+    /// undo data. Data outputs are left out, no wallet looks for them.
+    pub fn build(block: &Block, spent: &UndoData) -> Self {
+        let mut elements: Vec<Vec<u8>> = Vec::new();
+        for transaction in block.get_transactions() {
+            for output in transaction.get_outputs() {
+                if !output.script_pubkey.is_unspendable() {
+                    elements.push(output_element(output));
+                }
+            }
+        }
+        for (_, output) in spent {
+            elements.push(output_element(output));
+        }
+        return BlockFilter::new(&block.hash(), &elements);
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        return BlockFilter { data };
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        return &self.data;
+    }
+
+    /// The sorted hashes of the elements of the filter
+    fn decode(&self) -> Result<(u64, Vec<u64>), DecodeError> {
+        let mut reader = Reader::new(&self.data);
+        let n = reader.read_compact_size()?;
+        let mut bits = BitReader { bytes: reader.remaining(), position: 0 };
+        let mut hashes: Vec<u64> = Vec::new();
+        let mut last: u64 = 0;
+        for _ in 0..n {
+            let mut quotient: u64 = 0;
+            while bits.read_bit().ok_or(DecodeError::Truncated)? {
+                quotient += 1;
+            }
+            let remainder = bits.read_bits(FILTER_P).ok_or(DecodeError::Truncated)?;
+            last = last.saturating_add(quotient.saturating_mul(1 << FILTER_P)).saturating_add(remainder);
+            hashes.push(last);
+        }
+        return Ok((n, hashes));
+    }
+
+    /// Whether any of `elements` may be in the filter of the block `block_hash`. False positives
+    /// happen once in `FILTER_M` elements.
+    pub fn match_any(&self, block_hash: &H256, elements: &[Vec<u8>]) -> bool {
+        let (n, hashes) = match self.decode() {
+            Ok(decoded) => decoded,
+            Err(_) => return false,
+        };
+        let key = key(block_hash);
+        return elements.iter().any(|e| hashes.binary_search(&hash_to_range(key, e, n * FILTER_M)).is_ok());
+    }
+
+    /// The double SHA-256 of the filter
+    pub fn filter_hash(&self) -> H256 {
+        return double_sha256(&[&self.data]);
+    }
+
+    /// The header of the filter, committing to the headers of the filters of all ancestors
+    pub fn header(&self, previous_header: &H256) -> H256 {
+        return double_sha256(&[self.filter_hash().as_ref(), previous_header.as_ref()]);
+    }
+}
+
+fn double_sha256(parts: &[&[u8]]) -> H256 {
+    let mut context = DigestContext::new(&SHA256);
+    for part in parts {
+        context.update(part);
+    }
+    let first = context.finish();
+    return ring::digest::digest(&SHA256, first.as_ref()).into();
+}
+
+/// The filters of the blocks connected to the longest chain, and their headers
+#[derive(Serialize, Deserialize, Default)]
+pub struct FilterIndex {
+    filters: HashMap<H256, BlockFilter>,
+    headers: HashMap<H256, H256>,
+}
+
+impl FilterIndex {
+    pub fn new() -> Self {
+        return FilterIndex::default();
+    }
+
+    /// Build the filter of a block being connected, whose parent's filter is known unless it is
+    /// the genesis block
+    pub fn add(&mut self, block: &Block, spent: &UndoData) {
+        let hash = block.hash();
+        if self.filters.contains_key(&hash) {
+            return;
+        }
+        let filter = BlockFilter::build(block, spent);
+        let previous = self.headers.get(&block.get_parent()).cloned().unwrap_or_default();
+        self.headers.insert(hash, filter.header(&previous));
+        self.filters.insert(hash, filter);
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&BlockFilter> {
+        return self.filters.get(hash);
+    }
+
+    pub fn get_header(&self, hash: &H256) -> Option<H256> {
+        return self.headers.get(hash).cloned();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.filters.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.filters.is_empty();
+    }
+}
+
+/// Request for the filters of the blocks of the longest chain from `start_height` up to the
+/// block `stop_hash`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FilterRequest {
+    pub start_height: u32,
+    pub stop_hash: H256,
+}
+
+/// The filter of a block, answering a `GetCFilters`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CFilter {
+    pub block_hash: H256,
+    pub filter: BlockFilter,
+}
+
+/// The filter hashes of a range of blocks, with the filter header before the first, answering a
+/// `GetCFHeaders`. The client chains them into the filter headers.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CFHeaders {
+    pub stop_hash: H256,
+    pub previous_header: H256,
+    pub filter_hashes: Vec<H256>,
+}
+
+impl CFHeaders {
+    /// The filter headers of the range, the last one being that of `stop_hash`
+    pub fn headers(&self) -> Vec<H256> {
+        let mut previous = self.previous_header;
+        let mut headers: Vec<H256> = Vec::new();
+        for filter_hash in &self.filter_hashes {
+            previous = double_sha256(&[filter_hash.as_ref(), previous.as_ref()]);
+            headers.push(previous);
+        }
+        return headers;
+    }
+}
+
+impl Hashable for CFilter {
+    fn hash(&self) -> H256 {
+        return self.filter.filter_hash();
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+
+    #[test]
+    fn siphash_vectors() {
+        // the reference test vectors of SipHash-2-4, with key 00 01 .. 0f and inputs 00 01 ..
+        let (k0, k1) = (0x0706_0504_0302_0100, 0x0f0e_0d0c_0b0a_0908);
+        let input: Vec<u8> = (0..16).collect();
+        assert_eq!(siphash(k0, k1, &input[..0]), 0x726f_db47_dd0e_0e31);
+        assert_eq!(siphash(k0, k1, &input[..1]), 0x74f8_39c5_93dc_67fd);
+        assert_eq!(siphash(k0, k1, &input[..8]), 0x93f5_f579_9a93_2462);
+        assert_eq!(siphash(k0, k1, &input[..15]), 0xa129_ca61_49be_45e5);
+    }
+
+    #[test]
+    fn golomb_coded_sets() {
+        let block_hash = H256::from([5u8; 32]);
+        let elements: Vec<Vec<u8>> = (0..200u32).map(|i| i.to_le_bytes().to_vec()).collect();
+        let filter = BlockFilter::new(&block_hash, &elements);
+        assert_eq!(filter.decode().unwrap().0, 200);
+        for element in &elements {
+            assert!(filter.match_any(&block_hash, &[element.clone()]));
+        }
+        let others: Vec<Vec<u8>> = (1000..1100u32).map(|i| i.to_le_bytes().to_vec()).collect();
+        assert!(!filter.match_any(&block_hash, &others));
+        // the set is keyed by the block
+        assert!(!filter.match_any(&H256::from([6u8; 32]), &elements[..3]));
+        let empty = BlockFilter::new(&block_hash, &[]);
+        assert_eq!(empty.as_bytes(), &[0u8]);
+        assert!(!empty.match_any(&block_hash, &elements));
+        assert!(!BlockFilter::from_bytes(vec![5]).match_any(&block_hash, &elements));
+    }
+
+    #[test]
+    fn index() {
+        let genesis = generate_random_block(&H256::default());
+        let child = generate_random_block(&genesis.hash());
+        let mut index = FilterIndex::new();
+        index.add(&genesis, &Vec::new());
+        let spent: UndoData = vec![(crate::utxo::OutPoint::new(H256::default(), 0), TxOutput::new(5, H256::from([8u8; 32])))];
+        index.add(&child, &spent);
+        assert_eq!(index.len(), 2);
+        let filter = index.get(&child.hash()).unwrap();
+        let recipient = child.get_transactions()[0].get_outputs()[0].recipient.as_ref().to_vec();
+        assert!(filter.match_any(&child.hash(), &[recipient]));
+        assert!(filter.match_any(&child.hash(), &[H256::from([8u8; 32]).as_ref().to_vec()]));
+
+        // the headers chain the filters
+        let genesis_header = index.get_header(&genesis.hash()).unwrap();
+        assert_eq!(genesis_header, index.get(&genesis.hash()).unwrap().header(&H256::default()));
+        let range = CFHeaders { stop_hash: child.hash(), previous_header: genesis_header, filter_hashes: vec![filter.filter_hash()] };
+        assert_eq!(range.headers(), vec![index.get_header(&child.hash()).unwrap()]);
+    }
+}
@@ -1,58 +1,444 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use serde::{Serialize, Deserialize};
 
-use crate::block::Block;
+use crate::block::{Block, Header};
 use crate::crypto::merkle::MerkleTree;
-use crate::transaction::Transaction;
+use crate::transaction::{Transaction, TxOutput, BLOCK_REWARD};
 use crate::crypto::hash::{H256, Hashable};
+use crate::validation::{self, BadBlockCache, ValidationError, ValidationStage, MEDIAN_TIME_SPAN};
+use crate::store::{ChainStore, MemoryStore};
+use crate::orphans::OrphanBlocks;
+use crate::runtime::ThreadPool;
+use crate::headers::{HeaderError, HeaderTree};
+use crate::utxo::{OutPoint, UndoData, UtxoSet};
+use crate::block_filter::FilterIndex;
+use std::time::{Duration, SystemTime};
+use log::warn;
+use crossbeam::channel::{unbounded, Receiver, Sender};
+
+/// Reasons for a block to be refused by `Blockchain::insert`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InsertError {
+    /// The parent of the block is not in the blockchain
+    UnknownParent,
+    /// The block is already in the blockchain
+    Duplicate,
+    /// The block could not be stored
+    Storage(String),
+    /// The block is not in the blockchain
+    UnknownBlock,
+    /// The block has a checkpointed height but not the checkpointed hash
+    CheckpointMismatch,
+    /// The block forks off the longest chain below the last checkpoint
+    BelowCheckpoint,
+    /// The block forks off the longest chain below its pruned part
+    Pruned,
+    /// A block of a batch is not the child of the previous one
+    NotSequential,
+    /// A block of the branch failed validation when it was connected, the branch is invalidated
+    Invalid(H256, ValidationError),
+}
+
+impl std::fmt::Display for InsertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            InsertError::UnknownParent => write!(f, "unknown parent"),
+            InsertError::Duplicate => write!(f, "duplicate block"),
+            InsertError::Storage(e) => write!(f, "storage error: {}", e),
+            InsertError::UnknownBlock => write!(f, "unknown block"),
+            InsertError::CheckpointMismatch => write!(f, "block does not match checkpoint"),
+            InsertError::BelowCheckpoint => write!(f, "fork below the last checkpoint"),
+            InsertError::Pruned => write!(f, "fork below the pruned blocks"),
+            InsertError::NotSequential => write!(f, "batch blocks are not sequential"),
+            InsertError::Invalid(hash, e) => write!(f, "block {} failed to connect: {}", hash, e),
+        }
+    }
+}
+
+/// A change of the longest chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainEvent {
+    /// The block joined the longest chain
+    Connected(H256),
+    /// The block left the longest chain during a reorganization
+    Disconnected(H256),
+    /// The block became the tip, after the blocks leading to it were connected
+    NewTip(H256),
+}
+
+/// Status of a chain tip, see `Blockchain::chain_tips`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TipStatus {
+    /// The tip of the longest chain
+    Active,
+    /// The tip of a valid branch that is not the longest
+    ValidFork,
+    /// The tip of a branch containing a block known to be invalid
+    Invalid,
+}
+
+/// A block without children
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChainTip {
+    pub hash: H256,
+    pub height: u32,
+    /// Number of blocks since the branch left the longest chain, 0 for the active tip
+    pub branch_length: u32,
+    pub status: TipStatus,
+}
+
+/// Statistics about the blockchain, see `Blockchain::stats`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChainStats {
+    /// Number of known blocks, in all branches
+    pub blocks: usize,
+    pub height: u32,
+    /// Number of transactions in the longest chain, not counting pruned blocks
+    pub transactions: u64,
+    /// Average time between the last blocks of the longest chain, in milliseconds
+    pub average_block_time_ms: u64,
+    /// Difficulty target of the next block
+    pub difficulty: H256,
+    /// Total work of the longest chain
+    pub chain_work: H256,
+    /// Blocks waiting for their parent
+    pub orphans: usize,
+    /// Known blocks outside of the longest chain
+    pub stale_blocks: usize,
+}
 
 pub struct Blockchain {
-    ledger: HashMap<H256, Block>,
+    store: Box<dyn ChainStore>,
     heights: HashMap<H256, u32>,
+    /// Total work of each block and its ancestors
+    chain_work: HashMap<H256, H256>,
+    /// Hash of the block of the longest chain at each height
+    main_chain: Vec<H256>,
+    /// Number of transactions in the longest chain
+    main_chain_transactions: u64,
+    /// Blocks without children
+    tips: HashSet<H256>,
+    /// Headers of all blocks, and headers accepted ahead of their blocks
+    headers: HeaderTree,
+    /// Blocks marked invalid with `invalidate_block`, excluded from fork choice with their
+    /// descendants
+    invalidated: HashSet<H256>,
+    /// Hash of the block containing each transaction
+    tx_index: HashMap<H256, H256>,
     tip_hash: H256,
+    bad_blocks: BadBlockCache,
+    orphans: OrphanBlocks,
+    /// The latest events not taken yet, at most `MAX_PENDING_EVENTS`
+    pending_events: VecDeque<ChainEvent>,
+    /// Channels of the `subscribe` callers, dropped once the receiver is gone
+    subscribers: Vec<Sender<ChainEvent>>,
+    /// Hash the block at each height must have
+    checkpoints: BTreeMap<u32, H256>,
+    /// Unspent outputs of the longest chain
+    utxo: UtxoSet,
+    /// Outputs spent by each block of the longest chain, needed to disconnect it
+    undo: HashMap<H256, UndoData>,
+    /// Compact filters of the blocks connected to the longest chain
+    filters: FilterIndex,
+    /// Number of most recent blocks of the longest chain kept in full, if pruning
+    prune_depth: Option<u32>,
+    /// Height up to which blocks were pruned
+    pruned_height: u32,
+    /// Workers verifying the input signatures of blocks in parallel, if any
+    verification_pool: Option<ThreadPool>,
+    /// Last block checked against the chain state by `validate`, with the tip it was checked on,
+    /// not checked again when connected to that tip
+    connect_checked: Option<(H256, H256)>,
+}
+
+/// Number of blocks between two difficulty adjustments
+pub const RETARGET_INTERVAL: u32 = 10;
+/// Expected time between two blocks
+pub const TARGET_BLOCK_TIME: Duration = Duration::from_secs(10);
+/// Number of consecutive block hashes at the start of a locator
+pub const LOCATOR_DENSE_SPAN: usize = 10;
+/// Maximum factor by which the difficulty changes in one adjustment
+pub const MAX_RETARGET_FACTOR: u64 = 4;
+/// Timestamp of the genesis blocks, since the Unix epoch
+pub const GENESIS_TIME: Duration = Duration::from_secs(1_600_000_000);
+
+/// Metadata key of the hash of the genesis block
+const GENESIS_KEY: &str = "genesis";
+/// Metadata key of the hash of the tip
+const TIP_KEY: &str = "tip";
+/// Metadata key of the UTXO set at the last pruning, with the hash of the block it was taken at
+const UTXO_KEY: &str = "utxo";
+/// Metadata key of the undo data of the blocks kept in full at the last pruning, as they can no
+/// longer be replayed from the pruned ones
+const UNDO_KEY: &str = "undo";
+/// Metadata key of the filter index at the last pruning, when the blocks it was built from go
+const FILTERS_KEY: &str = "filters";
+/// Minimum number of blocks pruned at once, as pruning rewrites the block storage
+pub const PRUNE_INTERVAL: u32 = 100;
+/// Most events kept for `take_events`, the oldest being dropped first
+pub const MAX_PENDING_EVENTS: usize = 10_000;
+
+fn storage_error<E: std::fmt::Display>(e: E) -> InsertError {
+    InsertError::Storage(e.to_string())
 }
 
 impl Blockchain {
     /// Create a new blockchain, only containing the genesis block
     pub fn new() -> Self {
-        let parent = H256::from([0; 32]);
+        return Blockchain::with_genesis(Blockchain::genesis_block());
+    }
 
-        let difficulty: H256 = Blockchain::get_difficulty().into();
+    /// Create a regtest blockchain, see `regtest_genesis_block`
+    pub fn regtest() -> Self {
+        return Blockchain::with_genesis(Blockchain::regtest_genesis_block());
+    }
+
+    /// The genesis block of regtest chains. Its difficulty target lets any hash through and is
+    /// never adjusted, so blocks are mined instantly.
+    pub fn regtest_genesis_block() -> Block {
+        return Blockchain::genesis_block_with(Blockchain::regtest_difficulty());
+    }
+
+    /// Create the genesis block
+    pub fn genesis_block() -> Block {
+        return Blockchain::genesis_block_with(Blockchain::get_difficulty());
+    }
+
+    /// The genesis block with the `difficulty` target, the same on every node
+    fn genesis_block_with(difficulty: H256) -> Block {
+        let parent = H256::from([0; 32]);
 
         let mut transactions: Vec<Transaction> = Vec::new();
-        let transaction = Transaction::new("genesis in".to_string(), "genesis out".to_string());
+        let transaction = Transaction::coinbase(0, H256::default(), BLOCK_REWARD);
         transactions.push(transaction);
 
         let merkle_tree = MerkleTree::new(&transactions);
         let merkle_root = merkle_tree.root();
 
-        let genesis_block: Block = Block::new(parent, difficulty, transactions, merkle_root);
+        let timestamp = SystemTime::UNIX_EPOCH + GENESIS_TIME;
+        let genesis_block: Block = Block::new_at(parent, difficulty, transactions, merkle_root, 0, timestamp);
+        return genesis_block;
+    }
+
+    /// Create a new in-memory blockchain starting from the given genesis block
+    pub fn with_genesis(genesis_block: Block) -> Self {
+        let mut store = MemoryStore::new();
+        Blockchain::init_store(&mut store, &genesis_block).unwrap();
+        return Blockchain::open(Box::new(store)).unwrap();
+    }
+
+    fn init_store(store: &mut dyn ChainStore, genesis_block: &Block) -> std::io::Result<()> {
+        let hashed = bincode::serialize(&genesis_block.hash()).unwrap();
+        store.put_block(genesis_block)?;
+        store.put_meta(GENESIS_KEY, &hashed)?;
+        store.put_meta(TIP_KEY, &hashed)?;
+        return store.flush();
+    }
+
+    /// Start a blockchain in the empty `store` from the headers of a chain and the UTXO set at
+    /// its tip. The blocks are stored pruned, so reorganizations below the tip are refused.
+    pub fn from_snapshot(mut store: Box<dyn ChainStore>, headers: &[Header], utxo: &UtxoSet) -> std::io::Result<Self> {
+        let invalid = |what: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, what.to_string());
+        if store.num_blocks() != 0 {
+            return Err(invalid("store is not empty"));
+        }
+        let genesis = headers.first().ok_or_else(|| invalid("no headers"))?;
+        Blockchain::init_store(store.as_mut(), &Block::from_header(genesis.clone()))?;
+        for header in &headers[1..] {
+            store.put_block(&Block::from_header(header.clone()))?;
+        }
+        let tip = headers.last().unwrap().hash();
+        store.put_meta(TIP_KEY, &bincode::serialize(&tip).unwrap())?;
+        store.put_meta(UTXO_KEY, &bincode::serialize(&(tip, utxo)).unwrap())?;
+        store.flush()?;
+        return Blockchain::open(store);
+    }
+
+    /// Open the blockchain persisted in `store`, rebuilding the block index. An empty store is
+    /// initialized with a new genesis block.
+    pub fn open(store: Box<dyn ChainStore>) -> std::io::Result<Self> {
+        return Blockchain::load(store, true);
+    }
+
+    /// Build the block index and the chain state from the blocks in `store`. Unless
+    /// `use_saved_state`, the stored tip is ignored in favor of the tip with the most work, and
+    /// the UTXO set is replayed from genesis if the blocks were not pruned.
+    fn load(mut store: Box<dyn ChainStore>, use_saved_state: bool) -> std::io::Result<Self> {
+        if store.num_blocks() == 0 {
+            Blockchain::init_store(store.as_mut(), &Blockchain::genesis_block())?;
+        }
+        let invalid = |what: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, what.to_string());
+        let read_hash = |key: &str| -> Option<H256> {
+            store.get_meta(key).and_then(|v| bincode::deserialize(&v).ok())
+        };
+        let genesis_hash = read_hash(GENESIS_KEY).ok_or_else(|| invalid("missing genesis"))?;
+        let stored_tip = if use_saved_state { read_hash(TIP_KEY) } else { None };
 
-        let mut chain: HashMap<H256, Block> = HashMap::new();
-        let hashed = genesis_block.hash();
-        chain.insert(hashed, genesis_block);
         let mut heights: HashMap<H256, u32> = HashMap::new();
-        heights.insert(hashed, 0);
-        let blockchain = Blockchain {
-            ledger: chain,
+        let mut chain_work: HashMap<H256, H256> = HashMap::new();
+        let mut tips: HashSet<H256> = HashSet::new();
+        tips.insert(genesis_hash);
+        let mut transaction_counts: HashMap<H256, u64> = HashMap::new();
+        let mut headers: Option<HeaderTree> = None;
+        let mut pruned_height: u32 = 0;
+        heights.insert(genesis_hash, 0);
+        let mut tip_hash = genesis_hash;
+        for hash in store.block_hashes() {
+            let block = store.get_block(&hash).ok_or_else(|| invalid("unreadable block"))?;
+            transaction_counts.insert(hash, block.get_transactions().len() as u64);
+            if hash == genesis_hash {
+                chain_work.insert(hash, block.get_difficulty().work());
+                headers = Some(HeaderTree::new(block.get_header()));
+                continue;
+            }
+            let h = match heights.get(&block.get_parent()) {
+                Some(parent_height) => parent_height + 1,
+                None => {
+                    warn!("Ignoring stored block {} with unknown parent", hash);
+                    continue;
+                }
+            };
+            heights.insert(hash, h);
+            let tree = headers.as_mut().ok_or_else(|| invalid("block stored before genesis"))?;
+            let work = chain_work[&block.get_parent()].saturating_add(&tree.next_difficulty(&block.get_parent()).work());
+            tree.insert(block.get_header());
+            tree.set_body(&hash);
+            chain_work.insert(hash, work);
+            tips.remove(&block.get_parent());
+            tips.insert(hash);
+            if block.is_pruned() {
+                pruned_height = std::cmp::max(pruned_height, h);
+            }
+            if work > chain_work[&tip_hash] {
+                tip_hash = hash;
+            }
+        }
+        if let Some(tip) = stored_tip {
+            if heights.contains_key(&tip) {
+                tip_hash = tip;
+            }
+        }
+        let mut blockchain = Blockchain {
+            store,
             heights,
-            tip_hash: hashed,
+            chain_work,
+            main_chain: Vec::new(),
+            main_chain_transactions: 0,
+            tips,
+            headers: headers.ok_or_else(|| invalid("missing genesis"))?,
+            invalidated: HashSet::new(),
+            tx_index: HashMap::new(),
+            tip_hash,
+            bad_blocks: BadBlockCache::default(),
+            connect_checked: None,
+            orphans: OrphanBlocks::default(),
+            pending_events: VecDeque::new(),
+            subscribers: Vec::new(),
+            checkpoints: BTreeMap::new(),
+            utxo: UtxoSet::new(),
+            undo: HashMap::new(),
+            filters: FilterIndex::new(),
+            prune_depth: None,
+            pruned_height,
+            verification_pool: None,
         };
-        return blockchain;
+        let mut main_chain: Vec<H256> = blockchain.iter().map(|b| b.hash()).collect();
+        main_chain.reverse();
+        blockchain.main_chain_transactions = main_chain.iter().map(|h| transaction_counts.get(h).cloned().unwrap_or(0)).sum();
+        for hash in &main_chain {
+            let block = blockchain.get(hash);
+            blockchain.index_transactions(&block);
+        }
+        blockchain.main_chain = main_chain;
+        // rebuild the UTXO set from genesis, or from the snapshot taken when pruning
+        let snapshot: Option<(H256, UtxoSet)> = blockchain
+            .store
+            .get_meta(UTXO_KEY)
+            .and_then(|v| bincode::deserialize(&v).ok());
+        let mut start = None;
+        if let Some((hash, utxo)) = snapshot {
+            if (use_saved_state || pruned_height > 0) && blockchain.is_in_longest_chain(&hash) {
+                blockchain.utxo = utxo;
+                start = Some(hash);
+                if let Some(filters) = blockchain.store.get_meta(FILTERS_KEY).and_then(|v| bincode::deserialize(&v).ok()) {
+                    blockchain.filters = filters;
+                }
+                // the blocks below the snapshot are not replayed, their undo data was saved with it
+                let undo: HashMap<H256, UndoData> = blockchain.store.get_meta(UNDO_KEY).and_then(|v| bincode::deserialize(&v).ok()).unwrap_or_default();
+                let undo: HashMap<H256, UndoData> = undo.into_iter().filter(|(hash, _)| blockchain.is_in_longest_chain(hash)).collect();
+                blockchain.undo = undo;
+            }
+        }
+        if start.is_none() && pruned_height > 0 {
+            return Err(invalid("no UTXO set for the pruned blocks"));
+        }
+        let mut longest_chain: Vec<Block> = blockchain.iter().take_while(|b| Some(b.hash()) != start).collect();
+        longest_chain.reverse();
+        for block in &longest_chain {
+            let spent = blockchain.utxo.connect_block(block);
+            blockchain.filters.add(block, &spent);
+            blockchain.undo.insert(block.hash(), spent);
+        }
+        return Ok(blockchain);
     }
 
-    pub fn find(&self, hash: &H256) -> bool {
-        return match self.ledger.get(hash) {
-            Some(_) => true,
-            None => false,
+    /// Throw away the block index and the chain state, and rebuild them by replaying the stored
+    /// blocks, to recover from a corrupted index. Settings such as checkpoints are kept.
+    pub fn reindex(&mut self) -> std::io::Result<()> {
+        let store = std::mem::replace(&mut self.store, Box::new(MemoryStore::new()));
+        let mut rebuilt = Blockchain::load(store, false)?;
+        rebuilt.store.put_meta(TIP_KEY, &bincode::serialize(&rebuilt.tip_hash).unwrap())?;
+        rebuilt.store.flush()?;
+        rebuilt.bad_blocks = std::mem::take(&mut self.bad_blocks);
+        rebuilt.orphans = std::mem::take(&mut self.orphans);
+        rebuilt.subscribers = std::mem::take(&mut self.subscribers);
+        rebuilt.checkpoints = std::mem::take(&mut self.checkpoints);
+        rebuilt.prune_depth = self.prune_depth;
+        *self = rebuilt;
+        return Ok(());
+    }
+
+    /// Index the transactions of a block of the longest chain
+    fn index_transactions(&mut self, block: &Block) {
+        let hash = block.hash();
+        for transaction in block.get_transactions() {
+            self.tx_index.entry(transaction.hash()).or_insert(hash);
         }
     }
 
+    /// Drop the transactions of a block leaving the longest chain from the index
+    fn unindex_transactions(&mut self, block: &Block) {
+        let hash = block.hash();
+        for transaction in block.get_transactions() {
+            if self.tx_index.get(&transaction.hash()) == Some(&hash) {
+                self.tx_index.remove(&transaction.hash());
+            }
+        }
+    }
+
+    /// Find a transaction, with the hash of the block containing it
+    pub fn find_transaction(&self, txid: &H256) -> Option<(Transaction, H256)> {
+        let block_hash = self.tx_index.get(txid)?;
+        let block = self.get(block_hash);
+        let transaction = block.get_transactions().iter().find(|t| &t.hash() == txid)?.clone();
+        return Some((transaction, *block_hash));
+    }
+
+    /// Get the height of a block
+    pub fn height_of(&self, hash: &H256) -> Option<u32> {
+        return self.heights.get(hash).cloned();
+    }
+
+    pub fn find(&self, hash: &H256) -> bool {
+        return self.heights.contains_key(hash);
+    }
+
     pub fn get(&self, hash: &H256) -> Block {
-        return self.ledger.get(hash).unwrap().clone();
+        return self.store.get_block(hash).unwrap();
     }
 
     pub fn num_blocks(&self) -> usize {
-        return self.ledger.len();
+        return self.heights.len();
     }
 
     pub fn get_difficulty() -> H256 {
@@ -64,18 +450,546 @@ impl Blockchain {
         return difficulty;
     }
 
-    /// Insert a block into blockchain
-    pub fn insert(&mut self, block: &Block) {
-        let bl: Block = block.clone();
-        let parent_hash = bl.get_parent();
-        let parent_height: u32 = self.heights.get(&parent_hash).unwrap().clone();
-        let hashed = bl.hash();
+    /// The difficulty target of regtest chains, the highest possible
+    pub fn regtest_difficulty() -> H256 {
+        return [255u8; 32].into();
+    }
+
+    /// Whether this is a regtest chain, whose blocks all have the regtest difficulty
+    pub fn is_regtest(&self) -> bool {
+        return self.next_difficulty(&self.tip_hash) == Blockchain::regtest_difficulty();
+    }
+
+    /// The difficulty target a child of block `parent` must have. Every `RETARGET_INTERVAL`
+    /// blocks, the target is scaled by the time the last interval took compared to the expected
+    /// time, by at most a factor of `MAX_RETARGET_FACTOR`, and never above the initial target.
+    pub fn next_difficulty(&self, parent: &H256) -> H256 {
+        return self.headers.next_difficulty(parent);
+    }
+
+    /// Insert a block into blockchain. A block whose parent is not the tip starts or extends a
+    /// side branch, which becomes the longest chain once it has strictly more work than the
+    /// current one.
+    pub fn insert(&mut self, block: &Block) -> Result<(), InsertError> {
+        self.insert_unflushed(block)?;
+        self.store.flush().map_err(storage_error)?;
+        return Ok(());
+    }
+
+    /// Insert a chain of blocks, each the child of the previous one, flushing the store once at
+    /// the end. The whole batch is checked before any block is stored, and connected at once, so
+    /// that if a block fails to connect the chain is left as it was.
+    pub fn insert_batch(&mut self, blocks: Vec<Block>) -> Result<(), InsertError> {
+        if blocks.windows(2).any(|pair| pair[1].get_parent() != pair[0].hash()) {
+            return Err(InsertError::NotSequential);
+        }
+        let first = match blocks.first() {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+        let first_height = self.check_insert(first)?;
+        for (height, block) in (first_height..).zip(blocks.iter()).skip(1) {
+            if self.heights.contains_key(&block.hash()) {
+                return Err(InsertError::Duplicate);
+            }
+            if self.checkpoints.get(&height).map_or(false, |expected| *expected != block.hash()) {
+                return Err(InsertError::CheckpointMismatch);
+            }
+        }
+        let result = blocks
+            .iter()
+            .zip(first_height..)
+            .try_for_each(|(block, height)| self.add_block(block, height))
+            .and_then(|_| self.connect_if_best(&blocks.last().unwrap().hash()));
+        self.store.flush().map_err(storage_error)?;
+        return result;
+    }
+
+    fn insert_unflushed(&mut self, block: &Block) -> Result<(), InsertError> {
+        let height = self.check_insert(block)?;
+        self.add_block(block, height)?;
+        return self.connect_if_best(&block.hash());
+    }
+
+    /// Check that the block can be added to the tree. Returns its height.
+    fn check_insert(&self, block: &Block) -> Result<u32, InsertError> {
+        let hashed = block.hash();
+        if self.heights.contains_key(&hashed) {
+            return Err(InsertError::Duplicate);
+        }
+        let parent_hash = block.get_parent();
+        let parent_height: u32 = match self.heights.get(&parent_hash) {
+            Some(h) => *h,
+            None => return Err(InsertError::UnknownParent),
+        };
         let h = parent_height + 1;
-        if h > self.heights.get(&self.tip_hash).unwrap().clone() {
-            self.tip_hash = hashed;
+        if self.checkpoints.get(&h).map_or(false, |expected| *expected != hashed) {
+            return Err(InsertError::CheckpointMismatch);
         }
-        self.ledger.insert(hashed, bl);
+        if self.forks_below_checkpoint(&parent_hash) {
+            return Err(InsertError::BelowCheckpoint);
+        }
+        return Ok(h);
+    }
+
+    /// Store the block at height `h` and add it to the tree, without connecting it
+    fn add_block(&mut self, bl: &Block, h: u32) -> Result<(), InsertError> {
+        let parent_hash = bl.get_parent();
+        let hashed = bl.hash();
+        self.store.put_block(bl).map_err(storage_error)?;
+        // the work of a block is the one of the difficulty it must have, not the one it claims
+        let work = self.chain_work[&parent_hash].saturating_add(&self.next_difficulty(&parent_hash).work());
         self.heights.insert(hashed, h);
+        self.headers.insert(bl.get_header());
+        self.headers.set_body(&hashed);
+        self.chain_work.insert(hashed, work);
+        self.tips.remove(&parent_hash);
+        self.tips.insert(hashed);
+        return Ok(());
+    }
+
+    /// Make the block `hash` the tip if it has more work than the tip and is not invalidated
+    fn connect_if_best(&mut self, hash: &H256) -> Result<(), InsertError> {
+        if self.chain_work[hash] > self.chain_work[&self.tip_hash] && !self.is_invalidated(hash) {
+            self.reorg_to(hash)?;
+            self.prune()?;
+        }
+        return Ok(());
+    }
+
+    /// Validate and add headers ahead of their blocks, in order, so the blocks of the best header
+    /// chain can be downloaded next. Headers of blocks known to be invalid are refused. Returns
+    /// the number of new headers.
+    pub fn accept_headers(&mut self, headers: &[Header]) -> Result<usize, HeaderError> {
+        let mut accepted = 0;
+        for header in headers {
+            if let Some(reason) = self.bad_blocks.get(&header.hash()) {
+                return Err(HeaderError::Invalid(reason.clone()));
+            }
+            if self.headers.accept(header, SystemTime::now())? {
+                accepted += 1;
+            }
+        }
+        return Ok(accepted);
+    }
+
+    /// The tree of known headers
+    pub fn header_tree(&self) -> &HeaderTree {
+        return &self.headers;
+    }
+
+    /// The blocks of the branch of `hash` outside the longest chain, from `hash` down, and the
+    /// block of the longest chain the branch forks from. Follows the headers kept in memory, so
+    /// no block is read.
+    fn side_branch(&self, hash: &H256) -> (Vec<H256>, H256) {
+        let mut branch: Vec<H256> = Vec::new();
+        let mut current = *hash;
+        while !self.is_in_longest_chain(&current) {
+            branch.push(current);
+            current = self.headers.get(&current).unwrap().get_parent();
+        }
+        return (branch, current);
+    }
+
+    /// Height of the lowest block of the longest chain marked invalid with `invalidate_block`,
+    /// before the chain is reorganized away from it
+    fn invalidated_main_height(&self) -> Option<u32> {
+        return self.invalidated.iter().filter(|h| self.is_in_longest_chain(h)).map(|h| self.heights[h]).min();
+    }
+
+    /// Whether the block or one of its ancestors was marked invalid with `invalidate_block`
+    fn is_invalidated(&self, hash: &H256) -> bool {
+        if self.invalidated.is_empty() {
+            return false;
+        }
+        let (branch, fork) = self.side_branch(hash);
+        if branch.iter().any(|h| self.invalidated.contains(h)) {
+            return true;
+        }
+        return self.invalidated_main_height().map_or(false, |limit| self.heights[&fork] >= limit);
+    }
+
+    /// The block with the most work that neither is nor descends from an invalidated block
+    fn best_valid_block(&self) -> H256 {
+        let main_limit = self.invalidated_main_height();
+        let mut best = self.main_chain[0];
+        for tip in &self.tips {
+            let (branch, fork) = self.side_branch(tip);
+            // the highest block of the branch below all of its invalidated blocks
+            let mut candidate = match branch.iter().rposition(|h| self.invalidated.contains(h)) {
+                Some(lowest) => self.headers.get(&branch[lowest]).unwrap().get_parent(),
+                None => *tip,
+            };
+            if let Some(limit) = main_limit.filter(|limit| self.heights[&fork] >= *limit) {
+                candidate = self.main_chain[limit as usize - 1];
+            }
+            if self.chain_work[&candidate] > self.chain_work[&best] {
+                best = candidate;
+            }
+        }
+        return best;
+    }
+
+    /// Manually mark a block and its descendants invalid, reorganizing to the best chain without
+    /// them
+    pub fn invalidate_block(&mut self, hash: &H256) -> Result<(), InsertError> {
+        if !self.heights.contains_key(hash) || self.heights[hash] == 0 {
+            return Err(InsertError::UnknownBlock);
+        }
+        self.invalidated.insert(*hash);
+        let best = self.best_valid_block();
+        if best != self.tip_hash {
+            if let Err(e) = self.reorg_to(&best) {
+                self.invalidated.remove(hash);
+                return Err(e);
+            }
+        }
+        self.headers.mark_invalid(hash);
+        return Ok(());
+    }
+
+    /// Undo `invalidate_block`, switching back to the block's branch if it has the most work
+    pub fn reconsider_block(&mut self, hash: &H256) -> Result<(), InsertError> {
+        if !self.invalidated.remove(hash) {
+            return Err(InsertError::UnknownBlock);
+        }
+        self.headers.mark_valid(hash);
+        let best = self.best_valid_block();
+        if best != self.tip_hash {
+            self.reorg_to(&best)?;
+        }
+        return Ok(());
+    }
+
+    /// Find the last common ancestor of two blocks
+    pub fn fork_point(&self, a: &H256, b: &H256) -> Option<H256> {
+        let mut a = *a;
+        let mut b = *b;
+        let mut height_a = self.height_of(&a)?;
+        let mut height_b = self.height_of(&b)?;
+        while height_a > height_b {
+            a = self.get(&a).get_parent();
+            height_a -= 1;
+        }
+        while height_b > height_a {
+            b = self.get(&b).get_parent();
+            height_b -= 1;
+        }
+        while a != b {
+            a = self.get(&a).get_parent();
+            b = self.get(&b).get_parent();
+        }
+        return Some(a);
+    }
+
+    /// Require the block at `height` to have hash `hash`. The ancestors of a checkpointed block
+    /// whose header is known skip contextual and connection checks, and the longest chain can no
+    /// longer be reorganized below a checkpoint it contains.
+    pub fn add_checkpoint(&mut self, height: u32, hash: H256) {
+        self.checkpoints.insert(height, hash);
+    }
+
+    /// Get the highest checkpoint the longest chain has reached, as (height, hash)
+    pub fn last_checkpoint(&self) -> Option<(u32, H256)> {
+        return self.checkpoints.range(..=self.tip_height()).next_back().map(|(h, hash)| (*h, *hash));
+    }
+
+    /// Whether the block is the ancestor of a checkpointed block whose header is known, the
+    /// checkpoint committing to it
+    fn is_checkpointed(&self, hash: &H256) -> bool {
+        let height = match self.headers.height_of(hash) {
+            Some(height) => height,
+            None => return false,
+        };
+        return self.checkpoints.range(height..).any(|(checkpoint_height, checkpoint)| {
+            self.headers.height_of(checkpoint) == Some(*checkpoint_height)
+                && self.headers.ancestor_at(checkpoint, height) == Some(*hash)
+        });
+    }
+
+    /// Whether a branch from block `hash` would leave the longest chain below its last
+    /// checkpoint
+    fn forks_below_checkpoint(&self, hash: &H256) -> bool {
+        let (checkpoint_height, _) = match self.last_checkpoint() {
+            Some(checkpoint) => checkpoint,
+            None => return false,
+        };
+        return match self.fork_point(&self.tip_hash, hash) {
+            Some(fork) => self.heights[&fork] < checkpoint_height,
+            None => false,
+        };
+    }
+
+    /// Whether the block `hash` is part of the longest chain
+    pub fn is_in_longest_chain(&self, hash: &H256) -> bool {
+        return match self.height_of(hash) {
+            Some(h) => self.block_at_height(h) == Some(*hash),
+            None => false,
+        };
+    }
+
+    /// Get all blocks without children, the active tip first, then by decreasing height
+    pub fn chain_tips(&self) -> Vec<ChainTip> {
+        let mut tips: Vec<ChainTip> = self
+            .tips
+            .iter()
+            .map(|hash| {
+                let height = self.heights[hash];
+                let (branch, _) = self.side_branch(hash);
+                let status = if *hash == self.tip_hash {
+                    TipStatus::Active
+                } else if branch.iter().any(|h| self.bad_blocks.peek(h).is_some() || self.invalidated.contains(h)) {
+                    TipStatus::Invalid
+                } else {
+                    TipStatus::ValidFork
+                };
+                ChainTip {
+                    hash: *hash,
+                    height,
+                    branch_length: branch.len() as u32,
+                    status,
+                }
+            })
+            .collect();
+        tips.sort_by_key(|tip| (tip.status != TipStatus::Active, std::cmp::Reverse(tip.height)));
+        return tips;
+    }
+
+    /// Get the hash of the block of the longest chain at height `height`
+    pub fn block_at_height(&self, height: u32) -> Option<H256> {
+        return self.main_chain.get(height as usize).cloned();
+    }
+
+    /// Build a block locator for the longest chain: the hashes of the last `LOCATOR_DENSE_SPAN`
+    /// blocks from the tip, then exponentially spaced ones, always ending with genesis
+    pub fn locator(&self) -> Vec<H256> {
+        let mut locator: Vec<H256> = Vec::new();
+        let mut step: usize = 1;
+        let mut skip: usize = 0;
+        let mut last = self.tip_hash;
+        for block in self.iter() {
+            last = block.hash();
+            if skip == 0 {
+                locator.push(last);
+                if locator.len() >= LOCATOR_DENSE_SPAN {
+                    step *= 2;
+                }
+                skip = step;
+            }
+            skip -= 1;
+        }
+        if locator.last() != Some(&last) {
+            locator.push(last);
+        }
+        return locator;
+    }
+
+    /// Find the first hash of a peer's locator that is part of our longest chain, i.e. the point
+    /// where the two chains diverge
+    pub fn find_fork_point(&self, locator: &[H256]) -> Option<H256> {
+        return locator.iter().find(|h| self.is_in_longest_chain(h)).cloned();
+    }
+
+    /// Make `new_tip` the tip: disconnect the blocks of the current chain down to the fork point,
+    /// then validate and connect the blocks of the new branch, in order. If a block fails to
+    /// connect, it is invalidated and the previous chain is restored. Returns the resulting
+    /// events, which are also queued for `take_events`.
+    pub fn reorg_to(&mut self, new_tip: &H256) -> Result<Vec<ChainEvent>, InsertError> {
+        let fork = self.fork_point(&self.tip_hash, new_tip).ok_or(InsertError::UnknownBlock)?;
+        if self.forks_below_checkpoint(&fork) {
+            return Err(InsertError::BelowCheckpoint);
+        }
+        let disconnected: Vec<H256> = self.iter().map(|b| b.hash()).take_while(|h| *h != fork).collect();
+        let mut connected: Vec<H256> = self.iter_from(new_tip).map(|b| b.hash()).take_while(|h| *h != fork).collect();
+        connected.reverse();
+        let fork_height = self.heights[&fork];
+        if fork_height < self.pruned_height || disconnected.iter().any(|h| !self.undo.contains_key(h)) {
+            return Err(InsertError::Pruned);
+        }
+        let old_tip = self.tip_hash;
+        for hash in &disconnected {
+            self.disconnect(hash);
+        }
+        for (i, hash) in connected.iter().enumerate() {
+            let block = self.get(hash);
+            // checkpointed blocks are only checked for their proof of work
+            let checked = self.connect_checked.take() == Some((*hash, self.tip_hash)) || self.is_checkpointed(hash);
+            if let Err(e) = if checked { Ok(()) } else { self.check_connect(&block) } {
+                warn!("Block {} failed to connect: {}", hash, e);
+                self.invalidated.insert(*hash);
+                self.headers.mark_invalid(hash);
+                self.restore(&connected[..i], &disconnected, old_tip);
+                return Err(InsertError::Invalid(*hash, e));
+            }
+            self.connect(&block);
+        }
+        if let Err(e) = self.store.put_meta(TIP_KEY, &bincode::serialize(new_tip).unwrap()) {
+            self.restore(&connected, &disconnected, old_tip);
+            return Err(storage_error(e));
+        }
+        let mut events: Vec<ChainEvent> = disconnected.into_iter().map(ChainEvent::Disconnected).collect();
+        events.extend(connected.into_iter().map(ChainEvent::Connected));
+        events.push(ChainEvent::NewTip(*new_tip));
+        self.pending_events.extend(events.iter().cloned());
+        let excess = self.pending_events.len().saturating_sub(MAX_PENDING_EVENTS);
+        self.pending_events.drain(..excess);
+        self.subscribers.retain(|subscriber| events.iter().all(|e| subscriber.send(e.clone()).is_ok()));
+        return Ok(events);
+    }
+
+    /// Disconnect the tip from the chain state
+    fn disconnect(&mut self, hash: &H256) {
+        let block = self.get(hash);
+        let spent = self.undo.remove(hash).unwrap();
+        self.utxo.disconnect_block(&block, &spent);
+        self.unindex_transactions(&block);
+        self.main_chain.pop();
+        self.main_chain_transactions -= block.get_transactions().len() as u64;
+        self.tip_hash = block.get_parent();
+    }
+
+    /// Connect a child of the tip to the chain state
+    fn connect(&mut self, block: &Block) {
+        let hash = block.hash();
+        let spent = self.utxo.connect_block(block);
+        self.filters.add(block, &spent);
+        self.undo.insert(hash, spent);
+        self.index_transactions(block);
+        self.main_chain.push(hash);
+        self.main_chain_transactions += block.get_transactions().len() as u64;
+        self.tip_hash = hash;
+    }
+
+    /// Undo a partial reorganization: disconnect the `connected` blocks, then reconnect the
+    /// `disconnected` ones, both ordered as `reorg_to` applied them
+    fn restore(&mut self, connected: &[H256], disconnected: &[H256], old_tip: H256) {
+        for hash in connected.iter().rev() {
+            self.disconnect(hash);
+        }
+        for hash in disconnected.iter().rev() {
+            let block = self.get(hash);
+            self.connect(&block);
+        }
+        debug_assert_eq!(self.tip_hash, old_tip);
+    }
+
+    /// Keep only the last `depth` blocks of the longest chain in full, discarding the
+    /// transactions of older blocks (of any branch). Headers are kept, and reorganizations are
+    /// limited to the blocks kept in full.
+    pub fn set_prune_depth(&mut self, depth: Option<u32>) {
+        self.prune_depth = depth;
+    }
+
+    /// Number of most recent blocks of the longest chain kept in full, if pruning
+    pub fn prune_depth(&self) -> Option<u32> {
+        return self.prune_depth;
+    }
+
+    /// Height up to which blocks were pruned, 0 if none were
+    pub fn pruned_height(&self) -> u32 {
+        return self.pruned_height;
+    }
+
+    /// Prune the blocks below the pruning depth, once there are at least `PRUNE_INTERVAL` of
+    /// them. The UTXO set is saved first, as it can no longer be rebuilt from the blocks.
+    fn prune(&mut self) -> Result<(), InsertError> {
+        let depth = match self.prune_depth {
+            Some(depth) => depth,
+            None => return Ok(()),
+        };
+        let horizon = self.tip_height().saturating_sub(depth);
+        if horizon < self.pruned_height + PRUNE_INTERVAL {
+            return Ok(());
+        }
+        let snapshot = bincode::serialize(&(self.tip_hash, &self.utxo)).unwrap();
+        self.store.put_meta(UTXO_KEY, &snapshot).map_err(storage_error)?;
+        let filters = bincode::serialize(&self.filters).unwrap();
+        self.store.put_meta(FILTERS_KEY, &filters).map_err(storage_error)?;
+        let heights = &self.heights;
+        let kept: HashMap<&H256, &UndoData> = self.undo.iter().filter(|(hash, _)| heights[*hash] > horizon).collect();
+        self.store.put_meta(UNDO_KEY, &bincode::serialize(&kept).unwrap()).map_err(storage_error)?;
+        let prunable: Vec<H256> = self
+            .heights
+            .iter()
+            .filter(|(_, h)| **h > self.pruned_height && **h <= horizon)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in &prunable {
+            self.store.prune_block(hash).map_err(storage_error)?;
+            self.undo.remove(hash);
+        }
+        self.store.compact().map_err(storage_error)?;
+        let heights = &self.heights;
+        self.tx_index.retain(|_, block| heights.get(block).map_or(true, |h| *h == 0 || *h > horizon));
+        self.pruned_height = horizon;
+        return Ok(());
+    }
+
+    /// The compact filters of the blocks connected to the longest chain
+    pub fn filters(&self) -> &FilterIndex {
+        return &self.filters;
+    }
+
+    /// Get an unspent output of the longest chain
+    pub fn utxo(&self, outpoint: &OutPoint) -> Option<&TxOutput> {
+        return self.utxo.get(outpoint);
+    }
+
+    /// Get the unspent outputs of the longest chain
+    pub fn utxo_set(&self) -> &UtxoSet {
+        return &self.utxo;
+    }
+
+    /// Get a channel receiving the chain events from now on, in order. The events are sent while
+    /// the blockchain is being modified, so receivers need not hold its lock to process them.
+    pub fn subscribe(&mut self) -> Receiver<ChainEvent> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.push(sender);
+        return receiver;
+    }
+
+    /// Take the chain events that happened since the last call, at most the latest
+    /// `MAX_PENDING_EVENTS`
+    pub fn take_events(&mut self) -> Vec<ChainEvent> {
+        return std::mem::take(&mut self.pending_events).into();
+    }
+
+    /// Insert a block, or buffer it as an orphan if its parent is unknown. Once a block is
+    /// inserted, the orphans waiting for it are validated and inserted too. Returns the hashes of
+    /// all inserted blocks, which is empty if the block was buffered.
+    pub fn insert_or_buffer(&mut self, block: &Block) -> Result<Vec<H256>, InsertError> {
+        match self.insert(block) {
+            Err(InsertError::UnknownParent) => {
+                self.orphans.insert(block.clone());
+                return Ok(Vec::new());
+            }
+            Err(e) => return Err(e),
+            Ok(()) => {}
+        }
+        let mut inserted: Vec<H256> = vec![block.hash()];
+        let mut i = 0;
+        while i < inserted.len() {
+            for orphan in self.orphans.take_children(&inserted[i]) {
+                if let Err(e) = self.validate(&orphan) {
+                    warn!("Dropped orphan block {}: {}", orphan.hash(), e);
+                    continue;
+                }
+                if self.insert(&orphan).is_ok() {
+                    inserted.push(orphan.hash());
+                }
+            }
+            i += 1;
+        }
+        return Ok(inserted);
+    }
+
+    /// Whether the block is buffered as an orphan
+    pub fn is_orphan(&self, hash: &H256) -> bool {
+        return self.orphans.contains(hash);
+    }
+
+    pub fn num_orphans(&self) -> usize {
+        return self.orphans.len();
     }
 
     /// Get the last block's hash of the longest chain
@@ -83,26 +997,192 @@ impl Blockchain {
         return self.tip_hash;
     }
 
+    /// Get the total work of a block and its ancestors
+    pub fn chain_work(&self, hash: &H256) -> Option<H256> {
+        return self.chain_work.get(hash).cloned();
+    }
+
+    /// Get statistics about the blockchain, averaging block times over the last `window` blocks
+    pub fn stats(&self, window: u32) -> ChainStats {
+        let height = self.tip_height();
+        let window = std::cmp::min(window, height);
+        let average_block_time_ms = if window == 0 {
+            0
+        } else {
+            let first = self.get(&self.main_chain[(height - window) as usize]).get_timestamp();
+            let last = self.get(&self.tip_hash).get_timestamp();
+            let span = last.duration_since(first).map(|d| d.as_millis() as u64).unwrap_or(0);
+            span / window as u64
+        };
+        return ChainStats {
+            blocks: self.num_blocks(),
+            height,
+            transactions: self.main_chain_transactions,
+            average_block_time_ms,
+            difficulty: self.next_difficulty(&self.tip_hash),
+            chain_work: self.chain_work[&self.tip_hash],
+            orphans: self.num_orphans(),
+            stale_blocks: self.num_blocks() - self.main_chain.len(),
+        };
+    }
+
+    /// Get the height of the longest chain
+    pub fn tip_height(&self) -> u32 {
+        return self.heights.get(&self.tip_hash).unwrap().clone();
+    }
+
+    /// Get the headers of the longest chain from height `from` to height `to` (both inclusive)
+    pub fn headers_in_range(&self, from: u32, to: u32) -> Vec<Header> {
+        let to = std::cmp::min(to, self.tip_height());
+        if from > to {
+            return Vec::new();
+        }
+        return self.main_chain[from as usize..=to as usize]
+            .iter()
+            .map(|hash| self.get(hash).get_header().clone())
+            .collect();
+    }
+
+    /// Iterate over the blocks of the longest chain, from the tip back to genesis
+    pub fn iter(&self) -> ChainIter {
+        return self.iter_from(&self.tip());
+    }
+
+    /// Iterate over the block `hash` and its ancestors back to genesis, following parent links
+    pub fn iter_from(&self, hash: &H256) -> ChainIter {
+        return ChainIter { blockchain: self, next: Some(*hash) };
+    }
+
+    /// Get up to `n` ancestors of the block `hash`, starting with the block itself
+    pub fn ancestors(&self, hash: &H256, n: usize) -> Vec<Block> {
+        return self.iter_from(hash).take(n).collect();
+    }
+
+    /// Get the headers of up to `n` ancestors of the block `hash`, starting with the block itself
+    /// and following parent links backwards
+    pub fn ancestor_headers(&self, hash: &H256, n: usize) -> Vec<Header> {
+        return self.iter_from(hash).take(n).map(|b| b.get_header().clone()).collect();
+    }
+
+    /// Median time past of the tip, which the timestamp of the next block must exceed
+    pub fn median_time_past(&self) -> SystemTime {
+        let ancestors = self.ancestor_headers(&self.tip_hash, MEDIAN_TIME_SPAN);
+        return validation::median_time_past(&ancestors).unwrap_or(SystemTime::UNIX_EPOCH);
+    }
+
+    /// Check the block against its ancestors in this blockchain. The difficulty is only checked
+    /// if the parent is known.
+    pub fn validate_contextual(&self, block: &Block) -> Result<(), ValidationError> {
+        if self.find(&block.get_parent()) && block.get_difficulty() != self.next_difficulty(&block.get_parent()) {
+            return Err(ValidationError::WrongDifficulty);
+        }
+        let ancestors = self.ancestor_headers(&block.get_parent(), MEDIAN_TIME_SPAN);
+        validation::check_contextual(block.get_header(), &ancestors, SystemTime::now())?;
+        if let Some(parent_height) = self.height_of(&block.get_parent()) {
+            validation::check_lock_times(block, parent_height + 1, &ancestors)?;
+            validation::check_coinbase_height(block, parent_height + 1)?;
+        }
+        return Ok(());
+    }
+
+    /// Replace the cache of blocks known to be invalid, e.g. with one persisted on disk
+    pub fn set_bad_blocks(&mut self, bad_blocks: BadBlockCache) {
+        self.bad_blocks = bad_blocks;
+    }
+
+    /// Get the reason a block was previously rejected, if it was
+    pub fn bad_block_reason(&self, hash: &H256) -> Option<&ValidationError> {
+        return self.bad_blocks.peek(hash);
+    }
+
+    /// Verify the input signatures of blocks with the workers of `pool`, or sequentially if `None`
+    pub fn set_verification_pool(&mut self, pool: Option<ThreadPool>) {
+        self.verification_pool = pool;
+    }
+
+    /// Check the block against the chain state it connects to. Only possible if its parent is the
+    /// tip, other blocks pass this stage until they are connected.
+    pub fn validate_connect(&self, block: &Block) -> Result<(), ValidationError> {
+        if block.get_parent() != self.tip_hash {
+            return Ok(());
+        }
+        return self.check_connect(block);
+    }
+
+    /// Check a child of the tip against the chain state, with the verification pool if any
+    fn check_connect(&self, block: &Block) -> Result<(), ValidationError> {
+        return match &self.verification_pool {
+            Some(pool) => validation::check_connect_parallel(block, &self.utxo, pool),
+            None => validation::check_connect(block, &self.utxo),
+        };
+    }
+
+    /// Run the validation stages in order: stateless checks, checks against the ancestors, then
+    /// against the chain state. Blocks whose header is invalid are remembered, and rejected right
+    /// away next time; failures of the transactions are not, as the same header could come with
+    /// a valid body. Either way the header no longer leads the header chain.
+    pub fn validate(&mut self, block: &Block) -> Result<(), ValidationError> {
+        let hash = block.hash();
+        self.connect_checked = None;
+        if let Some(reason) = self.bad_blocks.get(&hash) {
+            return Err(reason.clone());
+        }
+        let result = validation::check_stateless(block).and_then(|_| {
+            // a checkpoint commits to its ancestors, only their proof of work and commitments
+            // to their transactions are checked
+            if self.is_checkpointed(&hash) {
+                return Ok(());
+            }
+            return self.validate_contextual(block).and_then(|_| self.validate_connect(block));
+        });
+        match &result {
+            Ok(()) if block.get_parent() == self.tip_hash => self.connect_checked = Some((hash, self.tip_hash)),
+            Ok(()) => {}
+            Err(e) if e.is_decided_by_header() => {
+                self.headers.mark_invalid(&hash);
+                if let Err(io_error) = self.bad_blocks.insert(hash, e.clone()) {
+                    warn!("Error persisting bad block {}: {}", hash, io_error);
+                }
+            }
+            Err(e) if e.stage() == ValidationStage::Connect => self.headers.mark_invalid(&hash),
+            Err(_) => {}
+        }
+        return result;
+    }
+
     /// Get the hash of all blocks in the longest chain
     #[cfg(any(test, test_utilities))]
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
-        let mut current: H256 = self.tip();
-        let mut path: Vec<H256> = Vec::new();
-        let mut h = self.heights.get(&current).unwrap().clone();
-        path.push(current);
-        while h > 0 {
-            current = self.ledger.get(&current).unwrap().get_parent();
-            path.push(current);
-            h = h - 1;
-        }
-        return path;
+        return self.iter().map(|b| b.hash()).collect();
+    }
+}
+
+/// Iterator over a block and its ancestors, see `Blockchain::iter_from`
+pub struct ChainIter<'a> {
+    blockchain: &'a Blockchain,
+    next: Option<H256>,
+}
+
+impl<'a> Iterator for ChainIter<'a> {
+    type Item = Block;
+
+    fn next(&mut self) -> Option<Block> {
+        let block = self.blockchain.store.get_block(&self.next?)?;
+        self.next = Some(block.get_parent());
+        return Some(block);
     }
 }
 
 #[cfg(any(test, test_utilities))]
 mod tests {
     use super::*;
-    use crate::block::test::generate_random_block;
+    use crate::block::test::{generate_random_block, generate_mined_block, generate_mined_block_with_height, generate_random_block_at};
+    use crate::store::FileStore;
+    use crate::store::tests::temp_dir;
+    use crate::validation::ValidationStage;
+    use crate::transaction::tests::{generate_random_transaction, generate_signed_transaction, generate_spending_transaction};
+    use crate::crypto::keys::KeyPair;
+    use crate::validation::TxError;
     use crate::crypto::hash::Hashable;
 
     #[test]
@@ -110,39 +1190,791 @@ mod tests {
         let mut blockchain = Blockchain::new();
         let genesis_hash = blockchain.tip();
         let block = generate_random_block(&genesis_hash);
-        blockchain.insert(&block);
+        blockchain.insert(&block).unwrap();
         assert_eq!(blockchain.tip(), block.hash());
     }
 
-    /*
+    #[test]
+    fn headers_range() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&block1).unwrap();
+        let block2 = generate_random_block(&block1.hash());
+        blockchain.insert(&block2).unwrap();
+        let headers = blockchain.headers_in_range(1, 5);
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[0].hash(), block1.hash());
+        assert_eq!(headers[1].hash(), block2.hash());
+        assert_eq!(blockchain.headers_in_range(0, 0)[0].hash(), genesis_hash);
+        assert!(blockchain.headers_in_range(2, 1).is_empty());
+    }
+
     #[test]
     fn insert_more() {
         let mut blockchain = Blockchain::new();
         let genesis_hash = blockchain.tip();
-        println!("genesis: {}", genesis_hash);
         let block1 = generate_random_block(&genesis_hash);
-        blockchain.insert(&block1);
-        println!("#1 {}", block1.hash());
+        blockchain.insert(&block1).unwrap();
         let block2 = generate_random_block(&genesis_hash);
-        blockchain.insert(&block2);
-        println!("#2 {}", block2.hash());
+        blockchain.insert(&block2).unwrap();
         let block3 = generate_random_block(&block2.hash());
-        blockchain.insert(&block3);
-        println!("#3 {}", block3.hash());
+        blockchain.insert(&block3).unwrap();
         let block4 = generate_random_block(&block2.hash());
-        blockchain.insert(&block4);
-        println!("#4 {}", block4.hash());
+        blockchain.insert(&block4).unwrap();
         let block5 = generate_random_block(&block4.hash());
-        blockchain.insert(&block5);
-        println!("#5 {}", block5.hash());
+        blockchain.insert(&block5).unwrap();
         let block6 = generate_random_block(&block1.hash());
-        blockchain.insert(&block6);
-        println!("#6 {}", block6.hash());
-        let tip_hash = blockchain.tip();
-        println!("tip is {}", tip_hash);
-        let xx = blockchain.all_blocks_in_longest_chain();
-        println!("{:?}", xx);
+        blockchain.insert(&block6).unwrap();
         assert_eq!(blockchain.tip(), block5.hash());
     }
-    */
+
+    #[test]
+    fn reopen_file_store() {
+        let dir = temp_dir("blockchain_store");
+        let mut blockchain = Blockchain::open(Box::new(FileStore::open(&dir).unwrap())).unwrap();
+        let genesis_hash = blockchain.tip();
+        let block1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&block1).unwrap();
+        let block2 = generate_random_block(&block1.hash());
+        blockchain.insert(&block2).unwrap();
+        let side = generate_random_block(&genesis_hash);
+        blockchain.insert(&side).unwrap();
+        drop(blockchain);
+
+        let blockchain = Blockchain::open(Box::new(FileStore::open(&dir).unwrap())).unwrap();
+        assert_eq!(blockchain.num_blocks(), 4);
+        assert_eq!(blockchain.tip(), block2.hash());
+        assert_eq!(blockchain.tip_height(), 2);
+        assert!(blockchain.find(&side.hash()));
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), vec![block2.hash(), block1.hash(), genesis_hash]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn insert_invalid() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block = generate_random_block(&genesis_hash);
+        blockchain.insert(&block).unwrap();
+        assert_eq!(blockchain.insert(&block), Err(InsertError::Duplicate));
+        let orphan = generate_random_block(&generate_random_block(&genesis_hash).hash());
+        assert_eq!(blockchain.insert(&orphan), Err(InsertError::UnknownParent));
+        assert_eq!(blockchain.tip(), block.hash());
+        assert_eq!(blockchain.num_blocks(), 2);
+    }
+
+    #[test]
+    fn validate_caches_bad_blocks() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let transactions = vec![generate_random_transaction()];
+        let merkle_root = MerkleTree::new(&transactions).root();
+        // no hash meets a zero target
+        let block = Block::new(genesis_hash, H256::default(), transactions, merkle_root);
+        assert_eq!(blockchain.bad_block_reason(&block.hash()), None);
+        assert_eq!(blockchain.validate(&block), Err(ValidationError::InvalidProofOfWork));
+        assert_eq!(blockchain.bad_block_reason(&block.hash()), Some(&ValidationError::InvalidProofOfWork));
+
+        // a body not matching the header says nothing of the header
+        let mutated = loop {
+            let block = Block::new(genesis_hash, Blockchain::get_difficulty(), vec![generate_random_transaction()], H256::default());
+            if validation::check_pow(block.get_header()).is_ok() {
+                break block;
+            }
+        };
+        assert_eq!(blockchain.validate(&mutated), Err(ValidationError::BadMerkleRoot));
+        assert_eq!(blockchain.bad_block_reason(&mutated.hash()), None);
+    }
+
+    #[test]
+    fn orphans_connect_on_parent_arrival() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block1 = generate_mined_block_with_height(&genesis_hash, 1);
+        let block2 = generate_mined_block_with_height(&block1.hash(), 2);
+        let block3 = generate_mined_block_with_height(&block2.hash(), 3);
+        assert_eq!(blockchain.insert_or_buffer(&block3), Ok(vec![]));
+        assert_eq!(blockchain.insert_or_buffer(&block2), Ok(vec![]));
+        assert!(blockchain.is_orphan(&block3.hash()));
+        assert_eq!(blockchain.num_orphans(), 2);
+        let inserted = blockchain.insert_or_buffer(&block1).unwrap();
+        assert_eq!(inserted, vec![block1.hash(), block2.hash(), block3.hash()]);
+        assert_eq!(blockchain.tip(), block3.hash());
+        assert_eq!(blockchain.num_orphans(), 0);
+    }
+
+    #[test]
+    fn iterate_chain() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut hashes = vec![genesis_hash];
+        for _ in 0..5 {
+            let block = generate_random_block(hashes.last().unwrap());
+            blockchain.insert(&block).unwrap();
+            hashes.push(block.hash());
+        }
+        let side = generate_random_block(&hashes[2]);
+        blockchain.insert(&side).unwrap();
+
+        let walked: Vec<H256> = blockchain.iter().map(|b| b.hash()).collect();
+        let mut expected = hashes.clone();
+        expected.reverse();
+        assert_eq!(walked, expected);
+        let from_side: Vec<H256> = blockchain.iter_from(&side.hash()).map(|b| b.hash()).collect();
+        assert_eq!(from_side, vec![side.hash(), hashes[2], hashes[1], genesis_hash]);
+        let ancestors: Vec<H256> = blockchain.ancestors(&hashes[4], 2).iter().map(|b| b.hash()).collect();
+        assert_eq!(ancestors, vec![hashes[4], hashes[3]]);
+        assert_eq!(blockchain.ancestors(&genesis_hash, 10).len(), 1);
+        assert_eq!(blockchain.iter_from(&H256::from([7u8; 32])).count(), 0);
+    }
+
+    #[test]
+    fn locator_and_fork_point() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut hashes = vec![genesis_hash];
+        for _ in 0..40 {
+            let block = generate_random_block(hashes.last().unwrap());
+            blockchain.insert(&block).unwrap();
+            hashes.push(block.hash());
+        }
+        let locator = blockchain.locator();
+        let expected: Vec<H256> = [40, 39, 38, 37, 36, 35, 34, 33, 32, 31, 29, 25, 17, 1, 0].iter().map(|h| hashes[*h]).collect();
+        assert_eq!(locator, expected);
+        assert_eq!(blockchain.find_fork_point(&locator), Some(hashes[40]));
+
+        // a peer that followed a side branch from height 30
+        let mut peer = Blockchain::with_genesis(blockchain.get(&genesis_hash));
+        for h in &hashes[1..=30] {
+            peer.insert(&blockchain.get(h)).unwrap();
+        }
+        let mut parent = hashes[30];
+        for _ in 0..5 {
+            let block = generate_random_block(&parent);
+            peer.insert(&block).unwrap();
+            parent = block.hash();
+        }
+        let fork = blockchain.find_fork_point(&peer.locator()).unwrap();
+        assert!(blockchain.is_in_longest_chain(&fork));
+        assert!(blockchain.height_of(&fork).unwrap() <= 30);
+        assert_eq!(blockchain.find_fork_point(&[H256::from([7u8; 32])]), None);
+    }
+
+    #[test]
+    fn checkpoints() {
+        let mut blockchain = Blockchain::new();
+        let mut hashes = vec![blockchain.tip()];
+        for _ in 0..6 {
+            let block = generate_random_block(hashes.last().unwrap());
+            hashes.push(block.hash());
+            if hashes.len() == 4 {
+                blockchain.add_checkpoint(3, block.hash());
+                // a competing block at the checkpointed height is refused
+                let other = generate_random_block(&hashes[2]);
+                assert_eq!(blockchain.insert(&other), Err(InsertError::CheckpointMismatch));
+            }
+            blockchain.insert(&block).unwrap();
+        }
+        assert_eq!(blockchain.last_checkpoint(), Some((3, hashes[3])));
+
+        // a longer branch forking below the checkpoint is refused
+        let fork = generate_random_block(&hashes[1]);
+        assert_eq!(blockchain.insert(&fork), Err(InsertError::BelowCheckpoint));
+        assert!(!blockchain.find(&fork.hash()));
+        // forking above it is fine
+        let fork = generate_random_block(&hashes[4]);
+        blockchain.insert(&fork).unwrap();
+
+        // blocks under a checkpoint of unknown header are fully validated
+        blockchain.add_checkpoint(10, H256::from([7u8; 32]));
+        let unmined = loop {
+            let block = generate_random_block(&hashes[6]);
+            if block.hash() > block.get_difficulty() {
+                break block;
+            }
+        };
+        assert_eq!(blockchain.validate(&unmined), Err(ValidationError::InvalidProofOfWork));
+
+        // the ancestors of a known checkpointed header skip the connection checks, other
+        // branches do not
+        let spend = generate_spending_transaction(&H256::from([9u8; 32]), 0);
+        let mine_spending = |tag: u64| loop {
+            let coinbase = Transaction::coinbase_with_extra_nonce(7, tag, &[], TxOutput::new(BLOCK_REWARD, H256::default()));
+            let transactions = vec![coinbase, spend.clone()];
+            let merkle_root = MerkleTree::new(&transactions).root();
+            let block = Block::new(hashes[6], Blockchain::get_difficulty(), transactions, merkle_root);
+            if block.hash() <= block.get_difficulty() {
+                break block;
+            }
+        };
+        let unspendable = mine_spending(7);
+        let checkpointed = generate_mined_block(&unspendable.hash());
+        let sibling = mine_spending(8);
+        blockchain.accept_headers(&[unspendable.get_header().clone(), checkpointed.get_header().clone(), sibling.get_header().clone()]).unwrap();
+        blockchain.add_checkpoint(8, checkpointed.hash());
+        assert_eq!(blockchain.validate(&unspendable), Ok(()));
+        assert!(matches!(blockchain.validate(&sibling), Err(ValidationError::InvalidTransaction(_, _))));
+        assert_eq!(blockchain.validate(&unmined), Err(ValidationError::InvalidProofOfWork));
+    }
+
+    #[test]
+    fn utxo_follows_reorg() {
+        let key = KeyPair::random();
+        let genesis_tx = Transaction::coinbase(0, key.public_key().address(), BLOCK_REWARD);
+        let merkle_root = MerkleTree::new(&[genesis_tx.clone()]).root();
+        let mut blockchain = Blockchain::with_genesis(Block::new(H256::default(), Blockchain::get_difficulty(), vec![genesis_tx.clone()], merkle_root));
+        let genesis_hash = blockchain.tip();
+        let genesis_coin = OutPoint::new(genesis_tx.hash(), 0);
+        assert!(blockchain.utxo(&genesis_coin).is_some());
+
+        let spend = generate_signed_transaction(&genesis_tx.hash(), 0, BLOCK_REWARD, &key);
+        let merkle_root = MerkleTree::new(&[spend.clone()]).root();
+        let block = Block::new(genesis_hash, Blockchain::get_difficulty(), vec![spend.clone()], merkle_root);
+        blockchain.insert(&block).unwrap();
+        assert!(blockchain.utxo(&genesis_coin).is_none());
+        assert_eq!(blockchain.utxo(&OutPoint::new(spend.hash(), 0)), Some(&spend.get_outputs()[0]));
+        assert_eq!(blockchain.find_transaction(&spend.hash()).map(|(_, block)| block), Some(block.hash()));
+
+        // a longer branch without the spend restores the genesis output
+        let fork_1 = generate_random_block(&genesis_hash);
+        let fork_2 = generate_random_block(&fork_1.hash());
+        blockchain.insert(&fork_1).unwrap();
+        blockchain.insert(&fork_2).unwrap();
+        assert_eq!(blockchain.tip(), fork_2.hash());
+        assert!(blockchain.utxo(&genesis_coin).is_some());
+        assert!(blockchain.utxo(&OutPoint::new(spend.hash(), 0)).is_none());
+        // the transactions of a side branch are not indexed
+        assert!(blockchain.find_transaction(&spend.hash()).is_none());
+        assert!(blockchain.find_transaction(&fork_2.get_transactions()[0].hash()).is_some());
+
+        // a longer branch that does not connect is invalidated, and the chain state restored
+        let invalid_1 = generate_random_block(&block.hash());
+        let unsigned = generate_spending_transaction(&spend.hash(), 0);
+        let merkle_root = MerkleTree::new(&[unsigned.clone()]).root();
+        let invalid_2 = Block::new(invalid_1.hash(), Blockchain::get_difficulty(), vec![unsigned.clone()], merkle_root);
+        blockchain.insert(&invalid_1).unwrap();
+        assert_eq!(
+            blockchain.insert(&invalid_2),
+            Err(InsertError::Invalid(invalid_2.hash(), ValidationError::InvalidTransaction(unsigned.hash(), TxError::BadSignature(0))))
+        );
+        assert_eq!(blockchain.tip(), fork_2.hash());
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), vec![fork_2.hash(), fork_1.hash(), genesis_hash]);
+        assert!(blockchain.utxo(&genesis_coin).is_some());
+        assert!(blockchain.utxo(&OutPoint::new(spend.hash(), 0)).is_none());
+        assert_eq!(blockchain.stats(10).transactions, 3);
+        // and its descendants are no longer candidates for the tip
+        let invalid_3 = generate_random_block(&invalid_2.hash());
+        blockchain.insert(&invalid_3).unwrap();
+        assert_eq!(blockchain.tip(), fork_2.hash());
+        // the failure is not cached by the header, another body may be valid
+        assert!(blockchain.bad_block_reason(&invalid_2.hash()).is_none());
+    }
+
+    #[test]
+    fn pruning() {
+        let dir = temp_dir("blockchain_pruning");
+        let depth = 5;
+        let mut hashes: Vec<H256> = Vec::new();
+        let spend;
+        {
+            let mut blockchain = Blockchain::open(Box::new(FileStore::open(&dir).unwrap())).unwrap();
+            blockchain.set_prune_depth(Some(depth));
+            hashes.push(blockchain.tip());
+            let key = KeyPair::random();
+            let funding = Transaction::coinbase(1, key.public_key().address(), BLOCK_REWARD);
+            let merkle_root = MerkleTree::new(&[funding.clone()]).root();
+            let block = Block::new(hashes[0], Blockchain::get_difficulty(), vec![funding.clone()], merkle_root);
+            blockchain.insert(&block).unwrap();
+            hashes.push(block.hash());
+            spend = generate_signed_transaction(&funding.hash(), 0, BLOCK_REWARD, &key);
+            let merkle_root = MerkleTree::new(&[spend.clone()]).root();
+            let block = Block::new(hashes[1], Blockchain::get_difficulty(), vec![spend.clone()], merkle_root);
+            blockchain.insert(&block).unwrap();
+            hashes.push(block.hash());
+            for _ in 2..PRUNE_INTERVAL + depth {
+                let block = generate_random_block(hashes.last().unwrap());
+                blockchain.insert(&block).unwrap();
+                hashes.push(block.hash());
+            }
+            let horizon = (hashes.len() - 1) as u32 - depth;
+            assert_eq!(blockchain.pruned_height(), horizon);
+            assert!(blockchain.get(&hashes[2]).is_pruned());
+            assert!(!blockchain.get(&hashes[horizon as usize + 1]).is_pruned());
+            assert!(blockchain.find_transaction(&spend.hash()).is_none());
+
+            // reorganizations below the pruned blocks are refused
+            let mut parent = hashes[horizon as usize - 1];
+            let mut result = Ok(());
+            for _ in 0..depth + 2 {
+                let block = generate_random_block(&parent);
+                result = blockchain.insert(&block);
+                parent = block.hash();
+            }
+            assert_eq!(result, Err(InsertError::Pruned));
+            assert_eq!(blockchain.tip(), *hashes.last().unwrap());
+        }
+        // the UTXO set survives a restart, and so does the undo data of the blocks kept in full
+        let mut blockchain = Blockchain::open(Box::new(FileStore::open(&dir).unwrap())).unwrap();
+        assert_eq!(blockchain.tip(), *hashes.last().unwrap());
+        assert_eq!(blockchain.utxo(&OutPoint::new(spend.hash(), 0)), Some(&spend.get_outputs()[0]));
+        assert_eq!(blockchain.headers_in_range(0, 3).len(), 4);
+        let fork_1 = generate_random_block(&hashes[hashes.len() - 2]);
+        let fork_2 = generate_random_block(&fork_1.hash());
+        blockchain.insert(&fork_1).unwrap();
+        blockchain.insert(&fork_2).unwrap();
+        assert_eq!(blockchain.tip(), fork_2.hash());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn heaviest_chain_wins() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let genesis_work = blockchain.chain_work(&genesis_hash).unwrap();
+
+        // two blocks of the required target
+        let easy_1 = generate_random_block(&genesis_hash);
+        let easy_2 = generate_random_block(&easy_1.hash());
+        blockchain.insert(&easy_1).unwrap();
+        blockchain.insert(&easy_2).unwrap();
+        assert_eq!(blockchain.tip(), easy_2.hash());
+
+        // a block claiming a target 4 times harder only weighs the target it must have
+        let transactions = vec![Transaction::coinbase(1, H256::default(), BLOCK_REWARD)];
+        let merkle_root = MerkleTree::new(&transactions).root();
+        let hard_target = Blockchain::get_difficulty().mul_div(1, 4);
+        let hard = Block::new(genesis_hash, hard_target, transactions, merkle_root);
+        blockchain.insert(&hard).unwrap();
+        assert_eq!(blockchain.tip(), easy_2.hash());
+        assert_eq!(blockchain.chain_work(&hard.hash()), blockchain.chain_work(&easy_1.hash()));
+        assert_eq!(
+            blockchain.chain_work(&easy_1.hash()).unwrap(),
+            genesis_work.saturating_add(&easy_1.get_difficulty().work())
+        );
+        assert!(blockchain.chain_work(&easy_2.hash()).unwrap() > blockchain.chain_work(&hard.hash()).unwrap());
+    }
+
+    #[test]
+    fn subscriptions() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let receiver = blockchain.subscribe();
+        let dropped = blockchain.subscribe();
+        drop(dropped);
+        let block = generate_random_block(&genesis_hash);
+        blockchain.insert(&block).unwrap();
+        assert_eq!(receiver.try_recv(), Ok(ChainEvent::Connected(block.hash())));
+        assert_eq!(receiver.try_recv(), Ok(ChainEvent::NewTip(block.hash())));
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(blockchain.subscribers.len(), 1);
+    }
+
+    #[test]
+    fn insert_batch() {
+        let mut blockchain = Blockchain::new();
+        let mut parent = blockchain.tip();
+        let mut blocks: Vec<Block> = Vec::new();
+        for _ in 0..5 {
+            let block = generate_random_block(&parent);
+            parent = block.hash();
+            blocks.push(block);
+        }
+        let mut shuffled = blocks.clone();
+        shuffled.swap(1, 2);
+        assert_eq!(blockchain.insert_batch(shuffled), Err(InsertError::NotSequential));
+        assert_eq!(blockchain.num_blocks(), 1);
+        blockchain.insert_batch(blocks.clone()).unwrap();
+        assert_eq!(blockchain.tip(), blocks[4].hash());
+        assert_eq!(blockchain.insert_batch(vec![generate_random_block(&H256::default())]), Err(InsertError::UnknownParent));
+
+        // a batch failing to connect in its middle leaves the chain as it was
+        let valid = generate_random_block(&blocks[4].hash());
+        let transactions = vec![Transaction::coinbase(6, H256::default(), BLOCK_REWARD), generate_spending_transaction(&H256::from([9u8; 32]), 0)];
+        let merkle_root = MerkleTree::new(&transactions).root();
+        let invalid = Block::new(valid.hash(), Blockchain::get_difficulty(), transactions, merkle_root);
+        let after = generate_random_block(&invalid.hash());
+        let batch = vec![valid.clone(), invalid.clone(), after];
+        assert!(matches!(blockchain.insert_batch(batch), Err(InsertError::Invalid(hash, _)) if hash == invalid.hash()));
+        assert_eq!(blockchain.tip(), blocks[4].hash());
+        assert_eq!(blockchain.utxo_set().hash(), {
+            let mut expected = Blockchain::new();
+            expected.insert_batch(blocks.clone()).unwrap();
+            expected.utxo_set().hash()
+        });
+
+        // and a batch refused by a checkpoint stores nothing
+        let stored = blockchain.num_blocks();
+        let next = generate_random_block(&blocks[4].hash());
+        blockchain.add_checkpoint(7, H256::from([7u8; 32]));
+        let batch = vec![next.clone(), generate_random_block(&next.hash())];
+        assert_eq!(blockchain.insert_batch(batch), Err(InsertError::CheckpointMismatch));
+        assert_eq!(blockchain.num_blocks(), stored);
+        assert!(!blockchain.find(&next.hash()));
+    }
+
+    #[test]
+    fn height_index() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let main1 = generate_random_block(&genesis_hash);
+        let main2 = generate_random_block(&main1.hash());
+        blockchain.insert(&main1).unwrap();
+        blockchain.insert(&main2).unwrap();
+        assert_eq!(blockchain.block_at_height(0), Some(genesis_hash));
+        assert_eq!(blockchain.block_at_height(2), Some(main2.hash()));
+        assert_eq!(blockchain.block_at_height(3), None);
+
+        let side1 = generate_random_block(&genesis_hash);
+        let side2 = generate_random_block(&side1.hash());
+        let side3 = generate_random_block(&side2.hash());
+        blockchain.insert(&side1).unwrap();
+        blockchain.insert(&side2).unwrap();
+        assert_eq!(blockchain.block_at_height(1), Some(main1.hash()));
+        blockchain.insert(&side3).unwrap();
+        assert_eq!(blockchain.block_at_height(1), Some(side1.hash()));
+        assert_eq!(blockchain.block_at_height(3), Some(side3.hash()));
+        assert_eq!(blockchain.height_of(&main2.hash()), Some(2));
+        assert!(!blockchain.is_in_longest_chain(&main2.hash()));
+    }
+
+    #[test]
+    fn chain_tips() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let main1 = generate_random_block(&genesis_hash);
+        let main2 = generate_random_block(&main1.hash());
+        let side = generate_random_block(&main1.hash());
+        let stale1 = generate_random_block(&genesis_hash);
+        let stale2 = generate_random_block(&stale1.hash());
+        for block in &[&main1, &main2, &side, &stale1, &stale2] {
+            blockchain.insert(block).unwrap();
+        }
+        let tips = blockchain.chain_tips();
+        assert_eq!(tips.len(), 3);
+        assert_eq!(tips[0], ChainTip { hash: main2.hash(), height: 2, branch_length: 0, status: TipStatus::Active });
+        let stale_tip = tips.iter().find(|t| t.hash == stale2.hash()).unwrap();
+        assert_eq!(stale_tip.branch_length, 2);
+        assert_eq!(stale_tip.status, TipStatus::ValidFork);
+        let side_tip = tips.iter().find(|t| t.hash == side.hash()).unwrap();
+        assert_eq!(side_tip.branch_length, 1);
+
+        // a block found invalid after the fact marks its branch invalid
+        blockchain.bad_blocks.insert(stale1.hash(), ValidationError::InvalidProofOfWork).unwrap();
+        let tips = blockchain.chain_tips();
+        assert_eq!(tips.iter().find(|t| t.hash == stale2.hash()).unwrap().status, TipStatus::Invalid);
+    }
+
+    #[test]
+    fn reindex() {
+        let dir = temp_dir("blockchain_reindex");
+        let mut blockchain = Blockchain::open(Box::new(FileStore::open(&dir).unwrap())).unwrap();
+        let genesis_hash = blockchain.tip();
+        let block1 = generate_random_block(&genesis_hash);
+        let block2 = generate_random_block(&block1.hash());
+        blockchain.insert(&block1).unwrap();
+        blockchain.insert(&block2).unwrap();
+        let utxo_hash = blockchain.utxo_set().hash();
+        drop(blockchain);
+
+        // corrupt the saved tip
+        let mut store = FileStore::open(&dir).unwrap();
+        store.put_meta(TIP_KEY, &bincode::serialize(&genesis_hash).unwrap()).unwrap();
+        drop(store);
+        let mut blockchain = Blockchain::open(Box::new(FileStore::open(&dir).unwrap())).unwrap();
+        assert_eq!(blockchain.tip(), genesis_hash);
+        blockchain.add_checkpoint(1, block1.hash());
+        blockchain.reindex().unwrap();
+        assert_eq!(blockchain.tip(), block2.hash());
+        assert_eq!(blockchain.block_at_height(1), Some(block1.hash()));
+        assert_eq!(blockchain.utxo_set().hash(), utxo_hash);
+        assert_eq!(blockchain.last_checkpoint(), Some((1, block1.hash())));
+        drop(blockchain);
+        let blockchain = Blockchain::open(Box::new(FileStore::open(&dir).unwrap())).unwrap();
+        assert_eq!(blockchain.tip(), block2.hash());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_readers() {
+        let mut blockchain = Blockchain::new();
+        for _ in 0..10 {
+            let block = generate_random_block(&blockchain.tip());
+            blockchain.insert(&block).unwrap();
+        }
+        let blockchain = std::sync::Arc::new(std::sync::RwLock::new(blockchain));
+        // readers hold the read lock at the same time
+        let guard = blockchain.read().unwrap();
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let blockchain = blockchain.clone();
+                std::thread::spawn(move || blockchain.read().unwrap().headers_in_range(0, 10).len())
+            })
+            .collect();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), 11);
+        }
+        drop(guard);
+        let block = generate_random_block(&blockchain.read().unwrap().tip());
+        blockchain.write().unwrap().insert(&block).unwrap();
+        assert_eq!(blockchain.read().unwrap().tip_height(), 11);
+    }
+
+    #[test]
+    fn validation_stages() {
+        let key = KeyPair::random();
+        let genesis_tx = Transaction::coinbase(0, key.public_key().address(), BLOCK_REWARD);
+        let transactions = vec![genesis_tx.clone()];
+        let merkle_root = MerkleTree::new(&transactions).root();
+        let mut blockchain = Blockchain::with_genesis(Block::new(H256::default(), Blockchain::get_difficulty(), transactions, merkle_root));
+        let genesis_hash = blockchain.tip();
+        let mine = |transactions: Vec<Transaction>, merkle_root: H256| loop {
+            let block = Block::new(genesis_hash, Blockchain::get_difficulty(), transactions.clone(), merkle_root);
+            if validation::check_pow(block.get_header()).is_ok() {
+                return block;
+            }
+        };
+
+        let transactions = vec![generate_random_transaction()];
+        let block = mine(transactions, H256::default());
+        let error = blockchain.validate(&block).unwrap_err();
+        assert_eq!(error, ValidationError::BadMerkleRoot);
+        assert_eq!(error.stage(), ValidationStage::Stateless);
+        // the pruned flag of a received block is not trusted
+        let coinbase = vec![Transaction::coinbase(1, key.public_key().address(), BLOCK_REWARD)];
+        let block = mine(coinbase.clone(), MerkleTree::new(&coinbase).root());
+        assert_eq!(validation::check_stateless(&block.pruned()), Err(ValidationError::BadMerkleRoot));
+
+        // a block has a single coinbase, its first transaction
+        let reward = Transaction::coinbase(1, key.public_key().address(), BLOCK_REWARD);
+        let spend = generate_signed_transaction(&genesis_tx.hash(), 0, BLOCK_REWARD, &key);
+        for transactions in vec![vec![spend.clone()], vec![spend.clone(), reward.clone()], vec![reward.clone(), reward.clone()]] {
+            let block = mine(transactions.clone(), MerkleTree::new(&transactions).root());
+            assert_eq!(blockchain.validate(&block), Err(ValidationError::BadCoinbasePosition));
+        }
+
+        let spend_again = generate_signed_transaction(&genesis_tx.hash(), 0, 10, &key);
+        let transactions = vec![reward.clone(), spend.clone(), spend_again];
+        let block = mine(transactions.clone(), MerkleTree::new(&transactions).root());
+        let error = blockchain.validate(&block).unwrap_err();
+        assert_eq!(error, ValidationError::DoubleSpend(OutPoint::new(genesis_tx.hash(), 0)));
+        assert_eq!(error.stage(), ValidationStage::Connect);
+
+        let missing = generate_signed_transaction(&H256::from([7u8; 32]), 0, 10, &key);
+        let transactions = vec![reward.clone(), missing.clone()];
+        let block = mine(transactions.clone(), MerkleTree::new(&transactions).root());
+        let missing_input = TxError::MissingInput(OutPoint::new(H256::from([7u8; 32]), 0));
+        assert_eq!(blockchain.validate(&block), Err(ValidationError::InvalidTransaction(missing.hash(), missing_input)));
+
+        let unsigned = generate_spending_transaction(&genesis_tx.hash(), 0);
+        let transactions = vec![reward.clone(), unsigned.clone()];
+        let block = mine(transactions.clone(), MerkleTree::new(&transactions).root());
+        assert_eq!(blockchain.validate(&block), Err(ValidationError::InvalidTransaction(unsigned.hash(), TxError::BadSignature(0))));
+
+        // the coinbase may claim the fee of the spend
+        let spend_with_fee = generate_signed_transaction(&genesis_tx.hash(), 0, BLOCK_REWARD - 5, &key);
+        let coinbase = Transaction::coinbase(1, key.public_key().address(), BLOCK_REWARD + 5);
+        let transactions = vec![coinbase, spend_with_fee.clone()];
+        let block = mine(transactions.clone(), MerkleTree::new(&transactions).root());
+        assert_eq!(blockchain.validate(&block), Ok(()));
+        let greedy = Transaction::coinbase(1, key.public_key().address(), BLOCK_REWARD + 6);
+        let transactions = vec![greedy, spend_with_fee];
+        let block = mine(transactions.clone(), MerkleTree::new(&transactions).root());
+        assert_eq!(blockchain.validate(&block), Err(ValidationError::BadCoinbaseValue));
+        // an overflowing coinbase does not wrap around to a small value, and outputs are bounded
+        // each and together
+        let address = key.public_key().address();
+        let max_money = crate::transaction::MAX_MONEY;
+        for outputs in vec![vec![u64::MAX, 2], vec![max_money, max_money]] {
+            let mut coinbase = Transaction::coinbase(1, address, BLOCK_REWARD);
+            coinbase.set_outputs(outputs.iter().map(|value| TxOutput::new(*value, address)).collect());
+            let transactions = vec![coinbase.clone()];
+            let block = mine(transactions.clone(), MerkleTree::new(&transactions).root());
+            assert_eq!(blockchain.validate(&block), Err(ValidationError::InvalidTransaction(coinbase.txid(), TxError::ValueOutOfRange)));
+        }
+
+        // spending an output created earlier in the same block is fine
+        let chained = generate_signed_transaction(&spend.hash(), 0, BLOCK_REWARD, &key);
+        let transactions = vec![reward, spend, chained];
+        let block = mine(transactions.clone(), MerkleTree::new(&transactions).root());
+        assert_eq!(blockchain.validate(&block), Ok(()));
+    }
+
+    #[test]
+    fn invalidate_and_reconsider() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let main1 = generate_random_block(&genesis_hash);
+        let main2 = generate_random_block(&main1.hash());
+        let main3 = generate_random_block(&main2.hash());
+        let side1 = generate_random_block(&genesis_hash);
+        let side2 = generate_random_block(&side1.hash());
+        for block in &[&main1, &main2, &main3, &side1, &side2] {
+            blockchain.insert(block).unwrap();
+        }
+        assert_eq!(blockchain.tip(), main3.hash());
+
+        // the side branch has more work than what remains of the main one
+        blockchain.invalidate_block(&main2.hash()).unwrap();
+        assert_eq!(blockchain.tip(), side2.hash());
+        assert_eq!(blockchain.header_tree().best(), side2.hash());
+        assert_eq!(
+            blockchain.chain_tips().iter().find(|t| t.hash == main3.hash()).unwrap().status,
+            TipStatus::Invalid
+        );
+        // descendants of an invalidated block never become the tip
+        let main4 = generate_random_block(&main3.hash());
+        blockchain.insert(&main4).unwrap();
+        assert_eq!(blockchain.tip(), side2.hash());
+
+        // invalidating the whole side branch falls back to main1
+        blockchain.invalidate_block(&side1.hash()).unwrap();
+        assert_eq!(blockchain.tip(), main1.hash());
+
+        blockchain.reconsider_block(&main2.hash()).unwrap();
+        assert_eq!(blockchain.tip(), main4.hash());
+        assert_eq!(blockchain.header_tree().best(), main4.hash());
+        assert_eq!(blockchain.reconsider_block(&main2.hash()), Err(InsertError::UnknownBlock));
+        assert_eq!(blockchain.invalidate_block(&genesis_hash), Err(InsertError::UnknownBlock));
+    }
+
+    #[test]
+    fn stats() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let start = blockchain.get(&genesis_hash).get_timestamp();
+        let mut parent = genesis_hash;
+        for i in 1..=4 {
+            let block = generate_random_block_at(&parent, start + Duration::from_secs(10 * i));
+            blockchain.insert(&block).unwrap();
+            parent = block.hash();
+        }
+        blockchain.insert(&generate_random_block(&genesis_hash)).unwrap();
+        let stats = blockchain.stats(2);
+        assert_eq!(stats.blocks, 6);
+        assert_eq!(stats.height, 4);
+        assert_eq!(stats.transactions, 5);
+        assert_eq!(stats.average_block_time_ms, 10_000);
+        assert_eq!(stats.chain_work, blockchain.chain_work(&parent).unwrap());
+        assert_eq!(stats.difficulty, Blockchain::get_difficulty());
+        assert_eq!(stats.stale_blocks, 1);
+        assert_eq!(stats.orphans, 0);
+        assert_eq!(blockchain.stats(100).average_block_time_ms, 10_000);
+        assert_eq!(Blockchain::new().stats(10).average_block_time_ms, 0);
+    }
+
+    #[test]
+    fn headers_first() {
+        let mut blockchain = Blockchain::new();
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut parent = blockchain.tip();
+        for _ in 0..3 {
+            let block = generate_mined_block(&parent);
+            parent = block.hash();
+            blocks.push(block);
+        }
+        let headers: Vec<Header> = blocks.iter().map(|b| b.get_header().clone()).collect();
+        assert_eq!(blockchain.accept_headers(&headers), Ok(3));
+        assert_eq!(blockchain.header_tree().best(), parent);
+        assert_eq!(blockchain.tip_height(), 0);
+        blockchain.insert(&blocks[0]).unwrap();
+        let missing: Vec<H256> = blocks[1..].iter().map(|b| b.hash()).collect();
+        assert_eq!(blockchain.header_tree().missing_bodies(10), missing);
+
+        let mut fresh = Blockchain::with_genesis(blockchain.get(&blockchain.block_at_height(0).unwrap()));
+        fresh.bad_blocks.insert(blocks[2].hash(), ValidationError::BadMerkleRoot).unwrap();
+        assert_eq!(fresh.accept_headers(&headers), Err(HeaderError::Invalid(ValidationError::BadMerkleRoot)));
+    }
+
+    #[test]
+    fn retarget() {
+        let mut blockchain = Blockchain::new();
+        let genesis = blockchain.get(&blockchain.tip());
+        let start = genesis.get_timestamp();
+        let mut parent = genesis.hash();
+        // blocks come twice as fast as expected
+        for i in 1..RETARGET_INTERVAL {
+            assert_eq!(blockchain.next_difficulty(&parent), Blockchain::get_difficulty());
+            let block = generate_random_block_at(&parent, start + TARGET_BLOCK_TIME * i / 2);
+            blockchain.insert(&block).unwrap();
+            parent = block.hash();
+        }
+        let expected = Blockchain::get_difficulty().mul_div(1, 2);
+        assert_eq!(blockchain.next_difficulty(&parent), expected);
+
+        let block = generate_random_block_at(&parent, start + TARGET_BLOCK_TIME * RETARGET_INTERVAL);
+        assert_eq!(blockchain.validate_contextual(&block), Err(ValidationError::WrongDifficulty));
+    }
+
+    #[test]
+    fn reorg_events() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let main1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&main1).unwrap();
+        let main2 = generate_random_block(&main1.hash());
+        blockchain.insert(&main2).unwrap();
+        assert_eq!(
+            blockchain.take_events(),
+            vec![
+                ChainEvent::Connected(main1.hash()),
+                ChainEvent::NewTip(main1.hash()),
+                ChainEvent::Connected(main2.hash()),
+                ChainEvent::NewTip(main2.hash()),
+            ]
+        );
+        let side1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&side1).unwrap();
+        let side2 = generate_random_block(&side1.hash());
+        blockchain.insert(&side2).unwrap();
+        assert!(blockchain.take_events().is_empty());
+        let side3 = generate_random_block(&side2.hash());
+        blockchain.insert(&side3).unwrap();
+        assert_eq!(
+            blockchain.take_events(),
+            vec![
+                ChainEvent::Disconnected(main2.hash()),
+                ChainEvent::Disconnected(main1.hash()),
+                ChainEvent::Connected(side1.hash()),
+                ChainEvent::Connected(side2.hash()),
+                ChainEvent::Connected(side3.hash()),
+                ChainEvent::NewTip(side3.hash()),
+            ]
+        );
+
+        assert_eq!(blockchain.fork_point(&main2.hash(), &side3.hash()), Some(genesis_hash));
+        // explicitly switching back
+        let events = blockchain.reorg_to(&main2.hash()).unwrap();
+        assert_eq!(events.len(), 6);
+        assert_eq!(blockchain.tip(), main2.hash());
+        assert_eq!(blockchain.reorg_to(&H256::default()), Err(InsertError::UnknownBlock));
+
+        // events not taken are bounded, the oldest being dropped
+        let mut parent = main2.hash();
+        for _ in 0..MAX_PENDING_EVENTS {
+            let block = generate_random_block(&parent);
+            blockchain.insert(&block).unwrap();
+            parent = block.hash();
+        }
+        let events = blockchain.take_events();
+        assert_eq!(events.len(), MAX_PENDING_EVENTS);
+        assert_eq!(events.last(), Some(&ChainEvent::NewTip(parent)));
+    }
+
+    #[test]
+    fn fork_switch() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let main1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&main1).unwrap();
+        let side1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&side1).unwrap();
+        // equal length, the first seen branch stays
+        assert_eq!(blockchain.tip(), main1.hash());
+        let side2 = generate_random_block(&side1.hash());
+        blockchain.insert(&side2).unwrap();
+        assert_eq!(blockchain.tip(), side2.hash());
+        assert_eq!(blockchain.tip_height(), 2);
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), vec![side2.hash(), side1.hash(), genesis_hash]);
+    }
 }
@@ -0,0 +1,259 @@
+//! Bloom filters of light clients, after BIP37: a client loads a filter of its addresses and
+//! outpoints into a peer, which then only relays the matching transactions, and serves blocks
+//! as their header with the matching transactions and their Merkle proofs. The filter gives the
+//! client some privacy by matching transactions of others too.
+
+use serde::{Serialize, Deserialize};
+
+use crate::block::{Block, Header};
+use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::merkle::{MerkleProof, MerkleTree};
+use crate::script::Instruction;
+use crate::transaction::Transaction;
+use crate::utxo::OutPoint;
+
+/// Most bytes of a filter
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36_000;
+/// Most hash functions of a filter
+pub const MAX_HASH_FUNCS: u32 = 50;
+/// Most bytes of an element added with `Message::FilterAdd`
+pub const MAX_FILTER_ADD_SIZE: usize = 520;
+
+/// Multiplier of the hash function number in the seed of each hash, as in BIP37
+const SEED_MULTIPLIER: u32 = 0xfba4_c795;
+
+/// How a filter grows as transactions match
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BloomFlags {
+    /// The filter never changes
+    UpdateNone,
+    /// The outpoints of matching outputs are added, so that their spending transactions match
+    UpdateAll,
+}
+
+/// A Bloom filter of the elements a light client cares about
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    data: Vec<u8>,
+    hash_funcs: u32,
+    /// Random value mixed into the hashes, so that filters of the same elements differ
+    tweak: u32,
+    flags: BloomFlags,
+}
+
+/// The MurmurHash3 (x86, 32 bits) of `data`, the hash of BIP37
+pub fn murmur3(seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let mut h = seed;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h ^= k;
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+    let tail = chunks.remainder();
+    let mut k: u32 = 0;
+    for (i, byte) in tail.iter().enumerate() {
+        k |= (*byte as u32) << (8 * i);
+    }
+    if !tail.is_empty() {
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h ^= k;
+    }
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    return h;
+}
+
+/// The bytes an outpoint is added to a filter as: the txid followed by the little endian index
+fn outpoint_bytes(outpoint: &OutPoint) -> Vec<u8> {
+    let mut bytes = outpoint.txid.as_ref().to_vec();
+    bytes.extend_from_slice(&outpoint.index.to_le_bytes());
+    return bytes;
+}
+
+/// The data pushed by a script, the elements matched against filters
+fn pushes(script: &[u8]) -> Vec<Vec<u8>> {
+    return match crate::script::Script::from_bytes(script.to_vec()).instructions() {
+        Ok(instructions) => instructions
+            .into_iter()
+            .filter_map(|i| match i {
+                Instruction::Push(data) if !data.is_empty() => Some(data),
+                _ => None,
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+}
+
+impl BloomFilter {
+    /// A filter sized for `elements` elements with a false positive rate of `fp_rate`, within
+    /// the size limits
+    pub fn new(elements: usize, fp_rate: f64, tweak: u32, flags: BloomFlags) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let bits = -1.0 / (ln2 * ln2) * elements.max(1) as f64 * fp_rate.ln();
+        let size = ((bits / 8.0) as usize).clamp(1, MAX_BLOOM_FILTER_SIZE);
+        let hash_funcs = ((size * 8) as f64 / elements.max(1) as f64 * ln2) as u32;
+        return BloomFilter {
+            data: vec![0u8; size],
+            hash_funcs: hash_funcs.clamp(1, MAX_HASH_FUNCS),
+            tweak,
+            flags,
+        };
+    }
+
+    /// Whether the filter is within the limits a node accepts
+    pub fn is_within_limits(&self) -> bool {
+        return !self.data.is_empty() && self.data.len() <= MAX_BLOOM_FILTER_SIZE && self.hash_funcs <= MAX_HASH_FUNCS;
+    }
+
+    fn bit(&self, n: u32, element: &[u8]) -> usize {
+        let seed = n.wrapping_mul(SEED_MULTIPLIER).wrapping_add(self.tweak);
+        return murmur3(seed, element) as usize % (self.data.len() * 8);
+    }
+
+    pub fn insert(&mut self, element: &[u8]) {
+        if self.data.is_empty() {
+            return;
+        }
+        for n in 0..self.hash_funcs {
+            let bit = self.bit(n, element);
+            self.data[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    pub fn contains(&self, element: &[u8]) -> bool {
+        if self.data.is_empty() {
+            return false;
+        }
+        return (0..self.hash_funcs).all(|n| {
+            let bit = self.bit(n, element);
+            self.data[bit / 8] & (1 << (bit % 8)) != 0
+        });
+    }
+
+    pub fn insert_outpoint(&mut self, outpoint: &OutPoint) {
+        self.insert(&outpoint_bytes(outpoint));
+    }
+
+    /// Whether the transaction is relevant to the filter: its txid, the recipient or a data push
+    /// of one of its outputs, or the outpoint or a data push of one of its inputs is in it. With
+    /// `BloomFlags::UpdateAll`, the outpoints of the matching outputs are added.
+    pub fn matches(&mut self, transaction: &Transaction) -> bool {
+        let txid = transaction.txid();
+        let mut matched = self.contains(txid.as_ref());
+        for (index, output) in transaction.get_outputs().iter().enumerate() {
+            let mut elements = vec![output.recipient.as_ref().to_vec()];
+            elements.extend(pushes(output.script_pubkey.as_bytes()));
+            if elements.iter().any(|e| self.contains(e)) {
+                matched = true;
+                if self.flags == BloomFlags::UpdateAll {
+                    self.insert_outpoint(&OutPoint::new(txid, index as u32));
+                }
+            }
+        }
+        if matched {
+            return true;
+        }
+        for input in transaction.get_inputs() {
+            if self.contains(&outpoint_bytes(&input.outpoint())) || pushes(&input.script).iter().any(|e| self.contains(e)) {
+                return true;
+            }
+        }
+        return false;
+    }
+}
+
+/// A block as served to a light client: its header, and the transactions matching its filter
+/// with their proof of inclusion
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FilteredBlock {
+    pub header: Header,
+    pub transactions: Vec<(Transaction, MerkleProof)>,
+}
+
+impl FilteredBlock {
+    /// Filter the transactions of `block`, updating the filter as they match
+    pub fn new(block: &Block, filter: &mut BloomFilter) -> Self {
+        let transactions = block.get_transactions();
+        let merkle_tree = MerkleTree::new(transactions);
+        let mut matched: Vec<(Transaction, MerkleProof)> = Vec::new();
+        for (index, transaction) in transactions.iter().enumerate() {
+            if filter.matches(transaction) {
+                matched.push((transaction.clone(), MerkleProof::new(&merkle_tree, index, transactions.len())));
+            }
+        }
+        return FilteredBlock {
+            header: block.get_header().clone(),
+            transactions: matched,
+        };
+    }
+
+    /// Whether each transaction is proven to be in the block of the header
+    pub fn verify(&self) -> bool {
+        let root = self.header.get_merkle_root();
+        return self.transactions.iter().all(|(transaction, proof)| proof.verify(&root, &transaction.hash()));
+    }
+}
+
+impl Hashable for FilteredBlock {
+    fn hash(&self) -> H256 {
+        return self.header.hash();
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::crypto::merkle::MerkleTree;
+    use crate::transaction::{TxInput, TxOutput, BLOCK_REWARD};
+
+    #[test]
+    fn murmur() {
+        // test vectors of MurmurHash3 x86_32
+        assert_eq!(murmur3(0, b""), 0);
+        assert_eq!(murmur3(1, b""), 0x514e_28b7);
+        assert_eq!(murmur3(0xffff_ffff, b""), 0x81f1_6f39);
+        assert_eq!(murmur3(0, &[0xff, 0xff, 0xff, 0xff]), 0x7629_3b50);
+        assert_eq!(murmur3(0x9747_b28c, b"Hello, world!"), 0x2488_4cba);
+        assert_eq!(murmur3(0x9747_b28c, b"abc"), 0xc84a_62dd);
+    }
+
+    #[test]
+    fn filtering() {
+        let mut filter = BloomFilter::new(10, 0.0001, 5, BloomFlags::UpdateAll);
+        assert!(filter.is_within_limits());
+        assert!(!filter.contains(b"alice"));
+        filter.insert(b"alice");
+        assert!(filter.contains(b"alice"));
+        assert_eq!(BloomFilter::new(1_000_000, 0.0000001, 0, BloomFlags::UpdateNone).data.len(), MAX_BLOOM_FILTER_SIZE);
+
+        let mine = H256::from([3u8; 32]);
+        filter.insert(mine.as_ref());
+        let paying = Transaction::new(vec![TxInput::new(H256::from([9u8; 32]), 0)], vec![TxOutput::new(5, H256::from([4u8; 32])), TxOutput::new(7, mine)]);
+        let spending = Transaction::new(vec![TxInput::new(paying.txid(), 1)], vec![TxOutput::new(6, H256::from([5u8; 32]))]);
+        let unrelated = Transaction::new(vec![TxInput::new(H256::from([8u8; 32]), 0)], vec![TxOutput::new(6, H256::from([5u8; 32]))]);
+        // the spending transaction matches through the outpoint added by the payment
+        assert!(!filter.clone().matches(&spending));
+        assert!(filter.matches(&paying));
+        assert!(filter.matches(&spending));
+        assert!(!filter.matches(&unrelated));
+
+        let transactions = vec![Transaction::coinbase(0, H256::from([1u8; 32]), BLOCK_REWARD), unrelated, paying.clone(), spending];
+        let merkle_root = MerkleTree::new(&transactions).root();
+        let block = Block::new(H256::default(), H256::default(), transactions, merkle_root);
+        let mut fresh = BloomFilter::new(10, 0.0001, 5, BloomFlags::UpdateAll);
+        fresh.insert(mine.as_ref());
+        let filtered = FilteredBlock::new(&block, &mut fresh);
+        assert_eq!(filtered.hash(), block.hash());
+        assert_eq!(filtered.transactions.len(), 2);
+        assert_eq!(filtered.transactions[0].0.txid(), paying.txid());
+        assert!(filtered.verify());
+    }
+}
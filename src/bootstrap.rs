@@ -0,0 +1,275 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::block::Block;
+use crate::blockchain::{Blockchain, InsertError};
+use crate::crypto::hash::{H256, Hashable};
+use crate::network::peer::MAX_MESSAGE_SIZE;
+use crate::validation::ValidationError;
+
+/// Largest record read or written, as a block must fit in a network message
+pub const MAX_RECORD_SIZE: usize = MAX_MESSAGE_SIZE;
+
+/// Reasons for a bootstrap file to be refused
+#[derive(Debug)]
+pub enum BootstrapError {
+    Io(std::io::Error),
+    Encoding(bincode::Error),
+    /// The file contains no block
+    Empty,
+    /// The file starts from another genesis block than the blockchain
+    GenesisMismatch(H256),
+    /// The blocks of the longest chain are pruned, so they cannot be exported
+    Pruned,
+    /// A block of the file failed validation
+    Invalid(H256, ValidationError),
+    /// A block of the file could not be inserted
+    Insert(H256, InsertError),
+}
+
+impl std::fmt::Display for BootstrapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BootstrapError::Io(e) => write!(f, "i/o error: {}", e),
+            BootstrapError::Encoding(e) => write!(f, "encoding error: {}", e),
+            BootstrapError::Empty => write!(f, "bootstrap file contains no blocks"),
+            BootstrapError::GenesisMismatch(hash) => write!(f, "unexpected genesis block {}", hash),
+            BootstrapError::Pruned => write!(f, "blocks of the longest chain are pruned"),
+            BootstrapError::Invalid(hash, e) => write!(f, "invalid block {}: {}", hash, e),
+            BootstrapError::Insert(hash, e) => write!(f, "error inserting block {}: {}", hash, e),
+        }
+    }
+}
+
+impl From<std::io::Error> for BootstrapError {
+    fn from(e: std::io::Error) -> Self {
+        BootstrapError::Io(e)
+    }
+}
+
+impl From<bincode::Error> for BootstrapError {
+    fn from(e: bincode::Error) -> Self {
+        BootstrapError::Encoding(e)
+    }
+}
+
+/// Write `bytes` as a record: its length, as 4 little-endian bytes, followed by the bytes
+pub fn write_record<W: Write>(writer: &mut W, bytes: &[u8]) -> std::io::Result<()> {
+    if bytes.len() > MAX_RECORD_SIZE {
+        return Err(record_too_large(bytes.len()));
+    }
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(bytes)?;
+    return Ok(());
+}
+
+fn record_too_large(length: usize) -> std::io::Error {
+    let message = format!("record of {} bytes exceeds {} bytes", length, MAX_RECORD_SIZE);
+    return std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+}
+
+/// Reads the records written by `write_record`, refusing those larger than `MAX_RECORD_SIZE`
+/// before allocating them
+pub struct RecordReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> RecordReader<R> {
+    pub fn new(reader: R) -> Self {
+        return RecordReader { reader };
+    }
+}
+
+impl<R: Read> Iterator for RecordReader<R> {
+    type Item = std::io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut length = [0u8; 4];
+        match self.reader.read_exact(&mut length) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return None,
+            Err(e) => return Some(Err(e)),
+        }
+        let length = u32::from_le_bytes(length) as usize;
+        if length > MAX_RECORD_SIZE {
+            return Some(Err(record_too_large(length)));
+        }
+        let mut bytes = vec![0u8; length];
+        if let Err(e) = self.reader.read_exact(&mut bytes) {
+            return Some(Err(e));
+        }
+        return Some(Ok(bytes));
+    }
+}
+
+/// Writes blocks to a bootstrap file, each as a record holding its bincode encoding
+pub struct BootstrapWriter {
+    file: BufWriter<File>,
+}
+
+impl BootstrapWriter {
+    pub fn create(path: &Path) -> Result<Self, BootstrapError> {
+        return Ok(BootstrapWriter {
+            file: BufWriter::new(File::create(path)?),
+        });
+    }
+
+    pub fn write(&mut self, block: &Block) -> Result<(), BootstrapError> {
+        write_record(&mut self.file, &bincode::serialize(block)?)?;
+        return Ok(());
+    }
+
+    pub fn finish(mut self) -> Result<(), BootstrapError> {
+        self.file.flush()?;
+        return Ok(());
+    }
+}
+
+/// Reads the blocks of a bootstrap file one at a time, see `BootstrapWriter`
+pub struct BootstrapReader {
+    records: RecordReader<BufReader<File>>,
+}
+
+impl BootstrapReader {
+    pub fn open(path: &Path) -> Result<Self, BootstrapError> {
+        return Ok(BootstrapReader {
+            records: RecordReader::new(BufReader::new(File::open(path)?)),
+        });
+    }
+}
+
+impl Iterator for BootstrapReader {
+    type Item = Result<Block, BootstrapError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let bytes = match self.records.next()? {
+            Ok(bytes) => bytes,
+            Err(e) => return Some(Err(e.into())),
+        };
+        return Some(bincode::deserialize(&bytes).map_err(BootstrapError::from));
+    }
+}
+
+/// Read the genesis block of a bootstrap file, to start a blockchain that can import it
+pub fn read_genesis(path: &Path) -> Result<Block, BootstrapError> {
+    return BootstrapReader::open(path)?.next().unwrap_or(Err(BootstrapError::Empty));
+}
+
+impl Blockchain {
+    /// Write the blocks of the longest chain, from the genesis block to the tip, to a bootstrap
+    /// file. Returns the number of blocks written.
+    pub fn export(&self, path: &Path) -> Result<u32, BootstrapError> {
+        if self.pruned_height() > 0 {
+            return Err(BootstrapError::Pruned);
+        }
+        let mut writer = BootstrapWriter::create(path)?;
+        for height in 0..=self.tip_height() {
+            writer.write(&self.get(&self.block_at_height(height).unwrap()))?;
+        }
+        writer.finish()?;
+        return Ok(self.tip_height() + 1);
+    }
+
+    /// Validate and insert the blocks of a bootstrap file, which must start from the same genesis
+    /// block. Blocks already in the blockchain are skipped. Returns the number of blocks inserted.
+    pub fn import(&mut self, path: &Path) -> Result<u32, BootstrapError> {
+        let genesis_hash = self.block_at_height(0).unwrap();
+        let mut imported = 0;
+        for (i, block) in BootstrapReader::open(path)?.enumerate() {
+            let block = block?;
+            let hash = block.hash();
+            if i == 0 && hash != genesis_hash {
+                return Err(BootstrapError::GenesisMismatch(hash));
+            }
+            if self.find(&hash) {
+                continue;
+            }
+            self.validate(&block).map_err(|e| BootstrapError::Invalid(hash, e))?;
+            self.insert(&block).map_err(|e| BootstrapError::Insert(hash, e))?;
+            imported += 1;
+        }
+        return Ok(imported);
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_mined_block_with_height;
+    use crate::crypto::merkle::MerkleTree;
+    use crate::transaction::tests::generate_random_transaction;
+
+    fn temp_path() -> std::path::PathBuf {
+        return std::env::temp_dir().join(format!("bootstrap_{}", rand::random::<u32>()));
+    }
+
+    #[test]
+    fn export_import() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut parent = genesis_hash;
+        for height in 1..=5 {
+            let block = generate_mined_block_with_height(&parent, height);
+            blockchain.insert(&block).unwrap();
+            parent = block.hash();
+        }
+        // a stale block is not exported
+        blockchain.insert(&generate_mined_block_with_height(&genesis_hash, 1)).unwrap();
+        let path = temp_path();
+        assert_eq!(blockchain.export(&path).unwrap(), 6);
+
+        let mut imported = Blockchain::with_genesis(read_genesis(&path).unwrap());
+        assert_eq!(imported.import(&path).unwrap(), 5);
+        assert_eq!(imported.tip(), blockchain.tip());
+        assert_eq!(imported.num_blocks(), 6);
+        // importing again skips the known blocks
+        assert_eq!(imported.import(&path).unwrap(), 0);
+
+        // genesis blocks only differ by their difficulty
+        match Blockchain::regtest().import(&path) {
+            Err(BootstrapError::GenesisMismatch(hash)) => assert_eq!(hash, genesis_hash),
+            _ => panic!("bootstrap file accepted with another genesis block"),
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_block() {
+        let blockchain = Blockchain::new();
+        let genesis = blockchain.get(&blockchain.tip());
+        let transactions = vec![generate_random_transaction()];
+        let merkle_root = MerkleTree::new(&transactions).root();
+        // no hash meets a zero target
+        let block = Block::new(genesis.hash(), H256::default(), transactions, merkle_root);
+        let path = temp_path();
+        let mut writer = BootstrapWriter::create(&path).unwrap();
+        writer.write(&genesis).unwrap();
+        writer.write(&block).unwrap();
+        writer.finish().unwrap();
+        let mut imported = Blockchain::with_genesis(genesis);
+        match imported.import(&path) {
+            Err(BootstrapError::Invalid(hash, ValidationError::InvalidProofOfWork)) => assert_eq!(hash, block.hash()),
+            _ => panic!("invalid block imported"),
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn oversized_record() {
+        let blockchain = Blockchain::new();
+        let genesis = blockchain.get(&blockchain.tip());
+        let path = temp_path();
+        let mut writer = BootstrapWriter::create(&path).unwrap();
+        writer.write(&genesis).unwrap();
+        writer.file.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        writer.finish().unwrap();
+        let mut imported = Blockchain::with_genesis(genesis);
+        match imported.import(&path) {
+            Err(BootstrapError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+            _ => panic!("oversized record read"),
+        }
+        assert!(write_record(&mut Vec::new(), &vec![0u8; MAX_RECORD_SIZE + 1]).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,458 @@
+use crate::coin_selection::{Candidate, CoinSelector, LargestFirst};
+use crate::crypto::keys;
+use crate::crypto::ripemd160::hash160;
+use crate::crypto::schnorr;
+use crate::crypto::hash::H256;
+use crate::fee::FeeRate;
+use crate::fee_estimator::FeeEstimator;
+use crate::policy::DEFAULT_DUST_THRESHOLD;
+use crate::script::Script;
+use crate::size::{InputType, KeyType};
+use crate::transaction::{Amount, InputSignature, SigHashType, SignError, SignatureScheme, Transaction, TxInput, TxOutput, SEQUENCE_FINAL, SEQUENCE_MAX_REPLACEABLE};
+use crate::utxo::OutPoint;
+
+/// Change below this value is left to the fee rather than creating a dust output, which would
+/// not be relayed
+pub const MIN_CHANGE: Amount = DEFAULT_DUST_THRESHOLD;
+
+/// Bytes added to the measured size of each input when computing the fee, as a new ECDSA
+/// signature may be up to 2 bytes longer than the one measured
+const SIGNATURE_SLACK: usize = 2;
+
+/// Reasons for `TransactionBuilder::build` to fail
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    /// No output to pay
+    NoOutputs,
+    /// The outputs the keys can spend are not worth the payments and the fee
+    InsufficientFunds { available: Amount, needed: Amount },
+    ValueOverflow,
+    Sign(SignError),
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BuildError::NoOutputs => write!(f, "no outputs"),
+            BuildError::InsufficientFunds { available, needed } => {
+                write!(f, "insufficient funds: {} available, {} needed", available, needed)
+            }
+            BuildError::ValueOverflow => write!(f, "value overflow"),
+            BuildError::Sign(e) => write!(f, "signing failed: {}", e),
+        }
+    }
+}
+
+impl From<SignError> for BuildError {
+    fn from(e: SignError) -> Self {
+        BuildError::Sign(e)
+    }
+}
+
+/// Builds a signed transaction paying the given outputs from the given unspent outputs: it
+/// selects the inputs with a `CoinSelector`, largest-first by default, adds change, and signs
+/// each input with the key able to spend it.
+pub struct TransactionBuilder<'a> {
+    utxos: Vec<(OutPoint, TxOutput)>,
+    outputs: Vec<TxOutput>,
+    /// Template of the change output, whose value is set by `build`
+    change: Option<TxOutput>,
+    fee_rate: FeeRate,
+    keys: Vec<&'a keys::KeyPair>,
+    schnorr_keys: Vec<&'a schnorr::KeyPair>,
+    selector: Box<dyn CoinSelector>,
+    lock_time: u32,
+    /// Sequence number of all inputs, by default the highest that lets the lock time apply
+    sequence: Option<u32>,
+}
+
+impl<'a> TransactionBuilder<'a> {
+    pub fn new(fee_rate: FeeRate) -> Self {
+        return TransactionBuilder {
+            utxos: Vec::new(),
+            outputs: Vec::new(),
+            change: None,
+            fee_rate,
+            keys: Vec::new(),
+            schnorr_keys: Vec::new(),
+            selector: Box::new(LargestFirst),
+            lock_time: 0,
+            sequence: None,
+        };
+    }
+
+    /// A builder paying the fee rate `estimator` suggests for the transaction to be mined within
+    /// `target_blocks`
+    pub fn with_estimate(estimator: &FeeEstimator, target_blocks: usize) -> Self {
+        return Self::new(estimator.suggest_feerate(target_blocks));
+    }
+
+    /// Add unspent outputs the transaction may spend. Those no key can spend are ignored.
+    pub fn utxos(mut self, utxos: Vec<(OutPoint, TxOutput)>) -> Self {
+        self.utxos.extend(utxos);
+        return self;
+    }
+
+    pub fn output(mut self, output: TxOutput) -> Self {
+        self.outputs.push(output);
+        return self;
+    }
+
+    /// Pay `value` to the ECDSA key whose address is `recipient`
+    pub fn pay_to(self, recipient: H256, value: Amount) -> Self {
+        return self.output(TxOutput::new(value, recipient));
+    }
+
+    /// Add an output carrying `data`, see `TxOutput::data`
+    pub fn data(self, data: &[u8]) -> Self {
+        return self.output(TxOutput::data(data));
+    }
+
+    /// Send the change to the ECDSA key whose address is `recipient`. Without a change
+    /// destination, all the change goes to the fee.
+    pub fn change_address(mut self, recipient: H256) -> Self {
+        self.change = Some(TxOutput::new(0, recipient));
+        return self;
+    }
+
+    /// Send the change to an output locked by `script_pubkey`
+    pub fn change_script(mut self, script_pubkey: Script) -> Self {
+        self.change = Some(TxOutput::with_script(0, script_pubkey));
+        return self;
+    }
+
+    pub fn key(mut self, key: &'a keys::KeyPair) -> Self {
+        self.keys.push(key);
+        return self;
+    }
+
+    pub fn schnorr_key(mut self, key: &'a schnorr::KeyPair) -> Self {
+        self.schnorr_keys.push(key);
+        return self;
+    }
+
+    /// Choose the inputs with `selector`. If it finds no selection, largest-first is used.
+    pub fn selector(mut self, selector: Box<dyn CoinSelector>) -> Self {
+        self.selector = selector;
+        return self;
+    }
+
+    /// Prevent the transaction from being in a block before `lock_time`, a height or a time, see
+    /// `Transaction::is_final`
+    pub fn lock_time(mut self, lock_time: u32) -> Self {
+        self.lock_time = lock_time;
+        return self;
+    }
+
+    /// Set the sequence number of all inputs
+    pub fn sequence(mut self, sequence: u32) -> Self {
+        self.sequence = Some(sequence);
+        return self;
+    }
+
+    /// Let the transaction be replaced by one paying a higher fee while unconfirmed, by setting
+    /// the sequence number of all inputs to `SEQUENCE_MAX_REPLACEABLE`
+    pub fn replaceable(self) -> Self {
+        return self.sequence(SEQUENCE_MAX_REPLACEABLE);
+    }
+
+    /// The unspent outputs one of the keys can spend, with the largest fee of spending them
+    fn candidates(&self) -> Vec<Candidate> {
+        let mut candidates: Vec<Candidate> = Vec::new();
+        for (outpoint, output) in &self.utxos {
+            let input_type = match self.input_type(output) {
+                Some(input_type) => input_type,
+                None => continue,
+            };
+            candidates.push(Candidate {
+                outpoint: *outpoint,
+                output: output.clone(),
+                fee: self.fee_rate.fee(input_type.vsize()),
+            });
+        }
+        return candidates;
+    }
+
+    /// The type of the input spending `output` with the key able to spend it, None if no key can
+    fn input_type(&self, output: &TxOutput) -> Option<InputType> {
+        match output.scheme {
+            SignatureScheme::Ecdsa => {
+                let spendable = self.keys.iter().any(|k| k.public_key().address() == output.recipient);
+                return if spendable { Some(InputType::Signature(KeyType::Ecdsa)) } else { None };
+            }
+            SignatureScheme::Schnorr => {
+                let spendable = self.schnorr_keys.iter().any(|k| k.public_key().address() == output.recipient);
+                return if spendable { Some(InputType::Signature(KeyType::Schnorr)) } else { None };
+            }
+            SignatureScheme::Script => {}
+        }
+        let key_hash = output.script_pubkey.key_hash()?;
+        let key_type = if self.keys.iter().any(|k| hash160(k.public_key().as_bytes()) == key_hash) {
+            KeyType::Ecdsa
+        } else if self.schnorr_keys.iter().any(|k| hash160(k.public_key().as_bytes()) == key_hash) {
+            KeyType::Schnorr
+        } else {
+            return None;
+        };
+        if output.script_pubkey.witness_program().is_some() {
+            return Some(InputType::P2wpkh(key_type));
+        }
+        return Some(InputType::P2pkh(key_type));
+    }
+
+    /// Sign the input at `index` of `transaction`, spending `output`, with the key able to spend
+    /// it. Returns the unlocking script and witness of the input, or None if no key can spend it.
+    fn unlock(&self, output: &TxOutput, transaction: &Transaction, index: usize) -> Option<Result<(Script, Vec<Vec<u8>>), SignError>> {
+        let signature_hash = transaction.signature_hash();
+        let message = signature_hash.as_ref();
+        match output.scheme {
+            SignatureScheme::Ecdsa => {
+                let key = self.keys.iter().find(|k| k.public_key().address() == output.recipient)?;
+                return Some(key.sign(message).map_err(SignError::from).map(|signature| {
+                    let public_key = key.public_key();
+                    (InputSignature::Ecdsa { signature, public_key }.to_script(), Vec::new())
+                }));
+            }
+            SignatureScheme::Schnorr => {
+                let key = self.schnorr_keys.iter().find(|k| k.public_key().address() == output.recipient)?;
+                return Some(key.sign(message).map_err(SignError::from).map(|signature| {
+                    let public_key = key.public_key();
+                    (InputSignature::Schnorr { signature, public_key }.to_script(), Vec::new())
+                }));
+            }
+            SignatureScheme::Script => {}
+        }
+        let key_hash = output.script_pubkey.key_hash()?;
+        let signed = if let Some(key) = self.keys.iter().find(|k| hash160(k.public_key().as_bytes()) == key_hash) {
+            let signature = transaction.script_signature(index, key, SigHashType::ALL);
+            signature.map(|signature| (signature, key.public_key().as_bytes().to_vec()))
+        } else {
+            let key = self.schnorr_keys.iter().find(|k| hash160(k.public_key().as_bytes()) == key_hash)?;
+            let signature = transaction.script_signature_schnorr(index, key, SigHashType::ALL);
+            signature.map(|signature| (signature, key.public_key().as_bytes().to_vec()))
+        };
+        return Some(signed.map(|(signature, public_key)| {
+            if output.script_pubkey.witness_program().is_some() {
+                (Script::new(), vec![signature, public_key])
+            } else {
+                (Script::p2pkh_unlock(&signature, &public_key), Vec::new())
+            }
+        }));
+    }
+
+    /// Assemble and sign the transaction spending `inputs`, with `change` if any
+    fn assemble(&self, inputs: &[(OutPoint, TxOutput)], change: Option<Amount>) -> Result<Transaction, BuildError> {
+        let sequence = match (self.sequence, self.lock_time) {
+            (Some(sequence), _) => sequence,
+            (None, 0) => SEQUENCE_FINAL,
+            (None, _) => SEQUENCE_FINAL - 1,
+        };
+        let tx_inputs = inputs
+            .iter()
+            .map(|(outpoint, _)| TxInput {
+                sequence,
+                ..TxInput::new(outpoint.txid, outpoint.index)
+            })
+            .collect();
+        let mut outputs = self.outputs.clone();
+        if let (Some(value), Some(template)) = (change, &self.change) {
+            let mut output = template.clone();
+            output.value = value;
+            outputs.push(output);
+        }
+        let mut transaction = Transaction::new(tx_inputs, outputs);
+        transaction.set_lock_time(self.lock_time);
+        for (index, (_, output)) in inputs.iter().enumerate() {
+            let (script, witness) = self.unlock(output, &transaction, index).unwrap()?;
+            transaction.set_script(index, &script);
+            transaction.set_witness(index, witness);
+        }
+        return Ok(transaction);
+    }
+
+    /// Add up `values`, failing if the total overflows
+    fn sum<I: IntoIterator<Item = Amount>>(values: I) -> Result<Amount, BuildError> {
+        let mut total: Amount = 0;
+        for value in values {
+            total = total.checked_add(value).ok_or(BuildError::ValueOverflow)?;
+        }
+        return Ok(total);
+    }
+
+    /// The fee at the fee rate of `transaction`
+    fn fee(&self, transaction: &Transaction) -> Amount {
+        return self.fee_rate.fee(transaction.vsize() + SIGNATURE_SLACK * transaction.get_inputs().len());
+    }
+
+    /// Select the inputs and sign them
+    pub fn build(self) -> Result<Transaction, BuildError> {
+        if self.outputs.is_empty() {
+            return Err(BuildError::NoOutputs);
+        }
+        let payment = TransactionBuilder::sum(self.outputs.iter().map(|output| output.value))?;
+        let candidates = self.candidates();
+        let available = TransactionBuilder::sum(candidates.iter().map(|c| c.output.value))?;
+        let target = TransactionBuilder::sum(vec![payment, self.fee(&self.assemble(&[], None)?)])?;
+        let change_cost = match &self.change {
+            Some(template) => self.fee_rate.fee(bincode::serialized_size(template).unwrap() as usize).saturating_add(MIN_CHANGE),
+            None => Amount::MAX,
+        };
+        let selection = self
+            .selector
+            .select(&candidates, target, change_cost)
+            .or_else(|| LargestFirst.select(&candidates, target, change_cost))
+            .ok_or(BuildError::InsufficientFunds { available, needed: target })?;
+        let selected: Vec<(OutPoint, TxOutput)> =
+            selection.iter().map(|i| (candidates[*i].outpoint, candidates[*i].output.clone())).collect();
+        let selected_value = TransactionBuilder::sum(selected.iter().map(|(_, output)| output.value))?;
+
+        // measure the fee on the signed transaction, with change if it is worth it
+        if self.change.is_some() {
+            let with_change = self.assemble(&selected, Some(0))?;
+            let fee = self.fee(&with_change);
+            if selected_value >= TransactionBuilder::sum(vec![payment, fee, MIN_CHANGE])? {
+                return self.assemble(&selected, Some(selected_value - payment - fee));
+            }
+        }
+        // returned as signed, so no slack is needed
+        let without_change = self.assemble(&selected, None)?;
+        let needed = TransactionBuilder::sum(vec![payment, self.fee_rate.fee(without_change.vsize())])?;
+        if selected_value < needed {
+            return Err(BuildError::InsufficientFunds { available, needed });
+        }
+        return Ok(without_change);
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block::Block;
+    use crate::coin_selection::{BranchAndBound, RandomImprove};
+    use crate::crypto::merkle::MerkleTree;
+    use crate::utxo::{created_outputs, UtxoSet};
+    use crate::validation::validate_transaction;
+
+    /// A UTXO set holding one output of each value in `outputs`
+    fn funded(outputs: Vec<TxOutput>) -> (UtxoSet, Vec<(OutPoint, TxOutput)>) {
+        let funding = Transaction::new(vec![TxInput::coinbase(vec![1])], outputs);
+        let mut utxo = UtxoSet::new();
+        let merkle_root = MerkleTree::new(&[funding.clone()]).root();
+        utxo.connect_block(&Block::new(H256::default(), H256::default(), vec![funding.clone()], merkle_root));
+        return (utxo, created_outputs(&funding));
+    }
+
+    #[test]
+    fn build() {
+        let key = keys::KeyPair::random();
+        let schnorr_key = schnorr::KeyPair::random();
+        let address = key.public_key().address();
+        let outputs = vec![
+            TxOutput::new(5_000, address),
+            TxOutput::with_scheme(3_000, schnorr_key.public_key().address(), SignatureScheme::Schnorr),
+            TxOutput::with_script(4_000, Script::p2pkh(&hash160(key.public_key().as_bytes()))),
+            TxOutput::with_script(6_000, Script::p2wpkh(&hash160(key.public_key().as_bytes()))),
+            // nobody's
+            TxOutput::new(100_000, H256::from([1u8; 32])),
+        ];
+        let (utxo, utxos) = funded(outputs);
+        let recipient = H256::from([2u8; 32]);
+        let fee_rate = FeeRate::from_sat_per_vb(2);
+
+        let transaction = TransactionBuilder::new(fee_rate)
+            .utxos(utxos.clone())
+            .pay_to(recipient, 15_000)
+            .change_address(address)
+            .key(&key)
+            .schnorr_key(&schnorr_key)
+            .build()
+            .unwrap();
+        assert_eq!(validate_transaction(&transaction, &utxo), Ok(()));
+        assert_eq!(transaction.get_inputs().len(), 4);
+        assert_eq!(transaction.get_outputs()[0], TxOutput::new(15_000, recipient));
+        assert_eq!(transaction.get_outputs()[1].recipient, address);
+        let fee = transaction.fee(&utxo).unwrap();
+        assert!(transaction.fee_rate(&utxo).unwrap() >= fee_rate);
+        assert!(fee >= fee_rate.fee(transaction.vsize()));
+        assert!(fee <= fee_rate.fee(transaction.vsize() + SIGNATURE_SLACK * 8));
+
+        // largest outputs first, without change when it is too small
+        let transaction = TransactionBuilder::new(fee_rate)
+            .utxos(utxos.clone())
+            .pay_to(recipient, 5_600)
+            .change_address(address)
+            .key(&key)
+            .schnorr_key(&schnorr_key)
+            .build()
+            .unwrap();
+        assert_eq!(validate_transaction(&transaction, &utxo), Ok(()));
+        assert_eq!(transaction.get_inputs()[0].outpoint(), utxos[3].0);
+        assert_eq!(transaction.get_outputs().len(), 1);
+
+        let too_much = TransactionBuilder::new(fee_rate).utxos(utxos.clone()).pay_to(recipient, 18_000).key(&key).build();
+        match too_much {
+            Err(BuildError::InsufficientFunds { available, .. }) => assert_eq!(available, 15_000),
+            _ => panic!("built a transaction spending outputs of other keys"),
+        }
+        assert_eq!(TransactionBuilder::new(fee_rate).utxos(utxos).key(&key).build().err(), Some(BuildError::NoOutputs));
+
+        // totals overflowing the amount type are refused
+        let large = Amount::MAX / 2 + 1;
+        let (_, utxos) = funded(vec![TxOutput::new(large, address), TxOutput::new(large, address)]);
+        let overflowing = TransactionBuilder::new(fee_rate).utxos(utxos).pay_to(recipient, 1_000).key(&key).build();
+        assert_eq!(overflowing.err(), Some(BuildError::ValueOverflow));
+    }
+
+    #[test]
+    fn selectors() {
+        let key = keys::KeyPair::random();
+        let address = key.public_key().address();
+        let values = [40_000, 25_000, 10_000, 7_000, 3_000];
+        let (utxo, utxos) = funded(values.iter().map(|value| TxOutput::new(*value, address)).collect());
+        let recipient = H256::from([2u8; 32]);
+        let fee_rate = FeeRate::from_sat_per_vb(1);
+        let build = |selector: Box<dyn CoinSelector>, value: Amount| {
+            let transaction = TransactionBuilder::new(fee_rate)
+                .utxos(utxos.clone())
+                .pay_to(recipient, value)
+                .change_address(address)
+                .key(&key)
+                .selector(selector)
+                .build()
+                .unwrap();
+            assert_eq!(validate_transaction(&transaction, &utxo), Ok(()));
+            transaction
+        };
+
+        // 10 000 + 7 000 pays 16 400 and the fee without change
+        let transaction = build(Box::new(BranchAndBound::default()), 16_400);
+        assert_eq!(transaction.get_inputs().len(), 2);
+        assert_eq!(transaction.get_outputs().len(), 1);
+        let transaction = build(Box::new(LargestFirst), 16_400);
+        assert_eq!(transaction.get_inputs().len(), 1);
+        assert_eq!(transaction.get_outputs().len(), 2);
+        // no changeless match, so largest-first is used
+        let transaction = build(Box::new(BranchAndBound::default()), 1_000);
+        assert_eq!(transaction.get_inputs()[0].outpoint(), utxos[0].0);
+        let transaction = build(Box::new(RandomImprove), 5_000);
+        assert_eq!(transaction.get_outputs().len(), 2);
+    }
+
+    #[test]
+    fn lock_time() {
+        let key = keys::KeyPair::random();
+        let (utxo, utxos) = funded(vec![TxOutput::new(10_000, key.public_key().address())]);
+        let builder = || TransactionBuilder::new(FeeRate::from_sat_per_vb(1)).utxos(utxos.clone()).pay_to(H256::default(), 5_000).key(&key);
+        let transaction = builder().lock_time(100).build().unwrap();
+        assert_eq!(validate_transaction(&transaction, &utxo), Ok(()));
+        assert_eq!(transaction.get_lock_time(), 100);
+        assert!(!transaction.is_final(100, 0));
+        assert!(transaction.is_final(101, 0));
+        let transaction = builder().lock_time(100).sequence(SEQUENCE_FINAL).build().unwrap();
+        assert!(transaction.is_final(100, 0));
+        assert!(!transaction.signals_replacement());
+        let transaction = builder().lock_time(100).replaceable().build().unwrap();
+        assert!(transaction.signals_replacement());
+        assert!(!transaction.is_final(100, 0));
+    }
+}
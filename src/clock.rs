@@ -0,0 +1,56 @@
+//! Sources of the current time, so that what depends on it can be replayed with a fixed clock
+
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// A source of the current time
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The time of the system
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        return SystemTime::now();
+    }
+}
+
+/// A clock starting at a given time, and moving forward by a fixed step each time it is read
+pub struct SteppingClock {
+    next: Mutex<SystemTime>,
+    step: Duration,
+}
+
+impl SteppingClock {
+    pub fn new(start: SystemTime, step: Duration) -> Self {
+        return SteppingClock {
+            next: Mutex::new(start),
+            step,
+        };
+    }
+}
+
+impl Clock for SteppingClock {
+    fn now(&self) -> SystemTime {
+        let mut next = self.next.lock().unwrap();
+        let now = *next;
+        *next += self.step;
+        return now;
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stepping() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let clock = SteppingClock::new(start, Duration::from_secs(2));
+        assert_eq!(clock.now(), start);
+        assert_eq!(clock.now(), start + Duration::from_secs(2));
+        assert!(SystemClock.now() > start);
+    }
+}
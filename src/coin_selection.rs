@@ -0,0 +1,205 @@
+use rand::seq::SliceRandom;
+
+use crate::transaction::{Amount, TxOutput};
+use crate::utxo::OutPoint;
+
+/// An unspent output a transaction may spend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub outpoint: OutPoint,
+    pub output: TxOutput,
+    /// The fee of spending the output, at the fee rate of the transaction
+    pub fee: Amount,
+}
+
+impl Candidate {
+    /// What the output adds to the transaction once the fee of spending it is paid
+    pub fn effective_value(&self) -> Amount {
+        return self.output.value.saturating_sub(self.fee);
+    }
+}
+
+/// A strategy choosing which outputs a transaction spends
+pub trait CoinSelector {
+    /// Choose candidates whose effective values add up to at least `target`, the payments plus
+    /// the fee of the transaction without inputs. Adding a change output costs `change_cost`,
+    /// its fee and the smallest change worth creating. Returns the indices of the chosen
+    /// candidates, or None if the strategy finds no selection or the values overflow.
+    fn select(&self, candidates: &[Candidate], target: Amount, change_cost: Amount) -> Option<Vec<usize>>;
+}
+
+/// Spend the outputs of highest value first, which keeps transactions small
+pub struct LargestFirst;
+
+impl CoinSelector for LargestFirst {
+    fn select(&self, candidates: &[Candidate], target: Amount, _change_cost: Amount) -> Option<Vec<usize>> {
+        let mut order: Vec<usize> = (0..candidates.len()).collect();
+        order.sort_by(|a, b| candidates[*b].effective_value().cmp(&candidates[*a].effective_value()));
+        let mut selected: Vec<usize> = Vec::new();
+        let mut total: Amount = 0;
+        for index in order {
+            if total >= target {
+                break;
+            }
+            total = total.checked_add(candidates[index].effective_value())?;
+            selected.push(index);
+        }
+        if total < target {
+            return None;
+        }
+        return Some(selected);
+    }
+}
+
+/// Search for a selection needing no change: its effective value exceeds the target by less than
+/// the cost of change, so the excess goes to the fee. Depth-first search over the outputs by
+/// decreasing value, giving up after `max_tries` steps.
+pub struct BranchAndBound {
+    pub max_tries: usize,
+}
+
+impl Default for BranchAndBound {
+    fn default() -> Self {
+        BranchAndBound { max_tries: 100_000 }
+    }
+}
+
+impl CoinSelector for BranchAndBound {
+    fn select(&self, candidates: &[Candidate], target: Amount, change_cost: Amount) -> Option<Vec<usize>> {
+        let mut order: Vec<usize> = (0..candidates.len()).filter(|i| candidates[*i].effective_value() > 0).collect();
+        order.sort_by(|a, b| candidates[*b].effective_value().cmp(&candidates[*a].effective_value()));
+        let values: Vec<Amount> = order.iter().map(|i| candidates[*i].effective_value()).collect();
+        // value of the outputs after each position, to prune branches that cannot reach the target
+        let mut remaining: Vec<Amount> = vec![0; values.len() + 1];
+        for i in (0..values.len()).rev() {
+            remaining[i] = remaining[i + 1].checked_add(values[i])?;
+        }
+        let upper = target.saturating_add(change_cost);
+        let mut best: Option<(Amount, Vec<usize>)> = None;
+        let mut included: Vec<usize> = Vec::new();
+        let mut total: Amount = 0;
+        let mut position = 0;
+        let mut tries = 0;
+        loop {
+            tries += 1;
+            if tries > self.max_tries {
+                break;
+            }
+            let backtrack = if total > upper || total + remaining[position] < target {
+                true
+            } else if total >= target {
+                if best.as_ref().map_or(true, |(waste, _)| total - target < *waste) {
+                    best = Some((total - target, included.clone()));
+                }
+                true
+            } else {
+                position == values.len()
+            };
+            if backtrack {
+                // leave out the last included output, and try the ones after it
+                match included.pop() {
+                    Some(last) => {
+                        total -= values[last];
+                        position = last + 1;
+                    }
+                    None => break,
+                }
+            } else {
+                included.push(position);
+                total += values[position];
+                position += 1;
+            }
+        }
+        return best.map(|(_, selected)| selected.iter().map(|i| order[*i]).collect());
+    }
+}
+
+/// Random-improve, from Cardano: pick random outputs until the target is reached, then keep
+/// adding random outputs while they bring the total closer to twice the target, without going
+/// over three times the target. This creates change of a size similar to the payments, which
+/// keeps the number of unspent outputs stable.
+pub struct RandomImprove;
+
+impl CoinSelector for RandomImprove {
+    fn select(&self, candidates: &[Candidate], target: Amount, _change_cost: Amount) -> Option<Vec<usize>> {
+        let mut order: Vec<usize> = (0..candidates.len()).collect();
+        order.shuffle(&mut rand::thread_rng());
+        let mut selected: Vec<usize> = Vec::new();
+        let mut total: Amount = 0;
+        let mut rest = order.into_iter();
+        while total < target {
+            let index = rest.next()?;
+            total = total.checked_add(candidates[index].effective_value())?;
+            selected.push(index);
+        }
+        let ideal = target.saturating_mul(2);
+        let limit = target.saturating_mul(3);
+        for index in rest {
+            let improved = match total.checked_add(candidates[index].effective_value()) {
+                Some(improved) => improved,
+                None => continue,
+            };
+            let closer = (improved as i128 - ideal as i128).abs() < (total as i128 - ideal as i128).abs();
+            if closer && improved <= limit {
+                total = improved;
+                selected.push(index);
+            }
+        }
+        return Some(selected);
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::H256;
+
+    fn candidates(values: &[Amount]) -> Vec<Candidate> {
+        return values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| Candidate {
+                outpoint: OutPoint::new(H256::default(), i as u32),
+                output: TxOutput::new(*value, H256::default()),
+                fee: 10,
+            })
+            .collect();
+    }
+
+    fn total(candidates: &[Candidate], selected: &[usize]) -> Amount {
+        return selected.iter().map(|i| candidates[*i].effective_value()).sum();
+    }
+
+    #[test]
+    fn largest_first() {
+        let c = candidates(&[110, 510, 310, 5]);
+        assert_eq!(LargestFirst.select(&c, 700, 50), Some(vec![1, 2]));
+        assert_eq!(LargestFirst.select(&c, 1000, 50), None);
+        let large = candidates(&[Amount::MAX / 2 + 100, Amount::MAX / 2 + 100]);
+        assert_eq!(LargestFirst.select(&large, Amount::MAX, 50), None);
+        assert_eq!(BranchAndBound::default().select(&large, 1000, 50), None);
+        assert_eq!(RandomImprove.select(&large, Amount::MAX, 0), None);
+    }
+
+    #[test]
+    fn branch_and_bound() {
+        let c = candidates(&[510, 310, 210, 110]);
+        // 300 + 100 is an exact match, where largest-first would pick 500
+        let selected = BranchAndBound::default().select(&c, 400, 20).unwrap();
+        assert_eq!(total(&c, &selected), 400);
+        let selected = BranchAndBound::default().select(&c, 790, 20).unwrap();
+        assert_eq!(total(&c, &selected), 800);
+        assert_eq!(BranchAndBound::default().select(&c, 450, 20), None);
+        assert_eq!(BranchAndBound::default().select(&c, 2000, 20), None);
+    }
+
+    #[test]
+    fn random_improve() {
+        let c = candidates(&[110; 20]);
+        for _ in 0..10 {
+            let selected = RandomImprove.select(&c, 250, 0).unwrap();
+            assert_eq!(total(&c, &selected), 500);
+        }
+        assert_eq!(RandomImprove.select(&c, 3000, 0), None);
+    }
+}
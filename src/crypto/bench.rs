@@ -0,0 +1,43 @@
+use serde::Serialize;
+use std::time::Instant;
+
+use crate::crypto::hash::H256;
+use crate::crypto::{keys, schnorr};
+use crate::transaction::{SignatureScheme, Transaction, TxInput, TxOutput};
+
+/// Signature verification throughput of one scheme
+#[derive(Serialize, Debug, Clone)]
+pub struct Throughput {
+    pub scheme: SignatureScheme,
+    pub verifications: usize,
+    pub seconds: f64,
+    pub per_second: f64,
+}
+
+/// Measure how fast transaction signatures of each scheme are verified, over `count`
+/// verifications of a signed transaction
+pub fn verification_throughput(count: usize) -> Vec<Throughput> {
+    let unsigned = Transaction::new(vec![TxInput::new(H256::from([1u8; 32]), 0)], vec![TxOutput::new(1, H256::default())]);
+    let mut ecdsa = unsigned.clone();
+    ecdsa.sign(&keys::KeyPair::random()).unwrap();
+    let mut schnorr = unsigned;
+    schnorr.sign_schnorr(&schnorr::KeyPair::random()).unwrap();
+    return vec![(SignatureScheme::Ecdsa, ecdsa), (SignatureScheme::Schnorr, schnorr)]
+        .into_iter()
+        .map(|(scheme, transaction)| {
+            let signature = transaction.get_inputs()[0].signature().unwrap();
+            let signature_hash = transaction.signature_hash();
+            let start = Instant::now();
+            for _ in 0..count {
+                assert!(signature.verify(&signature_hash));
+            }
+            let seconds = start.elapsed().as_secs_f64();
+            Throughput {
+                scheme,
+                verifications: count,
+                seconds,
+                per_second: count as f64 / seconds,
+            }
+        })
+        .collect();
+}
@@ -45,6 +45,17 @@ impl std::fmt::Debug for H256 {
     }
 }
 
+impl std::str::FromStr for H256 {
+    type Err = hex::FromHexError;
+
+    /// Parse the hex encoding produced by `Display`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut raw = [0u8; 32];
+        hex::decode_to_slice(s, &mut raw)?;
+        Ok(H256(raw))
+    }
+}
+
 impl std::convert::AsRef<[u8]> for H256 {
     fn as_ref(&self) -> &[u8] {
         &self.0
@@ -87,6 +98,152 @@ impl std::convert::From<ring::digest::Digest> for H256 {
     }
 }
 
+impl H256 {
+    /// Decode a target from the compact "bits" representation used in Bitcoin headers: the high
+    /// byte is the length of the number in bytes, the low three bytes its most significant bytes.
+    /// The sign bit is ignored, and targets that do not fit into 256 bits saturate.
+    pub fn from_compact(bits: u32) -> H256 {
+        let size = (bits >> 24) as usize;
+        let mantissa = (bits & 0x007f_ffff).to_be_bytes();
+        let mut raw = [0u8; 32];
+        if size > 32 {
+            return H256([255u8; 32]);
+        }
+        for i in 0..3 {
+            // byte i of the mantissa is the (size - i)-th byte from the end
+            if size > i {
+                raw[32 - size + i] = mantissa[1 + i];
+            }
+        }
+        H256(raw)
+    }
+
+    /// Compute `self * numerator / denominator`, treating the hash as a 256-bit big endian
+    /// number. Saturates to the maximum value on overflow.
+    pub fn mul_div(&self, numerator: u64, denominator: u64) -> H256 {
+        assert!(denominator != 0, "division by zero");
+        // multiply into 32-bit limbs, least significant first, with room for the overflow
+        let mut limbs = [0u64; 10];
+        let mut carry: u128 = 0;
+        for i in 0..8 {
+            let start = 28 - 4 * i;
+            let limb = u32::from_be_bytes(self.0[start..start + 4].try_into().unwrap()) as u128;
+            let product = limb * numerator as u128 + carry;
+            limbs[i] = (product & 0xffff_ffff) as u64;
+            carry = product >> 32;
+        }
+        limbs[8] = (carry & 0xffff_ffff) as u64;
+        limbs[9] = (carry >> 32) as u64;
+        // long division, most significant limb first
+        let mut remainder: u128 = 0;
+        for i in (0..10).rev() {
+            let current = (remainder << 32) | limbs[i] as u128;
+            limbs[i] = (current / denominator as u128) as u64;
+            remainder = current % denominator as u128;
+        }
+        if limbs[8] != 0 || limbs[9] != 0 {
+            return H256([255u8; 32]);
+        }
+        let mut raw = [0u8; 32];
+        for i in 0..8 {
+            let start = 28 - 4 * i;
+            raw[start..start + 4].copy_from_slice(&(limbs[i] as u32).to_be_bytes());
+        }
+        H256(raw)
+    }
+
+    fn to_limbs(&self) -> [u64; 4] {
+        let mut limbs = [0u64; 4];
+        for i in 0..4 {
+            limbs[i] = u64::from_be_bytes(self.0[8 * i..8 * i + 8].try_into().unwrap());
+        }
+        limbs
+    }
+
+    fn from_limbs(limbs: &[u64; 4]) -> H256 {
+        let mut raw = [0u8; 32];
+        for i in 0..4 {
+            raw[8 * i..8 * i + 8].copy_from_slice(&limbs[i].to_be_bytes());
+        }
+        H256(raw)
+    }
+
+    /// Add two hashes as 256-bit big endian numbers, saturating to the maximum value on overflow
+    pub fn saturating_add(&self, other: &H256) -> H256 {
+        let a = self.to_limbs();
+        let b = other.to_limbs();
+        let mut sum = [0u64; 4];
+        let mut carry = false;
+        for i in (0..4).rev() {
+            let (partial, overflow_1) = a[i].overflowing_add(b[i]);
+            let (partial, overflow_2) = partial.overflowing_add(carry as u64);
+            sum[i] = partial;
+            carry = overflow_1 || overflow_2;
+        }
+        if carry {
+            return H256([255u8; 32]);
+        }
+        H256::from_limbs(&sum)
+    }
+
+    /// The expected number of hashes needed to find a hash at most this target, that is
+    /// `2^256 / (target + 1)`
+    pub fn work(&self) -> H256 {
+        // 2^256 / (target + 1) = (2^256 - target - 1) / (target + 1) + 1
+        let divisor = self.saturating_add(&H256::from_limbs(&[0, 0, 0, 1]));
+        if divisor == H256([255u8; 32]) {
+            return H256::from_limbs(&[0, 0, 0, 1]);
+        }
+        let d = divisor.to_limbs();
+        let mut n = self.to_limbs();
+        for limb in n.iter_mut() {
+            *limb = !*limb;
+        }
+        // binary long division, most significant bit first
+        let mut quotient = [0u64; 4];
+        let mut remainder = [0u64; 4];
+        for bit in 0..256 {
+            let overflow = remainder[0] >> 63 == 1;
+            for i in 0..4 {
+                let next = if i < 3 { remainder[i + 1] >> 63 } else { (n[bit / 64] >> (63 - bit % 64)) & 1 };
+                remainder[i] = (remainder[i] << 1) | next;
+            }
+            if overflow || remainder >= d {
+                let mut borrow = false;
+                for i in (0..4).rev() {
+                    let (partial, borrow_1) = remainder[i].overflowing_sub(d[i]);
+                    let (partial, borrow_2) = partial.overflowing_sub(borrow as u64);
+                    remainder[i] = partial;
+                    borrow = borrow_1 || borrow_2;
+                }
+                quotient[bit / 64] |= 1 << (63 - bit % 64);
+            }
+        }
+        H256::from_limbs(&quotient).saturating_add(&H256::from_limbs(&[0, 0, 0, 1]))
+    }
+
+    /// Encode the number into the compact "bits" representation, truncating it to its three most
+    /// significant bytes
+    pub fn to_compact(&self) -> u32 {
+        let first = match self.0.iter().position(|b| *b != 0) {
+            Some(i) => i,
+            None => return 0,
+        };
+        let mut size = (32 - first) as u32;
+        let mut mantissa: u32 = 0;
+        for i in 0..3 {
+            let byte = if first + i < 32 { self.0[first + i] } else { 0 };
+            mantissa = (mantissa << 8) | byte as u32;
+        }
+        // the top bit of the mantissa is the sign bit, keep it clear
+        if mantissa & 0x0080_0000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+        (size << 24) | mantissa
+    }
+}
+
 impl Ord for H256 {
     fn cmp(&self, other: &H256) -> std::cmp::Ordering {
         let self_higher = u128::from_be_bytes(self.0[0..16].try_into().unwrap());
@@ -120,4 +277,49 @@ pub mod tests {
         (&raw_bytes).into()
     }
 
+    #[test]
+    fn work() {
+        let one = H256::from_limbs(&[0, 0, 0, 1]);
+        assert_eq!(H256([255u8; 32]).work(), one);
+        // a target of 2^255 - 1 takes two hashes on average
+        let mut raw = [255u8; 32];
+        raw[0] = 0x7f;
+        assert_eq!(H256(raw).work(), H256::from_limbs(&[0, 0, 0, 2]));
+        // a target of 2^224 - 1 takes 2^32 hashes
+        let mut raw = [255u8; 32];
+        raw[0..4].copy_from_slice(&[0, 0, 0, 0]);
+        assert_eq!(H256(raw).work(), H256::from_limbs(&[0, 0, 0, 1 << 32]));
+        assert_eq!(one.saturating_add(&one), H256::from_limbs(&[0, 0, 0, 2]));
+        assert_eq!(H256([255u8; 32]).saturating_add(&one), H256([255u8; 32]));
+        assert_eq!(H256::from_limbs(&[0, 0, 0, u64::MAX]).saturating_add(&one), H256::from_limbs(&[0, 0, 1, 0]));
+    }
+
+    #[test]
+    fn mul_div() {
+        let target = H256::from_compact(0x1d00ffff);
+        assert_eq!(target.mul_div(1, 2), H256::from_compact(0x1c7fff80));
+        assert_eq!(target.mul_div(4, 1), H256::from_compact(0x1d03fffc));
+        assert_eq!(target.mul_div(3, 3), target);
+        let mut max = [255u8; 32];
+        assert_eq!(H256::from(max).mul_div(2, 1), H256::from(max));
+        max[0] = 0;
+        assert_eq!(H256::from(max).mul_div(2, 2), H256::from(max));
+    }
+
+    #[test]
+    fn compact() {
+        let target = H256::from_compact(0x1d00ffff);
+        let mut expected = [0u8; 32];
+        expected[4] = 0xff;
+        expected[5] = 0xff;
+        assert_eq!(target, H256::from(expected));
+        assert_eq!(target.to_compact(), 0x1d00ffff);
+        assert_eq!(H256::from_compact(0x05009234).to_compact(), 0x05009234);
+        assert_eq!(H256::from_compact(0x01120000).as_ref()[31], 0x12);
+        assert_eq!(H256::from_compact(0x01120000).to_compact(), 0x01120000);
+        assert_eq!(H256::default().to_compact(), 0);
+        let mut high = [0u8; 32];
+        high[31] = 0x80;
+        assert_eq!(H256::from(high).to_compact(), 0x02008000);
+    }
 }
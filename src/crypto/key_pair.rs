@@ -1,5 +1,8 @@
 use ring::rand;
 use ring::signature::Ed25519KeyPair;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
 
 /// Generate a random key pair.
 pub fn random() -> Ed25519KeyPair {
@@ -7,3 +10,35 @@ pub fn random() -> Ed25519KeyPair {
     let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
     Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref().into()).unwrap()
 }
+
+/// Load the key pair kept in the file `path`, generating and saving one if there is no such file.
+pub fn load_or_create(path: &Path) -> std::io::Result<Ed25519KeyPair> {
+    let invalid = |_| std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid key file");
+    if path.exists() {
+        let mut pkcs8_bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut pkcs8_bytes)?;
+        return Ed25519KeyPair::from_pkcs8(&pkcs8_bytes).map_err(invalid);
+    }
+    let rng = rand::SystemRandom::new();
+    let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(pkcs8_bytes.as_ref())?;
+    return Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref()).map_err(invalid);
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use ring::signature::KeyPair;
+
+    #[test]
+    fn persistent() {
+        let path = std::env::temp_dir().join(format!("key_{}", ::rand::random::<u32>()));
+        let key = load_or_create(&path).unwrap();
+        assert_eq!(load_or_create(&path).unwrap().public_key().as_ref(), key.public_key().as_ref());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,258 @@
+use serde::{Serialize, Deserialize};
+use ring::digest::{SHA256, digest};
+use ring::rand::SystemRandom;
+use ring::signature::{
+    EcdsaKeyPair, KeyPair as _, UnparsedPublicKey, ECDSA_P256_SHA256_ASN1, ECDSA_P256_SHA256_ASN1_SIGNING,
+};
+
+use super::hash::H256;
+
+/// Order of the P-256 group, the upper bound of the R and S values of signatures
+const CURVE_ORDER: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xbc, 0xe6, 0xfa, 0xad, 0xa7, 0x17, 0x9e, 0x84, 0xf3, 0xb9, 0xca, 0xc2, 0xfc, 0x63, 0x25, 0x51,
+];
+
+/// Half the order of the P-256 group, rounded down: the largest S of a low-S signature
+const HALF_CURVE_ORDER: [u8; 32] = [
+    0x7f, 0xff, 0xff, 0xff, 0x80, 0x00, 0x00, 0x00, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xde, 0x73, 0x7d, 0x56, 0xd3, 0x8b, 0xcf, 0x42, 0x79, 0xdc, 0xe5, 0x61, 0x7e, 0x31, 0x92, 0xa8,
+];
+
+/// Reasons for a key to be rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyError {
+    /// The bytes are not a PKCS#8 encoded ECDSA P-256 key
+    Malformed(String),
+    /// The system random number generator failed
+    Random,
+    /// The deterministic nonce of a Schnorr signature is zero, which happens with negligible
+    /// probability. Only `schnorr::KeyPair` returns it: ECDSA signing draws its nonce in ring.
+    ZeroNonce,
+}
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            KeyError::Malformed(e) => write!(f, "malformed key: {}", e),
+            KeyError::Random => write!(f, "random number generator failure"),
+            KeyError::ZeroNonce => write!(f, "schnorr signing nonce is zero"),
+        }
+    }
+}
+
+/// An ECDSA key pair over P-256, the curve provided by ring, used to sign transactions
+pub struct KeyPair {
+    inner: EcdsaKeyPair,
+    /// The PKCS#8 encoding of the key pair, to store it
+    pkcs8: Vec<u8>,
+}
+
+impl KeyPair {
+    /// Generate a random key pair
+    pub fn random() -> Self {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        return KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+    }
+
+    /// Load a key pair from its PKCS#8 encoding, as returned by `to_pkcs8`
+    pub fn from_pkcs8(pkcs8: &[u8]) -> Result<Self, KeyError> {
+        let inner = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8)
+            .map_err(|e| KeyError::Malformed(e.to_string()))?;
+        return Ok(KeyPair {
+            inner,
+            pkcs8: pkcs8.to_vec(),
+        });
+    }
+
+    pub fn to_pkcs8(&self) -> &[u8] {
+        return &self.pkcs8;
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        return PublicKey(self.inner.public_key().as_ref().to_vec());
+    }
+
+    /// Sign `message`, returning an ASN.1 DER encoded signature with a low S, see `is_low_s`
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>, KeyError> {
+        let rng = SystemRandom::new();
+        let signature = self.inner.sign(&rng, message).map_err(|_| KeyError::Random)?;
+        return Ok(to_low_s(signature.as_ref()));
+    }
+}
+
+/// An uncompressed ECDSA P-256 public key
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct PublicKey(Vec<u8>);
+
+impl PublicKey {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        return PublicKey(bytes.to_vec());
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        return &self.0;
+    }
+
+    /// Check an ASN.1 DER encoded signature of `message`. Encodings other than `is_strict_der`
+    /// ones are refused.
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        if !is_strict_der(signature) {
+            return false;
+        }
+        return UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, &self.0).verify(message, signature).is_ok();
+    }
+
+    /// The hash of the key, which outputs paying to the key use as recipient
+    pub fn address(&self) -> H256 {
+        return digest(&SHA256, &self.0).into();
+    }
+}
+
+/// Whether `signature` is a DER sequence of two integers R and S, each minimally encoded and
+/// positive, with no other data, so that it cannot be re-encoded into another valid signature
+pub fn is_strict_der(signature: &[u8]) -> bool {
+    let len = signature.len();
+    if len < 8 || len > 72 || signature[0] != 0x30 || signature[1] as usize != len - 2 {
+        return false;
+    }
+    let r_len = signature[3] as usize;
+    if 5 + r_len >= len {
+        return false;
+    }
+    let s_len = signature[5 + r_len] as usize;
+    if r_len + s_len + 6 != len {
+        return false;
+    }
+    return is_der_integer(&signature[2..4 + r_len]) && is_der_integer(&signature[4 + r_len..]);
+}
+
+/// Whether `bytes` is an INTEGER tag, its length, and a positive value without needless padding
+fn is_der_integer(bytes: &[u8]) -> bool {
+    let value = &bytes[2..];
+    if bytes[0] != 0x02 || value.is_empty() || value[0] & 0x80 != 0 {
+        return false;
+    }
+    return !(value.len() > 1 && value[0] == 0 && value[1] & 0x80 == 0);
+}
+
+/// The S value of a strict DER signature, as 32 big-endian bytes. None if it does not fit.
+fn s_value(signature: &[u8]) -> Option<[u8; 32]> {
+    let r_len = signature[3] as usize;
+    let mut s = &signature[6 + r_len..];
+    while s.first() == Some(&0) {
+        s = &s[1..];
+    }
+    if s.len() > 32 {
+        return None;
+    }
+    let mut value = [0u8; 32];
+    value[32 - s.len()..].copy_from_slice(s);
+    return Some(value);
+}
+
+/// Whether `signature` is strict DER with S at most half the group order. For any signature
+/// (R, S), (R, order - S) is valid too, so only one of them is accepted, so that a third party
+/// cannot change the signature, and the id of its transaction.
+pub fn is_low_s(signature: &[u8]) -> bool {
+    if !is_strict_der(signature) {
+        return false;
+    }
+    return s_value(signature).map_or(false, |s| s <= HALF_CURVE_ORDER);
+}
+
+/// The low S form of a strict DER `signature`, replacing S by the group order minus S if needed
+pub fn to_low_s(signature: &[u8]) -> Vec<u8> {
+    if !is_strict_der(signature) || is_low_s(signature) {
+        return signature.to_vec();
+    }
+    return negate_s(signature);
+}
+
+/// The other valid form of a strict DER `signature`, with S replaced by the group order minus S
+pub fn negate_s(signature: &[u8]) -> Vec<u8> {
+    let s = match s_value(signature) {
+        Some(s) => s,
+        None => return signature.to_vec(),
+    };
+    let mut negated = [0u8; 32];
+    let mut borrow = 0i16;
+    for i in (0..32).rev() {
+        let mut digit = CURVE_ORDER[i] as i16 - s[i] as i16 - borrow;
+        borrow = if digit < 0 { 1 } else { 0 };
+        if digit < 0 {
+            digit += 256;
+        }
+        negated[i] = digit as u8;
+    }
+    let mut value = &negated[..];
+    while value.len() > 1 && value[0] == 0 {
+        value = &value[1..];
+    }
+    let padding = (value[0] & 0x80 != 0) as usize;
+    let r_len = signature[3] as usize;
+    let mut result = vec![0x30, (4 + r_len + padding + value.len()) as u8];
+    result.extend_from_slice(&signature[2..4 + r_len]);
+    result.extend_from_slice(&[0x02, (padding + value.len()) as u8]);
+    if padding == 1 {
+        result.push(0);
+    }
+    result.extend_from_slice(value);
+    return result;
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_verify() {
+        let key = KeyPair::random();
+        let signature = key.sign(b"message").unwrap();
+        assert!(key.public_key().verify(b"message", &signature));
+        assert!(!key.public_key().verify(b"other message", &signature));
+        assert!(!KeyPair::random().public_key().verify(b"message", &signature));
+
+        let reloaded = KeyPair::from_pkcs8(key.to_pkcs8()).unwrap();
+        assert_eq!(reloaded.public_key(), key.public_key());
+        assert_eq!(reloaded.public_key().address(), key.public_key().address());
+        assert!(KeyPair::from_pkcs8(b"not a key").is_err());
+    }
+
+    #[test]
+    fn canonical_signatures() {
+        let key = KeyPair::random();
+        let public_key = key.public_key();
+        for _ in 0..8 {
+            let signature = key.sign(b"message").unwrap();
+            assert!(is_strict_der(&signature));
+            assert!(is_low_s(&signature));
+            assert_eq!(to_low_s(&signature), signature);
+
+            // the same signature with the other S is valid, but not canonical
+            let high = negate_s(&signature);
+            assert_ne!(high, signature);
+            assert!(is_strict_der(&high));
+            assert!(!is_low_s(&high));
+            assert!(public_key.verify(b"message", &high));
+            assert_eq!(to_low_s(&high), signature);
+            assert_eq!(negate_s(&high), signature);
+
+            // padding R with a zero byte gives a valid BER encoding of the same signature
+            let r_len = signature[3] as usize;
+            let mut padded = vec![0x30, signature[1] + 1, 0x02, r_len as u8 + 1, 0x00];
+            padded.extend_from_slice(&signature[4..]);
+            assert!(!is_strict_der(&padded));
+            assert!(!public_key.verify(b"message", &padded));
+
+            let mut trailing = signature.clone();
+            trailing.push(0);
+            assert!(!is_strict_der(&trailing));
+            let mut negative = signature.clone();
+            negative[4] |= 0x80;
+            assert!(!is_strict_der(&negative));
+        }
+        assert!(!is_strict_der(&[]));
+    }
+}
@@ -18,9 +18,16 @@ pub struct MerkleTree {
     root: MerkleNode,
 }
 
-/// Build a Merkle tree from a set of leaves (recursively)
+/// Build a Merkle tree from a set of leaves (recursively). The root of no leaves is zero.
 fn build(leaves: Vec<MerkleNode>, leaf_size: usize) -> MerkleNode {
     let mut n = leaf_size;
+    if n == 0 {
+        return MerkleNode {
+            key: H256::default(),
+            left_child: Box::new(None),
+            right_child: Box::new(None),
+        };
+    }
     if n == 1 {
         let root = leaves[0].clone();
         return root;
@@ -81,20 +88,18 @@ impl MerkleTree {
 
     /// Returns the Merkle Proof of data at index i
     pub fn proof(&self, index: usize) -> Vec<H256> {
-        let mut binary: Vec<usize> = Vec::new();
-        let mut n = index;
-        while {
-            binary.push(n % 2);
-            n /= 2;
-            n != 0
-        } {}
-        let m = binary.len();
-        let mut current = self.root.clone();
+        let mut depth = 0;
+        let mut node = &self.root;
+        while let Some(left) = node.left_child.as_ref() {
+            depth += 1;
+            node = left;
+        }
+        let mut current = &self.root;
         let mut proof_vec: Vec<H256> = Vec::new();
-        for i in 0..m {
-            let lc = current.left_child.unwrap();
-            let rc = current.right_child.unwrap();
-            if binary[i] == 0 {
+        for level in (0..depth).rev() {
+            let lc = current.left_child.as_ref().as_ref().unwrap();
+            let rc = current.right_child.as_ref().as_ref().unwrap();
+            if (index >> level) & 1 == 0 {
                 proof_vec.push(rc.key);
                 current = lc;
             } else {
@@ -106,9 +111,51 @@ impl MerkleTree {
     }
 }
 
+/// A Merkle proof of inclusion of a datum, self-contained so that it can be handed to a light
+/// client
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    /// Index of the datum among the leaves
+    pub index: usize,
+    /// Total number of leaves
+    pub leaf_size: usize,
+    /// Sibling hashes, from the root down to the leaf
+    pub hashes: Vec<H256>,
+}
+
+impl MerkleProof {
+    pub fn new(tree: &MerkleTree, index: usize, leaf_size: usize) -> Self {
+        MerkleProof {
+            index,
+            leaf_size,
+            hashes: tree.proof(index),
+        }
+    }
+
+    /// Verify that `datum` (the hash of the data) is included in the tree with the given root
+    pub fn verify(&self, root: &H256, datum: &H256) -> bool {
+        return verify(root, datum, &self.hashes, self.index, self.leaf_size);
+    }
+}
+
 /// Verify that the datum hash with a vector of proofs will produce the Merkle root. Also need the
 /// index of datum and `leaf_size`, the total number of leaves.
 pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size: usize) -> bool {
+    let mut n = leaf_size;
+    let mut depth = 0;
+    while n > 1 {
+        n = (n + 1) / 2;
+        depth += 1;
+    }
+    if leaf_size == 0 || index >= leaf_size || proof.len() != depth {
+        return false;
+    }
+    return root_from_proof(datum, proof, index, leaf_size).eq(root);
+}
+
+/// The Merkle root of the tree whose leaf at `index` hashes to `datum`, given the proof of that
+/// leaf. Only the path from the leaf to the root is hashed, so changing a single leaf is cheap.
+pub fn root_from_proof(datum: &H256, proof: &[H256], index: usize, leaf_size: usize) -> H256 {
     let m = proof.len();
     let mut n = leaf_size;
     let mut i = index;
@@ -126,14 +173,11 @@ pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size
             let concat_hash = H256::from(hashed);
             current = concat_hash;
         }
-        n = n / 2;
+        n = (n + 1) / 2;
         i = i / 2;
         j = j + 1;
     }
-    if n == 1 && j == m + 1 && current.eq(root) {
-        return true;
-    }
-    return false;
+    return current;
 }
 
 #[cfg(test)]
@@ -168,6 +212,13 @@ mod tests {
     // notice that the order of these two matters
     }
 
+    #[test]
+    fn empty() {
+        let merkle_tree = MerkleTree::new::<H256>(&[]);
+        assert_eq!(merkle_tree.root(), H256::default());
+        assert!(merkle_tree.proof(0).is_empty());
+    }
+
     #[test]
     fn proof() {
     let input_data: Vec<H256> = gen_merkle_tree_data!();
@@ -188,6 +239,17 @@ mod tests {
     assert!(verify(&merkle_tree.root(), &input_data[0].hash(), &proof, 0, input_data.len()));
     }
 
+    #[test]
+    fn verifying_odd_size() {
+        let input_data: Vec<H256> = gen_merkle_tree_data!();
+        let input_data = vec![input_data[0], input_data[1], input_data[0]];
+        let merkle_tree = MerkleTree::new(&input_data);
+        let datum = input_data[2].hash();
+        assert!(verify(&merkle_tree.root(), &datum, &merkle_tree.proof(2), 2, 3));
+        // the last leaf is duplicated to pair it, but that copy is not a leaf of its own
+        assert!(!verify(&merkle_tree.root(), &datum, &merkle_tree.proof(3), 3, 3));
+    }
+
     macro_rules! gen_merkle_tree_assignment2 {
         () => {{
             vec![
@@ -256,4 +318,15 @@ mod tests {
         let p: H256 = (hex!("1e28fb71415f259bd4b0b3b98d67a1240b4f3bed5923aa222c5fdbd97c8fb002")).into();
         assert!(proof.contains(&p));
     }
+
+    #[test]
+    fn root_from_changed_leaf() {
+        let mut input_data: Vec<H256> = gen_merkle_tree_assignment2!();
+        input_data.truncate(5);
+        let proof = MerkleTree::new(&input_data).proof(0);
+        // the proof of the first leaf does not depend on it
+        input_data[0] = (hex!("00000000000000000000000000000000000000000000000000000000000000ff")).into();
+        let root = MerkleTree::new(&input_data).root();
+        assert_eq!(root_from_proof(&input_data[0].hash(), &proof, 0, input_data.len()), root);
+    }
 }
@@ -1,3 +1,8 @@
+pub mod bench;
 pub mod hash;
 pub mod merkle;
+pub mod ripemd160;
+pub mod schnorr;
 pub mod key_pair;
+pub mod keys;
+pub mod selftest;
@@ -0,0 +1,138 @@
+//! RIPEMD-160, which ring does not provide, used for the 20-byte hashes of scripts
+
+use ring::digest::{digest, SHA256};
+
+/// Message word selected by each round of the left line
+const LEFT_WORDS: [usize; 80] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+    7, 4, 13, 1, 10, 6, 15, 3, 12, 0, 9, 5, 2, 14, 11, 8,
+    3, 10, 14, 4, 9, 15, 8, 1, 2, 7, 0, 6, 13, 11, 5, 12,
+    1, 9, 11, 10, 0, 8, 12, 4, 13, 3, 7, 15, 14, 5, 6, 2,
+    4, 0, 5, 9, 7, 12, 2, 10, 14, 1, 3, 8, 11, 6, 15, 13,
+];
+
+/// Message word selected by each round of the right line
+const RIGHT_WORDS: [usize; 80] = [
+    5, 14, 7, 0, 9, 2, 11, 4, 13, 6, 15, 8, 1, 10, 3, 12,
+    6, 11, 3, 7, 0, 13, 5, 10, 14, 15, 8, 12, 4, 9, 1, 2,
+    15, 5, 1, 3, 7, 14, 6, 9, 11, 8, 12, 2, 10, 0, 4, 13,
+    8, 6, 4, 1, 3, 11, 15, 0, 5, 12, 2, 13, 9, 7, 10, 14,
+    12, 15, 10, 4, 1, 5, 8, 7, 6, 2, 13, 14, 0, 3, 9, 11,
+];
+
+const LEFT_SHIFTS: [u32; 80] = [
+    11, 14, 15, 12, 5, 8, 7, 9, 11, 13, 14, 15, 6, 7, 9, 8,
+    7, 6, 8, 13, 11, 9, 7, 15, 7, 12, 15, 9, 11, 7, 13, 12,
+    11, 13, 6, 7, 14, 9, 13, 15, 14, 8, 13, 6, 5, 12, 7, 5,
+    11, 12, 14, 15, 14, 15, 9, 8, 9, 14, 5, 6, 8, 6, 5, 12,
+    9, 15, 5, 11, 6, 8, 13, 12, 5, 12, 13, 14, 11, 8, 5, 6,
+];
+
+const RIGHT_SHIFTS: [u32; 80] = [
+    8, 9, 9, 11, 13, 15, 15, 5, 7, 7, 8, 11, 14, 14, 12, 6,
+    9, 13, 15, 7, 12, 8, 9, 11, 7, 7, 12, 7, 6, 15, 13, 11,
+    9, 7, 15, 11, 8, 6, 6, 14, 12, 13, 5, 14, 13, 13, 7, 5,
+    15, 5, 8, 11, 14, 14, 6, 14, 6, 9, 12, 9, 12, 5, 15, 8,
+    8, 5, 12, 9, 12, 5, 14, 6, 8, 13, 6, 5, 15, 13, 11, 11,
+];
+
+const LEFT_CONSTANTS: [u32; 5] = [0x00000000, 0x5a827999, 0x6ed9eba1, 0x8f1bbcdc, 0xa953fd4e];
+const RIGHT_CONSTANTS: [u32; 5] = [0x50a28be6, 0x5c4dd124, 0x6d703ef3, 0x7a6d76e9, 0x00000000];
+
+/// The boolean function of each group of 16 rounds
+fn f(group: usize, x: u32, y: u32, z: u32) -> u32 {
+    return match group {
+        0 => x ^ y ^ z,
+        1 => (x & y) | (!x & z),
+        2 => (x | !y) ^ z,
+        3 => (x & z) | (y & !z),
+        _ => x ^ (y | !z),
+    };
+}
+
+fn compress(state: &mut [u32; 5], block: &[u8]) {
+    let mut words = [0u32; 16];
+    for (i, word) in words.iter_mut().enumerate() {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&block[i * 4..i * 4 + 4]);
+        *word = u32::from_le_bytes(bytes);
+    }
+    let (mut al, mut bl, mut cl, mut dl, mut el) = (state[0], state[1], state[2], state[3], state[4]);
+    let (mut ar, mut br, mut cr, mut dr, mut er) = (state[0], state[1], state[2], state[3], state[4]);
+    for round in 0..80 {
+        let group = round / 16;
+        let t = al
+            .wrapping_add(f(group, bl, cl, dl))
+            .wrapping_add(words[LEFT_WORDS[round]])
+            .wrapping_add(LEFT_CONSTANTS[group])
+            .rotate_left(LEFT_SHIFTS[round])
+            .wrapping_add(el);
+        al = el;
+        el = dl;
+        dl = cl.rotate_left(10);
+        cl = bl;
+        bl = t;
+        let t = ar
+            .wrapping_add(f(4 - group, br, cr, dr))
+            .wrapping_add(words[RIGHT_WORDS[round]])
+            .wrapping_add(RIGHT_CONSTANTS[group])
+            .rotate_left(RIGHT_SHIFTS[round])
+            .wrapping_add(er);
+        ar = er;
+        er = dr;
+        dr = cr.rotate_left(10);
+        cr = br;
+        br = t;
+    }
+    let t = state[1].wrapping_add(cl).wrapping_add(dr);
+    state[1] = state[2].wrapping_add(dl).wrapping_add(er);
+    state[2] = state[3].wrapping_add(el).wrapping_add(ar);
+    state[3] = state[4].wrapping_add(al).wrapping_add(br);
+    state[4] = state[0].wrapping_add(bl).wrapping_add(cr);
+    state[0] = t;
+}
+
+/// The RIPEMD-160 hash of `data`
+pub fn ripemd160(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&((data.len() as u64) * 8).to_le_bytes());
+    for block in padded.chunks(64) {
+        compress(&mut state, block);
+    }
+    let mut hash = [0u8; 20];
+    for (i, word) in state.iter().enumerate() {
+        hash[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    return hash;
+}
+
+/// RIPEMD-160 of the SHA256 of `data`, the hash identifying keys and scripts in scripts
+pub fn hash160(data: &[u8]) -> [u8; 20] {
+    return ripemd160(digest(&SHA256, data).as_ref());
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vectors() {
+        let vectors: [(&[u8], &str); 4] = [
+            (b"", "9c1185a5c5e9fc54612808977ee8f548b2258d31"),
+            (b"abc", "8eb208f7e05d987a9b044a8e98c6b087f15a0bfc"),
+            (b"message digest", "5d0689ef49d2fae572b881b123a85ffa21595f36"),
+            (
+                b"12345678901234567890123456789012345678901234567890123456789012345678901234567890",
+                "9b752e45573d4b39f4dbd3323cab82bf63326bfb",
+            ),
+        ];
+        for (input, expected) in vectors.iter() {
+            assert_eq!(hex::encode(ripemd160(input)), *expected);
+        }
+    }
+}
@@ -0,0 +1,483 @@
+//! BIP340 Schnorr signatures over secp256k1. ring only provides the NIST curves, so the curve
+//! arithmetic is implemented here, on 256-bit integers stored as four little-endian `u64` limbs.
+//! It favors simplicity over speed, but the operations on secret keys and nonces take the same
+//! time whatever their values: the modular arithmetic has no data-dependent branches, and scalar
+//! multiplication is a Montgomery ladder over complete addition formulas.
+
+use serde::{Serialize, Deserialize};
+use ring::digest::{SHA256, digest};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::cmp::Ordering;
+
+use super::hash::H256;
+use super::keys::KeyError;
+
+type U256 = [u64; 4];
+
+/// The field prime
+const P: U256 = [0xFFFFFFFEFFFFFC2F, 0xFFFFFFFFFFFFFFFF, 0xFFFFFFFFFFFFFFFF, 0xFFFFFFFFFFFFFFFF];
+/// 2^256 - P
+const P_COMPLEMENT: U256 = [0x1000003D1, 0, 0, 0];
+/// (P + 1) / 4, the exponent of square roots since P = 3 mod 4
+const P_SQRT_EXPONENT: U256 = [0xFFFFFFFFBFFFFF0C, 0xFFFFFFFFFFFFFFFF, 0xFFFFFFFFFFFFFFFF, 0x3FFFFFFFFFFFFFFF];
+/// The group order
+const N: U256 = [0xBFD25E8CD0364141, 0xBAAEDCE6AF48A03B, 0xFFFFFFFFFFFFFFFE, 0xFFFFFFFFFFFFFFFF];
+/// 2^256 - N
+const N_COMPLEMENT: U256 = [0x402DA1732FC9BEBF, 0x4551231950B75FC4, 0x1, 0];
+const GX: U256 = [0x59F2815B16F81798, 0x029BFCDB2DCE28D9, 0x55A06295CE870B07, 0x79BE667EF9DCBBAC];
+const GY: U256 = [0x9C47D08FFB10D4B8, 0xFD17B448A6855419, 0x5DA4FBFC0E1108A8, 0x483ADA7726A3C465];
+const ZERO: U256 = [0, 0, 0, 0];
+const ONE: U256 = [1, 0, 0, 0];
+
+/// Compare public values; this is not constant-time
+fn cmp(a: &U256, b: &U256) -> Ordering {
+    for i in (0..4).rev() {
+        match a[i].cmp(&b[i]) {
+            Ordering::Equal => continue,
+            other => return other,
+        }
+    }
+    return Ordering::Equal;
+}
+
+fn is_zero(a: &U256) -> bool {
+    return (a[0] | a[1] | a[2] | a[3]) == 0;
+}
+
+/// All ones if `bit` is set, zero otherwise
+fn mask(bit: bool) -> u64 {
+    return 0u64.wrapping_sub(bit as u64);
+}
+
+/// `a` if `mask` is all ones, `b` if it is zero, without branching
+fn select(mask: u64, a: &U256, b: &U256) -> U256 {
+    let mut selected = ZERO;
+    for i in 0..4 {
+        selected[i] = (a[i] & mask) | (b[i] & !mask);
+    }
+    return selected;
+}
+
+fn add(a: &U256, b: &U256) -> (U256, bool) {
+    let mut sum = ZERO;
+    let mut carry = 0u128;
+    for i in 0..4 {
+        let s = a[i] as u128 + b[i] as u128 + carry;
+        sum[i] = s as u64;
+        carry = s >> 64;
+    }
+    return (sum, carry != 0);
+}
+
+fn sub(a: &U256, b: &U256) -> (U256, bool) {
+    let mut difference = ZERO;
+    let mut borrow = false;
+    for i in 0..4 {
+        let (d, b1) = a[i].overflowing_sub(b[i]);
+        let (d, b2) = d.overflowing_sub(borrow as u64);
+        difference[i] = d;
+        borrow = b1 | b2;
+    }
+    return (difference, borrow);
+}
+
+fn from_bytes(bytes: &[u8]) -> U256 {
+    let mut limbs = ZERO;
+    for i in 0..4 {
+        let mut limb = [0u8; 8];
+        limb.copy_from_slice(&bytes[24 - 8 * i..32 - 8 * i]);
+        limbs[i] = u64::from_be_bytes(limb);
+    }
+    return limbs;
+}
+
+fn to_bytes(a: &U256) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    for i in 0..4 {
+        bytes[24 - 8 * i..32 - 8 * i].copy_from_slice(&a[i].to_be_bytes());
+    }
+    return bytes;
+}
+
+/// Arithmetic modulo a prime `m` close to 2^256, given as `2^256 - m`. Operations take the same
+/// time whatever their operands, except `pow` which branches on its exponent.
+struct Modulus {
+    m: U256,
+    complement: U256,
+}
+
+const FIELD: Modulus = Modulus { m: P, complement: P_COMPLEMENT };
+const ORDER: Modulus = Modulus { m: N, complement: N_COMPLEMENT };
+
+impl Modulus {
+    fn add(&self, a: &U256, b: &U256) -> U256 {
+        let (sum, carry) = add(a, b);
+        let (reduced, borrow) = sub(&sum, &self.m);
+        return select(mask(carry | !borrow), &reduced, &sum);
+    }
+
+    fn sub(&self, a: &U256, b: &U256) -> U256 {
+        let (difference, borrow) = sub(a, b);
+        return add(&difference, &select(mask(borrow), &self.m, &ZERO)).0;
+    }
+
+    fn neg(&self, a: &U256) -> U256 {
+        return self.sub(&ZERO, a);
+    }
+
+    /// Reduce any 256-bit integer, which is below `2 * m`
+    fn reduce(&self, a: &U256) -> U256 {
+        let (reduced, borrow) = sub(a, &self.m);
+        return select(mask(!borrow), &reduced, a);
+    }
+
+    fn mul(&self, a: &U256, b: &U256) -> U256 {
+        let mut wide = mul_wide(a, b);
+        // fold the high half using 2^256 = complement (mod m): as the complement is below 2^130,
+        // it vanishes after four folds
+        for _ in 0..4 {
+            let mut low = ZERO;
+            let mut high = ZERO;
+            low.copy_from_slice(&wide[..4]);
+            high.copy_from_slice(&wide[4..]);
+            let folded = mul_wide(&high, &self.complement);
+            let mut carry = 0u128;
+            for i in 0..8 {
+                let s = folded[i] as u128 + if i < 4 { low[i] as u128 } else { 0 } + carry;
+                wide[i] = s as u64;
+                carry = s >> 64;
+            }
+        }
+        debug_assert!(wide[4..].iter().all(|limb| *limb == 0));
+        let mut low = ZERO;
+        low.copy_from_slice(&wide[..4]);
+        return self.reduce(&low);
+    }
+
+    /// `base` to the power of a public `exponent`
+    fn pow(&self, base: &U256, exponent: &U256) -> U256 {
+        let mut result = ONE;
+        for i in (0..256).rev() {
+            result = self.mul(&result, &result);
+            if (exponent[i / 64] >> (i % 64)) & 1 == 1 {
+                result = self.mul(&result, base);
+            }
+        }
+        return result;
+    }
+
+    /// Inverse of a non-zero element, by Fermat's little theorem
+    fn inv(&self, a: &U256) -> U256 {
+        let exponent = sub(&self.m, &[2, 0, 0, 0]).0;
+        return self.pow(a, &exponent);
+    }
+}
+
+fn mul_wide(a: &U256, b: &U256) -> [u64; 8] {
+    let mut product = [0u64; 8];
+    for i in 0..4 {
+        let mut carry = 0u128;
+        for j in 0..4 {
+            let t = a[i] as u128 * b[j] as u128 + product[i + j] as u128 + carry;
+            product[i + j] = t as u64;
+            carry = t >> 64;
+        }
+        product[i + 4] = carry as u64;
+    }
+    return product;
+}
+
+/// 3 * 7, the curve being y^2 = x^3 + 7
+const B3: U256 = [21, 0, 0, 0];
+
+/// A point of the curve in homogeneous projective coordinates, `(x / z, y / z)` in affine
+/// coordinates, the point at infinity having `z = 0`
+#[derive(Clone, Copy)]
+struct Point {
+    x: U256,
+    y: U256,
+    z: U256,
+}
+
+const INFINITY: Point = Point { x: ZERO, y: ONE, z: ZERO };
+const G: Point = Point { x: GX, y: GY, z: ONE };
+
+impl Point {
+    fn is_infinity(&self) -> bool {
+        return is_zero(&self.z);
+    }
+
+    /// `a` if `mask` is all ones, `b` if it is zero, without branching
+    fn select(mask: u64, a: &Point, b: &Point) -> Point {
+        return Point {
+            x: select(mask, &a.x, &b.x),
+            y: select(mask, &a.y, &b.y),
+            z: select(mask, &a.z, &b.z),
+        };
+    }
+
+    /// Complete doubling, algorithm 9 of Renes, Costello and Batina, "Complete addition formulas
+    /// for prime order elliptic curves"
+    fn double(&self) -> Point {
+        let f = &FIELD;
+        let t0 = f.mul(&self.y, &self.y);
+        let z3 = f.add(&t0, &t0);
+        let z3 = f.add(&z3, &z3);
+        let z3 = f.add(&z3, &z3);
+        let t1 = f.mul(&self.y, &self.z);
+        let t2 = f.mul(&self.z, &self.z);
+        let t2 = f.mul(&B3, &t2);
+        let x3 = f.mul(&t2, &z3);
+        let y3 = f.add(&t0, &t2);
+        let z3 = f.mul(&t1, &z3);
+        let t1 = f.add(&t2, &t2);
+        let t2 = f.add(&t1, &t2);
+        let t0 = f.sub(&t0, &t2);
+        let y3 = f.mul(&t0, &y3);
+        let y3 = f.add(&x3, &y3);
+        let t1 = f.mul(&self.x, &self.y);
+        let x3 = f.mul(&t0, &t1);
+        let x3 = f.add(&x3, &x3);
+        return Point { x: x3, y: y3, z: z3 };
+    }
+
+    /// Complete addition, valid for any two points, algorithm 7 of the same paper
+    fn add(&self, other: &Point) -> Point {
+        let f = &FIELD;
+        let t0 = f.mul(&self.x, &other.x);
+        let t1 = f.mul(&self.y, &other.y);
+        let t2 = f.mul(&self.z, &other.z);
+        let t3 = f.mul(&f.add(&self.x, &self.y), &f.add(&other.x, &other.y));
+        let t3 = f.sub(&t3, &f.add(&t0, &t1));
+        let t4 = f.mul(&f.add(&self.y, &self.z), &f.add(&other.y, &other.z));
+        let t4 = f.sub(&t4, &f.add(&t1, &t2));
+        let x3 = f.mul(&f.add(&self.x, &self.z), &f.add(&other.x, &other.z));
+        let y3 = f.sub(&x3, &f.add(&t0, &t2));
+        let t0 = f.add(&f.add(&t0, &t0), &t0);
+        let t2 = f.mul(&B3, &t2);
+        let z3 = f.add(&t1, &t2);
+        let t1 = f.sub(&t1, &t2);
+        let y3 = f.mul(&B3, &y3);
+        let x3 = f.sub(&f.mul(&t3, &t1), &f.mul(&t4, &y3));
+        let y3 = f.add(&f.mul(&t1, &z3), &f.mul(&y3, &t0));
+        let z3 = f.add(&f.mul(&z3, &t4), &f.mul(&t0, &t3));
+        return Point { x: x3, y: y3, z: z3 };
+    }
+
+    /// Multiplication by a scalar, with a Montgomery ladder that does the same operations
+    /// whatever the bits of `k`, as `k` may be secret
+    fn mul(&self, k: &U256) -> Point {
+        let mut r0 = INFINITY;
+        let mut r1 = *self;
+        for i in (0..256).rev() {
+            let swap = mask((k[i / 64] >> (i % 64)) & 1 == 1);
+            let (a, b) = (Point::select(swap, &r1, &r0), Point::select(swap, &r0, &r1));
+            let (a, b) = (a.double(), a.add(&b));
+            r0 = Point::select(swap, &b, &a);
+            r1 = Point::select(swap, &a, &b);
+        }
+        return r0;
+    }
+
+    /// Affine coordinates, or `None` for the point at infinity
+    fn to_affine(&self) -> Option<(U256, U256)> {
+        if self.is_infinity() {
+            return None;
+        }
+        let f = &FIELD;
+        let z_inv = f.inv(&self.z);
+        return Some((f.mul(&self.x, &z_inv), f.mul(&self.y, &z_inv)));
+    }
+
+    /// The point with x coordinate `x` and an even y coordinate, if there is one
+    fn lift_x(x: &U256) -> Option<Point> {
+        if cmp(x, &P) != Ordering::Less {
+            return None;
+        }
+        let f = &FIELD;
+        let c = f.add(&f.mul(&f.mul(x, x), x), &[7, 0, 0, 0]);
+        let y = f.pow(&c, &P_SQRT_EXPONENT);
+        if f.mul(&y, &y) != c {
+            return None;
+        }
+        let y = if y[0] & 1 == 0 { y } else { f.neg(&y) };
+        return Some(Point { x: *x, y, z: ONE });
+    }
+}
+
+/// SHA256 of the message prefixed twice with the SHA256 of `tag`, as defined by BIP340
+pub fn tagged_hash(tag: &str, message: &[u8]) -> H256 {
+    let tag_hash = digest(&SHA256, tag.as_bytes());
+    let mut data = Vec::with_capacity(64 + message.len());
+    data.extend_from_slice(tag_hash.as_ref());
+    data.extend_from_slice(tag_hash.as_ref());
+    data.extend_from_slice(message);
+    return digest(&SHA256, &data).into();
+}
+
+fn challenge(r: &[u8], public_key: &[u8], message: &[u8]) -> U256 {
+    let hash = tagged_hash("BIP0340/challenge", &[r, public_key, message].concat());
+    return ORDER.reduce(&from_bytes(hash.as_ref()));
+}
+
+/// A secp256k1 key pair for BIP340 signatures
+pub struct KeyPair {
+    /// The secret key, negated if needed so that the public point has an even y coordinate
+    secret: U256,
+    public_key: PublicKey,
+}
+
+impl KeyPair {
+    /// Generate a random key pair
+    pub fn random() -> Self {
+        let rng = SystemRandom::new();
+        loop {
+            let mut bytes = [0u8; 32];
+            rng.fill(&mut bytes).unwrap();
+            if let Ok(key) = KeyPair::from_secret(&bytes) {
+                return key;
+            }
+        }
+    }
+
+    /// Load a key pair from its 32-byte big-endian secret key
+    pub fn from_secret(bytes: &[u8]) -> Result<Self, KeyError> {
+        if bytes.len() != 32 {
+            return Err(KeyError::Malformed("secret key must be 32 bytes".to_string()));
+        }
+        let secret = from_bytes(bytes);
+        if is_zero(&secret) | !sub(&secret, &N).1 {
+            return Err(KeyError::Malformed("secret key out of range".to_string()));
+        }
+        let (x, y) = G.mul(&secret).to_affine().unwrap();
+        let secret = select(mask(y[0] & 1 == 1), &ORDER.neg(&secret), &secret);
+        return Ok(KeyPair {
+            secret,
+            public_key: PublicKey(to_bytes(&x)),
+        });
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        return self.public_key.clone();
+    }
+
+    /// Sign `message` with fresh auxiliary randomness
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>, KeyError> {
+        let mut aux = [0u8; 32];
+        SystemRandom::new().fill(&mut aux).map_err(|_| KeyError::Random)?;
+        return self.sign_with_aux(message, &aux);
+    }
+
+    /// Sign `message` with the given auxiliary randomness, as specified by BIP340
+    pub fn sign_with_aux(&self, message: &[u8], aux: &[u8; 32]) -> Result<Vec<u8>, KeyError> {
+        let secret = to_bytes(&self.secret);
+        let aux_hash = tagged_hash("BIP0340/aux", aux);
+        let t: Vec<u8> = secret.iter().zip(aux_hash.as_ref().iter()).map(|(a, b)| a ^ b).collect();
+        let nonce = tagged_hash("BIP0340/nonce", &[&t[..], &self.public_key.0, message].concat());
+        // the nonce is only zero with negligible probability, in which case signing fails
+        let k = ORDER.reduce(&from_bytes(nonce.as_ref()));
+        if is_zero(&k) {
+            return Err(KeyError::ZeroNonce);
+        }
+        let (rx, ry) = G.mul(&k).to_affine().unwrap();
+        let k = select(mask(ry[0] & 1 == 1), &ORDER.neg(&k), &k);
+        let r = to_bytes(&rx);
+        let e = challenge(&r, &self.public_key.0, message);
+        let s = ORDER.add(&k, &ORDER.mul(&e, &self.secret));
+        return Ok([r, to_bytes(&s)].concat());
+    }
+}
+
+/// A BIP340 public key: the x coordinate of a point with an even y coordinate
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        return PublicKey(bytes);
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        return &self.0;
+    }
+
+    /// Check a 64-byte signature of `message`
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        if signature.len() != 64 {
+            return false;
+        }
+        let point = match Point::lift_x(&from_bytes(&self.0)) {
+            Some(point) => point,
+            None => return false,
+        };
+        let r = from_bytes(&signature[..32]);
+        let s = from_bytes(&signature[32..]);
+        if cmp(&r, &P) != Ordering::Less || cmp(&s, &N) != Ordering::Less {
+            return false;
+        }
+        let e = challenge(&signature[..32], &self.0, message);
+        let rpoint = G.mul(&s).add(&point.mul(&ORDER.neg(&e)));
+        return match rpoint.to_affine() {
+            Some((x, y)) => y[0] & 1 == 0 && x == r,
+            None => false,
+        };
+    }
+
+    /// The hash of the key, which outputs paying to the key use as recipient
+    pub fn address(&self) -> H256 {
+        return digest(&SHA256, &self.0).into();
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+
+    fn decode<const L: usize>(s: &str) -> [u8; L] {
+        let mut bytes = [0u8; L];
+        hex::decode_to_slice(s, &mut bytes).unwrap();
+        return bytes;
+    }
+
+    #[test]
+    fn bip340_vectors() {
+        // test vectors 0 and 1 of BIP340
+        let key = KeyPair::from_secret(&decode::<32>("0000000000000000000000000000000000000000000000000000000000000003")).unwrap();
+        assert_eq!(key.public_key().0, decode::<32>("F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9"));
+        let message = [0u8; 32];
+        let signature = key.sign_with_aux(&message, &[0u8; 32]).unwrap();
+        assert_eq!(signature, decode::<64>("E907831F80848D1069A5371B402410364BDF1C5F8307B0084C55F1CE2DCA821525F66A4A85EA8B71E482A74F382D2CE5EBEEE8FDB2172F477DF4900D310536C0").to_vec());
+        assert!(key.public_key().verify(&message, &signature));
+
+        let key = KeyPair::from_secret(&decode::<32>("B7E151628AED2A6ABF7158809CF4F3C762E7160F38B4DA56A784D9045190CFEF")).unwrap();
+        assert_eq!(key.public_key().0, decode::<32>("DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659"));
+        let message = decode::<32>("243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89");
+        let aux = decode::<32>("0000000000000000000000000000000000000000000000000000000000000001");
+        let signature = key.sign_with_aux(&message, &aux).unwrap();
+        assert_eq!(signature, decode::<64>("6896BD60EEAE296DB48A229FF71DFE071BDE413E6D43F917DC8DCF8C78DE33418906D11AC976ABCCB20B091292BFF4EA897EFCB639EA871CFA95F6DE339E4B0A").to_vec());
+        assert!(key.public_key().verify(&message, &signature));
+    }
+
+    #[test]
+    fn complete_formulas() {
+        // the ladder goes through the point at infinity, doublings and additions of equal points
+        assert!(G.mul(&N).is_infinity());
+        assert!(G.add(&Point { x: GX, y: FIELD.neg(&GY), z: ONE }).is_infinity());
+        assert_eq!(G.add(&G).to_affine(), G.double().to_affine());
+        assert_eq!(G.mul(&[3, 0, 0, 0]).to_affine(), G.double().add(&G).to_affine());
+        assert_eq!(G.mul(&sub(&N, &ONE).0).to_affine(), Some((GX, FIELD.neg(&GY))));
+    }
+
+    #[test]
+    fn sign_verify() {
+        let key = KeyPair::random();
+        let signature = key.sign(b"message").unwrap();
+        assert!(key.public_key().verify(b"message", &signature));
+        assert!(!key.public_key().verify(b"other message", &signature));
+        assert!(!KeyPair::random().public_key().verify(b"message", &signature));
+        let mut tampered = signature.clone();
+        tampered[63] ^= 1;
+        assert!(!key.public_key().verify(b"message", &tampered));
+        assert!(KeyPair::from_secret(&[0u8; 32]).is_err());
+    }
+}
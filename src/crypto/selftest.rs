@@ -0,0 +1,86 @@
+use ring::digest::{digest, SHA256};
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+
+use super::hash::H256;
+use super::merkle::{self, MerkleTree};
+
+/// Known-answer tests of the cryptographic primitives, run at startup so that a miscompiled or
+/// corrupted binary refuses to start instead of silently disagreeing with the network.
+pub fn run() -> Result<(), String> {
+    sha256()?;
+    merkle_root()?;
+    signature()?;
+    compact_bits()?;
+    return Ok(());
+}
+
+fn decode(s: &str) -> Vec<u8> {
+    hex::decode(s).unwrap()
+}
+
+fn decode_hash(s: &str) -> H256 {
+    let mut raw = [0u8; 32];
+    raw.copy_from_slice(&decode(s));
+    raw.into()
+}
+
+fn sha256() -> Result<(), String> {
+    let expected = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    if digest(&SHA256, b"abc").as_ref() != &decode(expected)[..] {
+        return Err("SHA256 known-answer test failed".to_string());
+    }
+    return Ok(());
+}
+
+fn merkle_root() -> Result<(), String> {
+    let data: Vec<H256> = vec![
+        decode_hash("0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d"),
+        decode_hash("0101010101010101010101010101010101010101010101010101010101010202"),
+    ];
+    let expected = decode_hash("6b787718210e0b3b608814e04e61fde06d0df794319a12162f287412df3ec920");
+    let tree = MerkleTree::new(&data);
+    if tree.root() != expected {
+        return Err("Merkle root known-answer test failed".to_string());
+    }
+    let leaf = decode_hash("965b093a75a75895a351786dd7a188515173f6928a8af8c9baa4dcff268a4f0f");
+    let proof = tree.proof(0);
+    if proof != vec![leaf] || !merkle::verify(&expected, &super::hash::Hashable::hash(&data[0]), &proof, 0, 2) {
+        return Err("Merkle proof known-answer test failed".to_string());
+    }
+    return Ok(());
+}
+
+fn signature() -> Result<(), String> {
+    // test vector 1 of RFC 8032
+    let seed = decode("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60");
+    let public_key = decode("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+    let expected = decode(
+        "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+    );
+    let key = Ed25519KeyPair::from_seed_and_public_key(&seed, &public_key)
+        .map_err(|_| "Ed25519 key known-answer test failed".to_string())?;
+    if key.public_key().as_ref() != &public_key[..] || key.sign(b"").as_ref() != &expected[..] {
+        return Err("Ed25519 signing known-answer test failed".to_string());
+    }
+    let verifier = UnparsedPublicKey::new(&ED25519, &public_key);
+    if verifier.verify(b"", &expected).is_err() || verifier.verify(b"x", &expected).is_ok() {
+        return Err("Ed25519 verification known-answer test failed".to_string());
+    }
+    return Ok(());
+}
+
+fn compact_bits() -> Result<(), String> {
+    let target = decode_hash("00000000ffff0000000000000000000000000000000000000000000000000000");
+    if H256::from_compact(0x1d00ffff) != target || target.to_compact() != 0x1d00ffff {
+        return Err("compact bits known-answer test failed".to_string());
+    }
+    return Ok(());
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    #[test]
+    fn self_test_passes() {
+        assert_eq!(super::run(), Ok(()));
+    }
+}
@@ -0,0 +1,168 @@
+//! Primitives of Bitcoin's raw serialization format: little-endian integers, compact size
+//! integers counting items, and length-prefixed byte strings
+
+/// Largest count or byte length accepted when decoding, so a corrupt count cannot exhaust memory
+pub const MAX_DECODE_SIZE: u64 = 4_000_000;
+
+/// Reasons for decoding raw bytes to fail
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The bytes end in the middle of an item
+    Truncated,
+    /// Bytes are left after the decoded item
+    TrailingData(usize),
+    /// A compact size uses more bytes than needed
+    NonCanonicalSize,
+    /// A count or length above `MAX_DECODE_SIZE`
+    Oversized(u64),
+    /// The segregated witness flag is not 1
+    BadWitnessFlag(u8),
+    /// The witness flag is set, but no input has a witness
+    SuperfluousWitness,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "unexpected end of data"),
+            DecodeError::TrailingData(n) => write!(f, "{} bytes of trailing data", n),
+            DecodeError::NonCanonicalSize => write!(f, "non-canonical compact size"),
+            DecodeError::Oversized(n) => write!(f, "size {} too large", n),
+            DecodeError::BadWitnessFlag(flag) => write!(f, "unknown witness flag {}", flag),
+            DecodeError::SuperfluousWitness => write!(f, "witness flag set without witnesses"),
+        }
+    }
+}
+
+/// Append the compact size encoding of `n`: one byte below 0xfd, else a marker byte and 2, 4 or
+/// 8 little-endian bytes
+pub fn write_compact_size(buffer: &mut Vec<u8>, n: u64) {
+    if n < 0xfd {
+        buffer.push(n as u8);
+    } else if n <= 0xffff {
+        buffer.push(0xfd);
+        buffer.extend_from_slice(&(n as u16).to_le_bytes());
+    } else if n <= 0xffff_ffff {
+        buffer.push(0xfe);
+        buffer.extend_from_slice(&(n as u32).to_le_bytes());
+    } else {
+        buffer.push(0xff);
+        buffer.extend_from_slice(&n.to_le_bytes());
+    }
+}
+
+/// Append `bytes` prefixed with their length
+pub fn write_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    write_compact_size(buffer, bytes.len() as u64);
+    buffer.extend_from_slice(bytes);
+}
+
+/// A cursor decoding items from raw bytes
+pub struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        return Reader { data, position: 0 };
+    }
+
+    /// The next `n` bytes
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.data.len() - self.position < n {
+            return Err(DecodeError::Truncated);
+        }
+        let bytes = &self.data[self.position..self.position + n];
+        self.position += n;
+        return Ok(bytes);
+    }
+
+    /// The next byte, without consuming it
+    pub fn peek(&self) -> Option<u8> {
+        return self.data.get(self.position).copied();
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, DecodeError> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        return Ok(u32::from_le_bytes(bytes));
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, DecodeError> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        return Ok(u64::from_le_bytes(bytes));
+    }
+
+    pub fn read_array32(&mut self) -> Result<[u8; 32], DecodeError> {
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(self.take(32)?);
+        return Ok(bytes);
+    }
+
+    /// A compact size, rejecting non-canonical encodings and values above `MAX_DECODE_SIZE`
+    pub fn read_compact_size(&mut self) -> Result<u64, DecodeError> {
+        let (n, min) = match self.read_u8()? {
+            0xfd => {
+                let mut bytes = [0u8; 2];
+                bytes.copy_from_slice(self.take(2)?);
+                (u16::from_le_bytes(bytes) as u64, 0xfd)
+            }
+            0xfe => (self.read_u32()? as u64, 0x1_0000),
+            0xff => (self.read_u64()?, 0x1_0000_0000),
+            n => return Ok(n as u64),
+        };
+        if n < min {
+            return Err(DecodeError::NonCanonicalSize);
+        }
+        if n > MAX_DECODE_SIZE {
+            return Err(DecodeError::Oversized(n));
+        }
+        return Ok(n);
+    }
+
+    /// Bytes prefixed with their length
+    pub fn read_bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let len = self.read_compact_size()? as usize;
+        return Ok(self.take(len)?.to_vec());
+    }
+
+    /// The bytes not read yet
+    pub fn remaining(&self) -> &'a [u8] {
+        return &self.data[self.position..];
+    }
+
+    /// Fail unless all bytes were read
+    pub fn finish(&self) -> Result<(), DecodeError> {
+        if self.position < self.data.len() {
+            return Err(DecodeError::TrailingData(self.data.len() - self.position));
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_size() {
+        for &(n, len) in [(0u64, 1), (0xfc, 1), (0xfd, 3), (0xffff, 3), (0x1_0000, 5), (MAX_DECODE_SIZE, 5)].iter() {
+            let mut buffer = Vec::new();
+            write_compact_size(&mut buffer, n);
+            assert_eq!(buffer.len(), len);
+            let mut reader = Reader::new(&buffer);
+            assert_eq!(reader.read_compact_size(), Ok(n));
+            assert_eq!(reader.finish(), Ok(()));
+        }
+        assert_eq!(Reader::new(&[0xfd, 0xfc, 0x00]).read_compact_size(), Err(DecodeError::NonCanonicalSize));
+        assert_eq!(Reader::new(&[0xfe, 0, 0, 0, 1]).read_compact_size(), Err(DecodeError::Oversized(1 << 24)));
+        assert_eq!(Reader::new(&[0xfd, 0xff]).read_compact_size(), Err(DecodeError::Truncated));
+        assert_eq!(Reader::new(&[0x02, 0xaa]).read_bytes(), Err(DecodeError::Truncated));
+    }
+}
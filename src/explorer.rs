@@ -0,0 +1,127 @@
+use serde::{Serialize, Deserialize};
+
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::Hashable;
+use crate::transaction::{Transaction, TxInput, TxOutput};
+
+/// The output spent by an input, as resolved from the chain
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PreviousOutput {
+    /// Transaction that created the output
+    pub txid: String,
+    pub output: AnnotatedOutput,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AnnotatedInput {
+    /// The spent output as `txid:index`, or `coinbase`
+    pub input: String,
+    /// The spent output, if it could be resolved
+    pub previous_output: Option<PreviousOutput>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AnnotatedOutput {
+    pub value: u64,
+    pub recipient: String,
+    /// Hex of the data carried by data carrier outputs, see `Script::null_data`
+    pub data: Option<String>,
+}
+
+impl From<&TxOutput> for AnnotatedOutput {
+    fn from(output: &TxOutput) -> Self {
+        AnnotatedOutput {
+            value: output.value,
+            recipient: output.recipient.to_string(),
+            data: output.script_pubkey.null_data_payload().map(hex::encode),
+        }
+    }
+}
+
+/// A transaction with its inputs resolved and its position in the chain, for explorers
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AnnotatedTransaction {
+    pub txid: String,
+    /// Block containing the transaction, if confirmed
+    pub block: Option<String>,
+    pub height: Option<u32>,
+    pub inputs: Vec<AnnotatedInput>,
+    pub outputs: Vec<AnnotatedOutput>,
+}
+
+/// Resolve the output spent by `input` through the transaction index
+fn resolve_input(input: &TxInput, blockchain: &Blockchain) -> Option<PreviousOutput> {
+    if input.is_coinbase() {
+        return None;
+    }
+    let (previous, _) = blockchain.find_transaction(&input.prev_txid)?;
+    let output = previous.get_outputs().get(input.index as usize)?;
+    return Some(PreviousOutput {
+        txid: input.prev_txid.to_string(),
+        output: output.into(),
+    });
+}
+
+/// Annotate a transaction, raw or confirmed, using the transaction index of the blockchain
+pub fn annotate(transaction: &Transaction, blockchain: &Blockchain) -> AnnotatedTransaction {
+    let txid = transaction.hash();
+    let block = blockchain.find_transaction(&txid).map(|(_, block)| block);
+    let height = block.and_then(|b| blockchain.height_of(&b));
+    let inputs = transaction
+        .get_inputs()
+        .iter()
+        .map(|input| AnnotatedInput {
+            input: if input.is_coinbase() { "coinbase".to_string() } else { input.outpoint().to_string() },
+            previous_output: resolve_input(input, blockchain),
+        })
+        .collect();
+    let outputs = transaction.get_outputs().iter().map(AnnotatedOutput::from).collect();
+    return AnnotatedTransaction {
+        txid: txid.to_string(),
+        block: block.map(|b| b.to_string()),
+        height,
+        inputs,
+        outputs,
+    };
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block::Block;
+    use crate::crypto::hash::H256;
+    use crate::crypto::merkle::MerkleTree;
+
+    #[test]
+    fn annotate_confirmed_and_raw() {
+        let mut blockchain = Blockchain::new();
+        let funding = Transaction::coinbase(1, H256::from([1u8; 32]), 50);
+        let transactions = vec![funding.clone()];
+        let merkle_root = MerkleTree::new(&transactions).root();
+        let block = Block::new(blockchain.tip(), Blockchain::get_difficulty(), transactions, merkle_root);
+        blockchain.insert(&block).unwrap();
+
+        let annotated = annotate(&funding, &blockchain);
+        assert_eq!(annotated.block, Some(block.hash().to_string()));
+        assert_eq!(annotated.height, Some(1));
+        assert_eq!(annotated.inputs[0].input, "coinbase");
+        assert_eq!(annotated.inputs[0].previous_output, None);
+
+        let bob = TxOutput::new(20, H256::from([2u8; 32]));
+        let spending = Transaction::new(vec![TxInput::new(funding.hash(), 0)], vec![bob.clone()]);
+        let annotated = annotate(&spending, &blockchain);
+        assert_eq!(annotated.block, None);
+        assert_eq!(
+            annotated.inputs[0].previous_output,
+            Some(PreviousOutput {
+                txid: funding.hash().to_string(),
+                output: AnnotatedOutput {
+                    value: 50,
+                    recipient: H256::from([1u8; 32]).to_string(),
+                    data: None,
+                },
+            })
+        );
+        assert_eq!(annotated.outputs, vec![AnnotatedOutput::from(&bob)]);
+    }
+}
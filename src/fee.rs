@@ -0,0 +1,79 @@
+use serde::{Serialize, Deserialize};
+use std::ops::{Add, Sub};
+
+use crate::transaction::Amount;
+
+/// A fee per virtual byte of transaction, stored in satoshis per 1000 virtual bytes so that
+/// rates below 1 sat/vB can be expressed
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FeeRate(u64);
+
+impl FeeRate {
+    pub const ZERO: FeeRate = FeeRate(0);
+
+    pub fn from_sat_per_vb(sat_per_vb: u64) -> Self {
+        return FeeRate(sat_per_vb.saturating_mul(1000));
+    }
+
+    pub const fn from_sat_per_kvb(sat_per_kvb: u64) -> Self {
+        return FeeRate(sat_per_kvb);
+    }
+
+    /// The rate paid by a transaction of `vsize` virtual bytes paying `fee`
+    pub fn from_fee(fee: Amount, vsize: usize) -> Self {
+        if vsize == 0 {
+            return FeeRate::ZERO;
+        }
+        return FeeRate(fee.saturating_mul(1000) / vsize as u64);
+    }
+
+    pub fn sat_per_kvb(&self) -> u64 {
+        return self.0;
+    }
+
+    /// The fee at this rate of a transaction of `vsize` virtual bytes, rounded up
+    pub fn fee(&self, vsize: usize) -> Amount {
+        return (self.0.saturating_mul(vsize as u64) + 999) / 1000;
+    }
+}
+
+impl Add for FeeRate {
+    type Output = FeeRate;
+
+    fn add(self, other: FeeRate) -> FeeRate {
+        return FeeRate(self.0.saturating_add(other.0));
+    }
+}
+
+impl Sub for FeeRate {
+    type Output = FeeRate;
+
+    /// Saturates at zero
+    fn sub(self, other: FeeRate) -> FeeRate {
+        return FeeRate(self.0.saturating_sub(other.0));
+    }
+}
+
+impl std::fmt::Display for FeeRate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{:03} sat/vB", self.0 / 1000, self.0 % 1000)
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates() {
+        let rate = FeeRate::from_fee(1500, 1000);
+        assert_eq!(rate, FeeRate::from_sat_per_kvb(1500));
+        assert_eq!(rate.to_string(), "1.500 sat/vB");
+        assert_eq!(rate.fee(1000), 1500);
+        assert_eq!(rate.fee(1), 2);
+        assert!(rate > FeeRate::from_sat_per_vb(1));
+        assert_eq!(rate + FeeRate::from_sat_per_kvb(500), FeeRate::from_sat_per_vb(2));
+        assert_eq!(FeeRate::from_sat_per_vb(1) - rate, FeeRate::ZERO);
+        assert_eq!(FeeRate::from_fee(100, 0), FeeRate::ZERO);
+    }
+}
@@ -0,0 +1,285 @@
+//! Fee rate suggestions learned from how long the transactions seen in the mempool took to be
+//! mined, grouped in buckets of close fee rates
+
+use crossbeam::channel::select;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use crate::blockchain::{Blockchain, ChainEvent};
+use crate::crypto::hash::H256;
+use crate::fee::FeeRate;
+use crate::mempool::{Mempool, MempoolEvent, RemovalReason};
+
+/// Most blocks a transaction may be expected to wait for, see `FeeEstimator::estimate_feerate`
+pub const MAX_CONFIRMATION_TARGET: usize = 48;
+
+/// Rate suggested when there is not enough history to estimate one
+pub const FALLBACK_FEE_RATE: FeeRate = FeeRate::from_sat_per_kvb(1000);
+
+/// Lower bound of the lowest bucket, in sat/kvB
+const MIN_BUCKET_RATE: f64 = 1000.0;
+
+/// Lower bound above which no more buckets are added, in sat/kvB
+const MAX_BUCKET_RATE: f64 = 10_000_000.0;
+
+/// Ratio between the lower bounds of two consecutive buckets
+const BUCKET_SPACING: f64 = 1.1;
+
+/// Weight kept by past observations at each block, so that older blocks matter less
+const DECAY: f64 = 0.998;
+
+/// Share of the transactions of a range of buckets that must have been mined within the target
+/// for the range to be suggested
+const SUCCESS_THRESHOLD: f64 = 0.85;
+
+/// Weighted number of transactions a range of buckets needs before its share is trusted
+const MIN_DATA_POINTS: f64 = 2.0;
+
+/// A transaction waiting in the mempool
+struct Tracked {
+    bucket: usize,
+    /// Height of the tip when it entered the pool
+    height: u32,
+}
+
+/// Tracks the fee rates of the transactions of the mempool, and the number of blocks they took to
+/// be mined
+pub struct FeeEstimator {
+    /// Lower bound of each bucket, in sat/kvB
+    buckets: Vec<u64>,
+    /// Weighted number of transactions of each bucket mined within each number of blocks, by
+    /// target then bucket
+    confirmed: Vec<Vec<f64>>,
+    /// Weighted number of transactions of each bucket that were mined, or waited for longer than
+    /// `MAX_CONFIRMATION_TARGET` blocks
+    total: Vec<f64>,
+    tracked: HashMap<H256, Tracked>,
+}
+
+impl FeeEstimator {
+    pub fn new() -> Self {
+        let mut buckets: Vec<u64> = Vec::new();
+        let mut rate = MIN_BUCKET_RATE;
+        while rate <= MAX_BUCKET_RATE {
+            buckets.push(rate as u64);
+            rate *= BUCKET_SPACING;
+        }
+        return FeeEstimator {
+            confirmed: vec![vec![0.0; buckets.len()]; MAX_CONFIRMATION_TARGET],
+            total: vec![0.0; buckets.len()],
+            buckets,
+            tracked: HashMap::new(),
+        };
+    }
+
+    /// Bucket of `fee_rate`: the last whose lower bound it reaches, or the first
+    fn bucket_of(&self, fee_rate: FeeRate) -> usize {
+        return match self.buckets.binary_search(&fee_rate.sat_per_kvb()) {
+            Ok(bucket) => bucket,
+            Err(0) => 0,
+            Err(next) => next - 1,
+        };
+    }
+
+    /// Number of transactions waiting to be mined
+    pub fn num_tracked(&self) -> usize {
+        return self.tracked.len();
+    }
+
+    /// Start tracking a transaction paying `fee_rate` that entered the mempool while the tip was
+    /// at `height`
+    pub fn process_transaction(&mut self, txid: H256, fee_rate: FeeRate, height: u32) {
+        let bucket = self.bucket_of(fee_rate);
+        self.tracked.insert(txid, Tracked { bucket, height });
+    }
+
+    /// Stop tracking a transaction that left the mempool without being mined
+    pub fn remove_transaction(&mut self, txid: &H256) {
+        self.tracked.remove(txid);
+    }
+
+    /// Record the block at `height` mining the transactions with `txids`. Transactions waiting
+    /// for longer than `MAX_CONFIRMATION_TARGET` blocks count as failures and stop being tracked.
+    pub fn process_block(&mut self, height: u32, txids: &[H256]) {
+        for row in self.confirmed.iter_mut() {
+            row.iter_mut().for_each(|count| *count *= DECAY);
+        }
+        self.total.iter_mut().for_each(|count| *count *= DECAY);
+
+        for txid in txids {
+            let tracked = match self.tracked.remove(txid) {
+                Some(tracked) => tracked,
+                None => continue,
+            };
+            let blocks = std::cmp::max(height.saturating_sub(tracked.height), 1) as usize;
+            for target in blocks..=MAX_CONFIRMATION_TARGET {
+                self.confirmed[target - 1][tracked.bucket] += 1.0;
+            }
+            self.total[tracked.bucket] += 1.0;
+        }
+        let total = &mut self.total;
+        self.tracked.retain(|_, tracked| {
+            if height.saturating_sub(tracked.height) as usize > MAX_CONFIRMATION_TARGET {
+                total[tracked.bucket] += 1.0;
+                return false;
+            }
+            return true;
+        });
+    }
+
+    /// The lowest fee rate at which transactions were mostly mined within `target_blocks`,
+    /// between 1 and `MAX_CONFIRMATION_TARGET`. Buckets are grouped from the highest rate down
+    /// until they have enough transactions, and the rate is the lower bound of the last group
+    /// mined often enough. None without enough history.
+    pub fn estimate_feerate(&self, target_blocks: usize) -> Option<FeeRate> {
+        let target = std::cmp::min(std::cmp::max(target_blocks, 1), MAX_CONFIRMATION_TARGET);
+        let mut best: Option<usize> = None;
+        let (mut confirmed, mut total) = (0.0, 0.0);
+        for bucket in (0..self.buckets.len()).rev() {
+            confirmed += self.confirmed[target - 1][bucket];
+            total += self.total[bucket];
+            if total >= MIN_DATA_POINTS {
+                if confirmed / total < SUCCESS_THRESHOLD {
+                    break;
+                }
+                best = Some(bucket);
+                confirmed = 0.0;
+                total = 0.0;
+            }
+        }
+        return best.map(|bucket| FeeRate::from_sat_per_kvb(self.buckets[bucket]));
+    }
+
+    /// `estimate_feerate`, or `FALLBACK_FEE_RATE` without enough history
+    pub fn suggest_feerate(&self, target_blocks: usize) -> FeeRate {
+        return self.estimate_feerate(target_blocks).unwrap_or(FALLBACK_FEE_RATE);
+    }
+}
+
+impl Default for FeeEstimator {
+    fn default() -> Self {
+        FeeEstimator::new()
+    }
+}
+
+/// Keep `estimator` up to date in a thread: the transactions entering `mempool` are tracked, and
+/// those of the blocks connected to the longest chain of `blockchain` are recorded as mined
+pub fn follow(estimator: &Arc<RwLock<FeeEstimator>>, mempool: &Arc<RwLock<Mempool>>, blockchain: &Arc<RwLock<Blockchain>>) {
+    let chain_events = blockchain.write().unwrap().subscribe();
+    let mempool_events = mempool.write().unwrap().subscribe();
+    let estimator = Arc::clone(estimator);
+    let mempool = Arc::clone(mempool);
+    let blockchain = Arc::clone(blockchain);
+    thread::Builder::new()
+        .name("fee estimator".to_string())
+        .spawn(move || loop {
+            select! {
+                recv(chain_events) -> event => match event {
+                    Ok(ChainEvent::Connected(hash)) => {
+                        let blockchain = blockchain.read().unwrap();
+                        let block = blockchain.get(&hash);
+                        let txids: Vec<H256> = block.get_transactions().iter().map(|t| t.txid()).collect();
+                        estimator.write().unwrap().process_block(blockchain.height_of(&hash).unwrap(), &txids);
+                    }
+                    Ok(_) => {}
+                    Err(_) => return,
+                },
+                recv(mempool_events) -> event => match event {
+                    Ok(MempoolEvent::Added { txid }) => {
+                        let height = blockchain.read().unwrap().tip_height();
+                        if let Some(entry) = mempool.read().unwrap().get(&txid) {
+                            estimator.write().unwrap().process_transaction(txid, entry.fee_rate(), height);
+                        }
+                    }
+                    // the mined transactions are recorded with their block
+                    Ok(MempoolEvent::Removed { reason: RemovalReason::Block, .. }) => {}
+                    Ok(MempoolEvent::Removed { txid, .. }) => estimator.write().unwrap().remove_transaction(&txid),
+                    Err(_) => return,
+                },
+            }
+        })
+        .unwrap();
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block::Block;
+    use crate::crypto::keys::KeyPair;
+    use crate::crypto::merkle::MerkleTree;
+    use crate::mempool::tests::{funded, spend};
+    use crate::transaction::Transaction;
+    use crate::utxo::OutPoint;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn estimates() {
+        let mut estimator = FeeEstimator::new();
+        assert_eq!(estimator.estimate_feerate(1), None);
+        assert_eq!(estimator.suggest_feerate(1), FALLBACK_FEE_RATE);
+
+        // each block mines the high fee transactions of the previous block, and the low fee ones
+        // sent five blocks before
+        let high = FeeRate::from_sat_per_vb(20);
+        let low = FeeRate::from_sat_per_vb(2);
+        let txid = |height: u32, i: u8| {
+            let mut bytes = [0u8; 32];
+            bytes[0] = height as u8;
+            bytes[1] = i;
+            H256::from(bytes)
+        };
+        for height in 0..40u32 {
+            for i in 0..4 {
+                estimator.process_transaction(txid(height, i), high, height);
+                estimator.process_transaction(txid(height, 10 + i), low, height);
+            }
+            let mut mined: Vec<H256> = (0..4).map(|i| txid(height, i)).collect();
+            if height >= 5 {
+                mined.extend((0..4).map(|i| txid(height - 5, 10 + i)));
+            }
+            estimator.process_block(height + 1, &mined);
+        }
+
+        let fast = estimator.estimate_feerate(1).unwrap();
+        assert!(fast <= high && fast > low);
+        let slow = estimator.estimate_feerate(6).unwrap();
+        assert!(slow <= low);
+        assert_eq!(estimator.estimate_feerate(100), estimator.estimate_feerate(MAX_CONFIRMATION_TARGET));
+        // the low fee transactions of the last blocks are still waiting
+        assert_eq!(estimator.num_tracked(), 5 * 4);
+        estimator.remove_transaction(&txid(39, 10));
+        assert_eq!(estimator.num_tracked(), 5 * 4 - 1);
+    }
+
+    #[test]
+    fn follows_mempool_and_chain() {
+        let key = KeyPair::random();
+        let (_, funding) = funded(&key, 1, 100_000);
+        let merkle_root = MerkleTree::new(&[funding.clone()]).root();
+        let genesis = Block::new(H256::default(), Blockchain::get_difficulty(), vec![funding.clone()], merkle_root);
+        let blockchain = Arc::new(RwLock::new(Blockchain::with_genesis(genesis)));
+        let mempool = Arc::new(RwLock::new(Mempool::new()));
+        let estimator = Arc::new(RwLock::new(FeeEstimator::new()));
+        follow(&estimator, &mempool, &blockchain);
+        let wait_for = |tracked: usize| {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while estimator.read().unwrap().num_tracked() != tracked {
+                assert!(Instant::now() < deadline);
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+
+        // a transaction entering the mempool is tracked until a block mines it
+        let transaction = spend(&key, &[OutPoint::new(funding.txid(), 0)], 90_000);
+        mempool.write().unwrap().accept(transaction.clone(), blockchain.read().unwrap().utxo_set()).unwrap();
+        wait_for(1);
+        let mut blockchain = blockchain.write().unwrap();
+        let transactions = vec![Transaction::coinbase(1, H256::default(), 0), transaction];
+        let merkle_root = MerkleTree::new(&transactions).root();
+        let block = Block::new(blockchain.tip(), Blockchain::get_difficulty(), transactions, merkle_root);
+        blockchain.insert(&block).unwrap();
+        drop(blockchain);
+        wait_for(0);
+    }
+}
@@ -0,0 +1,358 @@
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+
+use crate::block::Header;
+use crate::blockchain::{Blockchain, LOCATOR_DENSE_SPAN, MAX_RETARGET_FACTOR, RETARGET_INTERVAL, TARGET_BLOCK_TIME};
+use crate::crypto::hash::{H256, Hashable};
+use crate::validation::{self, ValidationError, MEDIAN_TIME_SPAN};
+
+/// Reasons for a header to be refused by `HeaderTree::accept`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The parent of the header is not in the tree
+    UnknownParent,
+    Invalid(ValidationError),
+}
+
+impl std::fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HeaderError::UnknownParent => write!(f, "unknown parent"),
+            HeaderError::Invalid(e) => write!(f, "invalid header: {}", e),
+        }
+    }
+}
+
+impl HeaderError {
+    /// Whether the header is refused for good, rather than until later or until its parent is
+    /// known, see `ValidationError::is_permanent`
+    pub fn is_permanent(&self) -> bool {
+        return match self {
+            HeaderError::UnknownParent => false,
+            HeaderError::Invalid(e) => e.is_permanent(),
+        };
+    }
+}
+
+/// The tree of all known headers, whether or not the body of their block was downloaded, so
+/// that headers can be validated and the best chain found before downloading any block
+pub struct HeaderTree {
+    headers: HashMap<H256, Header>,
+    heights: HashMap<H256, u32>,
+    /// Total work of each header and its ancestors
+    chain_work: HashMap<H256, H256>,
+    /// Headers whose block is in the blockchain
+    bodies: HashSet<H256>,
+    /// Headers whose block turned out invalid, excluded with their descendants from the best
+    invalid: HashSet<H256>,
+    /// The header with the most work that neither is nor descends from an invalid one
+    best: H256,
+}
+
+impl HeaderTree {
+    /// Create a tree only containing the genesis header, whose body is known
+    pub fn new(genesis: &Header) -> Self {
+        let hash = genesis.hash();
+        let mut tree = HeaderTree {
+            headers: HashMap::new(),
+            heights: HashMap::new(),
+            chain_work: HashMap::new(),
+            bodies: HashSet::new(),
+            invalid: HashSet::new(),
+            best: hash,
+        };
+        tree.headers.insert(hash, genesis.clone());
+        tree.heights.insert(hash, 0);
+        tree.chain_work.insert(hash, genesis.get_difficulty().work());
+        tree.bodies.insert(hash);
+        return tree;
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        return self.headers.contains_key(hash);
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&Header> {
+        return self.headers.get(hash);
+    }
+
+    pub fn height_of(&self, hash: &H256) -> Option<u32> {
+        return self.heights.get(hash).cloned();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.headers.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.headers.is_empty();
+    }
+
+    /// Get the hash of the header with the most work
+    pub fn best(&self) -> H256 {
+        return self.best;
+    }
+
+    pub fn best_height(&self) -> u32 {
+        return self.heights[&self.best];
+    }
+
+    /// Whether the block of the header is in the blockchain
+    pub fn has_body(&self, hash: &H256) -> bool {
+        return self.bodies.contains(hash);
+    }
+
+    /// Get the headers of up to `n` ancestors of `hash`, starting with the header itself
+    pub fn ancestors(&self, hash: &H256, n: usize) -> Vec<Header> {
+        let mut ancestors: Vec<Header> = Vec::new();
+        let mut next = self.headers.get(hash);
+        while let Some(header) = next {
+            if ancestors.len() == n {
+                break;
+            }
+            ancestors.push(header.clone());
+            next = self.headers.get(&header.get_parent());
+        }
+        return ancestors;
+    }
+
+    /// The hash of the ancestor of `hash` at `height`, the header itself at its own height
+    pub fn ancestor_at(&self, hash: &H256, height: u32) -> Option<H256> {
+        let mut current = *hash;
+        let mut current_height = self.height_of(hash)?;
+        if height > current_height {
+            return None;
+        }
+        while current_height > height {
+            current = self.headers[&current].get_parent();
+            current_height -= 1;
+        }
+        return Some(current);
+    }
+
+    /// The difficulty target a child of `parent` must have, see `Blockchain::next_difficulty`
+    pub fn next_difficulty(&self, parent: &H256) -> H256 {
+        let parent_header = &self.headers[parent];
+        let height = self.heights[parent] + 1;
+        // regtest chains are never adjusted
+        if height % RETARGET_INTERVAL != 0 || parent_header.get_difficulty() == Blockchain::regtest_difficulty() {
+            return parent_header.get_difficulty();
+        }
+        let ancestors = self.ancestors(parent, RETARGET_INTERVAL as usize);
+        let first = ancestors.last().unwrap();
+        let expected = (TARGET_BLOCK_TIME * (RETARGET_INTERVAL - 1)).as_millis() as u64;
+        let actual = parent_header
+            .get_timestamp()
+            .duration_since(first.get_timestamp())
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let actual = std::cmp::max(actual, expected / MAX_RETARGET_FACTOR);
+        let actual = std::cmp::min(actual, expected * MAX_RETARGET_FACTOR);
+        let target = parent_header.get_difficulty().mul_div(actual, expected);
+        return std::cmp::min(target, Blockchain::get_difficulty());
+    }
+
+    /// Check the header against its ancestors: proof of work, difficulty and timestamp
+    pub fn check(&self, header: &Header, now: SystemTime) -> Result<(), HeaderError> {
+        let parent = header.get_parent();
+        if !self.contains(&parent) {
+            return Err(HeaderError::UnknownParent);
+        }
+        validation::check_pow(header).map_err(HeaderError::Invalid)?;
+        if header.get_difficulty() != self.next_difficulty(&parent) {
+            return Err(HeaderError::Invalid(ValidationError::WrongDifficulty));
+        }
+        let ancestors = self.ancestors(&parent, MEDIAN_TIME_SPAN);
+        validation::check_contextual(header, &ancestors, now).map_err(HeaderError::Invalid)?;
+        return Ok(());
+    }
+
+    /// Validate and add a header. Returns whether it was new.
+    pub fn accept(&mut self, header: &Header, now: SystemTime) -> Result<bool, HeaderError> {
+        if self.contains(&header.hash()) {
+            return Ok(false);
+        }
+        self.check(header, now)?;
+        self.insert(header);
+        return Ok(true);
+    }
+
+    /// Add a header without validating it, e.g. the header of a block already validated. Its
+    /// parent must be in the tree. Its work is the one of the difficulty it must have, whatever
+    /// the difficulty it claims.
+    pub fn insert(&mut self, header: &Header) {
+        let hash = header.hash();
+        if self.contains(&hash) {
+            return;
+        }
+        let parent = header.get_parent();
+        let work = self.chain_work[&parent].saturating_add(&self.next_difficulty(&parent).work());
+        self.heights.insert(hash, self.heights[&parent] + 1);
+        self.chain_work.insert(hash, work);
+        self.headers.insert(hash, header.clone());
+        if work > self.chain_work[&self.best] && !self.is_invalid(&hash) {
+            self.best = hash;
+        }
+    }
+
+    /// Whether the header or one of its ancestors was marked invalid
+    pub fn is_invalid(&self, hash: &H256) -> bool {
+        return self.invalid.iter().any(|bad| {
+            self.height_of(bad).map_or(false, |height| self.ancestor_at(hash, height) == Some(*bad))
+        });
+    }
+
+    /// Record that the block of the header is invalid, so that the best header is no longer
+    /// looked for among its descendants
+    pub fn mark_invalid(&mut self, hash: &H256) {
+        if self.contains(hash) && self.height_of(hash) != Some(0) && self.invalid.insert(*hash) {
+            self.find_best();
+        }
+    }
+
+    /// Undo `mark_invalid`
+    pub fn mark_valid(&mut self, hash: &H256) {
+        if self.invalid.remove(hash) {
+            self.find_best();
+        }
+    }
+
+    /// Find the valid header with the most work, the genesis one being always valid
+    fn find_best(&mut self) {
+        let mut candidates: Vec<(&H256, &H256)> = self.chain_work.iter().collect();
+        candidates.sort_by(|a, b| b.1.cmp(a.1));
+        self.best = candidates
+            .into_iter()
+            .map(|(hash, _)| *hash)
+            .find(|hash| !self.is_invalid(hash))
+            .unwrap();
+    }
+
+    /// Record that the block of the header is in the blockchain
+    pub fn set_body(&mut self, hash: &H256) {
+        if self.contains(hash) {
+            self.bodies.insert(*hash);
+        }
+    }
+
+    /// Build a block locator for the best header chain, like `Blockchain::locator`, so that peers
+    /// send the headers following those already known rather than those of our blocks
+    pub fn locator(&self) -> Vec<H256> {
+        let mut locator: Vec<H256> = Vec::new();
+        let mut step: usize = 1;
+        let mut skip: usize = 0;
+        let mut last = self.best;
+        let mut next = self.headers.get(&self.best);
+        while let Some(header) = next {
+            last = header.hash();
+            if skip == 0 {
+                locator.push(last);
+                if locator.len() >= LOCATOR_DENSE_SPAN {
+                    step *= 2;
+                }
+                skip = step;
+            }
+            skip -= 1;
+            next = self.headers.get(&header.get_parent());
+        }
+        if locator.last() != Some(&last) {
+            locator.push(last);
+        }
+        return locator;
+    }
+
+    /// Hashes of up to `n` blocks of the best header chain whose body is missing, from the lowest.
+    /// These are the blocks to download next in headers-first sync.
+    pub fn missing_bodies(&self, n: usize) -> Vec<H256> {
+        let mut missing: Vec<H256> = Vec::new();
+        let mut next = self.best;
+        while !self.has_body(&next) {
+            missing.push(next);
+            next = self.headers[&next].get_parent();
+        }
+        missing.reverse();
+        missing.truncate(n);
+        return missing;
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block::test::{generate_mined_block, generate_random_block_at};
+    use std::time::Duration;
+
+    #[test]
+    fn headers_first() {
+        let genesis = Blockchain::genesis_block();
+        let mut tree = HeaderTree::new(genesis.get_header());
+        let now = SystemTime::now();
+        let mut headers: Vec<Header> = Vec::new();
+        let mut parent = genesis.hash();
+        for _ in 0..3 {
+            let block = generate_mined_block(&parent);
+            headers.push(block.get_header().clone());
+            parent = block.hash();
+        }
+        assert_eq!(tree.accept(&headers[1], now), Err(HeaderError::UnknownParent));
+        for header in &headers {
+            assert_eq!(tree.accept(header, now), Ok(true));
+        }
+        assert_eq!(tree.accept(&headers[0], now), Ok(false));
+        assert_eq!(tree.best(), headers[2].hash());
+        assert_eq!(tree.best_height(), 3);
+        let hashes: Vec<H256> = headers.iter().map(|h| h.hash()).collect();
+        // the locator follows the headers, ahead of the blocks
+        assert_eq!(tree.locator(), vec![hashes[2], hashes[1], hashes[0], genesis.hash()]);
+        assert_eq!(tree.missing_bodies(10), hashes);
+        assert_eq!(tree.missing_bodies(1), vec![hashes[0]]);
+        tree.set_body(&hashes[0]);
+        assert_eq!(tree.missing_bodies(10), hashes[1..].to_vec());
+    }
+
+    #[test]
+    fn bogus_headers() {
+        let genesis = Blockchain::genesis_block();
+        let mut tree = HeaderTree::new(genesis.get_header());
+        let now = SystemTime::now();
+        let old = loop {
+            let block = generate_random_block_at(&genesis.hash(), genesis.get_timestamp() - Duration::from_secs(1));
+            if block.hash() <= block.get_difficulty() {
+                break block;
+            }
+        };
+        assert_eq!(
+            tree.accept(old.get_header(), now),
+            Err(HeaderError::Invalid(ValidationError::TimestampTooOld))
+        );
+        assert!(!tree.contains(&old.hash()));
+        assert_eq!(tree.len(), 1);
+    }
+
+    #[test]
+    fn invalid_best_header() {
+        let genesis = Blockchain::genesis_block();
+        let mut tree = HeaderTree::new(genesis.get_header());
+        let now = SystemTime::now();
+        let main = generate_mined_block(&genesis.hash());
+        let main2 = generate_mined_block(&main.hash());
+        let side = generate_mined_block(&genesis.hash());
+        for header in &[main.get_header(), main2.get_header(), side.get_header()] {
+            tree.accept(header, now).unwrap();
+        }
+        assert_eq!(tree.best(), main2.hash());
+        // the best header falls back to the valid branch, whatever the work of the invalid one
+        tree.mark_invalid(&main.hash());
+        assert!(tree.is_invalid(&main2.hash()));
+        assert_eq!(tree.best(), side.hash());
+        assert_eq!(tree.missing_bodies(10), vec![side.hash()]);
+        let main3 = generate_mined_block(&main2.hash());
+        assert_eq!(tree.accept(main3.get_header(), now), Ok(true));
+        assert_eq!(tree.best(), side.hash());
+        tree.mark_valid(&main.hash());
+        assert_eq!(tree.best(), main3.hash());
+        // the genesis header is never invalid
+        tree.mark_invalid(&genesis.hash());
+        assert_eq!(tree.best(), main3.hash());
+    }
+}
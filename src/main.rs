@@ -3,25 +3,74 @@
 extern crate hex_literal;
 
 pub mod api;
+pub mod archive;
 pub mod block;
+pub mod block_filter;
+pub mod bloom;
+pub mod builder;
+pub mod coin_selection;
+pub mod bootstrap;
 pub mod blockchain;
+pub mod clock;
 pub mod crypto;
+pub mod encoding;
+pub mod explorer;
+pub mod fee;
+pub mod fee_estimator;
+pub mod headers;
+pub mod mempool;
 pub mod miner;
+pub mod multisig;
 pub mod network;
+pub mod orphans;
+pub mod policy;
+pub mod psbt;
+pub mod replacement;
+pub mod runtime;
+pub mod script;
+pub mod simulation;
+pub mod size;
+pub mod snapshot;
+pub mod store;
 pub mod transaction;
+pub mod utxo;
+pub mod validation;
 
 use clap::clap_app;
 use crossbeam::channel;
-use log::{error, info};
-use api::Server as ApiServer;
+use log::{error, info, warn};
+use api::{Exports, Server as ApiServer};
 use network::{server, worker};
+use network::address_book::AddressBook;
+use network::{discovery, keepalive};
+use network::peer_manager::PeerLimits;
 use std::net;
 use std::process;
 use std::thread;
 use std::time;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, RwLock};
+use std::collections::BTreeMap;
 
 use crate::blockchain::Blockchain;
+use crate::fee::FeeRate;
+use crate::mempool::{Mempool, MempoolConfig};
+use crate::miner::CoinbaseConfig;
+use crate::transaction::TxOutput;
+use crate::archive::ChainArchive;
+use crate::validation::BadBlockCache;
+use crate::store::{CachedStore, ChainStore, FileStore, MemoryStore};
+use crate::snapshot::{HistoryValidator, UtxoSnapshot};
+use crate::runtime::Runtime;
+use crate::crypto::hash::H256;
+use crate::crypto::key_pair;
+use crate::fee_estimator::FeeEstimator;
+
+/// File of the address book in the data directory
+const ADDRESS_FILE: &str = "peers.dat";
+/// Directory of the exports requested through the API, in the data directory
+const EXPORT_DIR: &str = "exports";
+/// File of the key signing the archives of the node, in the data directory
+const ARCHIVE_KEY_FILE: &str = "archive_key.pk8";
 
 fn main() {
     // parse command line arguments
@@ -31,15 +80,79 @@ fn main() {
      (@arg verbose: -v ... "Increases the verbosity of logging")
      (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
-     (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
+     (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start, instead of discovering peers")
+     (@arg dns_seed: --("dns-seed") ... [HOST] "Discovers peers by resolving this host name, instead of trying the default seed nodes")
+     (@arg seed_node: --("seed-node") ... [ADDR] "Discovers peers by connecting to this node, instead of the default seed nodes")
+     (@arg max_inbound: --("max-inbound") [COUNT] "Limits the number of connections accepted from peers")
+     (@arg max_outbound: --("max-outbound") [COUNT] "Limits the number of connections opened to peers")
+     (@arg max_upload: --("max-upload") [MB] "Stops serving historical blocks once MB megabytes were sent to peers within a day")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg pool_size: --pool ... [SIZE] "Sets the size of a thread pool, as NAME=SIZE (validation, mining, network, storage)")
+     (@arg import_archive: --("import-archive") [FILE] "Initializes the blockchain from a verified chain-state archive")
+     (@arg archive_signer: --("archive-signer") [KEY] "Only imports archives signed by this hex-encoded public key")
+     (@arg prune: --prune [DEPTH] "Discards the transactions of blocks buried deeper than DEPTH blocks")
+     (@arg utxo_snapshot: --("utxo-snapshot") [FILE] "Starts from the UTXO snapshot in this file, requires --assume-utxo")
+     (@arg assume_utxo: --("assume-utxo") [HASH] "Hash of the trusted UTXO set of the snapshot")
+     (@arg block_cache: --("block-cache") [SIZE] default_value("1000") "Sets the number of blocks of the data directory cached in memory")
+     (@arg reindex: --reindex "Rebuilds the block index and chain state of the data directory from its blocks")
+     (@arg load_bootstrap: --("load-bootstrap") [FILE] "Validates and inserts the blocks of this bootstrap file, exported by another node")
+     (@arg checkpoint: --checkpoint ... [CHECKPOINT] "Requires the block at a height to have a hash, as HEIGHT:HASH")
+     (@arg bad_blocks: --("bad-blocks-file") [FILE] "Persists the hashes of invalid blocks to this file")
+     (@arg data_dir: --("data-dir") [DIR] "Persists the blockchain in this directory instead of keeping it in memory")
+     (@arg max_mempool: --("max-mempool") [MB] "Limits the memory taken by unconfirmed transactions to MB megabytes")
+     (@arg min_relay_fee: --("min-relay-fee") [RATE] "Sets the lowest fee rate of relayed transactions, in satoshis per 1000 virtual bytes")
+     (@arg payout_address: --("payout-address") [ADDR] "Pays the rewards of the mined blocks to this hex-encoded address")
+     (@arg coinbase_tag: --("coinbase-tag") [TAG] "Puts this text in the coinbase of the mined blocks")
+     (@arg regtest: --regtest "Runs an in-memory regtest chain, whose blocks are mined instantly and generated on demand through the API")
+     (@arg simulate: --simulate [FILE] "Runs the simulation scenario described in this JSON file, prints a report and exits")
+     (@arg bench_signatures: --("bench-signatures") [COUNT] "Measures the verification throughput of each signature scheme over COUNT signatures, prints it and exits")
+     (@arg protocol_spec: --("protocol-spec") "Prints the peer protocol description as JSON and exits")
     )
     .get_matches();
 
+    if let Some(path) = matches.value_of("simulate") {
+        match simulation::Scenario::load(std::path::Path::new(path)) {
+            Ok(scenario) => {
+                let report = simulation::run(&scenario);
+                println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                return;
+            }
+            Err(e) => {
+                eprintln!("Error loading scenario {}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(count) = matches.value_of("bench_signatures") {
+        match count.parse::<usize>() {
+            Ok(count) => {
+                let throughput = crypto::bench::verification_throughput(count);
+                println!("{}", serde_json::to_string_pretty(&throughput).unwrap());
+                return;
+            }
+            Err(e) => {
+                eprintln!("Error parsing signature count {}: {}", count, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if matches.is_present("protocol_spec") {
+        println!("{}", network::message::protocol_json());
+        return;
+    }
+
     // init logger
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
 
+    // refuse to run with broken cryptographic primitives
+    if let Err(e) = crypto::selftest::run() {
+        error!("Crypto self-test failed, refusing to start: {}", e);
+        process::exit(1);
+    }
+
     // parse p2p server address
     let p2p_addr = matches
         .value_of("peer_addr")
@@ -60,18 +173,191 @@ fn main() {
             process::exit(1);
         });
 
+    // parse the connection limits
+    let mut limits = PeerLimits::default();
+    for (arg, limit) in [("max_inbound", &mut limits.max_inbound), ("max_outbound", &mut limits.max_outbound)] {
+        if let Some(count) = matches.value_of(arg) {
+            *limit = count.parse::<usize>().unwrap_or_else(|e| {
+                error!("Error parsing connection limit {}: {}", count, e);
+                process::exit(1);
+            });
+        }
+    }
+
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::unbounded();
 
     // start the p2p server
-    let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
+    let (server_ctx, server) = server::new(p2p_addr, limits, msg_tx).unwrap();
     server_ctx.start().unwrap();
+    if let Some(size) = matches.value_of("max_upload") {
+        match size.parse::<u64>().map(|size| size.checked_mul(1_000_000)) {
+            Ok(Some(bytes)) => server.set_upload_target(Some(bytes)),
+            Ok(None) => {
+                error!("Error parsing upload target: {} megabytes is too large", size);
+                process::exit(1);
+            }
+            Err(e) => {
+                error!("Error parsing upload target: {}", e);
+                process::exit(1);
+            }
+        }
+    }
 
     // create the blockchain
-    let bc = Blockchain::new();
-    let blockchain = Arc::new(Mutex::new(bc));
+    let block_cache = matches
+        .value_of("block_cache")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing block cache size: {}", e);
+            process::exit(1);
+        });
+    let loads_chain = ["import_archive", "utxo_snapshot", "data_dir", "load_bootstrap"].iter().any(|a| matches.is_present(a));
+    if matches.is_present("regtest") && loads_chain {
+        error!("Regtest chains are kept in memory, and cannot be loaded or persisted");
+        process::exit(1);
+    }
+    // the history of a snapshot is validated in the background once the network is started
+    let mut history = None;
+    let mut bc = match matches.value_of("import_archive") {
+        Some(path) => {
+            let trusted_key = matches.value_of("archive_signer").map(|k| {
+                hex::decode(k).unwrap_or_else(|e| {
+                    error!("Error parsing archive signer key: {}", e);
+                    process::exit(1);
+                })
+            });
+            let imported = ChainArchive::load(std::path::Path::new(path))
+                .and_then(|a| a.import(trusted_key.as_deref()));
+            match imported {
+                Ok(bc) => {
+                    info!("Imported {} blocks from archive {}", bc.num_blocks(), path);
+                    bc
+                }
+                Err(e) => {
+                    error!("Error importing archive {}: {}", path, e);
+                    process::exit(1);
+                }
+            }
+        }
+        None if matches.is_present("utxo_snapshot") => {
+            let path = matches.value_of("utxo_snapshot").unwrap();
+            let mut trusted = [0u8; 32];
+            let parsed = matches.value_of("assume_utxo").map(|h| hex::decode_to_slice(h, &mut trusted));
+            if parsed.map_or(true, |r| r.is_err()) {
+                error!("Error parsing --assume-utxo, expected the hex-encoded hash of the UTXO set");
+                process::exit(1);
+            }
+            let store: Box<dyn ChainStore> = match matches.value_of("data_dir") {
+                Some(dir) => {
+                    let store = FileStore::open(std::path::Path::new(dir)).unwrap_or_else(|e| {
+                        error!("Error opening data directory {}: {}", dir, e);
+                        process::exit(1);
+                    });
+                    Box::new(CachedStore::new(store, block_cache))
+                }
+                None => Box::new(MemoryStore::new()),
+            };
+            let loaded = UtxoSnapshot::load(std::path::Path::new(path)).and_then(|snapshot| {
+                let bc = snapshot.load_into(store, &H256::from(trusted))?;
+                Ok((bc, HistoryValidator::new(&snapshot)))
+            });
+            match loaded {
+                Ok((bc, validator)) => {
+                    info!("Started from UTXO snapshot {} at height {}", path, bc.tip_height());
+                    history = Some(validator);
+                    bc
+                }
+                Err(e) => {
+                    error!("Error loading UTXO snapshot {}: {}", path, e);
+                    process::exit(1);
+                }
+            }
+        }
+        None => match matches.value_of("data_dir") {
+            Some(dir) => {
+                let opened = FileStore::open(std::path::Path::new(dir))
+                    .and_then(|store| Blockchain::open(Box::new(CachedStore::new(store, block_cache))))
+                    .and_then(|mut bc| {
+                        if matches.is_present("reindex") {
+                            info!("Reindexing {}", dir);
+                            bc.reindex()?;
+                        }
+                        Ok(bc)
+                    });
+                match opened {
+                    Ok(bc) => {
+                        info!("Loaded {} blocks from {}", bc.num_blocks(), dir);
+                        bc
+                    }
+                    Err(e) => {
+                        error!("Error opening data directory {}: {}", dir, e);
+                        process::exit(1);
+                    }
+                }
+            }
+            None => match matches.value_of("load_bootstrap") {
+                // a fresh in-memory blockchain starts from the genesis block of the file
+                Some(path) => match bootstrap::read_genesis(std::path::Path::new(path)) {
+                    Ok(genesis) => Blockchain::with_genesis(genesis),
+                    Err(e) => {
+                        error!("Error reading bootstrap file {}: {}", path, e);
+                        process::exit(1);
+                    }
+                },
+                None if matches.is_present("regtest") => Blockchain::regtest(),
+                None => Blockchain::new(),
+            },
+        },
+    };
+    if let Some(path) = matches.value_of("bad_blocks") {
+        match BadBlockCache::open(std::path::Path::new(path)) {
+            Ok(cache) => bc.set_bad_blocks(cache),
+            Err(e) => {
+                error!("Error opening bad block file {}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(depth) = matches.value_of("prune") {
+        match depth.parse::<u32>() {
+            Ok(depth) => bc.set_prune_depth(Some(depth)),
+            Err(e) => {
+                error!("Error parsing prune depth: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(values) = matches.values_of("checkpoint") {
+        for value in values {
+            let parsed = value.split_once(':').and_then(|(height, hash)| {
+                let height = height.parse::<u32>().ok()?;
+                let mut bytes = [0u8; 32];
+                hex::decode_to_slice(hash, &mut bytes).ok()?;
+                Some((height, H256::from(bytes)))
+            });
+            match parsed {
+                Some((height, hash)) => bc.add_checkpoint(height, hash),
+                None => {
+                    error!("Error parsing checkpoint {}, expected HEIGHT:HASH", value);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+    if let Some(path) = matches.value_of("load_bootstrap") {
+        match bc.import(std::path::Path::new(path)) {
+            Ok(n) => info!("Imported {} blocks from bootstrap file {}", n, path),
+            Err(e) => {
+                error!("Error importing bootstrap file {}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+    let blockchain = Arc::new(RwLock::new(bc));
 
-    // start the worker
+    // create the thread pools
     let p2p_workers = matches
         .value_of("p2p_workers")
         .unwrap()
@@ -80,24 +366,127 @@ fn main() {
             error!("Error parsing P2P workers: {}", e);
             process::exit(1);
         });
+    let mut pool_sizes: BTreeMap<String, usize> = BTreeMap::new();
+    pool_sizes.insert(runtime::NETWORK_POOL.to_string(), p2p_workers);
+    if let Some(values) = matches.values_of("pool_size") {
+        for value in values {
+            let parsed = value
+                .split_once('=')
+                .and_then(|(name, size)| size.parse::<usize>().ok().map(|size| (name.to_string(), size)));
+            match parsed {
+                Some((name, size)) => {
+                    pool_sizes.insert(name, size);
+                }
+                None => {
+                    error!("Error parsing pool size {}, expected NAME=SIZE", value);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+    let runtime = Arc::new(Runtime::new(&pool_sizes).unwrap_or_else(|e| {
+        error!("Error configuring the thread pools: {}", e);
+        process::exit(1);
+    }));
+    blockchain.write().unwrap().set_verification_pool(runtime.pool(runtime::VALIDATION_POOL).cloned());
+
+    // create the mempool, following the longest chain
+    let mut mempool_config = MempoolConfig::default();
+    if let Some(size) = matches.value_of("max_mempool") {
+        match size.parse::<usize>().map(|size| size.checked_mul(1_000_000)) {
+            Ok(Some(bytes)) => mempool_config.max_size = bytes,
+            Ok(None) => {
+                error!("Error parsing mempool size: {} megabytes is too large", size);
+                process::exit(1);
+            }
+            Err(e) => {
+                error!("Error parsing mempool size: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(rate) = matches.value_of("min_relay_fee") {
+        match rate.parse::<u64>() {
+            Ok(rate) => mempool_config.min_relay_fee_rate = FeeRate::from_sat_per_kvb(rate),
+            Err(e) => {
+                error!("Error parsing minimum relay fee rate: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+    let mempool = Arc::new(RwLock::new(Mempool::with_config(mempool_config)));
+    mempool::follow_chain(&mempool, &blockchain);
+    let fee_estimator = Arc::new(RwLock::new(FeeEstimator::new()));
+    fee_estimator::follow(&fee_estimator, &mempool, &blockchain);
+
+    // load the addresses of the known nodes, kept in the data directory
+    let address_file = matches.value_of("data_dir").map(|dir| std::path::Path::new(dir).join(ADDRESS_FILE));
+    let address_book = match &address_file {
+        Some(path) if path.exists() => AddressBook::load(path).unwrap_or_else(|e| {
+            warn!("Error loading the address book, starting from the seeds: {}", e);
+            AddressBook::new()
+        }),
+        _ => AddressBook::new(),
+    };
+    let address_book = Arc::new(RwLock::new(address_book));
+
+    // start the worker
     let worker_ctx = worker::new(
-        p2p_workers,
+        runtime.pool(runtime::NETWORK_POOL).unwrap(),
         msg_rx,
         &server,
         &blockchain,
+        &mempool,
+        &address_book,
     );
+    let worker_ctx = match history {
+        Some(validator) => {
+            let (outcome_tx, outcome_rx) = channel::unbounded();
+            thread::Builder::new()
+                .name("snapshot-validation".to_string())
+                .spawn(move || {
+                    if outcome_rx.recv() == Ok(false) {
+                        error!("Refusing the UTXO snapshot, restart without it");
+                        process::exit(1);
+                    }
+                })
+                .unwrap();
+            worker_ctx.with_history_validation(validator, outcome_tx)
+        }
+        None => worker_ctx,
+    };
     worker_ctx.start();
+    keepalive::start(&server);
 
-    // start the miner
-    let (miner_ctx, miner) = miner::new(
-        &server, &blockchain
+    // start the miner, and the worker inserting the blocks it mines
+    let (miner_ctx, miner, finished_blocks) = miner::new(
+        &blockchain, &mempool, runtime.pool(runtime::MINING_POOL).unwrap()
     );
+    let mut coinbase = CoinbaseConfig::default();
+    if let Some(address) = matches.value_of("payout_address") {
+        match address.parse::<H256>() {
+            Ok(address) => coinbase.payout = TxOutput::new(0, address),
+            Err(e) => {
+                error!("Error parsing payout address: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(tag) = matches.value_of("coinbase_tag") {
+        coinbase.tag = tag.as_bytes().to_vec();
+    }
+    if let Err(e) = miner.set_coinbase(coinbase) {
+        error!("Error setting the coinbase: {}", e);
+        process::exit(1);
+    }
     miner_ctx.start();
+    miner::worker::new(&server, finished_blocks, &blockchain).start();
 
     // connect to known peers
     if let Some(known_peers) = matches.values_of("known_peer") {
         let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
         let server = server.clone();
+        let blockchain = Arc::clone(&blockchain);
         thread::spawn(move || {
             for peer in known_peers {
                 loop {
@@ -108,7 +497,7 @@ fn main() {
                             break;
                         }
                     };
-                    match server.connect(addr) {
+                    match discovery::connect(&server, &blockchain, addr) {
                         Ok(_) => {
                             info!("Connected to outgoing peer {}", &addr);
                             break;
@@ -125,14 +514,41 @@ fn main() {
                 }
             }
         });
+    } else if !matches.is_present("regtest") {
+        // discover peers from the seeds
+        let mut discovery = discovery::new(&server, &blockchain, &address_book, p2p_addr);
+        if let Some(path) = address_file {
+            discovery = discovery.with_address_file(path);
+        }
+        if let Some(seeds) = matches.values_of("dns_seed") {
+            discovery = discovery.with_dns_seeds(seeds.map(|s| s.to_string()).collect());
+        }
+        if let Some(nodes) = matches.values_of("seed_node") {
+            discovery = discovery.with_seed_nodes(nodes.map(|s| s.to_string()).collect());
+        }
+        discovery.start();
     }
 
-
-    // start the API server
+    // start the API server, which only writes exports to the data directory
+    let exports = matches.value_of("data_dir").map(|dir| {
+        let dir = std::path::Path::new(dir);
+        let export_dir = dir.join(EXPORT_DIR);
+        let archive_key = std::fs::create_dir_all(&export_dir)
+            .and_then(|_| key_pair::load_or_create(&dir.join(ARCHIVE_KEY_FILE)))
+            .unwrap_or_else(|e| {
+                error!("Error preparing the export directory {}: {}", export_dir.display(), e);
+                process::exit(1);
+            });
+        Exports { dir: export_dir, archive_key }
+    });
     ApiServer::start(
         api_addr,
         &miner,
         &server,
+        &blockchain,
+        &runtime,
+        &fee_estimator,
+        exports,
     );
 
     loop {
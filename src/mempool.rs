@@ -0,0 +1,1727 @@
+//! Transactions waiting to be mined, each validated against the UTXO set and the transactions
+//! already in the pool. Like `Blockchain`, the pool is shared between threads behind a lock.
+
+use serde::{Serialize, Deserialize};
+use std::cmp::Reverse;
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::block::Block;
+use crate::blockchain::{Blockchain, ChainEvent};
+use crate::crypto::hash::H256;
+use crate::fee::FeeRate;
+use crate::orphans::OrphanTransactions;
+use crate::policy::{Policy, PolicyError};
+use crate::replacement::{self, Replaced, ReplacementError, INCREMENTAL_RELAY_FEE};
+use crate::transaction::{Amount, Transaction, TxOutput};
+use crate::utxo::{OutPoint, UtxoSet};
+use crate::validation::{self, TxError};
+
+/// Most memory the transactions of the pool may take, in bytes, see `Mempool::usage`
+pub const DEFAULT_MAX_SIZE: usize = 300_000_000;
+
+/// Most transactions the pool may hold
+pub const DEFAULT_MAX_COUNT: usize = 500_000;
+
+/// Lowest fee rate of the transactions accepted in the pool
+pub const DEFAULT_MIN_RELAY_FEE_RATE: FeeRate = FeeRate::from_sat_per_kvb(1000);
+
+/// Time after which a transaction that was not mined is removed from the pool
+pub const DEFAULT_EXPIRY: Duration = Duration::from_secs(336 * 60 * 60);
+
+/// Time for the minimum fee rate raised by evictions to decay by half
+pub const MIN_FEE_RATE_HALFLIFE: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Most transactions a transaction of the pool may descend from, counting itself
+pub const DEFAULT_MAX_ANCESTORS: usize = 25;
+
+/// Most virtual bytes of a transaction of the pool with its ancestors
+pub const DEFAULT_MAX_ANCESTOR_VSIZE: usize = 101_000;
+
+/// Most transactions of the pool descending from a transaction, counting itself
+pub const DEFAULT_MAX_DESCENDANTS: usize = 25;
+
+/// Most virtual bytes of a transaction of the pool with its descendants
+pub const DEFAULT_MAX_DESCENDANT_VSIZE: usize = 101_000;
+
+/// Most transactions of a package, see `Mempool::accept_package`
+pub const MAX_PACKAGE_COUNT: usize = 25;
+
+/// Most virtual bytes of the transactions of a package
+pub const MAX_PACKAGE_VSIZE: usize = 101_000;
+
+/// Estimated memory taken by the indexes of the pool for each transaction, in bytes
+const ENTRY_OVERHEAD: usize = 250;
+
+/// Number of packages in a row that may not fit in a nearly full block before `Mempool::select`
+/// stops looking for smaller ones
+const MAX_SELECTION_FAILURES: usize = 1000;
+
+/// Virtual bytes left under which a block counts as nearly full, see `MAX_SELECTION_FAILURES`
+const BLOCK_FULL_MARGIN: usize = 1000;
+
+/// A transaction of the pool, with what was computed when accepting it
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    transaction: Transaction,
+    fee: Amount,
+    vsize: usize,
+    time: SystemTime,
+    /// Position of the transaction in the order of acceptance
+    sequence: u64,
+    /// Txids of the transactions of the pool it spends outputs of
+    parents: HashSet<H256>,
+    /// Txids of the transactions of the pool spending its outputs
+    children: HashSet<H256>,
+    /// Number, fee and virtual size of the transaction with all its ancestors in the pool
+    ancestor_count: usize,
+    ancestor_fee: Amount,
+    ancestor_vsize: usize,
+    /// Number, fee and virtual size of the transaction with all its descendants in the pool
+    descendant_count: usize,
+    descendant_fee: Amount,
+    descendant_vsize: usize,
+    /// Memory taken by the entry, in bytes
+    usage: usize,
+}
+
+impl MempoolEntry {
+    pub fn get_transaction(&self) -> &Transaction {
+        return &self.transaction;
+    }
+
+    pub fn get_fee(&self) -> Amount {
+        return self.fee;
+    }
+
+    pub fn get_vsize(&self) -> usize {
+        return self.vsize;
+    }
+
+    /// When the transaction was accepted
+    pub fn get_time(&self) -> SystemTime {
+        return self.time;
+    }
+
+    pub fn fee_rate(&self) -> FeeRate {
+        return FeeRate::from_fee(self.fee, self.vsize);
+    }
+
+    pub fn get_parents(&self) -> &HashSet<H256> {
+        return &self.parents;
+    }
+
+    pub fn get_children(&self) -> &HashSet<H256> {
+        return &self.children;
+    }
+
+    pub fn get_ancestor_count(&self) -> usize {
+        return self.ancestor_count;
+    }
+
+    pub fn get_ancestor_fee(&self) -> Amount {
+        return self.ancestor_fee;
+    }
+
+    pub fn get_ancestor_vsize(&self) -> usize {
+        return self.ancestor_vsize;
+    }
+
+    /// Rate of the transaction with its ancestors in the pool, which must be mined with it: the
+    /// rate a miner gets for including it
+    pub fn ancestor_fee_rate(&self) -> FeeRate {
+        return FeeRate::from_fee(self.ancestor_fee, self.ancestor_vsize);
+    }
+
+    pub fn get_descendant_count(&self) -> usize {
+        return self.descendant_count;
+    }
+
+    pub fn get_descendant_fee(&self) -> Amount {
+        return self.descendant_fee;
+    }
+
+    pub fn get_descendant_vsize(&self) -> usize {
+        return self.descendant_vsize;
+    }
+
+    /// Rate of the transaction with its descendants in the pool, which are evicted with it
+    pub fn descendant_fee_rate(&self) -> FeeRate {
+        return FeeRate::from_fee(self.descendant_fee, self.descendant_vsize);
+    }
+
+    fn score(&self) -> Score {
+        return (self.ancestor_fee_rate(), Reverse(self.sequence), self.transaction.txid());
+    }
+
+    fn descendant_score(&self) -> Score {
+        return (self.descendant_fee_rate(), Reverse(self.sequence), self.transaction.txid());
+    }
+}
+
+/// Order of the transactions for mining, by ancestor fee rate, or for eviction, by descendant
+/// fee rate. Ties go to the earliest accepted first.
+type Score = (FeeRate, Reverse<u64>, H256);
+
+/// Why a transaction left the pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalReason {
+    /// It was mined in a block
+    Block,
+    /// It spends an output also spent by a transaction of a block, or descends from such a
+    /// transaction
+    Conflict,
+    /// The transaction with this txid replaced it or one of its ancestors, see `replacement`
+    Replaced(H256),
+    /// It stayed in the pool longer than the expiry without being mined, or descends from such
+    /// a transaction
+    Expired,
+    /// It was evicted to make room, see `MempoolConfig::max_size`
+    Evicted,
+    /// It spends outputs of transactions that left the chain in a reorganization and could not
+    /// return to the pool, or is no longer final after one
+    Reorg,
+}
+
+/// A change of the pool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolEvent {
+    Added { txid: H256 },
+    Removed { txid: H256, reason: RemovalReason },
+}
+
+/// Limits and acceptance rules of a pool, see the defaults. A relay node may keep a large pool,
+/// while a mining node may only keep the transactions it would mine.
+#[derive(Debug, Clone)]
+pub struct MempoolConfig {
+    /// Most memory the transactions may take, in bytes, see `Mempool::usage`
+    pub max_size: usize,
+    pub max_count: usize,
+    pub min_relay_fee_rate: FeeRate,
+    /// How long chains of unconfirmed transactions may get
+    pub max_ancestors: usize,
+    pub max_ancestor_vsize: usize,
+    pub max_descendants: usize,
+    pub max_descendant_vsize: usize,
+    pub expiry: Duration,
+    /// Rules a transaction must follow to be relayed
+    pub policy: Policy,
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        MempoolConfig {
+            max_size: DEFAULT_MAX_SIZE,
+            max_count: DEFAULT_MAX_COUNT,
+            min_relay_fee_rate: DEFAULT_MIN_RELAY_FEE_RATE,
+            max_ancestors: DEFAULT_MAX_ANCESTORS,
+            max_ancestor_vsize: DEFAULT_MAX_ANCESTOR_VSIZE,
+            max_descendants: DEFAULT_MAX_DESCENDANTS,
+            max_descendant_vsize: DEFAULT_MAX_DESCENDANT_VSIZE,
+            expiry: DEFAULT_EXPIRY,
+            policy: Policy::default(),
+        }
+    }
+}
+
+/// An entry of the pool as reported to clients, see `Mempool::entries`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MempoolEntryInfo {
+    pub txid: String,
+    pub vsize: usize,
+    pub fee: Amount,
+    pub fee_rate: FeeRate,
+    /// When the transaction was accepted, in seconds since the Unix epoch
+    pub time: u64,
+    /// Rate the transaction is ranked by for mining, see `MempoolEntry::ancestor_fee_rate`
+    pub ancestor_fee_rate: FeeRate,
+    pub ancestor_count: usize,
+    pub ancestor_vsize: usize,
+    pub descendant_count: usize,
+    pub descendant_vsize: usize,
+    /// Txids of the transactions of the pool it spends outputs of
+    pub depends: Vec<String>,
+    /// Txids of the transactions of the pool spending its outputs
+    pub spent_by: Vec<String>,
+}
+
+impl From<&MempoolEntry> for MempoolEntryInfo {
+    fn from(entry: &MempoolEntry) -> Self {
+        let txids = |set: &HashSet<H256>| {
+            let mut txids: Vec<String> = set.iter().map(|txid| txid.to_string()).collect();
+            txids.sort();
+            txids
+        };
+        MempoolEntryInfo {
+            txid: entry.transaction.txid().to_string(),
+            vsize: entry.vsize,
+            fee: entry.fee,
+            fee_rate: entry.fee_rate(),
+            time: entry.time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            ancestor_fee_rate: entry.ancestor_fee_rate(),
+            ancestor_count: entry.ancestor_count,
+            ancestor_vsize: entry.ancestor_vsize,
+            descendant_count: entry.descendant_count,
+            descendant_vsize: entry.descendant_vsize,
+            depends: txids(&entry.parents),
+            spent_by: txids(&entry.children),
+        }
+    }
+}
+
+/// Totals and limits of the pool, see `Mempool::info`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MempoolInfo {
+    pub count: usize,
+    /// Virtual size of all the transactions
+    pub vsize: usize,
+    pub total_fee: Amount,
+    /// Estimated memory taken, see `Mempool::usage`
+    pub usage: usize,
+    pub max_size: usize,
+    pub max_count: usize,
+    /// Fee rate a transaction must pay to enter the pool now, see `Mempool::min_fee_rate`
+    pub min_fee_rate: FeeRate,
+    pub min_relay_fee_rate: FeeRate,
+    pub orphans: usize,
+}
+
+/// Reasons for the pool to refuse a transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError {
+    /// The transaction is already in the pool
+    Duplicate(H256),
+    /// The transaction conflicts with transactions of the pool it may not replace
+    Replacement(ReplacementError),
+    /// The transaction spends an output of the transaction of the pool with this txid, which it
+    /// conflicts with
+    SpendsConflict(H256),
+    Invalid(TxError),
+    NonStandard(PolicyError),
+    /// The transaction pays less than the minimum fee rate of the pool, see
+    /// `Mempool::min_fee_rate`
+    LowFeeRate { fee_rate: FeeRate, min_fee_rate: FeeRate },
+    /// The pool is full of transactions paying more
+    Full,
+    /// The transaction would have more ancestors in the pool than allowed, or larger ones
+    AncestorLimit { count: usize, vsize: usize },
+    /// The transaction of the pool with this txid would have more descendants than allowed, or
+    /// larger ones
+    DescendantLimit(H256),
+    /// The outputs spent by the transaction are neither unspent nor created in the pool, it is
+    /// kept as an orphan until they arrive
+    MissingInputs(Vec<OutPoint>),
+    Package(PackageError),
+}
+
+impl std::fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MempoolError::Duplicate(txid) => write!(f, "transaction {} already in the pool", txid),
+            MempoolError::Replacement(e) => write!(f, "replacement refused: {}", e),
+            MempoolError::SpendsConflict(txid) => write!(f, "spends conflicting transaction {}", txid),
+            MempoolError::Invalid(e) => write!(f, "invalid transaction: {}", e),
+            MempoolError::NonStandard(e) => write!(f, "non-standard transaction: {}", e),
+            MempoolError::LowFeeRate { fee_rate, min_fee_rate } => {
+                write!(f, "fee rate {} below the minimum {}", fee_rate, min_fee_rate)
+            }
+            MempoolError::Full => write!(f, "mempool full"),
+            MempoolError::AncestorLimit { count, vsize } => {
+                write!(f, "{} ancestors of {} vbytes exceed the limits", count, vsize)
+            }
+            MempoolError::DescendantLimit(txid) => write!(f, "too many descendants of {}", txid),
+            MempoolError::MissingInputs(missing) => write!(f, "{} missing inputs", missing.len()),
+            MempoolError::Package(e) => write!(f, "package refused: {}", e),
+        }
+    }
+}
+
+/// Reasons for the pool to refuse a package, see `Mempool::accept_package`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PackageError {
+    /// More than `MAX_PACKAGE_COUNT` transactions, or more than `MAX_PACKAGE_VSIZE` virtual bytes
+    TooLarge,
+    /// A transaction comes twice, or before one of its parents of the package
+    NotSorted,
+    /// The transaction with this txid spends an output also spent by another transaction of the
+    /// package or of the pool
+    Conflict(H256),
+    /// The transaction with this txid was refused
+    Rejected { txid: H256, error: Box<MempoolError> },
+}
+
+impl std::fmt::Display for PackageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PackageError::TooLarge => write!(f, "too many transactions or vbytes"),
+            PackageError::NotSorted => write!(f, "transactions not sorted by dependency"),
+            PackageError::Conflict(txid) => write!(f, "transaction {} spends an output already spent", txid),
+            PackageError::Rejected { txid, error } => write!(f, "transaction {} refused: {}", txid, error),
+        }
+    }
+}
+
+impl From<PackageError> for MempoolError {
+    fn from(e: PackageError) -> Self {
+        MempoolError::Package(e)
+    }
+}
+
+impl From<TxError> for MempoolError {
+    fn from(e: TxError) -> Self {
+        MempoolError::Invalid(e)
+    }
+}
+
+impl From<ReplacementError> for MempoolError {
+    fn from(e: ReplacementError) -> Self {
+        MempoolError::Replacement(e)
+    }
+}
+
+impl From<PolicyError> for MempoolError {
+    fn from(e: PolicyError) -> Self {
+        MempoolError::NonStandard(e)
+    }
+}
+
+/// The pool of unconfirmed transactions. Transactions may spend outputs of the UTXO set or of
+/// other transactions of the pool, but no output is spent twice. Once the pool takes more than
+/// its maximum size, the transactions with the lowest descendant fee rate are evicted, and the
+/// minimum fee rate to enter the pool is raised above theirs.
+#[derive(Debug)]
+pub struct Mempool {
+    entries: HashMap<H256, MempoolEntry>,
+    /// Txid of the transaction of the pool spending each output
+    spent: HashMap<OutPoint, H256>,
+    /// Scores of all entries, see `MempoolEntry::ancestor_fee_rate`
+    by_score: BTreeSet<Score>,
+    /// Eviction scores of all entries, see `MempoolEntry::descendant_fee_rate`
+    by_descendant_score: BTreeSet<Score>,
+    /// Txid of each entry by its position in the order of acceptance, oldest first
+    by_sequence: BTreeMap<u64, H256>,
+    config: MempoolConfig,
+    next_sequence: u64,
+    /// Memory taken by all entries, in bytes
+    usage: usize,
+    /// Minimum fee rate set by the last eviction, decaying from the time it was set
+    rolling_min_fee_rate: FeeRate,
+    rolling_min_fee_time: SystemTime,
+    /// Transactions received before their parents
+    orphans: OrphanTransactions,
+    /// Channels of the `subscribe` callers, dropped once the receiver is gone
+    subscribers: Vec<Sender<MempoolEvent>>,
+    /// Height of the next block, which the transactions of the pool must be final in
+    next_height: u32,
+    /// Median time past of the next block, see `next_height`
+    next_mtp: SystemTime,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool {
+            entries: HashMap::new(),
+            spent: HashMap::new(),
+            by_score: BTreeSet::new(),
+            by_descendant_score: BTreeSet::new(),
+            by_sequence: BTreeMap::new(),
+            config: MempoolConfig::default(),
+            next_sequence: 0,
+            usage: 0,
+            rolling_min_fee_rate: FeeRate::ZERO,
+            rolling_min_fee_time: SystemTime::UNIX_EPOCH,
+            orphans: OrphanTransactions::default(),
+            subscribers: Vec::new(),
+            next_height: 0,
+            next_mtp: UNIX_EPOCH,
+        }
+    }
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        return Mempool::default();
+    }
+
+    pub fn with_config(config: MempoolConfig) -> Self {
+        return Mempool { config, ..Mempool::default() };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.entries.is_empty();
+    }
+
+    pub fn contains(&self, txid: &H256) -> bool {
+        return self.entries.contains_key(txid);
+    }
+
+    pub fn get(&self, txid: &H256) -> Option<&MempoolEntry> {
+        return self.entries.get(txid);
+    }
+
+    pub fn get_config(&self) -> &MempoolConfig {
+        return &self.config;
+    }
+
+    /// Apply `config` to the transactions accepted from now on, and evict transactions if the
+    /// pool holds more than it allows. Returns the evicted entries.
+    pub fn set_config(&mut self, config: MempoolConfig) -> Vec<MempoolEntry> {
+        self.config = config;
+        return self.trim(SystemTime::now());
+    }
+
+    /// Receive the events of the pool from now on
+    pub fn subscribe(&mut self) -> Receiver<MempoolEvent> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.push(sender);
+        return receiver;
+    }
+
+    fn emit(&mut self, events: Vec<MempoolEvent>) {
+        self.subscribers.retain(|subscriber| events.iter().all(|e| subscriber.send(e.clone()).is_ok()));
+    }
+
+    /// Notify the subscribers that the `removed` entries left the pool for `reason`
+    fn emit_removed(&mut self, removed: &[MempoolEntry], reason: RemovalReason) {
+        let events = removed.iter().map(|e| MempoolEvent::Removed { txid: e.transaction.txid(), reason }).collect();
+        self.emit(events);
+    }
+
+    /// Remove the transactions that stayed in the pool longer than the expiry, with their
+    /// descendants. Done on each acceptance, and meant to be called on each new block too.
+    /// Returns the removed entries.
+    pub fn expire(&mut self) -> Vec<MempoolEntry> {
+        return self.expire_at(SystemTime::now());
+    }
+
+    fn expire_at(&mut self, now: SystemTime) -> Vec<MempoolEntry> {
+        let mut expired: Vec<MempoolEntry> = Vec::new();
+        while let Some((_, txid)) = self.by_sequence.iter().next() {
+            let txid = *txid;
+            if self.entries[&txid].time + self.config.expiry > now {
+                break;
+            }
+            expired.extend(self.remove_with_descendants(&txid));
+        }
+        self.emit_removed(&expired, RemovalReason::Expired);
+        return expired;
+    }
+
+    pub fn get_orphans(&self) -> &OrphanTransactions {
+        return &self.orphans;
+    }
+
+    /// The orphans, to drop those of a peer that disconnected, see
+    /// `OrphanTransactions::remove_peer`
+    pub fn get_orphans_mut(&mut self) -> &mut OrphanTransactions {
+        return &mut self.orphans;
+    }
+
+    /// Estimated memory taken by the transactions of the pool, in bytes
+    pub fn usage(&self) -> usize {
+        return self.usage;
+    }
+
+    /// The fee rate a transaction must pay to enter the pool. The minimum relay fee rate until
+    /// the pool gets full, then above the rate of the evicted transactions, halving every
+    /// `MIN_FEE_RATE_HALFLIFE`.
+    pub fn min_fee_rate(&self) -> FeeRate {
+        return self.min_fee_rate_at(SystemTime::now());
+    }
+
+    fn min_fee_rate_at(&self, now: SystemTime) -> FeeRate {
+        return std::cmp::max(self.rolling_min_fee_rate_at(now), self.config.min_relay_fee_rate);
+    }
+
+    /// The minimum fee rate set by the last eviction, decayed at `now`
+    fn rolling_min_fee_rate_at(&self, now: SystemTime) -> FeeRate {
+        let elapsed = now.duration_since(self.rolling_min_fee_time).unwrap_or_default();
+        let halvings = elapsed.as_secs_f64() / MIN_FEE_RATE_HALFLIFE.as_secs_f64();
+        let rate = (self.rolling_min_fee_rate.sat_per_kvb() as f64 * 0.5f64.powf(halvings)).round() as u64;
+        // too low to matter anymore
+        if rate < INCREMENTAL_RELAY_FEE.sat_per_kvb() / 2 {
+            return FeeRate::ZERO;
+        }
+        return FeeRate::from_sat_per_kvb(rate);
+    }
+
+    /// Evict the transactions with the lowest descendant fee rate, with their descendants, until
+    /// the pool fits its maximum size and count, raising the minimum fee rate above theirs
+    fn trim(&mut self, now: SystemTime) -> Vec<MempoolEntry> {
+        let mut evicted: Vec<MempoolEntry> = Vec::new();
+        while self.usage > self.config.max_size || self.entries.len() > self.config.max_count {
+            let (fee_rate, _, txid) = match self.by_descendant_score.iter().next() {
+                Some(score) => *score,
+                None => break,
+            };
+            let min_fee_rate = fee_rate + INCREMENTAL_RELAY_FEE;
+            if min_fee_rate > self.rolling_min_fee_rate_at(now) {
+                self.rolling_min_fee_rate = min_fee_rate;
+                self.rolling_min_fee_time = now;
+            }
+            evicted.extend(self.remove_with_descendants(&txid));
+        }
+        self.emit_removed(&evicted, RemovalReason::Evicted);
+        return evicted;
+    }
+
+    /// Count a descendant of `fee` and `vsize` in the descendants of the entry with `txid`, or
+    /// stop counting it if not `added`
+    fn update_descendant_stats(&mut self, txid: &H256, fee: Amount, vsize: usize, added: bool) {
+        let entry = self.entries.get_mut(txid).unwrap();
+        self.by_descendant_score.remove(&entry.descendant_score());
+        if added {
+            entry.descendant_count += 1;
+            entry.descendant_fee = entry.descendant_fee.saturating_add(fee);
+            entry.descendant_vsize += vsize;
+        } else {
+            entry.descendant_count -= 1;
+            entry.descendant_fee -= fee;
+            entry.descendant_vsize -= vsize;
+        }
+        self.by_descendant_score.insert(entry.descendant_score());
+    }
+
+    /// Stop counting an ancestor of `fee` and `vsize` in the ancestors of the entry with `txid`
+    fn remove_ancestor_stats(&mut self, txid: &H256, fee: Amount, vsize: usize) {
+        let entry = self.entries.get_mut(txid).unwrap();
+        self.by_score.remove(&entry.score());
+        entry.ancestor_count -= 1;
+        entry.ancestor_fee -= fee;
+        entry.ancestor_vsize -= vsize;
+        self.by_score.insert(entry.score());
+    }
+
+    /// Txid of the transaction of the pool spending `outpoint`, if any
+    pub fn spender(&self, outpoint: &OutPoint) -> Option<H256> {
+        return self.spent.get(outpoint).cloned();
+    }
+
+    /// Txids of the transactions of the pool whose outputs `transaction` spends
+    fn parents_of(&self, transaction: &Transaction) -> HashSet<H256> {
+        return transaction
+            .get_inputs()
+            .iter()
+            .map(|input| input.prev_txid)
+            .filter(|txid| self.entries.contains_key(txid))
+            .collect();
+    }
+
+    /// Txids of the transactions of the pool in `parents`, and of their ancestors
+    fn ancestors_of(&self, parents: &HashSet<H256>) -> HashSet<H256> {
+        let mut ancestors: HashSet<H256> = parents.clone();
+        let mut pending: Vec<H256> = parents.iter().cloned().collect();
+        while let Some(txid) = pending.pop() {
+            for parent in self.entries[&txid].parents.iter() {
+                if ancestors.insert(*parent) {
+                    pending.push(*parent);
+                }
+            }
+        }
+        return ancestors;
+    }
+
+    /// Txids of the transactions of the pool the transaction with `txid` spends outputs of,
+    /// directly or not
+    pub fn ancestors(&self, txid: &H256) -> HashSet<H256> {
+        return match self.entries.get(txid) {
+            Some(entry) => self.ancestors_of(&entry.parents),
+            None => HashSet::new(),
+        };
+    }
+
+    /// Txids of the transactions of the pool spending outputs of the transaction with `txid`,
+    /// directly or not, each after its parents
+    pub fn descendants(&self, txid: &H256) -> Vec<H256> {
+        let mut descendants: Vec<H256> = Vec::new();
+        let mut seen: HashSet<H256> = HashSet::new();
+        let mut pending: Vec<H256> = vec![*txid];
+        while let Some(txid) = pending.pop() {
+            let entry = match self.entries.get(&txid) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            for child in entry.children.iter() {
+                if seen.insert(*child) {
+                    descendants.push(*child);
+                    pending.push(*child);
+                }
+            }
+        }
+        descendants.sort_by_key(|txid| self.entries[txid].sequence);
+        return descendants;
+    }
+
+    /// Whether the transaction of the pool with `txid` may be replaced: it or one of its
+    /// ancestors signals replacement, see `Transaction::signals_replacement`
+    pub fn is_replaceable(&self, txid: &H256) -> bool {
+        return Some(*txid)
+            .into_iter()
+            .chain(self.ancestors(txid))
+            .any(|t| self.entries.get(&t).map_or(false, |e| e.transaction.signals_replacement()));
+    }
+
+    /// The transactions of the pool `transaction` would evict: those spending the same outputs,
+    /// and their descendants
+    fn replaced_by(&self, transaction: &Transaction) -> Vec<Replaced> {
+        let mut conflicts: Vec<H256> = transaction.get_inputs().iter().filter_map(|i| self.spender(&i.outpoint())).collect();
+        conflicts.sort_by_key(|txid| self.entries[txid].sequence);
+        conflicts.dedup();
+        let mut seen: HashSet<H256> = HashSet::new();
+        let mut replaced: Vec<Replaced> = Vec::new();
+        for conflict in conflicts.iter() {
+            for txid in Some(*conflict).into_iter().chain(self.descendants(conflict)) {
+                if seen.insert(txid) {
+                    let entry = &self.entries[&txid];
+                    replaced.push(Replaced {
+                        txid,
+                        fee: entry.fee,
+                        vsize: entry.vsize,
+                        conflict: conflicts.contains(&txid),
+                        replaceable: self.is_replaceable(&txid),
+                    });
+                }
+            }
+        }
+        return replaced;
+    }
+
+    /// The output created by a transaction of the pool, if it can be spent
+    fn output(&self, outpoint: &OutPoint) -> Option<&TxOutput> {
+        let entry = self.entries.get(&outpoint.txid)?;
+        return entry.transaction.get_outputs().get(outpoint.index as usize).filter(|o| !o.is_unspendable());
+    }
+
+    /// Validate `transaction` against `utxo` and the pool, then add it, evicting transactions
+    /// paying less if the pool is full. A transaction spending outputs already spent in the pool
+    /// replaces the transactions spending them and their descendants, if it passes
+    /// `replacement::check_replacement`. Returns its txid.
+    pub fn accept(&mut self, transaction: Transaction, utxo: &UtxoSet) -> Result<H256, MempoolError> {
+        let now = SystemTime::now();
+        self.expire_at(now);
+        let txid = self.insert(transaction, utxo, now, true)?;
+        self.emit(vec![MempoolEvent::Added { txid }]);
+        self.trim(now);
+        if !self.entries.contains_key(&txid) {
+            return Err(MempoolError::Full);
+        }
+        return Ok(txid);
+    }
+
+    /// Validate `transaction` and add it to the pool at `now`, without notifying the subscribers
+    /// or trimming the pool. Its fee rate is only checked if `check_fee_rate`, as the
+    /// transactions of a package pay for each other.
+    fn insert(&mut self, transaction: Transaction, utxo: &UtxoSet, now: SystemTime, check_fee_rate: bool) -> Result<H256, MempoolError> {
+        let txid = transaction.txid();
+        if self.entries.contains_key(&txid) {
+            return Err(MempoolError::Duplicate(txid));
+        }
+        let lookup = |outpoint: &OutPoint| utxo.get(outpoint).or_else(|| self.output(outpoint));
+        let fee = validation::check_transaction(&transaction, lookup)?;
+        validation::check_final(&transaction, self.next_height, self.next_mtp)?;
+        self.config.policy.check(&transaction, lookup)?;
+
+        let vsize = transaction.vsize();
+        let fee_rate = FeeRate::from_fee(fee, vsize);
+        let min_fee_rate = self.min_fee_rate_at(now);
+        if check_fee_rate && fee_rate < min_fee_rate {
+            return Err(MempoolError::LowFeeRate { fee_rate, min_fee_rate });
+        }
+
+        let replaced = self.replaced_by(&transaction);
+        let parents = self.parents_of(&transaction);
+        let ancestors = self.ancestors_of(&parents);
+        if let Some(original) = replaced.iter().find(|r| ancestors.contains(&r.txid)) {
+            return Err(MempoolError::SpendsConflict(original.txid));
+        }
+        if !replaced.is_empty() {
+            replacement::check_replacement(fee, vsize, &replaced)?;
+        }
+        let ancestor_vsize = ancestors.iter().fold(vsize, |sum, a| sum + self.entries[a].vsize);
+        if ancestors.len() + 1 > self.config.max_ancestors || ancestor_vsize > self.config.max_ancestor_vsize {
+            return Err(MempoolError::AncestorLimit { count: ancestors.len() + 1, vsize: ancestor_vsize });
+        }
+        for ancestor in ancestors.iter() {
+            let entry = &self.entries[ancestor];
+            if entry.descendant_count + 1 > self.config.max_descendants
+                || entry.descendant_vsize + vsize > self.config.max_descendant_vsize
+            {
+                return Err(MempoolError::DescendantLimit(*ancestor));
+            }
+        }
+
+        for original in replaced.iter().filter(|r| r.conflict) {
+            let removed = self.remove_with_descendants(&original.txid);
+            self.emit_removed(&removed, RemovalReason::Replaced(txid));
+        }
+        for input in transaction.get_inputs() {
+            self.spent.insert(input.outpoint(), txid);
+        }
+        for parent in parents.iter() {
+            self.entries.get_mut(parent).unwrap().children.insert(txid);
+        }
+        for ancestor in ancestors.iter() {
+            self.update_descendant_stats(ancestor, fee, vsize, true);
+        }
+        let entry = MempoolEntry {
+            fee,
+            vsize,
+            time: now,
+            sequence: self.next_sequence,
+            parents,
+            children: HashSet::new(),
+            ancestor_count: ancestors.len() + 1,
+            ancestor_fee: ancestors.iter().fold(fee, |sum, a| sum.saturating_add(self.entries[a].fee)),
+            ancestor_vsize,
+            descendant_count: 1,
+            descendant_fee: fee,
+            descendant_vsize: vsize,
+            usage: bincode::serialized_size(&transaction).unwrap() as usize + ENTRY_OVERHEAD,
+            transaction,
+        };
+        self.next_sequence += 1;
+        self.usage += entry.usage;
+        self.by_score.insert(entry.score());
+        self.by_descendant_score.insert(entry.descendant_score());
+        self.by_sequence.insert(entry.sequence, txid);
+        self.entries.insert(txid, entry);
+        return Ok(txid);
+    }
+
+    /// Accept `transactions` together, or none of them. The transactions are judged by the fee
+    /// rate of the package as a whole, so that a child paying a high fee brings in a parent paying
+    /// none. Each must come after its parents of the package, and none may spend an output spent
+    /// in the pool, as packages do not replace transactions. Transactions already in the pool are
+    /// skipped. Returns the txids of the accepted transactions.
+    pub fn accept_package(&mut self, transactions: Vec<Transaction>, utxo: &UtxoSet) -> Result<Vec<H256>, MempoolError> {
+        let total_vsize: usize = transactions.iter().map(|t| t.vsize()).sum();
+        if transactions.len() > MAX_PACKAGE_COUNT || total_vsize > MAX_PACKAGE_VSIZE {
+            return Err(PackageError::TooLarge.into());
+        }
+        let package: HashSet<H256> = transactions.iter().map(|t| t.txid()).collect();
+        let mut seen: HashSet<H256> = HashSet::new();
+        let mut spent: HashSet<OutPoint> = HashSet::new();
+        for transaction in transactions.iter() {
+            let txid = transaction.txid();
+            for input in transaction.get_inputs() {
+                if package.contains(&input.prev_txid) && !seen.contains(&input.prev_txid) {
+                    return Err(PackageError::NotSorted.into());
+                }
+                let spent_in_pool = !self.entries.contains_key(&txid) && self.spent.contains_key(&input.outpoint());
+                if !spent.insert(input.outpoint()) || spent_in_pool {
+                    return Err(PackageError::Conflict(txid).into());
+                }
+            }
+            if !seen.insert(txid) {
+                return Err(PackageError::NotSorted.into());
+            }
+        }
+
+        let now = SystemTime::now();
+        self.expire_at(now);
+        let new: Vec<Transaction> = transactions.into_iter().filter(|t| !self.entries.contains_key(&t.txid())).collect();
+        if new.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut outputs: HashMap<OutPoint, &TxOutput> = HashMap::new();
+        for transaction in new.iter() {
+            for (index, output) in transaction.get_outputs().iter().enumerate().filter(|(_, o)| !o.is_unspendable()) {
+                outputs.insert(OutPoint::new(transaction.txid(), index as u32), output);
+            }
+        }
+        let mut fee: Amount = 0;
+        for transaction in new.iter() {
+            let lookup = |outpoint: &OutPoint| {
+                utxo.get(outpoint).or_else(|| self.output(outpoint)).or_else(|| outputs.get(outpoint).cloned())
+            };
+            match validation::check_transaction(transaction, lookup) {
+                Ok(paid) => fee = fee.saturating_add(paid),
+                Err(e) => {
+                    return Err(PackageError::Rejected { txid: transaction.txid(), error: Box::new(e.into()) }.into());
+                }
+            }
+        }
+        let fee_rate = FeeRate::from_fee(fee, new.iter().map(|t| t.vsize()).sum());
+        let min_fee_rate = self.min_fee_rate_at(now);
+        if fee_rate < min_fee_rate {
+            return Err(MempoolError::LowFeeRate { fee_rate, min_fee_rate });
+        }
+
+        let mut accepted: Vec<H256> = Vec::new();
+        for transaction in new {
+            let txid = transaction.txid();
+            if let Err(error) = self.insert(transaction, utxo, now, false) {
+                for txid in accepted.iter().rev() {
+                    self.remove_with_descendants(txid);
+                }
+                return Err(PackageError::Rejected { txid, error: Box::new(error) }.into());
+            }
+            accepted.push(txid);
+        }
+        self.emit(accepted.iter().map(|txid| MempoolEvent::Added { txid: *txid }).collect());
+        self.trim(now);
+        if accepted.iter().any(|txid| !self.entries.contains_key(txid)) {
+            return Err(MempoolError::Full);
+        }
+        return Ok(accepted);
+    }
+
+    /// Accept `transaction` received from `peer`, see `accept`, along with the orphans waiting
+    /// for it. If it spends outputs not found, it is kept as an orphan until they arrive. Returns
+    /// the txids of the accepted transactions.
+    pub fn accept_from(&mut self, transaction: Transaction, utxo: &UtxoSet, peer: SocketAddr) -> Result<Vec<H256>, MempoolError> {
+        match self.accept(transaction.clone(), utxo) {
+            Ok(txid) => {
+                let mut accepted = vec![txid];
+                accepted.extend(self.accept_orphans(&transaction, utxo));
+                return Ok(accepted);
+            }
+            Err(MempoolError::Invalid(TxError::MissingInput(_))) => {
+                let missing: Vec<OutPoint> = transaction
+                    .get_inputs()
+                    .iter()
+                    .map(|input| input.outpoint())
+                    .filter(|outpoint| utxo.get(outpoint).is_none() && self.output(outpoint).is_none())
+                    .collect();
+                self.orphans.insert(transaction, missing.clone(), peer);
+                return Err(MempoolError::MissingInputs(missing));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    /// Retry the orphans spending outputs of `parent`, which just entered the pool or a block,
+    /// and the orphans waiting for those accepted. Orphans still missing other outputs are kept.
+    /// Returns the txids of the accepted orphans.
+    pub fn accept_orphans(&mut self, parent: &Transaction, utxo: &UtxoSet) -> Vec<H256> {
+        let mut accepted: Vec<H256> = Vec::new();
+        let mut parents: Vec<Transaction> = vec![parent.clone()];
+        while let Some(parent) = parents.pop() {
+            for orphan in self.orphans.take_children(&parent) {
+                match self.accept(orphan.transaction.clone(), utxo) {
+                    Ok(txid) => {
+                        accepted.push(txid);
+                        parents.push(orphan.transaction);
+                    }
+                    Err(MempoolError::Invalid(TxError::MissingInput(_))) => {
+                        let missing = orphan.missing.into_iter().filter(|o| o.txid != parent.txid()).collect();
+                        self.orphans.insert(orphan.transaction, missing, orphan.peer);
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+        return accepted;
+    }
+
+    /// Remove a transaction and its descendants, as they can no longer be mined, for `reason`.
+    /// Returns the removed entries.
+    pub fn remove(&mut self, txid: &H256, reason: RemovalReason) -> Vec<MempoolEntry> {
+        let removed = self.remove_with_descendants(txid);
+        self.emit_removed(&removed, reason);
+        return removed;
+    }
+
+    /// Remove the transactions of the pool spending the outputs spent by `transaction`, which was
+    /// mined in a block, with their descendants. Returns the removed entries.
+    pub fn remove_conflicts(&mut self, transaction: &Transaction) -> Vec<MempoolEntry> {
+        let txid = transaction.txid();
+        let mut removed: Vec<MempoolEntry> = Vec::new();
+        for input in transaction.get_inputs() {
+            match self.spent.get(&input.outpoint()) {
+                Some(spender) if *spender != txid => {
+                    let spender = *spender;
+                    removed.extend(self.remove_with_descendants(&spender));
+                }
+                _ => {}
+            }
+        }
+        self.emit_removed(&removed, RemovalReason::Conflict);
+        return removed;
+    }
+
+    /// `remove` without notifying the subscribers
+    fn remove_with_descendants(&mut self, txid: &H256) -> Vec<MempoolEntry> {
+        if !self.entries.contains_key(txid) {
+            return Vec::new();
+        }
+        let removing: Vec<H256> = Some(*txid).into_iter().chain(self.descendants(txid)).collect();
+        let removed_set: HashSet<&H256> = removing.iter().collect();
+        for txid in removing.iter() {
+            let (fee, vsize) = (self.entries[txid].fee, self.entries[txid].vsize);
+            for ancestor in self.ancestors(txid).iter().filter(|a| !removed_set.contains(a)) {
+                self.update_descendant_stats(ancestor, fee, vsize, false);
+            }
+        }
+        return removing.iter().map(|txid| self.remove_entry(txid)).collect();
+    }
+
+    /// Remove a transaction mined in a block, keeping its descendants, which now spend outputs
+    /// of the chain. Its ancestors are mined before it, so they are expected to be removed
+    /// already.
+    pub fn remove_confirmed(&mut self, txid: &H256) -> Option<MempoolEntry> {
+        let (fee, vsize) = match self.entries.get(txid) {
+            Some(entry) => (entry.fee, entry.vsize),
+            None => return None,
+        };
+        for ancestor in self.ancestors(txid) {
+            self.update_descendant_stats(&ancestor, fee, vsize, false);
+        }
+        for descendant in self.descendants(txid) {
+            self.remove_ancestor_stats(&descendant, fee, vsize);
+        }
+        let entry = self.remove_entry(txid);
+        self.emit_removed(std::slice::from_ref(&entry), RemovalReason::Block);
+        return Some(entry);
+    }
+
+    /// Remove the entry with `txid` from the pool and its indexes, and unlink it from its
+    /// parents and children, leaving the stats of its ancestors and descendants to the caller
+    fn remove_entry(&mut self, txid: &H256) -> MempoolEntry {
+        let entry = self.entries.remove(txid).unwrap();
+        for parent in entry.parents.iter() {
+            if let Some(parent) = self.entries.get_mut(parent) {
+                parent.children.remove(txid);
+            }
+        }
+        for child in entry.children.iter() {
+            if let Some(child) = self.entries.get_mut(child) {
+                child.parents.remove(txid);
+            }
+        }
+        for input in entry.transaction.get_inputs() {
+            self.spent.remove(&input.outpoint());
+        }
+        self.by_score.remove(&entry.score());
+        self.by_descendant_score.remove(&entry.descendant_score());
+        self.by_sequence.remove(&entry.sequence);
+        self.usage -= entry.usage;
+        return entry;
+    }
+
+    /// Set the height and median time past of the next block, see `validation::check_final`.
+    /// The transactions no longer final in it, e.g. after a reorganization, leave the pool with
+    /// their descendants, which are returned.
+    pub fn set_next_block(&mut self, height: u32, mtp: SystemTime) -> Vec<MempoolEntry> {
+        self.next_height = height;
+        self.next_mtp = mtp;
+        let locked: Vec<H256> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| validation::check_final(&e.transaction, height, mtp).is_err())
+            .map(|(txid, _)| *txid)
+            .collect();
+        let mut removed: Vec<MempoolEntry> = Vec::new();
+        for txid in &locked {
+            // it may have left as the descendant of another one
+            if self.entries.contains_key(txid) {
+                removed.extend(self.remove_with_descendants(txid));
+            }
+        }
+        self.emit_removed(&removed, RemovalReason::Reorg);
+        return removed;
+    }
+
+    /// Follow a change of the longest chain. The transactions of the `disconnected` blocks, from
+    /// the tip down, return to the pool if still valid against `utxo`, the UTXO set of the new
+    /// tip. The transactions of the `connected` blocks, from the fork up, leave the pool along
+    /// with those conflicting with them, and the orphans waiting for them are accepted.
+    pub fn update_chain(&mut self, disconnected: &[Block], connected: &[Block], utxo: &UtxoSet) {
+        if !disconnected.is_empty() {
+            self.restore(disconnected, utxo);
+        }
+        let confirmed: Vec<&Transaction> =
+            connected.iter().flat_map(|b| b.get_transactions()).filter(|t| !t.is_coinbase()).collect();
+        for transaction in confirmed.iter() {
+            if self.remove_confirmed(&transaction.txid()).is_none() {
+                self.remove_conflicts(transaction);
+            }
+        }
+        for transaction in confirmed {
+            self.accept_orphans(transaction, utxo);
+        }
+        self.expire();
+    }
+
+    /// Accept again the transactions of the `disconnected` blocks, oldest first. The transactions
+    /// of the pool spending their outputs are accepted again after them, so that they come after
+    /// their parents; those no longer valid are removed.
+    fn restore(&mut self, disconnected: &[Block], utxo: &UtxoSet) {
+        let restored: Vec<&Transaction> =
+            disconnected.iter().rev().flat_map(|b| b.get_transactions()).filter(|t| !t.is_coinbase()).collect();
+        let txids: HashSet<H256> = restored.iter().map(|t| t.txid()).collect();
+        let spenders: Vec<H256> = self
+            .by_sequence
+            .values()
+            .filter(|txid| self.entries[txid].transaction.get_inputs().iter().any(|i| txids.contains(&i.prev_txid)))
+            .cloned()
+            .collect();
+        let mut waiting: Vec<MempoolEntry> = Vec::new();
+        for txid in spenders.iter() {
+            waiting.extend(self.remove_with_descendants(txid));
+        }
+        waiting.sort_by_key(|e| e.sequence);
+
+        for transaction in restored {
+            let _ = self.accept(transaction.clone(), utxo);
+        }
+        let lost: Vec<MempoolEntry> =
+            waiting.into_iter().filter(|e| self.accept(e.transaction.clone(), utxo).is_err()).collect();
+        self.emit_removed(&lost, RemovalReason::Reorg);
+    }
+
+    /// The transactions of the pool in the order they were accepted, so that each comes after
+    /// the transactions whose outputs it spends, ready to be put in a block
+    pub fn snapshot(&self) -> Vec<Transaction> {
+        return self.by_sequence.values().map(|txid| self.entries[txid].transaction.clone()).collect();
+    }
+
+    /// The transactions of the pool, in no particular order
+    pub fn transactions(&self) -> impl Iterator<Item = &Transaction> {
+        return self.entries.values().map(|e| &e.transaction);
+    }
+
+    /// What the pool knows of each of its transactions, in the order they were accepted
+    pub fn entries(&self) -> Vec<MempoolEntryInfo> {
+        return self.by_sequence.values().map(|txid| MempoolEntryInfo::from(&self.entries[txid])).collect();
+    }
+
+    pub fn info(&self) -> MempoolInfo {
+        return MempoolInfo {
+            count: self.entries.len(),
+            vsize: self.entries.values().map(|e| e.vsize).sum(),
+            total_fee: self.entries.values().map(|e| e.fee).sum(),
+            usage: self.usage,
+            max_size: self.config.max_size,
+            max_count: self.config.max_count,
+            min_fee_rate: self.min_fee_rate(),
+            min_relay_fee_rate: self.config.min_relay_fee_rate,
+            orphans: self.orphans.len(),
+        };
+    }
+
+    /// The most profitable transactions to mine, of at most `max_vsize` virtual bytes in total,
+    /// each after its parents. Transactions are picked by ancestor fee rate, along with their
+    /// ancestors not picked yet, so that a child paying a high fee brings in its parents. Once a
+    /// transaction is picked, its descendants are ranked by the rate of the ancestors left.
+    /// Packages that do not fit are skipped for smaller ones, until the block is nearly full and
+    /// `MAX_SELECTION_FAILURES` packages in a row did not fit.
+    pub fn select(&self, max_vsize: usize) -> Vec<Transaction> {
+        let mut selected: Vec<H256> = Vec::new();
+        let mut included: HashSet<H256> = HashSet::new();
+        // transactions not fitting, until their ancestors left change
+        let mut skipped: HashSet<H256> = HashSet::new();
+        // ancestor fee and size of transactions with picked ancestors, counting those left only
+        let mut modified: HashMap<H256, (Amount, usize)> = HashMap::new();
+        // scores of the modified transactions, with outdated ones skipped when popped
+        let mut modified_queue: BinaryHeap<Score> = BinaryHeap::new();
+        let mut by_score = self.by_score.iter().rev().peekable();
+        let mut vsize = 0;
+        let mut failures = 0;
+        loop {
+            while let Some((_, _, txid)) = by_score.peek() {
+                if !included.contains(txid) && !skipped.contains(txid) && !modified.contains_key(txid) {
+                    break;
+                }
+                by_score.next();
+            }
+            while let Some((fee_rate, _, txid)) = modified_queue.peek() {
+                let current = modified.get(txid).map(|&(fee, vsize)| FeeRate::from_fee(fee, vsize));
+                if current == Some(*fee_rate) && !skipped.contains(txid) {
+                    break;
+                }
+                modified_queue.pop();
+            }
+            let txid = match (by_score.peek(), modified_queue.peek()) {
+                (Some(&unmodified), Some(modified)) if unmodified > modified => by_score.next().unwrap().2,
+                (_, Some(_)) => modified_queue.pop().unwrap().2,
+                (Some(_), None) => by_score.next().unwrap().2,
+                (None, None) => break,
+            };
+            let mut package: Vec<H256> = self.ancestors(&txid).into_iter().filter(|a| !included.contains(a)).collect();
+            package.push(txid);
+            let package_vsize: usize = package.iter().map(|t| self.entries[t].vsize).sum();
+            if vsize + package_vsize > max_vsize {
+                skipped.insert(txid);
+                failures += 1;
+                if failures > MAX_SELECTION_FAILURES && vsize + BLOCK_FULL_MARGIN > max_vsize {
+                    break;
+                }
+                continue;
+            }
+            failures = 0;
+            vsize += package_vsize;
+            package.sort_by_key(|t| self.entries[t].sequence);
+            for picked in package {
+                included.insert(picked);
+                modified.remove(&picked);
+                let entry = &self.entries[&picked];
+                for descendant in self.descendants(&picked) {
+                    if included.contains(&descendant) {
+                        continue;
+                    }
+                    let other = &self.entries[&descendant];
+                    let (fee, size) = modified.get(&descendant).cloned().unwrap_or((other.ancestor_fee, other.ancestor_vsize));
+                    let left = (fee - entry.fee, size - entry.vsize);
+                    modified.insert(descendant, left);
+                    modified_queue.push((FeeRate::from_fee(left.0, left.1), Reverse(other.sequence), descendant));
+                    skipped.remove(&descendant);
+                }
+                selected.push(picked);
+            }
+        }
+        return selected.into_iter().map(|txid| self.entries[&txid].transaction.clone()).collect();
+    }
+}
+
+/// Keep `mempool` in sync with the longest chain of `blockchain` from now on, see
+/// `Mempool::update_chain`, from a thread following the chain events. The blockchain is locked
+/// before the pool.
+pub fn follow_chain(mempool: &Arc<RwLock<Mempool>>, blockchain: &Arc<RwLock<Blockchain>>) {
+    let events = {
+        let mut blockchain = blockchain.write().unwrap();
+        mempool.write().unwrap().set_next_block(blockchain.tip_height() + 1, blockchain.median_time_past());
+        blockchain.subscribe()
+    };
+    let mempool = Arc::clone(mempool);
+    let blockchain = Arc::clone(blockchain);
+    thread::Builder::new()
+        .name("mempool".to_string())
+        .spawn(move || {
+            let mut disconnected: Vec<H256> = Vec::new();
+            let mut connected: Vec<H256> = Vec::new();
+            for event in events.iter() {
+                match event {
+                    ChainEvent::Disconnected(hash) => disconnected.push(hash),
+                    ChainEvent::Connected(hash) => connected.push(hash),
+                    ChainEvent::NewTip(_) => {
+                        let blockchain = blockchain.read().unwrap();
+                        let blocks = |hashes: Vec<H256>| hashes.iter().map(|h| blockchain.get(h)).collect::<Vec<Block>>();
+                        let left = blocks(std::mem::take(&mut disconnected));
+                        let joined = blocks(std::mem::take(&mut connected));
+                        let mut mempool = mempool.write().unwrap();
+                        // the disconnected transactions return to the pool if final in the next block
+                        mempool.set_next_block(blockchain.tip_height() + 1, blockchain.median_time_past());
+                        mempool.update_chain(&left, &joined, blockchain.utxo_set());
+                    }
+                }
+            }
+        })
+        .unwrap();
+}
+
+#[cfg(any(test, test_utilities))]
+pub mod tests {
+    use super::*;
+    use crate::crypto::keys::KeyPair;
+    use crate::crypto::merkle::MerkleTree;
+    use crate::transaction::{TxInput, SEQUENCE_MAX_REPLACEABLE};
+
+    /// A UTXO set holding `n` outputs of `value` paying to `key`, with the transaction creating them
+    pub fn funded(key: &KeyPair, n: usize, value: Amount) -> (UtxoSet, Transaction) {
+        let outputs = (0..n).map(|_| TxOutput::new(value, key.public_key().address())).collect();
+        let funding = Transaction::new(vec![TxInput::coinbase(vec![1])], outputs);
+        let mut utxo = UtxoSet::new();
+        let merkle_root = MerkleTree::new(&[funding.clone()]).root();
+        utxo.connect_block(&Block::new(H256::default(), H256::default(), vec![funding.clone()], merkle_root));
+        return (utxo, funding);
+    }
+
+    /// A transaction spending `inputs`, all paying to `key`, with one output to `key` of `value`
+    pub fn spend(key: &KeyPair, inputs: &[OutPoint], value: Amount) -> Transaction {
+        let inputs = inputs.iter().map(|o| TxInput::new(o.txid, o.index)).collect();
+        let mut transaction = Transaction::new(inputs, vec![TxOutput::new(value, key.public_key().address())]);
+        transaction.sign(key).unwrap();
+        return transaction;
+    }
+
+    #[test]
+    fn ancestor_scores() {
+        let key = KeyPair::random();
+        let (utxo, funding) = funded(&key, 3, 100_000);
+        let coin = |i: u32| OutPoint::new(funding.txid(), i);
+        let mut mempool = Mempool::with_config(MempoolConfig { min_relay_fee_rate: FeeRate::ZERO, ..MempoolConfig::default() });
+        let txids = |transactions: Vec<Transaction>| transactions.iter().map(|t| t.txid()).collect::<Vec<H256>>();
+
+        // a child paying a high fee brings in its parent paying almost nothing
+        let parent = spend(&key, &[coin(0)], 99_900);
+        let child = spend(&key, &[OutPoint::new(parent.txid(), 0)], 79_900);
+        let other = spend(&key, &[coin(1)], 95_000);
+        for t in [&parent, &child, &other].iter() {
+            mempool.accept((*t).clone(), &utxo).unwrap();
+        }
+        let entry = mempool.get(&child.txid()).unwrap();
+        assert_eq!(entry.get_ancestor_fee(), 20_100);
+        assert_eq!(entry.get_ancestor_vsize(), parent.vsize() + child.vsize());
+        assert!(entry.ancestor_fee_rate() > mempool.get(&other.txid()).unwrap().fee_rate());
+        assert_eq!(txids(mempool.select(usize::MAX)), vec![parent.txid(), child.txid(), other.txid()]);
+        // the package does not fit, the other transaction does
+        assert_eq!(txids(mempool.select(other.vsize())), vec![other.txid()]);
+
+        // once a parent paying a high fee is picked, its child only counts for its own fee
+        let mut mempool = Mempool::new();
+        let parent = spend(&key, &[coin(0)], 90_000);
+        let child = spend(&key, &[OutPoint::new(parent.txid(), 0)], 87_000);
+        let other = spend(&key, &[coin(1)], 96_000);
+        let last = spend(&key, &[coin(2)], 99_000);
+        for t in [&parent, &child, &other, &last].iter() {
+            mempool.accept((*t).clone(), &utxo).unwrap();
+        }
+        assert!(mempool.get(&child.txid()).unwrap().ancestor_fee_rate() > mempool.get(&other.txid()).unwrap().fee_rate());
+        assert_eq!(
+            txids(mempool.select(usize::MAX)),
+            vec![parent.txid(), other.txid(), child.txid(), last.txid()]
+        );
+        assert_eq!(mempool.descendants(&parent.txid()), vec![child.txid()]);
+        assert_eq!(mempool.ancestors(&child.txid()), vec![parent.txid()].into_iter().collect());
+        mempool.remove(&parent.txid(), RemovalReason::Evicted);
+        assert_eq!(txids(mempool.select(usize::MAX)), vec![other.txid(), last.txid()]);
+    }
+
+    #[test]
+    fn lock_times() {
+        let key = KeyPair::random();
+        let (utxo, funding) = funded(&key, 1, 100_000);
+        let mut mempool = Mempool::new();
+        let mut locked = spend(&key, &[OutPoint::new(funding.txid(), 0)], 90_000);
+        locked.set_sequence(0, 0);
+        locked.set_lock_time(10);
+        locked.sign(&key).unwrap();
+        let child = spend(&key, &[OutPoint::new(locked.txid(), 0)], 80_000);
+
+        // the transaction may only be in a block above its lock time
+        mempool.set_next_block(10, UNIX_EPOCH);
+        assert_eq!(mempool.accept(locked.clone(), &utxo), Err(MempoolError::Invalid(TxError::NotFinal)));
+        mempool.set_next_block(11, UNIX_EPOCH);
+        mempool.accept(locked.clone(), &utxo).unwrap();
+        mempool.accept(child.clone(), &utxo).unwrap();
+        // a reorganization to a shorter chain takes it out again, with its child
+        let removed = mempool.set_next_block(10, UNIX_EPOCH);
+        assert_eq!(removed.len(), 2);
+        assert!(mempool.is_empty());
+    }
+
+    #[test]
+    fn replacement() {
+        let key = KeyPair::random();
+        let (utxo, funding) = funded(&key, 2, 100_000);
+        let coin = |i: u32| OutPoint::new(funding.txid(), i);
+        let replaceable = |inputs: &[OutPoint], value: Amount| {
+            let mut transaction = spend(&key, inputs, value);
+            transaction.set_sequence(0, SEQUENCE_MAX_REPLACEABLE);
+            transaction.sign(&key).unwrap();
+            transaction
+        };
+        let mut mempool = Mempool::new();
+        let events = mempool.subscribe();
+        let original = replaceable(&[coin(0)], 99_000);
+        let child = spend(&key, &[OutPoint::new(original.txid(), 0)], 98_000);
+        mempool.accept(original.clone(), &utxo).unwrap();
+        mempool.accept(child.clone(), &utxo).unwrap();
+        // the child inherits the signal of its parent
+        assert!(mempool.is_replaceable(&child.txid()));
+
+        // must pay for both evicted transactions, 2000, and its own relay
+        let cheap = spend(&key, &[coin(0)], 98_000);
+        assert!(matches!(
+            mempool.accept(cheap, &utxo),
+            Err(MempoolError::Replacement(ReplacementError::LowFee { fee: 2_000, .. }))
+        ));
+        let spends_original = spend(&key, &[coin(0), OutPoint::new(child.txid(), 0)], 150_000);
+        assert_eq!(mempool.accept(spends_original, &utxo), Err(MempoolError::SpendsConflict(original.txid())));
+        let replacement = spend(&key, &[coin(0)], 95_000);
+        mempool.accept(replacement.clone(), &utxo).unwrap();
+        assert!(!mempool.contains(&original.txid()) && !mempool.contains(&child.txid()));
+        assert_eq!(mempool.spender(&coin(0)), Some(replacement.txid()));
+        assert_eq!(mempool.len(), 1);
+        let removed: Vec<MempoolEvent> = events.try_iter().filter(|e| matches!(e, MempoolEvent::Removed { .. })).collect();
+        assert_eq!(
+            removed,
+            vec![
+                MempoolEvent::Removed { txid: original.txid(), reason: RemovalReason::Replaced(replacement.txid()) },
+                MempoolEvent::Removed { txid: child.txid(), reason: RemovalReason::Replaced(replacement.txid()) },
+            ]
+        );
+
+        // the replacement did not signal
+        let again = spend(&key, &[coin(0)], 90_000);
+        assert_eq!(
+            mempool.accept(again, &utxo),
+            Err(MempoolError::Replacement(ReplacementError::NotReplaceable(replacement.txid())))
+        );
+    }
+
+    #[test]
+    fn orphans() {
+        let key = KeyPair::random();
+        let (utxo, funding) = funded(&key, 2, 100_000);
+        let peer: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let mut mempool = Mempool::new();
+        let parent = spend(&key, &[OutPoint::new(funding.txid(), 0)], 99_000);
+        let child = spend(&key, &[OutPoint::new(parent.txid(), 0)], 98_000);
+        let grandchild = spend(&key, &[OutPoint::new(child.txid(), 0)], 97_000);
+        let other = spend(&key, &[OutPoint::new(parent.txid(), 0)], 10_000);
+        let joined = spend(&key, &[OutPoint::new(child.txid(), 0), OutPoint::new(other.txid(), 0)], 50_000);
+
+        assert_eq!(
+            mempool.accept_from(grandchild.clone(), &utxo, peer),
+            Err(MempoolError::MissingInputs(vec![OutPoint::new(child.txid(), 0)]))
+        );
+        assert!(mempool.accept_from(child.clone(), &utxo, peer).is_err());
+        assert!(mempool.accept_from(joined.clone(), &utxo, peer).is_err());
+        assert_eq!(mempool.get_orphans().len(), 3);
+        assert_eq!(
+            mempool.accept_from(parent.clone(), &utxo, peer),
+            Ok(vec![parent.txid(), child.txid(), grandchild.txid()])
+        );
+        // still waiting for the output of the other transaction
+        assert!(mempool.get_orphans().contains(&joined.txid()));
+        assert_eq!(mempool.get_orphans_mut().remove_peer(&peer), 1);
+        assert!(mempool.get_orphans().is_empty());
+    }
+
+    #[test]
+    fn events() {
+        let key = KeyPair::random();
+        let (utxo, funding) = funded(&key, 2, 100_000);
+        let coin = |i: u32| OutPoint::new(funding.txid(), i);
+        let mut mempool = Mempool::new();
+        let events = mempool.subscribe();
+        let parent = spend(&key, &[coin(0)], 99_000);
+        let child = spend(&key, &[OutPoint::new(parent.txid(), 0)], 98_000);
+        let other = spend(&key, &[coin(1)], 99_000);
+        for t in [&parent, &child, &other].iter() {
+            mempool.accept((*t).clone(), &utxo).unwrap();
+        }
+
+        mempool.remove_confirmed(&parent.txid()).unwrap();
+        // a block spends the output spent by `other` in another transaction
+        assert!(mempool.remove_conflicts(&child).is_empty());
+        let conflicting = spend(&key, &[coin(1)], 90_000);
+        assert_eq!(mempool.remove_conflicts(&conflicting).len(), 1);
+        let config = MempoolConfig { max_count: 0, ..MempoolConfig::default() };
+        assert_eq!(mempool.set_config(config).len(), 1);
+        assert!(mempool.is_empty());
+
+        let received: Vec<MempoolEvent> = events.try_iter().collect();
+        assert_eq!(
+            received,
+            vec![
+                MempoolEvent::Added { txid: parent.txid() },
+                MempoolEvent::Added { txid: child.txid() },
+                MempoolEvent::Added { txid: other.txid() },
+                MempoolEvent::Removed { txid: parent.txid(), reason: RemovalReason::Block },
+                MempoolEvent::Removed { txid: other.txid(), reason: RemovalReason::Conflict },
+                MempoolEvent::Removed { txid: child.txid(), reason: RemovalReason::Evicted },
+            ]
+        );
+    }
+
+    #[test]
+    fn chain_updates() {
+        let key = KeyPair::random();
+        let (mut utxo, funding) = funded(&key, 2, 100_000);
+        let coin = |i: u32| OutPoint::new(funding.txid(), i);
+        let mut mempool = Mempool::new();
+        let parent = spend(&key, &[coin(0)], 99_000);
+        let child = spend(&key, &[OutPoint::new(parent.txid(), 0)], 98_000);
+        let other = spend(&key, &[coin(1)], 99_000);
+        for t in [&parent, &child, &other].iter() {
+            mempool.accept((*t).clone(), &utxo).unwrap();
+        }
+
+        // a block mines the parent, and another transaction spending the coin of `other`
+        let conflicting = spend(&key, &[coin(1)], 95_000);
+        let transactions = vec![parent.clone(), conflicting.clone()];
+        let merkle_root = MerkleTree::new(&transactions).root();
+        let block = Block::new(H256::default(), H256::default(), transactions, merkle_root);
+        let spent = utxo.connect_block(&block);
+        mempool.update_chain(&[], &[block.clone()], &utxo);
+        assert_eq!(mempool.snapshot().iter().map(|t| t.txid()).collect::<Vec<H256>>(), vec![child.txid()]);
+        assert!(mempool.get(&child.txid()).unwrap().get_parents().is_empty());
+
+        // once the block is disconnected, its transactions come back before the child
+        utxo.disconnect_block(&block, &spent);
+        mempool.update_chain(&[block], &[], &utxo);
+        let snapshot: Vec<H256> = mempool.snapshot().iter().map(|t| t.txid()).collect();
+        assert_eq!(snapshot, vec![parent.txid(), conflicting.txid(), child.txid()]);
+        let entry = mempool.get(&child.txid()).unwrap();
+        assert_eq!(entry.get_parents(), &vec![parent.txid()].into_iter().collect());
+        assert_eq!(entry.get_ancestor_count(), 2);
+        assert_eq!(mempool.get(&parent.txid()).unwrap().get_descendant_count(), 2);
+    }
+
+    #[test]
+    fn package_acceptance() {
+        let key = KeyPair::random();
+        let (utxo, funding) = funded(&key, 2, 100_000);
+        let coin = |i: u32| OutPoint::new(funding.txid(), i);
+        let mut mempool = Mempool::new();
+
+        // the parent pays nothing, its child pays for both
+        let parent = spend(&key, &[coin(0)], 100_000);
+        let child = spend(&key, &[OutPoint::new(parent.txid(), 0)], 96_000);
+        assert!(matches!(mempool.accept(parent.clone(), &utxo), Err(MempoolError::LowFeeRate { .. })));
+        assert_eq!(
+            mempool.accept_package(vec![child.clone(), parent.clone()], &utxo),
+            Err(MempoolError::Package(PackageError::NotSorted))
+        );
+        assert_eq!(
+            mempool.accept_package(vec![parent.clone(); MAX_PACKAGE_COUNT + 1], &utxo),
+            Err(MempoolError::Package(PackageError::TooLarge))
+        );
+        let accepted = mempool.accept_package(vec![parent.clone(), child.clone()], &utxo).unwrap();
+        assert_eq!(accepted, vec![parent.txid(), child.txid()]);
+        assert_eq!(mempool.get(&child.txid()).unwrap().get_ancestor_fee(), 4_000);
+        assert_eq!(mempool.select(usize::MAX).len(), 2);
+        assert_eq!(mempool.accept_package(vec![parent.clone()], &utxo), Ok(Vec::new()));
+        let conflicting = spend(&key, &[coin(0)], 90_000);
+        assert_eq!(
+            mempool.accept_package(vec![conflicting.clone()], &utxo),
+            Err(MempoolError::Package(PackageError::Conflict(conflicting.txid())))
+        );
+
+        // a transaction refused once the first is in takes it out again
+        let other = spend(&key, &[coin(1)], 100_000);
+        let dust = spend(&key, &[OutPoint::new(other.txid(), 0)], 100);
+        let refused = mempool.accept_package(vec![other.clone(), dust.clone()], &utxo);
+        assert!(matches!(
+            refused,
+            Err(MempoolError::Package(PackageError::Rejected { txid, error }))
+                if txid == dust.txid() && matches!(*error, MempoolError::NonStandard(PolicyError::Dust { .. }))
+        ));
+        assert!(!mempool.contains(&other.txid()));
+        assert_eq!(mempool.len(), 2);
+    }
+
+    #[test]
+    fn expiry() {
+        let key = KeyPair::random();
+        let (utxo, funding) = funded(&key, 2, 100_000);
+        let mut mempool = Mempool::new();
+        let events = mempool.subscribe();
+        let parent = spend(&key, &[OutPoint::new(funding.txid(), 0)], 99_000);
+        let child = spend(&key, &[OutPoint::new(parent.txid(), 0)], 98_000);
+        mempool.accept(parent.clone(), &utxo).unwrap();
+        mempool.accept(child.clone(), &utxo).unwrap();
+        let accepted = mempool.get(&parent.txid()).unwrap().get_time();
+        assert!(mempool.expire().is_empty());
+
+        // the child is removed along with its expired parent, even though it is more recent
+        let other = spend(&key, &[OutPoint::new(funding.txid(), 1)], 99_000);
+        mempool.accept(other.clone(), &utxo).unwrap();
+        let expired = mempool.expire_at(accepted + DEFAULT_EXPIRY);
+        assert_eq!(expired.len(), 2);
+        assert_eq!(mempool.snapshot()[0].txid(), other.txid());
+        assert_eq!(mempool.len(), 1);
+        let removed: Vec<MempoolEvent> = events.try_iter().skip(3).collect();
+        assert_eq!(
+            removed,
+            vec![
+                MempoolEvent::Removed { txid: parent.txid(), reason: RemovalReason::Expired },
+                MempoolEvent::Removed { txid: child.txid(), reason: RemovalReason::Expired },
+            ]
+        );
+
+        mempool.set_config(MempoolConfig { expiry: Duration::from_secs(0), ..MempoolConfig::default() });
+        assert_eq!(mempool.expire().len(), 1);
+        assert!(mempool.is_empty());
+    }
+
+    #[test]
+    fn packages() {
+        let key = KeyPair::random();
+        let (utxo, funding) = funded(&key, 1, 100_000);
+        let config = MempoolConfig { max_ancestors: 3, max_descendants: 3, ..MempoolConfig::default() };
+        let mut mempool = Mempool::with_config(config);
+
+        let address = key.public_key().address();
+        let mut root = Transaction::new(
+            vec![TxInput::new(funding.txid(), 0)],
+            vec![TxOutput::new(45_000, address), TxOutput::new(45_000, address)],
+        );
+        root.sign(&key).unwrap();
+        let child = spend(&key, &[OutPoint::new(root.txid(), 0)], 40_000);
+        let grandchild = spend(&key, &[OutPoint::new(child.txid(), 0)], 35_000);
+        for t in [&root, &child, &grandchild].iter() {
+            mempool.accept((*t).clone(), &utxo).unwrap();
+        }
+        let entry = mempool.get(&child.txid()).unwrap();
+        assert_eq!(entry.get_parents(), &vec![root.txid()].into_iter().collect());
+        assert_eq!(entry.get_children(), &vec![grandchild.txid()].into_iter().collect());
+        assert_eq!(mempool.get(&grandchild.txid()).unwrap().get_ancestor_count(), 3);
+        assert_eq!(mempool.get(&root.txid()).unwrap().get_descendant_count(), 3);
+
+        let too_deep = spend(&key, &[OutPoint::new(grandchild.txid(), 0)], 30_000);
+        assert!(matches!(mempool.accept(too_deep, &utxo), Err(MempoolError::AncestorLimit { count: 4, .. })));
+        let sibling = spend(&key, &[OutPoint::new(root.txid(), 1)], 40_000);
+        assert_eq!(mempool.accept(sibling.clone(), &utxo), Err(MempoolError::DescendantLimit(root.txid())));
+
+        // the root confirms: its descendants stay, now without ancestors in the pool
+        let confirmed = mempool.remove_confirmed(&root.txid()).unwrap();
+        assert_eq!(confirmed.get_transaction().txid(), root.txid());
+        assert_eq!(mempool.len(), 2);
+        let entry = mempool.get(&child.txid()).unwrap();
+        assert!(entry.get_parents().is_empty());
+        assert_eq!(entry.get_ancestor_fee(), entry.get_fee());
+        assert_eq!(mempool.get(&grandchild.txid()).unwrap().get_ancestor_count(), 2);
+        assert_eq!(mempool.select(usize::MAX).len(), 2);
+
+        // the evicted child takes the grandchild along
+        assert_eq!(mempool.remove(&child.txid(), RemovalReason::Evicted).len(), 2);
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.usage(), 0);
+    }
+
+    #[test]
+    fn config() {
+        let key = KeyPair::random();
+        let (utxo, funding) = funded(&key, 3, 100_000);
+        let coin = |i: u32| OutPoint::new(funding.txid(), i);
+        let mut mempool = Mempool::new();
+        assert_eq!(mempool.min_fee_rate(), DEFAULT_MIN_RELAY_FEE_RATE);
+
+        let cheap = spend(&key, &[coin(0)], 99_950);
+        assert_eq!(
+            mempool.accept(cheap.clone(), &utxo),
+            Err(MempoolError::LowFeeRate {
+                fee_rate: FeeRate::from_fee(50, cheap.vsize()),
+                min_fee_rate: DEFAULT_MIN_RELAY_FEE_RATE
+            })
+        );
+        let low = spend(&key, &[coin(1)], 99_000);
+        let high = spend(&key, &[coin(2)], 90_000);
+        mempool.accept(low.clone(), &utxo).unwrap();
+        mempool.accept(high.clone(), &utxo).unwrap();
+
+        // a pool for mining keeps fewer transactions, and accepts cheaper ones
+        let config = MempoolConfig { max_count: 1, min_relay_fee_rate: FeeRate::ZERO, ..mempool.get_config().clone() };
+        let evicted = mempool.set_config(config);
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].get_transaction().txid(), low.txid());
+        assert!(mempool.contains(&high.txid()));
+        assert_eq!(mempool.get_config().max_count, 1);
+        assert!(matches!(mempool.accept(cheap, &utxo), Err(MempoolError::LowFeeRate { .. })));
+    }
+
+    #[test]
+    fn introspection() {
+        let key = KeyPair::random();
+        let (utxo, funding) = funded(&key, 2, 100_000);
+        let mut mempool = Mempool::new();
+        let parent = spend(&key, &[OutPoint::new(funding.txid(), 0)], 99_000);
+        let child = spend(&key, &[OutPoint::new(parent.txid(), 0)], 97_000);
+        let other = spend(&key, &[OutPoint::new(funding.txid(), 1)], 95_000);
+        for t in [&parent, &child, &other].iter() {
+            mempool.accept((*t).clone(), &utxo).unwrap();
+        }
+
+        let entries = mempool.entries();
+        let txids: Vec<String> = entries.iter().map(|e| e.txid.clone()).collect();
+        assert_eq!(txids, vec![parent.txid().to_string(), child.txid().to_string(), other.txid().to_string()]);
+        assert_eq!(entries[0].spent_by, vec![child.txid().to_string()]);
+        assert_eq!(entries[1].depends, vec![parent.txid().to_string()]);
+        assert_eq!(entries[1].fee, 2_000);
+        assert_eq!(entries[1].fee_rate, FeeRate::from_fee(2_000, child.vsize()));
+        assert_eq!(entries[1].ancestor_count, 2);
+        assert_eq!(entries[1].ancestor_fee_rate, FeeRate::from_fee(3_000, parent.vsize() + child.vsize()));
+        assert_eq!(entries[0].descendant_vsize, parent.vsize() + child.vsize());
+        assert!(entries[2].time > 0);
+
+        let info = mempool.info();
+        assert_eq!(info.count, 3);
+        assert_eq!(info.vsize, parent.vsize() + child.vsize() + other.vsize());
+        assert_eq!(info.total_fee, 8_000);
+        assert_eq!(info.usage, mempool.usage());
+        assert_eq!(info.min_fee_rate, DEFAULT_MIN_RELAY_FEE_RATE);
+        assert_eq!(info.orphans, 0);
+    }
+
+    #[test]
+    fn eviction() {
+        let key = KeyPair::random();
+        let (utxo, funding) = funded(&key, 4, 100_000);
+        let coin = |i: u32| OutPoint::new(funding.txid(), i);
+        let mut mempool = Mempool::with_config(MempoolConfig { min_relay_fee_rate: FeeRate::ZERO, ..MempoolConfig::default() });
+
+        // the parent pays little, but its child pays enough for both
+        let parent = spend(&key, &[coin(0)], 99_800);
+        let child = spend(&key, &[OutPoint::new(parent.txid(), 0)], 89_800);
+        let cheap = spend(&key, &[coin(1)], 99_000);
+        for t in [&parent, &child, &cheap].iter() {
+            mempool.accept((*t).clone(), &utxo).unwrap();
+        }
+        let entry = mempool.get(&parent.txid()).unwrap();
+        assert_eq!(entry.get_descendant_fee(), 10_200);
+        assert_eq!(entry.get_descendant_vsize(), parent.vsize() + child.vsize());
+        assert_eq!(mempool.min_fee_rate(), FeeRate::ZERO);
+
+        // signatures of different lengths make the transactions differ in size by a few bytes
+        let full = mempool.usage() + 8;
+        mempool.set_config(MempoolConfig { max_size: full, ..mempool.get_config().clone() });
+        let better = spend(&key, &[coin(2)], 90_000);
+        mempool.accept(better.clone(), &utxo).unwrap();
+        assert!(!mempool.contains(&cheap.txid()));
+        assert!(mempool.contains(&parent.txid()) && mempool.contains(&child.txid()));
+        assert!(mempool.usage() <= full);
+        let min_fee_rate = FeeRate::from_fee(1_000, cheap.vsize()) + INCREMENTAL_RELAY_FEE;
+        assert_eq!(mempool.min_fee_rate(), min_fee_rate);
+        assert!(matches!(mempool.accept(cheap.clone(), &utxo), Err(MempoolError::LowFeeRate { .. })));
+
+        // removing the child brings the parent to its own rate
+        mempool.remove(&child.txid(), RemovalReason::Evicted);
+        assert_eq!(mempool.get(&parent.txid()).unwrap().get_descendant_fee(), 200);
+        assert_eq!(mempool.get(&parent.txid()).unwrap().get_descendant_vsize(), parent.vsize());
+
+        let decayed = mempool.min_fee_rate_at(SystemTime::now() + MIN_FEE_RATE_HALFLIFE);
+        assert!(decayed <= FeeRate::from_sat_per_kvb(min_fee_rate.sat_per_kvb() / 2 + 1));
+        assert_eq!(mempool.min_fee_rate_at(SystemTime::now() + MIN_FEE_RATE_HALFLIFE * 20), FeeRate::ZERO);
+    }
+
+    #[test]
+    fn accept_and_remove() {
+        let key = KeyPair::random();
+        let (utxo, funding) = funded(&key, 2, 10_000);
+        let coin = OutPoint::new(funding.txid(), 0);
+        let mut mempool = Mempool::new();
+
+        let parent = spend(&key, &[coin], 9_000);
+        assert_eq!(mempool.accept(parent.clone(), &utxo), Ok(parent.txid()));
+        assert_eq!(mempool.accept(parent.clone(), &utxo), Err(MempoolError::Duplicate(parent.txid())));
+        let double_spend = spend(&key, &[coin], 8_000);
+        assert_eq!(
+            mempool.accept(double_spend, &utxo),
+            Err(MempoolError::Replacement(ReplacementError::NotReplaceable(parent.txid())))
+        );
+        let stolen = spend(&KeyPair::random(), &[OutPoint::new(funding.txid(), 1)], 9_000);
+        assert_eq!(mempool.accept(stolen, &utxo), Err(MempoolError::Invalid(TxError::BadSignature(0))));
+        let dust = spend(&key, &[OutPoint::new(funding.txid(), 1)], 100);
+        assert!(matches!(mempool.accept(dust, &utxo), Err(MempoolError::NonStandard(PolicyError::Dust { .. }))));
+
+        // spending an output of the pool
+        let child = spend(&key, &[OutPoint::new(parent.txid(), 0)], 8_000);
+        mempool.accept(child.clone(), &utxo).unwrap();
+        let entry = mempool.get(&child.txid()).unwrap();
+        assert_eq!(entry.get_fee(), 1_000);
+        assert_eq!(entry.fee_rate(), FeeRate::from_fee(1_000, child.vsize()));
+        let other = spend(&key, &[OutPoint::new(funding.txid(), 1)], 9_500);
+        mempool.accept(other.clone(), &utxo).unwrap();
+        let snapshot: Vec<H256> = mempool.snapshot().iter().map(|t| t.txid()).collect();
+        assert_eq!(snapshot, vec![parent.txid(), child.txid(), other.txid()]);
+
+        let removed: Vec<H256> = mempool.remove(&parent.txid(), RemovalReason::Evicted).iter().map(|e| e.get_transaction().txid()).collect();
+        assert_eq!(removed, vec![parent.txid(), child.txid()]);
+        assert!(!mempool.contains(&child.txid()));
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.spender(&coin), None);
+        assert_eq!(mempool.accept(spend(&key, &[coin], 8_000), &utxo).map(|_| ()), Ok(()));
+    }
+}
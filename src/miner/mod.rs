@@ -0,0 +1,987 @@
+//! Mining blocks of the transactions of the mempool, in a thread controlled through a `Handle`.
+//! The nonces are searched by the threads of the mining pool, each in its own range. Solved
+//! blocks are sent to the miner worker, which inserts and announces them. The block template is
+//! rebuilt when the tip changes, and refreshed from time to time as transactions arrive.
+
+pub mod worker;
+
+use log::info;
+use serde::{Serialize, Deserialize};
+use crossbeam::channel::{never, select, unbounded, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use rand::{FromEntropy, Rng, SeedableRng};
+use rand::rngs::StdRng;
+use std::collections::{HashSet, VecDeque};
+use std::time;
+use std::thread;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::blockchain::{Blockchain, ChainEvent};
+use crate::clock::{Clock, SystemClock};
+use crate::block::{self, Block, Header};
+use crate::mempool::{Mempool, MempoolEvent};
+use crate::transaction::{Amount, Transaction, TxOutput, BLOCK_REWARD};
+use crate::crypto::merkle::{self, MerkleTree};
+use crate::crypto::hash::{H256, Hashable};
+use crate::runtime::ThreadPool;
+use crate::validation::MAX_BLOCK_WEIGHT;
+
+/// Number of nonces tried by each thread between two checks of the control signals and of the
+/// block template
+const NONCES_PER_BATCH: u32 = 1000;
+
+/// Shortest time between two refreshes of the block template with the new transactions of the
+/// mempool. A new tip always rebuilds it.
+const TEMPLATE_REFRESH_INTERVAL: time::Duration = time::Duration::from_secs(5);
+
+/// Longest time waited for a solved block to become the tip before mining the next one
+const SUBMIT_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+/// Time between the timestamps of a block generated on demand and its parent, see
+/// `Handle::generate_block`
+const GENERATED_BLOCK_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+/// Number of the last templates handed to external miners whose solutions are accepted
+const MAX_EXTERNAL_TEMPLATES: usize = 16;
+
+/// Longest tag of the coinbase, see `CoinbaseConfig`
+pub const MAX_COINBASE_TAG: usize = 64;
+
+/// Shortest time the hash rate is measured over
+const HASH_RATE_WINDOW: time::Duration = time::Duration::from_secs(1);
+
+enum ControlSignal {
+    Start(u64), // the number is the mean interval between block generation, in microseconds
+    Step(u64),
+    Pause,
+    Coinbase(CoinbaseConfig),
+    Exit,
+}
+
+enum OperatingState {
+    Paused,
+    Run(u64),
+    /// Mining the given number of blocks before pausing
+    Step(u64),
+    ShutDown,
+}
+
+/// What the miner is doing, see `MinerStatus`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MinerState {
+    Paused,
+    Running,
+    /// Mining `remaining` more blocks before pausing
+    Stepping { remaining: u64 },
+    ShutDown,
+}
+
+/// Progress of the miner, see `Handle::status`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MinerStatus {
+    pub state: MinerState,
+    /// Parent of the block being mined, once a template was built
+    pub tip: Option<H256>,
+    /// Number of nonces tried since the miner was created
+    pub attempts: u64,
+    /// Nonces tried per second, measured over the last rounds
+    pub hash_rate: f64,
+    pub blocks_mined: u64,
+    /// Blocks mined that left the longest chain, or were not on it once submitted
+    pub orphaned: u64,
+}
+
+/// What the coinbase of the mined blocks pays to, and the tag it carries
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CoinbaseConfig {
+    /// The output of the coinbase, whose value the miner sets to the reward and the fees
+    pub payout: TxOutput,
+    /// Arbitrary data put in the input of the coinbase, of at most `MAX_COINBASE_TAG` bytes
+    pub tag: Vec<u8>,
+}
+
+impl Default for CoinbaseConfig {
+    fn default() -> Self {
+        CoinbaseConfig {
+            payout: TxOutput::new(0, H256::default()),
+            tag: Vec::new(),
+        }
+    }
+}
+
+/// Reasons for a coinbase configuration to be refused, see `Handle::set_coinbase`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CoinbaseError {
+    /// The tag has more than `MAX_COINBASE_TAG` bytes
+    TagTooLong(usize),
+}
+
+impl std::fmt::Display for CoinbaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CoinbaseError::TagTooLong(len) => write!(f, "tag of {} bytes, at most {} allowed", len, MAX_COINBASE_TAG),
+        }
+    }
+}
+
+/// A block to mine by an external miner, see `Handle::block_template`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockTemplate {
+    /// The header to solve, by changing its nonce
+    pub header: Header,
+    pub height: u32,
+    /// The hash of the header must not be above the target
+    pub target: H256,
+    /// Value of the coinbase: the reward and the fees
+    pub coinbase_value: Amount,
+    /// Ids of the transactions of the block, the coinbase first
+    pub transactions: Vec<String>,
+}
+
+pub struct Context {
+    /// Channel for receiving control signal
+    control_chan: Receiver<ControlSignal>,
+    operating_state: OperatingState,
+    /// Channel for sending the solved blocks to the worker
+    finished_block_chan: Sender<Block>,
+    blockchain: Arc<RwLock<Blockchain>>,
+    mempool: Arc<RwLock<Mempool>>,
+    /// Threads searching the nonces
+    pool: ThreadPool,
+    /// Number of batches of nonces tried by each thread, see `batch_start`
+    batch: u64,
+    coinbase: CoinbaseConfig,
+    /// The block being mined, and when it was built
+    template: Option<(Arc<Template>, time::Instant)>,
+    chain_events: Receiver<ChainEvent>,
+    mempool_events: Receiver<MempoolEvent>,
+    /// Whether a new tip was reported since the template was built
+    tip_changed: bool,
+    /// Whether the mempool changed since the template was built
+    mempool_changed: bool,
+    /// Blocks mined that are on the longest chain, counted as orphaned if they leave it
+    connected: HashSet<H256>,
+    status: Arc<RwLock<MinerStatus>>,
+    /// Number of nonces tried, counted by the mining threads
+    attempts: Arc<AtomicU64>,
+    /// Start of the current hash rate measure, and the number of nonces tried then
+    rate_window: (time::Instant, u64),
+    /// Source of the timestamps of the mined blocks
+    clock: Arc<dyn Clock>,
+    /// Source of the intervals waited between blocks when throttled
+    rng: StdRng,
+}
+
+#[derive(Clone)]
+pub struct Handle {
+    /// Channel for sending signal to the miner thread
+    control_chan: Sender<ControlSignal>,
+    status: Arc<RwLock<MinerStatus>>,
+    attempts: Arc<AtomicU64>,
+    blockchain: Arc<RwLock<Blockchain>>,
+    mempool: Arc<RwLock<Mempool>>,
+    /// The coinbase of the templates of external miners
+    coinbase: Arc<RwLock<CoinbaseConfig>>,
+    /// The blocks of the last templates handed to external miners, oldest first
+    templates: Arc<Mutex<VecDeque<Block>>>,
+}
+
+/// Create a miner searching nonces with the threads of `pool`, paused until started through its
+/// handle, and the channel receiving the blocks it solves, see `worker`
+pub fn new(
+    blockchain: &Arc<RwLock<Blockchain>>, mempool: &Arc<RwLock<Mempool>>, pool: &ThreadPool
+) -> (Context, Handle, Receiver<Block>) {
+    let (signal_chan_sender, signal_chan_receiver) = unbounded();
+    let (finished_block_sender, finished_block_receiver) = unbounded();
+    let status = Arc::new(RwLock::new(MinerStatus {
+        state: MinerState::Paused,
+        tip: None,
+        attempts: 0,
+        hash_rate: 0.0,
+        blocks_mined: 0,
+        orphaned: 0,
+    }));
+    let attempts = Arc::new(AtomicU64::new(0));
+    let chain_events = blockchain.write().unwrap().subscribe();
+    let mempool_events = mempool.write().unwrap().subscribe();
+
+    let ctx = Context {
+        control_chan: signal_chan_receiver,
+        operating_state: OperatingState::Paused,
+        finished_block_chan: finished_block_sender,
+        blockchain: Arc::clone(&blockchain),
+        mempool: Arc::clone(&mempool),
+        pool: pool.clone(),
+        batch: 0,
+        coinbase: CoinbaseConfig::default(),
+        template: None,
+        chain_events,
+        mempool_events,
+        tip_changed: false,
+        mempool_changed: false,
+        connected: HashSet::new(),
+        status: Arc::clone(&status),
+        attempts: Arc::clone(&attempts),
+        rate_window: (time::Instant::now(), 0),
+        clock: Arc::new(SystemClock),
+        rng: StdRng::from_entropy(),
+    };
+
+    let handle = Handle {
+        control_chan: signal_chan_sender,
+        status,
+        attempts,
+        blockchain: Arc::clone(&blockchain),
+        mempool: Arc::clone(&mempool),
+        coinbase: Arc::new(RwLock::new(CoinbaseConfig::default())),
+        templates: Arc::new(Mutex::new(VecDeque::new())),
+    };
+
+    (ctx, handle, finished_block_receiver)
+}
+
+impl Handle {
+    pub fn exit(&self) {
+        self.control_chan.send(ControlSignal::Exit).unwrap();
+    }
+
+    /// Mine continuously, waiting a random interval of mean `lambda` microseconds after each
+    /// block, see `throttle_interval`
+    pub fn start(&self, lambda: u64) {
+        self.control_chan
+            .send(ControlSignal::Start(lambda))
+            .unwrap();
+    }
+
+    /// Mine exactly `blocks` blocks, then pause
+    pub fn step(&self, blocks: u64) {
+        self.control_chan.send(ControlSignal::Step(blocks)).unwrap();
+    }
+
+    /// Stop mining until started again
+    pub fn pause(&self) {
+        self.control_chan.send(ControlSignal::Pause).unwrap();
+    }
+
+    /// Pay the next blocks as `config` says, from the next template on
+    pub fn set_coinbase(&self, config: CoinbaseConfig) -> Result<(), CoinbaseError> {
+        if config.tag.len() > MAX_COINBASE_TAG {
+            return Err(CoinbaseError::TagTooLong(config.tag.len()));
+        }
+        *self.coinbase.write().unwrap() = config.clone();
+        self.control_chan.send(ControlSignal::Coinbase(config)).unwrap();
+        return Ok(());
+    }
+
+    /// A template of a block on the tip for an external miner, whose solution is accepted by
+    /// `solved_block` until `MAX_EXTERNAL_TEMPLATES` newer ones were handed out
+    pub fn block_template(&self) -> BlockTemplate {
+        let template = {
+            let blockchain = self.blockchain.read().unwrap();
+            let mempool = self.mempool.read().unwrap();
+            Template::new(&blockchain, &mempool, &self.coinbase.read().unwrap())
+        };
+        // a random extra nonce, so that external miners do not solve the same block
+        let block = template.block(rand::random(), time::SystemTime::now());
+        let info = BlockTemplate {
+            header: block.get_header().clone(),
+            height: template.height,
+            target: block.get_difficulty(),
+            coinbase_value: template.coinbase_value,
+            transactions: block.get_transactions().iter().map(|t| t.txid().to_string()).collect(),
+        };
+        let mut templates = self.templates.lock().unwrap();
+        if templates.len() == MAX_EXTERNAL_TEMPLATES {
+            templates.pop_front();
+        }
+        templates.push_back(block);
+        return info;
+    }
+
+    /// A solved block on the tip, mined on the calling thread for regtest chains, where the first
+    /// nonce solves it. Its extra nonce and first nonce are 0, and its timestamp is
+    /// `GENERATED_BLOCK_INTERVAL` after its parent's, so the same chain is generated every time.
+    pub fn generate_block(&self) -> Block {
+        let template = {
+            let blockchain = self.blockchain.read().unwrap();
+            let mempool = self.mempool.read().unwrap();
+            Template::new(&blockchain, &mempool, &self.coinbase.read().unwrap())
+        };
+        let timestamp = template.parent_time + GENERATED_BLOCK_INTERVAL;
+        let mut header = template.header(0, timestamp);
+        let mut nonce: u32 = 0;
+        loop {
+            header.set_nonce(nonce);
+            if header.hash() <= header.get_difficulty() {
+                return template.block(0, timestamp).with_header(header);
+            }
+            nonce = nonce.wrapping_add(1);
+        }
+    }
+
+    /// The block of a template handed out by `block_template`, with the header solved by the
+    /// external miner, or None if the header matches none of the templates
+    pub fn solved_block(&self, header: Header) -> Option<Block> {
+        let templates = self.templates.lock().unwrap();
+        let block = templates.iter().find(|b| {
+            b.get_header().get_merkle_root() == header.get_merkle_root() && b.get_parent() == header.get_parent()
+        })?;
+        return Some(block.with_header(header));
+    }
+
+    /// The progress of the miner, with the nonces tried up to now
+    pub fn status(&self) -> MinerStatus {
+        let mut status = self.status.read().unwrap().clone();
+        status.attempts = self.attempts.load(Ordering::Relaxed);
+        return status;
+    }
+}
+
+/// A block to mine on the tip, shared by the mining threads, which each put their own extra
+/// nonce in the coinbase
+pub struct Template {
+    parent: H256,
+    parent_time: time::SystemTime,
+    height: u32,
+    difficulty: H256,
+    /// Value of the coinbase: the reward and the fees
+    coinbase_value: Amount,
+    coinbase: CoinbaseConfig,
+    /// The most profitable transactions of the mempool, following the coinbase
+    transactions: Vec<Transaction>,
+    /// Merkle proof of the coinbase, which does not depend on it, so that the Merkle root of each
+    /// extra nonce only hashes the path of the coinbase
+    coinbase_proof: Vec<H256>,
+    /// The witness commitment, which does not depend on the coinbase either, carried by an output
+    /// of the coinbase
+    witness_commitment: H256,
+}
+
+impl Template {
+    /// The template of a child of the tip paid as `coinbase` says, with the most profitable
+    /// transactions of the mempool fitting in the weight left by the coinbase. The blockchain is
+    /// locked before the mempool.
+    pub fn new(blockchain: &Blockchain, mempool: &Mempool, coinbase: &CoinbaseConfig) -> Self {
+        let parent = blockchain.tip();
+        let mut template = Template {
+            parent,
+            parent_time: blockchain.header_tree().get(&parent).unwrap().get_timestamp(),
+            height: blockchain.tip_height() + 1,
+            difficulty: blockchain.next_difficulty(&parent),
+            coinbase_value: BLOCK_REWARD,
+            coinbase: coinbase.clone(),
+            transactions: Vec::new(),
+            coinbase_proof: Vec::new(),
+            witness_commitment: H256::default(),
+        };
+        // the weight of the coinbase does not depend on its value, extra nonce or commitment
+        let transactions = mempool.select((MAX_BLOCK_WEIGHT - template.coinbase(0).weight()) / 4);
+        let fees: Amount = transactions.iter().filter_map(|t| mempool.get(&t.txid())).map(|e| e.get_fee()).sum();
+        template.coinbase_value = BLOCK_REWARD.saturating_add(fees);
+        let all: Vec<Transaction> = Some(template.coinbase(0)).into_iter().chain(transactions.iter().cloned()).collect();
+        template.coinbase_proof = MerkleTree::new(&all).proof(0);
+        template.witness_commitment = block::witness_commitment(&all);
+        template.transactions = transactions;
+        return template;
+    }
+
+    /// The coinbase of the template with `extra_nonce`
+    fn coinbase(&self, extra_nonce: u64) -> Transaction {
+        let mut payout = self.coinbase.payout.clone();
+        payout.value = self.coinbase_value;
+        let mut coinbase = Transaction::coinbase_with_extra_nonce(self.height, extra_nonce, &self.coinbase.tag, payout);
+        let outputs = [coinbase.get_outputs(), &[block::witness_commitment_output(&self.witness_commitment)]].concat();
+        coinbase.set_outputs(outputs);
+        return coinbase;
+    }
+
+    pub fn get_parent(&self) -> H256 {
+        return self.parent;
+    }
+
+    /// The Merkle root of the block with `coinbase`, hashing only its path
+    fn merkle_root(&self, coinbase: &Transaction) -> H256 {
+        return merkle::root_from_proof(&coinbase.hash(), &self.coinbase_proof, 0, self.transactions.len() + 1);
+    }
+
+    /// The header of the block of the template with `extra_nonce` in its coinbase, stamped with
+    /// `timestamp`, without assembling the block
+    pub fn header(&self, extra_nonce: u64, timestamp: time::SystemTime) -> Header {
+        let merkle_root = self.merkle_root(&self.coinbase(extra_nonce));
+        return Header::new(self.parent, self.difficulty, merkle_root, 0, timestamp);
+    }
+
+    /// The block of the template with `extra_nonce` in its coinbase, stamped with `timestamp`
+    pub fn block(&self, extra_nonce: u64, timestamp: time::SystemTime) -> Block {
+        let coinbase = self.coinbase(extra_nonce);
+        let merkle_root = self.merkle_root(&coinbase);
+        let transactions: Vec<Transaction> = Some(coinbase).into_iter().chain(self.transactions.iter().cloned()).collect();
+        return Block::new_at(self.parent, self.difficulty, transactions, merkle_root, 0, timestamp);
+    }
+}
+
+/// The extra nonce and the first nonce of the `batch`-th batch of nonces tried by thread `worker`
+/// out of `workers`. The nonces are split in disjoint ranges, one per thread, and a thread moves
+/// to the next extra nonce once it tried all the nonces of its range.
+fn batch_start(worker: u32, workers: u32, batch: u64) -> (u64, u32) {
+    let range = u32::MAX / workers;
+    let batches_per_range = std::cmp::max(range / NONCES_PER_BATCH, 1) as u64;
+    let first = worker * range + (batch % batches_per_range) as u32 * NONCES_PER_BATCH;
+    return (batch / batches_per_range, first);
+}
+
+/// A wait exponentially distributed with a mean of `mean` microseconds, so that the blocks mined
+/// in continuous mode arrive as a Poisson process, as they would from a whole network
+fn throttle_interval<R: Rng>(rng: &mut R, mean: u64) -> time::Duration {
+    let u: f64 = rng.gen_range(std::f64::EPSILON, 1.0);
+    return time::Duration::from_micros((-u.ln() * mean as f64) as u64);
+}
+
+/// Try a batch of nonces on the header of `template` with `extra_nonce`, until one solves it or
+/// another thread sets `found`, counting them in `attempts`. The block is only assembled once
+/// solved.
+fn grind(
+    template: &Template, extra_nonce: u64, first: u32, timestamp: time::SystemTime, found: &AtomicBool, attempts: &AtomicU64
+) -> Option<Block> {
+    let mut header = template.header(extra_nonce, timestamp);
+    let difficulty = header.get_difficulty();
+    let mut solved: Option<Block> = None;
+    let mut tried = 0;
+    for nonce in first..first.saturating_add(NONCES_PER_BATCH) {
+        if found.load(Ordering::Relaxed) {
+            break;
+        }
+        header.set_nonce(nonce);
+        tried += 1;
+        if header.hash() <= difficulty {
+            // another thread may have solved it at the same time
+            if !found.swap(true, Ordering::SeqCst) {
+                solved = Some(template.block(extra_nonce, timestamp).with_header(header));
+            }
+            break;
+        }
+    }
+    attempts.fetch_add(tried, Ordering::Relaxed);
+    return solved;
+}
+
+impl Context {
+    /// Stamp the mined blocks with the time of `clock` instead of the system time
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        return self;
+    }
+
+    /// Draw the throttling intervals from an RNG seeded with `seed`. With a stepping clock and at
+    /// most one mining thread, the same blocks are then mined on each run.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = StdRng::seed_from_u64(seed);
+        return self;
+    }
+
+    pub fn start(mut self) {
+        thread::Builder::new()
+            .name("miner".to_string())
+            .spawn(move || {
+                self.miner_loop();
+            })
+            .unwrap();
+        info!("Miner initialized into paused mode");
+    }
+
+    fn handle_control_signal(&mut self, signal: ControlSignal) {
+        match signal {
+            ControlSignal::Exit => {
+                info!("Miner shutting down");
+                self.operating_state = OperatingState::ShutDown;
+            }
+            ControlSignal::Start(i) => {
+                info!("Miner starting in continuous mode with lambda {}", i);
+                self.operating_state = OperatingState::Run(i);
+            }
+            ControlSignal::Step(0) => {
+                self.operating_state = OperatingState::Paused;
+            }
+            ControlSignal::Step(n) => {
+                info!("Miner mining {} blocks", n);
+                self.operating_state = OperatingState::Step(n);
+            }
+            ControlSignal::Pause => {
+                info!("Miner paused");
+                self.operating_state = OperatingState::Paused;
+            }
+            ControlSignal::Coinbase(config) => {
+                info!("Miner paying to {}", config.payout.recipient);
+                self.coinbase = config;
+                self.template = None;
+            }
+        }
+        self.update_state();
+    }
+
+    /// Publish the operating state in the status
+    fn update_state(&self) {
+        let state = match self.operating_state {
+            OperatingState::Paused => MinerState::Paused,
+            OperatingState::Run(_) => MinerState::Running,
+            OperatingState::Step(remaining) => MinerState::Stepping { remaining },
+            OperatingState::ShutDown => MinerState::ShutDown,
+        };
+        self.status.write().unwrap().state = state;
+    }
+
+    /// Publish the progress of a round of mining on `tip` in the status
+    fn update_progress(&mut self, tip: H256, mined: bool) {
+        let attempts = self.attempts.load(Ordering::Relaxed);
+        let mut status = self.status.write().unwrap();
+        status.tip = Some(tip);
+        status.attempts = attempts;
+        if mined {
+            status.blocks_mined += 1;
+        }
+        let (start, start_attempts) = self.rate_window;
+        let elapsed = start.elapsed();
+        if elapsed >= HASH_RATE_WINDOW {
+            status.hash_rate = (attempts - start_attempts) as f64 / elapsed.as_secs_f64();
+            self.rate_window = (time::Instant::now(), attempts);
+        }
+    }
+
+    /// The template to mine on. It is rebuilt when a new tip was reported, and refreshed with the
+    /// new transactions of the mempool once it is `TEMPLATE_REFRESH_INTERVAL` old.
+    fn template(&mut self) -> Arc<Template> {
+        let events: Vec<ChainEvent> = self.chain_events.try_iter().collect();
+        for event in events {
+            self.handle_event(Ok(event));
+        }
+        self.mempool_changed |= self.mempool_events.try_iter().count() > 0;
+        if let Some((template, built)) = &self.template {
+            if !self.tip_changed && !(self.mempool_changed && built.elapsed() >= TEMPLATE_REFRESH_INTERVAL) {
+                return Arc::clone(template);
+            }
+        }
+        // build the template under read locks, so mining does not block the other threads
+        let template = {
+            let blockchain = self.blockchain.read().unwrap();
+            let mempool = self.mempool.read().unwrap();
+            Arc::new(Template::new(&blockchain, &mempool, &self.coinbase))
+        };
+        self.template = Some((Arc::clone(&template), time::Instant::now()));
+        self.tip_changed = false;
+        self.mempool_changed = false;
+        return template;
+    }
+
+    /// Note an event of the blockchain received on `chain_events`, which is no longer listened
+    /// to once disconnected
+    fn handle_event(&mut self, event: Result<ChainEvent, crossbeam::channel::RecvError>) {
+        match event {
+            Ok(event) => self.tip_changed |= self.handle_chain_event(&event),
+            Err(_) => self.chain_events = never(),
+        }
+    }
+
+    /// Wait for a control signal while paused, handling the events of the blockchain and the
+    /// mempool meanwhile so that their channels do not grow
+    fn wait_paused(&mut self) {
+        let (control_chan, chain_events, mempool_events) =
+            (self.control_chan.clone(), self.chain_events.clone(), self.mempool_events.clone());
+        select! {
+            recv(control_chan) -> signal => self.handle_control_signal(signal.unwrap()),
+            recv(chain_events) -> event => self.handle_event(event),
+            recv(mempool_events) -> event => match event {
+                Ok(_) => self.mempool_changed = true,
+                Err(_) => self.mempool_events = never(),
+            },
+        }
+    }
+
+    /// Count the blocks mined leaving the longest chain as orphaned. Returns whether the event
+    /// reports a new tip.
+    fn handle_chain_event(&mut self, event: &ChainEvent) -> bool {
+        match event {
+            ChainEvent::Disconnected(hash) if self.connected.remove(hash) => {
+                self.status.write().unwrap().orphaned += 1;
+            }
+            ChainEvent::NewTip(_) => return true,
+            _ => {}
+        }
+        return false;
+    }
+
+    /// Wait until the solved block `hash` becomes the tip, or the worker had enough time to drop
+    /// it, so that the next template is not built on its parent again. The block is counted as
+    /// orphaned if it is not on the longest chain then.
+    fn await_tip(&mut self, hash: H256) {
+        self.template = None;
+        let deadline = time::Instant::now() + SUBMIT_TIMEOUT;
+        while let Some(timeout) = deadline.checked_duration_since(time::Instant::now()) {
+            match self.chain_events.recv_timeout(timeout) {
+                Ok(event) => {
+                    self.handle_chain_event(&event);
+                    if event == ChainEvent::NewTip(hash) {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        if self.blockchain.read().unwrap().is_in_longest_chain(&hash) {
+            self.connected.insert(hash);
+        } else {
+            self.status.write().unwrap().orphaned += 1;
+        }
+    }
+
+    /// Try a batch of nonces on `template` in each thread of the pool, or in this thread if the
+    /// pool has none. The first thread to solve the block stops the others.
+    fn mine(&mut self, template: &Arc<Template>) -> Option<Block> {
+        let batch = self.batch;
+        self.batch += 1;
+        let found = Arc::new(AtomicBool::new(false));
+        let workers = self.pool.size() as u32;
+        let timestamp = self.clock.now();
+        if workers == 0 {
+            let (extra_nonce, first) = batch_start(0, 1, batch);
+            return grind(template, extra_nonce, first, timestamp, &found, &self.attempts);
+        }
+        let (sender, receiver) = unbounded();
+        for worker in 0..workers {
+            let (template, found, sender) = (Arc::clone(template), Arc::clone(&found), sender.clone());
+            let attempts = Arc::clone(&self.attempts);
+            self.pool.execute(move || {
+                let (extra_nonce, first) = batch_start(worker, workers, batch);
+                let _ = sender.send(grind(&template, extra_nonce, first, timestamp, &found, &attempts));
+            });
+        }
+        drop(sender);
+        return receiver.iter().flatten().next();
+    }
+
+    fn miner_loop(&mut self) {
+        let mut num_mined = 0;
+        loop {
+            // check and react to control signals
+            match self.operating_state {
+                OperatingState::Paused => {
+                    self.wait_paused();
+                    continue;
+                }
+                OperatingState::ShutDown => {
+                    return;
+                }
+                _ => match self.control_chan.try_recv() {
+                    Ok(signal) => {
+                        self.handle_control_signal(signal);
+                    }
+                    Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
+                },
+            }
+            if !matches!(self.operating_state, OperatingState::Run(_) | OperatingState::Step(_)) {
+                continue;
+            }
+
+            let template = self.template();
+            let found = self.mine(&template);
+            self.update_progress(template.get_parent(), found.is_some());
+            let block = match found {
+                Some(block) => block,
+                None => continue,
+            };
+
+            num_mined += 1;
+            let hash = block.hash();
+            info!("Successfully mined block #{}: {}", num_mined, hash);
+            if self.finished_block_chan.send(block).is_err() {
+                info!("Miner worker gone, shutting down");
+                return;
+            }
+            self.await_tip(hash);
+
+            match self.operating_state {
+                OperatingState::Run(i) if i != 0 => {
+                    // control signals end the wait early
+                    let interval = throttle_interval(&mut self.rng, i);
+                    match self.control_chan.recv_timeout(interval) {
+                        Ok(signal) => self.handle_control_signal(signal),
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => panic!("Miner control channel detached"),
+                    }
+                }
+                OperatingState::Step(remaining) => {
+                    self.operating_state = match remaining - 1 {
+                        0 => OperatingState::Paused,
+                        left => OperatingState::Step(left),
+                    };
+                    self.update_state();
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::mempool::tests::{funded, spend};
+    use crate::block::test::generate_random_block;
+    use crate::blockchain::{GENESIS_TIME, RETARGET_INTERVAL};
+    use crate::clock::SteppingClock;
+    use crate::crypto::keys::KeyPair;
+    use crate::utxo::OutPoint;
+
+    #[test]
+    fn template() {
+        let blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        let key = KeyPair::random();
+        let (utxo, funding) = funded(&key, 1, 100_000);
+        let transaction = spend(&key, &[OutPoint::new(funding.txid(), 0)], 90_000);
+        mempool.accept(transaction.clone(), &utxo).unwrap();
+
+        let coinbase = CoinbaseConfig {
+            payout: TxOutput::new(0, key.public_key().address()),
+            tag: b"/test/".to_vec(),
+        };
+        let template = Template::new(&blockchain, &mempool, &coinbase);
+        let block = template.block(0, time::SystemTime::now());
+        assert_eq!(block.get_parent(), blockchain.tip());
+        let transactions = block.get_transactions();
+        assert_eq!(transactions.len(), 2);
+        assert!(transactions[0].is_coinbase());
+        assert_eq!(transactions[0].output_value(), Some(BLOCK_REWARD + 10_000));
+        assert_eq!(transactions[0].get_outputs()[0].recipient, key.public_key().address());
+        assert!(transactions[0].get_inputs()[0].script.ends_with(b"/test/"));
+        assert_eq!(transactions[1].txid(), transaction.txid());
+        assert!(block.verify_witness_commitment());
+        assert!(block.weight() <= MAX_BLOCK_WEIGHT);
+        assert_eq!(block.get_header().get_merkle_root(), MerkleTree::new(transactions).root());
+        // each extra nonce gives another Merkle root
+        let other = template.block(1, time::SystemTime::now());
+        assert_ne!(other.get_header().get_merkle_root(), block.get_header().get_merkle_root());
+        assert_eq!(other.get_header().get_merkle_root(), MerkleTree::new(other.get_transactions()).root());
+        assert!(other.verify_witness_commitment());
+        // the header of an extra nonce is that of its block
+        let timestamp = other.get_timestamp();
+        assert_eq!(template.header(1, timestamp).hash(), other.hash());
+        assert_ne!(template.header(2, timestamp).hash(), other.hash());
+    }
+
+    #[test]
+    fn template_refresh() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let mempool = Arc::new(RwLock::new(Mempool::new()));
+        let (mut ctx, _handle, _blocks) = new(&blockchain, &mempool, &ThreadPool::new("mining", 0));
+        let template = ctx.template();
+        assert!(Arc::ptr_eq(&template, &ctx.template()));
+
+        // new transactions are only picked up once the template is old enough
+        let key = KeyPair::random();
+        let (utxo, funding) = funded(&key, 1, 100_000);
+        let transaction = spend(&key, &[OutPoint::new(funding.txid(), 0)], 90_000);
+        mempool.write().unwrap().accept(transaction.clone(), &utxo).unwrap();
+        assert!(Arc::ptr_eq(&template, &ctx.template()));
+        if let Some((_, built)) = ctx.template.as_mut() {
+            *built -= TEMPLATE_REFRESH_INTERVAL;
+        }
+        let refreshed = ctx.template();
+        assert_eq!(refreshed.transactions.len(), 1);
+        assert!(Arc::ptr_eq(&refreshed, &ctx.template()));
+
+        // a new tip rebuilds it at once
+        let found = AtomicBool::new(false);
+        let attempts = AtomicU64::new(0);
+        let block = (0..).find_map(|batch| grind(&template, 0, batch * NONCES_PER_BATCH, time::SystemTime::now(), &found, &attempts)).unwrap();
+        blockchain.write().unwrap().insert(&block).unwrap();
+        let rebuilt = ctx.template();
+        assert_eq!(rebuilt.get_parent(), block.hash());
+    }
+
+    #[test]
+    fn nonce_ranges() {
+        let workers = 4;
+        let range = u32::MAX / workers;
+        let starts: Vec<(u64, u32)> = (0..workers).map(|w| batch_start(w, workers, 0)).collect();
+        assert_eq!(starts, vec![(0, 0), (0, range), (0, 2 * range), (0, 3 * range)]);
+        assert_eq!(batch_start(1, workers, 1), (0, range + NONCES_PER_BATCH));
+        // once its range is exhausted, a thread starts it over with the next extra nonce
+        let batches_per_range = (range / NONCES_PER_BATCH) as u64;
+        let (_, last) = batch_start(3, workers, batches_per_range - 1);
+        assert!(last + NONCES_PER_BATCH <= 4 * range);
+        assert_eq!(batch_start(3, workers, batches_per_range), (1, 3 * range));
+        assert_eq!(batch_start(0, 1, 5), (0, 5 * NONCES_PER_BATCH));
+    }
+
+    #[test]
+    fn orphaned_blocks() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let mempool = Arc::new(RwLock::new(Mempool::new()));
+        let (mut ctx, handle, _blocks) = new(&blockchain, &mempool, &ThreadPool::new("mining", 0));
+        let genesis = blockchain.read().unwrap().tip();
+        let template = ctx.template();
+        let (found, attempts) = (AtomicBool::new(false), AtomicU64::new(0));
+        let block = (0..).find_map(|batch| grind(&template, 0, batch * NONCES_PER_BATCH, time::SystemTime::now(), &found, &attempts)).unwrap();
+        blockchain.write().unwrap().insert(&block).unwrap();
+        ctx.await_tip(block.hash());
+        assert_eq!(handle.status().orphaned, 0);
+
+        // a longer branch replaces the mined block
+        let side1 = generate_random_block(&genesis);
+        let side2 = generate_random_block(&side1.hash());
+        blockchain.write().unwrap().insert(&side1).unwrap();
+        blockchain.write().unwrap().insert(&side2).unwrap();
+        ctx.template();
+        assert_eq!(handle.status().orphaned, 1);
+
+        // so does a block mined on a stale parent
+        let stale = template.block(1, time::SystemTime::now());
+        ctx.await_tip(stale.hash());
+        assert_eq!(handle.status().orphaned, 2);
+    }
+
+    #[test]
+    fn external_templates() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let mempool = Arc::new(RwLock::new(Mempool::new()));
+        let (_ctx, handle, _blocks) = new(&blockchain, &mempool, &ThreadPool::new("mining", 0));
+        let template = handle.block_template();
+        assert_eq!(template.header.get_parent(), blockchain.read().unwrap().tip());
+        assert_eq!(template.transactions.len(), 1);
+        let mut header = template.header.clone();
+        while header.hash() > template.target {
+            header.set_nonce(header.get_nonce().wrapping_add(1));
+        }
+        let block = handle.solved_block(header.clone()).unwrap();
+        assert_eq!(block.hash(), header.hash());
+        blockchain.write().unwrap().validate(&block).unwrap();
+
+        // only the last templates are remembered
+        for _ in 0..MAX_EXTERNAL_TEMPLATES {
+            handle.block_template();
+        }
+        assert!(handle.solved_block(header).is_none());
+    }
+
+    #[test]
+    fn generated_blocks() {
+        let generate = |n: usize| -> Vec<H256> {
+            let blockchain = Arc::new(RwLock::new(Blockchain::regtest()));
+            let mempool = Arc::new(RwLock::new(Mempool::new()));
+            let (_ctx, handle, _blocks) = new(&blockchain, &mempool, &ThreadPool::new("mining", 0));
+            (0..n)
+                .map(|_| {
+                    let block = handle.generate_block();
+                    let mut blockchain = blockchain.write().unwrap();
+                    blockchain.validate(&block).unwrap();
+                    blockchain.insert(&block).unwrap();
+                    assert!(blockchain.is_regtest());
+                    block.hash()
+                })
+                .collect()
+        };
+        assert!(!Blockchain::new().is_regtest());
+        // past the first difficulty adjustment, which regtest chains skip
+        let hashes = generate(RETARGET_INTERVAL as usize + 2);
+        assert_eq!(generate(RETARGET_INTERVAL as usize + 2), hashes);
+    }
+
+    #[test]
+    fn deterministic_mining() {
+        let mine = || -> Vec<H256> {
+            let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+            let mempool = Arc::new(RwLock::new(Mempool::new()));
+            let (ctx, handle, blocks) = new(&blockchain, &mempool, &ThreadPool::new("mining", 0));
+            let start = time::UNIX_EPOCH + GENESIS_TIME + time::Duration::from_secs(3600);
+            let clock = Arc::new(SteppingClock::new(start, time::Duration::from_secs(1)));
+            ctx.with_seed(7).with_clock(clock).start();
+            handle.step(3);
+            let hashes = (0..3)
+                .map(|_| {
+                    let block = blocks.recv_timeout(time::Duration::from_secs(30)).unwrap();
+                    blockchain.write().unwrap().insert(&block).unwrap();
+                    block.hash()
+                })
+                .collect();
+            handle.exit();
+            hashes
+        };
+        let hashes = mine();
+        assert_eq!(mine(), hashes);
+    }
+
+    #[test]
+    fn throttling() {
+        let mut rng = rand::thread_rng();
+        let mean = 1_000_000;
+        let samples: Vec<time::Duration> = (0..10_000).map(|_| throttle_interval(&mut rng, mean)).collect();
+        let average = samples.iter().sum::<time::Duration>().as_micros() as f64 / samples.len() as f64;
+        assert!((average - mean as f64).abs() < 0.05 * mean as f64);
+        // about 1/e of the waits are longer than the mean
+        let longer = samples.iter().filter(|s| s.as_micros() > mean as u128).count() as f64 / samples.len() as f64;
+        assert!((longer - (-1.0f64).exp()).abs() < 0.03);
+        assert_eq!(throttle_interval(&mut rng, 0), time::Duration::from_micros(0));
+    }
+
+    #[test]
+    fn drains_events_while_paused() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let mempool = Arc::new(RwLock::new(Mempool::new()));
+        let (mut ctx, _handle, _blocks) = new(&blockchain, &mempool, &ThreadPool::new("mining", 0));
+        let block = generate_random_block(&blockchain.read().unwrap().tip());
+        blockchain.write().unwrap().insert(&block).unwrap();
+        assert!(!ctx.chain_events.is_empty());
+        while !ctx.chain_events.is_empty() {
+            ctx.wait_paused();
+        }
+        assert!(ctx.tip_changed);
+        assert!(matches!(ctx.operating_state, OperatingState::Paused));
+    }
+
+    #[test]
+    fn mines_on_tip() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let mempool = Arc::new(RwLock::new(Mempool::new()));
+        let pool = ThreadPool::new("mining", 3);
+        let (ctx, handle, blocks) = new(&blockchain, &mempool, &pool);
+        ctx.start();
+        handle.start(0);
+        let block = blocks.recv_timeout(time::Duration::from_secs(30)).unwrap();
+        handle.exit();
+        assert_eq!(block.get_parent(), blockchain.read().unwrap().tip());
+        assert!(block.hash() <= block.get_difficulty());
+        blockchain.write().unwrap().validate(&block).unwrap();
+    }
+
+    #[test]
+    fn step_and_status() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let mempool = Arc::new(RwLock::new(Mempool::new()));
+        let pool = ThreadPool::new("mining", 2);
+        let (ctx, handle, blocks) = new(&blockchain, &mempool, &pool);
+        assert_eq!(handle.status().state, MinerState::Paused);
+        let payout = TxOutput::new(0, H256::from([7u8; 32]));
+        let tag = vec![0u8; MAX_COINBASE_TAG + 1];
+        assert_eq!(handle.set_coinbase(CoinbaseConfig { payout: payout.clone(), tag }), Err(CoinbaseError::TagTooLong(MAX_COINBASE_TAG + 1)));
+        handle.set_coinbase(CoinbaseConfig { payout, tag: Vec::new() }).unwrap();
+        ctx.start();
+        handle.step(2);
+        for _ in 0..2 {
+            let block = blocks.recv_timeout(time::Duration::from_secs(30)).unwrap();
+            assert_eq!(block.get_transactions()[0].get_outputs()[0].recipient, H256::from([7u8; 32]));
+        }
+        // the miner pauses by itself once both blocks are mined
+        let deadline = time::Instant::now() + time::Duration::from_secs(30);
+        while handle.status().state != MinerState::Paused {
+            assert!(time::Instant::now() < deadline);
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        let status = handle.status();
+        assert_eq!(status.blocks_mined, 2);
+        assert_eq!(status.tip, Some(blockchain.read().unwrap().tip()));
+        assert!(status.attempts >= 2);
+        assert!(blocks.try_recv().is_err());
+        handle.exit();
+    }
+}
@@ -0,0 +1,91 @@
+use crossbeam::channel::Receiver;
+use log::{info, warn};
+use std::thread;
+use std::sync::{Arc, RwLock};
+
+use crate::network::server::Handle as ServerHandle;
+use crate::network::message::Message;
+use crate::blockchain::{Blockchain, InsertError};
+use crate::block::Block;
+use crate::crypto::hash::{H256, Hashable};
+use crate::validation::ValidationError;
+
+/// Inserts the blocks solved by the miner into the blockchain and announces them to the peers
+pub struct Context {
+    finished_block_chan: Receiver<Block>,
+    server: ServerHandle,
+    blockchain: Arc<RwLock<Blockchain>>,
+}
+
+/// Reasons for a solved block to be dropped, see `submit`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubmitError {
+    Invalid(ValidationError),
+    Insert(InsertError),
+}
+
+impl std::fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SubmitError::Invalid(e) => write!(f, "invalid block: {}", e),
+            SubmitError::Insert(e) => write!(f, "error inserting block: {}", e),
+        }
+    }
+}
+
+impl From<ValidationError> for SubmitError {
+    fn from(e: ValidationError) -> Self {
+        SubmitError::Invalid(e)
+    }
+}
+
+impl From<InsertError> for SubmitError {
+    fn from(e: InsertError) -> Self {
+        SubmitError::Insert(e)
+    }
+}
+
+/// Validate and insert a solved block, as its transactions may have been mined meanwhile, then
+/// announce it. Returns its hash.
+pub fn submit(blockchain: &Arc<RwLock<Blockchain>>, server: &ServerHandle, block: &Block) -> Result<H256, SubmitError> {
+    let hash = block.hash();
+    {
+        let mut blockchain = blockchain.write().unwrap();
+        blockchain.validate(block)?;
+        blockchain.insert(block)?;
+    }
+    server.broadcast(Message::NewBlockHashes(vec![hash]));
+    return Ok(hash);
+}
+
+pub fn new(
+    server: &ServerHandle,
+    finished_block_chan: Receiver<Block>,
+    blockchain: &Arc<RwLock<Blockchain>>,
+) -> Context {
+    Context {
+        finished_block_chan,
+        server: server.clone(),
+        blockchain: Arc::clone(blockchain),
+    }
+}
+
+impl Context {
+    pub fn start(self) {
+        thread::Builder::new()
+            .name("miner-worker".to_string())
+            .spawn(move || {
+                for block in self.finished_block_chan.iter() {
+                    self.submit(block);
+                }
+                info!("Miner stopped, miner worker shutting down");
+            })
+            .unwrap();
+    }
+
+    fn submit(&self, block: Block) {
+        if let Err(e) = submit(&self.blockchain, &self.server, &block) {
+            warn!("Dropped mined block {}: {}", block.hash(), e);
+        }
+    }
+}
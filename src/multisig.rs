@@ -0,0 +1,197 @@
+use serde::{Serialize, Deserialize};
+
+use crate::crypto::keys::{self, KeyError};
+use crate::crypto::schnorr;
+use crate::script::{check_script_signature, Script, SignatureChecker};
+use crate::transaction::SigHashType;
+
+/// Reasons for a multisig signature to be refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MultisigError {
+    /// The script is not a `Script::multisig` script
+    NotMultisig,
+    /// The key is not one of the keys of the script
+    UnknownKey,
+    BadSignature,
+    /// The sighash type does not apply to the input
+    BadSigHashType,
+    /// Fewer signatures than required were collected
+    Incomplete { have: usize, need: usize },
+    /// The signatures being merged are for another script
+    ScriptMismatch,
+    Key(KeyError),
+}
+
+impl std::fmt::Display for MultisigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MultisigError::NotMultisig => write!(f, "not a multisig script"),
+            MultisigError::UnknownKey => write!(f, "key not in the script"),
+            MultisigError::BadSignature => write!(f, "invalid signature"),
+            MultisigError::BadSigHashType => write!(f, "sighash type not applicable"),
+            MultisigError::Incomplete { have, need } => write!(f, "{} of {} signatures", have, need),
+            MultisigError::ScriptMismatch => write!(f, "signatures of another script"),
+            MultisigError::Key(e) => write!(f, "signing failed: {}", e),
+        }
+    }
+}
+
+impl From<KeyError> for MultisigError {
+    fn from(e: KeyError) -> Self {
+        MultisigError::Key(e)
+    }
+}
+
+/// The signatures collected so far for an input spending a `Script::multisig` output, or a
+/// p2sh or p2wsh output with a multisig script. Each signer adds their signature, in any order,
+/// and passes it on, e.g. serialized, until enough signatures are collected to unlock it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PartialMultisig {
+    script: Script,
+    /// The number of signatures required
+    m: usize,
+    public_keys: Vec<Vec<u8>>,
+    /// The signature of each key, if collected
+    signatures: Vec<Option<Vec<u8>>>,
+}
+
+impl PartialMultisig {
+    pub fn new(script: Script) -> Result<Self, MultisigError> {
+        let (m, public_keys) = script.multisig_keys().ok_or(MultisigError::NotMultisig)?;
+        let signatures = vec![None; public_keys.len()];
+        return Ok(PartialMultisig {
+            script,
+            m,
+            public_keys,
+            signatures,
+        });
+    }
+
+    pub fn get_script(&self) -> &Script {
+        return &self.script;
+    }
+
+    /// Add the signature by `public_key`, followed by its sighash type, after checking it against
+    /// `checker`, e.g. a `TransactionChecker` of the input
+    pub fn add_signature(&mut self, public_key: &[u8], signature: Vec<u8>, checker: &dyn SignatureChecker) -> Result<(), MultisigError> {
+        let index = self.public_keys.iter().position(|k| k[..] == public_key[..]).ok_or(MultisigError::UnknownKey)?;
+        if !check_script_signature(&signature, public_key, checker) {
+            return Err(MultisigError::BadSignature);
+        }
+        self.signatures[index] = Some(signature);
+        return Ok(());
+    }
+
+    /// Sign the hash given by `checker` for `sighash_type` with the ECDSA `key`
+    pub fn sign(&mut self, key: &keys::KeyPair, checker: &dyn SignatureChecker, sighash_type: SigHashType) -> Result<(), MultisigError> {
+        let signature_hash = checker.signature_hash(sighash_type).ok_or(MultisigError::BadSigHashType)?;
+        let mut signature = key.sign(signature_hash.as_ref())?;
+        signature.push(sighash_type.to_byte());
+        return self.add_signature(key.public_key().as_bytes(), signature, checker);
+    }
+
+    /// Sign the hash given by `checker` for `sighash_type` with the Schnorr `key`
+    pub fn sign_schnorr(&mut self, key: &schnorr::KeyPair, checker: &dyn SignatureChecker, sighash_type: SigHashType) -> Result<(), MultisigError> {
+        let signature_hash = checker.signature_hash(sighash_type).ok_or(MultisigError::BadSigHashType)?;
+        let mut signature = key.sign(signature_hash.as_ref())?;
+        signature.push(sighash_type.to_byte());
+        return self.add_signature(key.public_key().as_bytes(), signature, checker);
+    }
+
+    /// Add the signatures collected by another signer for the same script
+    pub fn merge(&mut self, other: &PartialMultisig) -> Result<(), MultisigError> {
+        if other.script != self.script {
+            return Err(MultisigError::ScriptMismatch);
+        }
+        for (mine, theirs) in self.signatures.iter_mut().zip(other.signatures.iter()) {
+            if mine.is_none() {
+                *mine = theirs.clone();
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn signature_count(&self) -> usize {
+        return self.signatures.iter().filter(|s| s.is_some()).count();
+    }
+
+    pub fn is_complete(&self) -> bool {
+        return self.signature_count() >= self.m;
+    }
+
+    /// The first `m` signatures, in the order of the keys
+    fn selected_signatures(&self) -> Result<Vec<Vec<u8>>, MultisigError> {
+        if !self.is_complete() {
+            return Err(MultisigError::Incomplete { have: self.signature_count(), need: self.m });
+        }
+        return Ok(self.signatures.iter().filter_map(|s| s.clone()).take(self.m).collect());
+    }
+
+    /// The unlocking script of an output locked by the multisig script itself
+    pub fn unlock(&self) -> Result<Script, MultisigError> {
+        let mut script = Script::new();
+        for signature in self.selected_signatures()? {
+            script = script.push_data(&signature);
+        }
+        return Ok(script);
+    }
+
+    /// The unlocking script of a `Script::p2sh_of` output of the multisig script
+    pub fn unlock_p2sh(&self) -> Result<Script, MultisigError> {
+        return Ok(Script::p2sh_unlock(&self.selected_signatures()?, &self.script));
+    }
+
+    /// The witness of an input spending a `Script::p2wsh_of` output of the multisig script
+    pub fn witness(&self) -> Result<Vec<Vec<u8>>, MultisigError> {
+        let mut witness = self.selected_signatures()?;
+        witness.push(self.script.as_bytes().to_vec());
+        return Ok(witness);
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::H256;
+    use crate::script::{verify, ScriptError};
+
+    #[test]
+    fn two_of_three() {
+        let keys: Vec<keys::KeyPair> = (0..2).map(|_| keys::KeyPair::random()).collect();
+        let schnorr_key = schnorr::KeyPair::random();
+        let mut public_keys: Vec<Vec<u8>> = keys.iter().map(|k| k.public_key().as_bytes().to_vec()).collect();
+        public_keys.push(schnorr_key.public_key().as_bytes().to_vec());
+        let script = Script::multisig(2, &public_keys);
+        assert_eq!(script.multisig_keys(), Some((2, public_keys.clone())));
+        let hash = H256::from([9u8; 32]);
+
+        // signers add their signatures to copies, which are then merged
+        let mut first = PartialMultisig::new(script.clone()).unwrap();
+        let mut second = first.clone();
+        second.sign_schnorr(&schnorr_key, &hash, SigHashType::ALL).unwrap();
+        assert_eq!(first.unlock(), Err(MultisigError::Incomplete { have: 0, need: 2 }));
+        first.sign(&keys[0], &hash, SigHashType::ALL).unwrap();
+        assert!(!first.is_complete());
+        first.merge(&second).unwrap();
+        assert!(first.is_complete());
+        assert_eq!(verify(&first.unlock().unwrap(), &[], &script, &hash), Ok(()));
+        assert_eq!(verify(&first.unlock_p2sh().unwrap(), &[], &Script::p2sh_of(&script), &hash), Ok(()));
+        assert_eq!(verify(&Script::new(), &first.witness().unwrap(), &Script::p2wsh_of(&script), &hash), Ok(()));
+
+        // signatures in another order than the keys fail
+        let mut signature_0 = keys[0].sign(hash.as_ref()).unwrap();
+        signature_0.push(SigHashType::ALL.to_byte());
+        let mut signature_1 = keys[1].sign(hash.as_ref()).unwrap();
+        signature_1.push(SigHashType::ALL.to_byte());
+        let swapped = Script::new().push_data(&signature_1).push_data(&signature_0);
+        assert_eq!(verify(&swapped, &[], &script, &hash), Err(ScriptError::False));
+
+        let outsider = keys::KeyPair::random();
+        assert_eq!(first.sign(&outsider, &hash, SigHashType::ALL), Err(MultisigError::UnknownKey));
+        assert_eq!(
+            first.add_signature(&public_keys[1], signature_0, &hash),
+            Err(MultisigError::BadSignature)
+        );
+        assert_eq!(PartialMultisig::new(Script::p2pkh(&[0u8; 20])), Err(MultisigError::NotMultisig));
+    }
+}
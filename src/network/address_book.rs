@@ -0,0 +1,330 @@
+//! The addresses of the nodes heard of, to connect to when the node starts or loses peers.
+//! Addresses gossiped by peers enter the new table, and move to the tried table once connected
+//! to. Each table is split in buckets chosen by a keyed hash of the network group of the address,
+//! so that a peer gossiping many addresses of its own network only fills a few buckets.
+
+use rand::Rng;
+use ring::digest::{Context as DigestContext, SHA256};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Number of buckets of the new table
+pub const NEW_BUCKETS: usize = 64;
+/// Number of buckets of the tried table
+pub const TRIED_BUCKETS: usize = 16;
+/// Most addresses per bucket
+pub const BUCKET_SIZE: usize = 16;
+/// Most addresses sent in an `Addr` message
+pub const MAX_ADDR_PER_MESSAGE: usize = 1000;
+/// Addresses not seen for longer are not gossiped
+pub const ADDR_HORIZON: Duration = Duration::from_secs(30 * 24 * 3600);
+/// Gossiped last seen times further ahead of the local clock are not trusted
+pub const MAX_FUTURE_LAST_SEEN: Duration = Duration::from_secs(10 * 60);
+/// Age given to the addresses gossiped with an untrusted last seen time, so they are tried late
+pub const UNTRUSTED_LAST_SEEN_AGE: Duration = Duration::from_secs(5 * 24 * 3600);
+
+/// A gossiped node address
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressRecord {
+    pub addr: SocketAddr,
+    /// Services offered by the node, see `message::SERVICE_NETWORK`
+    pub services: u64,
+    /// When the node was last heard of, in seconds since the Unix epoch
+    pub last_seen: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+    record: AddressRecord,
+    tried: bool,
+    bucket: usize,
+    /// Failed connection attempts since the last success
+    attempts: u32,
+}
+
+/// The known addresses, in the new and tried tables
+#[derive(Serialize, Deserialize)]
+pub struct AddressBook {
+    /// Secret key of the bucket hashes, so that peers cannot aim at a bucket
+    key: [u8; 32],
+    entries: HashMap<SocketAddr, Entry>,
+}
+
+/// Seconds since the Unix epoch
+pub fn unix_time(time: SystemTime) -> u64 {
+    return time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+}
+
+/// Whether a connection to `addr` may reach a node: it has a port, and its IP address is neither
+/// unspecified, broadcast, multicast nor reserved for documentation. Loopback and private
+/// addresses are routable, as the nodes of a local network use them.
+pub fn is_routable(addr: &SocketAddr) -> bool {
+    if addr.port() == 0 || addr.ip().is_unspecified() || addr.ip().is_multicast() {
+        return false;
+    }
+    return match addr.ip() {
+        IpAddr::V4(ip) => !ip.is_broadcast() && !ip.is_documentation(),
+        // 2001:db8::/32 is reserved for documentation
+        IpAddr::V6(ip) => ip.segments()[..2] != [0x2001, 0xdb8],
+    };
+}
+
+/// The network group of an address: its /16 for IPv4, its /32 for IPv6
+fn group(ip: &IpAddr) -> Vec<u8> {
+    return match ip {
+        IpAddr::V4(ip) => ip.octets()[..2].to_vec(),
+        IpAddr::V6(ip) => ip.octets()[..4].to_vec(),
+    };
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        return AddressBook {
+            key: rand::random(),
+            entries: HashMap::new(),
+        };
+    }
+
+    /// Keyed hash of `parts` reduced to one of `buckets` buckets
+    fn bucket(&self, parts: &[&[u8]], buckets: usize) -> usize {
+        let mut context = DigestContext::new(&SHA256);
+        context.update(&self.key);
+        for part in parts {
+            context.update(part);
+        }
+        let digest = context.finish();
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&digest.as_ref()[..8]);
+        return (u64::from_le_bytes(bytes) % buckets as u64) as usize;
+    }
+
+    /// New addresses go in a bucket of the groups of the address and of its source
+    fn new_bucket(&self, addr: &SocketAddr, source: &IpAddr) -> usize {
+        return self.bucket(&[b"new", &group(&addr.ip()), &group(source)], NEW_BUCKETS);
+    }
+
+    /// Tried addresses go in a bucket of their address and group
+    fn tried_bucket(&self, addr: &SocketAddr) -> usize {
+        return self.bucket(&[b"tried", addr.to_string().as_bytes(), &group(&addr.ip())], TRIED_BUCKETS);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.entries.is_empty();
+    }
+
+    /// Number of addresses in the tried table
+    pub fn num_tried(&self) -> usize {
+        return self.entries.values().filter(|e| e.tried).count();
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        return self.entries.contains_key(addr);
+    }
+
+    pub fn is_tried(&self, addr: &SocketAddr) -> bool {
+        return self.entries.get(addr).map_or(false, |e| e.tried);
+    }
+
+    /// The address of the table and bucket that was seen the longest ago
+    fn oldest_in(&self, tried: bool, bucket: usize) -> Option<SocketAddr> {
+        return self
+            .entries
+            .iter()
+            .filter(|(_, e)| e.tried == tried && e.bucket == bucket)
+            .min_by_key(|(_, e)| e.record.last_seen)
+            .map(|(addr, _)| *addr);
+    }
+
+    fn bucket_len(&self, tried: bool, bucket: usize) -> usize {
+        return self.entries.values().filter(|e| e.tried == tried && e.bucket == bucket).count();
+    }
+
+    /// Add the addresses gossiped by the node at `source` at `now`, or refresh their last seen
+    /// time. Last seen times ahead of `now` are clamped to it, and those too far ahead are made
+    /// old. When a bucket is full, the address seen the longest ago is evicted.
+    pub fn add(&mut self, records: &[AddressRecord], source: IpAddr, now: SystemTime) {
+        let now = unix_time(now);
+        for record in records {
+            let last_seen = if record.last_seen > now + MAX_FUTURE_LAST_SEEN.as_secs() {
+                now.saturating_sub(UNTRUSTED_LAST_SEEN_AGE.as_secs())
+            } else {
+                std::cmp::min(record.last_seen, now)
+            };
+            let record = &AddressRecord { last_seen, ..*record };
+            if let Some(entry) = self.entries.get_mut(&record.addr) {
+                entry.record.last_seen = std::cmp::max(entry.record.last_seen, record.last_seen);
+                entry.record.services |= record.services;
+                continue;
+            }
+            let bucket = self.new_bucket(&record.addr, &source);
+            if self.bucket_len(false, bucket) >= BUCKET_SIZE {
+                let oldest = self.oldest_in(false, bucket).unwrap();
+                self.entries.remove(&oldest);
+            }
+            self.entries.insert(record.addr, Entry { record: *record, tried: false, bucket, attempts: 0 });
+        }
+    }
+
+    /// Move an address to the tried table after a successful connection at `now`. When its
+    /// bucket is full, the address seen the longest ago goes back to the new table.
+    pub fn mark_good(&mut self, addr: SocketAddr, services: u64, now: SystemTime) {
+        let mut entry = match self.entries.remove(&addr) {
+            Some(entry) => entry,
+            None => Entry { record: AddressRecord { addr, services, last_seen: 0 }, tried: false, bucket: 0, attempts: 0 },
+        };
+        let bucket = self.tried_bucket(&addr);
+        if !entry.tried && self.bucket_len(true, bucket) >= BUCKET_SIZE {
+            let oldest = self.oldest_in(true, bucket).unwrap();
+            let evicted = self.entries.remove(&oldest).unwrap();
+            self.add(&[evicted.record], oldest.ip(), now);
+        }
+        entry.record.last_seen = unix_time(now);
+        entry.record.services = services;
+        entry.tried = true;
+        entry.bucket = bucket;
+        entry.attempts = 0;
+        self.entries.insert(addr, entry);
+    }
+
+    /// Record a failed connection to an address
+    pub fn mark_failed(&mut self, addr: &SocketAddr) {
+        if let Some(entry) = self.entries.get_mut(addr) {
+            entry.attempts += 1;
+        }
+    }
+
+    /// A random address to connect to, from the tried or the new table with equal chances,
+    /// preferring the addresses that failed the least
+    pub fn select<R: Rng>(&self, rng: &mut R) -> Option<SocketAddr> {
+        let num_tried = self.num_tried();
+        if self.entries.is_empty() {
+            return None;
+        }
+        let tried = match (num_tried, self.entries.len() - num_tried) {
+            (0, _) => false,
+            (_, 0) => true,
+            _ => rng.gen(),
+        };
+        let table: Vec<&Entry> = self.entries.values().filter(|e| e.tried == tried).collect();
+        // pick two and keep the one that failed the least
+        let first = table[rng.gen_range(0, table.len())];
+        let second = table[rng.gen_range(0, table.len())];
+        let chosen = if second.attempts < first.attempts { second } else { first };
+        return Some(chosen.record.addr);
+    }
+
+    /// Up to `max` random addresses seen since `ADDR_HORIZON` before `now`, to gossip
+    pub fn sample<R: Rng>(&self, rng: &mut R, max: usize, now: SystemTime) -> Vec<AddressRecord> {
+        let horizon = unix_time(now).saturating_sub(ADDR_HORIZON.as_secs());
+        let mut records: Vec<AddressRecord> =
+            self.entries.values().map(|e| e.record).filter(|r| r.last_seen >= horizon).collect();
+        rand::seq::SliceRandom::shuffle(&mut records[..], rng);
+        records.truncate(max);
+        return records;
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let bytes = bincode::serialize(self).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        File::create(path)?.write_all(&bytes)?;
+        return Ok(());
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let mut bytes: Vec<u8> = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        return bincode::deserialize(&bytes).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+    }
+}
+
+impl Default for AddressBook {
+    fn default() -> Self {
+        AddressBook::new()
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+
+    fn record(addr: &str, last_seen: u64) -> AddressRecord {
+        return AddressRecord { addr: addr.parse().unwrap(), services: 1, last_seen };
+    }
+
+    #[test]
+    fn routable() {
+        for addr in &["1.2.3.4:6000", "127.0.0.1:6000", "10.0.0.1:6000", "[2001:4860::1]:6000"] {
+            assert!(is_routable(&addr.parse().unwrap()), "{}", addr);
+        }
+        for addr in &["1.2.3.4:0", "0.0.0.0:6000", "255.255.255.255:6000", "224.0.0.1:6000", "192.0.2.1:6000", "[::]:6000", "[2001:db8::1]:6000"] {
+            assert!(!is_routable(&addr.parse().unwrap()), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn future_last_seen() {
+        let mut book = AddressBook::new();
+        let source: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = UNIX_EPOCH + UNTRUSTED_LAST_SEEN_AGE * 2;
+        let last_seen = |book: &AddressBook, addr: &str| book.entries[&addr.parse().unwrap()].record.last_seen;
+        // a clock slightly ahead is clamped to now, a timestamp far ahead makes the address old
+        book.add(&[record("1.2.3.4:6000", unix_time(now) + 60)], source, now);
+        assert_eq!(last_seen(&book, "1.2.3.4:6000"), unix_time(now));
+        book.add(&[record("1.2.3.5:6000", u64::MAX)], source, now);
+        assert_eq!(last_seen(&book, "1.2.3.5:6000"), unix_time(now - UNTRUSTED_LAST_SEEN_AGE));
+        // nor does it refresh a known address
+        book.add(&[record("1.2.3.4:6000", u64::MAX)], source, now + Duration::from_secs(1));
+        assert_eq!(last_seen(&book, "1.2.3.4:6000"), unix_time(now));
+    }
+
+    #[test]
+    fn tables() {
+        let mut book = AddressBook::new();
+        book.key = [7u8; 32];
+        let source: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        book.add(&[record("1.2.3.4:6000", 10), record("1.2.3.5:6000", 20)], source, now);
+        book.add(&[record("1.2.3.4:6000", 30)], source, now);
+        assert_eq!(book.len(), 2);
+        assert_eq!(book.num_tried(), 0);
+
+        // addresses of one group gossiped by one source share a bucket, so they evict each other
+        let many: Vec<AddressRecord> = (0..100).map(|i| record(&format!("5.6.{}.{}:6000", i / 10, i), 100 + i)).collect();
+        book.add(&many, source, now);
+        assert_eq!(book.len(), 2 + BUCKET_SIZE);
+        assert!(book.contains(&many[99].addr));
+        assert!(!book.contains(&many[0].addr));
+
+        book.mark_good("1.2.3.4:6000".parse().unwrap(), 1, now);
+        assert!(book.is_tried(&"1.2.3.4:6000".parse().unwrap()));
+        book.mark_good("9.9.9.9:6000".parse().unwrap(), 1, now);
+        assert_eq!(book.num_tried(), 2);
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..20 {
+            assert!(book.contains(&book.select(&mut rng).unwrap()));
+        }
+        assert!(AddressBook::new().select(&mut rng).is_none());
+
+        // only recent addresses are gossiped
+        let sample = book.sample(&mut rng, MAX_ADDR_PER_MESSAGE, now + ADDR_HORIZON);
+        assert_eq!(sample.len(), 2);
+        assert_eq!(book.sample(&mut rng, 1, now).len(), 1);
+
+        let path = std::env::temp_dir().join(format!("peers_{}", rand::random::<u32>()));
+        book.save(&path).unwrap();
+        let loaded = AddressBook::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.len(), book.len());
+        assert_eq!(loaded.num_tried(), 2);
+        assert_eq!(loaded.key, book.key);
+    }
+}
@@ -0,0 +1,252 @@
+//! Compact block relay, after BIP152: a block is sent as its header and short ids of its
+//! transactions, which the receiver finds in its mempool, only asking for the ones it misses.
+//! Short ids are keyed by the block and a random nonce, so that they cannot be collided in
+//! advance; a collision still only costs a download of the full block.
+
+use ring::digest::{Context as DigestContext, SHA256};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+
+use crate::block::{Block, Header};
+use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::merkle::MerkleTree;
+use crate::transaction::Transaction;
+
+/// A transaction id shortened to 6 bytes
+pub type ShortId = u64;
+
+/// Mask keeping the 6 bytes of a short id
+const SHORT_ID_MASK: u64 = 0xffff_ffff_ffff;
+
+/// A transaction sent whole in a compact block, because the receiver cannot have it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PrefilledTransaction {
+    /// Position of the transaction in the block
+    pub index: u32,
+    pub transaction: Transaction,
+}
+
+/// A block as its header and the short ids of its transactions, the coinbase being prefilled
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactBlock {
+    pub header: Header,
+    /// Random nonce keying the short ids
+    pub nonce: u64,
+    /// Short ids of the transactions not prefilled, in block order
+    pub short_ids: Vec<ShortId>,
+    pub prefilled: Vec<PrefilledTransaction>,
+}
+
+/// Request for transactions of a compact block, by their position in the block
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockTxnRequest {
+    pub block: H256,
+    pub indexes: Vec<u32>,
+}
+
+/// The transactions requested by a `BlockTxnRequest`, in the order of the request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockTxn {
+    pub block: H256,
+    pub transactions: Vec<Transaction>,
+}
+
+/// Reasons for a compact block not to be turned into its block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompactError {
+    /// The prefilled transactions and short ids do not describe a list of transactions
+    Malformed,
+    /// Not the number of transactions requested, or not those of the block
+    WrongTransactions,
+    /// The transactions found do not match the merkle root, e.g. after a short id collision
+    MerkleMismatch,
+}
+
+impl std::fmt::Display for CompactError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CompactError::Malformed => write!(f, "malformed compact block"),
+            CompactError::WrongTransactions => write!(f, "wrong transactions for the compact block"),
+            CompactError::MerkleMismatch => write!(f, "transactions do not match the merkle root"),
+        }
+    }
+}
+
+impl CompactBlock {
+    /// The compact form of `block`, with short ids keyed by `nonce`
+    pub fn new(block: &Block, nonce: u64) -> Self {
+        let mut compact = CompactBlock {
+            header: block.get_header().clone(),
+            nonce,
+            short_ids: Vec::new(),
+            prefilled: Vec::new(),
+        };
+        for (index, transaction) in block.get_transactions().iter().enumerate() {
+            if index == 0 {
+                compact.prefilled.push(PrefilledTransaction { index: 0, transaction: transaction.clone() });
+            } else {
+                compact.short_ids.push(compact.short_id(&transaction.txid()));
+            }
+        }
+        return compact;
+    }
+
+    /// Short id of the transaction `txid` in this block: the first 6 bytes of the hash of the
+    /// header, the nonce and the txid
+    pub fn short_id(&self, txid: &H256) -> ShortId {
+        let mut context = DigestContext::new(&SHA256);
+        context.update(self.header.hash().as_ref());
+        context.update(&self.nonce.to_le_bytes());
+        context.update(txid.as_ref());
+        let digest = context.finish();
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&digest.as_ref()[..8]);
+        return u64::from_le_bytes(bytes) & SHORT_ID_MASK;
+    }
+
+    /// Number of transactions of the block
+    pub fn len(&self) -> usize {
+        return self.short_ids.len() + self.prefilled.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+}
+
+impl Hashable for CompactBlock {
+    fn hash(&self) -> H256 {
+        return self.header.hash();
+    }
+}
+
+/// A compact block being reconstructed, with the transactions found so far
+pub struct PartialBlock {
+    compact: CompactBlock,
+    transactions: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    /// Place the prefilled transactions, and find the others among `candidates`, e.g. the
+    /// transactions of the mempool
+    pub fn new<'a, I>(compact: CompactBlock, candidates: I) -> Result<Self, CompactError>
+    where
+        I: IntoIterator<Item = &'a Transaction>,
+    {
+        // a block has at least its coinbase
+        if compact.is_empty() {
+            return Err(CompactError::Malformed);
+        }
+        let mut transactions: Vec<Option<Transaction>> = vec![None; compact.len()];
+        for prefilled in &compact.prefilled {
+            match transactions.get_mut(prefilled.index as usize) {
+                Some(slot @ None) => *slot = Some(prefilled.transaction.clone()),
+                _ => return Err(CompactError::Malformed),
+            }
+        }
+        // the positions left are those of the short ids, in order
+        let positions: Vec<usize> = (0..transactions.len()).filter(|i| transactions[*i].is_none()).collect();
+        let mut by_short_id: HashMap<ShortId, usize> = HashMap::new();
+        for (short_id, position) in compact.short_ids.iter().zip(positions.iter()) {
+            by_short_id.insert(*short_id, *position);
+        }
+        for candidate in candidates {
+            if let Some(position) = by_short_id.get(&compact.short_id(&candidate.txid())) {
+                transactions[*position] = Some(candidate.clone());
+            }
+        }
+        return Ok(PartialBlock { compact, transactions });
+    }
+
+    pub fn hash(&self) -> H256 {
+        return self.compact.hash();
+    }
+
+    /// Positions of the transactions not found yet
+    pub fn missing(&self) -> Vec<u32> {
+        return (0..self.transactions.len()).filter(|i| self.transactions[*i].is_none()).map(|i| i as u32).collect();
+    }
+
+    /// Place the transactions answering the request of the `missing` ones
+    pub fn fill(&mut self, transactions: Vec<Transaction>) -> Result<(), CompactError> {
+        let missing = self.missing();
+        if transactions.len() != missing.len() {
+            return Err(CompactError::WrongTransactions);
+        }
+        for (index, transaction) in missing.into_iter().zip(transactions.into_iter()) {
+            self.transactions[index as usize] = Some(transaction);
+        }
+        return Ok(());
+    }
+
+    /// The reconstructed block, once no transaction is missing
+    pub fn block(&self) -> Result<Block, CompactError> {
+        let transactions: Vec<Transaction> = match self.transactions.iter().cloned().collect() {
+            Some(transactions) => transactions,
+            None => return Err(CompactError::WrongTransactions),
+        };
+        let header = &self.compact.header;
+        if MerkleTree::new(&transactions).root() != header.get_merkle_root() {
+            return Err(CompactError::MerkleMismatch);
+        }
+        let block = Block::new(header.get_parent(), header.get_difficulty(), transactions, header.get_merkle_root())
+            .with_header(header.clone());
+        return Ok(block);
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::transaction::tests::generate_random_transaction;
+    use crate::transaction::{Transaction, BLOCK_REWARD};
+
+    fn block(transactions: &[Transaction]) -> Block {
+        let mut all = vec![Transaction::coinbase(0, H256::from([1u8; 32]), BLOCK_REWARD)];
+        all.extend(transactions.iter().cloned());
+        let merkle_root = MerkleTree::new(&all).root();
+        return Block::new(H256::default(), H256::default(), all, merkle_root);
+    }
+
+    #[test]
+    fn reconstruction() {
+        let transactions: Vec<Transaction> = (0..4).map(|_| generate_random_transaction()).collect();
+        let block = block(&transactions);
+        let compact = CompactBlock::new(&block, 42);
+        assert_eq!(compact.len(), 5);
+        assert_eq!(compact.prefilled.len(), 1);
+        assert_eq!(compact.hash(), block.hash());
+        // short ids depend on the nonce
+        assert_ne!(CompactBlock::new(&block, 43).short_ids, compact.short_ids);
+
+        // the mempool knows all but one, and unrelated ones
+        let mempool = vec![transactions[0].clone(), transactions[3].clone(), transactions[1].clone(), generate_random_transaction()];
+        let mut partial = PartialBlock::new(compact.clone(), mempool.iter()).unwrap();
+        assert_eq!(partial.missing(), vec![3]);
+        assert_eq!(partial.block().err(), Some(CompactError::WrongTransactions));
+        assert_eq!(partial.fill(vec![]), Err(CompactError::WrongTransactions));
+        partial.fill(vec![transactions[2].clone()]).unwrap();
+        let rebuilt = partial.block().unwrap();
+        assert_eq!(rebuilt.hash(), block.hash());
+        let txids: Vec<H256> = rebuilt.get_transactions().iter().map(|t| t.txid()).collect();
+        let expected: Vec<H256> = block.get_transactions().iter().map(|t| t.txid()).collect();
+        assert_eq!(txids, expected);
+
+        // the witness commitment travels in the coinbase, under the merkle root
+        let mut committed = block.clone();
+        committed.commit_witnesses();
+        let partial = PartialBlock::new(CompactBlock::new(&committed, 42), committed.get_transactions()[1..].iter()).unwrap();
+        let rebuilt = partial.block().unwrap();
+        assert!(rebuilt.get_witness_commitment().is_some());
+        assert_eq!(rebuilt.get_witness_commitment(), committed.get_witness_commitment());
+
+        // a wrong transaction is caught by the merkle root
+        let mut partial = PartialBlock::new(compact.clone(), mempool.iter()).unwrap();
+        partial.fill(vec![generate_random_transaction()]).unwrap();
+        assert_eq!(partial.block().err(), Some(CompactError::MerkleMismatch));
+
+        let mut malformed = compact;
+        malformed.prefilled[0].index = 9;
+        assert_eq!(PartialBlock::new(malformed, mempool.iter()).err().map(|e| e.to_string()), Some("malformed compact block".to_string()));
+    }
+}
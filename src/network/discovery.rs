@@ -0,0 +1,217 @@
+//! Finding peers to connect to: addresses of the address book fill the free outbound slots. The
+//! book starts with the addresses of DNS seeds and of seed nodes, and is saved as it learns more.
+
+use log::{debug, info, warn};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use super::address_book::{self, AddressBook, AddressRecord};
+use super::message::Message;
+use super::peer::{self, Direction};
+use super::server::Handle as ServerHandle;
+use super::worker;
+use crate::blockchain::Blockchain;
+
+/// Port of the peers whose address does not give one
+pub const DEFAULT_PORT: u16 = 6000;
+
+/// Nodes tried when no seed is configured: those of a local network started with the default
+/// ports
+pub const SEED_NODES: &[&str] = &["127.0.0.1:6000", "127.0.0.1:6001", "127.0.0.1:6002", "127.0.0.1:6003"];
+
+/// Time between two attempts to fill the free outbound slots
+pub const DISCOVERY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Addresses drawn from the address book on each attempt to fill the slots
+const SELECTIONS: usize = 64;
+
+/// The addresses of `hosts`, names or IP addresses with an optional port, `default_port`
+/// otherwise. Hosts which do not resolve are skipped.
+pub fn resolve(hosts: &[String], default_port: u16) -> Vec<SocketAddr> {
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    for host in hosts {
+        let resolved = match host.to_socket_addrs() {
+            Ok(resolved) => resolved,
+            Err(_) => match (host.as_str(), default_port).to_socket_addrs() {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    warn!("Error resolving seed {}: {}", host, e);
+                    continue;
+                }
+            },
+        };
+        for addr in resolved {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+    return addrs;
+}
+
+/// Open a connection to `addr` and send it our `Version`
+pub fn connect(server: &ServerHandle, blockchain: &Arc<RwLock<Blockchain>>, addr: SocketAddr) -> std::io::Result<peer::Handle> {
+    let peer = server.connect(addr)?;
+    let version = worker::local_version(&blockchain.read().unwrap(), server);
+    peer.write(Message::Version(version));
+    return Ok(peer);
+}
+
+/// Connects to the addresses of the address book while outbound slots are free
+pub struct Context {
+    server: ServerHandle,
+    blockchain: Arc<RwLock<Blockchain>>,
+    address_book: Arc<RwLock<AddressBook>>,
+    /// File the address book is saved to after each attempt
+    path: Option<PathBuf>,
+    /// Our own address, never connected to
+    local_addr: SocketAddr,
+    dns_seeds: Vec<String>,
+    seed_nodes: Vec<String>,
+}
+
+pub fn new(
+    server: &ServerHandle,
+    blockchain: &Arc<RwLock<Blockchain>>,
+    address_book: &Arc<RwLock<AddressBook>>,
+    local_addr: SocketAddr,
+) -> Context {
+    Context {
+        server: server.clone(),
+        blockchain: Arc::clone(blockchain),
+        address_book: Arc::clone(address_book),
+        path: None,
+        local_addr,
+        dns_seeds: Vec::new(),
+        seed_nodes: SEED_NODES.iter().map(|s| s.to_string()).collect(),
+    }
+}
+
+impl Context {
+    /// Resolve these host names for peers, instead of trying the default seed nodes
+    pub fn with_dns_seeds(mut self, seeds: Vec<String>) -> Self {
+        self.dns_seeds = seeds;
+        self.seed_nodes.clear();
+        return self;
+    }
+
+    /// Try these addresses for peers, instead of the default seed nodes
+    pub fn with_seed_nodes(mut self, nodes: Vec<String>) -> Self {
+        self.seed_nodes = nodes;
+        return self;
+    }
+
+    /// Save the address book to `path` after each attempt to fill the slots
+    pub fn with_address_file(mut self, path: PathBuf) -> Self {
+        self.path = Some(path);
+        return self;
+    }
+
+    pub fn start(self) {
+        thread::Builder::new()
+            .name("discovery".to_string())
+            .spawn(move || loop {
+                self.fill_slots();
+                self.save();
+                thread::sleep(DISCOVERY_INTERVAL);
+            })
+            .unwrap();
+    }
+
+    /// Addresses drawn from the address book, but our own, those of the connected peers and those
+    /// that are not routable. An empty book is first filled with the addresses of the seeds.
+    fn candidates(&self) -> Vec<SocketAddr> {
+        let mut address_book = self.address_book.write().unwrap();
+        if address_book.is_empty() {
+            let mut hosts = self.dns_seeds.clone();
+            hosts.extend(self.seed_nodes.iter().cloned());
+            let now = std::time::SystemTime::now();
+            let last_seen = address_book::unix_time(now);
+            for addr in resolve(&hosts, DEFAULT_PORT) {
+                address_book.add(&[AddressRecord { addr, services: 0, last_seen }], addr.ip(), now);
+            }
+        }
+        let mut rng = rand::thread_rng();
+        let mut candidates: Vec<SocketAddr> = Vec::new();
+        for _ in 0..SELECTIONS {
+            match address_book.select(&mut rng) {
+                Some(addr)
+                    if addr != self.local_addr
+                        && address_book::is_routable(&addr)
+                        && !candidates.contains(&addr)
+                        && !self.server.is_connected(addr) =>
+                {
+                    candidates.push(addr);
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+        return candidates;
+    }
+
+    /// Try each candidate once, until the outbound slots are full
+    fn fill_slots(&self) {
+        for addr in self.candidates() {
+            if self.server.free_slots(Direction::Outgoing) == 0 {
+                return;
+            }
+            match connect(&self.server, &self.blockchain, addr) {
+                Ok(_) => info!("Connected to discovered peer {}", addr),
+                Err(e) => {
+                    debug!("Error connecting to discovered peer {}: {}", addr, e);
+                    self.address_book.write().unwrap().mark_failed(&addr);
+                }
+            }
+        }
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = self.address_book.read().unwrap().save(path) {
+                warn!("Error saving the address book to {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::network::server;
+    use crossbeam::channel;
+
+    #[test]
+    fn seeds() {
+        let addrs = resolve(&["localhost:1234".to_string(), "127.0.0.2".to_string(), "[bad".to_string()], 6000);
+        assert!(addrs.contains(&"127.0.0.1:1234".parse().unwrap()));
+        assert!(addrs.contains(&"127.0.0.2:6000".parse().unwrap()));
+
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let (_server_ctx, server) = server::new("127.0.0.1:0".parse().unwrap(), Default::default(), channel::unbounded().0).unwrap();
+        let local: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let address_book = Arc::new(RwLock::new(AddressBook::new()));
+        let defaults = new(&server, &blockchain, &address_book, local).candidates();
+        // the seeds fill the empty book, and our own address is skipped
+        assert_eq!(address_book.read().unwrap().len(), SEED_NODES.len());
+        assert_eq!(defaults.len(), SEED_NODES.len() - 1);
+        assert!(defaults.contains(&"127.0.0.1:6000".parse().unwrap()));
+        assert!(!defaults.contains(&local));
+
+        let address_book = Arc::new(RwLock::new(AddressBook::new()));
+        let discovery = new(&server, &blockchain, &address_book, local)
+            .with_dns_seeds(vec!["127.0.0.3".to_string()])
+            .with_seed_nodes(vec!["127.0.0.4:7000".to_string()]);
+        let mut candidates = discovery.candidates();
+        candidates.sort();
+        let expected: Vec<SocketAddr> = vec!["127.0.0.3:6000".parse().unwrap(), "127.0.0.4:7000".parse().unwrap()];
+        assert_eq!(candidates, expected);
+        // the seeds are not used once the book knows addresses
+        address_book.write().unwrap().mark_good("127.0.0.5:6000".parse().unwrap(), 1, std::time::SystemTime::now());
+        let discovery = discovery.with_dns_seeds(Vec::new()).with_seed_nodes(Vec::new());
+        assert_eq!(discovery.candidates().len(), 3);
+    }
+}
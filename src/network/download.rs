@@ -0,0 +1,136 @@
+//! Scheduling the download of block bodies during sync: the missing blocks of the best header
+//! chain, within a window above the lowest one, are spread over the peers with a limit on the
+//! requests each has in flight. Requests a peer leaves unanswered for too long are given to
+//! others, and the peer is left aside for a while.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::crypto::hash::H256;
+
+/// Number of missing blocks, from the lowest, that may be requested at once
+pub const BLOCK_DOWNLOAD_WINDOW: usize = 1024;
+/// Most blocks requested from one peer and not received yet
+pub const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 16;
+/// Time a peer has to deliver a requested block before its requests go to other peers
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The blocks requested and not received yet
+pub struct Downloader {
+    /// The peer each block was requested from, and when
+    in_flight: HashMap<H256, (SocketAddr, Instant)>,
+    /// Peers that stalled, with the time until which they are not asked for blocks
+    stalled: HashMap<SocketAddr, Instant>,
+}
+
+impl Downloader {
+    pub fn new() -> Self {
+        return Downloader {
+            in_flight: HashMap::new(),
+            stalled: HashMap::new(),
+        };
+    }
+
+    /// Number of blocks requested from `peer` and not received yet
+    pub fn in_flight(&self, peer: SocketAddr) -> usize {
+        return self.in_flight.values().filter(|(p, _)| *p == peer).count();
+    }
+
+    pub fn is_requested(&self, hash: &H256) -> bool {
+        return self.in_flight.contains_key(hash);
+    }
+
+    /// Pick the blocks of `missing`, from the lowest, to request from `peer` at `now`, up to its
+    /// free request slots. Blocks already in flight and stalled peers are skipped.
+    pub fn assign(&mut self, peer: SocketAddr, missing: &[H256], now: Instant) -> Vec<H256> {
+        if let Some(until) = self.stalled.get(&peer) {
+            if now < *until {
+                return Vec::new();
+            }
+            self.stalled.remove(&peer);
+        }
+        let free = MAX_BLOCKS_IN_FLIGHT_PER_PEER.saturating_sub(self.in_flight(peer));
+        let assigned: Vec<H256> = missing.iter().filter(|hash| !self.in_flight.contains_key(hash)).take(free).cloned().collect();
+        for hash in &assigned {
+            self.in_flight.insert(*hash, (peer, now));
+        }
+        return assigned;
+    }
+
+    /// Record that a block was received, returning the peer it was requested from
+    pub fn received(&mut self, hash: &H256) -> Option<SocketAddr> {
+        return self.in_flight.remove(hash).map(|(peer, _)| peer);
+    }
+
+    /// Forget the requests made to `peer`, e.g. when it disconnects, so they can be made again
+    pub fn release(&mut self, peer: SocketAddr) -> Vec<H256> {
+        let released: Vec<H256> = self.in_flight.iter().filter(|(_, (p, _))| *p == peer).map(|(h, _)| *h).collect();
+        for hash in &released {
+            self.in_flight.remove(hash);
+        }
+        return released;
+    }
+
+    /// Release the requests of the peers with a request older than `STALL_TIMEOUT` at `now`, and
+    /// leave these peers aside for `STALL_TIMEOUT`. Returns the stalled peers.
+    pub fn check_stalls(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let mut stalled: Vec<SocketAddr> = self
+            .in_flight
+            .values()
+            .filter(|(_, requested)| now.saturating_duration_since(*requested) > STALL_TIMEOUT)
+            .map(|(peer, _)| *peer)
+            .collect();
+        stalled.sort();
+        stalled.dedup();
+        for peer in &stalled {
+            self.release(*peer);
+            self.stalled.insert(*peer, now + STALL_TIMEOUT);
+        }
+        return stalled;
+    }
+}
+
+impl Default for Downloader {
+    fn default() -> Self {
+        Downloader::new()
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scheduling() {
+        let mut downloader = Downloader::new();
+        let (a, b): (SocketAddr, SocketAddr) = ("127.0.0.1:1".parse().unwrap(), "127.0.0.1:2".parse().unwrap());
+        let missing: Vec<H256> = (0..40u8).map(|i| H256::from([i; 32])).collect();
+        let now = Instant::now();
+
+        // peers get distinct ranges, within their limit
+        let first = downloader.assign(a, &missing, now);
+        assert_eq!(first, missing[..MAX_BLOCKS_IN_FLIGHT_PER_PEER].to_vec());
+        assert!(downloader.assign(a, &missing, now).is_empty());
+        let second = downloader.assign(b, &missing, now);
+        assert_eq!(second, missing[MAX_BLOCKS_IN_FLIGHT_PER_PEER..2 * MAX_BLOCKS_IN_FLIGHT_PER_PEER].to_vec());
+
+        // a delivered block frees a slot of its peer
+        assert_eq!(downloader.received(&missing[0]), Some(a));
+        assert_eq!(downloader.received(&missing[0]), None);
+        assert_eq!(downloader.in_flight(a), MAX_BLOCKS_IN_FLIGHT_PER_PEER - 1);
+        assert_eq!(downloader.assign(a, &missing[1..], now), vec![missing[2 * MAX_BLOCKS_IN_FLIGHT_PER_PEER]]);
+
+        // b answers in time, a stalls and its blocks go to b
+        let later = now + STALL_TIMEOUT + Duration::from_secs(1);
+        for hash in &second {
+            downloader.received(hash);
+        }
+        assert_eq!(downloader.check_stalls(later), vec![a]);
+        assert_eq!(downloader.in_flight(a), 0);
+        assert_eq!(downloader.assign(b, &missing[1..], later), missing[1..1 + MAX_BLOCKS_IN_FLIGHT_PER_PEER].to_vec());
+        assert!(downloader.assign(a, &missing, later).is_empty());
+        assert!(!downloader.assign(a, &missing, later + STALL_TIMEOUT).is_empty());
+        assert_eq!(downloader.release(b).len(), MAX_BLOCKS_IN_FLIGHT_PER_PEER);
+    }
+}
@@ -0,0 +1,102 @@
+//! Keeping the connections alive: peers are pinged periodically, and disconnected when they do
+//! not answer in time or do not complete the handshake, so dead connections free their slots
+
+use log::info;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::message::Message;
+use super::peer;
+use super::server::Handle as ServerHandle;
+
+/// Time between the pings of a peer
+pub const PING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Time a peer has to answer a ping
+pub const PING_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Time a peer has to complete the handshake after connecting
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Time between two checks of the peers
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What to do with a peer when checking it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Wait,
+    Ping,
+    Disconnect,
+}
+
+fn action(peer: &peer::Handle, now: Instant) -> Action {
+    if !peer.is_handshake_complete() {
+        if now.saturating_duration_since(peer.connected_at()) > HANDSHAKE_TIMEOUT {
+            return Action::Disconnect;
+        }
+        return Action::Wait;
+    }
+    return match peer.last_ping() {
+        None => Action::Ping,
+        Some((sent, true)) if now.saturating_duration_since(sent) > PING_TIMEOUT => Action::Disconnect,
+        Some((_, true)) => Action::Wait,
+        Some((sent, false)) if now.saturating_duration_since(sent) >= PING_INTERVAL => Action::Ping,
+        Some((_, false)) => Action::Wait,
+    };
+}
+
+/// Check the peers of `server` periodically, pinging them and disconnecting the unresponsive ones
+pub fn start(server: &ServerHandle) {
+    let server = server.clone();
+    thread::Builder::new()
+        .name("keepalive".to_string())
+        .spawn(move || loop {
+            thread::sleep(CHECK_INTERVAL);
+            let now = Instant::now();
+            for peer in server.peer_handles() {
+                match action(&peer, now) {
+                    Action::Wait => {}
+                    Action::Ping => peer.write(Message::Ping(peer.start_ping(now))),
+                    Action::Disconnect => {
+                        info!("Peer {} unresponsive, disconnecting", peer.addr());
+                        server.disconnect(peer.addr());
+                    }
+                }
+            }
+        })
+        .unwrap();
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use super::super::message::VersionInfo;
+    use super::super::peer::tests::connected;
+
+    #[test]
+    fn actions() {
+        let (ctx, _remote) = connected();
+        let peer = ctx.handle;
+        let start = peer.connected_at();
+        assert_eq!(action(&peer, start), Action::Wait);
+        assert_eq!(action(&peer, start + HANDSHAKE_TIMEOUT + Duration::from_secs(1)), Action::Disconnect);
+
+        peer.set_version(VersionInfo { version: 2, services: 1, best_height: 0, nonce: 1 });
+        peer.acknowledge();
+        assert_eq!(action(&peer, start), Action::Ping);
+        let nonce = peer.start_ping(start);
+        assert_eq!(action(&peer, start + Duration::from_secs(1)), Action::Wait);
+        assert_eq!(peer.pong("other", start + Duration::from_millis(50)), None);
+        assert_eq!(peer.pong(&nonce, start + Duration::from_millis(80)), Some(Duration::from_millis(80)));
+        assert_eq!(peer.get_latency(), Some(Duration::from_millis(80)));
+        // a pong is only counted once
+        assert_eq!(peer.pong(&nonce, start + Duration::from_millis(90)), None);
+
+        assert_eq!(action(&peer, start + PING_INTERVAL - Duration::from_secs(1)), Action::Wait);
+        let later = start + PING_INTERVAL;
+        assert_eq!(action(&peer, later), Action::Ping);
+        peer.start_ping(later);
+        assert_eq!(action(&peer, later + PING_TIMEOUT), Action::Wait);
+        assert_eq!(action(&peer, later + PING_TIMEOUT + Duration::from_secs(1)), Action::Disconnect);
+    }
+}
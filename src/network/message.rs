@@ -1,13 +1,221 @@
 use serde::{Serialize, Deserialize};
 
-use crate::block::Block;
+use super::address_book::AddressRecord;
+use super::compact::{BlockTxn, BlockTxnRequest, CompactBlock};
+use crate::block_filter::{CFHeaders, CFilter, FilterRequest};
+use crate::bloom::{BloomFilter, FilteredBlock};
+use crate::block::{Block, Header};
 use crate::crypto::hash::H256;
+use crate::transaction::Transaction;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum Message {
+/// Version of the peer protocol spoken by this node
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// Oldest version of the peer protocol a peer may speak, older peers are disconnected
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// First version of the peer protocol with compact block relay
+pub const COMPACT_BLOCKS_VERSION: u32 = 3;
+
+/// Service bit of the nodes serving the full blocks of the longest chain
+pub const SERVICE_NETWORK: u64 = 1;
+
+/// Service bit of the pruning nodes, serving only the recent blocks they keep in full
+pub const SERVICE_NETWORK_LIMITED: u64 = 1 << 10;
+
+/// Service bit of the nodes serving light clients through Bloom filters
+pub const SERVICE_BLOOM: u64 = 1 << 2;
+
+/// Service bit of the nodes serving compact block filters
+pub const SERVICE_COMPACT_FILTERS: u64 = 1 << 6;
+
+/// What a node tells about itself when connecting, see `Message::Version`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct VersionInfo {
+    pub version: u32,
+    /// Bitfield of the services offered, see `SERVICE_NETWORK`
+    pub services: u64,
+    /// Height of the tip of the node
+    pub best_height: u32,
+    /// Random nonce of the node, for it to detect connections to itself
+    pub nonce: u64,
+}
+
+/// Machine-readable description of one message of the peer protocol
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MessageSpec {
+    /// The tag identifying the message on the wire
+    pub tag: u32,
+    pub name: &'static str,
+    /// The Rust type of the payload, encoded with bincode
+    pub payload: &'static str,
+    pub description: &'static str,
+}
+
+/// Define the `Message` enum together with its codec and its protocol description, so the wire
+/// format and the published spec are generated from the same source.
+macro_rules! define_messages {
+    ( $( #[doc = $doc:expr] $name:ident($payload:ty), )* ) => {
+        #[derive(Serialize, Deserialize, Debug, Clone)]
+        pub enum Message {
+            $( #[doc = $doc] $name($payload), )*
+        }
+
+        /// Describe all messages of the peer protocol, in the order of their tags
+        pub fn protocol() -> Vec<MessageSpec> {
+            let names = [$( stringify!($name), )*];
+            let payloads = [$( stringify!($payload), )*];
+            let descriptions = [$( $doc.trim(), )*];
+            let mut specs: Vec<MessageSpec> = Vec::new();
+            for i in 0..names.len() {
+                specs.push(MessageSpec {
+                    tag: i as u32,
+                    name: names[i],
+                    payload: payloads[i],
+                    description: descriptions[i],
+                });
+            }
+            return specs;
+        }
+
+        /// The name of the message with tag `tag`
+        pub fn message_name(tag: u32) -> Option<&'static str> {
+            const NAMES: &[&str] = &[$( stringify!($name), )*];
+            return NAMES.get(tag as usize).copied();
+        }
+    };
+}
+
+define_messages! {
+    /// Liveness probe, answered with a `Pong` carrying the same nonce
     Ping(String),
+    /// Answer to a `Ping`
     Pong(String),
+    /// Announce the hashes of newly received blocks
     NewBlockHashes(Vec<H256>),
+    /// Request the blocks with the given hashes
     GetBlocks(Vec<H256>),
+    /// Deliver requested blocks
     Blocks(Vec<Block>),
+    /// Request the headers following the first hash of the locator found in the longest chain
+    GetHeaders(Vec<H256>),
+    /// Deliver requested headers, in chain order
+    Headers(Vec<Header>),
+    /// Announce the txids of transactions newly accepted in the mempool
+    NewTxHashes(Vec<H256>),
+    /// Request the transactions of the mempool with the given txids
+    GetTxs(Vec<H256>),
+    /// Deliver requested transactions
+    Txs(Vec<Transaction>),
+    /// First message on a connection, describing the node; others are ignored until received
+    Version(VersionInfo),
+    /// Accept the `Version` of the peer
+    Verack(()),
+    /// Request addresses of nodes to connect to
+    GetAddr(()),
+    /// Deliver addresses of nodes, at most `address_book::MAX_ADDR_PER_MESSAGE`
+    Addr(Vec<AddressRecord>),
+    /// Request a block in compact form, answered with a `CompactBlock`
+    GetCompactBlock(H256),
+    /// Deliver a block as its header and the short ids of its transactions
+    CompactBlock(CompactBlock),
+    /// Request the transactions of a compact block missing from the mempool
+    GetBlockTxn(BlockTxnRequest),
+    /// Deliver the transactions requested by a `GetBlockTxn`
+    BlockTxn(BlockTxn),
+    /// Only relay the transactions matching this Bloom filter from now on
+    FilterLoad(BloomFilter),
+    /// Add an element to the loaded Bloom filter
+    FilterAdd(Vec<u8>),
+    /// Remove the loaded Bloom filter, relaying all transactions again
+    FilterClear(()),
+    /// Request blocks filtered with the loaded Bloom filter, answered with `FilteredBlock`s
+    GetFilteredBlocks(Vec<H256>),
+    /// Deliver a block as its header and the transactions matching the filter, with their proofs
+    FilteredBlock(FilteredBlock),
+    /// Request the compact filters of a range of blocks of the longest chain
+    GetCFilters(FilterRequest),
+    /// Deliver the compact filter of a block
+    CFilter(CFilter),
+    /// Request the filter hashes of a range of blocks of the longest chain
+    GetCFHeaders(FilterRequest),
+    /// Deliver filter hashes, with the filter header preceding them
+    CFHeaders(CFHeaders),
+}
+
+impl Message {
+    /// Encode the message into its wire format: the variant tag as a little endian `u32`,
+    /// followed by the bincode encoding of the payload
+    pub fn encode(&self) -> Vec<u8> {
+        return bincode::serialize(self).unwrap();
+    }
+
+    /// Decode a message from its wire format
+    pub fn decode(bytes: &[u8]) -> bincode::Result<Message> {
+        return bincode::deserialize(bytes);
+    }
+}
+
+/// The protocol description as pretty-printed JSON
+pub fn protocol_json() -> String {
+    return serde_json::to_string_pretty(&protocol()).unwrap();
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block_filter::BlockFilter;
+    use std::convert::TryInto;
+
+    #[test]
+    fn tags_match_codec() {
+        let samples = vec![
+            Message::Ping("a".to_string()),
+            Message::Pong("b".to_string()),
+            Message::NewBlockHashes(vec![H256::default()]),
+            Message::GetBlocks(vec![]),
+            Message::Blocks(vec![]),
+            Message::GetHeaders(vec![]),
+            Message::Headers(vec![]),
+            Message::NewTxHashes(vec![]),
+            Message::GetTxs(vec![]),
+            Message::Txs(vec![]),
+            Message::Version(VersionInfo { version: PROTOCOL_VERSION, services: 0, best_height: 0, nonce: 0 }),
+            Message::Verack(()),
+            Message::GetAddr(()),
+            Message::Addr(vec![]),
+            Message::GetCompactBlock(H256::default()),
+            Message::CompactBlock(CompactBlock {
+                header: crate::blockchain::Blockchain::genesis_block().get_header().clone(),
+                nonce: 0,
+                short_ids: vec![],
+                prefilled: vec![],
+            }),
+            Message::GetBlockTxn(BlockTxnRequest { block: H256::default(), indexes: vec![] }),
+            Message::BlockTxn(BlockTxn { block: H256::default(), transactions: vec![] }),
+            Message::FilterLoad(BloomFilter::new(1, 0.1, 0, crate::bloom::BloomFlags::UpdateNone)),
+            Message::FilterAdd(vec![1]),
+            Message::FilterClear(()),
+            Message::GetFilteredBlocks(vec![]),
+            Message::FilteredBlock(FilteredBlock {
+                header: crate::blockchain::Blockchain::genesis_block().get_header().clone(),
+                transactions: vec![],
+            }),
+            Message::GetCFilters(FilterRequest { start_height: 0, stop_hash: H256::default() }),
+            Message::CFilter(CFilter { block_hash: H256::default(), filter: BlockFilter::new(&H256::default(), &[]) }),
+            Message::GetCFHeaders(FilterRequest { start_height: 0, stop_hash: H256::default() }),
+            Message::CFHeaders(CFHeaders { stop_hash: H256::default(), previous_header: H256::default(), filter_hashes: vec![] }),
+        ];
+        let specs = protocol();
+        assert_eq!(specs.len(), samples.len());
+        for (spec, msg) in specs.iter().zip(samples.iter()) {
+            let encoded = msg.encode();
+            let tag = u32::from_le_bytes(encoded[0..4].try_into().unwrap());
+            assert_eq!(spec.tag, tag);
+            assert!(format!("{:?}", msg).starts_with(spec.name));
+            assert!(Message::decode(&encoded).is_ok());
+        }
+        assert_eq!(specs[2].payload, "Vec<H256>");
+        assert_eq!(specs[0].description, "Liveness probe, answered with a `Pong` carrying the same nonce");
+    }
 }
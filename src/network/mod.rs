@@ -1,4 +1,12 @@
+pub mod address_book;
+pub mod compact;
+pub mod discovery;
+pub mod download;
+pub mod keepalive;
 pub mod message;
 pub mod peer;
+pub mod peer_manager;
+pub mod rate_limit;
 pub mod server;
+pub mod traffic;
 pub mod worker;
@@ -1,10 +1,31 @@
 use super::message;
+use super::rate_limit::RateLimiter;
+use super::traffic::{Traffic, TrafficStats};
+use crate::bloom::BloomFilter;
+use crate::crypto::hash::H256;
+use crate::transaction::Transaction;
 use log::{trace, warn};
 use mio;
 use mio_extras::channel;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::io::{Read, Write};
 use std::sync::mpsc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+/// Misbehavior score at which a peer is banned
+pub const BAN_THRESHOLD: u32 = 100;
+
+/// Largest message accepted from or sent to a peer, in bytes. A peer announcing a longer one is
+/// disconnected before its payload is buffered.
+pub const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+/// Most transactions requested from a peer and not received yet
+pub const MAX_REQUESTED_TXS: usize = 50_000;
+/// Time after which a transaction requested from a peer is no longer expected
+pub const TX_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
 
 enum DecodeState {
     Length,
@@ -23,6 +44,9 @@ pub struct ReadContext {
     msg_length: usize,
     read_length: usize,
     state: DecodeState,
+    /// The traffic of the peer, and of all peers
+    stats: Arc<Mutex<TrafficStats>>,
+    traffic: Arc<Mutex<Traffic>>,
 }
 
 impl ReadContext {
@@ -45,6 +69,12 @@ impl ReadContext {
                         DecodeState::Length => {
                             let message_length =
                                 u32::from_be_bytes(self.buffer[0..4].try_into().unwrap());
+                            if message_length as usize > MAX_MESSAGE_SIZE {
+                                return Err(std::io::Error::new(
+                                    std::io::ErrorKind::InvalidData,
+                                    format!("message of {} bytes is too long", message_length),
+                                ));
+                            }
                             self.state = DecodeState::Payload;
                             self.read_length = 0;
                             self.msg_length = message_length as usize;
@@ -60,6 +90,8 @@ impl ReadContext {
                             self.read_length = 0;
                             self.msg_length = std::mem::size_of::<u32>();
                             trace!("Received full message");
+                            self.stats.lock().unwrap().record_received(&new_payload);
+                            self.traffic.lock().unwrap().record_received(&new_payload);
                             Ok(ReadResult::Message(new_payload))
                         }
                     }
@@ -91,6 +123,9 @@ pub struct WriteContext {
     msg_length: usize,
     written_length: usize,
     state: WriteState,
+    /// The traffic of the peer, and of all peers
+    stats: Arc<Mutex<TrafficStats>>,
+    traffic: Arc<Mutex<Traffic>>,
 }
 
 impl WriteContext {
@@ -130,6 +165,8 @@ impl WriteContext {
                             },
                         };
 
+                        self.stats.lock().unwrap().record_sent(&msg);
+                        self.traffic.lock().unwrap().record_sent(&msg, Instant::now());
                         // encode the message and the length
                         self.msg_buffer = msg;
                         self.msg_length = self.msg_buffer.len();
@@ -158,7 +195,9 @@ impl WriteContext {
 pub fn new(
     stream: mio::net::TcpStream,
     direction: Direction,
+    traffic: &Arc<Mutex<Traffic>>,
 ) -> std::io::Result<(Context, Handle)> {
+    let stats = Arc::new(Mutex::new(TrafficStats::default()));
     let reader_stream = stream.try_clone()?;
     let writer_stream = stream.try_clone()?;
     let addr = stream.peer_addr()?;
@@ -169,6 +208,8 @@ pub fn new(
         msg_length: std::mem::size_of::<u32>(),
         read_length: 0,
         state: DecodeState::Length,
+        stats: Arc::clone(&stats),
+        traffic: Arc::clone(traffic),
     };
     let bufwriter = std::io::BufWriter::new(writer_stream);
     let (write_sender, write_receiver) = channel::channel();
@@ -180,10 +221,22 @@ pub fn new(
         msg_length: 0,
         written_length: 0,
         state: WriteState::Payload,
+        stats: Arc::clone(&stats),
+        traffic: Arc::clone(traffic),
     };
     let handle = Handle {
         write_queue: write_sender,
         addr,
+        direction,
+        misbehavior: Arc::new(AtomicU32::new(0)),
+        version: Arc::new(RwLock::new(None)),
+        acknowledged: Arc::new(AtomicBool::new(false)),
+        connected_at: Instant::now(),
+        ping: Arc::new(Mutex::new(PingState::default())),
+        filter: Arc::new(Mutex::new(None)),
+        limiter: Arc::new(Mutex::new(RateLimiter::new(Instant::now()))),
+        requested_txs: Arc::new(Mutex::new(HashMap::new())),
+        stats,
     };
     let ctx = Context {
         addr,
@@ -196,7 +249,7 @@ pub fn new(
     Ok((ctx, handle))
 }
 
-#[derive(Copy, Clone)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Direction {
     Incoming,
     Outgoing,
@@ -211,18 +264,273 @@ pub struct Context {
     pub direction: Direction,
 }
 
+/// The pings sent to a peer, see `network::keepalive`
+#[derive(Default)]
+struct PingState {
+    /// Nonce of the ping waiting for its pong
+    nonce: Option<String>,
+    /// When the last ping was sent
+    sent: Option<Instant>,
+    /// Round trip time of the last answered ping
+    latency: Option<Duration>,
+}
+
 #[derive(Clone)]
 pub struct Handle {
     addr: std::net::SocketAddr,
+    direction: Direction,
     write_queue: channel::Sender<Vec<u8>>,
+    misbehavior: Arc<AtomicU32>,
+    /// The `Version` sent by the peer, once received
+    version: Arc<RwLock<Option<message::VersionInfo>>>,
+    /// Whether the peer accepted our `Version`
+    acknowledged: Arc<AtomicBool>,
+    connected_at: Instant,
+    ping: Arc<Mutex<PingState>>,
+    /// The Bloom filter loaded by a light client, see `Message::FilterLoad`
+    filter: Arc<Mutex<Option<BloomFilter>>>,
+    /// The rates of the messages and announcements of the peer, see `network::rate_limit`
+    limiter: Arc<Mutex<RateLimiter>>,
+    /// The transactions requested from the peer with `GetTxs`, with the time of the request
+    requested_txs: Arc<Mutex<HashMap<H256, Instant>>>,
+    /// The bytes exchanged with the peer, see `network::traffic`
+    stats: Arc<Mutex<TrafficStats>>,
 }
 
 impl Handle {
+    pub fn addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// The `Version` sent by the peer, or None before it is received
+    pub fn get_version(&self) -> Option<message::VersionInfo> {
+        return self.version.read().unwrap().clone();
+    }
+
+    pub fn set_version(&self, version: message::VersionInfo) {
+        *self.version.write().unwrap() = Some(version);
+    }
+
+    /// Record that the peer accepted our `Version`
+    pub fn acknowledge(&self) {
+        self.acknowledged.store(true, Ordering::SeqCst);
+    }
+
+    pub fn connected_at(&self) -> Instant {
+        return self.connected_at;
+    }
+
+    /// Record a ping sent at `now`, returning its nonce. An unanswered ping is forgotten.
+    pub fn start_ping(&self, now: Instant) -> String {
+        let nonce = rand::random::<u64>().to_string();
+        let mut ping = self.ping.lock().unwrap();
+        ping.nonce = Some(nonce.clone());
+        ping.sent = Some(now);
+        return nonce;
+    }
+
+    /// When the last ping was sent, and whether it is still waiting for its pong
+    pub fn last_ping(&self) -> Option<(Instant, bool)> {
+        let ping = self.ping.lock().unwrap();
+        return ping.sent.map(|sent| (sent, ping.nonce.is_some()));
+    }
+
+    /// Record the pong of the pending ping received at `now`, returning the round trip time, or
+    /// None if `nonce` is not the one of the pending ping
+    pub fn pong(&self, nonce: &str, now: Instant) -> Option<Duration> {
+        let mut ping = self.ping.lock().unwrap();
+        if ping.nonce.as_deref() != Some(nonce) {
+            return None;
+        }
+        ping.nonce = None;
+        let latency = now.saturating_duration_since(ping.sent.unwrap());
+        ping.latency = Some(latency);
+        return Some(latency);
+    }
+
+    /// Round trip time of the last answered ping
+    pub fn get_latency(&self) -> Option<Duration> {
+        return self.ping.lock().unwrap().latency;
+    }
+
+    /// Replace the Bloom filter of the peer, None to relay everything again
+    pub fn set_filter(&self, filter: Option<BloomFilter>) {
+        *self.filter.lock().unwrap() = filter;
+    }
+
+    /// Add an element to the Bloom filter of the peer. Returns false if it has none.
+    pub fn filter_add(&self, element: &[u8]) -> bool {
+        return match self.filter.lock().unwrap().as_mut() {
+            Some(filter) => {
+                filter.insert(element);
+                true
+            }
+            None => false,
+        };
+    }
+
+    pub fn has_filter(&self) -> bool {
+        return self.filter.lock().unwrap().is_some();
+    }
+
+    /// Whether the transaction is to be relayed to the peer: it has no filter, or the transaction
+    /// matches it
+    pub fn is_relevant(&self, transaction: &Transaction) -> bool {
+        return match self.filter.lock().unwrap().as_mut() {
+            Some(filter) => filter.matches(transaction),
+            None => true,
+        };
+    }
+
+    /// Apply `f` to the Bloom filter of the peer, if it has one
+    pub fn with_filter<T>(&self, f: impl FnOnce(&mut BloomFilter) -> T) -> Option<T> {
+        return self.filter.lock().unwrap().as_mut().map(f);
+    }
+
+    /// Count a message received from the peer at `now`. Returns false if it exceeds its rate.
+    pub fn allow_message(&self, now: Instant) -> bool {
+        return self.limiter.lock().unwrap().allow_message(now);
+    }
+
+    /// Count `count` hashes announced by the peer at `now`. Returns false if they exceed its rate.
+    pub fn allow_inventory(&self, count: usize, now: Instant) -> bool {
+        return self.limiter.lock().unwrap().allow_inventory(count, now);
+    }
+
+    /// Record the transactions requested from the peer at `now`, up to `MAX_REQUESTED_TXS`
+    /// outstanding. Returns those recorded, the only ones to request.
+    pub fn request_txs(&self, txids: Vec<H256>, now: Instant) -> Vec<H256> {
+        let mut requested = self.requested_txs.lock().unwrap();
+        requested.retain(|_, at| now.saturating_duration_since(*at) < TX_REQUEST_TIMEOUT);
+        let mut recorded = Vec::new();
+        for txid in txids {
+            if requested.len() >= MAX_REQUESTED_TXS {
+                break;
+            }
+            if requested.insert(txid, now).is_none() {
+                recorded.push(txid);
+            }
+        }
+        return recorded;
+    }
+
+    /// Whether the transaction was requested from the peer, after which it is no longer expected
+    pub fn take_requested_tx(&self, txid: &H256) -> bool {
+        return self.requested_txs.lock().unwrap().remove(txid).is_some();
+    }
+
+    /// The bytes exchanged with the peer so far
+    pub fn get_traffic(&self) -> TrafficStats {
+        return self.stats.lock().unwrap().clone();
+    }
+
+    /// Whether both sides received the `Version` of the other
+    pub fn is_handshake_complete(&self) -> bool {
+        return self.acknowledged.load(Ordering::SeqCst) && self.version.read().unwrap().is_some();
+    }
+
+    /// Increase the misbehavior score of the peer. Returns whether the peer is now banned, after
+    /// which its messages are ignored and nothing more is sent to it.
+    pub fn penalize(&self, score: u32) -> bool {
+        let total = self.misbehavior.fetch_add(score, Ordering::SeqCst).saturating_add(score);
+        if total >= BAN_THRESHOLD {
+            warn!("Banning peer {} with misbehavior score {}", self.addr, total);
+            return true;
+        }
+        return false;
+    }
+
+    pub fn is_banned(&self) -> bool {
+        self.misbehavior.load(Ordering::SeqCst) >= BAN_THRESHOLD
+    }
+
     pub fn write(&self, msg: message::Message) {
         // TODO: return result
-        let buffer = bincode::serialize(&msg).unwrap();
+        if self.is_banned() {
+            return;
+        }
+        let buffer = msg.encode();
+        if buffer.len() > MAX_MESSAGE_SIZE {
+            warn!("Dropped message of {} bytes for peer {}, too long", buffer.len(), self.addr);
+            return;
+        }
         if self.write_queue.send(buffer).is_err() {
             warn!("Failed to send write request for peer {}, channel detached", self.addr);
         }
     }
 }
+
+#[cfg(any(test, test_utilities))]
+pub mod tests {
+    use super::*;
+    use super::message::Message;
+
+    /// A peer context on one end of a loopback connection, and the other end
+    pub fn connected() -> (Context, std::net::TcpStream) {
+        return connected_with(Direction::Incoming);
+    }
+
+    /// `connected`, with the connection opened in `direction`
+    pub fn connected_with(direction: Direction) -> (Context, std::net::TcpStream) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let traffic = Arc::new(Mutex::new(Traffic::new(Instant::now())));
+        let (ctx, _) = new(mio::net::TcpStream::from_stream(stream).unwrap(), direction, &traffic).unwrap();
+        return (ctx, remote);
+    }
+
+    /// Read from the peer until a message or an error, as the event loop would
+    fn next(ctx: &mut Context) -> std::io::Result<Vec<u8>> {
+        loop {
+            match ctx.reader.read() {
+                Ok(ReadResult::Message(m)) => return Ok(m),
+                Ok(ReadResult::EOF) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(ReadResult::Continue) => {}
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Flush the messages written to the peer, and read the first one from the other end
+    pub fn received(ctx: &mut Context, remote: &mut std::net::TcpStream) -> Message {
+        assert!(matches!(ctx.writer.write().unwrap(), WriteResult::Complete));
+        let mut length = [0u8; 4];
+        remote.read_exact(&mut length).unwrap();
+        let mut payload = vec![0u8; u32::from_be_bytes(length) as usize];
+        remote.read_exact(&mut payload).unwrap();
+        return Message::decode(&payload).unwrap();
+    }
+
+    #[test]
+    fn framing() {
+        let (mut ctx, mut remote) = connected();
+        // frames are a big endian length followed by the encoded message
+        let encoded = Message::Ping("hello".to_string()).encode();
+        remote.write_all(&(encoded.len() as u32).to_be_bytes()).unwrap();
+        remote.write_all(&encoded[..3]).unwrap();
+        remote.write_all(&encoded[3..]).unwrap();
+        match Message::decode(&next(&mut ctx).unwrap()).unwrap() {
+            Message::Ping(nonce) => assert_eq!(nonce, "hello"),
+            m => panic!("unexpected message {:?}", m),
+        }
+
+        // queued messages are framed the same way
+        ctx.handle.write(Message::Pong("world".to_string()));
+        assert!(matches!(received(&mut ctx, &mut remote), Message::Pong(ref n) if n == "world"));
+        let traffic = ctx.handle.get_traffic();
+        assert_eq!(traffic.received_by_message["Ping"], encoded.len() as u64 + 4);
+        assert_eq!(traffic.bytes_sent, Message::Pong("world".to_string()).encode().len() as u64 + 4);
+
+        // an overlong message is refused before its payload arrives
+        remote.write_all(&((MAX_MESSAGE_SIZE + 1) as u32).to_be_bytes()).unwrap();
+        assert_eq!(next(&mut ctx).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+}
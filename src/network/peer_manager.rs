@@ -0,0 +1,196 @@
+//! The peers the server is connected to, each with an id, within limits on the number of
+//! connections they opened and the number we opened
+
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use super::message::VersionInfo;
+use super::peer::{self, Direction};
+use super::traffic::TrafficStats;
+
+/// Identifier of a connection, never reused while the node runs
+pub type PeerId = u64;
+
+/// Most connections accepted from peers, and most opened to peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerLimits {
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+}
+
+impl Default for PeerLimits {
+    fn default() -> Self {
+        return PeerLimits {
+            max_inbound: 117,
+            max_outbound: 8,
+        };
+    }
+}
+
+/// What the rest of the node sees of a connected peer
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub id: PeerId,
+    pub addr: SocketAddr,
+    pub direction: Direction,
+    /// The `Version` sent by the peer, None until the handshake
+    pub version: Option<VersionInfo>,
+    pub banned: bool,
+    /// Round trip time of the last answered ping
+    pub latency: Option<Duration>,
+    /// The bytes exchanged with the peer
+    pub traffic: TrafficStats,
+}
+
+/// Reasons for a connection to be refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerError {
+    /// All the slots of this direction are taken
+    NoSlot(Direction),
+    AlreadyConnected(SocketAddr),
+}
+
+impl std::fmt::Display for PeerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PeerError::NoSlot(Direction::Incoming) => write!(f, "no inbound connection slot left"),
+            PeerError::NoSlot(Direction::Outgoing) => write!(f, "no outbound connection slot left"),
+            PeerError::AlreadyConnected(addr) => write!(f, "already connected to {}", addr),
+        }
+    }
+}
+
+impl From<PeerError> for std::io::Error {
+    fn from(e: PeerError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+    }
+}
+
+/// The connected peers, by id
+pub struct PeerManager {
+    limits: PeerLimits,
+    next_id: PeerId,
+    peers: BTreeMap<PeerId, peer::Handle>,
+}
+
+impl PeerManager {
+    pub fn new(limits: PeerLimits) -> Self {
+        return PeerManager {
+            limits,
+            next_id: 0,
+            peers: BTreeMap::new(),
+        };
+    }
+
+    pub fn get_limits(&self) -> PeerLimits {
+        return self.limits;
+    }
+
+    /// Number of connected peers in `direction`
+    pub fn count(&self, direction: Direction) -> usize {
+        return self.peers.values().filter(|p| p.direction() == direction).count();
+    }
+
+    /// Number of connections that may still be made in `direction`
+    pub fn free_slots(&self, direction: Direction) -> usize {
+        let max = match direction {
+            Direction::Incoming => self.limits.max_inbound,
+            Direction::Outgoing => self.limits.max_outbound,
+        };
+        return max.saturating_sub(self.count(direction));
+    }
+
+    /// Whether a connection in `direction` with a peer at `addr` would be accepted by `add`
+    pub fn check(&self, addr: SocketAddr, direction: Direction) -> Result<(), PeerError> {
+        if self.find(addr).is_some() {
+            return Err(PeerError::AlreadyConnected(addr));
+        }
+        if self.free_slots(direction) == 0 {
+            return Err(PeerError::NoSlot(direction));
+        }
+        return Ok(());
+    }
+
+    /// Take a slot for a new connection, returning its id
+    pub fn add(&mut self, peer: peer::Handle) -> Result<PeerId, PeerError> {
+        self.check(peer.addr(), peer.direction())?;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.peers.insert(id, peer);
+        return Ok(id);
+    }
+
+    /// Free the slot of a connection
+    pub fn remove(&mut self, id: PeerId) -> Option<peer::Handle> {
+        return self.peers.remove(&id);
+    }
+
+    pub fn get(&self, id: PeerId) -> Option<&peer::Handle> {
+        return self.peers.get(&id);
+    }
+
+    /// The id of the connection with the peer at `addr`
+    pub fn find(&self, addr: SocketAddr) -> Option<PeerId> {
+        return self.peers.iter().find(|(_, p)| p.addr() == addr).map(|(id, _)| *id);
+    }
+
+    /// The connected peers, by id
+    pub fn handles(&self) -> impl Iterator<Item = (PeerId, &peer::Handle)> {
+        return self.peers.iter().map(|(id, p)| (*id, p));
+    }
+
+    pub fn list(&self) -> Vec<PeerInfo> {
+        return self
+            .peers
+            .iter()
+            .map(|(id, p)| PeerInfo {
+                id: *id,
+                addr: p.addr(),
+                direction: p.direction(),
+                version: p.get_version(),
+                banned: p.is_banned(),
+                latency: p.get_latency(),
+                traffic: p.get_traffic(),
+            })
+            .collect();
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use super::super::peer::tests::connected_with;
+
+    #[test]
+    fn slots() {
+        let mut manager = PeerManager::new(PeerLimits { max_inbound: 1, max_outbound: 2 });
+        let (incoming, _r1) = connected_with(Direction::Incoming);
+        let (second, _r2) = connected_with(Direction::Incoming);
+        let (outgoing, _r3) = connected_with(Direction::Outgoing);
+
+        let first = manager.add(incoming.handle.clone()).unwrap();
+        assert_eq!(manager.add(incoming.handle.clone()), Err(PeerError::AlreadyConnected(incoming.addr)));
+        assert_eq!(manager.add(second.handle.clone()), Err(PeerError::NoSlot(Direction::Incoming)));
+        let id = manager.add(outgoing.handle.clone()).unwrap();
+        assert_ne!(id, first);
+        assert_eq!(manager.count(Direction::Outgoing), 1);
+        assert_eq!(manager.free_slots(Direction::Outgoing), 1);
+        assert_eq!(manager.free_slots(Direction::Incoming), 0);
+        assert_eq!(manager.find(outgoing.addr), Some(id));
+
+        let list = manager.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[1].id, id);
+        assert_eq!(list[1].direction, Direction::Outgoing);
+        assert_eq!(list[1].version, None);
+
+        // a freed slot is taken again, under a new id
+        assert!(manager.remove(first).is_some());
+        assert!(manager.get(first).is_none());
+        let id = manager.add(second.handle.clone()).unwrap();
+        assert!(id > first);
+        assert_eq!(manager.handles().count(), 2);
+    }
+}
@@ -0,0 +1,101 @@
+//! Limits on what a peer may send: its messages and the hashes it announces are counted against
+//! token buckets refilled at a steady rate, so that bursts pass but a sustained flood is dropped
+//! and penalized instead of filling the queues of the node.
+
+use std::time::Instant;
+
+/// Messages a peer may send per second, sustained
+pub const MAX_MESSAGES_PER_SECOND: f64 = 100.0;
+/// Messages a peer may send at once
+pub const MESSAGE_BURST: f64 = 1000.0;
+/// Hashes a peer may announce per second with `NewBlockHashes` and `NewTxHashes`, sustained
+pub const MAX_INVENTORY_PER_SECOND: f64 = 1000.0;
+/// Hashes a peer may announce at once
+pub const INVENTORY_BURST: f64 = 10_000.0;
+/// Most hashes in one inventory message or request
+pub const MAX_INVENTORY_PER_MESSAGE: usize = 50_000;
+/// Misbehavior score of a message dropped for exceeding the rate of the peer
+pub const FLOOD_PENALTY: u32 = 1;
+
+/// Tokens refilled at `rate` per second, up to `capacity`
+struct TokenBucket {
+    tokens: f64,
+    capacity: f64,
+    rate: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, rate: f64, now: Instant) -> Self {
+        return TokenBucket { tokens: capacity, capacity, rate, last: now };
+    }
+
+    /// Take `amount` tokens at `now`, if there are enough
+    fn take(&mut self, amount: f64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = self.last.max(now);
+        if self.tokens < amount {
+            return false;
+        }
+        self.tokens -= amount;
+        return true;
+    }
+}
+
+/// The rates of a peer
+pub struct RateLimiter {
+    messages: TokenBucket,
+    inventory: TokenBucket,
+}
+
+impl RateLimiter {
+    pub fn new(now: Instant) -> Self {
+        return RateLimiter {
+            messages: TokenBucket::new(MESSAGE_BURST, MAX_MESSAGES_PER_SECOND, now),
+            inventory: TokenBucket::new(INVENTORY_BURST, MAX_INVENTORY_PER_SECOND, now),
+        };
+    }
+
+    /// Count a message received at `now`. Returns false if it exceeds the rate of the peer.
+    pub fn allow_message(&mut self, now: Instant) -> bool {
+        return self.messages.take(1.0, now);
+    }
+
+    /// Count `count` hashes announced at `now`. Returns false if they exceed the rate of the peer,
+    /// in which case none of them is counted.
+    pub fn allow_inventory(&mut self, count: usize, now: Instant) -> bool {
+        return self.inventory.take(count as f64, now);
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn buckets() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(now);
+        // a burst passes, then the rate applies
+        for _ in 0..MESSAGE_BURST as usize {
+            assert!(limiter.allow_message(now));
+        }
+        assert!(!limiter.allow_message(now));
+        assert!(!limiter.allow_message(now + Duration::from_millis(5)));
+        assert!(limiter.allow_message(now + Duration::from_millis(20)));
+        // the bucket does not fill beyond the burst
+        let later = now + Duration::from_secs(3600);
+        for _ in 0..MESSAGE_BURST as usize {
+            assert!(limiter.allow_message(later));
+        }
+        assert!(!limiter.allow_message(later));
+
+        // refused hashes are not counted
+        assert!(!limiter.allow_inventory(INVENTORY_BURST as usize + 1, now));
+        assert!(limiter.allow_inventory(INVENTORY_BURST as usize, now));
+        assert!(!limiter.allow_inventory(1, now));
+        assert!(limiter.allow_inventory(MAX_INVENTORY_PER_SECOND as usize, now + Duration::from_secs(1)));
+    }
+}
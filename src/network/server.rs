@@ -1,31 +1,50 @@
+//! The P2P server: a single mio event loop reads and writes the sockets of all peers, so the
+//! number of connections is bounded by the peer limits and file descriptors, not by threads.
+//! Complete messages are handed to the worker pool over a channel; the `Handle` is the blocking
+//! API the rest of the node uses to connect, broadcast and disconnect.
+
 use super::message;
 use super::peer::{self, ReadResult, WriteResult};
+use super::peer_manager::{PeerId, PeerInfo, PeerLimits, PeerManager};
+use super::rate_limit;
+use super::traffic::{Traffic, TrafficReport};
 use crossbeam::channel as cbchannel;
 use log::{debug, error, info, trace, warn};
 use mio::{self, net};
 use mio_extras::channel;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
-const MAX_INCOMING_CLIENT: usize = 256;
 const MAX_EVENT: usize = 1024;
+/// Longest wait for an outgoing connection to be established
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub fn new(
     addr: std::net::SocketAddr,
+    limits: PeerLimits,
     msg_sink: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = channel::channel();
+    let manager = Arc::new(RwLock::new(PeerManager::new(limits)));
+    let traffic = Arc::new(Mutex::new(Traffic::new(Instant::now())));
     let handle = Handle {
         control_chan: control_signal_sender,
+        nonce: rand::random(),
+        manager: Arc::clone(&manager),
+        traffic: Arc::clone(&traffic),
+        disconnections: Arc::new(Mutex::new(Vec::new())),
     };
     let ctx = Context {
         peers: slab::Slab::new(),
         peer_list: vec![],
+        manager,
+        traffic,
         addr,
         poll: mio::Poll::new()?,
         control_chan: control_signal_receiver,
         new_msg_chan: msg_sink,
-        _handle: handle.clone(),
+        handle: handle.clone(),
     };
     Ok((ctx, handle))
 }
@@ -33,11 +52,15 @@ pub fn new(
 pub struct Context {
     peers: slab::Slab<peer::Context>,
     peer_list: Vec<usize>,
+    /// The slots and ids of the peers
+    manager: Arc<RwLock<PeerManager>>,
+    /// The bytes exchanged with all peers
+    traffic: Arc<Mutex<Traffic>>,
     addr: std::net::SocketAddr,
     poll: mio::Poll,
     control_chan: channel::Receiver<ControlSignal>,
     new_msg_chan: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
-    _handle: Handle,
+    handle: Handle,
 }
 
 impl Context {
@@ -57,29 +80,25 @@ impl Context {
         stream: net::TcpStream,
         direction: peer::Direction,
     ) -> std::io::Result<peer::Handle> {
+        // refuse the peer if there is no slot left for it
+        self.manager.read().unwrap().check(stream.peer_addr()?, direction)?;
+
         // get a new slot in the connection set
         let vacant = self.peers.vacant_entry();
         let key: usize = vacant.key();
-        if key >= MAX_INCOMING_CLIENT {
-            // too many connections
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "max peer reached, cannot accept new connections",
-            ));
-        }
 
         // set two tokens, one for socket and one for write queue
         let socket_token = mio::Token(key * 2);
         let writer_token = mio::Token(key * 2 + 1);
 
         // register the new connection
+        let (ctx, handle) = peer::new(stream, direction, &self.traffic)?;
         self.poll.register(
-            &stream,
+            &ctx.stream,
             socket_token,
             mio::Ready::readable(),
             mio::PollOpt::edge(),
         )?;
-        let (ctx, handle) = peer::new(stream, direction)?;
 
         // register the writer queue
         self.poll.register(
@@ -90,18 +109,31 @@ impl Context {
         )?;
 
         // insert the context and return the handle
+        let id = self.manager.write().unwrap().add(handle.clone())?;
         vacant.insert(ctx);
         // record the key of this peer
         self.peer_list.push(key);
-        trace!("Registering peer with event token={}", key);
+        trace!("Registering peer {} with event token={}", id, key);
         Ok(handle)
     }
 
-    /// Connect to a peer, and register this peer
-    fn connect(&mut self, addr: &std::net::SocketAddr) -> std::io::Result<peer::Handle> {
-        // we need to estabilsh a stdlib tcp stream, since we need it to block
-        debug!("Establishing connection to peer {}", addr);
-        let stream = std::net::TcpStream::connect(addr)?;
+    /// Drop the connection of a peer and free its slot
+    fn remove(&mut self, peer_id: usize) {
+        let peer = self.peers.remove(peer_id);
+        let _ = peer.stream.shutdown(std::net::Shutdown::Both);
+        let mut manager = self.manager.write().unwrap();
+        if let Some(id) = manager.find(peer.addr) {
+            manager.remove(id);
+        }
+        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
+        self.peer_list.swap_remove(index);
+        self.handle.disconnections.lock().unwrap().retain(|subscriber| subscriber.send(peer.addr).is_ok());
+    }
+
+    /// Register an outgoing connection, established by `Handle::connect` off the event loop
+    fn connect(&mut self, addr: &std::net::SocketAddr, stream: std::net::TcpStream) -> std::io::Result<peer::Handle> {
+        // the slots may have filled up while connecting
+        self.manager.read().unwrap().check(*addr, peer::Direction::Outgoing)?;
         let mio_stream = net::TcpStream::from_stream(stream)?;
         self.register(mio_stream, peer::Direction::Outgoing)
     }
@@ -128,13 +160,24 @@ impl Context {
         match req {
             ControlSignal::ConnectNewPeer(req) => {
                 trace!("Processing ConnectNewPeer command");
-                let handle = self.connect(&req.addr);
+                let handle = self.connect(&req.addr, req.stream);
                 req.result_chan.send(handle).unwrap();
             }
             ControlSignal::BroadcastMessage(msg) => {
                 trace!("Processing BroadcastMessage command");
-                for peer_id in &self.peer_list {
-                    self.peers[*peer_id].handle.write(msg.clone());
+                for (_, peer) in self.manager.read().unwrap().handles() {
+                    // peers only get messages once they know who we are
+                    if peer.is_handshake_complete() {
+                        peer.write(msg.clone());
+                    }
+                }
+            }
+            ControlSignal::Disconnect(addr) => {
+                trace!("Processing Disconnect command");
+                let found = self.peer_list.iter().find(|&&id| self.peers[id].addr == addr).cloned();
+                if let Some(peer_id) = found {
+                    info!("Disconnecting peer {}", addr);
+                    self.remove(peer_id);
                 }
             }
         }
@@ -164,9 +207,7 @@ impl Context {
                 Ok(ReadResult::EOF) => {
                     // EOF, remove it from the connections set
                     info!("Peer {} dropped connection", peer.addr);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    self.remove(peer_id);
                     break;
                 }
                 Ok(ReadResult::Continue) => {
@@ -176,6 +217,16 @@ impl Context {
                 }
                 Ok(ReadResult::Message(m)) => {
                     trace!("Peer {} yield message", peer_id);
+                    // a flooding peer is dropped before its messages reach the workers
+                    if !peer.handle.allow_message(Instant::now()) {
+                        debug!("Dropped message from peer {}, over its rate", peer.addr);
+                        if peer.handle.penalize(rate_limit::FLOOD_PENALTY) {
+                            info!("Peer {} banned, disconnecting", peer.addr);
+                            self.remove(peer_id);
+                            break;
+                        }
+                        continue;
+                    }
                     // we just received a full message
                     self.new_msg_chan.send((m, peer.handle.clone())).unwrap();
                     continue;
@@ -187,9 +238,7 @@ impl Context {
                         break;
                     } else {
                         warn!("Error reading peer {}, disconnecting: {}", peer.addr, e);
-                        self.peers.remove(peer_id);
-                        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                        self.peer_list.swap_remove(index);
+                        self.remove(peer_id);
                         break;
                     }
                 }
@@ -224,9 +273,7 @@ impl Context {
             Ok(WriteResult::EOF) => {
                 // EOF, remove it from the connections set
                 info!("Peer {} dropped connection", peer.addr);
-                self.peers.remove(peer_id);
-                let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                self.peer_list.swap_remove(index);
+                self.remove(peer_id);
             }
             Ok(WriteResult::ChanClosed) => {
                 // the channel is closed. no more writes.
@@ -246,9 +293,7 @@ impl Context {
                 // socket is not ready anymore, stop reading
                 } else {
                     warn!("Error writing peer {}, disconnecting: {}", peer.addr, e);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    self.remove(peer_id);
                 }
             }
         }
@@ -367,13 +412,25 @@ impl Context {
 #[derive(Clone)]
 pub struct Handle {
     control_chan: channel::Sender<ControlSignal>,
+    /// Random nonce sent in our `Version`, to detect connections to ourselves
+    nonce: u64,
+    manager: Arc<RwLock<PeerManager>>,
+    traffic: Arc<Mutex<Traffic>>,
+    /// Receivers of the addresses of the peers removed, see `subscribe_disconnections`
+    disconnections: Arc<Mutex<Vec<cbchannel::Sender<std::net::SocketAddr>>>>,
 }
 
 impl Handle {
+    /// Connect to a peer, and register this peer. The connection is established on the calling
+    /// thread, waiting at most `CONNECT_TIMEOUT`, so that the event loop never blocks on it.
     pub fn connect(&self, addr: std::net::SocketAddr) -> std::io::Result<peer::Handle> {
+        debug!("Establishing connection to peer {}", addr);
+        self.manager.read().unwrap().check(addr, peer::Direction::Outgoing)?;
+        let stream = std::net::TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
         let (sender, receiver) = cbchannel::unbounded();
         let request = ConnectRequest {
             addr,
+            stream,
             result_chan: sender,
         };
         self.control_chan
@@ -387,14 +444,80 @@ impl Handle {
             .send(ControlSignal::BroadcastMessage(msg))
             .unwrap();
     }
+
+    /// Drop the connection to the peer at `addr`, if any
+    pub fn disconnect(&self, addr: std::net::SocketAddr) {
+        self.control_chan
+            .send(ControlSignal::Disconnect(addr))
+            .unwrap();
+    }
+
+    pub fn nonce(&self) -> u64 {
+        return self.nonce;
+    }
+
+    /// Number of connections that may still be made in `direction`
+    pub fn free_slots(&self, direction: peer::Direction) -> usize {
+        return self.manager.read().unwrap().free_slots(direction);
+    }
+
+    /// Whether a peer at `addr` is connected
+    pub fn is_connected(&self, addr: std::net::SocketAddr) -> bool {
+        return self.manager.read().unwrap().find(addr).is_some();
+    }
+
+    /// The handles of the connected peers
+    pub fn peer_handles(&self) -> Vec<peer::Handle> {
+        return self.manager.read().unwrap().handles().map(|(_, p)| p.clone()).collect();
+    }
+
+    /// The connected peers
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        return self.manager.read().unwrap().list();
+    }
+
+    /// The bytes exchanged with all peers, and the state of the upload target
+    pub fn traffic(&self) -> TrafficReport {
+        return self.traffic.lock().unwrap().report(Instant::now());
+    }
+
+    /// Limit the bytes sent per `traffic::UPLOAD_TARGET_TIMEFRAME`, None to remove the limit
+    pub fn set_upload_target(&self, target: Option<u64>) {
+        self.traffic.lock().unwrap().set_upload_target(target);
+    }
+
+    /// Whether historical blocks are no longer to be served, see `traffic::HISTORICAL_BLOCK_DEPTH`
+    pub fn is_upload_target_reached(&self) -> bool {
+        return self.traffic.lock().unwrap().is_upload_target_reached(Instant::now());
+    }
+
+    /// Receive the address of each peer removed from now on, to drop the state kept for it
+    pub fn subscribe_disconnections(&self) -> cbchannel::Receiver<std::net::SocketAddr> {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.disconnections.lock().unwrap().push(sender);
+        return receiver;
+    }
+
+    /// Drop the connection with id `id`. Returns whether it was connected.
+    pub fn disconnect_peer(&self, id: PeerId) -> bool {
+        let addr = match self.manager.read().unwrap().get(id) {
+            Some(peer) => peer.addr(),
+            None => return false,
+        };
+        self.disconnect(addr);
+        return true;
+    }
 }
 
 enum ControlSignal {
     ConnectNewPeer(ConnectRequest),
     BroadcastMessage(message::Message),
+    Disconnect(std::net::SocketAddr),
 }
 
 struct ConnectRequest {
     addr: std::net::SocketAddr,
+    /// The connection to the peer, already established
+    stream: std::net::TcpStream,
     result_chan: cbchannel::Sender<std::io::Result<peer::Handle>>,
 }
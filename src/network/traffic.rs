@@ -0,0 +1,165 @@
+//! Accounting of the bytes exchanged with peers, per peer and per message, and the upload
+//! target: once the bytes sent within a timeframe reach it, historical blocks are no longer
+//! served, so that a node on a metered connection keeps relaying new blocks and transactions.
+
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+
+use super::message;
+
+/// Timeframe of the upload target
+pub const UPLOAD_TARGET_TIMEFRAME: Duration = Duration::from_secs(24 * 60 * 60);
+/// Depth from which a block is historical, and not served once the upload target is reached
+pub const HISTORICAL_BLOCK_DEPTH: u32 = 144;
+/// Bytes of the length prefix framing each message
+const FRAME_OVERHEAD: u64 = 4;
+
+/// Name of the message of an encoded payload, from its tag
+fn message_name(payload: &[u8]) -> &'static str {
+    let tag = match payload.get(..4) {
+        Some(bytes) => u32::from_le_bytes(bytes.try_into().unwrap()),
+        None => return "unknown",
+    };
+    return message::message_name(tag).unwrap_or("unknown");
+}
+
+/// Bytes sent and received, framing included
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Bytes sent by message name
+    pub sent_by_message: BTreeMap<String, u64>,
+    /// Bytes received by message name
+    pub received_by_message: BTreeMap<String, u64>,
+}
+
+impl TrafficStats {
+    /// Count an encoded message sent, returning its bytes on the wire
+    pub fn record_sent(&mut self, payload: &[u8]) -> u64 {
+        let bytes = payload.len() as u64 + FRAME_OVERHEAD;
+        self.bytes_sent += bytes;
+        *self.sent_by_message.entry(message_name(payload).to_string()).or_insert(0) += bytes;
+        return bytes;
+    }
+
+    /// Count an encoded message received, returning its bytes on the wire
+    pub fn record_received(&mut self, payload: &[u8]) -> u64 {
+        let bytes = payload.len() as u64 + FRAME_OVERHEAD;
+        self.bytes_received += bytes;
+        *self.received_by_message.entry(message_name(payload).to_string()).or_insert(0) += bytes;
+        return bytes;
+    }
+}
+
+/// What the node reports of its traffic
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TrafficReport {
+    /// The bytes exchanged with all peers since the start
+    pub totals: TrafficStats,
+    /// Most bytes to send per `UPLOAD_TARGET_TIMEFRAME`, None for no target
+    pub upload_target: Option<u64>,
+    /// Bytes sent in the current timeframe
+    pub sent_in_timeframe: u64,
+    pub upload_target_reached: bool,
+}
+
+/// The traffic of all peers, with the upload target
+pub struct Traffic {
+    totals: TrafficStats,
+    upload_target: Option<u64>,
+    timeframe_start: Instant,
+    sent_in_timeframe: u64,
+}
+
+impl Traffic {
+    pub fn new(now: Instant) -> Self {
+        return Traffic {
+            totals: TrafficStats::default(),
+            upload_target: None,
+            timeframe_start: now,
+            sent_in_timeframe: 0,
+        };
+    }
+
+    /// Start a new timeframe if the current one is over at `now`
+    fn roll(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.timeframe_start) >= UPLOAD_TARGET_TIMEFRAME {
+            self.timeframe_start = now;
+            self.sent_in_timeframe = 0;
+        }
+    }
+
+    pub fn record_sent(&mut self, payload: &[u8], now: Instant) {
+        self.roll(now);
+        self.sent_in_timeframe += self.totals.record_sent(payload);
+    }
+
+    pub fn record_received(&mut self, payload: &[u8]) {
+        self.totals.record_received(payload);
+    }
+
+    pub fn get_totals(&self) -> &TrafficStats {
+        return &self.totals;
+    }
+
+    /// Limit the bytes sent per `UPLOAD_TARGET_TIMEFRAME`, None to remove the limit
+    pub fn set_upload_target(&mut self, target: Option<u64>) {
+        self.upload_target = target;
+    }
+
+    /// Whether the bytes sent in the timeframe of `now` reached the upload target
+    pub fn is_upload_target_reached(&mut self, now: Instant) -> bool {
+        self.roll(now);
+        return self.upload_target.map_or(false, |target| self.sent_in_timeframe >= target);
+    }
+
+    pub fn report(&mut self, now: Instant) -> TrafficReport {
+        let upload_target_reached = self.is_upload_target_reached(now);
+        return TrafficReport {
+            totals: self.totals.clone(),
+            upload_target: self.upload_target,
+            sent_in_timeframe: self.sent_in_timeframe,
+            upload_target_reached,
+        };
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use super::message::Message;
+
+    #[test]
+    fn accounting() {
+        let now = Instant::now();
+        let mut traffic = Traffic::new(now);
+        let ping = Message::Ping("hello".to_string()).encode();
+        let pong = Message::Pong("hello".to_string()).encode();
+        traffic.record_sent(&ping, now);
+        traffic.record_sent(&ping, now);
+        traffic.record_received(&pong);
+        traffic.record_received(&[1, 2]);
+        let totals = traffic.get_totals();
+        assert_eq!(totals.bytes_sent, 2 * (ping.len() as u64 + 4));
+        assert_eq!(totals.sent_by_message["Ping"], totals.bytes_sent);
+        assert_eq!(totals.received_by_message["Pong"], pong.len() as u64 + 4);
+        assert_eq!(totals.received_by_message["unknown"], 6);
+        assert_eq!(totals.bytes_received, pong.len() as u64 + 10);
+        let sent = totals.bytes_sent;
+
+        // the target applies to the bytes sent in the current timeframe
+        assert!(!traffic.is_upload_target_reached(now));
+        traffic.set_upload_target(Some(sent + 1));
+        assert!(!traffic.is_upload_target_reached(now));
+        traffic.record_sent(&ping, now);
+        assert!(traffic.report(now).upload_target_reached);
+        let later = now + UPLOAD_TARGET_TIMEFRAME;
+        let report = traffic.report(later);
+        assert!(!report.upload_target_reached);
+        assert_eq!(report.sent_in_timeframe, 0);
+        assert_eq!(report.totals.bytes_sent, 3 * (ping.len() as u64 + 4));
+    }
+}
@@ -1,103 +1,1376 @@
 use crossbeam::channel;
-use log::{debug, warn};
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::net::SocketAddr;
 use std::thread;
-use std::sync::{Arc, Mutex};
-use std::rc::Rc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 use log::error;
 
-use super::message::Message;
+use super::address_book::{self, AddressBook};
+use super::compact::{BlockTxn, BlockTxnRequest, CompactBlock, PartialBlock};
+use super::download::{self, Downloader};
+use super::message::{self, Message, VersionInfo};
 use super::peer;
+use super::rate_limit;
+use super::traffic;
 use crate::network::server::Handle as ServerHandle;
-use crate::blockchain::Blockchain;
-use crate::block::Block;
-use crate::crypto::hash::H256;
+use crate::blockchain::{Blockchain, InsertError};
+use crate::mempool::{Mempool, MempoolError};
+use crate::transaction::Transaction;
+use crate::block::{Block, Header};
+use crate::block_filter::{self, CFHeaders, CFilter, FilterRequest};
+use crate::bloom::{self, FilteredBlock};
+use crate::headers::HeaderError;
+use crate::crypto::hash::{H256, Hashable};
+use crate::runtime::ThreadPool;
+use crate::snapshot::HistoryValidator;
+use crate::validation::{self, TxError};
+
+/// Maximum number of headers sent in answer to a `GetHeaders`
+pub const MAX_HEADERS: u32 = 2000;
+/// Time between two checks for peers stalling the block download
+const STALL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Misbehavior score of a message received before the `Version` of the peer
+const EARLY_MESSAGE_PENALTY: u32 = 10;
+/// Misbehavior score of a Bloom filter message out of limits or without a loaded filter
+const FILTER_PENALTY: u32 = 100;
+/// Misbehavior score of an `Addr` message with too many addresses
+const OVERSIZED_ADDR_PENALTY: u32 = 20;
+/// Misbehavior score of an inventory message or request with too many hashes
+const OVERSIZED_INVENTORY_PENALTY: u32 = 20;
+/// Misbehavior score of an announcement dropped for exceeding the inventory rate of the peer
+const INVENTORY_FLOOD_PENALTY: u32 = 5;
+/// Misbehavior score of a transaction sent without being requested
+const UNSOLICITED_TX_PENALTY: u32 = 5;
+/// Misbehavior score of a transaction breaking the consensus rules
+const INVALID_TX_PENALTY: u32 = 10;
+/// Most bytes of blocks in one `Blocks` response, well below the size of a message. The blocks
+/// left out are requested again.
+const MAX_BLOCKS_RESPONSE_SIZE: usize = peer::MAX_MESSAGE_SIZE / 2;
+/// Number of blocks of the history of a snapshot requested at a time
+const HISTORY_BATCH: usize = 16;
+
+/// The items of a request without duplicates, in their order
+fn unique<T: Hash + Eq + Copy>(items: Vec<T>) -> Vec<T> {
+    let mut seen = HashSet::new();
+    return items.into_iter().filter(|item| seen.insert(*item)).collect();
+}
+
+/// Whether a transaction refused for `e` breaks the consensus rules whatever the state of the
+/// node, rather than spending outputs it does not know or being refused by its policy
+fn is_invalid_transaction(e: &MempoolError) -> bool {
+    return match e {
+        MempoolError::Invalid(TxError::MissingInput(_)) | MempoolError::Invalid(TxError::NotFinal) => false,
+        MempoolError::Invalid(TxError::Unverified(_)) => false,
+        MempoolError::Invalid(_) => true,
+        _ => false,
+    };
+}
+
+/// Whether `count` hashes sent by `peer` are within its limits, penalizing it otherwise. The
+/// hashes it announces also count against its inventory rate.
+fn check_inventory(peer: &peer::Handle, count: usize, announced: bool) -> bool {
+    if count > rate_limit::MAX_INVENTORY_PER_MESSAGE {
+        peer.penalize(OVERSIZED_INVENTORY_PENALTY);
+        return false;
+    }
+    if announced && !peer.allow_inventory(count, Instant::now()) {
+        debug!("Dropped {} hashes announced by {}, over its rate", count, peer.addr());
+        peer.penalize(INVENTORY_FLOOD_PENALTY);
+        return false;
+    }
+    return true;
+}
+
+/// The blocks of the longest chain from the start height of `request` to its stop block, or
+/// nothing if the stop block is not in the longest chain or the range is longer than `max`
+fn filter_range(blockchain: &Blockchain, request: &FilterRequest, max: u32) -> Vec<H256> {
+    if !blockchain.is_in_longest_chain(&request.stop_hash) {
+        return Vec::new();
+    }
+    let stop = blockchain.height_of(&request.stop_hash).unwrap();
+    if request.start_height > stop || stop - request.start_height >= max {
+        return Vec::new();
+    }
+    return (request.start_height..=stop).filter_map(|h| blockchain.block_at_height(h)).collect();
+}
+
+/// Whether the block is buried deep enough not to be served once the upload target is reached
+fn is_historical(blockchain: &Blockchain, hash: &H256) -> bool {
+    return match blockchain.height_of(hash) {
+        Some(height) => blockchain.tip_height().saturating_sub(height) >= traffic::HISTORICAL_BLOCK_DEPTH,
+        None => false,
+    };
+}
+
+/// The block `hash` with its transactions, if it is known and was not pruned
+fn servable_block(blockchain: &Blockchain, hash: &H256) -> Option<Block> {
+    if !blockchain.find(hash) {
+        return None;
+    }
+    let block = blockchain.get(hash);
+    if block.is_pruned() {
+        return None;
+    }
+    return Some(block);
+}
+
+/// The `Version` describing this node to its peers. A pruning node does not claim to serve the
+/// whole chain.
+pub fn local_version(blockchain: &Blockchain, server: &ServerHandle) -> VersionInfo {
+    let network = if blockchain.prune_depth().is_some() || blockchain.pruned_height() > 0 {
+        message::SERVICE_NETWORK_LIMITED
+    } else {
+        message::SERVICE_NETWORK
+    };
+    return VersionInfo {
+        version: message::PROTOCOL_VERSION,
+        services: network | message::SERVICE_BLOOM | message::SERVICE_COMPACT_FILTERS,
+        best_height: blockchain.tip_height(),
+        nonce: server.nonce(),
+    };
+}
 
 #[derive(Clone)]
 pub struct Context {
     msg_chan: channel::Receiver<(Vec<u8>, peer::Handle)>,
-    num_worker: usize,
+    pool: ThreadPool,
     server: ServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
+    blockchain: Arc<RwLock<Blockchain>>,
+    mempool: Arc<RwLock<Mempool>>,
+    address_book: Arc<RwLock<AddressBook>>,
+    /// The blocks requested from peers during sync
+    downloader: Arc<Mutex<Downloader>>,
+    /// Compact blocks waiting for the transactions missing from the mempool, with the time
+    /// they were requested
+    partial_blocks: Arc<Mutex<HashMap<H256, (PartialBlock, Instant)>>>,
+    /// The replay of the history of the UTXO snapshot the chain started from, until it is done
+    history: Arc<Mutex<Option<HistoryValidation>>>,
+    /// The addresses of the peers removed by the server
+    disconnections: channel::Receiver<SocketAddr>,
+}
+
+/// The blocks of the history of a snapshot are downloaded in the background and replayed, and
+/// whether they lead to its UTXO set is sent on `outcome`
+struct HistoryValidation {
+    validator: HistoryValidator,
+    outcome: channel::Sender<bool>,
+    /// The blocks requested, with the time of the request
+    requested: Vec<H256>,
+    requested_at: Option<Instant>,
 }
 
 pub fn new(
-    num_worker: usize,
+    pool: &ThreadPool,
     msg_src: channel::Receiver<(Vec<u8>, peer::Handle)>,
     server: &ServerHandle,
-    blockchain: &Arc<Mutex<Blockchain>>
+    blockchain: &Arc<RwLock<Blockchain>>,
+    mempool: &Arc<RwLock<Mempool>>,
+    address_book: &Arc<RwLock<AddressBook>>,
 ) -> Context {
     Context {
         msg_chan: msg_src,
-        num_worker,
+        pool: pool.clone(),
         server: server.clone(),
         blockchain: Arc::clone(blockchain),
+        mempool: Arc::clone(mempool),
+        address_book: Arc::clone(address_book),
+        downloader: Arc::new(Mutex::new(Downloader::new())),
+        partial_blocks: Arc::new(Mutex::new(HashMap::new())),
+        history: Arc::new(Mutex::new(None)),
+        disconnections: server.subscribe_disconnections(),
     }
 }
 
 impl Context {
+    /// Download and replay the history of the snapshot the chain started from, sending whether
+    /// it leads to the UTXO set of the snapshot on `outcome`
+    pub fn with_history_validation(self, validator: HistoryValidator, outcome: channel::Sender<bool>) -> Self {
+        *self.history.lock().unwrap() = Some(HistoryValidation {
+            validator,
+            outcome,
+            requested: Vec::new(),
+            requested_at: None,
+        });
+        return self;
+    }
+
+    /// Dispatch incoming messages to the worker pool
     pub fn start(self) {
-        let num_worker = self.num_worker;
-        for i in 0..num_worker {
-            let cloned = self.clone();
-            thread::spawn(move || {
-                cloned.worker_loop();
-                warn!("Worker thread {} exited", i);
-            });
-        }
-    }
-
-    fn worker_loop(&self) {
-        loop {
-            let msg = self.msg_chan.recv().unwrap();
-            let (msg, peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
-            match msg {
-                Message::Ping(nonce) => {
-                    debug!("Ping: {}", nonce);
-                    peer.write(Message::Pong(nonce.to_string()));
-                }
-                Message::Pong(nonce) => {
-                    debug!("Pong: {}", nonce);
-                }
-                Message::NewBlockHashes(block_hashes) => {
-                    let bc = Arc::clone(&self.blockchain);
-                    debug!("NewBlockHashes: {:?}", block_hashes);
-                    let blockchain = (*bc).lock().unwrap();
-                    let mut vec: Vec<H256> = Vec::new();
-                    for block_hash in &block_hashes {
-                        if !blockchain.find(&block_hash) {
-                            vec.push(block_hash.clone());
+        let stalls = self.clone();
+        thread::Builder::new()
+            .name("block-download".to_string())
+            .spawn(move || loop {
+                thread::sleep(STALL_CHECK_INTERVAL);
+                stalls.check_stalls();
+            })
+            .unwrap();
+        let cleanup = self.clone();
+        thread::Builder::new()
+            .name("peer-cleanup".to_string())
+            .spawn(move || {
+                for addr in cleanup.disconnections.iter() {
+                    cleanup.handle_disconnection(addr);
+                }
+            })
+            .unwrap();
+        thread::Builder::new()
+            .name("worker-dispatch".to_string())
+            .spawn(move || {
+                loop {
+                    let (msg, peer) = match self.msg_chan.recv() {
+                        Ok(m) => m,
+                        Err(_) => {
+                            warn!("Message channel closed, stopping dispatch");
+                            return;
                         }
+                    };
+                    // the messages of a peer are handled in order until its version is known, so
+                    // that the messages following it are not mistaken for early ones
+                    if peer.get_version().is_none() {
+                        self.handle_message(msg, peer);
+                        continue;
+                    }
+                    let cloned = self.clone();
+                    self.pool.execute(move || cloned.handle_message(msg, peer));
+                }
+            })
+            .unwrap();
+    }
+
+    fn handle_message(&self, msg: Vec<u8>, peer: peer::Handle) {
+        if peer.is_banned() {
+            return;
+        }
+        let msg = match Message::decode(&msg) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("Error decoding message from peer: {}", e);
+                return;
+            }
+        };
+        match msg {
+            Message::Version(version) => {
+                self.handle_version(version, &peer);
+                return;
+            }
+            Message::Verack(()) => {
+                debug!("Verack from {}", peer.addr());
+                peer.acknowledge();
+                return;
+            }
+            _ => {}
+        }
+        if peer.get_version().is_none() {
+            debug!("Ignored message from {} before its version", peer.addr());
+            peer.penalize(EARLY_MESSAGE_PENALTY);
+            return;
+        }
+        match msg {
+            Message::Version(_) | Message::Verack(()) => unreachable!(),
+            Message::Ping(nonce) => {
+                debug!("Ping: {}", nonce);
+                peer.write(Message::Pong(nonce.to_string()));
+            }
+            Message::Pong(nonce) => {
+                match peer.pong(&nonce, Instant::now()) {
+                    Some(latency) => debug!("Pong from {} after {:?}", peer.addr(), latency),
+                    None => debug!("Pong: {}", nonce),
+                }
+            }
+            Message::NewBlockHashes(block_hashes) => {
+                debug!("NewBlockHashes: {:?}", block_hashes);
+                if !check_inventory(&peer, block_hashes.len(), true) {
+                    return;
+                }
+                let blockchain = self.blockchain.read().unwrap();
+                let tree = blockchain.header_tree();
+                // only the blocks of known headers are downloaded, the headers of the others first
+                let mut vec: Vec<H256> = Vec::new();
+                let mut unknown = false;
+                for block_hash in &block_hashes {
+                    if !tree.contains(block_hash) {
+                        unknown = true;
+                    } else if !blockchain.find(block_hash) {
+                        vec.push(*block_hash);
                     }
+                }
+                if unknown {
+                    peer.write(Message::GetHeaders(tree.locator()));
+                }
+                if !vec.is_empty() {
                     debug!("Asking for blocks: {:?}", vec);
                     peer.write(Message::GetBlocks(vec));
                 }
-                Message::GetBlocks(block_hashes) => {
-                    let bc = Arc::clone(&self.blockchain);
-                    debug!("GetBlocks: {:?}", block_hashes);
-                    let blockchain = (*bc).lock().unwrap();
-                    let mut vec: Vec<Block> = Vec::new();
-                    for block_hash in &block_hashes {
-                       if !blockchain.find(&block_hash) {
-                           error!("Error finding the block {:?}", block_hash);
-                       } else {
-                           vec.push(blockchain.get(&block_hash));
-                       }
+            }
+            Message::GetBlocks(block_hashes) => {
+                let bc = Arc::clone(&self.blockchain);
+                debug!("GetBlocks: {:?}", block_hashes);
+                if !check_inventory(&peer, block_hashes.len(), false) {
+                    return;
+                }
+                let blockchain = bc.read().unwrap();
+                let throttled = self.server.is_upload_target_reached();
+                let mut vec: Vec<Block> = Vec::new();
+                let mut size = 0;
+                for block_hash in &unique(block_hashes) {
+                    if throttled && is_historical(&blockchain, block_hash) {
+                        debug!("Upload target reached, not serving historical block {}", block_hash);
+                    } else if let Some(block) = servable_block(&blockchain, block_hash) {
+                        size += bincode::serialized_size(&block).unwrap() as usize;
+                        if size > MAX_BLOCKS_RESPONSE_SIZE && !vec.is_empty() {
+                            debug!("Response to {} full, leaving out the blocks from {}", peer.addr(), block_hash);
+                            break;
+                        }
+                        vec.push(block);
+                    } else {
+                        error!("Error finding the block {:?}", block_hash);
+                    }
+                }
+                debug!("Sending the blocks: {:?}", vec);
+                peer.write(Message::Blocks(vec));
+            }
+            Message::Blocks(blocks) => {
+                debug!("Blocks: {:?}", blocks);
+                let blocks = self.replay_history(blocks, &peer);
+                self.process_blocks(blocks, &peer);
+            }
+            Message::GetCompactBlock(hash) => {
+                debug!("GetCompactBlock: {}", hash);
+                let blockchain = self.blockchain.read().unwrap();
+                if let Some(block) = self.uploadable_block(&blockchain, &hash) {
+                    peer.write(Message::CompactBlock(CompactBlock::new(&block, rand::random())));
+                }
+            }
+            Message::CompactBlock(compact) => {
+                debug!("CompactBlock: {} with {} transactions", compact.hash(), compact.len());
+                self.handle_compact_block(compact, &peer);
+            }
+            Message::GetBlockTxn(request) => {
+                debug!("GetBlockTxn: {} transactions of {}", request.indexes.len(), request.block);
+                if !check_inventory(&peer, request.indexes.len(), false) {
+                    return;
+                }
+                let blockchain = self.blockchain.read().unwrap();
+                let block = match self.uploadable_block(&blockchain, &request.block) {
+                    Some(block) => block,
+                    None => return,
+                };
+                let mut transactions: Vec<Transaction> = Vec::new();
+                for index in &unique(request.indexes) {
+                    match block.get_transactions().get(*index as usize) {
+                        Some(transaction) => transactions.push(transaction.clone()),
+                        None => {
+                            peer.penalize(peer::BAN_THRESHOLD);
+                            return;
+                        }
+                    }
+                }
+                peer.write(Message::BlockTxn(BlockTxn { block: request.block, transactions }));
+            }
+            Message::BlockTxn(response) => {
+                debug!("BlockTxn: {} transactions of {}", response.transactions.len(), response.block);
+                let mut partial = match self.partial_blocks.lock().unwrap().remove(&response.block) {
+                    Some((partial, _)) => partial,
+                    None => return,
+                };
+                match partial.fill(response.transactions) {
+                    Ok(()) => self.complete_block(partial, &peer),
+                    Err(e) => {
+                        debug!("Downloading block {} whole: {}", response.block, e);
+                        peer.write(Message::GetBlocks(vec![response.block]));
+                    }
+                }
+            }
+            Message::FilterLoad(filter) => {
+                debug!("FilterLoad from {}", peer.addr());
+                if !filter.is_within_limits() {
+                    peer.penalize(FILTER_PENALTY);
+                    return;
+                }
+                peer.set_filter(Some(filter));
+            }
+            Message::FilterAdd(element) => {
+                debug!("FilterAdd from {}", peer.addr());
+                if element.len() > bloom::MAX_FILTER_ADD_SIZE || !peer.filter_add(&element) {
+                    peer.penalize(FILTER_PENALTY);
+                }
+            }
+            Message::FilterClear(()) => {
+                debug!("FilterClear from {}", peer.addr());
+                peer.set_filter(None);
+            }
+            Message::GetFilteredBlocks(block_hashes) => {
+                debug!("GetFilteredBlocks: {:?}", block_hashes);
+                if !check_inventory(&peer, block_hashes.len(), false) {
+                    return;
+                }
+                if !peer.has_filter() {
+                    peer.penalize(FILTER_PENALTY);
+                    return;
+                }
+                let blockchain = self.blockchain.read().unwrap();
+                let throttled = self.server.is_upload_target_reached();
+                for block_hash in &unique(block_hashes) {
+                    if throttled && is_historical(&blockchain, block_hash) {
+                        continue;
+                    }
+                    let block = match servable_block(&blockchain, block_hash) {
+                        Some(block) => block,
+                        None => continue,
+                    };
+                    if let Some(filtered) = peer.with_filter(|filter| FilteredBlock::new(&block, filter)) {
+                        peer.write(Message::FilteredBlock(filtered));
+                    }
+                }
+            }
+            Message::FilteredBlock(filtered) => {
+                // only light clients ask for filtered blocks
+                debug!("Ignored filtered block {} from {}", filtered.hash(), peer.addr());
+            }
+            Message::GetCFilters(request) => {
+                debug!("GetCFilters: {:?}", request);
+                let blockchain = self.blockchain.read().unwrap();
+                for hash in filter_range(&blockchain, &request, block_filter::MAX_CFILTERS) {
+                    if let Some(filter) = blockchain.filters().get(&hash) {
+                        peer.write(Message::CFilter(CFilter { block_hash: hash, filter: filter.clone() }));
+                    }
+                }
+            }
+            Message::GetCFHeaders(request) => {
+                debug!("GetCFHeaders: {:?}", request);
+                let blockchain = self.blockchain.read().unwrap();
+                let hashes = filter_range(&blockchain, &request, block_filter::MAX_CFHEADERS);
+                let first = match hashes.first() {
+                    Some(first) => first,
+                    None => return,
+                };
+                let filters = blockchain.filters();
+                let previous_header = match blockchain.height_of(first).unwrap() {
+                    0 => H256::default(),
+                    height => match blockchain.block_at_height(height - 1).and_then(|h| filters.get_header(&h)) {
+                        Some(header) => header,
+                        None => return,
+                    },
+                };
+                let filter_hashes: Option<Vec<H256>> = hashes.iter().map(|h| filters.get(h).map(|f| f.filter_hash())).collect();
+                if let Some(filter_hashes) = filter_hashes {
+                    peer.write(Message::CFHeaders(CFHeaders { stop_hash: request.stop_hash, previous_header, filter_hashes }));
+                }
+            }
+            Message::CFilter(_) | Message::CFHeaders(_) => {
+                // only light clients ask for filters
+                debug!("Ignored filter message from {}", peer.addr());
+            }
+            Message::GetHeaders(locator) => {
+                let blockchain = self.blockchain.read().unwrap();
+                debug!("GetHeaders: {:?}", locator);
+                let start = match blockchain.find_fork_point(&locator) {
+                    Some(fork) => blockchain.height_of(&fork).unwrap() + 1,
+                    None => 0,
+                };
+                let headers: Vec<Header> = blockchain.headers_in_range(start, start + MAX_HEADERS - 1);
+                peer.write(Message::Headers(headers));
+            }
+            Message::Headers(headers) => {
+                debug!("Headers: {} received", headers.len());
+                let mut blockchain = self.blockchain.write().unwrap();
+                match blockchain.accept_headers(&headers) {
+                    Ok(_) => {}
+                    Err(HeaderError::UnknownParent) => {
+                        // headers not connecting to ours, ask again from our best header chain
+                        peer.write(Message::GetHeaders(blockchain.header_tree().locator()));
+                        return;
+                    }
+                    Err(e) => {
+                        warn!("Rejected headers: {}", e);
+                        // headers too far in the future may be valid later
+                        if e.is_permanent() {
+                            peer.penalize(peer::BAN_THRESHOLD);
+                        }
+                        return;
+                    }
+                }
+                self.request_blocks(&blockchain, Some(&peer));
+                if headers.len() as u32 == MAX_HEADERS {
+                    // the peer may have more headers after the last one
+                    let last = headers.last().unwrap().hash();
+                    peer.write(Message::GetHeaders(vec![last]));
+                }
+            }
+            Message::NewTxHashes(txids) => {
+                debug!("NewTxHashes: {:?}", txids);
+                if !check_inventory(&peer, txids.len(), true) {
+                    return;
+                }
+                let mempool = self.mempool.read().unwrap();
+                let missing: Vec<H256> = unique(txids).into_iter().filter(|txid| !mempool.contains(txid)).collect();
+                let missing = peer.request_txs(missing, Instant::now());
+                if !missing.is_empty() {
+                    peer.write(Message::GetTxs(missing));
+                }
+            }
+            Message::GetTxs(txids) => {
+                debug!("GetTxs: {:?}", txids);
+                if !check_inventory(&peer, txids.len(), false) {
+                    return;
+                }
+                let mempool = self.mempool.read().unwrap();
+                let transactions: Vec<Transaction> = unique(txids)
+                    .iter()
+                    .filter_map(|txid| mempool.get(txid))
+                    .map(|entry| entry.get_transaction().clone())
+                    .collect();
+                peer.write(Message::Txs(transactions));
+            }
+            Message::Txs(transactions) => {
+                debug!("Txs: {} received", transactions.len());
+                if !check_inventory(&peer, transactions.len(), false) {
+                    return;
+                }
+                let mut accepted: Vec<H256> = Vec::new();
+                {
+                    let blockchain = self.blockchain.read().unwrap();
+                    let mut mempool = self.mempool.write().unwrap();
+                    for transaction in transactions {
+                        let txid = transaction.txid();
+                        if !peer.take_requested_tx(&txid) {
+                            debug!("Ignored transaction {} sent by {} without being requested", txid, peer.addr());
+                            if peer.penalize(UNSOLICITED_TX_PENALTY) {
+                                break;
+                            }
+                            continue;
+                        }
+                        match mempool.accept_from(transaction, blockchain.utxo_set(), peer.addr()) {
+                            Ok(txids) => accepted.extend(txids),
+                            Err(e) if is_invalid_transaction(&e) => {
+                                warn!("Invalid transaction {} from {}: {}", txid, peer.addr(), e);
+                                if peer.penalize(INVALID_TX_PENALTY) {
+                                    break;
+                                }
+                            }
+                            Err(e) => debug!("Ignored transaction {}: {}", txid, e),
+                        }
+                    }
+                }
+                self.relay_transactions(&accepted);
+            }
+            Message::GetAddr(()) => {
+                debug!("GetAddr from {}", peer.addr());
+                let records = self.address_book.read().unwrap().sample(
+                    &mut rand::thread_rng(),
+                    address_book::MAX_ADDR_PER_MESSAGE,
+                    SystemTime::now(),
+                );
+                peer.write(Message::Addr(records));
+            }
+            Message::Addr(records) => {
+                debug!("Addr: {} received from {}", records.len(), peer.addr());
+                if records.len() > address_book::MAX_ADDR_PER_MESSAGE {
+                    peer.penalize(OVERSIZED_ADDR_PENALTY);
+                    return;
+                }
+                self.address_book.write().unwrap().add(&records, peer.addr().ip(), SystemTime::now());
+            }
+        }
+    }
+
+    /// Announce transactions newly accepted in the mempool to the peers, those with a Bloom
+    /// filter only being told about the matching ones
+    fn relay_transactions(&self, txids: &[H256]) {
+        if txids.is_empty() {
+            return;
+        }
+        let transactions: Vec<Transaction> = {
+            let mempool = self.mempool.read().unwrap();
+            txids.iter().filter_map(|txid| mempool.get(txid)).map(|e| e.get_transaction().clone()).collect()
+        };
+        for peer in self.server.peer_handles() {
+            if !peer.is_handshake_complete() {
+                continue;
+            }
+            let relevant: Vec<H256> = transactions.iter().filter(|t| peer.is_relevant(t)).map(|t| t.txid()).collect();
+            if !relevant.is_empty() {
+                peer.write(Message::NewTxHashes(relevant));
+            }
+        }
+    }
+
+    /// Validate and insert blocks received from `peer`, continue the download and relay the new
+    /// blocks
+    fn process_blocks(&self, blocks: Vec<Block>, peer: &peer::Handle) {
+        let bc = Arc::clone(&self.blockchain);
+        // run the stateless checks before taking the write lock, so invalid blocks do not
+        // block readers
+        let blocks: Vec<Block> = blocks
+            .into_iter()
+            .filter(|block| match validation::check_stateless(block) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Rejected block {}: {}", block.hash(), e);
+                    peer.penalize(peer::BAN_THRESHOLD);
+                    false
+                }
+            })
+            .collect();
+        let mut blockchain = bc.write().unwrap();
+        let mut inserted: Vec<H256> = Vec::new();
+        {
+            let mut downloader = self.downloader.lock().unwrap();
+            for block in &blocks {
+                downloader.received(&block.hash());
+            }
+        }
+        for block in &blocks {
+            // the header must connect to the known ones before the body is looked at
+            match blockchain.accept_headers(&[block.get_header().clone()]) {
+                Ok(_) => {}
+                Err(HeaderError::UnknownParent) => {
+                    debug!("Block {} without known parent header", block.hash());
+                    peer.write(Message::GetHeaders(blockchain.header_tree().locator()));
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Rejected block {}: {}", block.hash(), e);
+                    if e.is_permanent() {
+                        peer.penalize(peer::BAN_THRESHOLD);
+                    }
+                    continue;
+                }
+            }
+            if let Err(e) = blockchain.validate(&block) {
+                warn!("Rejected block {}: {}", block.hash(), e);
+                if e.is_permanent() {
+                    peer.penalize(peer::BAN_THRESHOLD);
+                }
+                continue;
+            }
+            match blockchain.insert_or_buffer(&block) {
+                Ok(ref hashes) if hashes.is_empty() => {
+                    // its parent is still downloading, and is requested below
+                    debug!("Buffered orphan block {}", block.hash());
+                }
+                Ok(hashes) => inserted.extend(hashes),
+                Err(e @ InsertError::Invalid(_, _)) => {
+                    // the block or the branch it extends does not connect
+                    warn!("Rejected block {}: {}", block.hash(), e);
+                    peer.penalize(peer::BAN_THRESHOLD);
+                }
+                Err(e) => {
+                    debug!("Ignored block {}: {}", block.hash(), e);
+                }
+            }
+        }
+        // continue downloading the blocks of the best header chain
+        self.request_blocks(&blockchain, Some(peer));
+        drop(blockchain);
+        // relay the new blocks, the peers already knowing them ignore the announcement
+        if !inserted.is_empty() {
+            self.server.broadcast(Message::NewBlockHashes(inserted));
+        }
+    }
+
+    /// Request the missing blocks of the best header chain, within the download window, spread
+    /// over `peer`, which sent them, and the peers which announced a chain reaching them
+    fn request_blocks(&self, blockchain: &Blockchain, peer: Option<&peer::Handle>) {
+        let tree = blockchain.header_tree();
+        let missing: Vec<H256> = tree
+            .missing_bodies(download::BLOCK_DOWNLOAD_WINDOW)
+            .into_iter()
+            .filter(|hash| !blockchain.is_orphan(hash))
+            .collect();
+        if missing.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let mut downloader = self.downloader.lock().unwrap();
+        if let Some(peer) = peer {
+            let assigned = downloader.assign(peer.addr(), &missing, now);
+            self.request_bodies(peer, assigned, tree.best());
+        }
+        for other in self.server.peer_handles() {
+            if !other.is_handshake_complete() || peer.map_or(false, |p| p.addr() == other.addr()) {
+                continue;
+            }
+            let best_height = other.get_version().map_or(0, |v| v.best_height);
+            let reachable: Vec<H256> = missing
+                .iter()
+                .filter(|hash| tree.height_of(hash).map_or(false, |h| h <= best_height))
+                .cloned()
+                .collect();
+            let assigned = downloader.assign(other.addr(), &reachable, now);
+            self.request_bodies(&other, assigned, tree.best());
+        }
+    }
+
+    /// Request blocks from `peer`. The block of the best header alone is requested in compact
+    /// form, its transactions being likely in the mempool.
+    fn request_bodies(&self, peer: &peer::Handle, hashes: Vec<H256>, best: H256) {
+        if hashes.is_empty() {
+            return;
+        }
+        let compact = peer.get_version().map_or(false, |v| v.version >= message::COMPACT_BLOCKS_VERSION);
+        if compact && hashes == [best] {
+            peer.write(Message::GetCompactBlock(best));
+        } else {
+            peer.write(Message::GetBlocks(hashes));
+        }
+    }
+
+    /// Rebuild a compact block from the mempool, asking `peer` for the transactions missing
+    fn handle_compact_block(&self, compact: CompactBlock, peer: &peer::Handle) {
+        let hash = compact.hash();
+        {
+            let mut blockchain = self.blockchain.write().unwrap();
+            if blockchain.find(&hash) {
+                return;
+            }
+            match blockchain.accept_headers(&[compact.header.clone()]) {
+                Ok(_) => {}
+                Err(HeaderError::UnknownParent) => {
+                    peer.write(Message::GetHeaders(blockchain.header_tree().locator()));
+                    return;
+                }
+                Err(e) => {
+                    warn!("Rejected compact block {}: {}", hash, e);
+                    if e.is_permanent() {
+                        peer.penalize(peer::BAN_THRESHOLD);
                     }
-                    debug!("Sending the blocks: {:?}", vec);
-                    peer.write(Message::Blocks(vec));
-                }
-                Message::Blocks(blocks) => {
-                    let bc = Arc::clone(&self.blockchain);
-                    debug!("Blocks: {:?}", blocks);
-                    let mut blockchain = bc.lock().unwrap();
-                    for block in &blocks {
-                        blockchain.insert(&block);
+                    return;
+                }
+            }
+        }
+        let partial = match PartialBlock::new(compact, self.mempool.read().unwrap().transactions()) {
+            Ok(partial) => partial,
+            Err(e) => {
+                warn!("Rejected compact block {}: {}", hash, e);
+                peer.penalize(peer::BAN_THRESHOLD);
+                return;
+            }
+        };
+        let missing = partial.missing();
+        if missing.is_empty() {
+            self.complete_block(partial, peer);
+            return;
+        }
+        debug!("Compact block {} misses {} transactions", hash, missing.len());
+        self.partial_blocks.lock().unwrap().insert(hash, (partial, Instant::now()));
+        peer.write(Message::GetBlockTxn(BlockTxnRequest { block: hash, indexes: missing }));
+    }
+
+    /// Process a reconstructed block, or download it whole if its transactions are not those
+    /// of the header, e.g. after a short id collision
+    fn complete_block(&self, partial: PartialBlock, peer: &peer::Handle) {
+        match partial.block() {
+            Ok(block) => self.process_blocks(vec![block], peer),
+            Err(e) => {
+                debug!("Downloading block {} whole: {}", partial.hash(), e);
+                peer.write(Message::GetBlocks(vec![partial.hash()]));
+            }
+        }
+    }
+
+    /// Give the blocks requested from stalling peers to others
+    fn check_stalls(&self) {
+        let now = Instant::now();
+        self.request_history(now);
+        // compact blocks left unanswered are forgotten, their requests stall and are made again
+        self.partial_blocks
+            .lock()
+            .unwrap()
+            .retain(|_, (_, requested)| now.saturating_duration_since(*requested) <= download::STALL_TIMEOUT);
+        let stalled = self.downloader.lock().unwrap().check_stalls(now);
+        if stalled.is_empty() {
+            return;
+        }
+        for addr in &stalled {
+            info!("Peer {} stalled the block download", addr);
+        }
+        let blockchain = self.blockchain.read().unwrap();
+        self.request_blocks(&blockchain, None);
+    }
+
+    /// `servable_block`, unless the block is historical and the upload target is reached
+    fn uploadable_block(&self, blockchain: &Blockchain, hash: &H256) -> Option<Block> {
+        if is_historical(blockchain, hash) && self.server.is_upload_target_reached() {
+            debug!("Upload target reached, not serving historical block {}", hash);
+            return None;
+        }
+        return servable_block(blockchain, hash);
+    }
+
+    /// Forget the orphan transactions sent by a peer removed by the server
+    fn handle_disconnection(&self, addr: SocketAddr) {
+        let removed = self.mempool.write().unwrap().get_orphans_mut().remove_peer(&addr);
+        if removed > 0 {
+            debug!("Dropped {} orphan transactions of {}", removed, addr);
+        }
+    }
+
+    /// Request the next blocks of the history of the snapshot from a full node, unless the
+    /// previous request is still running
+    fn request_history(&self, now: Instant) {
+        let mut history = self.history.lock().unwrap();
+        let history = match history.as_mut() {
+            Some(history) => history,
+            None => return,
+        };
+        let pending = history.requested_at.map_or(false, |at| {
+            !history.requested.is_empty() && now.saturating_duration_since(at) <= download::STALL_TIMEOUT
+        });
+        if pending {
+            return;
+        }
+        let full_node = self.server.peer_handles().into_iter().find(|peer| {
+            peer.is_handshake_complete()
+                && peer.get_version().map_or(false, |v| v.services & message::SERVICE_NETWORK != 0)
+        });
+        if let Some(peer) = full_node {
+            history.requested = history.validator.next_batch(HISTORY_BATCH);
+            history.requested_at = Some(now);
+            peer.write(Message::GetBlocks(history.requested.clone()));
+        }
+    }
+
+    /// Replay the blocks of the history of the snapshot among `blocks`, returning the others.
+    /// Once the history is replayed, the outcome is sent and the validation stops.
+    fn replay_history(&self, blocks: Vec<Block>, peer: &peer::Handle) -> Vec<Block> {
+        let mut guard = self.history.lock().unwrap();
+        let history = match guard.as_mut() {
+            Some(history) => history,
+            None => return blocks,
+        };
+        let (mut replayed, others): (Vec<Block>, Vec<Block>) =
+            blocks.into_iter().partition(|block| history.requested.contains(&block.hash()));
+        // blocks received out of order are dropped, and requested again
+        replayed.sort_by_key(|block| history.requested.iter().position(|hash| *hash == block.hash()));
+        for block in &replayed {
+            if history.validator.next_needed() != Some(block.hash()) {
+                continue;
+            }
+            history.requested.retain(|hash| *hash != block.hash());
+            match history.validator.add_block(block) {
+                Ok(None) => {}
+                Ok(Some(valid)) => {
+                    if valid {
+                        info!("Validated the history of the UTXO snapshot");
+                    } else {
+                        error!("The history of the UTXO snapshot does not lead to its UTXO set");
                     }
-                    let x = blockchain.recv();
-                    self.blockchain = Arc::new(x);
+                    let _ = history.outcome.send(valid);
+                    *guard = None;
+                    break;
                 }
+                Err(e) => {
+                    // the body does not match its header
+                    warn!("Rejected block {}: {}", block.hash(), e);
+                    peer.penalize(peer::BAN_THRESHOLD);
+                    break;
+                }
+            }
+        }
+        return others;
+    }
+
+    /// Accept the `Version` of a peer, answering with ours if it connected to us, or disconnect
+    /// it if it is too old or is ourselves. Headers are requested from peers with a longer chain.
+    fn handle_version(&self, version: VersionInfo, peer: &peer::Handle) {
+        debug!("Version from {}: {:?}", peer.addr(), version);
+        if peer.get_version().is_some() {
+            peer.penalize(EARLY_MESSAGE_PENALTY);
+            return;
+        }
+        if version.nonce == self.server.nonce() {
+            info!("Disconnecting from ourselves at {}", peer.addr());
+            self.server.disconnect(peer.addr());
+            return;
+        }
+        if version.version < message::MIN_PROTOCOL_VERSION {
+            info!("Disconnecting peer {} with obsolete protocol version {}", peer.addr(), version.version);
+            self.server.disconnect(peer.addr());
+            return;
+        }
+        let (best_height, services) = (version.best_height, version.services);
+        peer.set_version(version);
+        let blockchain = self.blockchain.read().unwrap();
+        if peer.direction() == peer::Direction::Incoming {
+            peer.write(Message::Version(local_version(&blockchain, &self.server)));
+        }
+        peer.write(Message::Verack(()));
+        if best_height > blockchain.tip_height() {
+            peer.write(Message::GetHeaders(blockchain.header_tree().locator()));
+        }
+        // the peers we connected to listen at their address, ask them for more
+        if peer.direction() == peer::Direction::Outgoing {
+            self.address_book.write().unwrap().mark_good(peer.addr(), services, SystemTime::now());
+            peer.write(Message::GetAddr(()));
+        }
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use super::peer::tests::{connected, received};
+    use crate::mempool::tests::{funded, spend};
+    use crate::crypto::keys::KeyPair;
+    use crate::block::test::{generate_mined_block, generate_mined_block_with_height, generate_random_block, generate_random_block_at};
+    use crate::bloom::{BloomFilter, BloomFlags};
+    use crate::crypto::merkle::MerkleTree;
+    use crate::transaction::tests::generate_random_transaction;
+    use crate::network::server;
+    use crate::utxo::OutPoint;
+    use crate::transaction::TxOutput;
+
+    /// A worker on a fresh chain, with its server which is not started
+    fn worker(mempool: &Arc<RwLock<Mempool>>) -> (Context, server::Context) {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let (_msg_tx, msg_rx) = channel::unbounded();
+        let (server_ctx, server) = server::new("127.0.0.1:0".parse().unwrap(), Default::default(), channel::unbounded().0).unwrap();
+        let address_book = Arc::new(RwLock::new(AddressBook::new()));
+        let worker = new(&ThreadPool::new("network", 0), msg_rx, &server, &blockchain, mempool, &address_book);
+        return (worker, server_ctx);
+    }
+
+    fn version(version: u32, best_height: u32, nonce: u64) -> Message {
+        return Message::Version(VersionInfo { version, services: message::SERVICE_NETWORK, best_height, nonce });
+    }
+
+    #[test]
+    fn handshake() {
+        let (worker, _server) = worker(&Arc::new(RwLock::new(Mempool::new())));
+        let (mut peer, mut remote) = connected();
+        // nothing is answered before the version of the peer
+        worker.handle_message(Message::Ping("early".to_string()).encode(), peer.handle.clone());
+        // nor to an obsolete peer, or to ourselves
+        worker.handle_message(version(message::MIN_PROTOCOL_VERSION - 1, 0, 1).encode(), peer.handle.clone());
+        worker.handle_message(version(message::PROTOCOL_VERSION, 0, worker.server.nonce()).encode(), peer.handle.clone());
+        assert!(peer.handle.get_version().is_none());
+
+        // an incoming peer gets our version back, and headers are asked to longer chains
+        worker.handle_message(version(message::PROTOCOL_VERSION, 5, 1).encode(), peer.handle.clone());
+        assert_eq!(peer.handle.get_version().unwrap().best_height, 5);
+        match received(&mut peer, &mut remote) {
+            Message::Version(ours) => {
+                assert_eq!(ours.version, message::PROTOCOL_VERSION);
+                assert_eq!(ours.best_height, 0);
+                assert_eq!(ours.nonce, worker.server.nonce());
             }
+            m => panic!("unexpected message {:?}", m),
+        }
+        assert!(matches!(received(&mut peer, &mut remote), Message::Verack(())));
+        assert!(matches!(received(&mut peer, &mut remote), Message::GetHeaders(_)));
+        assert!(!peer.handle.is_handshake_complete());
+        worker.handle_message(Message::Verack(()).encode(), peer.handle.clone());
+        assert!(peer.handle.is_handshake_complete());
+        worker.handle_message(Message::Ping("late".to_string()).encode(), peer.handle.clone());
+        assert!(matches!(received(&mut peer, &mut remote), Message::Pong(ref n) if n == "late"));
+
+        // gossiped addresses are handed out again
+        let last_seen = address_book::unix_time(SystemTime::now());
+        let record = address_book::AddressRecord { addr: "1.2.3.4:6000".parse().unwrap(), services: 1, last_seen };
+        worker.handle_message(Message::Addr(vec![record]).encode(), peer.handle.clone());
+        worker.handle_message(Message::GetAddr(()).encode(), peer.handle.clone());
+        assert!(matches!(received(&mut peer, &mut remote), Message::Addr(ref records) if records == &vec![record]));
+    }
+
+    #[test]
+    fn headers_first_sync() {
+        let (worker, _server) = worker(&Arc::new(RwLock::new(Mempool::new())));
+        let (mut peer, mut remote) = connected();
+        peer.handle.set_version(VersionInfo { version: message::PROTOCOL_VERSION, services: 0, best_height: 3, nonce: 1 });
+        let genesis = worker.blockchain.read().unwrap().tip();
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut parent = genesis;
+        for height in 1..=3 {
+            let block = generate_mined_block_with_height(&parent, height);
+            parent = block.hash();
+            blocks.push(block);
+        }
+        let hashes: Vec<H256> = blocks.iter().map(|b| b.hash()).collect();
+
+        // an announced block is not downloaded before its header
+        worker.handle_message(Message::NewBlockHashes(vec![hashes[2]]).encode(), peer.handle.clone());
+        assert!(matches!(received(&mut peer, &mut remote), Message::GetHeaders(ref locator) if locator == &vec![genesis]));
+        let headers: Vec<Header> = blocks.iter().map(|b| b.get_header().clone()).collect();
+        worker.handle_message(Message::Headers(headers).encode(), peer.handle.clone());
+        assert!(matches!(received(&mut peer, &mut remote), Message::GetBlocks(ref requested) if requested == &hashes));
+        assert_eq!(worker.blockchain.read().unwrap().header_tree().locator()[0], hashes[2]);
+
+        // bodies arriving out of order wait for their parents
+        worker.handle_message(Message::Blocks(vec![blocks[2].clone()]).encode(), peer.handle.clone());
+        worker.handle_message(Message::Blocks(blocks[..2].to_vec()).encode(), peer.handle.clone());
+        assert_eq!(worker.blockchain.read().unwrap().tip(), hashes[2]);
+
+        // a block whose header does not connect makes us ask for headers instead
+        let stray = generate_mined_block(&H256::from([7u8; 32]));
+        worker.handle_message(Message::Blocks(vec![stray.clone()]).encode(), peer.handle.clone());
+        assert!(matches!(received(&mut peer, &mut remote), Message::GetHeaders(ref locator) if locator[0] == hashes[2]));
+        assert!(!worker.blockchain.read().unwrap().find(&stray.hash()));
+        assert!(!peer.handle.is_banned());
+    }
+
+    #[test]
+    fn compact_blocks() {
+        let (worker, _server) = worker(&Arc::new(RwLock::new(Mempool::new())));
+        let (mut peer, mut remote) = connected();
+        peer.handle.set_version(VersionInfo { version: message::COMPACT_BLOCKS_VERSION, services: 0, best_height: 1, nonce: 1 });
+        let genesis = worker.blockchain.read().unwrap().tip();
+
+        // the block of the best header is requested compact
+        let served = generate_mined_block_with_height(&genesis, 1);
+        worker.handle_message(Message::Headers(vec![served.get_header().clone()]).encode(), peer.handle.clone());
+        assert!(matches!(received(&mut peer, &mut remote), Message::GetCompactBlock(hash) if hash == served.hash()));
+        worker.handle_message(Message::Blocks(vec![served.clone()]).encode(), peer.handle.clone());
+        assert_eq!(worker.blockchain.read().unwrap().tip(), served.hash());
+
+        // and served compact, with the transactions asked for
+        worker.handle_message(Message::GetCompactBlock(served.hash()).encode(), peer.handle.clone());
+        match received(&mut peer, &mut remote) {
+            Message::CompactBlock(compact) => {
+                assert_eq!(compact.hash(), served.hash());
+                assert_eq!(compact.prefilled.len(), 1);
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+        let request = BlockTxnRequest { block: served.hash(), indexes: vec![0] };
+        worker.handle_message(Message::GetBlockTxn(request).encode(), peer.handle.clone());
+        assert!(matches!(received(&mut peer, &mut remote), Message::BlockTxn(ref r) if r.transactions.len() == 1));
+
+        // a transaction missing from the mempool is asked for, and a wrong one makes us download
+        // the whole block
+        let transaction = generate_random_transaction();
+        let mut transactions = served.get_transactions().to_vec();
+        transactions.push(transaction);
+        let block = loop {
+            let merkle_root = MerkleTree::new(&transactions).root();
+            let block = Block::new(served.hash(), served.get_difficulty(), transactions.clone(), merkle_root);
+            if block.hash() <= block.get_difficulty() {
+                break block;
+            }
+        };
+        worker.handle_message(Message::CompactBlock(CompactBlock::new(&block, 7)).encode(), peer.handle.clone());
+        let expected = BlockTxnRequest { block: block.hash(), indexes: vec![1] };
+        assert!(matches!(received(&mut peer, &mut remote), Message::GetBlockTxn(ref r) if r == &expected));
+        let wrong = BlockTxn { block: block.hash(), transactions: vec![generate_random_transaction()] };
+        worker.handle_message(Message::BlockTxn(wrong).encode(), peer.handle.clone());
+        assert!(matches!(received(&mut peer, &mut remote), Message::GetBlocks(ref hashes) if hashes == &vec![block.hash()]));
+        assert!(!peer.handle.is_banned());
+    }
+
+    #[test]
+    fn empty_compact_block() {
+        let (worker, _server) = worker(&Arc::new(RwLock::new(Mempool::new())));
+        let (peer, _remote) = connected();
+        peer.handle.set_version(VersionInfo { version: message::COMPACT_BLOCKS_VERSION, services: 0, best_height: 1, nonce: 1 });
+        let genesis = worker.blockchain.read().unwrap().tip();
+        let block = generate_mined_block_with_height(&genesis, 1);
+        worker.handle_message(Message::Headers(vec![block.get_header().clone()]).encode(), peer.handle.clone());
+
+        // the known header needs no proof of work, but a block without transactions is refused
+        let mut compact = CompactBlock::new(&block, 7);
+        compact.prefilled.clear();
+        worker.handle_message(Message::CompactBlock(compact).encode(), peer.handle.clone());
+        assert!(peer.handle.is_banned());
+        assert_eq!(worker.blockchain.read().unwrap().tip(), genesis);
+    }
+
+    #[test]
+    fn bloom_filters() {
+        let (worker, _server) = worker(&Arc::new(RwLock::new(Mempool::new())));
+        let (mut peer, mut remote) = connected();
+        peer.handle.set_version(VersionInfo { version: message::PROTOCOL_VERSION, services: 0, best_height: 0, nonce: 1 });
+        let genesis = worker.blockchain.read().unwrap().tip();
+        let block = generate_mined_block(&genesis);
+        worker.blockchain.write().unwrap().insert(&block).unwrap();
+        let coinbase = block.get_transactions()[0].clone();
+
+        // the filter selects the transactions of the blocks served, with their proofs
+        let mut filter = BloomFilter::new(10, 0.0001, 0, BloomFlags::UpdateNone);
+        filter.insert(b"unrelated");
+        worker.handle_message(Message::FilterLoad(filter).encode(), peer.handle.clone());
+        worker.handle_message(Message::FilterAdd(coinbase.get_outputs()[0].recipient.as_ref().to_vec()).encode(), peer.handle.clone());
+        assert!(peer.handle.is_relevant(&coinbase));
+        assert!(!peer.handle.is_relevant(&generate_random_transaction()));
+        worker.handle_message(Message::GetFilteredBlocks(vec![block.hash()]).encode(), peer.handle.clone());
+        match received(&mut peer, &mut remote) {
+            Message::FilteredBlock(filtered) => {
+                assert_eq!(filtered.hash(), block.hash());
+                assert_eq!(filtered.transactions.len(), 1);
+                assert_eq!(filtered.transactions[0].0.txid(), coinbase.txid());
+                assert!(filtered.verify());
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+
+        // without a filter everything is relayed, and filtered blocks are not served
+        worker.handle_message(Message::FilterClear(()).encode(), peer.handle.clone());
+        assert!(peer.handle.is_relevant(&generate_random_transaction()));
+        assert!(!peer.handle.is_banned());
+        worker.handle_message(Message::FilterAdd(vec![0u8; bloom::MAX_FILTER_ADD_SIZE + 1]).encode(), peer.handle.clone());
+        assert!(peer.handle.is_banned());
+    }
+
+    #[test]
+    fn compact_filters() {
+        let (worker, _server) = worker(&Arc::new(RwLock::new(Mempool::new())));
+        let (mut peer, mut remote) = connected();
+        peer.handle.set_version(VersionInfo { version: message::PROTOCOL_VERSION, services: 0, best_height: 0, nonce: 1 });
+        let genesis = worker.blockchain.read().unwrap().tip();
+        let first = generate_mined_block(&genesis);
+        let second = generate_mined_block(&first.hash());
+        worker.blockchain.write().unwrap().insert(&first).unwrap();
+        worker.blockchain.write().unwrap().insert(&second).unwrap();
+
+        let request = FilterRequest { start_height: 1, stop_hash: second.hash() };
+        worker.handle_message(Message::GetCFilters(request.clone()).encode(), peer.handle.clone());
+        let recipient = first.get_transactions()[0].get_outputs()[0].recipient.as_ref().to_vec();
+        match received(&mut peer, &mut remote) {
+            Message::CFilter(cfilter) => {
+                assert_eq!(cfilter.block_hash, first.hash());
+                assert!(cfilter.filter.match_any(&first.hash(), &[recipient.clone()]));
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+        assert!(matches!(received(&mut peer, &mut remote), Message::CFilter(ref f) if f.block_hash == second.hash()));
+
+        // the filter hashes chain into our filter headers
+        worker.handle_message(Message::GetCFHeaders(request).encode(), peer.handle.clone());
+        match received(&mut peer, &mut remote) {
+            Message::CFHeaders(cfheaders) => {
+                let blockchain = worker.blockchain.read().unwrap();
+                assert_eq!(cfheaders.previous_header, blockchain.filters().get_header(&genesis).unwrap());
+                assert_eq!(cfheaders.headers().last().cloned(), blockchain.filters().get_header(&second.hash()));
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+        // ranges beyond the stop block are not served
+        let backwards = FilterRequest { start_height: 3, stop_hash: second.hash() };
+        worker.handle_message(Message::GetCFilters(backwards).encode(), peer.handle.clone());
+        worker.handle_message(Message::Ping("done".to_string()).encode(), peer.handle.clone());
+        assert!(matches!(received(&mut peer, &mut remote), Message::Pong(_)));
+    }
+
+    #[test]
+    fn transaction_gossip() {
+        let mempool = Arc::new(RwLock::new(Mempool::new()));
+        let key = KeyPair::random();
+        let (utxo, funding) = funded(&key, 1, 100_000);
+        let transaction = spend(&key, &[OutPoint::new(funding.txid(), 0)], 90_000);
+        mempool.write().unwrap().accept(transaction.clone(), &utxo).unwrap();
+        let (worker, _server) = worker(&mempool);
+        let (mut peer, mut remote) = connected();
+        peer.handle.set_version(VersionInfo { version: message::PROTOCOL_VERSION, services: 0, best_height: 0, nonce: 1 });
+
+        // only the unknown transactions are requested
+        let unknown = H256::from([1u8; 32]);
+        worker.handle_message(Message::NewTxHashes(vec![transaction.txid(), unknown]).encode(), peer.handle.clone());
+        match received(&mut peer, &mut remote) {
+            Message::GetTxs(txids) => assert_eq!(txids, vec![unknown]),
+            m => panic!("unexpected message {:?}", m),
+        }
+        // the known ones are delivered
+        worker.handle_message(Message::GetTxs(vec![unknown, transaction.txid()]).encode(), peer.handle.clone());
+        match received(&mut peer, &mut remote) {
+            Message::Txs(transactions) => {
+                assert_eq!(transactions.len(), 1);
+                assert_eq!(transactions[0].txid(), transaction.txid());
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+    }
+
+    #[test]
+    fn unsolicited_transactions() {
+        let (worker, _server) = worker(&Arc::new(RwLock::new(Mempool::new())));
+        let (mut peer, mut remote) = connected();
+        peer.handle.set_version(VersionInfo { version: message::PROTOCOL_VERSION, services: 0, best_height: 0, nonce: 1 });
+        let key = KeyPair::random();
+        let (_, funding) = funded(&key, 1, 100_000);
+        let orphan = spend(&key, &[OutPoint::new(funding.txid(), 0)], 90_000);
+
+        // a requested transaction spending unknown outputs is no misbehavior
+        worker.handle_message(Message::NewTxHashes(vec![orphan.txid(), orphan.txid()]).encode(), peer.handle.clone());
+        assert!(matches!(received(&mut peer, &mut remote), Message::GetTxs(ref txids) if txids == &vec![orphan.txid()]));
+        worker.handle_message(Message::Txs(vec![orphan.clone()]).encode(), peer.handle.clone());
+        assert!(!peer.handle.is_banned());
+
+        // a requested invalid transaction is penalized
+        let invalid = Transaction::new(Vec::new(), vec![TxOutput::new(1, H256::default())]);
+        assert!(peer.handle.request_txs(vec![invalid.txid()], Instant::now()) == vec![invalid.txid()]);
+        worker.handle_message(Message::Txs(vec![invalid]).encode(), peer.handle.clone());
+        assert!(!peer.handle.is_banned());
+
+        // and so are transactions no longer expected
+        for _ in 0..18 {
+            worker.handle_message(Message::Txs(vec![orphan.clone()]).encode(), peer.handle.clone());
+        }
+        assert!(peer.handle.is_banned());
+    }
+
+    #[test]
+    fn inventory_limits() {
+        let (worker, _server) = worker(&Arc::new(RwLock::new(Mempool::new())));
+        let (mut peer, mut remote) = connected();
+        peer.handle.set_version(VersionInfo { version: message::PROTOCOL_VERSION, services: 0, best_height: 0, nonce: 1 });
+
+        // announcements are followed until the rate of the peer is exceeded
+        let unknown = H256::from([1u8; 32]);
+        worker.handle_message(Message::NewTxHashes(vec![unknown]).encode(), peer.handle.clone());
+        assert!(matches!(received(&mut peer, &mut remote), Message::GetTxs(ref txids) if txids == &vec![unknown]));
+        // use up the rate of the peer a minute ahead, so that it does not refill meanwhile
+        assert!(peer.handle.allow_inventory(rate_limit::INVENTORY_BURST as usize, Instant::now() + Duration::from_secs(60)));
+        worker.handle_message(Message::NewTxHashes(vec![H256::from([2u8; 32])]).encode(), peer.handle.clone());
+        worker.handle_message(Message::Ping("flood".to_string()).encode(), peer.handle.clone());
+        assert!(matches!(received(&mut peer, &mut remote), Message::Pong(_)));
+        assert!(!peer.handle.is_banned());
+
+        // oversized requests are refused, and get the peer banned
+        let oversized = vec![H256::default(); rate_limit::MAX_INVENTORY_PER_MESSAGE + 1];
+        for _ in 0..5 {
+            worker.handle_message(Message::GetTxs(oversized.clone()).encode(), peer.handle.clone());
+        }
+        assert!(peer.handle.is_banned());
+    }
+
+    #[test]
+    fn upload_target() {
+        let (worker, _server) = worker(&Arc::new(RwLock::new(Mempool::new())));
+        let (mut peer, mut remote) = connected();
+        peer.handle.set_version(VersionInfo { version: message::PROTOCOL_VERSION, services: 0, best_height: 0, nonce: 1 });
+        let hashes: Vec<H256> = {
+            let mut blockchain = worker.blockchain.write().unwrap();
+            for _ in 0..traffic::HISTORICAL_BLOCK_DEPTH {
+                let block = generate_mined_block(&blockchain.tip());
+                blockchain.insert(&block).unwrap();
+            }
+            (0..=blockchain.tip_height()).filter_map(|h| blockchain.block_at_height(h)).collect()
+        };
+        let (oldest, recent) = (hashes[0], *hashes.last().unwrap());
+
+        worker.handle_message(Message::GetBlocks(vec![oldest, recent]).encode(), peer.handle.clone());
+        assert!(matches!(received(&mut peer, &mut remote), Message::Blocks(ref blocks) if blocks.len() == 2));
+        // once the target is reached, only the recent blocks are served
+        worker.server.set_upload_target(Some(0));
+        assert!(worker.server.traffic().upload_target_reached);
+        worker.handle_message(Message::GetBlocks(vec![oldest, recent]).encode(), peer.handle.clone());
+        match received(&mut peer, &mut remote) {
+            Message::Blocks(blocks) => {
+                assert_eq!(blocks.len(), 1);
+                assert_eq!(blocks[0].hash(), recent);
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+        // nor in compact form or in parts, the historical block being left unanswered
+        worker.handle_message(Message::GetCompactBlock(oldest).encode(), peer.handle.clone());
+        worker.handle_message(Message::GetCompactBlock(recent).encode(), peer.handle.clone());
+        assert!(matches!(received(&mut peer, &mut remote), Message::CompactBlock(ref compact) if compact.hash() == recent));
+        for block in &[oldest, recent] {
+            let request = BlockTxnRequest { block: *block, indexes: vec![0] };
+            worker.handle_message(Message::GetBlockTxn(request).encode(), peer.handle.clone());
+        }
+        assert!(matches!(received(&mut peer, &mut remote), Message::BlockTxn(ref response) if response.block == recent));
+    }
+
+    #[test]
+    fn pruned_blocks() {
+        let (worker, _server) = worker(&Arc::new(RwLock::new(Mempool::new())));
+        let (mut peer, mut remote) = connected();
+        peer.handle.set_version(VersionInfo { version: message::PROTOCOL_VERSION, services: 0, best_height: 0, nonce: 1 });
+        let (pruned, kept) = {
+            let mut blockchain = worker.blockchain.write().unwrap();
+            assert_eq!(local_version(&blockchain, &worker.server).services & message::SERVICE_NETWORK, message::SERVICE_NETWORK);
+            blockchain.set_prune_depth(Some(1));
+            for _ in 0..=crate::blockchain::PRUNE_INTERVAL {
+                let block = generate_random_block(&blockchain.tip());
+                blockchain.insert(&block).unwrap();
+            }
+            let services = local_version(&blockchain, &worker.server).services;
+            assert_eq!(services & (message::SERVICE_NETWORK | message::SERVICE_NETWORK_LIMITED), message::SERVICE_NETWORK_LIMITED);
+            (blockchain.block_at_height(1).unwrap(), blockchain.tip())
+        };
+        // only the blocks kept in full are served, once each
+        worker.handle_message(Message::GetBlocks(vec![pruned, kept, kept]).encode(), peer.handle.clone());
+        match received(&mut peer, &mut remote) {
+            Message::Blocks(blocks) => {
+                assert_eq!(blocks.len(), 1);
+                assert_eq!(blocks[0].hash(), kept);
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+    }
+
+    #[test]
+    fn replays_snapshot_history() {
+        let mut source = Blockchain::new();
+        let mut blocks = vec![source.get(&source.tip())];
+        for _ in 0..2 {
+            let block = generate_random_block(&source.tip());
+            source.insert(&block).unwrap();
+            blocks.push(block);
+        }
+        let snapshot = crate::snapshot::UtxoSnapshot::create(&source);
+        let (outcome_tx, outcome_rx) = channel::unbounded();
+        let (worker, _server) = worker(&Arc::new(RwLock::new(Mempool::new())));
+        let worker = worker.with_history_validation(HistoryValidator::new(&snapshot), outcome_tx);
+        let (peer, _remote) = connected();
+        peer.handle.set_version(VersionInfo { version: message::PROTOCOL_VERSION, services: 0, best_height: 0, nonce: 1 });
+        // without full nodes connected nothing is requested
+        worker.request_history(Instant::now());
+        assert!(worker.history.lock().unwrap().as_ref().unwrap().requested.is_empty());
+        {
+            let mut history = worker.history.lock().unwrap();
+            let history = history.as_mut().unwrap();
+            history.requested = history.validator.next_batch(HISTORY_BATCH);
+        }
+        // the blocks are replayed in order, whatever the order they arrive in
+        worker.handle_message(Message::Blocks(vec![blocks[1].clone(), blocks[0].clone()]).encode(), peer.handle.clone());
+        assert!(outcome_rx.try_recv().is_err());
+        assert_eq!(worker.history.lock().unwrap().as_ref().unwrap().requested, vec![blocks[2].hash()]);
+        worker.handle_message(Message::Blocks(vec![blocks[2].clone()]).encode(), peer.handle.clone());
+        assert_eq!(outcome_rx.try_recv(), Ok(true));
+        assert!(worker.history.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn future_headers() {
+        let (worker, _server) = worker(&Arc::new(RwLock::new(Mempool::new())));
+        let (peer, _remote) = connected();
+        peer.handle.set_version(VersionInfo { version: message::PROTOCOL_VERSION, services: 0, best_height: 0, nonce: 1 });
+        let genesis = worker.blockchain.read().unwrap().tip();
+        let future = SystemTime::now() + validation::MAX_FUTURE_BLOCK_TIME * 2;
+        let block = loop {
+            let block = generate_random_block_at(&genesis, future);
+            if block.hash() <= block.get_difficulty() {
+                break block;
+            }
+        };
+        // a header too far in the future may be valid later, and is no misbehavior
+        worker.handle_message(Message::Headers(vec![block.get_header().clone()]).encode(), peer.handle.clone());
+        worker.handle_message(Message::Blocks(vec![block.clone()]).encode(), peer.handle.clone());
+        assert!(!peer.handle.is_banned());
+        assert!(!worker.blockchain.read().unwrap().header_tree().contains(&block.hash()));
+    }
+
+    #[test]
+    fn disconnection_drops_orphans() {
+        let mempool = Arc::new(RwLock::new(Mempool::new()));
+        let (worker, _server) = worker(&mempool);
+        let addr: SocketAddr = "1.2.3.4:6000".parse().unwrap();
+        let other: SocketAddr = "1.2.3.5:6000".parse().unwrap();
+        let orphan = generate_random_transaction();
+        let kept = generate_random_transaction();
+        {
+            let mut mempool = mempool.write().unwrap();
+            let orphans = mempool.get_orphans_mut();
+            orphans.insert(orphan.clone(), vec![OutPoint::new(H256::from([1u8; 32]), 0)], addr);
+            orphans.insert(kept.clone(), vec![OutPoint::new(H256::from([2u8; 32]), 0)], other);
         }
+        worker.handle_disconnection(addr);
+        let mempool = mempool.read().unwrap();
+        assert!(!mempool.get_orphans().contains(&orphan.txid()));
+        assert!(mempool.get_orphans().contains(&kept.txid()));
     }
 }
@@ -0,0 +1,277 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+
+use crate::block::Block;
+use crate::crypto::hash::{H256, Hashable};
+use crate::transaction::Transaction;
+use crate::utxo::OutPoint;
+
+/// Default number of orphan blocks kept
+pub const DEFAULT_MAX_ORPHANS: usize = 100;
+
+/// Default number of orphan transactions kept
+pub const DEFAULT_MAX_ORPHAN_TRANSACTIONS: usize = 100;
+
+/// Default number of orphan transactions kept from a single peer
+pub const DEFAULT_MAX_ORPHANS_PER_PEER: usize = 20;
+
+/// Largest orphan transaction kept, in virtual bytes, as it cannot be validated until its
+/// parents arrive
+pub const MAX_ORPHAN_TX_VSIZE: usize = 100_000;
+
+/// A bounded buffer of blocks whose parent is not known yet, keyed by the missing parent. When
+/// full, the oldest orphan is evicted.
+pub struct OrphanBlocks {
+    by_parent: HashMap<H256, Vec<Block>>,
+    /// Hash and parent hash of each orphan, oldest first
+    arrival: VecDeque<(H256, H256)>,
+    max_orphans: usize,
+}
+
+impl OrphanBlocks {
+    pub fn new(max_orphans: usize) -> Self {
+        OrphanBlocks {
+            by_parent: HashMap::new(),
+            arrival: VecDeque::new(),
+            max_orphans,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        return self.arrival.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.arrival.is_empty();
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        return self.arrival.iter().any(|(h, _)| h == hash);
+    }
+
+    /// Buffer an orphan. Returns false if it was already buffered.
+    pub fn insert(&mut self, block: Block) -> bool {
+        let hash = block.hash();
+        if self.max_orphans == 0 || self.contains(&hash) {
+            return false;
+        }
+        if self.arrival.len() >= self.max_orphans {
+            let (oldest, oldest_parent) = self.arrival.pop_front().unwrap();
+            if let Some(siblings) = self.by_parent.get_mut(&oldest_parent) {
+                siblings.retain(|b| b.hash() != oldest);
+                if siblings.is_empty() {
+                    self.by_parent.remove(&oldest_parent);
+                }
+            }
+        }
+        let parent = block.get_parent();
+        self.arrival.push_back((hash, parent));
+        self.by_parent.entry(parent).or_insert_with(Vec::new).push(block);
+        return true;
+    }
+
+    /// Remove and return the orphans waiting for `parent`
+    pub fn take_children(&mut self, parent: &H256) -> Vec<Block> {
+        let children = self.by_parent.remove(parent).unwrap_or_default();
+        if !children.is_empty() {
+            self.arrival.retain(|(_, p)| p != parent);
+        }
+        return children;
+    }
+}
+
+impl Default for OrphanBlocks {
+    fn default() -> Self {
+        OrphanBlocks::new(DEFAULT_MAX_ORPHANS)
+    }
+}
+
+/// A transaction spending outputs not found yet, with the peer that sent it
+#[derive(Debug, Clone)]
+pub struct OrphanTransaction {
+    pub transaction: Transaction,
+    /// The outputs it spends that were missing when it arrived
+    pub missing: Vec<OutPoint>,
+    pub peer: SocketAddr,
+}
+
+/// A bounded buffer of transactions whose parents are not known yet, keyed by the outputs they
+/// are missing. Each peer may only fill part of it. When full, the oldest orphan is evicted.
+#[derive(Debug)]
+pub struct OrphanTransactions {
+    orphans: HashMap<H256, OrphanTransaction>,
+    /// Txids of the orphans missing each output
+    by_missing: HashMap<OutPoint, HashSet<H256>>,
+    /// Txids of the orphans, oldest first
+    arrival: VecDeque<H256>,
+    /// Number of orphans sent by each peer
+    per_peer: HashMap<SocketAddr, usize>,
+    max_orphans: usize,
+    max_per_peer: usize,
+}
+
+impl OrphanTransactions {
+    pub fn new(max_orphans: usize, max_per_peer: usize) -> Self {
+        OrphanTransactions {
+            orphans: HashMap::new(),
+            by_missing: HashMap::new(),
+            arrival: VecDeque::new(),
+            per_peer: HashMap::new(),
+            max_orphans,
+            max_per_peer,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        return self.orphans.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.orphans.is_empty();
+    }
+
+    pub fn contains(&self, txid: &H256) -> bool {
+        return self.orphans.contains_key(txid);
+    }
+
+    /// Number of orphans kept from `peer`
+    pub fn from_peer(&self, peer: &SocketAddr) -> usize {
+        return self.per_peer.get(peer).cloned().unwrap_or(0);
+    }
+
+    /// Buffer an orphan missing the outputs `missing`. Returns false if it was already buffered,
+    /// is too large, or `peer` sent too many orphans already.
+    pub fn insert(&mut self, transaction: Transaction, missing: Vec<OutPoint>, peer: SocketAddr) -> bool {
+        let txid = transaction.txid();
+        if self.max_orphans == 0
+            || self.contains(&txid)
+            || self.from_peer(&peer) >= self.max_per_peer
+            || transaction.vsize() > MAX_ORPHAN_TX_VSIZE
+        {
+            return false;
+        }
+        if self.orphans.len() >= self.max_orphans {
+            let oldest = self.arrival[0];
+            self.remove(&oldest);
+        }
+        for outpoint in missing.iter() {
+            self.by_missing.entry(*outpoint).or_insert_with(HashSet::new).insert(txid);
+        }
+        *self.per_peer.entry(peer).or_insert(0) += 1;
+        self.arrival.push_back(txid);
+        self.orphans.insert(txid, OrphanTransaction { transaction, missing, peer });
+        return true;
+    }
+
+    /// Remove and return an orphan
+    pub fn remove(&mut self, txid: &H256) -> Option<OrphanTransaction> {
+        let orphan = self.orphans.remove(txid)?;
+        for outpoint in orphan.missing.iter() {
+            if let Some(waiting) = self.by_missing.get_mut(outpoint) {
+                waiting.remove(txid);
+                if waiting.is_empty() {
+                    self.by_missing.remove(outpoint);
+                }
+            }
+        }
+        if let Some(count) = self.per_peer.get_mut(&orphan.peer) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_peer.remove(&orphan.peer);
+            }
+        }
+        self.arrival.retain(|t| t != txid);
+        return Some(orphan);
+    }
+
+    /// Remove and return the orphans missing outputs of `parent`, oldest first
+    pub fn take_children(&mut self, parent: &Transaction) -> Vec<OrphanTransaction> {
+        let txid = parent.txid();
+        let mut children: HashSet<H256> = HashSet::new();
+        for index in 0..parent.get_outputs().len() {
+            if let Some(waiting) = self.by_missing.get(&OutPoint::new(txid, index as u32)) {
+                children.extend(waiting.iter().cloned());
+            }
+        }
+        let ordered: Vec<H256> = self.arrival.iter().filter(|t| children.contains(t)).cloned().collect();
+        return ordered.iter().filter_map(|t| self.remove(t)).collect();
+    }
+
+    /// Remove the orphans sent by `peer`, once it disconnects. Returns how many were removed.
+    pub fn remove_peer(&mut self, peer: &SocketAddr) -> usize {
+        let sent: Vec<H256> = self.orphans.values().filter(|o| o.peer == *peer).map(|o| o.transaction.txid()).collect();
+        for txid in sent.iter() {
+            self.remove(txid);
+        }
+        return sent.len();
+    }
+}
+
+impl Default for OrphanTransactions {
+    fn default() -> Self {
+        OrphanTransactions::new(DEFAULT_MAX_ORPHAN_TRANSACTIONS, DEFAULT_MAX_ORPHANS_PER_PEER)
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::transaction::tests::generate_spending_transaction;
+
+    #[test]
+    fn bounded_by_parent() {
+        let mut orphans = OrphanBlocks::new(2);
+        let parent = generate_random_block(&H256::default());
+        let a = generate_random_block(&parent.hash());
+        let b = generate_random_block(&parent.hash());
+        let c = generate_random_block(&a.hash());
+        assert!(orphans.insert(a.clone()));
+        assert!(!orphans.insert(a.clone()));
+        assert!(orphans.insert(b.clone()));
+        assert!(orphans.insert(c.clone()));
+        // a was evicted to make room for c
+        assert_eq!(orphans.len(), 2);
+        assert!(!orphans.contains(&a.hash()));
+        let children = orphans.take_children(&parent.hash());
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].hash(), b.hash());
+        assert_eq!(orphans.take_children(&a.hash()).len(), 1);
+        assert!(orphans.is_empty());
+    }
+
+    #[test]
+    fn transactions_by_missing_output() {
+        let alice: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let bob: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let mut orphans = OrphanTransactions::new(3, 2);
+        let parent = generate_spending_transaction(&H256::default(), 0);
+        let first = generate_spending_transaction(&parent.txid(), 0);
+        let second = generate_spending_transaction(&parent.txid(), 0);
+        let other = generate_spending_transaction(&H256::from([9u8; 32]), 0);
+        let missing = vec![OutPoint::new(parent.txid(), 0)];
+        assert!(orphans.insert(first.clone(), missing.clone(), alice));
+        assert!(!orphans.insert(first.clone(), missing.clone(), alice));
+        assert!(orphans.insert(second.clone(), missing.clone(), alice));
+        // alice sent her share
+        assert!(!orphans.insert(other.clone(), vec![OutPoint::new(other.get_inputs()[0].prev_txid, 0)], alice));
+        assert_eq!(orphans.from_peer(&alice), 2);
+
+        let children = orphans.take_children(&parent);
+        let txids: Vec<H256> = children.iter().map(|o| o.transaction.txid()).collect();
+        assert_eq!(txids, vec![first.txid(), second.txid()]);
+        assert_eq!(children[0].peer, alice);
+        assert!(orphans.is_empty());
+        assert_eq!(orphans.from_peer(&alice), 0);
+
+        // the oldest is evicted once full
+        for (i, peer) in [alice, bob, bob, alice].iter().enumerate() {
+            let t = generate_spending_transaction(&H256::from([i as u8 + 1; 32]), 0);
+            assert!(orphans.insert(t.clone(), vec![t.get_inputs()[0].outpoint()], *peer));
+        }
+        assert_eq!(orphans.len(), 3);
+        assert_eq!(orphans.from_peer(&alice), 1);
+        assert_eq!(orphans.remove_peer(&bob), 2);
+        assert_eq!(orphans.len(), 1);
+    }
+}
@@ -0,0 +1,254 @@
+//! Standardness rules checked before accepting a transaction to relay or mine, stricter than
+//! validity: a valid transaction breaking them may still be in a block mined by someone else
+
+use crate::crypto::keys;
+use crate::script::{Instruction, Script};
+use crate::transaction::{Amount, InputSignature, SignatureScheme, Transaction, TxInput, TxOutput};
+use crate::utxo::OutPoint;
+
+/// Outputs worth less than this cost more in fees to spend than they are worth
+pub const DEFAULT_DUST_THRESHOLD: Amount = 546;
+
+/// Largest unlocking script and locking script of a standard transaction, in bytes
+pub const DEFAULT_MAX_SCRIPT_SIZE: usize = 1650;
+
+/// Most bytes a standard data carrier output may carry, see `Script::null_data`
+pub const DEFAULT_MAX_DATA_SIZE: usize = 80;
+
+/// Most signature checks a standard transaction may run, see `Script::sigop_count`
+pub const DEFAULT_MAX_SIGOPS: usize = 4000;
+
+/// Reasons for a transaction not to be standard
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyError {
+    /// The output at the index is worth less than the dust threshold
+    Dust { index: usize, value: Amount },
+    /// The unlocking script of the input at the index is too large
+    InputScriptSize(usize),
+    /// The locking script of the output at the index is too large
+    OutputScriptSize(usize),
+    /// The output at the index is unspendable, but does not only push at most the allowed data
+    DataCarrier(usize),
+    /// More than one output carries data
+    MultipleDataOutputs,
+    TooManySigops(usize),
+    /// The input at the index carries an ECDSA signature whose S is not low, see
+    /// `keys::is_low_s`
+    HighS(usize),
+}
+
+impl std::fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PolicyError::Dust { index, value } => write!(f, "output {} of {} is dust", index, value),
+            PolicyError::InputScriptSize(index) => write!(f, "unlocking script of input {} too large", index),
+            PolicyError::OutputScriptSize(index) => write!(f, "locking script of output {} too large", index),
+            PolicyError::DataCarrier(index) => write!(f, "output {} is not a standard data carrier", index),
+            PolicyError::MultipleDataOutputs => write!(f, "more than one data carrier output"),
+            PolicyError::TooManySigops(count) => write!(f, "{} signature operations", count),
+            PolicyError::HighS(index) => write!(f, "signature of input {} has a high S", index),
+        }
+    }
+}
+
+/// The limits a transaction must keep to be relayed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    pub dust_threshold: Amount,
+    pub max_script_size: usize,
+    /// Most bytes of data a transaction may carry in its data carrier output
+    pub max_data_size: usize,
+    pub max_sigops: usize,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            dust_threshold: DEFAULT_DUST_THRESHOLD,
+            max_script_size: DEFAULT_MAX_SCRIPT_SIZE,
+            max_data_size: DEFAULT_MAX_DATA_SIZE,
+            max_sigops: DEFAULT_MAX_SIGOPS,
+        }
+    }
+}
+
+impl Policy {
+    /// Check that `transaction`, spending the outputs found by `lookup`, is standard. It is meant
+    /// to be valid already, see `validation::check_transaction`.
+    pub fn check<'a, F>(&self, transaction: &Transaction, lookup: F) -> Result<(), PolicyError>
+    where
+        F: Fn(&OutPoint) -> Option<&'a TxOutput>,
+    {
+        let mut data_outputs = 0;
+        for (index, output) in transaction.get_outputs().iter().enumerate() {
+            if output.is_unspendable() {
+                // never spent, so never dust
+                match output.script_pubkey.null_data_payload() {
+                    Some(data) if data.len() <= self.max_data_size => data_outputs += 1,
+                    _ => return Err(PolicyError::DataCarrier(index)),
+                }
+                if data_outputs > 1 {
+                    return Err(PolicyError::MultipleDataOutputs);
+                }
+                continue;
+            }
+            if output.value < self.dust_threshold {
+                return Err(PolicyError::Dust { index, value: output.value });
+            }
+            if output.script_pubkey.len() > self.max_script_size {
+                return Err(PolicyError::OutputScriptSize(index));
+            }
+        }
+        let mut sigops = transaction.get_outputs().iter().map(|o| o.script_pubkey.sigop_count(false)).sum::<usize>();
+        for (index, input) in transaction.get_inputs().iter().enumerate() {
+            if input.script.len() > self.max_script_size {
+                return Err(PolicyError::InputScriptSize(index));
+            }
+            if !has_low_s_signatures(input) {
+                return Err(PolicyError::HighS(index));
+            }
+            sigops += input_sigops(input, lookup(&input.outpoint()));
+        }
+        if sigops > self.max_sigops {
+            return Err(PolicyError::TooManySigops(sigops));
+        }
+        return Ok(());
+    }
+}
+
+/// Signature checks run by `input` spending `spent`: those of its unlocking script, and of the
+/// redeem script or witness script it reveals
+fn input_sigops(input: &TxInput, spent: Option<&TxOutput>) -> usize {
+    let script_sig = Script::from_bytes(input.script.clone());
+    let mut count = script_sig.sigop_count(false);
+    let script_pubkey = match spent {
+        Some(output) if output.scheme == SignatureScheme::Script => &output.script_pubkey,
+        _ => return count,
+    };
+    if script_pubkey.is_p2sh() {
+        if let Some(Instruction::Push(redeem_script)) = script_sig.instructions().ok().and_then(|i| i.last().cloned()) {
+            count += Script::from_bytes(redeem_script).sigop_count(true);
+        }
+    } else if let Some(program) = script_pubkey.witness_program() {
+        count += match (program.len(), input.witness.last()) {
+            (20, _) => 1,
+            (_, Some(witness_script)) => Script::from_bytes(witness_script.clone()).sigop_count(true),
+            (_, None) => 0,
+        };
+    }
+    return count;
+}
+
+/// Whether the ECDSA signatures `input` carries have a low S. In scripts and witnesses, any item
+/// encoded as a signature followed by a sighash type is taken for one.
+fn has_low_s_signatures(input: &TxInput) -> bool {
+    if let Some(InputSignature::Ecdsa { signature, .. }) = input.signature() {
+        return keys::is_low_s(&signature);
+    }
+    let pushes = Script::from_bytes(input.script.clone()).instructions().unwrap_or_default();
+    let script_items = pushes.into_iter().filter_map(|i| match i {
+        Instruction::Push(item) => Some(item),
+        Instruction::Op(_) => None,
+    });
+    return script_items.chain(input.witness.iter().cloned()).all(|item| match item.split_last() {
+        Some((_, signature)) if keys::is_strict_der(signature) => keys::is_low_s(signature),
+        _ => true,
+    });
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::H256;
+
+    #[test]
+    fn standardness() {
+        let policy = Policy::default();
+        let keys: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; 33]).collect();
+        let redeem_script = Script::multisig(2, &keys);
+        let spent = TxOutput::with_script(10_000, Script::p2sh_of(&redeem_script));
+        let mut input = TxInput::new(H256::from([1u8; 32]), 0);
+        input.script = Script::p2sh_unlock(&[vec![0u8; 72], vec![0u8; 72]], &redeem_script).as_bytes().to_vec();
+        let pay = |value: Amount| TxOutput::with_script(value, Script::p2pkh(&[5u8; 20]));
+        let transaction = Transaction::new(vec![input.clone()], vec![pay(1_000)]);
+        let lookup = |_: &OutPoint| Some(&spent);
+        assert_eq!(policy.check(&transaction, lookup), Ok(()));
+        assert_eq!(input_sigops(&input, Some(&spent)), 3);
+        assert_eq!(input_sigops(&input, None), 0);
+
+        let dust = Transaction::new(vec![input.clone()], vec![pay(1_000), pay(545)]);
+        assert_eq!(policy.check(&dust, lookup), Err(PolicyError::Dust { index: 1, value: 545 }));
+        let relaxed = Policy { dust_threshold: 0, ..Policy::default() };
+        assert_eq!(relaxed.check(&dust, lookup), Ok(()));
+
+        let mut large = input.clone();
+        large.script = vec![0u8; DEFAULT_MAX_SCRIPT_SIZE + 1];
+        let transaction = Transaction::new(vec![input.clone(), large], vec![pay(1_000)]);
+        assert_eq!(policy.check(&transaction, lookup), Err(PolicyError::InputScriptSize(1)));
+
+        let checks = Script::from_bytes(vec![crate::script::opcodes::OP_CHECKMULTISIG; 300]);
+        let transaction = Transaction::new(vec![input], vec![TxOutput::with_script(1_000, checks)]);
+        assert_eq!(policy.check(&transaction, lookup), Err(PolicyError::TooManySigops(300 * 16 + 3)));
+    }
+
+    #[test]
+    fn data_carrier() {
+        let policy = Policy::default();
+        let spent = TxOutput::new(10_000, H256::default());
+        let lookup = |_: &OutPoint| Some(&spent);
+        let input = TxInput::new(H256::from([1u8; 32]), 0);
+        let pay = TxOutput::with_script(1_000, Script::p2pkh(&[5u8; 20]));
+        let with = |outputs: Vec<TxOutput>| Transaction::new(vec![input.clone()], outputs);
+
+        let anchor = TxOutput::data(&[7u8; DEFAULT_MAX_DATA_SIZE]);
+        assert_eq!(anchor.script_pubkey.null_data_payload(), Some(vec![7u8; DEFAULT_MAX_DATA_SIZE]));
+        assert_eq!(policy.check(&with(vec![pay.clone(), anchor.clone()]), lookup), Ok(()));
+        assert_eq!(
+            policy.check(&with(vec![anchor.clone(), anchor.clone()]), lookup),
+            Err(PolicyError::MultipleDataOutputs)
+        );
+        let large = TxOutput::data(&[7u8; DEFAULT_MAX_DATA_SIZE + 1]);
+        assert_eq!(policy.check(&with(vec![pay.clone(), large.clone()]), lookup), Err(PolicyError::DataCarrier(1)));
+        let relaxed = Policy { max_data_size: 200, ..Policy::default() };
+        assert_eq!(relaxed.check(&with(vec![large]), lookup), Ok(()));
+        let script = Script::null_data(&[1]).push_opcode(crate::script::opcodes::OP_CHECKSIG);
+        assert_eq!(policy.check(&with(vec![TxOutput::with_script(0, script)]), lookup), Err(PolicyError::DataCarrier(0)));
+    }
+
+    #[test]
+    fn high_s() {
+        let key = keys::KeyPair::random();
+        let policy = Policy::default();
+        let spent = TxOutput::with_script(10_000, Script::p2pkh(&crate::crypto::ripemd160::hash160(key.public_key().as_bytes())));
+        let lookup = |_: &OutPoint| Some(&spent);
+        let mut transaction = Transaction::new(
+            vec![TxInput::new(crate::crypto::hash::H256::from([1u8; 32]), 0)],
+            vec![TxOutput::new(5_000, crate::crypto::hash::H256::default())],
+        );
+        transaction.sign_p2pkh(&key).unwrap();
+        assert_eq!(policy.check(&transaction, lookup), Ok(()));
+
+        // the signature with the other S is valid, but gives another txid
+        let items = Script::from_bytes(transaction.get_inputs()[0].script.clone()).instructions().unwrap();
+        let (signature, public_key) = match (&items[0], &items[1]) {
+            (Instruction::Push(signature), Instruction::Push(public_key)) => (signature.clone(), public_key.clone()),
+            _ => unreachable!(),
+        };
+        let (sighash_type, der) = signature.split_last().unwrap();
+        let mut high = keys::negate_s(der);
+        high.push(*sighash_type);
+        let mut malleated = transaction.clone();
+        malleated.set_script(0, &Script::p2pkh_unlock(&high, &public_key));
+        assert_ne!(malleated.txid(), transaction.txid());
+        assert_eq!(policy.check(&malleated, lookup), Err(PolicyError::HighS(0)));
+
+        let mut legacy = Transaction::new(transaction.get_inputs().to_vec(), transaction.get_outputs().to_vec());
+        legacy.sign(&key).unwrap();
+        assert!(has_low_s_signatures(&legacy.get_inputs()[0]));
+        let mut input = legacy.get_inputs()[0].clone();
+        if let Some(InputSignature::Ecdsa { signature, public_key }) = input.signature() {
+            input.script = InputSignature::Ecdsa { signature: keys::negate_s(&signature), public_key }.to_script().as_bytes().to_vec();
+        }
+        assert!(!has_low_s_signatures(&input));
+    }
+}
@@ -0,0 +1,432 @@
+//! Partially signed transactions: an unsigned transaction passed between the parties that sign
+//! it, carrying what each of them needs to know about the inputs
+
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+
+use crate::crypto::hash::H256;
+use crate::crypto::keys::{self, KeyError};
+use crate::crypto::ripemd160::hash160;
+use crate::crypto::schnorr;
+use crate::multisig::{MultisigError, PartialMultisig};
+use crate::script::{Script, TransactionChecker};
+use crate::transaction::{Amount, InputSignature, SigHashType, SignatureScheme, Transaction, TxOutput};
+use crate::utxo::OutPoint;
+use crate::validation::{check_transaction, TxError};
+
+/// Reasons for an operation on a `Psbt` to fail
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PsbtError {
+    /// The transaction given to `Psbt::new` has scripts or witnesses
+    Signed,
+    /// The transactions being combined are different
+    TransactionMismatch,
+    /// The output spent by the input at the index is not known
+    MissingUtxo(usize),
+    /// No signatures unlocking the input at the index were collected
+    MissingSignature(usize),
+    /// The output spent by the input at the index is locked by a script no signer knows how to
+    /// unlock, or its redeem or witness script is missing
+    UnsupportedScript(usize),
+    /// The input at the index is not finalized
+    NotFinalized(usize),
+    Multisig(usize, MultisigError),
+    /// The extracted transaction is not valid
+    Invalid(TxError),
+    Key(KeyError),
+    Decode,
+}
+
+impl std::fmt::Display for PsbtError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PsbtError::Signed => write!(f, "transaction already signed"),
+            PsbtError::TransactionMismatch => write!(f, "partially signed transactions of different transactions"),
+            PsbtError::MissingUtxo(index) => write!(f, "output spent by input {} unknown", index),
+            PsbtError::MissingSignature(index) => write!(f, "no signature for input {}", index),
+            PsbtError::UnsupportedScript(index) => write!(f, "cannot unlock the output spent by input {}", index),
+            PsbtError::NotFinalized(index) => write!(f, "input {} not finalized", index),
+            PsbtError::Multisig(index, e) => write!(f, "input {}: {}", index, e),
+            PsbtError::Invalid(e) => write!(f, "invalid transaction: {}", e),
+            PsbtError::Key(e) => write!(f, "signing failed: {}", e),
+            PsbtError::Decode => write!(f, "malformed partially signed transaction"),
+        }
+    }
+}
+
+impl From<KeyError> for PsbtError {
+    fn from(e: KeyError) -> Self {
+        PsbtError::Key(e)
+    }
+}
+
+/// Where a key comes from: the fingerprint of the master key it derives from, and the derivation
+/// path, so a signer can find its keys
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeySource {
+    pub fingerprint: [u8; 4],
+    pub path: Vec<u32>,
+}
+
+/// What the signers know about an input
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct PsbtInput {
+    /// The output the input spends
+    pub utxo: Option<TxOutput>,
+    /// Type of the signatures to make, `SigHashType::ALL` if None
+    pub sighash_type: Option<SigHashType>,
+    /// Signature by each public key, followed by its sighash type for script outputs
+    pub partial_signatures: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Redeem script of a p2sh output
+    pub redeem_script: Option<Script>,
+    /// Witness script of a p2wsh output
+    pub witness_script: Option<Script>,
+    pub derivations: BTreeMap<Vec<u8>, KeySource>,
+    /// Unlocking script and witness, once enough signatures are collected
+    pub final_script: Option<Script>,
+    pub final_witness: Option<Vec<Vec<u8>>>,
+}
+
+/// What the signers know about an output, e.g. to recognize their change
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct PsbtOutput {
+    pub redeem_script: Option<Script>,
+    pub witness_script: Option<Script>,
+    pub derivations: BTreeMap<Vec<u8>, KeySource>,
+}
+
+/// An unsigned transaction with the data about its inputs and outputs its signers need. Each
+/// signer adds their signatures, partially signed transactions with different signatures are
+/// combined, then the inputs are finalized and the signed transaction extracted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Psbt {
+    transaction: Transaction,
+    inputs: Vec<PsbtInput>,
+    outputs: Vec<PsbtOutput>,
+}
+
+impl Psbt {
+    /// A partially signed transaction of `transaction`, which must not be signed yet
+    pub fn new(transaction: Transaction) -> Result<Self, PsbtError> {
+        if transaction.get_inputs().iter().any(|i| !i.script.is_empty() || !i.witness.is_empty()) {
+            return Err(PsbtError::Signed);
+        }
+        let inputs = vec![PsbtInput::default(); transaction.get_inputs().len()];
+        let outputs = vec![PsbtOutput::default(); transaction.get_outputs().len()];
+        return Ok(Psbt { transaction, inputs, outputs });
+    }
+
+    pub fn get_transaction(&self) -> &Transaction {
+        return &self.transaction;
+    }
+
+    pub fn get_inputs(&self) -> &[PsbtInput] {
+        return &self.inputs;
+    }
+
+    pub fn get_outputs(&self) -> &[PsbtOutput] {
+        return &self.outputs;
+    }
+
+    pub fn input_mut(&mut self, index: usize) -> &mut PsbtInput {
+        return &mut self.inputs[index];
+    }
+
+    pub fn output_mut(&mut self, index: usize) -> &mut PsbtOutput {
+        return &mut self.outputs[index];
+    }
+
+    /// The fee of the transaction, None if an output spent is not known or they are worth less
+    /// than the outputs
+    pub fn fee(&self) -> Option<Amount> {
+        let mut value: Amount = 0;
+        for input in &self.inputs {
+            value = value.checked_add(input.utxo.as_ref()?.value)?;
+        }
+        return value.checked_sub(self.transaction.output_value()?);
+    }
+
+    /// Sign all inputs the ECDSA `key` can help unlock. Returns the number of inputs signed.
+    pub fn sign(&mut self, key: &keys::KeyPair) -> Result<usize, PsbtError> {
+        let public_key = key.public_key();
+        return self.sign_with(SignatureScheme::Ecdsa, public_key.as_bytes(), &public_key.address(), |message| key.sign(message));
+    }
+
+    /// Sign all inputs the Schnorr `key` can help unlock, see `sign`
+    pub fn sign_schnorr(&mut self, key: &schnorr::KeyPair) -> Result<usize, PsbtError> {
+        let public_key = key.public_key();
+        return self.sign_with(SignatureScheme::Schnorr, public_key.as_bytes(), &public_key.address(), |message| key.sign(message));
+    }
+
+    fn sign_with<F>(&mut self, scheme: SignatureScheme, public_key: &[u8], address: &H256, sign: F) -> Result<usize, PsbtError>
+    where
+        F: Fn(&[u8]) -> Result<Vec<u8>, KeyError>,
+    {
+        let mut signed = 0;
+        for index in 0..self.inputs.len() {
+            let input = &self.inputs[index];
+            let utxo = match &input.utxo {
+                Some(utxo) => utxo,
+                None => continue,
+            };
+            let signature = if utxo.scheme == SignatureScheme::Script {
+                if !input.can_sign(utxo, public_key) {
+                    continue;
+                }
+                let sighash_type = input.sighash_type.unwrap_or(SigHashType::ALL);
+                let signature_hash = match self.transaction.signature_hash_for(index, sighash_type) {
+                    Some(signature_hash) => signature_hash,
+                    None => continue,
+                };
+                let mut signature = sign(signature_hash.as_ref())?;
+                signature.push(sighash_type.to_byte());
+                signature
+            } else if utxo.scheme == scheme && utxo.recipient == *address {
+                sign(self.transaction.signature_hash().as_ref())?
+            } else {
+                continue;
+            };
+            self.inputs[index].partial_signatures.insert(public_key.to_vec(), signature);
+            signed += 1;
+        }
+        return Ok(signed);
+    }
+
+    /// Add the data and signatures of another partially signed transaction of the same
+    /// transaction
+    pub fn combine(&mut self, other: &Psbt) -> Result<(), PsbtError> {
+        if other.transaction.txid() != self.transaction.txid() {
+            return Err(PsbtError::TransactionMismatch);
+        }
+        for (mine, theirs) in self.inputs.iter_mut().zip(other.inputs.iter()) {
+            mine.utxo = mine.utxo.take().or_else(|| theirs.utxo.clone());
+            mine.sighash_type = mine.sighash_type.or(theirs.sighash_type);
+            mine.redeem_script = mine.redeem_script.take().or_else(|| theirs.redeem_script.clone());
+            mine.witness_script = mine.witness_script.take().or_else(|| theirs.witness_script.clone());
+            mine.final_script = mine.final_script.take().or_else(|| theirs.final_script.clone());
+            mine.final_witness = mine.final_witness.take().or_else(|| theirs.final_witness.clone());
+            for (key, signature) in &theirs.partial_signatures {
+                mine.partial_signatures.entry(key.clone()).or_insert_with(|| signature.clone());
+            }
+            for (key, source) in &theirs.derivations {
+                mine.derivations.entry(key.clone()).or_insert_with(|| source.clone());
+            }
+        }
+        for (mine, theirs) in self.outputs.iter_mut().zip(other.outputs.iter()) {
+            mine.redeem_script = mine.redeem_script.take().or_else(|| theirs.redeem_script.clone());
+            mine.witness_script = mine.witness_script.take().or_else(|| theirs.witness_script.clone());
+            for (key, source) in &theirs.derivations {
+                mine.derivations.entry(key.clone()).or_insert_with(|| source.clone());
+            }
+        }
+        return Ok(());
+    }
+
+    /// Build the unlocking script and witness of each input from the signatures collected, and
+    /// drop the data only signers need
+    pub fn finalize(&mut self) -> Result<(), PsbtError> {
+        for index in 0..self.inputs.len() {
+            if self.inputs[index].final_script.is_some() || self.inputs[index].final_witness.is_some() {
+                continue;
+            }
+            let (script, witness) = self.finalize_input(index)?;
+            let input = &mut self.inputs[index];
+            input.final_script = Some(script);
+            input.final_witness = Some(witness);
+            input.partial_signatures.clear();
+            input.derivations.clear();
+            input.redeem_script = None;
+            input.witness_script = None;
+        }
+        return Ok(());
+    }
+
+    fn finalize_input(&self, index: usize) -> Result<(Script, Vec<Vec<u8>>), PsbtError> {
+        let input = &self.inputs[index];
+        let utxo = input.utxo.as_ref().ok_or(PsbtError::MissingUtxo(index))?;
+        let missing = PsbtError::MissingSignature(index);
+        let signatures = &input.partial_signatures;
+        match utxo.scheme {
+            SignatureScheme::Ecdsa => {
+                let (public_key, signature) = signatures
+                    .iter()
+                    .map(|(k, s)| (keys::PublicKey::from_bytes(k), s))
+                    .find(|(k, _)| k.address() == utxo.recipient)
+                    .ok_or(missing)?;
+                let signature = InputSignature::Ecdsa { signature: signature.clone(), public_key };
+                return Ok((signature.to_script(), Vec::new()));
+            }
+            SignatureScheme::Schnorr => {
+                let (public_key, signature) = signatures
+                    .iter()
+                    .filter(|(k, _)| k.len() == 32)
+                    .map(|(k, s)| {
+                        let mut bytes = [0u8; 32];
+                        bytes.copy_from_slice(k);
+                        (schnorr::PublicKey::from_bytes(bytes), s)
+                    })
+                    .find(|(k, _)| k.address() == utxo.recipient)
+                    .ok_or(missing)?;
+                let signature = InputSignature::Schnorr { signature: signature.clone(), public_key };
+                return Ok((signature.to_script(), Vec::new()));
+            }
+            SignatureScheme::Script => {}
+        }
+        let script_pubkey = &utxo.script_pubkey;
+        if let Some(key_hash) = script_pubkey.key_hash() {
+            let (public_key, signature) = signatures.iter().find(|(k, _)| hash160(k) == key_hash).ok_or(missing)?;
+            if script_pubkey.witness_program().is_some() {
+                return Ok((Script::new(), vec![signature.clone(), public_key.clone()]));
+            }
+            return Ok((Script::p2pkh_unlock(signature, public_key), Vec::new()));
+        }
+        let multisig_script = input.multisig_script(utxo).ok_or(PsbtError::UnsupportedScript(index))?;
+        let mut partial = PartialMultisig::new(multisig_script.clone()).map_err(|e| PsbtError::Multisig(index, e))?;
+        let checker = TransactionChecker::new(&self.transaction, index);
+        for (public_key, signature) in signatures {
+            partial.add_signature(public_key, signature.clone(), &checker).map_err(|e| PsbtError::Multisig(index, e))?;
+        }
+        let unlocked = if script_pubkey.is_p2sh() {
+            partial.unlock_p2sh().map(|script| (script, Vec::new()))
+        } else if script_pubkey.witness_program().is_some() {
+            partial.witness().map(|witness| (Script::new(), witness))
+        } else {
+            partial.unlock().map(|script| (script, Vec::new()))
+        };
+        return unlocked.map_err(|e| PsbtError::Multisig(index, e));
+    }
+
+    /// The signed transaction, once all inputs are finalized. It is checked against the outputs
+    /// it spends.
+    pub fn extract(&self) -> Result<Transaction, PsbtError> {
+        let mut transaction = self.transaction.clone();
+        for (index, input) in self.inputs.iter().enumerate() {
+            if input.final_script.is_none() && input.final_witness.is_none() {
+                return Err(PsbtError::NotFinalized(index));
+            }
+            transaction.set_script(index, &input.final_script.clone().unwrap_or_default());
+            transaction.set_witness(index, input.final_witness.clone().unwrap_or_default());
+        }
+        let utxos: Vec<(OutPoint, &TxOutput)> = transaction
+            .get_inputs()
+            .iter()
+            .zip(self.inputs.iter())
+            .filter_map(|(i, psbt_input)| psbt_input.utxo.as_ref().map(|utxo| (i.outpoint(), utxo)))
+            .collect();
+        let lookup = |outpoint: &OutPoint| utxos.iter().find(|(o, _)| o == outpoint).map(|(_, utxo)| *utxo);
+        check_transaction(&transaction, lookup).map_err(PsbtError::Invalid)?;
+        return Ok(transaction);
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        return bincode::serialize(self).unwrap();
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, PsbtError> {
+        let psbt: Psbt = bincode::deserialize(bytes).map_err(|_| PsbtError::Decode)?;
+        if psbt.inputs.len() != psbt.transaction.get_inputs().len() || psbt.outputs.len() != psbt.transaction.get_outputs().len() {
+            return Err(PsbtError::Decode);
+        }
+        return Ok(psbt);
+    }
+}
+
+impl PsbtInput {
+    /// The multisig script `utxo` is locked by: itself, or its redeem or witness script
+    fn multisig_script<'a>(&'a self, utxo: &'a TxOutput) -> Option<&'a Script> {
+        let script_pubkey = &utxo.script_pubkey;
+        let script = if script_pubkey.is_p2sh() {
+            self.redeem_script.as_ref().filter(|s| Script::p2sh_of(s) == *script_pubkey)?
+        } else if script_pubkey.witness_program().map_or(false, |program| program.len() == 32) {
+            self.witness_script.as_ref().filter(|s| Script::p2wsh_of(s) == *script_pubkey)?
+        } else {
+            script_pubkey
+        };
+        return script.multisig_keys().map(|_| script);
+    }
+
+    /// Whether a signature by `public_key` helps unlock the script output `utxo`
+    fn can_sign(&self, utxo: &TxOutput, public_key: &[u8]) -> bool {
+        if let Some(key_hash) = utxo.script_pubkey.key_hash() {
+            return hash160(public_key) == key_hash;
+        }
+        return match self.multisig_script(utxo).and_then(|s| s.multisig_keys()) {
+            Some((_, keys)) => keys.iter().any(|k| k[..] == public_key[..]),
+            None => false,
+        };
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::transaction::TxInput;
+
+    #[test]
+    fn multisig_signers() {
+        let keys: Vec<keys::KeyPair> = (0..3).map(|_| keys::KeyPair::random()).collect();
+        let public_keys: Vec<Vec<u8>> = keys.iter().map(|k| k.public_key().as_bytes().to_vec()).collect();
+        let witness_script = Script::multisig(2, &public_keys);
+        let single = keys::KeyPair::random();
+        let utxos = vec![
+            TxOutput::with_script(5_000, Script::p2wsh_of(&witness_script)),
+            TxOutput::with_script(3_000, Script::p2wpkh(&hash160(single.public_key().as_bytes()))),
+            TxOutput::new(2_000, single.public_key().address()),
+        ];
+        let inputs = (0..3u8).map(|i| TxInput::new(H256::from([i + 1; 32]), 0)).collect();
+        let transaction = Transaction::new(inputs, vec![TxOutput::new(9_000, H256::default())]);
+
+        let mut psbt = Psbt::new(transaction).unwrap();
+        for (index, utxo) in utxos.iter().enumerate() {
+            psbt.input_mut(index).utxo = Some(utxo.clone());
+        }
+        psbt.input_mut(0).witness_script = Some(witness_script.clone());
+        assert_eq!(psbt.fee(), Some(1_000));
+
+        // the signers sign copies, passed around serialized
+        let mut first = Psbt::deserialize(&psbt.serialize()).unwrap();
+        assert_eq!(first.sign(&keys[0]).unwrap(), 1);
+        assert_eq!(first.sign(&single).unwrap(), 2);
+        let mut second = Psbt::deserialize(&psbt.serialize()).unwrap();
+        assert_eq!(second.sign(&keys[2]).unwrap(), 1);
+        assert_eq!(second.sign(&keys::KeyPair::random()).unwrap(), 0);
+
+        let mut incomplete = first.clone();
+        assert_eq!(
+            incomplete.finalize(),
+            Err(PsbtError::Multisig(0, MultisigError::Incomplete { have: 1, need: 2 }))
+        );
+        assert_eq!(first.extract().err(), Some(PsbtError::NotFinalized(0)));
+
+        first.combine(&second).unwrap();
+        first.finalize().unwrap();
+        assert!(first.get_inputs()[0].partial_signatures.is_empty());
+        let signed = first.extract().unwrap();
+        assert_eq!(signed.signature_hash(), psbt.get_transaction().signature_hash());
+        assert!(signed.has_witness());
+        assert_eq!(signed.get_inputs()[0].witness.len(), 3);
+
+        let other = Psbt::new(Transaction::new(vec![TxInput::new(H256::default(), 0)], Vec::new())).unwrap();
+        assert_eq!(first.combine(&other), Err(PsbtError::TransactionMismatch));
+        assert_eq!(Psbt::new(signed).err(), Some(PsbtError::Signed));
+        assert_eq!(Psbt::deserialize(&[1, 2, 3]).err(), Some(PsbtError::Decode));
+    }
+
+    #[test]
+    fn missing_data() {
+        let key = keys::KeyPair::random();
+        let redeem_script = Script::multisig(1, &[key.public_key().as_bytes().to_vec()]);
+        let transaction = Transaction::new(vec![TxInput::new(H256::from([1u8; 32]), 0)], vec![TxOutput::new(1, H256::default())]);
+        let mut psbt = Psbt::new(transaction).unwrap();
+        assert_eq!(psbt.sign(&key).unwrap(), 0);
+        assert_eq!(psbt.clone().finalize(), Err(PsbtError::MissingUtxo(0)));
+        psbt.input_mut(0).utxo = Some(TxOutput::with_script(10, Script::p2sh_of(&redeem_script)));
+        // the key is only known to be in the redeem script once it is given
+        assert_eq!(psbt.sign(&key).unwrap(), 0);
+        assert_eq!(psbt.clone().finalize(), Err(PsbtError::UnsupportedScript(0)));
+        psbt.input_mut(0).redeem_script = Some(redeem_script);
+        assert_eq!(psbt.sign(&key).unwrap(), 1);
+        psbt.finalize().unwrap();
+        assert_eq!(psbt.extract().unwrap().get_inputs()[0].script, psbt.get_inputs()[0].final_script.clone().unwrap().as_bytes());
+    }
+}
@@ -0,0 +1,132 @@
+//! Opt-in replace-by-fee: rules for an unconfirmed transaction to replace the unconfirmed
+//! transactions it conflicts with, and their descendants
+
+use crate::crypto::hash::H256;
+use crate::fee::FeeRate;
+use crate::transaction::Amount;
+
+/// Most transactions a replacement may evict, counting descendants of the conflicts
+pub const MAX_REPLACED: usize = 100;
+
+/// Rate a replacement must pay for its own size on top of the fees of the transactions it
+/// evicts, so that relaying it is paid for
+pub const INCREMENTAL_RELAY_FEE: FeeRate = FeeRate::from_sat_per_kvb(1000);
+
+/// An unconfirmed transaction a replacement would evict
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replaced {
+    pub txid: H256,
+    pub fee: Amount,
+    pub vsize: usize,
+    /// Whether it spends an output the replacement spends, rather than descending from a
+    /// transaction that does
+    pub conflict: bool,
+    /// Whether it or one of its unconfirmed ancestors signals replacement, see
+    /// `Transaction::signals_replacement`
+    pub replaceable: bool,
+}
+
+impl Replaced {
+    pub fn fee_rate(&self) -> FeeRate {
+        return FeeRate::from_fee(self.fee, self.vsize);
+    }
+}
+
+/// Reasons for `check_replacement` to refuse a replacement
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplacementError {
+    /// A conflicting transaction did not opt in to replacement
+    NotReplaceable(H256),
+    /// More than `MAX_REPLACED` transactions would be evicted
+    TooManyReplaced(usize),
+    /// The replacement pays a lower rate than a conflicting transaction
+    LowFeeRate { txid: H256, fee_rate: FeeRate, replaced: FeeRate },
+    /// The replacement does not pay for the evicted transactions and its own relay
+    LowFee { fee: Amount, needed: Amount },
+}
+
+impl std::fmt::Display for ReplacementError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ReplacementError::NotReplaceable(txid) => write!(f, "conflicting transaction {} is not replaceable", txid),
+            ReplacementError::TooManyReplaced(count) => write!(f, "would replace {} transactions", count),
+            ReplacementError::LowFeeRate { txid, fee_rate, replaced } => {
+                write!(f, "fee rate {} is not above {} of conflicting transaction {}", fee_rate, replaced, txid)
+            }
+            ReplacementError::LowFee { fee, needed } => write!(f, "fee {} is below the {} needed", fee, needed),
+        }
+    }
+}
+
+/// Whether a transaction paying `fee` for `vsize` virtual bytes may replace the transactions
+/// in `replaced`, the ones it conflicts with and all their descendants: all conflicts signal
+/// replacement, at most `MAX_REPLACED` are evicted, it pays a higher rate than each conflict,
+/// and its fee covers those of all evicted transactions plus `INCREMENTAL_RELAY_FEE` for its
+/// own size.
+pub fn check_replacement(fee: Amount, vsize: usize, replaced: &[Replaced]) -> Result<(), ReplacementError> {
+    if let Some(original) = replaced.iter().find(|r| r.conflict && !r.replaceable) {
+        return Err(ReplacementError::NotReplaceable(original.txid));
+    }
+    if replaced.len() > MAX_REPLACED {
+        return Err(ReplacementError::TooManyReplaced(replaced.len()));
+    }
+    let fee_rate = FeeRate::from_fee(fee, vsize);
+    for original in replaced.iter().filter(|r| r.conflict) {
+        if fee_rate <= original.fee_rate() {
+            return Err(ReplacementError::LowFeeRate { txid: original.txid, fee_rate, replaced: original.fee_rate() });
+        }
+    }
+    let needed = replaced
+        .iter()
+        .fold(INCREMENTAL_RELAY_FEE.fee(vsize), |sum, r| sum.saturating_add(r.fee));
+    if fee < needed {
+        return Err(ReplacementError::LowFee { fee, needed });
+    }
+    return Ok(());
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::transaction::{Transaction, TxInput, TxOutput, SEQUENCE_FINAL, SEQUENCE_MAX_REPLACEABLE};
+
+    fn replaced(i: u8, fee: Amount, conflict: bool, replaceable: bool) -> Replaced {
+        return Replaced { txid: H256::from([i; 32]), fee, vsize: 200, conflict, replaceable };
+    }
+
+    #[test]
+    fn signaling() {
+        let mut t = Transaction::new(
+            vec![TxInput::new(H256::from([1u8; 32]), 0), TxInput::new(H256::from([2u8; 32]), 0)],
+            vec![TxOutput::new(1, H256::default())],
+        );
+        t.set_sequence(0, SEQUENCE_FINAL);
+        t.set_sequence(1, SEQUENCE_FINAL - 1);
+        assert!(!t.signals_replacement());
+        t.set_sequence(1, SEQUENCE_MAX_REPLACEABLE);
+        assert!(t.signals_replacement());
+    }
+
+    #[test]
+    fn rules() {
+        let conflict = replaced(1, 1_000, true, true);
+        let child = replaced(2, 500, false, false);
+        // pays for both evicted transactions and 200 vbytes of relay
+        assert_eq!(check_replacement(1_700, 200, &[conflict.clone(), child.clone()]), Ok(()));
+        assert_eq!(
+            check_replacement(1_699, 200, &[conflict.clone(), child.clone()]),
+            Err(ReplacementError::LowFee { fee: 1_699, needed: 1_700 })
+        );
+        // a large replacement paying more in total, at a lower rate
+        assert!(matches!(
+            check_replacement(5_000, 2_000, &[conflict.clone()]),
+            Err(ReplacementError::LowFeeRate { .. })
+        ));
+        assert_eq!(
+            check_replacement(10_000, 200, &[replaced(3, 1_000, true, false)]),
+            Err(ReplacementError::NotReplaceable(H256::from([3u8; 32])))
+        );
+        let many: Vec<Replaced> = (0..=MAX_REPLACED as u8).map(|i| replaced(i, 1, i == 0, true)).collect();
+        assert_eq!(check_replacement(100_000, 200, &many), Err(ReplacementError::TooManyReplaced(MAX_REPLACED + 1)));
+    }
+}
@@ -0,0 +1,253 @@
+use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// How often idle workers check whether the pool has shrunk
+const IDLE_CHECK_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+struct PoolState {
+    /// Number of workers the pool should have
+    target: AtomicUsize,
+    /// Number of workers currently running
+    running: AtomicUsize,
+    /// Identifier of the next worker to spawn
+    next_id: AtomicUsize,
+}
+
+/// A pool of worker threads executing jobs, whose size can be changed while it runs
+#[derive(Clone)]
+pub struct ThreadPool {
+    name: String,
+    job_sender: Sender<Job>,
+    job_receiver: Receiver<Job>,
+    state: Arc<PoolState>,
+    resize_lock: Arc<Mutex<()>>,
+}
+
+impl ThreadPool {
+    pub fn new(name: &str, size: usize) -> Self {
+        let (job_sender, job_receiver) = unbounded();
+        let pool = ThreadPool {
+            name: name.to_string(),
+            job_sender,
+            job_receiver,
+            state: Arc::new(PoolState {
+                target: AtomicUsize::new(0),
+                running: AtomicUsize::new(0),
+                next_id: AtomicUsize::new(0),
+            }),
+            resize_lock: Arc::new(Mutex::new(())),
+        };
+        pool.resize(size);
+        return pool;
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The configured number of workers
+    pub fn size(&self) -> usize {
+        self.state.target.load(Ordering::SeqCst)
+    }
+
+    /// Queue a job to be run by one of the workers
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.job_sender.send(Box::new(job)).unwrap();
+    }
+
+    /// Change the number of workers. Extra workers exit once they finish their current job.
+    pub fn resize(&self, size: usize) {
+        let _guard = self.resize_lock.lock().unwrap();
+        self.state.target.store(size, Ordering::SeqCst);
+        while self.state.running.load(Ordering::SeqCst) < size {
+            self.spawn_worker();
+        }
+        info!("Thread pool {} resized to {} workers", self.name, size);
+    }
+
+    fn spawn_worker(&self) {
+        let id = self.state.next_id.fetch_add(1, Ordering::SeqCst);
+        let receiver = self.job_receiver.clone();
+        let state = Arc::clone(&self.state);
+        let name = self.name.clone();
+        state.running.fetch_add(1, Ordering::SeqCst);
+        thread::Builder::new()
+            .name(format!("{}-{}", name, id))
+            .spawn(move || {
+                loop {
+                    // leave if the pool has more workers than it should
+                    let running = state.running.load(Ordering::SeqCst);
+                    if running > state.target.load(Ordering::SeqCst)
+                        && state
+                            .running
+                            .compare_exchange(running, running - 1, Ordering::SeqCst, Ordering::SeqCst)
+                            .is_ok()
+                    {
+                        debug!("Worker {}-{} exiting", name, id);
+                        return;
+                    }
+                    match receiver.recv_timeout(IDLE_CHECK_INTERVAL) {
+                        Ok(job) => {
+                            // a panicking job must not take its worker down with it
+                            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                                warn!("Job of worker {}-{} panicked", name, id);
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => {
+                            state.running.fetch_sub(1, Ordering::SeqCst);
+                            return;
+                        }
+                    }
+                }
+            })
+            .unwrap();
+    }
+
+    /// The number of workers currently alive, which lags behind `size` while shrinking
+    pub fn running(&self) -> usize {
+        self.state.running.load(Ordering::SeqCst)
+    }
+}
+
+/// Names of the thread pools of a node
+pub const VALIDATION_POOL: &str = "validation";
+pub const MINING_POOL: &str = "mining";
+pub const NETWORK_POOL: &str = "network";
+pub const STORAGE_POOL: &str = "storage";
+
+const POOLS: [&str; 4] = [VALIDATION_POOL, MINING_POOL, NETWORK_POOL, STORAGE_POOL];
+
+/// Reasons for a pool size to be refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PoolError {
+    /// No pool of the node has this name
+    UnknownPool(String),
+    /// A pool without workers would never run its jobs
+    ZeroSize(String),
+}
+
+impl std::fmt::Display for PoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PoolError::UnknownPool(name) => write!(f, "unknown pool {}", name),
+            PoolError::ZeroSize(name) => write!(f, "size of pool {} must be positive", name),
+        }
+    }
+}
+
+/// Check that a pool of the node is named `name`, and that `size` is positive
+fn check_size(name: &str, size: usize) -> Result<(), PoolError> {
+    if !POOLS.contains(&name) {
+        return Err(PoolError::UnknownPool(name.to_string()));
+    }
+    if size == 0 {
+        return Err(PoolError::ZeroSize(name.to_string()));
+    }
+    return Ok(());
+}
+
+/// Central owner of the thread pools of the node
+pub struct Runtime {
+    pools: BTreeMap<String, ThreadPool>,
+}
+
+impl Runtime {
+    /// Create the runtime with the given pool sizes. Pools of a node not mentioned get one worker.
+    pub fn new(sizes: &BTreeMap<String, usize>) -> Result<Self, PoolError> {
+        for (name, size) in sizes {
+            check_size(name, *size)?;
+        }
+        let mut pools: BTreeMap<String, ThreadPool> = BTreeMap::new();
+        for name in POOLS.iter() {
+            let size = sizes.get(*name).cloned().unwrap_or(1);
+            pools.insert(name.to_string(), ThreadPool::new(name, size));
+        }
+        return Ok(Runtime { pools });
+    }
+
+    pub fn pool(&self, name: &str) -> Option<&ThreadPool> {
+        return self.pools.get(name);
+    }
+
+    /// Resize the pool `name`
+    pub fn resize(&self, name: &str, size: usize) -> Result<(), PoolError> {
+        check_size(name, size)?;
+        self.pools[name].resize(size);
+        return Ok(());
+    }
+
+    /// The configured size of each pool
+    pub fn sizes(&self) -> BTreeMap<String, usize> {
+        return self.pools.iter().map(|(name, pool)| (name.clone(), pool.size())).collect();
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+
+    fn wait_until<F: Fn() -> bool>(condition: F) {
+        for _ in 0..100 {
+            if condition() {
+                return;
+            }
+            thread::sleep(time::Duration::from_millis(20));
+        }
+        panic!("condition not reached");
+    }
+
+    #[test]
+    fn execute_and_resize() {
+        let pool = ThreadPool::new("test", 2);
+        let counter = Arc::new(AtomicUsize::new(0));
+        for _ in 0..10 {
+            let counter = Arc::clone(&counter);
+            pool.execute(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        wait_until(|| counter.load(Ordering::SeqCst) == 10);
+        pool.resize(5);
+        assert_eq!(pool.running(), 5);
+        pool.resize(1);
+        assert_eq!(pool.size(), 1);
+        wait_until(|| pool.running() == 1);
+        let counter2 = Arc::clone(&counter);
+        pool.execute(move || {
+            counter2.fetch_add(1, Ordering::SeqCst);
+        });
+        wait_until(|| counter.load(Ordering::SeqCst) == 11);
+    }
+
+    #[test]
+    fn runtime_pools() {
+        let mut sizes = BTreeMap::new();
+        sizes.insert(NETWORK_POOL.to_string(), 3);
+        let runtime = Runtime::new(&sizes).unwrap();
+        assert_eq!(runtime.sizes()[NETWORK_POOL], 3);
+        assert_eq!(runtime.sizes()[MINING_POOL], 1);
+        assert_eq!(runtime.resize(STORAGE_POOL, 2), Ok(()));
+        assert_eq!(runtime.resize("unknown", 2), Err(PoolError::UnknownPool("unknown".to_string())));
+        assert_eq!(runtime.resize(STORAGE_POOL, 0), Err(PoolError::ZeroSize(STORAGE_POOL.to_string())));
+        assert_eq!(runtime.pool(STORAGE_POOL).unwrap().size(), 2);
+
+        // nor is a runtime created with such sizes
+        sizes.insert("unknown".to_string(), 1);
+        assert_eq!(Runtime::new(&sizes).err(), Some(PoolError::UnknownPool("unknown".to_string())));
+        sizes.remove("unknown");
+        sizes.insert(MINING_POOL.to_string(), 0);
+        assert_eq!(Runtime::new(&sizes).err(), Some(PoolError::ZeroSize(MINING_POOL.to_string())));
+    }
+}
@@ -0,0 +1,836 @@
+//! A small stack-based script language, a subset of Bitcoin script. An output locks coins with a
+//! script, and an input unlocks them with a script pushing the data that makes it succeed: both
+//! are run one after the other on the same stack, and the spend is valid if the top of the stack
+//! is then true.
+//!
+//! Outputs locked by a witness program are instead unlocked by the witness of the input, which is
+//! not part of the txid, so that signing cannot change the txid of the spending transaction.
+
+use serde::{Serialize, Deserialize};
+use ring::digest::{digest, SHA256};
+
+use crate::crypto::hash::H256;
+use crate::crypto::keys;
+use crate::crypto::ripemd160::hash160;
+use crate::crypto::schnorr;
+use crate::transaction::{SigHashType, Transaction};
+
+pub mod opcodes {
+    /// Push an empty item, which is false
+    pub const OP_0: u8 = 0x00;
+    /// Opcodes from 0x01 to 0x4b push that many following bytes
+    pub const OP_PUSHBYTES_MAX: u8 = 0x4b;
+    /// Push the number of bytes given by the next byte
+    pub const OP_PUSHDATA1: u8 = 0x4c;
+    /// Push the number of bytes given by the next 2 little-endian bytes
+    pub const OP_PUSHDATA2: u8 = 0x4d;
+    /// Opcodes from OP_1 to OP_16 push the number they name
+    pub const OP_1: u8 = 0x51;
+    pub const OP_16: u8 = 0x60;
+    pub const OP_VERIFY: u8 = 0x69;
+    pub const OP_RETURN: u8 = 0x6a;
+    pub const OP_DROP: u8 = 0x75;
+    pub const OP_DUP: u8 = 0x76;
+    pub const OP_SWAP: u8 = 0x7c;
+    pub const OP_EQUAL: u8 = 0x87;
+    pub const OP_EQUALVERIFY: u8 = 0x88;
+    pub const OP_SHA256: u8 = 0xa8;
+    pub const OP_HASH160: u8 = 0xa9;
+    pub const OP_HASH256: u8 = 0xaa;
+    pub const OP_CHECKSIG: u8 = 0xac;
+    pub const OP_CHECKSIGVERIFY: u8 = 0xad;
+    /// `<sig 1> .. <sig m> <m> <key 1> .. <key n> <n> OP_CHECKMULTISIG`: whether the m
+    /// signatures are made by m of the n keys, in the same order. Unlike in Bitcoin, no extra
+    /// item is popped.
+    pub const OP_CHECKMULTISIG: u8 = 0xae;
+    pub const OP_CHECKMULTISIGVERIFY: u8 = 0xaf;
+}
+
+use opcodes::*;
+
+/// Maximum size of a script, in bytes
+pub const MAX_SCRIPT_SIZE: usize = 10_000;
+
+/// Maximum size of a stack item, in bytes
+pub const MAX_ITEM_SIZE: usize = 520;
+
+/// Maximum number of items on the stack
+pub const MAX_STACK_SIZE: usize = 1000;
+
+/// Maximum number of keys of OP_CHECKMULTISIG
+pub const MAX_MULTISIG_KEYS: usize = 16;
+
+/// Reasons for a script to fail
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ScriptError {
+    /// A push runs past the end of the script
+    Truncated,
+    UnknownOpcode(u8),
+    /// The script, a pushed item or the stack exceeds its maximum size
+    TooLarge,
+    /// An opcode needs more items than the stack holds
+    StackUnderflow,
+    /// An item used as a number is not one, or is out of range
+    BadNumber,
+    /// An unlocking script does something else than pushing data
+    PushOnly,
+    /// OP_RETURN was run
+    Return,
+    /// A VERIFY opcode found a false item
+    VerifyFailed,
+    /// The scripts ran to completion, without leaving true on the stack
+    False,
+    /// An ECDSA signature is not strict DER, see `keys::is_strict_der`
+    SignatureEncoding,
+    /// The witness does not match the witness program of the output
+    WitnessMismatch,
+    /// An input spending a witness program has an unlocking script
+    WitnessMalleated,
+    /// An input spending an output that is not a witness program has a witness
+    UnexpectedWitness,
+}
+
+impl std::fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ScriptError::Truncated => write!(f, "push past the end of the script"),
+            ScriptError::UnknownOpcode(op) => write!(f, "unknown opcode {:#04x}", op),
+            ScriptError::TooLarge => write!(f, "size limit exceeded"),
+            ScriptError::StackUnderflow => write!(f, "stack underflow"),
+            ScriptError::BadNumber => write!(f, "bad number"),
+            ScriptError::PushOnly => write!(f, "unlocking script is not push only"),
+            ScriptError::Return => write!(f, "OP_RETURN executed"),
+            ScriptError::VerifyFailed => write!(f, "verify failed"),
+            ScriptError::False => write!(f, "script evaluated to false"),
+            ScriptError::SignatureEncoding => write!(f, "non-canonical signature encoding"),
+            ScriptError::WitnessMismatch => write!(f, "witness does not match witness program"),
+            ScriptError::WitnessMalleated => write!(f, "unlocking script of a witness program spend"),
+            ScriptError::UnexpectedWitness => write!(f, "witness of a spend that is not a witness program"),
+        }
+    }
+}
+
+/// An element of a script: data to push, or another opcode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    Push(Vec<u8>),
+    Op(u8),
+}
+
+/// The bytes of a script
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Script(Vec<u8>);
+
+impl Script {
+    pub fn new() -> Self {
+        return Script(Vec::new());
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        return Script(bytes);
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        return &self.0;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.0.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.0.is_empty();
+    }
+
+    /// Append an opcode
+    pub fn push_opcode(mut self, opcode: u8) -> Self {
+        self.0.push(opcode);
+        return self;
+    }
+
+    /// Append the shortest push of `data`
+    pub fn push_data(mut self, data: &[u8]) -> Self {
+        if data.is_empty() {
+            self.0.push(OP_0);
+        } else if data.len() <= OP_PUSHBYTES_MAX as usize {
+            self.0.push(data.len() as u8);
+        } else if data.len() <= u8::MAX as usize {
+            self.0.push(OP_PUSHDATA1);
+            self.0.push(data.len() as u8);
+        } else {
+            self.0.push(OP_PUSHDATA2);
+            self.0.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        }
+        self.0.extend_from_slice(data);
+        return self;
+    }
+
+    /// Pay to public key hash: `OP_DUP OP_HASH160 <h160> OP_EQUALVERIFY OP_CHECKSIG`, spent by
+    /// pushing a signature and a public key whose `hash160` is `h160`, see `p2pkh_unlock`
+    pub fn p2pkh(h160: &[u8; 20]) -> Self {
+        return Script::new()
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_data(h160)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_CHECKSIG);
+    }
+
+    /// The unlocking script of a `p2pkh` output
+    pub fn p2pkh_unlock(signature: &[u8], public_key: &[u8]) -> Self {
+        return Script::new().push_data(signature).push_data(public_key);
+    }
+
+    /// `<m> <key 1> .. <key n> <n> OP_CHECKMULTISIG`, unlocked by signatures of `m` of the
+    /// `public_keys`, in the same order, see `multisig::PartialMultisig`. `m` and the number of
+    /// keys must be between 1 and 16.
+    pub fn multisig(m: usize, public_keys: &[Vec<u8>]) -> Self {
+        assert!(m >= 1 && m <= public_keys.len() && public_keys.len() <= MAX_MULTISIG_KEYS);
+        let mut script = Script::new().push_opcode(OP_1 + m as u8 - 1);
+        for public_key in public_keys {
+            script = script.push_data(public_key);
+        }
+        return script.push_opcode(OP_1 + public_keys.len() as u8 - 1).push_opcode(OP_CHECKMULTISIG);
+    }
+
+    /// The number of signatures required and the keys of a `multisig` script, if it is one
+    pub fn multisig_keys(&self) -> Option<(usize, Vec<Vec<u8>>)> {
+        let instructions = self.instructions().ok()?;
+        if instructions.len() < 4 || instructions[instructions.len() - 1] != Instruction::Op(OP_CHECKMULTISIG) {
+            return None;
+        }
+        let items: Vec<&Vec<u8>> = instructions[..instructions.len() - 1]
+            .iter()
+            .map(|i| match i {
+                Instruction::Push(data) => Some(data),
+                Instruction::Op(_) => None,
+            })
+            .collect::<Option<Vec<&Vec<u8>>>>()?;
+        let m = small_number(items[0]).ok()?;
+        let n = small_number(items[items.len() - 1]).ok()?;
+        if n != items.len() - 2 || m == 0 || m > n {
+            return None;
+        }
+        return Some((m, items[1..items.len() - 1].iter().map(|k| k.to_vec()).collect()));
+    }
+
+    /// The hash of the public key a `p2pkh` script pays to, if it is one
+    pub fn p2pkh_hash(&self) -> Option<[u8; 20]> {
+        let b = &self.0;
+        if b.len() == 25 && b[0] == OP_DUP && b[1] == OP_HASH160 && b[2] == 20 && b[23] == OP_EQUALVERIFY && b[24] == OP_CHECKSIG {
+            let mut h160 = [0u8; 20];
+            h160.copy_from_slice(&b[3..23]);
+            return Some(h160);
+        }
+        return None;
+    }
+
+    /// Pay to script hash: `OP_HASH160 <h160> OP_EQUAL`, spent by pushing the inputs of a redeem
+    /// script whose `hash160` is `h160`, then the redeem script itself, see `p2sh_unlock`. The
+    /// redeem script is then run on its inputs.
+    pub fn p2sh(h160: &[u8; 20]) -> Self {
+        return Script::new().push_opcode(OP_HASH160).push_data(h160).push_opcode(OP_EQUAL);
+    }
+
+    /// The `p2sh` script locking an output to `redeem_script`
+    pub fn p2sh_of(redeem_script: &Script) -> Self {
+        return Script::p2sh(&hash160(redeem_script.as_bytes()));
+    }
+
+    /// The unlocking script of a `p2sh` output: the inputs of the redeem script, then the script
+    pub fn p2sh_unlock(inputs: &[Vec<u8>], redeem_script: &Script) -> Self {
+        let mut script = Script::new();
+        for input in inputs {
+            script = script.push_data(input);
+        }
+        return script.push_data(redeem_script.as_bytes());
+    }
+
+    /// Whether the script is a `p2sh` script
+    pub fn is_p2sh(&self) -> bool {
+        let b = &self.0;
+        return b.len() == 23 && b[0] == OP_HASH160 && b[1] == 20 && b[22] == OP_EQUAL;
+    }
+
+    /// Pay to witness public key hash: `OP_0 <h160>`, spent with the witness `[signature,
+    /// public key]`, which must unlock `p2pkh(h160)`
+    pub fn p2wpkh(h160: &[u8; 20]) -> Self {
+        return Script::new().push_opcode(OP_0).push_data(h160);
+    }
+
+    /// Pay to witness script hash: `OP_0 <sha256>`, spent with a witness made of the inputs of
+    /// a witness script whose SHA256 is `sha256`, followed by the script
+    pub fn p2wsh(sha256: &H256) -> Self {
+        return Script::new().push_opcode(OP_0).push_data(sha256.as_ref());
+    }
+
+    /// The `p2wsh` script locking an output to `witness_script`
+    pub fn p2wsh_of(witness_script: &Script) -> Self {
+        return Script::p2wsh(&witness_script.address());
+    }
+
+    /// Data carrier: `OP_RETURN <data>`, locking an output no input can unlock, so that it is
+    /// kept out of the UTXO set
+    pub fn null_data(data: &[u8]) -> Self {
+        return Script::new().push_opcode(OP_RETURN).push_data(data);
+    }
+
+    /// Whether no input can ever unlock the script, as it starts with OP_RETURN
+    pub fn is_unspendable(&self) -> bool {
+        return self.0.first() == Some(&OP_RETURN);
+    }
+
+    /// The data carried by a script of OP_RETURN followed by pushes only, see `null_data`
+    pub fn null_data_payload(&self) -> Option<Vec<u8>> {
+        if !self.is_unspendable() {
+            return None;
+        }
+        let mut payload: Vec<u8> = Vec::new();
+        for instruction in Script::from_bytes(self.0[1..].to_vec()).instructions().ok()? {
+            match instruction {
+                Instruction::Push(data) => payload.extend(data),
+                Instruction::Op(_) => return None,
+            }
+        }
+        return Some(payload);
+    }
+
+    /// The program of a witness program script: the 20 or 32 bytes following OP_0
+    pub fn witness_program(&self) -> Option<&[u8]> {
+        let b = &self.0;
+        if b.len() >= 2 && b[0] == OP_0 && b[1] as usize == b.len() - 2 && (b.len() == 22 || b.len() == 34) {
+            return Some(&b[2..]);
+        }
+        return None;
+    }
+
+    /// The hash of the public key a `p2pkh` or `p2wpkh` script pays to, if it is one
+    pub fn key_hash(&self) -> Option<[u8; 20]> {
+        if let Some(h160) = self.p2pkh_hash() {
+            return Some(h160);
+        }
+        let program = self.witness_program().filter(|program| program.len() == 20)?;
+        let mut h160 = [0u8; 20];
+        h160.copy_from_slice(program);
+        return Some(h160);
+    }
+
+    /// The SHA256 of the script, which outputs locked by the script use as recipient
+    pub fn address(&self) -> H256 {
+        return digest(&SHA256, &self.0).into();
+    }
+
+    /// Decode the script into instructions
+    pub fn instructions(&self) -> Result<Vec<Instruction>, ScriptError> {
+        let b = &self.0;
+        let mut instructions: Vec<Instruction> = Vec::new();
+        let mut i = 0;
+        while i < b.len() {
+            let opcode = b[i];
+            i += 1;
+            let length = match opcode {
+                OP_0 => 0,
+                1..=OP_PUSHBYTES_MAX => opcode as usize,
+                OP_PUSHDATA1 => {
+                    let length = *b.get(i).ok_or(ScriptError::Truncated)? as usize;
+                    i += 1;
+                    length
+                }
+                OP_PUSHDATA2 => {
+                    if i + 2 > b.len() {
+                        return Err(ScriptError::Truncated);
+                    }
+                    let length = u16::from_le_bytes([b[i], b[i + 1]]) as usize;
+                    i += 2;
+                    length
+                }
+                OP_1..=OP_16 => {
+                    instructions.push(Instruction::Push(vec![opcode - OP_1 + 1]));
+                    continue;
+                }
+                _ => {
+                    instructions.push(Instruction::Op(opcode));
+                    continue;
+                }
+            };
+            if i + length > b.len() {
+                return Err(ScriptError::Truncated);
+            }
+            instructions.push(Instruction::Push(b[i..i + length].to_vec()));
+            i += length;
+        }
+        return Ok(instructions);
+    }
+
+    /// Whether the script only pushes data
+    pub fn is_push_only(&self) -> bool {
+        return match self.instructions() {
+            Ok(instructions) => instructions.iter().all(|i| matches!(i, Instruction::Push(_))),
+            Err(_) => false,
+        };
+    }
+
+    /// Number of signature checks the script may run, counted without running it. A multisig
+    /// check counts for its number of keys if `accurate` and they are pushed just before it,
+    /// else for `MAX_MULTISIG_KEYS`. A script with an invalid push counts for none, as it cannot
+    /// run.
+    pub fn sigop_count(&self, accurate: bool) -> usize {
+        let instructions = self.instructions().unwrap_or_default();
+        let mut count = 0;
+        for (i, instruction) in instructions.iter().enumerate() {
+            count += match instruction {
+                Instruction::Op(OP_CHECKSIG) | Instruction::Op(OP_CHECKSIGVERIFY) => 1,
+                Instruction::Op(OP_CHECKMULTISIG) | Instruction::Op(OP_CHECKMULTISIGVERIFY) => {
+                    match i.checked_sub(1).map(|previous| &instructions[previous]) {
+                        Some(Instruction::Push(n)) if accurate => small_number(n).unwrap_or(MAX_MULTISIG_KEYS),
+                        _ => MAX_MULTISIG_KEYS,
+                    }
+                }
+                _ => 0,
+            };
+        }
+        return count;
+    }
+}
+
+/// The truth value of a stack item: false if all its bytes are zero, or it is negative zero
+fn is_true(item: &[u8]) -> bool {
+    for (i, byte) in item.iter().enumerate() {
+        if *byte != 0 {
+            return !(i == item.len() - 1 && *byte == 0x80);
+        }
+    }
+    return false;
+}
+
+fn from_bool(value: bool) -> Vec<u8> {
+    return if value { vec![1] } else { Vec::new() };
+}
+
+/// Check a signature of `signature_hash` by `public_key`: a 32-byte key is a BIP340 Schnorr key,
+/// any other an ECDSA P-256 key
+pub fn check_signature(signature: &[u8], public_key: &[u8], signature_hash: &H256) -> bool {
+    if public_key.len() == 32 {
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(public_key);
+        return schnorr::PublicKey::from_bytes(bytes).verify(signature_hash.as_ref(), signature);
+    }
+    return keys::PublicKey::from_bytes(public_key).verify(signature_hash.as_ref(), signature);
+}
+
+/// Provides the hash a signature in a script commits to
+pub trait SignatureChecker {
+    /// The hash signed by signatures of the given type, None if the type does not apply
+    fn signature_hash(&self, sighash_type: SigHashType) -> Option<H256>;
+}
+
+/// A fixed hash, whatever the sighash type
+impl SignatureChecker for H256 {
+    fn signature_hash(&self, _sighash_type: SigHashType) -> Option<H256> {
+        return Some(*self);
+    }
+}
+
+/// Checks the signatures of an input of a transaction, see `Transaction::signature_hash_for`
+pub struct TransactionChecker<'a> {
+    transaction: &'a Transaction,
+    index: usize,
+}
+
+impl<'a> TransactionChecker<'a> {
+    pub fn new(transaction: &'a Transaction, index: usize) -> Self {
+        return TransactionChecker { transaction, index };
+    }
+}
+
+impl<'a> SignatureChecker for TransactionChecker<'a> {
+    fn signature_hash(&self, sighash_type: SigHashType) -> Option<H256> {
+        return self.transaction.signature_hash_for(self.index, sighash_type);
+    }
+}
+
+/// Check a signature in a script, which is followed by its sighash type
+pub fn check_script_signature(signature: &[u8], public_key: &[u8], checker: &dyn SignatureChecker) -> bool {
+    let (sighash_type, signature) = match signature.split_last() {
+        Some((byte, signature)) => (SigHashType::from_byte(*byte), signature),
+        None => return false,
+    };
+    return match sighash_type.and_then(|t| checker.signature_hash(t)) {
+        Some(signature_hash) => check_signature(signature, public_key, &signature_hash),
+        None => false,
+    };
+}
+
+/// Fail if `signature`, followed by its sighash type, is checked against the ECDSA `public_key`
+/// but is not strict DER. An empty signature is allowed to fail a check on purpose, and a
+/// 64-byte one is a Schnorr signature, which multisig scripts may check against other keys. Any
+/// other invalid one must stop the script, else its encoding could be changed without changing
+/// the outcome.
+fn check_signature_encoding(signature: &[u8], public_key: &[u8]) -> Result<(), ScriptError> {
+    if signature.is_empty() || signature.len() == 65 || public_key.len() == 32 {
+        return Ok(());
+    }
+    if !keys::is_strict_der(&signature[..signature.len() - 1]) {
+        return Err(ScriptError::SignatureEncoding);
+    }
+    return Ok(());
+}
+
+/// Read an item pushed by OP_0 to OP_16 as a number
+fn small_number(item: &[u8]) -> Result<usize, ScriptError> {
+    return match item {
+        [] => Ok(0),
+        [n] if *n <= 16 => Ok(*n as usize),
+        _ => Err(ScriptError::BadNumber),
+    };
+}
+
+/// Run OP_CHECKMULTISIG on `stack`, returning whether the signatures are valid
+fn check_multisig(stack: &mut Vec<Vec<u8>>, checker: &dyn SignatureChecker) -> Result<bool, ScriptError> {
+    let n = small_number(&pop(stack)?)?;
+    if n > MAX_MULTISIG_KEYS {
+        return Err(ScriptError::BadNumber);
+    }
+    let mut public_keys: Vec<Vec<u8>> = Vec::new();
+    for _ in 0..n {
+        public_keys.push(pop(stack)?);
+    }
+    public_keys.reverse();
+    let m = small_number(&pop(stack)?)?;
+    if m > n {
+        return Err(ScriptError::BadNumber);
+    }
+    let mut signatures: Vec<Vec<u8>> = Vec::new();
+    for _ in 0..m {
+        signatures.push(pop(stack)?);
+    }
+    signatures.reverse();
+    // each signature must match a key after the key of the previous signature
+    let mut keys = public_keys.iter();
+    for signature in &signatures {
+        let mut matched = false;
+        for public_key in keys.by_ref() {
+            check_signature_encoding(signature, public_key)?;
+            if check_script_signature(signature, public_key, checker) {
+                matched = true;
+                break;
+            }
+        }
+        if !matched {
+            return Ok(false);
+        }
+    }
+    return Ok(true);
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, ScriptError> {
+    return stack.pop().ok_or(ScriptError::StackUnderflow);
+}
+
+/// Run `script` on `stack`. Signatures are checked against the hashes given by `checker`.
+pub fn execute(script: &Script, stack: &mut Vec<Vec<u8>>, checker: &dyn SignatureChecker) -> Result<(), ScriptError> {
+    if script.len() > MAX_SCRIPT_SIZE {
+        return Err(ScriptError::TooLarge);
+    }
+    for instruction in script.instructions()? {
+        match instruction {
+            Instruction::Push(data) => {
+                if data.len() > MAX_ITEM_SIZE {
+                    return Err(ScriptError::TooLarge);
+                }
+                stack.push(data);
+            }
+            Instruction::Op(OP_VERIFY) => {
+                if !is_true(&pop(stack)?) {
+                    return Err(ScriptError::VerifyFailed);
+                }
+            }
+            Instruction::Op(OP_RETURN) => return Err(ScriptError::Return),
+            Instruction::Op(OP_DROP) => {
+                pop(stack)?;
+            }
+            Instruction::Op(OP_DUP) => {
+                let top = stack.last().ok_or(ScriptError::StackUnderflow)?.clone();
+                stack.push(top);
+            }
+            Instruction::Op(OP_SWAP) => {
+                let a = pop(stack)?;
+                let b = pop(stack)?;
+                stack.push(a);
+                stack.push(b);
+            }
+            Instruction::Op(op @ OP_EQUAL) | Instruction::Op(op @ OP_EQUALVERIFY) => {
+                let equal = pop(stack)? == pop(stack)?;
+                if op == OP_EQUALVERIFY {
+                    if !equal {
+                        return Err(ScriptError::VerifyFailed);
+                    }
+                } else {
+                    stack.push(from_bool(equal));
+                }
+            }
+            Instruction::Op(OP_SHA256) => {
+                let item = pop(stack)?;
+                stack.push(digest(&SHA256, &item).as_ref().to_vec());
+            }
+            Instruction::Op(OP_HASH160) => {
+                let item = pop(stack)?;
+                stack.push(hash160(&item).to_vec());
+            }
+            Instruction::Op(OP_HASH256) => {
+                let item = pop(stack)?;
+                stack.push(digest(&SHA256, digest(&SHA256, &item).as_ref()).as_ref().to_vec());
+            }
+            Instruction::Op(op @ OP_CHECKSIG) | Instruction::Op(op @ OP_CHECKSIGVERIFY) => {
+                let public_key = pop(stack)?;
+                let signature = pop(stack)?;
+                check_signature_encoding(&signature, &public_key)?;
+                let valid = check_script_signature(&signature, &public_key, checker);
+                if op == OP_CHECKSIGVERIFY {
+                    if !valid {
+                        return Err(ScriptError::VerifyFailed);
+                    }
+                } else {
+                    stack.push(from_bool(valid));
+                }
+            }
+            Instruction::Op(op @ OP_CHECKMULTISIG) | Instruction::Op(op @ OP_CHECKMULTISIGVERIFY) => {
+                let valid = check_multisig(stack, checker)?;
+                if op == OP_CHECKMULTISIGVERIFY {
+                    if !valid {
+                        return Err(ScriptError::VerifyFailed);
+                    }
+                } else {
+                    stack.push(from_bool(valid));
+                }
+            }
+            Instruction::Op(op) => return Err(ScriptError::UnknownOpcode(op)),
+        }
+        if stack.len() > MAX_STACK_SIZE {
+            return Err(ScriptError::TooLarge);
+        }
+    }
+    return Ok(());
+}
+
+fn check_true(stack: &[Vec<u8>]) -> Result<(), ScriptError> {
+    return match stack.last() {
+        Some(top) if is_true(top) => Ok(()),
+        _ => Err(ScriptError::False),
+    };
+}
+
+/// Check that the witness of an input unlocks an output locked by the witness `program`
+fn verify_witness(program: &[u8], witness: &[Vec<u8>], checker: &dyn SignatureChecker) -> Result<(), ScriptError> {
+    if witness.iter().any(|item| item.len() > MAX_ITEM_SIZE) {
+        return Err(ScriptError::TooLarge);
+    }
+    let mut stack = witness.to_vec();
+    let script = if program.len() == 20 {
+        if witness.len() != 2 {
+            return Err(ScriptError::WitnessMismatch);
+        }
+        let mut h160 = [0u8; 20];
+        h160.copy_from_slice(program);
+        Script::p2pkh(&h160)
+    } else {
+        let script = Script::from_bytes(stack.pop().ok_or(ScriptError::WitnessMismatch)?);
+        if script.address().as_ref() != program {
+            return Err(ScriptError::WitnessMismatch);
+        }
+        script
+    };
+    execute(&script, &mut stack, checker)?;
+    return check_true(&stack);
+}
+
+/// Check that `script_sig` and `witness` unlock an output locked by `script_pubkey`. The
+/// unlocking script may only push data, so that nobody but its signers can change what it proves.
+/// For a `p2sh` output, the redeem script pushed last must also succeed on the items pushed before
+/// it. A witness program output must be unlocked by the witness alone, and other outputs by the
+/// unlocking script alone.
+pub fn verify(script_sig: &Script, witness: &[Vec<u8>], script_pubkey: &Script, checker: &dyn SignatureChecker) -> Result<(), ScriptError> {
+    if let Some(program) = script_pubkey.witness_program() {
+        if !script_sig.is_empty() {
+            return Err(ScriptError::WitnessMalleated);
+        }
+        return verify_witness(program, witness, checker);
+    }
+    if !witness.is_empty() {
+        return Err(ScriptError::UnexpectedWitness);
+    }
+    if script_sig.instructions()?.iter().any(|i| matches!(i, Instruction::Op(_))) {
+        return Err(ScriptError::PushOnly);
+    }
+    let mut stack: Vec<Vec<u8>> = Vec::new();
+    execute(script_sig, &mut stack, checker)?;
+    let mut redeem_stack = stack.clone();
+    execute(script_pubkey, &mut stack, checker)?;
+    check_true(&stack)?;
+    if script_pubkey.is_p2sh() {
+        let redeem_script = Script::from_bytes(pop(&mut redeem_stack)?);
+        execute(&redeem_script, &mut redeem_stack, checker)?;
+        check_true(&redeem_stack)?;
+    }
+    return Ok(());
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::crypto::keys::KeyPair;
+
+    /// Append the sighash type to a signature, as scripts expect
+    pub fn with_type(mut signature: Vec<u8>) -> Vec<u8> {
+        signature.push(SigHashType::ALL.to_byte());
+        return signature;
+    }
+
+    #[test]
+    fn encoding() {
+        let long = vec![7u8; 300];
+        let script = Script::new().push_data(&[]).push_data(&[1, 2]).push_data(&long).push_opcode(OP_1).push_opcode(OP_DUP);
+        assert_eq!(
+            script.instructions(),
+            Ok(vec![
+                Instruction::Push(Vec::new()),
+                Instruction::Push(vec![1, 2]),
+                Instruction::Push(long),
+                Instruction::Push(vec![1]),
+                Instruction::Op(OP_DUP),
+            ])
+        );
+        assert!(!script.is_push_only());
+        assert_eq!(Script::from_bytes(vec![5, 1, 2]).instructions(), Err(ScriptError::Truncated));
+        assert_eq!(Script::p2pkh(&[3u8; 20]).p2pkh_hash(), Some([3u8; 20]));
+        assert_eq!(Script::new().push_opcode(OP_1).p2pkh_hash(), None);
+    }
+
+    #[test]
+    fn p2pkh() {
+        let key = KeyPair::random();
+        let public_key = key.public_key();
+        let script_pubkey = Script::p2pkh(&hash160(public_key.as_bytes()));
+        let signature_hash = H256::from([9u8; 32]);
+        let signature = with_type(key.sign(signature_hash.as_ref()).unwrap());
+        let script_sig = Script::p2pkh_unlock(&signature, public_key.as_bytes());
+        assert_eq!(verify(&script_sig, &[], &script_pubkey, &signature_hash), Ok(()));
+        // a signature of something else
+        assert_eq!(verify(&script_sig, &[], &script_pubkey, &H256::from([8u8; 32])), Err(ScriptError::False));
+        // the key of someone else
+        let other = KeyPair::random();
+        let signature = with_type(other.sign(signature_hash.as_ref()).unwrap());
+        let script_sig = Script::p2pkh_unlock(&signature, other.public_key().as_bytes());
+        assert_eq!(verify(&script_sig, &[], &script_pubkey, &signature_hash), Err(ScriptError::VerifyFailed));
+        assert_eq!(verify(&Script::new(), &[], &script_pubkey, &signature_hash), Err(ScriptError::StackUnderflow));
+    }
+
+    #[test]
+    fn signature_encoding() {
+        let key = KeyPair::random();
+        let public_key = key.public_key();
+        let signature_hash = H256::from([9u8; 32]);
+        let der = key.sign(signature_hash.as_ref()).unwrap();
+        // padding R gives another encoding of the same signature
+        let mut padded = vec![0x30, der[1] + 1, 0x02, der[3] + 1, 0x00];
+        padded.extend_from_slice(&der[4..]);
+        let checksig = Script::new().push_data(public_key.as_bytes()).push_opcode(OP_CHECKSIG);
+        assert_eq!(verify(&Script::new().push_data(&with_type(der)), &[], &checksig, &signature_hash), Ok(()));
+        let script_sig = Script::new().push_data(&with_type(padded.clone()));
+        assert_eq!(verify(&script_sig, &[], &checksig, &signature_hash), Err(ScriptError::SignatureEncoding));
+        let multisig = Script::multisig(1, &[public_key.as_bytes().to_vec()]);
+        assert_eq!(verify(&script_sig, &[], &multisig, &signature_hash), Err(ScriptError::SignatureEncoding));
+        // an empty signature may fail on purpose
+        let not_checksig = checksig.push_opcode(OP_0).push_opcode(OP_EQUAL);
+        assert_eq!(verify(&Script::new().push_data(&[]), &[], &not_checksig, &signature_hash), Ok(()));
+        let script_sig = Script::new().push_data(&with_type(padded));
+        assert_eq!(verify(&script_sig, &[], &not_checksig, &signature_hash), Err(ScriptError::SignatureEncoding));
+    }
+
+    #[test]
+    fn schnorr_checksig() {
+        let key = schnorr::KeyPair::random();
+        let public_key = key.public_key();
+        let script_pubkey = Script::new().push_data(public_key.as_bytes()).push_opcode(OP_CHECKSIG);
+        let signature_hash = H256::from([9u8; 32]);
+        let script_sig = Script::new().push_data(&with_type(key.sign(signature_hash.as_ref()).unwrap()));
+        assert_eq!(verify(&script_sig, &[], &script_pubkey, &signature_hash), Ok(()));
+    }
+
+    #[test]
+    fn p2sh() {
+        let hash = H256::from([9u8; 32]);
+        let key = schnorr::KeyPair::random();
+        let redeem_script = Script::new().push_data(key.public_key().as_bytes()).push_opcode(OP_CHECKSIG);
+        let script_pubkey = Script::p2sh_of(&redeem_script);
+        assert!(script_pubkey.is_p2sh());
+        assert!(!redeem_script.is_p2sh());
+        let signature = with_type(key.sign(hash.as_ref()).unwrap());
+        let script_sig = Script::p2sh_unlock(&[signature.clone()], &redeem_script);
+        assert_eq!(verify(&script_sig, &[], &script_pubkey, &hash), Ok(()));
+        // the redeem script must match the hash, and succeed
+        let other = Script::new().push_opcode(OP_1);
+        assert_eq!(verify(&Script::p2sh_unlock(&[], &other), &[], &script_pubkey, &hash), Err(ScriptError::False));
+        let bad_signature = key.sign(H256::default().as_ref()).unwrap();
+        let script_sig = Script::p2sh_unlock(&[bad_signature], &redeem_script);
+        assert_eq!(verify(&script_sig, &[], &script_pubkey, &hash), Err(ScriptError::False));
+        assert_eq!(verify(&Script::new(), &[], &script_pubkey, &hash), Err(ScriptError::StackUnderflow));
+    }
+
+    #[test]
+    fn witness_programs() {
+        let hash = H256::from([9u8; 32]);
+        let key = KeyPair::random();
+        let public_key = key.public_key().as_bytes().to_vec();
+        let signature = with_type(key.sign(hash.as_ref()).unwrap());
+        let script_pubkey = Script::p2wpkh(&hash160(&public_key));
+        assert_eq!(script_pubkey.witness_program(), Some(&hash160(&public_key)[..]));
+        let witness = vec![signature.clone(), public_key.clone()];
+        assert_eq!(verify(&Script::new(), &witness, &script_pubkey, &hash), Ok(()));
+        assert_eq!(verify(&Script::new(), &witness[1..], &script_pubkey, &hash), Err(ScriptError::WitnessMismatch));
+        let script_sig = Script::p2pkh_unlock(&signature, &public_key);
+        assert_eq!(verify(&script_sig, &witness, &script_pubkey, &hash), Err(ScriptError::WitnessMalleated));
+        // the unlocking script of a p2pkh output cannot be moved to the witness
+        let legacy = Script::p2pkh(&hash160(&public_key));
+        assert_eq!(legacy.witness_program(), None);
+        assert_eq!(verify(&Script::new(), &witness, &legacy, &hash), Err(ScriptError::UnexpectedWitness));
+
+        let witness_script = Script::new().push_data(&public_key).push_opcode(OP_CHECKSIG);
+        let script_pubkey = Script::p2wsh_of(&witness_script);
+        let witness = vec![signature.clone(), witness_script.as_bytes().to_vec()];
+        assert_eq!(verify(&Script::new(), &witness, &script_pubkey, &hash), Ok(()));
+        let other = Script::new().push_opcode(OP_1);
+        let witness = vec![other.as_bytes().to_vec()];
+        assert_eq!(verify(&Script::new(), &witness, &script_pubkey, &hash), Err(ScriptError::WitnessMismatch));
+    }
+
+    #[test]
+    fn rules() {
+        let hash = H256::default();
+        let pass = Script::new().push_opcode(OP_1);
+        assert_eq!(verify(&Script::new(), &[], &pass, &hash), Ok(()));
+        assert_eq!(verify(&pass.clone().push_opcode(OP_DROP), &[], &pass, &hash), Err(ScriptError::PushOnly));
+        assert_eq!(verify(&Script::new(), &[], &Script::new().push_data(&[0, 0x80]), &hash), Err(ScriptError::False));
+        assert_eq!(verify(&Script::new(), &[], &pass.clone().push_opcode(OP_RETURN), &hash), Err(ScriptError::Return));
+        assert_eq!(verify(&Script::new(), &[], &Script::from_bytes(vec![0xff]), &hash), Err(ScriptError::UnknownOpcode(0xff)));
+        let preimage = b"secret";
+        let hash_lock = Script::new()
+            .push_opcode(OP_SHA256)
+            .push_data(digest(&SHA256, preimage).as_ref())
+            .push_opcode(OP_EQUAL);
+        assert_eq!(verify(&Script::new().push_data(preimage), &[], &hash_lock, &hash), Ok(()));
+        assert_eq!(verify(&Script::new().push_data(b"guess"), &[], &hash_lock, &hash), Err(ScriptError::False));
+    }
+    #[test]
+    fn sigop_count() {
+        assert_eq!(Script::p2pkh(&[1u8; 20]).sigop_count(false), 1);
+        let keys = vec![vec![2u8; 33], vec![3u8; 33], vec![4u8; 33]];
+        let multisig = Script::multisig(2, &keys);
+        assert_eq!(multisig.sigop_count(true), 3);
+        assert_eq!(multisig.sigop_count(false), MAX_MULTISIG_KEYS);
+        let twice = multisig.clone().push_opcode(OP_CHECKSIGVERIFY).push_opcode(OP_CHECKMULTISIG);
+        assert_eq!(twice.sigop_count(true), 3 + 1 + MAX_MULTISIG_KEYS);
+        assert_eq!(Script::p2sh_of(&multisig).sigop_count(true), 0);
+        assert_eq!(Script::from_bytes(vec![OP_CHECKSIG, 0x05]).sigop_count(true), 0);
+    }
+}
@@ -0,0 +1,274 @@
+use serde::{Serialize, Deserialize};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::fs::File;
+use std::path::Path;
+
+use crate::block::Block;
+use crate::blockchain::{Blockchain, InsertError, TARGET_BLOCK_TIME};
+use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::merkle::MerkleTree;
+use crate::transaction::{Transaction, TxInput, TxOutput};
+
+/// Description of a simulated network, loaded from a JSON file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Scenario {
+    pub nodes: usize,
+    /// Undirected links between nodes; blocks are relayed along them
+    pub topology: Vec<(usize, usize)>,
+    /// Share of the total hash power of each node; defaults to equal shares
+    #[serde(default)]
+    pub hash_power: Vec<f64>,
+    /// Link latency in milliseconds, indexed by sender and receiver; defaults to zero
+    #[serde(default)]
+    pub latency_ms: Vec<Vec<u64>>,
+    /// Transactions created per second across the network
+    #[serde(default)]
+    pub transactions_per_second: f64,
+    pub duration_secs: u64,
+    /// Mean time between blocks of the whole network; defaults to the target block time
+    #[serde(default)]
+    pub block_interval_ms: Option<u64>,
+    #[serde(default)]
+    pub seed: u64,
+}
+
+/// Outcome of a simulation run
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Report {
+    pub blocks_mined: Vec<u32>,
+    /// Height of the longest chain of each node at the end of the run
+    pub final_heights: Vec<u32>,
+    /// Blocks that ended up outside the longest chain of node 0
+    pub stale_blocks: u32,
+    pub stale_rate: f64,
+    pub transactions_confirmed: u64,
+    /// Whether all nodes agree on the tip at the end of the run
+    pub converged: bool,
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        let scenario: Scenario = serde_json::from_reader(file).map_err(|e| e.to_string())?;
+        scenario.check()?;
+        return Ok(scenario);
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.nodes == 0 {
+            return Err("a scenario needs at least one node".to_string());
+        }
+        if !self.hash_power.is_empty() && self.hash_power.len() != self.nodes {
+            return Err("hash_power must have one entry per node".to_string());
+        }
+        if !self.latency_ms.is_empty()
+            && (self.latency_ms.len() != self.nodes || self.latency_ms.iter().any(|row| row.len() != self.nodes))
+        {
+            return Err("latency_ms must be a nodes x nodes matrix".to_string());
+        }
+        if self.topology.iter().any(|(a, b)| *a >= self.nodes || *b >= self.nodes) {
+            return Err("topology refers to an unknown node".to_string());
+        }
+        return Ok(());
+    }
+
+    fn latency(&self, from: usize, to: usize) -> u64 {
+        if self.latency_ms.is_empty() {
+            return 0;
+        }
+        return self.latency_ms[from][to] * 1000;
+    }
+
+    fn neighbors(&self, node: usize) -> Vec<usize> {
+        let mut neighbors: Vec<usize> = Vec::new();
+        for (a, b) in &self.topology {
+            if *a == node {
+                neighbors.push(*b);
+            } else if *b == node {
+                neighbors.push(*a);
+            }
+        }
+        return neighbors;
+    }
+}
+
+enum Event {
+    /// A node finds a block
+    Mine(usize),
+    /// A node receives a block
+    Deliver(usize, Block),
+}
+
+struct Node {
+    blockchain: Blockchain,
+    /// Received blocks whose parent has not arrived yet
+    pending: Vec<Block>,
+    seen: HashSet<H256>,
+}
+
+/// Run the scenario as a discrete event simulation, with simulated time in microseconds
+pub fn run(scenario: &Scenario) -> Report {
+    let mut rng = StdRng::seed_from_u64(scenario.seed);
+    let genesis = Blockchain::genesis_block();
+    let mut nodes: Vec<Node> = (0..scenario.nodes)
+        .map(|_| Node {
+            blockchain: Blockchain::with_genesis(genesis.clone()),
+            pending: Vec::new(),
+            seen: HashSet::new(),
+        })
+        .collect();
+    let hash_power: Vec<f64> = if scenario.hash_power.is_empty() {
+        vec![1.0; scenario.nodes]
+    } else {
+        scenario.hash_power.clone()
+    };
+    let total_power: f64 = hash_power.iter().sum();
+    let interval = scenario
+        .block_interval_ms
+        .map(|ms| ms as f64 * 1000.0)
+        .unwrap_or(TARGET_BLOCK_TIME.as_micros() as f64);
+    let end = scenario.duration_secs * 1_000_000;
+
+    // events ordered by time, then by insertion order
+    let mut queue: BinaryHeap<Reverse<(u64, u64)>> = BinaryHeap::new();
+    let mut events: Vec<Option<Event>> = Vec::new();
+    let schedule = |queue: &mut BinaryHeap<Reverse<(u64, u64)>>, events: &mut Vec<Option<Event>>, time: u64, event: Event| {
+        queue.push(Reverse((time, events.len() as u64)));
+        events.push(Some(event));
+    };
+    let next_mining = |rng: &mut StdRng, node: usize| -> u64 {
+        // exponential inter-arrival times, with a rate proportional to the hash power
+        let rate = hash_power[node] / total_power / interval;
+        let u: f64 = rng.gen_range(std::f64::EPSILON, 1.0);
+        (-u.ln() / rate) as u64
+    };
+    for node in 0..scenario.nodes {
+        if hash_power[node] > 0.0 {
+            let delay = next_mining(&mut rng, node);
+            schedule(&mut queue, &mut events, delay, Event::Mine(node));
+        }
+    }
+
+    let mut blocks_mined = vec![0u32; scenario.nodes];
+    let mut mined: Vec<H256> = Vec::new();
+    let mut last_mining_time = 0u64;
+    while let Some(Reverse((time, id))) = queue.pop() {
+        if time > end {
+            break;
+        }
+        let event = events[id as usize].take().unwrap();
+        let (node, block) = match event {
+            Event::Mine(node) => {
+                let elapsed = (time - last_mining_time) as f64 / 1_000_000.0;
+                last_mining_time = time;
+                let tx_count = (scenario.transactions_per_second * elapsed).round() as usize;
+                // each transaction spends one output of the coinbase, only their number matters
+                let recipient = H256::from([node as u8; 32]);
+                let input = TxInput::coinbase((mined.len() as u32).to_le_bytes().to_vec());
+                let coinbase = Transaction::new(vec![input], vec![TxOutput::new(1, recipient); tx_count + 1]);
+                let mut transactions = vec![coinbase.clone()];
+                for i in 0..tx_count {
+                    let input = TxInput::new(coinbase.txid(), i as u32);
+                    transactions.push(Transaction::new(vec![input], vec![TxOutput::new(1, recipient)]));
+                }
+                let merkle_root = MerkleTree::new(&transactions).root();
+                let parent = nodes[node].blockchain.tip();
+                let block = Block::new(parent, Blockchain::get_difficulty(), transactions, merkle_root);
+                blocks_mined[node] += 1;
+                mined.push(block.hash());
+                let delay = next_mining(&mut rng, node);
+                schedule(&mut queue, &mut events, time + delay, Event::Mine(node));
+                (node, block)
+            }
+            Event::Deliver(node, block) => (node, block),
+        };
+        if !nodes[node].seen.insert(block.hash()) {
+            continue;
+        }
+        // insert the block, and the pending blocks it unlocks; relay whatever got inserted
+        let mut relay: Vec<Block> = Vec::new();
+        let state = &mut nodes[node];
+        state.pending.push(block);
+        let mut progress = true;
+        while progress {
+            progress = false;
+            let pending = std::mem::take(&mut state.pending);
+            for candidate in pending {
+                match state.blockchain.insert(&candidate) {
+                    Ok(()) => {
+                        relay.push(candidate);
+                        progress = true;
+                    }
+                    Err(InsertError::UnknownParent) => state.pending.push(candidate),
+                    Err(_) => {}
+                }
+            }
+        }
+        for block in relay {
+            for neighbor in scenario.neighbors(node) {
+                let arrival = time + scenario.latency(node, neighbor);
+                schedule(&mut queue, &mut events, arrival, Event::Deliver(neighbor, block.clone()));
+            }
+        }
+    }
+
+    let reference = &nodes[0].blockchain;
+    let main_chain: HashSet<H256> = reference.iter().map(|b| b.hash()).filter(|h| *h != genesis.hash()).collect();
+    let stale_blocks = mined.iter().filter(|h| !main_chain.contains(h)).count() as u32;
+    let mut transactions_confirmed: u64 = 0;
+    for hash in &main_chain {
+        transactions_confirmed += reference.get(hash).get_transactions().len() as u64 - 1;
+    }
+    let tip = reference.tip();
+    return Report {
+        blocks_mined,
+        final_heights: nodes.iter().map(|n| n.blockchain.tip_height()).collect(),
+        stale_blocks,
+        stale_rate: if mined.is_empty() { 0.0 } else { stale_blocks as f64 / mined.len() as f64 },
+        transactions_confirmed,
+        converged: nodes.iter().all(|n| n.blockchain.tip() == tip),
+    };
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+
+    fn scenario(latency: u64) -> Scenario {
+        Scenario {
+            nodes: 3,
+            topology: vec![(0, 1), (1, 2)],
+            hash_power: vec![1.0, 1.0, 2.0],
+            latency_ms: vec![vec![0, latency, latency]; 3],
+            transactions_per_second: 2.0,
+            duration_secs: 600,
+            block_interval_ms: Some(10_000),
+            seed: 7,
+        }
+    }
+
+    #[test]
+    fn deterministic_and_consistent() {
+        let report = run(&scenario(100));
+        assert_eq!(report, run(&scenario(100)));
+        let total: u32 = report.blocks_mined.iter().sum();
+        assert!(total > 0);
+        assert!(report.final_heights.iter().all(|h| *h <= total));
+        assert!(report.stale_blocks <= total);
+    }
+
+    #[test]
+    fn parse_scenario() {
+        let json = r#"{"nodes": 2, "topology": [[0, 1]], "duration_secs": 60}"#;
+        let scenario: Scenario = serde_json::from_str(json).unwrap();
+        assert!(scenario.check().is_ok());
+        let report = run(&scenario);
+        assert_eq!(report.final_heights.len(), 2);
+        let json = r#"{"nodes": 2, "topology": [[0, 5]], "duration_secs": 60}"#;
+        let scenario: Scenario = serde_json::from_str(json).unwrap();
+        assert!(scenario.check().is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Predicting the size of a transaction from the types of its inputs before it is signed, so
+//! that its fee can be known in advance
+
+use crate::crypto::keys;
+use crate::crypto::schnorr;
+use crate::script::Script;
+use crate::transaction::{InputSignature, Transaction, TxInput, TxOutput};
+
+/// Size of an uncompressed ECDSA P-256 public key
+pub const ECDSA_PUBLIC_KEY_SIZE: usize = 65;
+
+/// Size of the longest DER encoded ECDSA P-256 signature
+pub const MAX_ECDSA_SIGNATURE_SIZE: usize = 72;
+
+pub const SCHNORR_PUBLIC_KEY_SIZE: usize = 32;
+
+pub const SCHNORR_SIGNATURE_SIZE: usize = 64;
+
+/// The signature scheme of a key signing an input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    Ecdsa,
+    Schnorr,
+}
+
+impl KeyType {
+    pub fn public_key_size(&self) -> usize {
+        return match self {
+            KeyType::Ecdsa => ECDSA_PUBLIC_KEY_SIZE,
+            KeyType::Schnorr => SCHNORR_PUBLIC_KEY_SIZE,
+        };
+    }
+
+    pub fn max_signature_size(&self) -> usize {
+        return match self {
+            KeyType::Ecdsa => MAX_ECDSA_SIGNATURE_SIZE,
+            KeyType::Schnorr => SCHNORR_SIGNATURE_SIZE,
+        };
+    }
+
+    /// The largest signature in a script, followed by its sighash type
+    fn max_script_signature(&self) -> Vec<u8> {
+        return vec![0u8; self.max_signature_size() + 1];
+    }
+}
+
+/// How an input unlocks the output it spends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputType {
+    /// An output of `SignatureScheme::Ecdsa` or `SignatureScheme::Schnorr`
+    Signature(KeyType),
+    P2pkh(KeyType),
+    P2wpkh(KeyType),
+    /// A `p2sh` output of `Script::multisig(required, _)` over `keys` keys
+    P2shMultisig { required: usize, keys: usize, key_type: KeyType },
+}
+
+impl InputType {
+    /// An input of this type holding placeholders as large as its signatures and keys can be
+    pub fn placeholder(&self) -> TxInput {
+        let mut input = TxInput::default();
+        match *self {
+            InputType::Signature(KeyType::Ecdsa) => {
+                let signature = vec![0u8; MAX_ECDSA_SIGNATURE_SIZE];
+                let public_key = keys::PublicKey::from_bytes(&[0u8; ECDSA_PUBLIC_KEY_SIZE]);
+                input.script = InputSignature::Ecdsa { signature, public_key }.to_script().as_bytes().to_vec();
+            }
+            InputType::Signature(KeyType::Schnorr) => {
+                let signature = vec![0u8; SCHNORR_SIGNATURE_SIZE];
+                let public_key = schnorr::PublicKey::from_bytes([0u8; SCHNORR_PUBLIC_KEY_SIZE]);
+                input.script = InputSignature::Schnorr { signature, public_key }.to_script().as_bytes().to_vec();
+            }
+            InputType::P2pkh(key_type) => {
+                let public_key = vec![0u8; key_type.public_key_size()];
+                input.script = Script::p2pkh_unlock(&key_type.max_script_signature(), &public_key).as_bytes().to_vec();
+            }
+            InputType::P2wpkh(key_type) => {
+                input.witness = vec![key_type.max_script_signature(), vec![0u8; key_type.public_key_size()]];
+            }
+            InputType::P2shMultisig { required, keys, key_type } => {
+                let public_keys = vec![vec![0u8; key_type.public_key_size()]; keys];
+                let signatures = vec![key_type.max_script_signature(); required];
+                let redeem_script = Script::multisig(required, &public_keys);
+                input.script = Script::p2sh_unlock(&signatures, &redeem_script).as_bytes().to_vec();
+            }
+        }
+        return input;
+    }
+
+    /// Weight the input adds to a transaction, at most
+    pub fn weight(&self) -> usize {
+        let input = self.placeholder();
+        let witness_size: usize = input.witness.iter().map(|w| 8 + w.len()).sum();
+        let size = bincode::serialized_size(&input).unwrap() as usize;
+        return (size - witness_size) * 4 + witness_size;
+    }
+
+    /// Virtual size the input adds to a transaction, at most
+    pub fn vsize(&self) -> usize {
+        return (self.weight() + 3) / 4;
+    }
+}
+
+/// Largest weight of a transaction with inputs of types `inputs`, paying `outputs`, once signed
+pub fn estimate_weight(inputs: &[InputType], outputs: &[TxOutput]) -> usize {
+    let inputs = inputs.iter().map(|i| i.placeholder()).collect();
+    return Transaction::new(inputs, outputs.to_vec()).weight();
+}
+
+/// Largest virtual size of a transaction once signed, see `estimate_weight`
+pub fn estimate_vsize(inputs: &[InputType], outputs: &[TxOutput]) -> usize {
+    return (estimate_weight(inputs, outputs) + 3) / 4;
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::H256;
+    use crate::multisig::PartialMultisig;
+    use crate::script::TransactionChecker;
+    use crate::transaction::SigHashType;
+
+    #[test]
+    fn estimates() {
+        let ecdsa = keys::KeyPair::random();
+        let schnorr_key = schnorr::KeyPair::random();
+        let outputs = vec![TxOutput::new(1_000, H256::default()), TxOutput::with_script(500, Script::p2pkh(&[1u8; 20]))];
+        let unsigned = |n: usize| {
+            let inputs = (0..n).map(|i| TxInput::new(H256::from([i as u8 + 1; 32]), 0)).collect();
+            Transaction::new(inputs, outputs.clone())
+        };
+        let check = |input_types: &[InputType], signed: &Transaction| {
+            let estimate = estimate_vsize(input_types, &outputs);
+            assert!(estimate >= signed.vsize());
+            // ECDSA signatures may be a few bytes shorter than the longest
+            assert!(estimate <= signed.vsize() + 8 * input_types.len());
+            let sum: usize = input_types.iter().map(|i| i.weight()).sum();
+            assert_eq!(estimate_weight(input_types, &outputs), unsigned(0).weight() + sum);
+        };
+
+        let mut t = unsigned(1);
+        t.sign(&ecdsa).unwrap();
+        check(&[InputType::Signature(KeyType::Ecdsa)], &t);
+        let mut t = unsigned(2);
+        t.sign_schnorr(&schnorr_key).unwrap();
+        check(&[InputType::Signature(KeyType::Schnorr); 2], &t);
+        let mut t = unsigned(1);
+        t.sign_p2pkh(&ecdsa).unwrap();
+        check(&[InputType::P2pkh(KeyType::Ecdsa)], &t);
+        let mut t = unsigned(2);
+        t.sign_p2wpkh(&ecdsa).unwrap();
+        check(&[InputType::P2wpkh(KeyType::Ecdsa); 2], &t);
+        assert!(InputType::P2wpkh(KeyType::Ecdsa).vsize() < InputType::P2pkh(KeyType::Ecdsa).vsize());
+
+        let others: Vec<keys::KeyPair> = (0..2).map(|_| keys::KeyPair::random()).collect();
+        let public_keys: Vec<Vec<u8>> = others.iter().chain(Some(&ecdsa)).map(|k| k.public_key().as_bytes().to_vec()).collect();
+        let redeem_script = Script::multisig(2, &public_keys);
+        let mut t = unsigned(1);
+        let mut partial = PartialMultisig::new(redeem_script).unwrap();
+        let checker = TransactionChecker::new(&t, 0);
+        partial.sign(&others[0], &checker, SigHashType::ALL).unwrap();
+        partial.sign(&ecdsa, &checker, SigHashType::ALL).unwrap();
+        t.set_script(0, &partial.unlock_p2sh().unwrap());
+        check(&[InputType::P2shMultisig { required: 2, keys: 3, key_type: KeyType::Ecdsa }], &t);
+    }
+}
@@ -0,0 +1,204 @@
+use serde::{Serialize, Deserialize};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::block::{Block, Header};
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::merkle::MerkleTree;
+use crate::store::ChainStore;
+use crate::utxo::UtxoSet;
+
+/// The UTXO set at the tip of the longest chain, with the headers leading to it, to start a node
+/// without downloading and replaying the whole history
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UtxoSnapshot {
+    headers: Vec<Header>,
+    utxo: UtxoSet,
+}
+
+/// Reasons for a snapshot to be rejected
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(std::io::Error),
+    Encoding(bincode::Error),
+    BrokenChain,
+    /// The hash of the UTXO set is not the trusted one
+    UntrustedUtxo(H256),
+    /// A block replayed for background validation is not the expected one
+    UnexpectedBlock(H256),
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "i/o error: {}", e),
+            SnapshotError::Encoding(e) => write!(f, "encoding error: {}", e),
+            SnapshotError::BrokenChain => write!(f, "headers do not form a chain"),
+            SnapshotError::UntrustedUtxo(hash) => write!(f, "untrusted UTXO set {}", hash),
+            SnapshotError::UnexpectedBlock(hash) => write!(f, "unexpected block {}", hash),
+        }
+    }
+}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(e: std::io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+impl From<bincode::Error> for SnapshotError {
+    fn from(e: bincode::Error) -> Self {
+        SnapshotError::Encoding(e)
+    }
+}
+
+impl UtxoSnapshot {
+    /// Take a snapshot of the longest chain of `blockchain`
+    pub fn create(blockchain: &Blockchain) -> Self {
+        return UtxoSnapshot {
+            headers: blockchain.headers_in_range(0, blockchain.tip_height()),
+            utxo: blockchain.utxo_set().clone(),
+        };
+    }
+
+    pub fn get_tip(&self) -> H256 {
+        return self.headers.last().map(|h| h.hash()).unwrap_or_default();
+    }
+
+    pub fn get_height(&self) -> u32 {
+        return self.headers.len().saturating_sub(1) as u32;
+    }
+
+    /// Hash of the UTXO set, to be compared with a trusted value
+    pub fn utxo_hash(&self) -> H256 {
+        return self.utxo.hash();
+    }
+
+    /// Start a blockchain in the empty `store` from the snapshot. The UTXO set must have the
+    /// `trusted` hash, as it cannot be checked before the history is replayed.
+    pub fn load_into(&self, store: Box<dyn ChainStore>, trusted: &H256) -> Result<Blockchain, SnapshotError> {
+        if self.headers.is_empty() || self.headers.windows(2).any(|pair| pair[1].get_parent() != pair[0].hash()) {
+            return Err(SnapshotError::BrokenChain);
+        }
+        if self.utxo_hash() != *trusted {
+            return Err(SnapshotError::UntrustedUtxo(self.utxo_hash()));
+        }
+        return Ok(Blockchain::from_snapshot(store, &self.headers, &self.utxo)?);
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), SnapshotError> {
+        let mut file = File::create(path)?;
+        file.write_all(&bincode::serialize(self)?)?;
+        return Ok(());
+    }
+
+    pub fn load(path: &Path) -> Result<Self, SnapshotError> {
+        let mut bytes: Vec<u8> = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        return Ok(bincode::deserialize(&bytes)?);
+    }
+}
+
+/// Replays the full blocks of the history of a snapshot, in order, to check its UTXO set
+pub struct HistoryValidator {
+    hashes: Vec<H256>,
+    expected: H256,
+    utxo: UtxoSet,
+}
+
+impl HistoryValidator {
+    pub fn new(snapshot: &UtxoSnapshot) -> Self {
+        return HistoryValidator {
+            hashes: snapshot.headers.iter().rev().map(|h| h.hash()).collect(),
+            expected: snapshot.utxo_hash(),
+            utxo: UtxoSet::new(),
+        };
+    }
+
+    /// Hash of the next block to replay, `None` once done
+    pub fn next_needed(&self) -> Option<H256> {
+        return self.hashes.last().cloned();
+    }
+
+    /// Hashes of the next `count` blocks to replay, in order
+    pub fn next_batch(&self, count: usize) -> Vec<H256> {
+        return self.hashes.iter().rev().take(count).cloned().collect();
+    }
+
+    /// Replay the next block. Returns whether the snapshot is valid once the last block was
+    /// replayed, `None` before.
+    pub fn add_block(&mut self, block: &Block) -> Result<Option<bool>, SnapshotError> {
+        let hash = block.hash();
+        let complete = MerkleTree::new(block.get_transactions()).root() == block.get_header().get_merkle_root();
+        if self.next_needed() != Some(hash) || block.is_pruned() || !complete {
+            return Err(SnapshotError::UnexpectedBlock(hash));
+        }
+        self.hashes.pop();
+        self.utxo.connect_block(block);
+        if self.hashes.is_empty() {
+            return Ok(Some(self.utxo.hash() == self.expected));
+        }
+        return Ok(None);
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::store::MemoryStore;
+    use crate::transaction::{Transaction, BLOCK_REWARD};
+    use crate::utxo::OutPoint;
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut source = Blockchain::new();
+        let mut blocks = vec![source.get(&source.tip())];
+        for _ in 0..3 {
+            let block = generate_random_block(&source.tip());
+            source.insert(&block).unwrap();
+            blocks.push(block);
+        }
+        let snapshot = UtxoSnapshot::create(&source);
+        assert_eq!(snapshot.get_height(), 3);
+        let trusted = source.utxo_set().hash();
+        assert!(matches!(
+            snapshot.load_into(Box::new(MemoryStore::new()), &H256::default()),
+            Err(SnapshotError::UntrustedUtxo(_))
+        ));
+        let mut blockchain = snapshot.load_into(Box::new(MemoryStore::new()), &trusted).unwrap();
+        assert_eq!(blockchain.tip(), source.tip());
+        assert_eq!(blockchain.tip_height(), 3);
+        let coinbase = blocks[3].get_transactions()[0].hash();
+        assert_eq!(blockchain.utxo(&OutPoint::new(coinbase, 0)), source.utxo(&OutPoint::new(coinbase, 0)));
+        let block = generate_random_block(&blockchain.tip());
+        blockchain.insert(&block).unwrap();
+        assert_eq!(blockchain.tip_height(), 4);
+
+        let mut validator = HistoryValidator::new(&snapshot);
+        assert!(validator.add_block(&blocks[1]).is_err());
+        assert_eq!(validator.add_block(&blocks[0]).unwrap(), None);
+        assert_eq!(validator.next_batch(2), vec![blocks[1].hash(), blocks[2].hash()]);
+        assert_eq!(validator.add_block(&blocks[1]).unwrap(), None);
+        assert_eq!(validator.add_block(&blocks[2]).unwrap(), None);
+        assert_eq!(validator.add_block(&blocks[3]).unwrap(), Some(true));
+        assert_eq!(validator.next_needed(), None);
+    }
+
+    #[test]
+    fn tampered_snapshot_fails_validation() {
+        let mut source = Blockchain::new();
+        let genesis = source.get(&source.tip());
+        let block = generate_random_block(&source.tip());
+        source.insert(&block).unwrap();
+        let mut snapshot = UtxoSnapshot::create(&source);
+        let fake = Transaction::coinbase(1, H256::from([6u8; 32]), BLOCK_REWARD);
+        let merkle_root = MerkleTree::new(&[fake.clone()]).root();
+        snapshot.utxo.connect_block(&Block::new(H256::default(), Blockchain::get_difficulty(), vec![fake], merkle_root));
+        let mut validator = HistoryValidator::new(&snapshot);
+        validator.add_block(&genesis).unwrap();
+        assert_eq!(validator.add_block(&block).unwrap(), Some(false));
+    }
+}
@@ -0,0 +1,476 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::block::Block;
+use crate::crypto::hash::{H256, Hashable};
+
+/// Storage of the blocks and metadata (such as the tip) of a blockchain
+pub trait ChainStore: Send + Sync {
+    fn get_block(&self, hash: &H256) -> Option<Block>;
+
+    fn contains_block(&self, hash: &H256) -> bool;
+
+    fn put_block(&mut self, block: &Block) -> std::io::Result<()>;
+
+    /// Hashes of all stored blocks, parents before children
+    fn block_hashes(&self) -> Vec<H256>;
+
+    fn num_blocks(&self) -> usize;
+
+    fn get_meta(&self, key: &str) -> Option<Vec<u8>>;
+
+    fn put_meta(&mut self, key: &str, value: &[u8]) -> std::io::Result<()>;
+
+    /// Make sure everything written so far survives a crash
+    fn flush(&mut self) -> std::io::Result<()>;
+
+    /// Replace a block with its pruned version, keeping only its header. The space may only be
+    /// reclaimed by `compact`.
+    fn prune_block(&mut self, hash: &H256) -> std::io::Result<()>;
+
+    /// Reclaim the space of pruned blocks
+    fn compact(&mut self) -> std::io::Result<()> {
+        return Ok(());
+    }
+}
+
+/// A store keeping everything in memory
+#[derive(Default)]
+pub struct MemoryStore {
+    blocks: HashMap<H256, Block>,
+    order: Vec<H256>,
+    meta: HashMap<String, Vec<u8>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl ChainStore for MemoryStore {
+    fn get_block(&self, hash: &H256) -> Option<Block> {
+        return self.blocks.get(hash).cloned();
+    }
+
+    fn contains_block(&self, hash: &H256) -> bool {
+        return self.blocks.contains_key(hash);
+    }
+
+    fn put_block(&mut self, block: &Block) -> std::io::Result<()> {
+        let hash = block.hash();
+        if self.blocks.insert(hash, block.clone()).is_none() {
+            self.order.push(hash);
+        }
+        return Ok(());
+    }
+
+    fn block_hashes(&self) -> Vec<H256> {
+        return self.order.clone();
+    }
+
+    fn num_blocks(&self) -> usize {
+        return self.blocks.len();
+    }
+
+    fn get_meta(&self, key: &str) -> Option<Vec<u8>> {
+        return self.meta.get(key).cloned();
+    }
+
+    fn put_meta(&mut self, key: &str, value: &[u8]) -> std::io::Result<()> {
+        self.meta.insert(key.to_string(), value.to_vec());
+        return Ok(());
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return Ok(());
+    }
+
+    fn prune_block(&mut self, hash: &H256) -> std::io::Result<()> {
+        if let Some(block) = self.blocks.get_mut(hash) {
+            *block = block.pruned();
+        }
+        return Ok(());
+    }
+}
+
+/// A store persisting blocks in an append-only file of length-prefixed records, with an in-memory
+/// index of record offsets rebuilt when the store is opened. Metadata lives in a separate file.
+pub struct FileStore {
+    /// Offset and length of the record of each block
+    index: HashMap<H256, (u64, u32)>,
+    order: Vec<H256>,
+    meta: HashMap<String, Vec<u8>>,
+    meta_path: PathBuf,
+    blocks_path: PathBuf,
+    reader: Mutex<File>,
+    writer: File,
+    end: u64,
+    /// Blocks pruned since the last compaction, still stored in full
+    pruned: HashSet<H256>,
+}
+
+const BLOCKS_FILE: &str = "blocks.dat";
+const META_FILE: &str = "meta.dat";
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl FileStore {
+    /// Open the store in directory `dir`, creating it if needed
+    pub fn open(dir: &Path) -> std::io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let blocks_path = dir.join(BLOCKS_FILE);
+        let writer = OpenOptions::new().create(true).append(true).open(&blocks_path)?;
+        let mut reader = File::open(&blocks_path)?;
+
+        let mut index: HashMap<H256, (u64, u32)> = HashMap::new();
+        let mut order: Vec<H256> = Vec::new();
+        let mut bytes: Vec<u8> = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let mut offset: usize = 0;
+        while offset + 4 <= bytes.len() {
+            let len = u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+            if offset + 4 + len > bytes.len() {
+                // a record torn by a crash, it is overwritten by the next write
+                break;
+            }
+            let block: Block = bincode::deserialize(&bytes[offset + 4..offset + 4 + len]).map_err(invalid_data)?;
+            let hash = block.hash();
+            if index.insert(hash, (offset as u64 + 4, len as u32)).is_none() {
+                order.push(hash);
+            }
+            offset += 4 + len;
+        }
+        if offset < bytes.len() {
+            writer.set_len(offset as u64)?;
+        }
+
+        let meta_path = dir.join(META_FILE);
+        let mut meta: HashMap<String, Vec<u8>> = HashMap::new();
+        if meta_path.exists() {
+            let mut meta_bytes: Vec<u8> = Vec::new();
+            File::open(&meta_path)?.read_to_end(&mut meta_bytes)?;
+            meta = bincode::deserialize(&meta_bytes).map_err(invalid_data)?;
+        }
+
+        return Ok(FileStore {
+            index,
+            order,
+            meta,
+            meta_path,
+            blocks_path,
+            reader: Mutex::new(reader),
+            writer,
+            end: offset as u64,
+            pruned: HashSet::new(),
+        });
+    }
+
+    fn read_block(&self, hash: &H256) -> Option<Block> {
+        let (offset, len) = *self.index.get(hash)?;
+        let mut reader = self.reader.lock().unwrap();
+        let mut buffer = vec![0u8; len as usize];
+        reader.seek(SeekFrom::Start(offset)).ok()?;
+        reader.read_exact(&mut buffer).ok()?;
+        return bincode::deserialize(&buffer).ok();
+    }
+}
+
+impl ChainStore for FileStore {
+    fn get_block(&self, hash: &H256) -> Option<Block> {
+        let block = self.read_block(hash)?;
+        if self.pruned.contains(hash) {
+            return Some(block.pruned());
+        }
+        return Some(block);
+    }
+
+    fn contains_block(&self, hash: &H256) -> bool {
+        return self.index.contains_key(hash);
+    }
+
+    fn put_block(&mut self, block: &Block) -> std::io::Result<()> {
+        let hash = block.hash();
+        if self.index.contains_key(&hash) {
+            return Ok(());
+        }
+        let bytes = bincode::serialize(block).map_err(invalid_data)?;
+        let mut record: Vec<u8> = Vec::with_capacity(bytes.len() + 4);
+        record.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        record.extend_from_slice(&bytes);
+        self.writer.write_all(&record)?;
+        self.index.insert(hash, (self.end + 4, bytes.len() as u32));
+        self.order.push(hash);
+        self.end += record.len() as u64;
+        return Ok(());
+    }
+
+    fn block_hashes(&self) -> Vec<H256> {
+        return self.order.clone();
+    }
+
+    fn num_blocks(&self) -> usize {
+        return self.index.len();
+    }
+
+    fn get_meta(&self, key: &str) -> Option<Vec<u8>> {
+        return self.meta.get(key).cloned();
+    }
+
+    fn put_meta(&mut self, key: &str, value: &[u8]) -> std::io::Result<()> {
+        self.meta.insert(key.to_string(), value.to_vec());
+        // write to a temporary file first, so a crash never leaves a half-written meta file
+        let tmp_path = self.meta_path.with_extension("tmp");
+        File::create(&tmp_path)?.write_all(&bincode::serialize(&self.meta).map_err(invalid_data)?)?;
+        fs::rename(&tmp_path, &self.meta_path)?;
+        return Ok(());
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()?;
+        return self.writer.sync_data();
+    }
+
+    fn prune_block(&mut self, hash: &H256) -> std::io::Result<()> {
+        if self.index.contains_key(hash) {
+            self.pruned.insert(*hash);
+        }
+        return Ok(());
+    }
+
+    /// Rewrite the blocks file without the transactions of pruned blocks, then swap it in
+    fn compact(&mut self) -> std::io::Result<()> {
+        if self.pruned.is_empty() {
+            return Ok(());
+        }
+        let tmp_path = self.blocks_path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        let mut index: HashMap<H256, (u64, u32)> = HashMap::new();
+        let mut end: u64 = 0;
+        for hash in &self.order {
+            let block = self.get_block(hash).ok_or_else(|| invalid_data("unreadable block"))?;
+            let bytes = bincode::serialize(&block).map_err(invalid_data)?;
+            tmp.write_all(&(bytes.len() as u32).to_be_bytes())?;
+            tmp.write_all(&bytes)?;
+            index.insert(*hash, (end + 4, bytes.len() as u32));
+            end += bytes.len() as u64 + 4;
+        }
+        tmp.sync_data()?;
+        fs::rename(&tmp_path, &self.blocks_path)?;
+        self.writer = OpenOptions::new().append(true).open(&self.blocks_path)?;
+        self.reader = Mutex::new(File::open(&self.blocks_path)?);
+        self.index = index;
+        self.end = end;
+        self.pruned.clear();
+        return Ok(());
+    }
+}
+
+/// Least recently used blocks, evicting the oldest once full
+struct LruBlocks {
+    capacity: usize,
+    blocks: HashMap<H256, (Block, u64)>,
+    /// Hash of the block accessed at each tick
+    recency: BTreeMap<u64, H256>,
+    tick: u64,
+}
+
+impl LruBlocks {
+    fn get(&mut self, hash: &H256) -> Option<Block> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (block, last_access) = self.blocks.get_mut(hash)?;
+        self.recency.remove(last_access);
+        self.recency.insert(tick, *hash);
+        *last_access = tick;
+        return Some(block.clone());
+    }
+
+    fn insert(&mut self, block: Block) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        let hash = block.hash();
+        if let Some((_, last_access)) = self.blocks.insert(hash, (block, self.tick)) {
+            self.recency.remove(&last_access);
+        }
+        self.recency.insert(self.tick, hash);
+        while self.blocks.len() > self.capacity {
+            let (&oldest, _) = self.recency.iter().next().unwrap();
+            let evicted = self.recency.remove(&oldest).unwrap();
+            self.blocks.remove(&evicted);
+        }
+    }
+
+    fn remove(&mut self, hash: &H256) {
+        if let Some((_, last_access)) = self.blocks.remove(hash) {
+            self.recency.remove(&last_access);
+        }
+    }
+}
+
+/// A store keeping the most recently accessed blocks of another store in memory
+pub struct CachedStore<S: ChainStore> {
+    inner: S,
+    cache: Mutex<LruBlocks>,
+}
+
+impl<S: ChainStore> CachedStore<S> {
+    /// Cache up to `capacity` blocks of `inner`
+    pub fn new(inner: S, capacity: usize) -> Self {
+        let cache = LruBlocks {
+            capacity,
+            blocks: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        };
+        return CachedStore { inner, cache: Mutex::new(cache) };
+    }
+
+    /// Number of blocks currently cached
+    pub fn cached(&self) -> usize {
+        return self.cache.lock().unwrap().blocks.len();
+    }
+}
+
+impl<S: ChainStore> ChainStore for CachedStore<S> {
+    fn get_block(&self, hash: &H256) -> Option<Block> {
+        if let Some(block) = self.cache.lock().unwrap().get(hash) {
+            return Some(block);
+        }
+        let block = self.inner.get_block(hash)?;
+        self.cache.lock().unwrap().insert(block.clone());
+        return Some(block);
+    }
+
+    fn contains_block(&self, hash: &H256) -> bool {
+        return self.inner.contains_block(hash);
+    }
+
+    fn put_block(&mut self, block: &Block) -> std::io::Result<()> {
+        self.inner.put_block(block)?;
+        // new blocks are usually the tip, and accessed right away
+        self.cache.lock().unwrap().insert(block.clone());
+        return Ok(());
+    }
+
+    fn block_hashes(&self) -> Vec<H256> {
+        return self.inner.block_hashes();
+    }
+
+    fn num_blocks(&self) -> usize {
+        return self.inner.num_blocks();
+    }
+
+    fn get_meta(&self, key: &str) -> Option<Vec<u8>> {
+        return self.inner.get_meta(key);
+    }
+
+    fn put_meta(&mut self, key: &str, value: &[u8]) -> std::io::Result<()> {
+        return self.inner.put_meta(key, value);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.inner.flush();
+    }
+
+    fn prune_block(&mut self, hash: &H256) -> std::io::Result<()> {
+        self.cache.lock().unwrap().remove(hash);
+        return self.inner.prune_block(hash);
+    }
+
+    fn compact(&mut self) -> std::io::Result<()> {
+        return self.inner.compact();
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+pub mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+
+    /// A fresh directory under the system temporary directory
+    pub fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}_{}", name, rand::random::<u32>()));
+        let _ = fs::remove_dir_all(&dir);
+        return dir;
+    }
+
+    #[test]
+    fn file_store_reopen() {
+        let dir = temp_dir("file_store");
+        let block1 = generate_random_block(&H256::default());
+        let block2 = generate_random_block(&block1.hash());
+        {
+            let mut store = FileStore::open(&dir).unwrap();
+            store.put_block(&block1).unwrap();
+            store.put_block(&block2).unwrap();
+            store.put_block(&block1).unwrap();
+            store.put_meta("tip", block2.hash().as_ref()).unwrap();
+            store.flush().unwrap();
+        }
+        let store = FileStore::open(&dir).unwrap();
+        assert_eq!(store.num_blocks(), 2);
+        assert_eq!(store.block_hashes(), vec![block1.hash(), block2.hash()]);
+        assert_eq!(store.get_block(&block2.hash()).unwrap().hash(), block2.hash());
+        assert_eq!(store.get_meta("tip"), Some(block2.hash().as_ref().to_vec()));
+        assert!(!store.contains_block(&H256::default()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn file_store_compact() {
+        let dir = temp_dir("file_store_compact");
+        let block1 = generate_random_block(&H256::default());
+        let block2 = generate_random_block(&block1.hash());
+        let block3 = generate_random_block(&block2.hash());
+        {
+            let mut store = FileStore::open(&dir).unwrap();
+            store.put_block(&block1).unwrap();
+            store.put_block(&block2).unwrap();
+            store.prune_block(&block1.hash()).unwrap();
+            assert!(store.get_block(&block1.hash()).unwrap().is_pruned());
+            let size = fs::metadata(dir.join(BLOCKS_FILE)).unwrap().len();
+            store.compact().unwrap();
+            assert!(fs::metadata(dir.join(BLOCKS_FILE)).unwrap().len() < size);
+            store.put_block(&block3).unwrap();
+            store.flush().unwrap();
+        }
+        let store = FileStore::open(&dir).unwrap();
+        assert_eq!(store.block_hashes(), vec![block1.hash(), block2.hash(), block3.hash()]);
+        assert!(store.get_block(&block1.hash()).unwrap().is_pruned());
+        assert!(!store.get_block(&block2.hash()).unwrap().is_pruned());
+        assert_eq!(store.get_block(&block3.hash()).unwrap().get_transactions().len(), block3.get_transactions().len());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cached_store_evicts_least_recently_used() {
+        let block1 = generate_random_block(&H256::default());
+        let block2 = generate_random_block(&block1.hash());
+        let block3 = generate_random_block(&block2.hash());
+        let mut store = CachedStore::new(MemoryStore::new(), 2);
+        store.put_block(&block1).unwrap();
+        store.put_block(&block2).unwrap();
+        // touch block1, so block2 is evicted next
+        assert!(store.get_block(&block1.hash()).is_some());
+        store.put_block(&block3).unwrap();
+        assert_eq!(store.cached(), 2);
+        let cache = store.cache.lock().unwrap();
+        assert!(cache.blocks.contains_key(&block1.hash()));
+        assert!(!cache.blocks.contains_key(&block2.hash()));
+        drop(cache);
+        // evicted blocks are still read from the inner store
+        assert_eq!(store.get_block(&block2.hash()).unwrap().hash(), block2.hash());
+        assert_eq!(store.num_blocks(), 3);
+        store.prune_block(&block3.hash()).unwrap();
+        assert!(store.get_block(&block3.hash()).unwrap().is_pruned());
+    }
+}
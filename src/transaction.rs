@@ -1,122 +1,980 @@
-extern crate untrusted;
-
 use serde::{Serialize, Deserialize};
-use ring::signature::{Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
 use ring::digest::{SHA256, digest};
 
+use std::sync::OnceLock;
+
 use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::keys::{self, KeyError};
+use crate::crypto::schnorr;
+use crate::encoding::{self, DecodeError, Reader};
+use crate::script::Script;
+use crate::fee::FeeRate;
+use crate::utxo::{OutPoint, UtxoSet};
+
+/// An amount of coins, in satoshis
+pub type Amount = u64;
+
+/// Value of the output of a coinbase transaction
+pub const BLOCK_REWARD: Amount = 50_0000_0000;
+
+/// Largest value of an output, and of the outputs of a transaction together
+pub const MAX_MONEY: Amount = 21_000_000 * 1_0000_0000;
+
+/// Version of new transactions
+pub const TX_VERSION: i32 = 2;
+
+/// Sequence number of an input that does not let the lock time of its transaction apply
+pub const SEQUENCE_FINAL: u32 = u32::MAX;
+
+/// Highest sequence number of an input signaling that its transaction may be replaced by one
+/// paying a higher fee while unconfirmed, see `Transaction::signals_replacement`
+pub const SEQUENCE_MAX_REPLACEABLE: u32 = SEQUENCE_FINAL - 2;
+
+/// Lock times below this are block heights, others are UNIX timestamps in seconds
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+/// Reasons for an input not to be signed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignError {
+    /// No signature hash applies to the input at this index, see `Transaction::signature_hash_for`
+    NoSignatureHash(usize),
+    Key(KeyError),
+}
+
+impl std::fmt::Display for SignError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SignError::NoSignatureHash(index) => write!(f, "no signature hash for input {}", index),
+            SignError::Key(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<KeyError> for SignError {
+    fn from(e: KeyError) -> Self {
+        SignError::Key(e)
+    }
+}
+
+/// Reference to an output of a previous transaction, with the data unlocking it
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct TxInput {
+    pub prev_txid: H256,
+    pub index: u32,
+    /// Signature or script proving the right to spend the output
+    pub script: Vec<u8>,
+    /// Items proving the right to spend a witness program output, see `script::verify`. Unlike
+    /// the script, they are not part of the txid, so they cannot be changed to change the txid.
+    pub witness: Vec<Vec<u8>>,
+    /// The lock time of the transaction only applies if an input has a sequence number below
+    /// `SEQUENCE_FINAL`
+    pub sequence: u32,
+}
+
+impl TxInput {
+    /// An input spending output `index` of transaction `prev_txid`, not signed yet
+    pub fn new(prev_txid: H256, index: u32) -> Self {
+        return TxInput {
+            prev_txid,
+            index,
+            script: Vec::new(),
+            witness: Vec::new(),
+            sequence: SEQUENCE_FINAL,
+        };
+    }
+
+    /// The input of a coinbase transaction, which spends no output and carries arbitrary data
+    pub fn coinbase(data: Vec<u8>) -> Self {
+        return TxInput {
+            prev_txid: H256::default(),
+            index: u32::MAX,
+            script: data,
+            witness: Vec::new(),
+            sequence: SEQUENCE_FINAL,
+        };
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        return self.prev_txid == H256::default() && self.index == u32::MAX;
+    }
+
+    /// The output spent by the input
+    pub fn outpoint(&self) -> OutPoint {
+        return OutPoint::new(self.prev_txid, self.index);
+    }
+
+    /// The signature in the script of the input, if it holds one
+    pub fn signature(&self) -> Option<InputSignature> {
+        return bincode::deserialize(&self.script).ok();
+    }
+}
+
+/// The signature scheme an output must be spent with
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SignatureScheme {
+    /// ECDSA over P-256, see `crypto::keys`
+    Ecdsa,
+    /// BIP340 Schnorr over secp256k1, see `crypto::schnorr`
+    Schnorr,
+    /// Any signatures the locking script of the output asks for, see `script::verify`
+    Script,
+}
+
+impl Default for SignatureScheme {
+    fn default() -> Self {
+        SignatureScheme::Ecdsa
+    }
+}
+
+/// Which parts of the transaction a signature in a script commits to. It is the last byte of the
+/// signature, see `Transaction::signature_hash_for`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SigHashType(u8);
+
+impl SigHashType {
+    /// All inputs and outputs
+    pub const ALL: SigHashType = SigHashType(1);
+    /// All inputs and no output, so that anyone may choose where the coins go
+    pub const NONE: SigHashType = SigHashType(2);
+    /// All inputs, and the output at the position of the signed input
+    pub const SINGLE: SigHashType = SigHashType(3);
+    /// Flag committing to the signed input only, so that others may add inputs
+    pub const ANYONECANPAY: u8 = 0x80;
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        return match byte & !SigHashType::ANYONECANPAY {
+            1..=3 => Some(SigHashType(byte)),
+            _ => None,
+        };
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        return self.0;
+    }
+
+    /// The same type, committing to the signed input only
+    pub fn anyone_can_pay(&self) -> Self {
+        return SigHashType(self.0 | SigHashType::ANYONECANPAY);
+    }
+
+    pub fn is_anyone_can_pay(&self) -> bool {
+        return self.0 & SigHashType::ANYONECANPAY != 0;
+    }
+
+    /// The type without the ANYONECANPAY flag
+    fn base(&self) -> SigHashType {
+        return SigHashType(self.0 & !SigHashType::ANYONECANPAY);
+    }
+}
+
+/// The content of the script of a signed input: a signature of the transaction, and the key
+/// whose address the spent output pays to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum InputSignature {
+    Ecdsa { signature: Vec<u8>, public_key: keys::PublicKey },
+    Schnorr { signature: Vec<u8>, public_key: schnorr::PublicKey },
+}
+
+impl InputSignature {
+    pub fn scheme(&self) -> SignatureScheme {
+        return match self {
+            InputSignature::Ecdsa { .. } => SignatureScheme::Ecdsa,
+            InputSignature::Schnorr { .. } => SignatureScheme::Schnorr,
+        };
+    }
 
-/// A possibly signed transaction
+    /// The address of the signing key
+    pub fn address(&self) -> H256 {
+        return match self {
+            InputSignature::Ecdsa { public_key, .. } => public_key.address(),
+            InputSignature::Schnorr { public_key, .. } => public_key.address(),
+        };
+    }
+
+    /// Check the signature of `signature_hash`, see `Transaction::signature_hash`
+    pub fn verify(&self, signature_hash: &H256) -> bool {
+        return match self {
+            InputSignature::Ecdsa { signature, public_key } => public_key.verify(signature_hash.as_ref(), signature),
+            InputSignature::Schnorr { signature, public_key } => public_key.verify(signature_hash.as_ref(), signature),
+        };
+    }
+
+    /// The script of an input carrying the signature, see `TxInput::signature`
+    pub fn to_script(&self) -> Script {
+        return Script::from_bytes(bincode::serialize(self).unwrap());
+    }
+
+    /// Whether the signature may spend `output`: it uses the scheme of the output, is made by the
+    /// key the output pays to, and is valid
+    pub fn unlocks(&self, output: &TxOutput, signature_hash: &H256) -> bool {
+        return self.scheme() == output.scheme && self.address() == output.recipient && self.verify(signature_hash);
+    }
+}
+
+/// An amount paid to a recipient, identified by the hash of its public key, or of the script
+/// locking the output
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TxOutput {
+    pub value: Amount,
+    pub recipient: H256,
+    pub scheme: SignatureScheme,
+    /// The locking script of outputs using `SignatureScheme::Script`, empty otherwise
+    pub script_pubkey: Script,
+}
+
+impl TxOutput {
+    /// An output spent with an ECDSA signature
+    pub fn new(value: u64, recipient: H256) -> Self {
+        return TxOutput::with_scheme(value, recipient, SignatureScheme::Ecdsa);
+    }
+
+    pub fn with_scheme(value: u64, recipient: H256, scheme: SignatureScheme) -> Self {
+        return TxOutput {
+            value,
+            recipient,
+            scheme,
+            script_pubkey: Script::new(),
+        };
+    }
+
+    /// An output spent by an input whose script unlocks `script_pubkey`
+    pub fn with_script(value: u64, script_pubkey: Script) -> Self {
+        return TxOutput {
+            value,
+            recipient: script_pubkey.address(),
+            scheme: SignatureScheme::Script,
+            script_pubkey,
+        };
+    }
+
+    /// An output worth nothing carrying `data`, see `Script::null_data`
+    pub fn data(data: &[u8]) -> Self {
+        return TxOutput::with_script(0, Script::null_data(data));
+    }
+
+    /// Whether the output can never be spent, see `Script::is_unspendable`
+    pub fn is_unspendable(&self) -> bool {
+        return self.scheme == SignatureScheme::Script && self.script_pubkey.is_unspendable();
+    }
+}
+
+/// A transfer of the outputs of previous transactions to new outputs
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Transaction {
-    input: String,
-    output: String,
-    signature: Option<MySignature>,
+    version: i32,
+    inputs: Vec<TxInput>,
+    outputs: Vec<TxOutput>,
+    /// The transaction cannot be in a block before this height or median time past, see
+    /// `LOCKTIME_THRESHOLD` and `is_final`. Zero for no lock.
+    lock_time: u32,
+    /// The ids of the transaction, computed on first use. Methods changing the transaction must
+    /// reset them with `reset_ids`.
+    #[serde(skip)]
+    txid: OnceLock<H256>,
+    #[serde(skip)]
+    wtxid: OnceLock<H256>,
 }
 
 impl Transaction {
-    pub fn new(input: String, output: String) -> Self {
+    pub fn new(inputs: Vec<TxInput>, outputs: Vec<TxOutput>) -> Self {
         let transaction = Transaction {
-            input,
-            output,
-            signature: Option::None,
+            version: TX_VERSION,
+            inputs,
+            outputs,
+            lock_time: 0,
+            txid: OnceLock::new(),
+            wtxid: OnceLock::new(),
         };
         return transaction;
     }
-    /*
-    fn set_signature(&mut self, signature: &Signature) {
-        if self.is_signed() {
-            eprintln!("Ignored attempt to sign the already signed transaction");
-            return ;
+
+    /// The coinbase transaction of the block at `height`, paying `value` to `recipient`. The
+    /// height is committed to in the input, so coinbase transactions of different blocks have
+    /// different ids.
+    pub fn coinbase(height: u32, recipient: H256, value: u64) -> Self {
+        let input = TxInput::coinbase(height.to_le_bytes().to_vec());
+        return Transaction::new(vec![input], vec![TxOutput::new(value, recipient)]);
+    }
+
+    /// A coinbase paying `output`, with `extra_nonce` and the miner's `tag` following the height
+    /// in the input, so that miners get a new Merkle root to try once they tried all the nonces of
+    /// the header
+    pub fn coinbase_with_extra_nonce(height: u32, extra_nonce: u64, tag: &[u8], output: TxOutput) -> Self {
+        let data = [&height.to_le_bytes()[..], &extra_nonce.to_le_bytes()[..], tag].concat();
+        let input = TxInput::coinbase(data);
+        return Transaction::new(vec![input], vec![output]);
+    }
+
+    pub fn get_inputs(&self) -> &[TxInput] {
+        return &self.inputs;
+    }
+
+    pub fn get_outputs(&self) -> &[TxOutput] {
+        return &self.outputs;
+    }
+
+    /// Replace the outputs, e.g. to add a `block::witness_commitment_output` to a coinbase
+    pub fn set_outputs(&mut self, outputs: Vec<TxOutput>) {
+        self.outputs = outputs;
+        self.reset_ids();
+    }
+
+    pub fn get_version(&self) -> i32 {
+        return self.version;
+    }
+
+    pub fn set_version(&mut self, version: i32) {
+        self.version = version;
+        self.reset_ids();
+    }
+
+    pub fn get_lock_time(&self) -> u32 {
+        return self.lock_time;
+    }
+
+    /// Set the lock time. It only applies if an input has a sequence number below
+    /// `SEQUENCE_FINAL`, see `set_sequence`.
+    pub fn set_lock_time(&mut self, lock_time: u32) {
+        self.lock_time = lock_time;
+        self.reset_ids();
+    }
+
+    pub fn set_sequence(&mut self, index: usize, sequence: u32) {
+        self.inputs[index].sequence = sequence;
+        self.reset_ids();
+    }
+
+    /// Whether the transaction may be in a block at `height` whose ancestors have median time
+    /// past `time`, in seconds since the UNIX epoch: it has no lock time, its lock time has
+    /// passed, or all its inputs opt out of it
+    pub fn is_final(&self, height: u32, time: u64) -> bool {
+        if self.lock_time == 0 {
+            return true;
+        }
+        let threshold = if self.lock_time < LOCKTIME_THRESHOLD { height as u64 } else { time };
+        if (self.lock_time as u64) < threshold {
+            return true;
+        }
+        return self.inputs.iter().all(|input| input.sequence == SEQUENCE_FINAL);
+    }
+
+    /// Whether the transaction opts in to being replaced by a conflicting one paying a higher
+    /// fee while unconfirmed: an input has a sequence number of at most
+    /// `SEQUENCE_MAX_REPLACEABLE`
+    pub fn signals_replacement(&self) -> bool {
+        return self.inputs.iter().any(|input| input.sequence <= SEQUENCE_MAX_REPLACEABLE);
+    }
+
+    /// Whether the transaction creates new coins, rather than spending existing outputs
+    pub fn is_coinbase(&self) -> bool {
+        return self.inputs.len() == 1 && self.inputs[0].is_coinbase();
+    }
+
+    /// Sum of the values of the outputs, None if it overflows
+    pub fn output_value(&self) -> Option<Amount> {
+        let mut value: Amount = 0;
+        for output in &self.outputs {
+            value = value.checked_add(output.value)?;
         }
-        let my_signature = MySignature {
-            value: signature.as_ref().to_vec(),
+        return Some(value);
+    }
+
+    /// The value of the spent outputs minus the value of the outputs, zero for a coinbase
+    /// transaction. None if a spent output is not in `utxo`, or the outputs are worth more.
+    pub fn fee(&self, utxo: &UtxoSet) -> Option<Amount> {
+        if self.is_coinbase() {
+            return Some(0);
+        }
+        let mut inputs: Amount = 0;
+        for input in &self.inputs {
+            inputs = inputs.checked_add(utxo.get(&input.outpoint())?.value)?;
+        }
+        return inputs.checked_sub(self.output_value()?);
+    }
+
+    /// The fee rate of the transaction, see `fee`
+    pub fn fee_rate(&self, utxo: &UtxoSet) -> Option<FeeRate> {
+        return Some(FeeRate::from_fee(self.fee(utxo)?, self.vsize()));
+    }
+
+    /// Size of the encoding of the transaction, in bytes
+    pub fn size(&self) -> usize {
+        return bincode::serialized_size(self).unwrap() as usize;
+    }
+
+    /// Size of the encoding of the transaction without its witnesses
+    pub fn base_size(&self) -> usize {
+        let witness_size: usize = self.inputs.iter().map(|i| i.witness.iter().map(|w| 8 + w.len()).sum::<usize>()).sum();
+        return self.size() - witness_size;
+    }
+
+    /// Weight of the transaction: witness bytes count for 1, other bytes for 4
+    pub fn weight(&self) -> usize {
+        return self.base_size() * 3 + self.size();
+    }
+
+    /// Size in virtual bytes, the weight divided by 4, rounded up. Fee rates are per virtual byte.
+    pub fn vsize(&self) -> usize {
+        return (self.weight() + 3) / 4;
+    }
+
+    /// Whether an input carries witness data
+    pub fn has_witness(&self) -> bool {
+        return self.inputs.iter().any(|input| !input.witness.is_empty());
+    }
+
+    /// Hash identifying the transaction, computed on first use. It does not cover the witnesses.
+    pub fn txid(&self) -> H256 {
+        return *self.txid.get_or_init(|| {
+            if !self.has_witness() {
+                return self.wtxid();
+            }
+            let mut stripped = self.clone();
+            for input in stripped.inputs.iter_mut() {
+                input.witness.clear();
+            }
+            let serialized = bincode::serialize(&stripped).unwrap();
+            digest(&SHA256, &serialized).into()
+        });
+    }
+
+    /// The hash covered by the signatures of outputs using `SignatureScheme::Ecdsa` or
+    /// `SignatureScheme::Schnorr`: the hash of the transaction with the scripts and witnesses of
+    /// all inputs empty, as a signature cannot sign itself
+    pub fn signature_hash(&self) -> H256 {
+        let mut unsigned = self.clone();
+        for input in unsigned.inputs.iter_mut() {
+            input.script.clear();
+            input.witness.clear();
+        }
+        let serialized = bincode::serialize(&unsigned).unwrap();
+        return digest(&SHA256, &serialized).into();
+    }
+
+    /// The hash covered by a signature of the input at `index` in a script, committing to the
+    /// parts of the transaction chosen by `sighash_type`, the index, and the type. Scripts and
+    /// witnesses are left out as in `signature_hash`. None if the index is out of range, or
+    /// there is no output at the index for `SigHashType::SINGLE`.
+    pub fn signature_hash_for(&self, index: usize, sighash_type: SigHashType) -> Option<H256> {
+        if index >= self.inputs.len() {
+            return None;
+        }
+        let mut unsigned = self.clone();
+        for input in unsigned.inputs.iter_mut() {
+            input.script.clear();
+            input.witness.clear();
+        }
+        let base = sighash_type.base();
+        if base == SigHashType::NONE || base == SigHashType::SINGLE {
+            // the other inputs may be replaced
+            for (i, input) in unsigned.inputs.iter_mut().enumerate() {
+                if i != index {
+                    input.sequence = 0;
+                }
+            }
+        }
+        if base == SigHashType::NONE {
+            unsigned.outputs.clear();
+        } else if base == SigHashType::SINGLE {
+            if index >= unsigned.outputs.len() {
+                return None;
+            }
+            unsigned.outputs.truncate(index + 1);
+            for output in unsigned.outputs[..index].iter_mut() {
+                *output = TxOutput::default();
+            }
+        }
+        if sighash_type.is_anyone_can_pay() {
+            unsigned.inputs = vec![unsigned.inputs[index].clone()];
+        }
+        let serialized = bincode::serialize(&(&unsigned, index as u32, sighash_type.to_byte())).unwrap();
+        return Some(digest(&SHA256, &serialized).into());
+    }
+
+    /// Sign the input at `index` with the ECDSA `key`, for a script, see `signature_hash_for`.
+    /// Returns the signature followed by the sighash type, as scripts expect.
+    pub fn script_signature(&self, index: usize, key: &keys::KeyPair, sighash_type: SigHashType) -> Result<Vec<u8>, SignError> {
+        let signature_hash = self.signature_hash_for(index, sighash_type).ok_or(SignError::NoSignatureHash(index))?;
+        let mut signature = key.sign(signature_hash.as_ref())?;
+        signature.push(sighash_type.to_byte());
+        return Ok(signature);
+    }
+
+    /// Sign the input at `index` with the Schnorr `key`, for a script, see `script_signature`
+    pub fn script_signature_schnorr(&self, index: usize, key: &schnorr::KeyPair, sighash_type: SigHashType) -> Result<Vec<u8>, SignError> {
+        let signature_hash = self.signature_hash_for(index, sighash_type).ok_or(SignError::NoSignatureHash(index))?;
+        let mut signature = key.sign(signature_hash.as_ref())?;
+        signature.push(sighash_type.to_byte());
+        return Ok(signature);
+    }
+
+    /// Sign all inputs that spend an output with the ECDSA `key`
+    pub fn sign(&mut self, key: &keys::KeyPair) -> Result<(), KeyError> {
+        let signature = InputSignature::Ecdsa {
+            signature: key.sign(self.signature_hash().as_ref())?,
+            public_key: key.public_key(),
         };
-        self.signature = Option::from(my_signature);
+        self.set_signature(&signature);
+        return Ok(());
     }
 
-    fn is_signed(&self) -> bool {
-        return match self.signature {
-            Some(_) => true,
-            None => false,
+    /// Sign all inputs that spend an output with the Schnorr `key`
+    pub fn sign_schnorr(&mut self, key: &schnorr::KeyPair) -> Result<(), KeyError> {
+        let signature = InputSignature::Schnorr {
+            signature: key.sign(self.signature_hash().as_ref())?,
+            public_key: key.public_key(),
         };
+        self.set_signature(&signature);
+        return Ok(());
     }
-    */
-}
 
-impl Hashable for Transaction {
-    fn hash(&self) -> H256 {
-        let serialized = bincode::serialize(&self).unwrap();
-        let hashed = digest(&SHA256, &serialized);
-        let hashed256 = H256::from(hashed);
-        return hashed256;
+    /// Sign all inputs that spend an output with the ECDSA `key`, for outputs locked by
+    /// `Script::p2pkh` of the key
+    pub fn sign_p2pkh(&mut self, key: &keys::KeyPair) -> Result<(), SignError> {
+        for index in 0..self.inputs.len() {
+            if self.inputs[index].is_coinbase() {
+                continue;
+            }
+            let signature = self.script_signature(index, key, SigHashType::ALL)?;
+            self.inputs[index].script = Script::p2pkh_unlock(&signature, key.public_key().as_bytes()).as_bytes().to_vec();
+        }
+        self.reset_ids();
+        return Ok(());
     }
-}
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
-pub struct MySignature {
-    value: Vec<u8>,
-}
+    /// Sign all inputs that spend an output with the ECDSA `key`, for outputs locked by
+    /// `Script::p2wpkh` of the key. The signatures go in the witnesses, so the txid is known
+    /// before signing.
+    pub fn sign_p2wpkh(&mut self, key: &keys::KeyPair) -> Result<(), SignError> {
+        for index in 0..self.inputs.len() {
+            if self.inputs[index].is_coinbase() {
+                continue;
+            }
+            let signature = self.script_signature(index, key, SigHashType::ALL)?;
+            self.inputs[index].script.clear();
+            self.inputs[index].witness = vec![signature, key.public_key().as_bytes().to_vec()];
+        }
+        self.reset_ids();
+        return Ok(());
+    }
 
-/// Create digital signature of a transaction
-pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
-    let serialized = bincode::serialize(&t).unwrap();
-    let hashed = digest(&SHA256, &serialized);
-    let signature = key.sign(hashed.as_ref());
-    return signature;
-}
+    /// Set the witness of the input at `index`, e.g. the inputs of a `Script::p2wsh` witness
+    /// script followed by the script
+    pub fn set_witness(&mut self, index: usize, witness: Vec<Vec<u8>>) {
+        self.inputs[index].witness = witness;
+        self.reset_ids();
+    }
 
-//TODO Change the verify function
-/// Verify digital signature of a transaction, using public key instead of secret key
-pub fn verify(t: &Transaction, public_key: &<Ed25519KeyPair as KeyPair>::PublicKey, signature: &Signature) -> bool {
-    let serialized = bincode::serialize(&t).unwrap();
-    let hashed = digest(&SHA256, &serialized);
+    /// Set the unlocking script of the input at `index`, e.g. a `Script::p2sh_unlock` script
+    /// carrying the redeem script and its inputs
+    pub fn set_script(&mut self, index: usize, script: &Script) {
+        self.inputs[index].script = script.as_bytes().to_vec();
+        self.reset_ids();
+    }
+
+    fn set_signature(&mut self, signature: &InputSignature) {
+        let script = signature.to_script();
+        for input in self.inputs.iter_mut().filter(|input| !input.is_coinbase()) {
+            input.script = script.as_bytes().to_vec();
+        }
+        // the scripts are part of the ids
+        self.reset_ids();
+    }
+
+    /// Forget the cached ids after a change to the transaction
+    fn reset_ids(&mut self) {
+        self.txid = OnceLock::new();
+        self.wtxid = OnceLock::new();
+    }
+
+    /// Whether all inputs that spend an output carry a valid ECDSA signature by `public_key`
+    pub fn verify_signature(&self, public_key: &keys::PublicKey) -> bool {
+        let signature_hash = self.signature_hash();
+        return self.inputs.iter().filter(|input| !input.is_coinbase()).all(|input| match input.signature() {
+            Some(s @ InputSignature::Ecdsa { .. }) => s.address() == public_key.address() && s.verify(&signature_hash),
+            _ => false,
+        });
+    }
 
-    let public_key = untrusted::Input::from(public_key.as_ref());
-    let msg = untrusted::Input::from(hashed.as_ref());
-    let sgn = untrusted::Input::from(signature.as_ref());
+    /// Hash of the transaction including its witness data, computed on first use. It is the
+    /// txid for transactions without witnesses.
+    pub fn wtxid(&self) -> H256 {
+        return *self.wtxid.get_or_init(|| {
+            let serialized = bincode::serialize(&self).unwrap();
+            digest(&SHA256, &serialized).into()
+        });
+    }
 
-    let verification = VerificationAlgorithm::verify(&EdDSAParameters, public_key, msg, sgn);
-    let status= match verification {
-        Ok(_) => true,
-        Err(_) => false,
-    };
-    return status;
+    /// The transaction in Bitcoin's raw format: version, inputs, outputs, witnesses if any
+    /// input has one, and lock time. None if an output is not locked by a script, as Bitcoin
+    /// outputs all are.
+    pub fn consensus_encode(&self) -> Option<Vec<u8>> {
+        let mut buffer = Vec::with_capacity(self.size());
+        buffer.extend_from_slice(&self.version.to_le_bytes());
+        let witness = self.has_witness();
+        if witness {
+            buffer.extend_from_slice(&[0x00, 0x01]);
+        }
+        encoding::write_compact_size(&mut buffer, self.inputs.len() as u64);
+        for input in self.inputs.iter() {
+            buffer.extend_from_slice(input.prev_txid.as_ref());
+            buffer.extend_from_slice(&input.index.to_le_bytes());
+            encoding::write_bytes(&mut buffer, &input.script);
+            buffer.extend_from_slice(&input.sequence.to_le_bytes());
+        }
+        encoding::write_compact_size(&mut buffer, self.outputs.len() as u64);
+        for output in self.outputs.iter() {
+            if output.scheme != SignatureScheme::Script {
+                return None;
+            }
+            buffer.extend_from_slice(&output.value.to_le_bytes());
+            encoding::write_bytes(&mut buffer, output.script_pubkey.as_bytes());
+        }
+        if witness {
+            for input in self.inputs.iter() {
+                encoding::write_compact_size(&mut buffer, input.witness.len() as u64);
+                for item in input.witness.iter() {
+                    encoding::write_bytes(&mut buffer, item);
+                }
+            }
+        }
+        buffer.extend_from_slice(&self.lock_time.to_le_bytes());
+        return Some(buffer);
+    }
+
+    /// Decode a transaction in Bitcoin's raw format, see `consensus_encode`. Its outputs are
+    /// locked by scripts.
+    pub fn consensus_decode(data: &[u8]) -> Result<Transaction, DecodeError> {
+        let mut reader = Reader::new(data);
+        let version = reader.read_u32()? as i32;
+        let witness = reader.peek() == Some(0x00);
+        if witness {
+            reader.read_u8()?;
+            let flag = reader.read_u8()?;
+            if flag != 0x01 {
+                return Err(DecodeError::BadWitnessFlag(flag));
+            }
+        }
+        let mut inputs = Vec::new();
+        for _ in 0..reader.read_compact_size()? {
+            let prev_txid = H256::from(reader.read_array32()?);
+            let index = reader.read_u32()?;
+            let script = reader.read_bytes()?;
+            let sequence = reader.read_u32()?;
+            inputs.push(TxInput { prev_txid, index, script, witness: Vec::new(), sequence });
+        }
+        let mut outputs = Vec::new();
+        for _ in 0..reader.read_compact_size()? {
+            let value = reader.read_u64()?;
+            let script_pubkey = Script::from_bytes(reader.read_bytes()?);
+            outputs.push(TxOutput::with_script(value, script_pubkey));
+        }
+        if witness {
+            for input in inputs.iter_mut() {
+                for _ in 0..reader.read_compact_size()? {
+                    input.witness.push(reader.read_bytes()?);
+                }
+            }
+        }
+        let mut transaction = Transaction::new(inputs, outputs);
+        if witness && !transaction.has_witness() {
+            return Err(DecodeError::SuperfluousWitness);
+        }
+        transaction.version = version;
+        transaction.lock_time = reader.read_u32()?;
+        reader.finish()?;
+        return Ok(transaction);
+    }
+}
+
+impl Hashable for Transaction {
+    fn hash(&self) -> H256 {
+        return self.txid();
+    }
 }
 
 #[cfg(any(test, test_utilities))]
-mod tests {
+pub mod tests {
     use super::*;
-    use crate::crypto::key_pair;
+    use crate::block::Block;
+    use crate::crypto::keys::KeyPair;
+    use crate::crypto::merkle::MerkleTree;
+    use crate::crypto::ripemd160::hash160;
+    use rand::Rng;
 
     pub fn generate_random_transaction() -> Transaction {
-        Default::default()
-        //unimplemented!()
+        let mut rng = rand::thread_rng();
+        let input = TxInput::new(H256::from(rng.gen::<[u8; 32]>()), rng.gen_range(0, 4));
+        let output = TxOutput::new(rng.gen_range(1, 1000), H256::from(rng.gen::<[u8; 32]>()));
+        return Transaction::new(vec![input], vec![output]);
+    }
+
+    /// A transaction spending output `index` of transaction `prev_txid`, paying `value` back to
+    /// the address of `key`, and signed with it
+    pub fn generate_signed_transaction(prev_txid: &H256, index: u32, value: u64, key: &KeyPair) -> Transaction {
+        let output = TxOutput::new(value, key.public_key().address());
+        let mut transaction = Transaction::new(vec![TxInput::new(*prev_txid, index)], vec![output]);
+        transaction.sign(key).unwrap();
+        return transaction;
+    }
+
+    /// A transaction spending output `index` of transaction `prev_txid` to a random recipient
+    pub fn generate_spending_transaction(prev_txid: &H256, index: u32) -> Transaction {
+        let recipient = H256::from(rand::thread_rng().gen::<[u8; 32]>());
+        return Transaction::new(vec![TxInput::new(*prev_txid, index)], vec![TxOutput::new(1, recipient)]);
     }
 
     #[test]
     fn sign_verify() {
-        let t = generate_random_transaction();
-        let key = key_pair::random();
-        let signature = sign(&t, &key);
-        assert!(verify(&t, &(key.public_key()), &signature));
+        let mut t = generate_random_transaction();
+        let key = KeyPair::random();
+        let unsigned_txid = t.txid();
+        t.sign(&key).unwrap();
+        assert!(t.verify_signature(&key.public_key()));
+        assert_ne!(t.txid(), unsigned_txid);
+        assert_eq!(t.get_inputs()[0].signature().unwrap().address(), key.public_key().address());
     }
 
     #[test]
     fn assignment2_transaction_1() {
-        let t = generate_random_transaction();
-        let key = key_pair::random();
-        let signature = sign(&t, &key);
-        assert!(verify(&t, &(key.public_key()), &signature));
+        let mut t = generate_random_transaction();
+        let key = KeyPair::random();
+        assert!(!t.verify_signature(&key.public_key()));
+        t.sign(&key).unwrap();
+        assert!(t.verify_signature(&key.public_key()));
     }
     #[test]
     fn assignment2_transaction_2() {
+        let mut t = generate_random_transaction();
+        let key = KeyPair::random();
+        t.sign(&key).unwrap();
+        let key_2 = KeyPair::random();
+        // the signature does not cover another transaction
+        let mut t_2 = generate_random_transaction();
+        t_2.inputs[0].script = t.inputs[0].script.clone();
+        assert!(!t_2.verify_signature(&key.public_key()));
+        assert!(!t.verify_signature(&key_2.public_key()));
+        // nor a modified one
+        t.outputs[0].value += 1;
+        assert!(!t.verify_signature(&key.public_key()));
+    }
+
+    #[test]
+    fn signature_schemes() {
+        let ecdsa_key = KeyPair::random();
+        let schnorr_key = schnorr::KeyPair::random();
+        let ecdsa_output = TxOutput::new(10, ecdsa_key.public_key().address());
+        let schnorr_output = TxOutput::with_scheme(10, schnorr_key.public_key().address(), SignatureScheme::Schnorr);
+
+        let mut t = generate_random_transaction();
+        t.sign_schnorr(&schnorr_key).unwrap();
+        let signature = t.get_inputs()[0].signature().unwrap();
+        assert_eq!(signature.scheme(), SignatureScheme::Schnorr);
+        assert!(signature.unlocks(&schnorr_output, &t.signature_hash()));
+        assert!(!signature.unlocks(&ecdsa_output, &t.signature_hash()));
+        // a Schnorr signature is not an ECDSA one, even with the same address
+        let mislabeled = TxOutput::new(10, schnorr_key.public_key().address());
+        assert!(!signature.unlocks(&mislabeled, &t.signature_hash()));
+
+        t.sign(&ecdsa_key).unwrap();
+        let signature = t.get_inputs()[0].signature().unwrap();
+        assert!(signature.unlocks(&ecdsa_output, &t.signature_hash()));
+        assert!(!signature.unlocks(&schnorr_output, &t.signature_hash()));
+    }
+
+    #[test]
+    fn txid_is_cached() {
         let t = generate_random_transaction();
-        let key = key_pair::random();
-        let signature = sign(&t, &key);
-        let key_2 = key_pair::random();
-        let t_2 = generate_random_transaction();
-        assert!(!verify(&t_2, &(key.public_key()), &signature));
-        assert!(!verify(&t, &(key_2.public_key()), &signature));
+        let txid = t.txid();
+        assert_eq!(t.hash(), txid);
+        let copy: Transaction = bincode::deserialize(&bincode::serialize(&t).unwrap()).unwrap();
+        assert_eq!(copy.txid(), txid);
+        // the cached id is not part of the encoding
+        assert_eq!(bincode::serialize(&t).unwrap(), bincode::serialize(&copy).unwrap());
+        assert_eq!(t.wtxid(), txid);
+
+        // signing changes the transaction, so the cached ids are reset
+        let key = KeyPair::random();
+        let mut signed = generate_spending_transaction(&txid, 0);
+        let unsigned_txid = signed.txid();
+        assert_eq!(signed.wtxid(), unsigned_txid);
+        signed.sign(&key).unwrap();
+        assert_ne!(signed.txid(), unsigned_txid);
+        assert_ne!(signed.wtxid(), unsigned_txid);
+        assert_eq!(signed.wtxid(), signed.txid());
+    }
+
+    #[test]
+    fn witness_excluded_from_txid() {
+        let key = KeyPair::random();
+        let mut t = generate_spending_transaction(&H256::from([1u8; 32]), 0);
+        let unsigned_txid = t.txid();
+        t.sign_p2wpkh(&key).unwrap();
+        assert!(t.has_witness());
+        assert_eq!(t.txid(), unsigned_txid);
+        let first_wtxid = t.wtxid();
+        assert_ne!(first_wtxid, unsigned_txid);
+        // signing again gives another signature, but the same txid
+        t.sign_p2wpkh(&key).unwrap();
+        assert_eq!(t.txid(), unsigned_txid);
+        assert_ne!(t.wtxid(), first_wtxid);
+        let copy: Transaction = bincode::deserialize(&bincode::serialize(&t).unwrap()).unwrap();
+        assert_eq!(copy.wtxid(), t.wtxid());
+        assert_eq!(copy.get_inputs()[0].witness, t.get_inputs()[0].witness);
+    }
+
+    #[test]
+    fn fee() {
+        let key = KeyPair::random();
+        let funding = Transaction::coinbase(1, key.public_key().address(), 100);
+        let mut utxo = UtxoSet::new();
+        let merkle_root = MerkleTree::new(&[funding.clone()]).root();
+        utxo.connect_block(&Block::new(H256::default(), H256::default(), vec![funding.clone()], merkle_root));
+        assert_eq!(funding.fee(&utxo), Some(0));
+        let mut t = generate_signed_transaction(&funding.txid(), 0, 60, &key);
+        assert_eq!(t.fee(&utxo), Some(40));
+        assert_eq!(t.vsize(), t.size());
+        assert_eq!(t.fee_rate(&utxo), Some(FeeRate::from_fee(40, t.size())));
+        assert_eq!(generate_signed_transaction(&funding.txid(), 0, 101, &key).fee(&utxo), None);
+        assert_eq!(generate_signed_transaction(&funding.txid(), 1, 1, &key).fee(&utxo), None);
+
+        // witness bytes weigh less
+        t.set_witness(0, vec![vec![0u8; 100]]);
+        assert_eq!(t.base_size() + 108, t.size());
+        assert_eq!(t.vsize(), (t.base_size() * 4 + 108 + 3) / 4);
+    }
+
+    #[test]
+    fn lock_time() {
+        let mut t = generate_spending_transaction(&H256::from([1u8; 32]), 0);
+        assert!(t.is_final(0, 0));
+        let txid = t.txid();
+        t.set_lock_time(100);
+        assert_ne!(t.txid(), txid);
+        // all inputs opt out
+        assert!(t.is_final(50, 0));
+        t.set_sequence(0, SEQUENCE_FINAL - 1);
+        assert!(!t.is_final(50, 0));
+        assert!(!t.is_final(100, 0));
+        assert!(t.is_final(101, 0));
+        t.set_lock_time(LOCKTIME_THRESHOLD + 1000);
+        assert!(!t.is_final(1_000_000, LOCKTIME_THRESHOLD as u64 + 1000));
+        assert!(t.is_final(0, LOCKTIME_THRESHOLD as u64 + 1001));
+    }
+
+    #[test]
+    fn sighash_types() {
+        let input = |i: u8| TxInput::new(H256::from([i; 32]), 0);
+        let output = |value: u64| TxOutput::new(value, H256::from([9u8; 32]));
+        let t = Transaction::new(vec![input(1), input(2)], vec![output(10), output(20)]);
+        let hash = |t: &Transaction, index: usize, sighash_type: SigHashType| t.signature_hash_for(index, sighash_type).unwrap();
+
+        // another output only breaks ALL, and SINGLE when it is at the signed position
+        let mut more = t.clone();
+        more.outputs.push(output(30));
+        assert_ne!(hash(&t, 0, SigHashType::ALL), hash(&more, 0, SigHashType::ALL));
+        assert_eq!(hash(&t, 0, SigHashType::NONE), hash(&more, 0, SigHashType::NONE));
+        assert_eq!(hash(&t, 1, SigHashType::SINGLE), hash(&more, 1, SigHashType::SINGLE));
+        more.outputs[0].value = 11;
+        assert_eq!(hash(&t, 1, SigHashType::SINGLE), hash(&more, 1, SigHashType::SINGLE));
+        more.outputs[1].value = 21;
+        assert_ne!(hash(&t, 1, SigHashType::SINGLE), hash(&more, 1, SigHashType::SINGLE));
+
+        // another input only breaks types without ANYONECANPAY
+        let mut more = t.clone();
+        more.inputs.push(input(3));
+        for sighash_type in [SigHashType::ALL, SigHashType::NONE, SigHashType::SINGLE].iter() {
+            assert_ne!(hash(&t, 0, *sighash_type), hash(&more, 0, *sighash_type));
+            let anyone = sighash_type.anyone_can_pay();
+            assert_eq!(hash(&t, 0, anyone), hash(&more, 0, anyone));
+        }
+
+        // the type and index are committed to
+        assert_ne!(hash(&t, 0, SigHashType::ALL), hash(&t, 1, SigHashType::ALL));
+        assert_ne!(hash(&t, 0, SigHashType::ALL), hash(&t, 0, SigHashType::ALL.anyone_can_pay()));
+        assert_eq!(t.signature_hash_for(2, SigHashType::ALL), None);
+        let single = Transaction::new(vec![input(1), input(2)], vec![output(10)]);
+        assert_eq!(single.signature_hash_for(1, SigHashType::SINGLE), None);
+        // which is an error when signing, not a panic
+        let key = keys::KeyPair::random();
+        assert_eq!(single.script_signature(1, &key, SigHashType::SINGLE), Err(SignError::NoSignatureHash(1)));
+        assert_eq!(t.script_signature(2, &key, SigHashType::ALL), Err(SignError::NoSignatureHash(2)));
+        assert_eq!(SigHashType::from_byte(0x83), Some(SigHashType::SINGLE.anyone_can_pay()));
+        assert_eq!(SigHashType::from_byte(0), None);
+        assert_eq!(SigHashType::from_byte(0x84), None);
+    }
+
+    /// The display form of the id Bitcoin gives to the raw transaction, its double SHA256 in
+    /// reverse byte order
+    fn bitcoin_txid(raw: &[u8]) -> String {
+        let mut hash = digest(&SHA256, digest(&SHA256, raw).as_ref()).as_ref().to_vec();
+        hash.reverse();
+        return hex::encode(hash);
+    }
+
+    #[test]
+    fn consensus_encoding() {
+        // the coinbase of the genesis block, and the first transaction between two people, in
+        // block 170
+        let vectors = [
+            (
+                "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000",
+                "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+                vec![50_0000_0000],
+            ),
+            (
+                "0100000001c997a5e56e104102fa209c6a852dd90660a20b2d9c352423edce25857fcd3704000000004847304402204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd410220181522ec8eca07de4860a4acdd12909d831cc56cbbac4622082221a8768d1d0901ffffffff0200ca9a3b00000000434104ae1a62fe09c5f51b13905f07f06b99a2f7159b2225f374cd378d71302fa28414e7aab37397f554a7df5f142c21c1b7303b8a0626f1baded5c72a704f7e6cd84cac00286bee0000000043410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac00000000",
+                "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16",
+                vec![10_0000_0000, 40_0000_0000],
+            ),
+        ];
+        for (raw, txid, values) in vectors.iter() {
+            let raw = hex::decode(raw).unwrap();
+            assert_eq!(bitcoin_txid(&raw), *txid);
+            let t = Transaction::consensus_decode(&raw).unwrap();
+            assert_eq!(t.get_version(), 1);
+            assert_eq!(t.get_inputs().len(), 1);
+            assert_eq!(t.get_outputs().iter().map(|o| o.value).collect::<Vec<_>>(), *values);
+            assert_eq!(t.consensus_encode().unwrap(), raw);
+            let mut extra = raw.clone();
+            extra.push(0);
+            assert_eq!(Transaction::consensus_decode(&extra).err(), Some(DecodeError::TrailingData(1)));
+            assert_eq!(Transaction::consensus_decode(&raw[..raw.len() - 1]).err(), Some(DecodeError::Truncated));
+        }
+        assert!(Transaction::consensus_decode(&hex::decode(vectors[0].0).unwrap()).unwrap().is_coinbase());
+
+        // witnesses follow the outputs, after a marker and a flag
+        let key = KeyPair::random();
+        let output = TxOutput::with_script(1_000, Script::p2wpkh(&hash160(key.public_key().as_bytes())));
+        let mut t = Transaction::new(vec![TxInput::new(H256::from([1u8; 32]), 0)], vec![output]);
+        t.set_lock_time(100);
+        t.sign_p2wpkh(&key).unwrap();
+        let raw = t.consensus_encode().unwrap();
+        assert_eq!(&raw[4..6], &[0x00, 0x01]);
+        let decoded = Transaction::consensus_decode(&raw).unwrap();
+        assert_eq!(decoded.wtxid(), t.wtxid());
+        assert_eq!(decoded.txid(), t.txid());
+        let mut flag = raw.clone();
+        flag[5] = 2;
+        assert_eq!(Transaction::consensus_decode(&flag).err(), Some(DecodeError::BadWitnessFlag(2)));
+        t.set_witness(0, Vec::new());
+        let mut superfluous = t.consensus_encode().unwrap();
+        superfluous.splice(4..4, vec![0x00, 0x01]);
+        superfluous.insert(superfluous.len() - 4, 0x00);
+        assert_eq!(Transaction::consensus_decode(&superfluous).err(), Some(DecodeError::SuperfluousWitness));
+
+        // outputs paying an address have no script
+        assert_eq!(generate_random_transaction().consensus_encode(), None);
+    }
+
+    #[test]
+    fn coinbase() {
+        let recipient = H256::from([1u8; 32]);
+        let first = Transaction::coinbase(1, recipient, BLOCK_REWARD);
+        let second = Transaction::coinbase(2, recipient, BLOCK_REWARD);
+        assert!(first.is_coinbase());
+        assert_ne!(first.txid(), second.txid());
+        assert_eq!(first.output_value(), Some(BLOCK_REWARD));
+        assert!(!generate_random_transaction().is_coinbase());
     }
 }
@@ -0,0 +1,164 @@
+use serde::{Serialize, Deserialize};
+use ring::digest::{SHA256, digest};
+use std::collections::{HashMap, HashSet};
+
+use crate::block::Block;
+use crate::crypto::hash::H256;
+use crate::transaction::{Transaction, TxOutput};
+
+/// Reference to an output of a transaction
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OutPoint {
+    pub txid: H256,
+    pub index: u32,
+}
+
+impl OutPoint {
+    pub fn new(txid: H256, index: u32) -> Self {
+        return OutPoint { txid, index };
+    }
+}
+
+impl std::fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.txid, self.index)
+    }
+}
+
+/// Outputs spent by a transaction, none for a coinbase transaction
+pub fn spent_outpoints(transaction: &Transaction) -> Vec<OutPoint> {
+    return transaction
+        .get_inputs()
+        .iter()
+        .filter(|input| !input.is_coinbase())
+        .map(|input| input.outpoint())
+        .collect();
+}
+
+/// Outputs created by a transaction, leaving out those that can never be spent
+pub fn created_outputs(transaction: &Transaction) -> Vec<(OutPoint, TxOutput)> {
+    let txid = transaction.txid();
+    return transaction
+        .get_outputs()
+        .iter()
+        .enumerate()
+        .filter(|(_, output)| !output.is_unspendable())
+        .map(|(i, output)| (OutPoint::new(txid, i as u32), output.clone()))
+        .collect();
+}
+
+/// Outputs spent by a block, needed to disconnect it
+pub type UndoData = Vec<(OutPoint, TxOutput)>;
+
+/// The unspent transaction outputs of the longest chain
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct UtxoSet {
+    outputs: HashMap<OutPoint, TxOutput>,
+}
+
+impl UtxoSet {
+    pub fn new() -> Self {
+        return UtxoSet::default();
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&TxOutput> {
+        return self.outputs.get(outpoint);
+    }
+
+    pub fn contains(&self, outpoint: &OutPoint) -> bool {
+        return self.outputs.contains_key(outpoint);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.outputs.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.outputs.is_empty();
+    }
+
+    /// Hash committing to the whole set, independent of insertion order
+    pub fn hash(&self) -> H256 {
+        let mut entries: Vec<(&OutPoint, &TxOutput)> = self.outputs.iter().collect();
+        entries.sort();
+        let serialized = bincode::serialize(&entries).unwrap();
+        return digest(&SHA256, &serialized).into();
+    }
+
+    /// Spend the outputs the transactions of the block refer to, and add the ones they create.
+    /// Returns the spent outputs.
+    pub fn connect_block(&mut self, block: &Block) -> UndoData {
+        let mut spent: UndoData = Vec::new();
+        for transaction in block.get_transactions() {
+            for outpoint in spent_outpoints(transaction) {
+                if let Some(output) = self.outputs.remove(&outpoint) {
+                    spent.push((outpoint, output));
+                }
+            }
+            self.outputs.extend(created_outputs(transaction));
+        }
+        return spent;
+    }
+
+    /// Undo `connect_block`, given the outputs it spent
+    pub fn disconnect_block(&mut self, block: &Block, spent: &UndoData) {
+        let mut created: HashSet<OutPoint> = HashSet::new();
+        for transaction in block.get_transactions() {
+            for (outpoint, _) in created_outputs(transaction) {
+                self.outputs.remove(&outpoint);
+                created.insert(outpoint);
+            }
+        }
+        // outputs both created and spent within the block stay spent
+        for (outpoint, output) in spent {
+            if !created.contains(outpoint) {
+                self.outputs.insert(*outpoint, output.clone());
+            }
+        }
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::crypto::merkle::MerkleTree;
+    use crate::transaction::TxInput;
+
+    fn block_with(transactions: Vec<Transaction>) -> Block {
+        let merkle_root = MerkleTree::new(&transactions).root();
+        return Block::new(H256::from([0u8; 32]), Blockchain::get_difficulty(), transactions, merkle_root);
+    }
+
+    #[test]
+    fn connect_disconnect() {
+        let alice = TxOutput::new(50, H256::from([1u8; 32]));
+        let bob = TxOutput::new(30, H256::from([2u8; 32]));
+        let carol = TxOutput::new(20, H256::from([3u8; 32]));
+        let coinbase = Transaction::coinbase(1, alice.recipient, alice.value);
+        let first = block_with(vec![coinbase.clone()]);
+        let coin = OutPoint::new(coinbase.txid(), 0);
+        let spend = Transaction::new(vec![TxInput::new(coin.txid, coin.index)], vec![bob.clone(), carol.clone(), TxOutput::data(b"anchor")]);
+        let second = block_with(vec![spend.clone()]);
+
+        let mut utxo = UtxoSet::new();
+        utxo.connect_block(&first);
+        assert_eq!(utxo.get(&coin), Some(&alice));
+        let spent = utxo.connect_block(&second);
+        assert!(!utxo.contains(&coin));
+        assert_eq!(utxo.get(&OutPoint::new(spend.txid(), 0)), Some(&bob));
+        assert_eq!(utxo.get(&OutPoint::new(spend.txid(), 1)), Some(&carol));
+        // the data carrier output is never spendable
+        assert!(!utxo.contains(&OutPoint::new(spend.txid(), 2)));
+        assert_eq!(utxo.len(), 2);
+        let after_spend = utxo.hash();
+
+        assert_eq!(spent, vec![(coin, alice.clone())]);
+        utxo.disconnect_block(&second, &spent);
+        assert_eq!(utxo.get(&coin), Some(&alice));
+        assert_eq!(utxo.len(), 1);
+        assert_ne!(utxo.hash(), after_spend);
+        utxo.connect_block(&second);
+        assert_eq!(utxo.hash(), after_spend);
+    }
+}
@@ -0,0 +1,1005 @@
+use serde::{Serialize, Deserialize};
+use crossbeam::channel::{unbounded, Sender};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::block::{Block, Header};
+use crate::crypto::merkle::MerkleTree;
+use crate::runtime::ThreadPool;
+use crate::script::{self, Script, ScriptError, TransactionChecker};
+use crate::transaction::{SignatureScheme, Transaction, TxOutput, BLOCK_REWARD, MAX_MONEY};
+use crate::utxo::{self, OutPoint, UtxoSet};
+use crate::crypto::hash::{H256, Hashable};
+
+/// Number of ancestors whose median timestamp a new block must exceed
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// How far in the future a block timestamp may be, compared to the local clock
+pub const MAX_FUTURE_BLOCK_TIME: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// Largest total weight of the transactions of a block
+pub const MAX_BLOCK_WEIGHT: usize = 4_000_000;
+
+/// Reasons for a block to be rejected
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// The hash of the header is above its difficulty target
+    InvalidProofOfWork,
+    /// The difficulty target is not the one required at this height
+    WrongDifficulty,
+    /// The timestamp is not greater than the median time past of its ancestors
+    TimestampTooOld,
+    /// The timestamp is too far ahead of the local clock
+    TimestampTooNew,
+    /// The merkle root of the header does not match the transactions
+    BadMerkleRoot,
+    /// The witness commitment does not match the transactions
+    BadWitnessCommitment,
+    /// A transaction of the block is invalid
+    InvalidTransaction(H256, TxError),
+    /// A transaction of the block has a lock time that has not passed
+    NonFinalTransaction(H256),
+    /// Two transactions of the block spend the same output
+    DoubleSpend(OutPoint),
+    /// The coinbase transactions create more than the block reward and the fees
+    BadCoinbaseValue,
+    /// The first transaction is not a coinbase, or another one is
+    BadCoinbasePosition,
+    /// The coinbase does not start with the height of the block
+    BadCoinbaseHeight,
+    /// The transactions weigh more than `MAX_BLOCK_WEIGHT`
+    TooHeavy(usize),
+}
+
+/// Reasons for a transaction to be rejected, see `validate_transaction`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TxError {
+    /// The transaction has no input or no output
+    Empty,
+    /// A coinbase transaction outside of a block
+    Coinbase,
+    /// An input spends an output that does not exist or is already spent
+    MissingInput(OutPoint),
+    /// Two inputs spend the same output
+    DuplicateInput(OutPoint),
+    /// The input at this position is not signed by the key the spent output pays to
+    BadSignature(usize),
+    /// The script of the input at this position does not unlock the spent output
+    Script(usize, ScriptError),
+    /// The outputs are worth more than the spent outputs
+    InsufficientValue { inputs: u64, outputs: u64 },
+    /// The values do not fit in 64 bits
+    ValueOverflow,
+    /// An output, or the outputs together, are worth more than `MAX_MONEY`
+    ValueOutOfRange,
+    /// The lock time of the transaction has not passed
+    NotFinal,
+    /// The signature of the input at this position could not be verified, as its worker failed
+    Unverified(usize),
+}
+
+impl std::fmt::Display for TxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TxError::Empty => write!(f, "no inputs or no outputs"),
+            TxError::Coinbase => write!(f, "coinbase transaction outside of a block"),
+            TxError::MissingInput(outpoint) => write!(f, "missing input {}", outpoint),
+            TxError::DuplicateInput(outpoint) => write!(f, "input {} spent twice", outpoint),
+            TxError::BadSignature(index) => write!(f, "bad signature of input {}", index),
+            TxError::Script(index, e) => write!(f, "script of input {} failed: {}", index, e),
+            TxError::InsufficientValue { inputs, outputs } => {
+                write!(f, "outputs worth {} but inputs only {}", outputs, inputs)
+            }
+            TxError::ValueOverflow => write!(f, "value overflow"),
+            TxError::ValueOutOfRange => write!(f, "outputs worth more than {}", MAX_MONEY),
+            TxError::NotFinal => write!(f, "lock time not passed"),
+            TxError::Unverified(index) => write!(f, "signature of input {} not verified", index),
+        }
+    }
+}
+
+/// The stages of block validation, from the cheapest to the most expensive
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationStage {
+    /// Checks of the block alone
+    Stateless,
+    /// Checks against the ancestors of the block
+    Contextual,
+    /// Checks against the chain state the block is connected to
+    Connect,
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ValidationError::InvalidProofOfWork => write!(f, "hash above difficulty target"),
+            ValidationError::WrongDifficulty => write!(f, "unexpected difficulty target"),
+            ValidationError::TimestampTooOld => write!(f, "timestamp not after median time past"),
+            ValidationError::TimestampTooNew => write!(f, "timestamp too far in the future"),
+            ValidationError::BadMerkleRoot => write!(f, "merkle root does not match transactions"),
+            ValidationError::BadWitnessCommitment => write!(f, "witness commitment does not match transactions"),
+            ValidationError::InvalidTransaction(txid, e) => write!(f, "invalid transaction {}: {}", txid, e),
+            ValidationError::NonFinalTransaction(txid) => write!(f, "transaction {} is not final", txid),
+            ValidationError::DoubleSpend(outpoint) => write!(f, "output {} spent twice", outpoint),
+            ValidationError::BadCoinbaseValue => write!(f, "coinbase worth more than reward and fees"),
+            ValidationError::BadCoinbasePosition => write!(f, "coinbase not the first and only one"),
+            ValidationError::BadCoinbaseHeight => write!(f, "coinbase does not commit to the block height"),
+            ValidationError::TooHeavy(weight) => write!(f, "weight {} above the limit of {}", weight, MAX_BLOCK_WEIGHT),
+        }
+    }
+}
+
+impl ValidationError {
+    /// Whether the failure is a property of the block itself, rather than of the local clock, so
+    /// that the block can be rejected forever
+    pub fn is_permanent(&self) -> bool {
+        return match self {
+            ValidationError::TimestampTooNew => false,
+            _ => true,
+        };
+    }
+
+    /// Whether the failure is decided by the header alone, so that any block with this header
+    /// hash is invalid. Failures of the transactions are not: another body may match the header.
+    pub fn is_decided_by_header(&self) -> bool {
+        return match self {
+            ValidationError::InvalidProofOfWork | ValidationError::WrongDifficulty | ValidationError::TimestampTooOld => true,
+            _ => false,
+        };
+    }
+
+    /// The validation stage that detected the failure
+    pub fn stage(&self) -> ValidationStage {
+        return match self {
+            ValidationError::InvalidProofOfWork
+            | ValidationError::BadMerkleRoot
+            | ValidationError::BadWitnessCommitment
+            | ValidationError::BadCoinbasePosition
+            | ValidationError::TooHeavy(_) => ValidationStage::Stateless,
+            ValidationError::WrongDifficulty
+            | ValidationError::TimestampTooOld
+            | ValidationError::TimestampTooNew
+            | ValidationError::BadCoinbaseHeight
+            | ValidationError::NonFinalTransaction(_) => ValidationStage::Contextual,
+            ValidationError::InvalidTransaction(_, _)
+            | ValidationError::DoubleSpend(_)
+            | ValidationError::BadCoinbaseValue => ValidationStage::Connect,
+        };
+    }
+}
+
+/// Check that the hash of the header meets its difficulty target
+pub fn check_pow(header: &Header) -> Result<(), ValidationError> {
+    if header.hash() > header.get_difficulty() {
+        return Err(ValidationError::InvalidProofOfWork);
+    }
+    return Ok(());
+}
+
+/// Run all checks of the block that need no other data: proof of work, weight, and commitments to
+/// the transactions. Cheap enough to run before taking any lock.
+pub fn check_stateless(block: &Block) -> Result<(), ValidationError> {
+    check_pow(block.get_header())?;
+    let weight = block.weight();
+    if weight > MAX_BLOCK_WEIGHT {
+        return Err(ValidationError::TooHeavy(weight));
+    }
+    // a pruned block has nothing left to commit to, and is never relayed
+    let transactions = block.get_transactions();
+    if block.is_pruned() || transactions.is_empty() || MerkleTree::new(transactions).root() != block.get_header().get_merkle_root() {
+        return Err(ValidationError::BadMerkleRoot);
+    }
+    if !transactions[0].is_coinbase() || transactions[1..].iter().any(|t| t.is_coinbase()) {
+        return Err(ValidationError::BadCoinbasePosition);
+    }
+    if !block.verify_witness_commitment() {
+        return Err(ValidationError::BadWitnessCommitment);
+    }
+    // the wtxid of a coinbase is left out of the witness commitment, so it cannot have a witness
+    if let Some(coinbase) = transactions.iter().find(|t| t.is_coinbase() && t.has_witness()) {
+        return Err(ValidationError::InvalidTransaction(coinbase.txid(), TxError::Script(0, ScriptError::UnexpectedWitness)));
+    }
+    return Ok(());
+}
+
+/// Check a transaction against the outputs it spends, as found by `lookup`: it has inputs and
+/// outputs, each input spends a distinct existing output and is signed by the key that output
+/// pays to, and the outputs are worth no more than the inputs. Returns the fee, the difference.
+pub fn check_transaction<'a, F>(transaction: &Transaction, lookup: F) -> Result<u64, TxError>
+where
+    F: Fn(&OutPoint) -> Option<&'a TxOutput>,
+{
+    return check_transaction_with(transaction, lookup, true);
+}
+
+/// `check_transaction`, leaving out the signature checks unless `signatures`
+fn check_transaction_with<'a, F>(transaction: &Transaction, lookup: F, signatures: bool) -> Result<u64, TxError>
+where
+    F: Fn(&OutPoint) -> Option<&'a TxOutput>,
+{
+    if transaction.get_inputs().is_empty() || transaction.get_outputs().is_empty() {
+        return Err(TxError::Empty);
+    }
+    if transaction.is_coinbase() {
+        return Err(TxError::Coinbase);
+    }
+    let mut signature_hash: Option<H256> = None;
+    let mut spent: HashSet<OutPoint> = HashSet::new();
+    let mut inputs: u64 = 0;
+    for (index, input) in transaction.get_inputs().iter().enumerate() {
+        let outpoint = input.outpoint();
+        if !spent.insert(outpoint) {
+            return Err(TxError::DuplicateInput(outpoint));
+        }
+        let output = lookup(&outpoint).ok_or(TxError::MissingInput(outpoint))?;
+        if signatures {
+            if output.scheme != SignatureScheme::Script && signature_hash.is_none() {
+                signature_hash = Some(transaction.signature_hash());
+            }
+            check_input(transaction, index, output, signature_hash.as_ref())?;
+        }
+        inputs = inputs.checked_add(output.value).ok_or(TxError::ValueOverflow)?;
+    }
+    let outputs = check_output_values(transaction)?;
+    if outputs > inputs {
+        return Err(TxError::InsufficientValue { inputs, outputs });
+    }
+    return Ok(inputs - outputs);
+}
+
+/// Check that the outputs of `transaction` are each, and together, worth at most `MAX_MONEY`.
+/// Returns their value.
+fn check_output_values(transaction: &Transaction) -> Result<u64, TxError> {
+    let mut outputs: u64 = 0;
+    for output in transaction.get_outputs() {
+        outputs = outputs.checked_add(output.value).ok_or(TxError::ValueOverflow)?;
+        if output.value > MAX_MONEY || outputs > MAX_MONEY {
+            return Err(TxError::ValueOutOfRange);
+        }
+    }
+    return Ok(outputs);
+}
+
+/// Check that the input at `index` of `transaction` unlocks `output`, the output it spends.
+/// `signature_hash` is the `Transaction::signature_hash` of the transaction, needed for outputs
+/// not locked by a script.
+fn check_input(transaction: &Transaction, index: usize, output: &TxOutput, signature_hash: Option<&H256>) -> Result<(), TxError> {
+    let input = &transaction.get_inputs()[index];
+    if output.scheme == SignatureScheme::Script {
+        let script_sig = Script::from_bytes(input.script.clone());
+        let checker = TransactionChecker::new(transaction, index);
+        return script::verify(&script_sig, &input.witness, &output.script_pubkey, &checker).map_err(|e| TxError::Script(index, e));
+    }
+    // the witness of a signature spend is covered by neither the txid nor the signature
+    if !input.witness.is_empty() {
+        return Err(TxError::Script(index, ScriptError::UnexpectedWitness));
+    }
+    if !signature_hash.map_or(false, |hash| input.signature().map_or(false, |s| s.unlocks(output, hash))) {
+        return Err(TxError::BadSignature(index));
+    }
+    return Ok(());
+}
+
+/// Check a transaction spending outputs of `utxo`, see `check_transaction`. Used both to accept
+/// transactions to relay and to connect blocks.
+pub fn validate_transaction(transaction: &Transaction, utxo: &UtxoSet) -> Result<(), TxError> {
+    check_transaction(transaction, |outpoint| utxo.get(outpoint))?;
+    return Ok(());
+}
+
+/// Check that `transaction` may be in a block at `height` whose ancestors have median time past
+/// `mtp`, see `Transaction::is_final`. Transactions to relay are checked for the next block.
+pub fn check_final(transaction: &Transaction, height: u32, mtp: SystemTime) -> Result<(), TxError> {
+    let time = mtp.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    if !transaction.is_final(height, time) {
+        return Err(TxError::NotFinal);
+    }
+    return Ok(());
+}
+
+/// Check that the lock times of the transactions of the block at `height` have passed, given the
+/// headers of its ancestors, from its parent
+pub fn check_lock_times(block: &Block, height: u32, ancestors: &[Header]) -> Result<(), ValidationError> {
+    let mtp = median_time_past(ancestors).unwrap_or(UNIX_EPOCH);
+    for transaction in block.get_transactions() {
+        if check_final(transaction, height, mtp).is_err() {
+            return Err(ValidationError::NonFinalTransaction(transaction.hash()));
+        }
+    }
+    return Ok(());
+}
+
+/// Check that the coinbase of the block at `height` starts with the height, so that coinbases,
+/// and the outputs they create, differ from one block to another
+pub fn check_coinbase_height(block: &Block, height: u32) -> Result<(), ValidationError> {
+    let committed = block
+        .get_transactions()
+        .first()
+        .and_then(|coinbase| coinbase.get_inputs().first())
+        .map_or(false, |input| input.script.starts_with(&height.to_le_bytes()));
+    if !committed {
+        return Err(ValidationError::BadCoinbaseHeight);
+    }
+    return Ok(());
+}
+
+/// Check the transactions of the block against `utxo`, the chain state at its parent, with
+/// `check_transaction`. Outputs created earlier in the block can be spent by later transactions,
+/// but no output can be spent twice. The coinbase transactions may create at most the block
+/// reward and the fees.
+pub fn check_connect(block: &Block, utxo: &UtxoSet) -> Result<(), ValidationError> {
+    return check_connect_with(block, utxo, true);
+}
+
+/// `check_connect`, with the input signatures checked by the workers of `pool`, see
+/// `verify_signatures`
+pub fn check_connect_parallel(block: &Block, utxo: &UtxoSet, pool: &ThreadPool) -> Result<(), ValidationError> {
+    check_connect_with(block, utxo, false)?;
+    return match verify_signatures(block, utxo, pool).to_error() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    };
+}
+
+/// `check_connect`, leaving out the signature checks unless `signatures`
+fn check_connect_with(block: &Block, utxo: &UtxoSet, signatures: bool) -> Result<(), ValidationError> {
+    let mut spent: HashSet<OutPoint> = HashSet::new();
+    let mut created: HashMap<OutPoint, TxOutput> = HashMap::new();
+    let mut fees: u64 = 0;
+    let mut coinbase_value: u64 = 0;
+    for transaction in block.get_transactions() {
+        if transaction.is_coinbase() {
+            let value = check_output_values(transaction).map_err(|e| ValidationError::InvalidTransaction(transaction.txid(), e))?;
+            coinbase_value = coinbase_value.saturating_add(value);
+        } else {
+            let lookup = |outpoint: &OutPoint| utxo.get(outpoint).or_else(|| created.get(outpoint));
+            let fee = check_transaction_with(transaction, lookup, signatures)
+                .map_err(|e| ValidationError::InvalidTransaction(transaction.txid(), e))?;
+            fees = fees.saturating_add(fee);
+            for outpoint in utxo::spent_outpoints(transaction) {
+                if !spent.insert(outpoint) {
+                    return Err(ValidationError::DoubleSpend(outpoint));
+                }
+            }
+        }
+        created.extend(utxo::created_outputs(transaction));
+    }
+    if coinbase_value > BLOCK_REWARD.saturating_add(fees) {
+        return Err(ValidationError::BadCoinbaseValue);
+    }
+    return Ok(());
+}
+
+/// The input signature failures of the transactions of a block, see `verify_signatures`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SignatureReport {
+    /// Txid and errors of each transaction with inputs failing their checks, in block order
+    failures: Vec<(H256, Vec<TxError>)>,
+}
+
+impl SignatureReport {
+    pub fn is_valid(&self) -> bool {
+        return self.failures.is_empty();
+    }
+
+    pub fn get_failures(&self) -> &[(H256, Vec<TxError>)] {
+        return &self.failures;
+    }
+
+    /// The error of the block: the first failure of its first invalid transaction
+    pub fn to_error(&self) -> Option<ValidationError> {
+        let (txid, errors) = self.failures.first()?;
+        return Some(ValidationError::InvalidTransaction(*txid, errors[0].clone()));
+    }
+}
+
+/// An input to verify: the position of its transaction in the block, its index, the output it
+/// spends and the signature hash of its transaction, if needed
+type SignatureCheck = (usize, usize, TxOutput, Option<H256>);
+
+/// Run `checks`, returning the failures with the position of their transaction
+fn run_checks(transactions: &[Transaction], checks: &[SignatureCheck]) -> Vec<(usize, TxError)> {
+    return checks
+        .iter()
+        .filter_map(|(position, index, output, signature_hash)| {
+            check_input(&transactions[*position], *index, output, signature_hash.as_ref()).err().map(|e| (*position, e))
+        })
+        .collect();
+}
+
+/// The result of a chunk taken by a worker of `run_chunks`, sent when dropped so that it is sent
+/// even if the worker panics, empty then
+struct ChunkResult<R> {
+    index: usize,
+    result: Option<R>,
+    sender: Sender<(usize, Option<R>)>,
+}
+
+impl<R> Drop for ChunkResult<R> {
+    fn drop(&mut self) {
+        let _ = self.sender.send((self.index, self.result.take()));
+    }
+}
+
+/// Run `job` on each chunk of `chunk_size` items in parallel. The workers of `pool` and the
+/// calling thread take the chunks from a shared queue, so the caller never waits on chunks no
+/// worker took, e.g. if the pool has no workers. Returns the result of each chunk, None for the
+/// chunks whose worker panicked.
+fn run_chunks<T, R, F>(items: &[T], chunk_size: usize, pool: &ThreadPool, job: F) -> Vec<Option<R>>
+where
+    T: Clone + Send + 'static,
+    R: Send + 'static,
+    F: Fn(&[T]) -> R + Send + Sync + 'static,
+{
+    let (queue_sender, queue) = unbounded();
+    let count = items.chunks(chunk_size).len();
+    for (index, chunk) in items.chunks(chunk_size).enumerate() {
+        queue_sender.send((index, chunk.to_vec())).unwrap();
+    }
+    drop(queue_sender);
+    let job = Arc::new(job);
+    let (sender, receiver) = unbounded();
+    for _ in 0..std::cmp::min(count, pool.size()) {
+        let (queue, job, sender) = (queue.clone(), Arc::clone(&job), sender.clone());
+        pool.execute(move || {
+            while let Ok((index, chunk)) = queue.try_recv() {
+                let mut report = ChunkResult { index, result: None, sender: sender.clone() };
+                report.result = Some(job(&chunk));
+            }
+        });
+    }
+    let mut results: Vec<Option<R>> = (0..count).map(|_| None).collect();
+    let mut taken_by_workers = count;
+    while let Ok((index, chunk)) = queue.try_recv() {
+        results[index] = Some(job(&chunk));
+        taken_by_workers -= 1;
+    }
+    // each chunk a worker took is reported exactly once
+    for (index, result) in receiver.iter().take(taken_by_workers) {
+        results[index] = result;
+    }
+    return results;
+}
+
+/// Check the signatures of all inputs of `block` spending outputs of `utxo` or of earlier
+/// transactions of the block, split evenly between the workers of `pool` and the calling thread.
+/// Inputs spending unknown outputs are left to `check_connect`, inputs whose worker failed are
+/// reported unverified. Returns the failures of each transaction.
+pub fn verify_signatures(block: &Block, utxo: &UtxoSet, pool: &ThreadPool) -> SignatureReport {
+    let transactions: Arc<Vec<Transaction>> = Arc::new(block.get_transactions().to_vec());
+    let mut created: HashMap<OutPoint, TxOutput> = HashMap::new();
+    let mut checks: Vec<SignatureCheck> = Vec::new();
+    for (position, transaction) in transactions.iter().enumerate() {
+        if !transaction.is_coinbase() {
+            let mut signature_hash: Option<H256> = None;
+            for (index, input) in transaction.get_inputs().iter().enumerate() {
+                let outpoint = input.outpoint();
+                let output = match utxo.get(&outpoint).or_else(|| created.get(&outpoint)) {
+                    Some(output) => output.clone(),
+                    None => continue,
+                };
+                if output.scheme != SignatureScheme::Script && signature_hash.is_none() {
+                    signature_hash = Some(transaction.signature_hash());
+                }
+                checks.push((position, index, output, signature_hash));
+            }
+        }
+        created.extend(utxo::created_outputs(transaction));
+    }
+    let mut failed: Vec<(usize, TxError)> = Vec::new();
+    if pool.size() == 0 || checks.len() < 2 {
+        failed = run_checks(&transactions, &checks);
+    } else {
+        let chunk_size = (checks.len() + pool.size()) / (pool.size() + 1);
+        let shared = Arc::clone(&transactions);
+        let results = run_chunks(&checks, chunk_size, pool, move |chunk| run_checks(&shared, chunk));
+        for (chunk, result) in checks.chunks(chunk_size).zip(results) {
+            match result {
+                Some(failures) => failed.extend(failures),
+                None => failed.extend(chunk.iter().map(|(position, index, _, _)| (*position, TxError::Unverified(*index)))),
+            }
+        }
+    }
+    let mut by_position: BTreeMap<usize, Vec<TxError>> = BTreeMap::new();
+    for (position, e) in failed {
+        by_position.entry(position).or_default().push(e);
+    }
+    let failures = by_position
+        .into_iter()
+        .map(|(position, mut errors)| {
+            errors.sort_by_key(|e| match e {
+                TxError::BadSignature(index) | TxError::Script(index, _) | TxError::Unverified(index) => *index,
+                _ => 0,
+            });
+            (transactions[position].txid(), errors)
+        })
+        .collect();
+    return SignatureReport { failures };
+}
+
+/// Median timestamp of the last `MEDIAN_TIME_SPAN` ancestors. `ancestors` is ordered from the
+/// parent backwards; only its first `MEDIAN_TIME_SPAN` entries are used.
+pub fn median_time_past(ancestors: &[Header]) -> Option<SystemTime> {
+    let n = std::cmp::min(ancestors.len(), MEDIAN_TIME_SPAN);
+    if n == 0 {
+        return None;
+    }
+    let mut timestamps: Vec<SystemTime> = ancestors[..n].iter().map(|h| h.get_timestamp()).collect();
+    timestamps.sort();
+    return Some(timestamps[n / 2]);
+}
+
+/// Check the timestamp of `header` against the median time past of its ancestors and the local
+/// clock `now`
+pub fn check_timestamp(header: &Header, ancestors: &[Header], now: SystemTime) -> Result<(), ValidationError> {
+    if let Some(mtp) = median_time_past(ancestors) {
+        if header.get_timestamp() <= mtp {
+            return Err(ValidationError::TimestampTooOld);
+        }
+    }
+    if header.get_timestamp() > now + MAX_FUTURE_BLOCK_TIME {
+        return Err(ValidationError::TimestampTooNew);
+    }
+    return Ok(());
+}
+
+/// Run all checks of `header` that depend on its position in the chain. `ancestors` is ordered
+/// from the parent backwards, as returned by `Blockchain::ancestor_headers`.
+pub fn check_contextual(header: &Header, ancestors: &[Header], now: SystemTime) -> Result<(), ValidationError> {
+    check_timestamp(header, ancestors, now)?;
+    return Ok(());
+}
+
+/// Most blocks remembered by a `BadBlockCache`, the least recently used being forgotten first
+pub const MAX_BAD_BLOCKS: usize = 10_000;
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> std::io::Error {
+    return std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string());
+}
+
+/// Hashes of blocks whose header failed validation, with the reason, so that repeated relays of
+/// the same block are rejected without validating it again. Optionally backed by a file, to which
+/// new entries are appended, and which is rewritten once it holds twice the capacity.
+pub struct BadBlockCache {
+    capacity: usize,
+    reasons: HashMap<H256, (ValidationError, u64)>,
+    /// Hash of the block used at each tick
+    recency: BTreeMap<u64, H256>,
+    tick: u64,
+    file: Option<(PathBuf, File)>,
+    /// Records in the file, including those of forgotten blocks
+    records: usize,
+}
+
+impl Default for BadBlockCache {
+    fn default() -> Self {
+        return BadBlockCache::with_capacity(MAX_BAD_BLOCKS);
+    }
+}
+
+impl BadBlockCache {
+    /// Create a cache in memory remembering up to `capacity` blocks
+    pub fn with_capacity(capacity: usize) -> Self {
+        return BadBlockCache {
+            capacity,
+            reasons: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            file: None,
+            records: 0,
+        };
+    }
+
+    /// Create a cache persisted at `path`, loading the entries already stored there
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        return BadBlockCache::open_with_capacity(path, MAX_BAD_BLOCKS);
+    }
+
+    /// `open`, remembering up to `capacity` blocks
+    pub fn open_with_capacity(path: &Path, capacity: usize) -> std::io::Result<Self> {
+        let mut cache = BadBlockCache::with_capacity(capacity);
+        let mut bytes: Vec<u8> = Vec::new();
+        if path.exists() {
+            File::open(path)?.read_to_end(&mut bytes)?;
+        }
+        let mut offset: usize = 0;
+        while offset + 4 <= bytes.len() {
+            let len = u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+            if offset + 4 + len > bytes.len() {
+                // a record torn by a crash, it is overwritten by the next one
+                break;
+            }
+            let (hash, reason): (H256, ValidationError) =
+                bincode::deserialize(&bytes[offset + 4..offset + 4 + len]).map_err(invalid_data)?;
+            if reason.is_decided_by_header() {
+                cache.remember(hash, reason);
+            }
+            cache.records += 1;
+            offset += 4 + len;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        if offset < bytes.len() {
+            file.set_len(offset as u64)?;
+        }
+        cache.file = Some((path.to_path_buf(), file));
+        return Ok(cache);
+    }
+
+    /// Get the reason a block failed, counting as a use of the entry
+    pub fn get(&mut self, hash: &H256) -> Option<&ValidationError> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (reason, last_use) = self.reasons.get_mut(hash)?;
+        self.recency.remove(last_use);
+        self.recency.insert(tick, *hash);
+        *last_use = tick;
+        return Some(reason);
+    }
+
+    /// Get the reason a block failed, without counting as a use of the entry
+    pub fn peek(&self, hash: &H256) -> Option<&ValidationError> {
+        return self.reasons.get(hash).map(|(reason, _)| reason);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.reasons.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.reasons.is_empty();
+    }
+
+    /// Record a failed block, appending it to the file of the cache if it has one
+    pub fn insert(&mut self, hash: H256, reason: ValidationError) -> std::io::Result<()> {
+        let record = bincode::serialize(&(hash, &reason)).unwrap();
+        self.remember(hash, reason);
+        if let Some((_, file)) = &mut self.file {
+            file.write_all(&[&(record.len() as u32).to_be_bytes()[..], &record].concat())?;
+            self.records += 1;
+            if self.records > 2 * self.capacity {
+                self.rewrite()?;
+            }
+        }
+        return Ok(());
+    }
+
+    /// Add an entry, forgetting the least recently used ones beyond the capacity
+    fn remember(&mut self, hash: H256, reason: ValidationError) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        if let Some((_, last_use)) = self.reasons.insert(hash, (reason, self.tick)) {
+            self.recency.remove(&last_use);
+        }
+        self.recency.insert(self.tick, hash);
+        while self.reasons.len() > self.capacity {
+            let (&oldest, _) = self.recency.iter().next().unwrap();
+            let forgotten = self.recency.remove(&oldest).unwrap();
+            self.reasons.remove(&forgotten);
+        }
+    }
+
+    /// Rewrite the file with the entries remembered, the least recently used first, then swap
+    /// it in
+    fn rewrite(&mut self) -> std::io::Result<()> {
+        let path = match &self.file {
+            Some((path, _)) => path.clone(),
+            None => return Ok(()),
+        };
+        let tmp_path = path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        for hash in self.recency.values() {
+            let record = bincode::serialize(&(hash, &self.reasons[hash].0)).unwrap();
+            tmp.write_all(&(record.len() as u32).to_be_bytes())?;
+            tmp.write_all(&record)?;
+        }
+        tmp.sync_data()?;
+        std::fs::rename(&tmp_path, &path)?;
+        self.file = Some((path.clone(), OpenOptions::new().append(true).open(&path)?));
+        self.records = self.reasons.len();
+        return Ok(());
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block_at;
+    use crate::crypto::keys::KeyPair;
+    use crate::crypto::ripemd160::hash160;
+    use crate::transaction::tests::generate_spending_transaction;
+    use crate::transaction::{SigHashType, TxInput, LOCKTIME_THRESHOLD};
+    use crate::crypto::hash::H256;
+    use std::time::UNIX_EPOCH;
+
+    fn headers_at(seconds: &[u64]) -> Vec<Header> {
+        return seconds
+            .iter()
+            .map(|s| generate_random_block_at(&H256::default(), UNIX_EPOCH + Duration::from_secs(*s)).get_header().clone())
+            .collect();
+    }
+
+    #[test]
+    fn lock_times() {
+        let mut locked = generate_spending_transaction(&H256::default(), 0);
+        locked.set_sequence(0, 0);
+        locked.set_lock_time(LOCKTIME_THRESHOLD + 20);
+        let transactions = vec![locked.clone()];
+        let merkle_root = MerkleTree::new(&transactions).root();
+        let block = Block::new(H256::default(), H256::default(), transactions, merkle_root);
+        let ancestors = headers_at(&[LOCKTIME_THRESHOLD as u64 + 20, LOCKTIME_THRESHOLD as u64 + 10]);
+        assert_eq!(
+            check_lock_times(&block, 1, &ancestors),
+            Err(ValidationError::NonFinalTransaction(locked.hash()))
+        );
+        let ancestors = headers_at(&[LOCKTIME_THRESHOLD as u64 + 30, LOCKTIME_THRESHOLD as u64 + 21]);
+        assert_eq!(check_lock_times(&block, 1, &ancestors), Ok(()));
+        let mtp = UNIX_EPOCH + Duration::from_secs(LOCKTIME_THRESHOLD as u64);
+        assert_eq!(check_final(&locked, 1, mtp), Err(TxError::NotFinal));
+    }
+
+    #[test]
+    fn median() {
+        assert_eq!(median_time_past(&[]), None);
+        let ancestors = headers_at(&[30, 10, 20]);
+        assert_eq!(median_time_past(&ancestors), Some(UNIX_EPOCH + Duration::from_secs(20)));
+        // only the last 11 ancestors count
+        let ancestors = headers_at(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 100, 100, 100, 100]);
+        assert_eq!(median_time_past(&ancestors), Some(UNIX_EPOCH + Duration::from_secs(6)));
+    }
+
+    #[test]
+    fn timestamp() {
+        let ancestors = headers_at(&[30, 10, 20]);
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        let header = &headers_at(&[20])[0];
+        assert_eq!(check_timestamp(header, &ancestors, now), Err(ValidationError::TimestampTooOld));
+        let header = &headers_at(&[21])[0];
+        assert_eq!(check_timestamp(header, &ancestors, now), Ok(()));
+        let header = &headers_at(&[1000 + 2 * 60 * 60 + 1])[0];
+        assert_eq!(check_timestamp(header, &ancestors, now), Err(ValidationError::TimestampTooNew));
+        assert_eq!(check_contextual(&headers_at(&[5])[0], &[], now), Ok(()));
+    }
+
+    #[test]
+    fn transaction() {
+        let key = KeyPair::random();
+        let funding = Transaction::coinbase(1, key.public_key().address(), 100);
+        let mut utxo = UtxoSet::new();
+        let merkle_root = MerkleTree::new(&[funding.clone()]).root();
+        utxo.connect_block(&Block::new(H256::default(), H256::default(), vec![funding.clone()], merkle_root));
+        let coin = TxInput::new(funding.txid(), 0);
+        let build = |inputs: Vec<TxInput>, value: u64| {
+            let mut transaction = Transaction::new(inputs, vec![TxOutput::new(value, H256::default())]);
+            transaction.sign(&key).unwrap();
+            transaction
+        };
+
+        assert_eq!(validate_transaction(&build(vec![coin.clone()], 100), &utxo), Ok(()));
+        assert_eq!(
+            validate_transaction(&build(vec![coin.clone()], 101), &utxo),
+            Err(TxError::InsufficientValue { inputs: 100, outputs: 101 })
+        );
+        assert_eq!(
+            validate_transaction(&build(vec![coin.clone(), coin.clone()], 1), &utxo),
+            Err(TxError::DuplicateInput(coin.outpoint()))
+        );
+        let missing = TxInput::new(funding.txid(), 1);
+        assert_eq!(
+            validate_transaction(&build(vec![missing.clone()], 1), &utxo),
+            Err(TxError::MissingInput(missing.outpoint()))
+        );
+        let mut stolen = build(vec![coin.clone()], 100);
+        stolen.sign(&KeyPair::random()).unwrap();
+        assert_eq!(validate_transaction(&stolen, &utxo), Err(TxError::BadSignature(0)));
+        assert_eq!(validate_transaction(&funding, &utxo), Err(TxError::Coinbase));
+        assert_eq!(validate_transaction(&build(vec![], 1), &utxo), Err(TxError::Empty));
+
+        // a witness is only expected when spending a witness program
+        let mut stuffed = build(vec![coin.clone()], 100);
+        stuffed.set_witness(0, vec![vec![1]]);
+        assert_eq!(validate_transaction(&stuffed, &utxo), Err(TxError::Script(0, ScriptError::UnexpectedWitness)));
+        let mut coinbase = Transaction::coinbase(2, key.public_key().address(), 100);
+        coinbase.set_witness(0, vec![vec![1]]);
+        let mut block = Block::new(H256::default(), H256::from([255u8; 32]), vec![coinbase], H256::default());
+        block.commit_witnesses();
+        let txid = block.get_transactions()[0].txid();
+        assert_eq!(check_stateless(&block), Err(ValidationError::InvalidTransaction(txid, TxError::Script(0, ScriptError::UnexpectedWitness))));
+    }
+
+    #[test]
+    fn script_output() {
+        let key = KeyPair::random();
+        let script_pubkey = Script::p2pkh(&hash160(key.public_key().as_bytes()));
+        let funding = Transaction::new(vec![TxInput::coinbase(vec![1])], vec![TxOutput::with_script(100, script_pubkey)]);
+        let mut utxo = UtxoSet::new();
+        let merkle_root = MerkleTree::new(&[funding.clone()]).root();
+        utxo.connect_block(&Block::new(H256::default(), H256::default(), vec![funding.clone()], merkle_root));
+        let mut spend = Transaction::new(vec![TxInput::new(funding.txid(), 0)], vec![TxOutput::new(100, H256::default())]);
+
+        spend.set_script(0, &Script::from_bytes(vec![5, 1, 2]));
+        assert_eq!(validate_transaction(&spend, &utxo), Err(TxError::Script(0, ScriptError::Truncated)));
+        spend.sign_p2pkh(&KeyPair::random()).unwrap();
+        assert_eq!(validate_transaction(&spend, &utxo), Err(TxError::Script(0, ScriptError::VerifyFailed)));
+        spend.sign_p2pkh(&key).unwrap();
+        assert_eq!(validate_transaction(&spend, &utxo), Ok(()));
+
+        // an output locked to the hash of a script requiring the key
+        let redeem_script = Script::new().push_data(key.public_key().as_bytes()).push_opcode(script::opcodes::OP_CHECKSIG);
+        let funding = Transaction::new(vec![TxInput::coinbase(vec![2])], vec![TxOutput::with_script(90, Script::p2sh_of(&redeem_script))]);
+        let merkle_root = MerkleTree::new(&[funding.clone()]).root();
+        utxo.connect_block(&Block::new(H256::default(), H256::default(), vec![funding.clone()], merkle_root));
+        let mut redeem = Transaction::new(vec![TxInput::new(funding.txid(), 0)], vec![TxOutput::new(90, H256::default())]);
+        let signature = redeem.script_signature(0, &key, SigHashType::ALL).unwrap();
+        redeem.set_script(0, &Script::p2sh_unlock(&[signature], &redeem_script));
+        assert_eq!(validate_transaction(&redeem, &utxo), Ok(()));
+        redeem.set_script(0, &Script::p2sh_unlock(&[], &redeem_script));
+        assert_eq!(validate_transaction(&redeem, &utxo), Err(TxError::Script(0, ScriptError::StackUnderflow)));
+    }
+
+    #[test]
+    fn witness_chain() {
+        let key = KeyPair::random();
+        let script_pubkey = Script::p2wpkh(&hash160(key.public_key().as_bytes()));
+        let funding = Transaction::new(vec![TxInput::coinbase(vec![1])], vec![TxOutput::with_script(100, script_pubkey.clone())]);
+        let mut utxo = UtxoSet::new();
+        let merkle_root = MerkleTree::new(&[funding.clone()]).root();
+        utxo.connect_block(&Block::new(H256::default(), H256::default(), vec![funding.clone()], merkle_root));
+
+        // the child is built on the txid of its parent before the parent is signed
+        let mut parent = Transaction::new(vec![TxInput::new(funding.txid(), 0)], vec![TxOutput::with_script(90, script_pubkey)]);
+        let mut child = Transaction::new(vec![TxInput::new(parent.txid(), 0)], vec![TxOutput::new(80, H256::default())]);
+        parent.sign_p2wpkh(&key).unwrap();
+        child.sign_p2wpkh(&key).unwrap();
+        assert_eq!(child.get_inputs()[0].prev_txid, parent.txid());
+        assert_eq!(validate_transaction(&parent, &utxo), Ok(()));
+        let transactions = vec![funding.clone(), parent.clone(), child];
+        let merkle_root = MerkleTree::new(&transactions).root();
+        let mut block = Block::new(H256::default(), H256::default(), transactions, merkle_root);
+        assert!(!block.verify_witness_commitment());
+        block.commit_witnesses();
+        assert!(block.verify_witness_commitment());
+
+        // the witness cannot be moved to the unlocking script
+        let mut moved = parent.clone();
+        let witness = moved.get_inputs()[0].witness.clone();
+        moved.set_script(0, &Script::p2pkh_unlock(&witness[0], &witness[1]));
+        assert_eq!(validate_transaction(&moved, &utxo), Err(TxError::Script(0, ScriptError::WitnessMalleated)));
+    }
+
+    #[test]
+    fn block_weight() {
+        let transaction = generate_spending_transaction(&H256::default(), 0);
+        let coinbase = Transaction::coinbase(0, H256::default(), 0);
+        let count = (MAX_BLOCK_WEIGHT - coinbase.weight()) / transaction.weight();
+        let difficulty = H256::from([255u8; 32]);
+        let block_of = |count: usize| {
+            let mut transactions = vec![coinbase.clone()];
+            transactions.extend(vec![transaction.clone(); count]);
+            let merkle_root = MerkleTree::new(&transactions).root();
+            Block::new(H256::default(), difficulty, transactions, merkle_root)
+        };
+        assert_eq!(check_stateless(&block_of(count)), Ok(()));
+        let heavy = block_of(count + 1);
+        assert_eq!(heavy.weight(), coinbase.weight() + (count + 1) * transaction.weight());
+        assert_eq!(check_stateless(&heavy), Err(ValidationError::TooHeavy(heavy.weight())));
+    }
+
+    #[test]
+    fn parallel_signatures() {
+        let key = KeyPair::random();
+        let outputs = (0..6).map(|_| TxOutput::new(100, key.public_key().address())).collect();
+        let funding = Transaction::new(vec![TxInput::coinbase(vec![1])], outputs);
+        let mut utxo = UtxoSet::new();
+        let merkle_root = MerkleTree::new(&[funding.clone()]).root();
+        utxo.connect_block(&Block::new(H256::default(), H256::default(), vec![funding.clone()], merkle_root));
+        let block_of = |spends: &[Transaction]| {
+            let mut transactions = vec![Transaction::coinbase(2, H256::default(), BLOCK_REWARD)];
+            transactions.extend_from_slice(spends);
+            let merkle_root = MerkleTree::new(&transactions).root();
+            Block::new(H256::default(), H256::default(), transactions, merkle_root)
+        };
+        let mut spends: Vec<Transaction> = (0..6)
+            .map(|i| {
+                let mut spend = generate_spending_transaction(&funding.txid(), i);
+                spend.sign(&key).unwrap();
+                spend
+            })
+            .collect();
+        // spending an output of an earlier transaction of the block
+        let mut child = generate_spending_transaction(&spends[0].txid(), 0);
+        child.sign(&KeyPair::random()).unwrap();
+
+        let pool = ThreadPool::new("test", 3);
+        let block = block_of(&spends);
+        assert!(verify_signatures(&block, &utxo, &pool).is_valid());
+        assert_eq!(check_connect_parallel(&block, &utxo, &pool), Ok(()));
+        assert_eq!(check_connect(&block, &utxo), Ok(()));
+
+        spends[2].sign(&KeyPair::random()).unwrap();
+        spends[4].sign(&KeyPair::random()).unwrap();
+        spends.push(child);
+        let block = block_of(&spends);
+        let report = verify_signatures(&block, &utxo, &pool);
+        let failed: Vec<H256> = report.get_failures().iter().map(|(txid, _)| *txid).collect();
+        assert_eq!(failed, vec![spends[2].txid(), spends[4].txid(), spends[6].txid()]);
+        assert!(report.get_failures().iter().all(|(_, errors)| errors == &vec![TxError::BadSignature(0)]));
+        assert_eq!(verify_signatures(&block, &utxo, &ThreadPool::new("inline", 0)), report);
+        assert_eq!(check_connect_parallel(&block, &utxo, &pool), check_connect(&block, &utxo));
+    }
+
+    #[test]
+    fn failed_workers() {
+        let pool = ThreadPool::new("failing", 2);
+        let items: Vec<usize> = (0..40).collect();
+        let results = run_chunks(&items, 1, &pool, |chunk: &[usize]| {
+            if std::thread::current().name().map_or(false, |name| name.starts_with("failing")) {
+                panic!("worker failure");
+            }
+            std::thread::sleep(Duration::from_millis(5));
+            chunk[0]
+        });
+        // the chunks of the workers are missing, not taken as done
+        assert_eq!(results.len(), 40);
+        assert!(results.iter().any(|r| r.is_none()));
+        assert!(results.iter().enumerate().all(|(i, r)| r.map_or(true, |item| item == i)));
+        assert_eq!(pool.running(), 2);
+
+        // without workers, the calling thread does all the work
+        let idle = ThreadPool::new("idle", 0);
+        let results = run_chunks(&items, 7, &idle, |chunk: &[usize]| chunk.len());
+        assert_eq!(results, vec![Some(7), Some(7), Some(7), Some(7), Some(7), Some(5)]);
+    }
+
+    #[test]
+    fn bad_block_cache() {
+        let path = std::env::temp_dir().join(format!("bad_blocks_{}", rand::random::<u32>()));
+        let hash: H256 = [7u8; 32].into();
+        {
+            let mut cache = BadBlockCache::open(&path).unwrap();
+            assert!(cache.is_empty());
+            cache.insert(hash, ValidationError::InvalidProofOfWork).unwrap();
+        }
+        let mut cache = BadBlockCache::open(&path).unwrap();
+        assert_eq!(cache.get(&hash), Some(&ValidationError::InvalidProofOfWork));
+        assert_eq!(cache.len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bad_block_cache_capacity() {
+        let path = std::env::temp_dir().join(format!("bad_blocks_{}", rand::random::<u32>()));
+        let hashes: Vec<H256> = (0..10u8).map(|i| [i; 32].into()).collect();
+        let record_size = 4 + bincode::serialize(&(hashes[0], ValidationError::InvalidProofOfWork)).unwrap().len() as u64;
+        {
+            let mut cache = BadBlockCache::open_with_capacity(&path, 2).unwrap();
+            cache.insert(hashes[0], ValidationError::InvalidProofOfWork).unwrap();
+            cache.insert(hashes[1], ValidationError::WrongDifficulty).unwrap();
+            // the least recently used block is forgotten first
+            assert!(cache.get(&hashes[0]).is_some());
+            cache.insert(hashes[2], ValidationError::TimestampTooOld).unwrap();
+            assert_eq!(cache.len(), 2);
+            assert!(cache.peek(&hashes[0]).is_some());
+            assert!(cache.peek(&hashes[1]).is_none());
+        }
+        {
+            let mut cache = BadBlockCache::open_with_capacity(&path, 2).unwrap();
+            assert_eq!(cache.len(), 2);
+            assert!(cache.peek(&hashes[2]).is_some());
+            // records are appended, and the file rewritten once it holds twice the capacity
+            for hash in &hashes[3..] {
+                cache.insert(*hash, ValidationError::InvalidProofOfWork).unwrap();
+                assert!(std::fs::metadata(&path).unwrap().len() <= 4 * record_size);
+            }
+        }
+        let cache = BadBlockCache::open_with_capacity(&path, 2).unwrap();
+        assert!(cache.peek(&hashes[8]).is_some() && cache.peek(&hashes[9]).is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+}